@@ -0,0 +1,180 @@
+//! Checks of the images to be produced by the queued jobs.
+//!
+//! Two jobs computing the same output path would silently clobber each
+//! other, the second compression overwriting the first. The paths are
+//! checked while the queue is assembled, before any work starts.
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+
+use crate::context::ImageContext;
+
+/// What to do if an image to be produced already exists in the output directory.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OverwritePolicy {
+	/// Replace the existing image.
+	#[default]
+	Replace,
+	/// Refuse to build anything.
+	Error,
+}
+
+/// An image to be produced by a job.
+#[derive(Clone, Debug)]
+pub struct Artifact {
+	/// Full path to the image.
+	pub path: PathBuf,
+	/// Which job produces it.
+	pub job: String,
+	/// Fields the name is made of.
+	pub fields: Vec<(&'static str, String)>,
+}
+
+impl ImageContext<'_> {
+	pub fn artifact(&self) -> Artifact {
+		Artifact {
+			path: self.output_dir().join(&self.filename),
+			job: format!(
+				"{} ({})",
+				&self.device.id,
+				self.variant.to_string().to_lowercase()
+			),
+			fields: vec![
+				("variant", self.variant.to_string().to_lowercase()),
+				("vendor", self.device.vendor.clone()),
+				("id", self.device.id.clone()),
+				("arch", self.device.arch.to_string().to_lowercase()),
+				("compression", self.compress.get_extension().to_owned()),
+			],
+		}
+	}
+}
+
+/// Fields having the same value in all of the artifacts.
+fn identical_fields(artifacts: &[&Artifact]) -> Vec<&'static str> {
+	let first = match artifacts.first() {
+		Some(a) => a,
+		None => return Vec::new(),
+	};
+	first.fields
+		.iter()
+		.filter(|(name, value)| {
+			artifacts
+				.iter()
+				.all(|a| a.fields.iter().any(|(n, v)| n == name && v == value))
+		})
+		.map(|(name, _)| *name)
+		.collect()
+}
+
+/// Make sure no two jobs produce the same image, and no existing image is replaced if not allowed.
+pub fn check_artifacts(artifacts: &[Artifact], policy: OverwritePolicy) -> Result<()> {
+	let mut by_path: HashMap<&PathBuf, Vec<&Artifact>> = HashMap::new();
+	let mut order = Vec::new();
+	for artifact in artifacts {
+		let jobs = by_path.entry(&artifact.path).or_default();
+		if jobs.is_empty() {
+			order.push(&artifact.path);
+		}
+		jobs.push(artifact);
+	}
+	let mut errors = Vec::new();
+	for path in order {
+		let jobs = &by_path[path];
+		if jobs.len() > 1 {
+			let names = jobs.iter().map(|a| a.job.as_str()).collect::<Vec<_>>();
+			errors.push(format!(
+				"{} jobs produce {}:\n\t- {}\n\tIdentical fields: {}",
+				jobs.len(),
+				path.display(),
+				names.join("\n\t- "),
+				identical_fields(jobs).join(", ")
+			));
+		} else if policy == OverwritePolicy::Error && path.exists() {
+			errors.push(format!(
+				"{} already exists, produced by {}",
+				path.display(),
+				jobs[0].job
+			));
+		}
+	}
+	if !errors.is_empty() {
+		bail!(
+			"Conflicting output files:\n{}\nNothing has been built.",
+			errors.join("\n")
+		);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::Path;
+
+	use super::*;
+	use crate::{
+		naming::image_name,
+		testutil::{load_device, test_context, TempDir},
+	};
+
+	/// Artifacts of the base images of the fixture device as `(id, name template)`.
+	fn artifacts(outdir: &Path, devices: &[(&str, &str)]) -> Result<Vec<Artifact>> {
+		let fixture = load_device(
+			outdir,
+			"partition_map = \"gpt\"\n\n[[partition]]\nnum = 1\ntype = \"linux\"\nusage = \"rootfs\"\nfilesystem = \"ext4\"\nmountpoint = \"/\"\nsize = \"rest\"\n",
+		)?;
+		let mut artifacts = Vec::new();
+		for (id, template) in devices {
+			let mut device = fixture.clone();
+			device.id = id.to_string();
+			device.vendor = "raspberrypi".into();
+			device.name_template = Some(template.parse()?);
+			let mut ctx = test_context(&device, outdir);
+			let name = image_name(
+				device.name_template.as_ref().unwrap(),
+				&device,
+				ctx.variant,
+				"20241108",
+				None,
+			)?;
+			ctx.filename = format!("{}.img{}", name, ctx.compress.get_extension());
+			artifacts.push(ctx.artifact());
+		}
+		Ok(artifacts)
+	}
+
+	#[test]
+	fn test_check_artifacts() -> Result<()> {
+		let outdir = TempDir::new("artifacts")?;
+		// A name made of the vendor and the variant only.
+		let artifacts = artifacts(
+			&outdir,
+			&[
+				("rpi-5b", "{vendor}-{variant}"),
+				("rpi-4b", "{vendor}-{variant}"),
+				("rpi-3b", "{vendor}-{variant}-3b"),
+			],
+		)?;
+		let err = check_artifacts(&artifacts, OverwritePolicy::Replace)
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("rpi-5b (base)"));
+		assert!(err.contains("rpi-4b (base)"));
+		assert!(!err.contains("rpi-3b"));
+		assert!(err.contains("Identical fields: variant, vendor, arch, compression"));
+		assert!(check_artifacts(&artifacts[1..], OverwritePolicy::Replace).is_ok());
+		// Existing images
+		let existing =
+			outdir.join("os-arm64/base/rawimg/raspberrypi/raspberrypi-base-3b.img");
+		assert_eq!(artifacts[2].path, existing);
+		std::fs::create_dir_all(existing.parent().unwrap())?;
+		std::fs::write(&existing, "")?;
+		assert!(check_artifacts(&artifacts[1..], OverwritePolicy::Replace).is_ok());
+		let err = check_artifacts(&artifacts[1..], OverwritePolicy::Error)
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("raspberrypi-base-3b.img already exists, produced by rpi-3b"));
+		Ok(())
+	}
+}
@@ -5,20 +5,88 @@
 //! - Run a script (within the same directory as the `device.toml` file)
 //! - Apply (“flash”) a file to the specific partition of the target image
 //! - Apply (“flash”) a file to the specific offset of the target image
+//! - Write a configuration file (e.g. `grub.cfg`) from a template
+//! - Generate `extlinux.conf`, see [`crate::extlinux`]
+//! - Compile a U-Boot boot script with `mkimage`, see [`crate::bootscr`]
+//! - Install GRUB to the EFI system partition, see [`crate::grub`]
+//! - Install systemd-boot to the EFI system partition, see [`crate::sdboot`]
+//! - Write a file to an offset of the image with overlap checks, see [`crate::raw`]
+//! - Write the SPL of the Allwinner SoCs, see [`crate::sunxi`]
 //!
 //! For details please go to [`BootloaderSpec`].
 //!
+//! The steps are applied in the order of declaration, unless they declare
+//! dependencies on other steps. See [`BootloaderStep`] for details.
+//!
 use std::{
+	collections::{BTreeMap, HashMap},
 	fs::File,
 	io::{copy, BufReader, Seek},
 	path::{Path, PathBuf},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use log::info;
 use serde::Deserialize;
 
-use crate::{context::ImageContext, utils::run_script_with_chroot};
+use crate::{
+	bootscr::UbootScriptSpec,
+	cmdline::substitute,
+	context::ImageContext,
+	device::PartitionMapData,
+	extlinux::ExtlinuxSpec,
+	fetch::BlobSource,
+	grub::GrubEfiSpec,
+	raw::RawSpec,
+	sdboot::SystemdBootSpec,
+	sunxi::SunxiSpec,
+	utils::{get_partition_path, run_script_with_chroot},
+};
+
+pub const STEP_VAR_PREFIX: &str = "{{step:";
+const STEP_VAR_SUFFIX: &str = ":output}}";
+
+/// A bootloader step, which is a [`BootloaderSpec`] with optional ordering information.
+///
+/// Steps are applied in the order of declaration by default. A step can be
+/// given an `id`, and other steps can declare that they must be applied
+/// `after` it. Steps not related to each other keep their declaration order.
+///
+/// A step can also declare the path of the file it produces as its `output`
+/// (within the target root filesystem). Later steps can refer to it with
+/// `{{step:<id>:output}}` in their `path`, which implies `after`.
+///
+/// Dependency cycles are reported by the `check` action.
+///
+/// ```toml
+/// [[bootloader]]
+/// type = "flash_partition"
+/// path = "{{step:fit:output}}"
+/// partition = 2
+///
+/// [[bootloader]]
+/// id = "fit"
+/// type = "script"
+/// name = "assemble-fit.sh"
+/// output = "/usr/lib/u-boot/u-boot.itb"
+///
+/// [[bootloader]]
+/// type = "script"
+/// name = "finish.sh"
+/// after = ["fit"]
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct BootloaderStep {
+	/// Unique ID of this step.
+	pub id: Option<String>,
+	/// IDs of the steps which must be applied before this one.
+	#[serde(default)]
+	pub after: Vec<String>,
+	/// Path to the file produced by this step, within the target root filesystem.
+	pub output: Option<PathBuf>,
+	#[serde(flatten)]
+	pub spec: BootloaderSpec,
+}
 
 /// Specifies how to apply a bootloader image (file) to the target image.
 ///
@@ -83,7 +151,86 @@ use crate::{context::ImageContext, utils::run_script_with_chroot};
 /// offset = 0x400
 /// ```
 ///
+/// ### Write a configuration file from a template
+///
+/// The template must present within the same directory of the device specification file.
+/// `{KERNEL_CMDLINE}` and the [placeholders] of the partitions in the template are substituted,
+/// shell and GRUB variables (`${var}`) are left as is.
+///
+/// ```toml
+/// [[bootloader]]
+/// type = config
+/// # Path to the template, within the same directory as the device.toml file.
+/// template = extlinux.conf.in
+/// # Path to the configuration file within the target root filesystem.
+/// path = "/boot/extlinux/extlinux.conf"
+/// ```
+///
+/// ### Generate `extlinux.conf`
+///
+/// The paths are filled in from the kernel installed in the target, see [`crate::extlinux`] for the fields.
+///
+/// ```toml
+/// [[bootloader]]
+/// type = extlinux
+/// append = "root=PARTUUID={ROOT_PARTUUID} rw console=ttyS2,1500000"
+/// ```
+///
+/// ### Compile a U-Boot boot script
+///
+/// The script is compiled with `mkimage` on the host, see [`crate::bootscr`] for the fields.
+///
+/// ```toml
+/// [[bootloader]]
+/// type = uboot_script
+/// source = boot.cmd
+/// path = "/boot/boot.scr"
+/// ```
+///
+/// ### Install GRUB to the EFI system partition
+///
+/// `grub-install` and `grub-mkconfig` are run inside the target, see [`crate::grub`] for the fields.
+///
+/// ```toml
+/// [[bootloader]]
+/// type = grub_efi
+/// removable = true
+/// ```
+///
+/// ### Install systemd-boot to the EFI system partition
+///
+/// An entry is written for every installed kernel, see [`crate::sdboot`] for the fields.
+///
+/// ```toml
+/// [[bootloader]]
+/// type = systemd_boot
+/// timeout = 3
+/// ```
+///
+/// ### Write a file to an offset of the image
+///
+/// Like `dd`, but the write range is verified against the partitions, see [`crate::raw`] for the fields.
+///
+/// ```toml
+/// [[bootloader]]
+/// type = raw
+/// file = u-boot-sunxi-with-spl.bin
+/// offset = "8KiB"
+/// ```
+///
+/// ### Write the SPL of the Allwinner SoCs
+///
+/// The offset is selected by the SoC, and the eGON checksum of the SPL is verified, see [`crate::sunxi`] for the fields.
+///
+/// ```toml
+/// [[bootloader]]
+/// type = sunxi
+/// file = u-boot-sunxi-with-spl.bin
+/// soc = h616
+/// ```
+///
 /// [Defined variables]: crate::device::DeviceSpec#available-defined-variables
+/// [placeholders]: crate::cmdline
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum BootloaderSpec {
@@ -103,6 +250,7 @@ pub enum BootloaderSpec {
 	/// Flash a bootloader image to the specific partition of the target image.
 	///
 	/// The path must be (or point to) a regular file within the target root filesystem.
+	/// The image can be downloaded instead with `source`, in place of `path`, see [`crate::fetch`].
 	///
 	/// ```toml
 	/// [[bootloader]]
@@ -112,15 +260,22 @@ pub enum BootloaderSpec {
 	/// # The index of the target partition
 	/// partition = 1
 	/// ```
-	FlashPartition { path: PathBuf, partition: u64 },
+	FlashPartition {
+		path: Option<PathBuf>,
+		partition: u64,
+		/// Where the image is downloaded from.
+		source: Option<BlobSource>,
+	},
 	/// Flash a bootloader image to the specific location of the target image.
 	///
 	/// The path must be (or point to) a regular file within the target root filesystem.
+	/// The image can be downloaded instead with `source`, in place of `path`, see [`crate::fetch`].
 	///
 	/// <div class="warning">
 	///
 	/// - Always make sure the image will not overlap existing partitions and filesystems.
-	/// - If your bootloader image is too large (e.g. exceeds 960KiB), you must adjust the starting position of the first partition (since the default starting sector is 2048 (1 MiB)).
+	/// - If your bootloader image is too large (e.g. exceeds 960KiB), you must enlarge the gap before the first partition with `first_partition_offset` (since the default starting sector is 2048 (1 MiB)).
+	/// - The offset must be within the gap before the first partition, which is verified by `check`, unless the step writes to a [reserved region].
 	/// - Therefore it is advised to create dedicated partitions reserved for bootloaders and flash them to their specific partition.
 	///
 	/// </div>
@@ -132,7 +287,92 @@ pub enum BootloaderSpec {
 	/// # Offset from the start of the target image in bytes.
 	/// offset = 0x400
 	/// ```
-	FlashOffset { path: PathBuf, offset: u64 },
+	///
+	/// With `reserved`, the file is written to the [reserved region] of the name, and the offset is relative to the start of the region:
+	///
+	/// ```toml
+	/// [[bootloader]]
+	/// type = flash_offset
+	/// path = "/usr/lib/u-boot/env.bin"
+	/// reserved = "env"
+	/// ```
+	///
+	/// [reserved region]: crate::reserved
+	FlashOffset {
+		path: Option<PathBuf>,
+		/// Where the image is downloaded from.
+		source: Option<BlobSource>,
+		/// Resolved to the offset from the start of the image when the specification is loaded.
+		offset: u64,
+		/// Name of the reserved region to write to.
+		reserved: Option<String>,
+	},
+	/// Write a configuration file of the bootloader from a template within the same directory as `device.toml`.
+	///
+	/// `{KERNEL_CMDLINE}` and the placeholders of the partitions in the template are substituted.
+	///
+	/// ```toml
+	/// [[bootloader]]
+	/// type = config
+	/// template = grub.cfg.in
+	/// path = "/boot/grub/grub.cfg"
+	/// ```
+	Config { template: String, path: PathBuf },
+	/// Generate `extlinux.conf` in the boot partition, see [`crate::extlinux`].
+	///
+	/// ```toml
+	/// [[bootloader]]
+	/// type = extlinux
+	/// fdtdir = "/boot/dtbs"
+	/// ```
+	Extlinux(ExtlinuxSpec),
+	/// Compile a U-Boot boot script with `mkimage`, and install it into the boot partition, see [`crate::bootscr`].
+	///
+	/// ```toml
+	/// [[bootloader]]
+	/// type = uboot_script
+	/// source = boot.cmd
+	/// path = "/boot/boot.scr"
+	/// ```
+	UbootScript(UbootScriptSpec),
+	/// Install GRUB to the EFI system partition and generate `grub.cfg`, see [`crate::grub`].
+	///
+	/// ```toml
+	/// [[bootloader]]
+	/// type = grub_efi
+	/// target = "arm64-efi"
+	/// esp = 1
+	/// ```
+	GrubEfi(GrubEfiSpec),
+	/// Install systemd-boot to the EFI system partition, and write an entry for every installed kernel, see [`crate::sdboot`].
+	///
+	/// ```toml
+	/// [[bootloader]]
+	/// type = systemd_boot
+	/// title = "AOSC OS"
+	/// entry_id = "aosc"
+	/// ```
+	SystemdBoot(SystemdBootSpec),
+	/// Write a file to an offset of the image, out of the partition table and the partitions, see [`crate::raw`].
+	///
+	/// ```toml
+	/// [[bootloader]]
+	/// type = raw
+	/// file = "/usr/lib/u-boot/rk64/idbloader.img"
+	/// from_target = true
+	/// offset = "32KiB"
+	/// ```
+	Raw(RawSpec),
+	/// Write `u-boot-sunxi-with-spl.bin` at the offset of the SoC, after verifying the SPL, see [`crate::sunxi`].
+	///
+	/// ```toml
+	/// [[bootloader]]
+	/// type = sunxi
+	/// file = "/usr/lib/u-boot/orangepi_zero3/u-boot-sunxi-with-spl.bin"
+	/// from_target = true
+	/// soc = h616
+	/// ```
+	Sunxi(SunxiSpec),
 }
 
 impl BootloaderSpec {
@@ -150,18 +390,14 @@ impl BootloaderSpec {
 		run_script_with_chroot(container, &Path::new("/tmp").join(filename), binds, None)
 	}
 
-	fn apply_offset<P, Q, R>(img: P, offset: u64, container: Q, loopdev: R) -> Result<()>
+	fn apply_offset<P, Q>(img: P, offset: u64, loopdev: Q) -> Result<()>
 	where
 		P: AsRef<Path>,
 		Q: AsRef<Path>,
-		R: AsRef<Path>,
 	{
 		let img = img.as_ref();
-		let container = container.as_ref();
 		let loopdev = loopdev.as_ref();
-		// Users want to specify absolute paths. However join()ing with an absolute path replaces the whole path.
-		let img_canon = container.join(img.to_string_lossy().trim_start_matches('/'));
-		let img_fd = File::options().read(true).create(false).open(&img_canon)?;
+		let img_fd = File::options().read(true).create(false).open(img)?;
 		let mut loop_dev_fd = File::options()
 			.write(true)
 			.truncate(false)
@@ -174,17 +410,14 @@ impl BootloaderSpec {
 		Ok(())
 	}
 
-	fn apply_to_partition<P, Q, R>(img: P, container: Q, partition: R) -> Result<()>
+	fn apply_to_partition<P, Q>(img: P, partition: Q) -> Result<()>
 	where
 		P: AsRef<Path>,
 		Q: AsRef<Path>,
-		R: AsRef<Path>,
 	{
 		let img = img.as_ref();
-		let container = container.as_ref();
 		let partition = partition.as_ref();
-		let img_canon = container.join(img.to_string_lossy().trim_start_matches('/'));
-		let img_fd = File::options().read(true).create(false).open(&img_canon)?;
+		let img_fd = File::options().read(true).create(false).open(img)?;
 		let mut partition_fd = File::options()
 			.write(true)
 			.truncate(false)
@@ -194,15 +427,115 @@ impl BootloaderSpec {
 		copy(&mut bufrdr, &mut partition_fd)?;
 		Ok(())
 	}
+
+	pub(crate) fn write_config<P, Q>(
+		template: P,
+		path: Q,
+		container: &Path,
+		vars: &BTreeMap<String, String>,
+	) -> Result<()>
+	where
+		P: AsRef<Path>,
+		Q: AsRef<Path>,
+	{
+		let template = template.as_ref();
+		let path = path.as_ref();
+		info!("Writing {} from {}", path.display(), template.display());
+		let content = std::fs::read_to_string(template).context(format!(
+			"Failed to read the template {}",
+			template.display()
+		))?;
+		let content = substitute(&content, vars)
+			.context(format!("Invalid template {}", template.display()))?;
+		let dst = container.join(path.to_string_lossy().trim_start_matches('/'));
+		if let Some(parent) = dst.parent() {
+			std::fs::create_dir_all(parent)?;
+		}
+		std::fs::write(&dst, content)
+			.context(format!("Failed to write {}", path.display()))?;
+		Ok(())
+	}
+}
+
+/// Name of the file of a `flash_partition` or `flash_offset` step for humans.
+pub(crate) fn flash_file_name(path: Option<&Path>, source: Option<&BlobSource>) -> String {
+	match (path, source) {
+		(Some(path), _) => path.display().to_string(),
+		(None, Some(source)) => format!("{} downloaded from {}", source.name(), source.url),
+		(None, None) => "(no file)".to_owned(),
+	}
+}
+
+/// Make sure a `flash_partition` or `flash_offset` step has either `path` or `source`.
+pub(crate) fn check_flash_file(
+	path: Option<&Path>,
+	source: Option<&BlobSource>,
+	steps: &[BootloaderStep],
+) -> Result<()> {
+	match (path, source) {
+		(Some(path), None) => resolve_step_vars(path, steps).map(|_| ()),
+		(None, Some(source)) => source.check().map(|_| ()),
+		(Some(_), Some(_)) => bail!("A bootloader file can not have both path and source"),
+		(None, None) => bail!("A bootloader file requires either path or source"),
+	}
+}
+
+impl BootloaderSpec {
+	/// Where the file of the step is downloaded from, if it is.
+	pub(crate) fn blob_source(&self) -> Option<&BlobSource> {
+		match self {
+			BootloaderSpec::FlashPartition { source, .. }
+			| BootloaderSpec::FlashOffset { source, .. } => source.as_ref(),
+			BootloaderSpec::Raw(spec) => spec.input.source.as_ref(),
+			BootloaderSpec::Sunxi(spec) => spec.input.source.as_ref(),
+			BootloaderSpec::Script { .. }
+			| BootloaderSpec::Config { .. }
+			| BootloaderSpec::Extlinux(_)
+			| BootloaderSpec::UbootScript(_)
+			| BootloaderSpec::GrubEfi(_)
+			| BootloaderSpec::SystemdBoot(_) => None,
+		}
+	}
 }
 
 impl ImageContext<'_> {
+	/// Path to the file of a `flash_partition` or `flash_offset` step on the host, downloads it if it is from a `source`.
+	pub(crate) fn flash_file_path(
+		&self,
+		path: Option<&Path>,
+		source: Option<&BlobSource>,
+		root: &Path,
+		steps: &[BootloaderStep],
+	) -> Result<PathBuf> {
+		match (path, source) {
+			(_, Some(source)) => self.fetch_blob(source),
+			(Some(path), None) => {
+				let path = resolve_step_vars(path, steps)?;
+				// Users want to specify absolute paths. However join()ing with an absolute path replaces the whole path.
+				Ok(root.join(path.to_string_lossy().trim_start_matches('/')))
+			}
+			(None, None) => bail!("A bootloader file requires either path or source"),
+		}
+	}
+
+	/// Download the files of the bootloader steps, so a build does not fail
+	/// in the bootloader stage because of a broken link.
+	pub(crate) fn fetch_bootloader_blobs(&self) -> Result<()> {
+		for step in self.device.bootloaders.iter().flatten() {
+			if let Some(source) = step.spec.blob_source() {
+				self.fetch_blob(source)?;
+			}
+		}
+		Ok(())
+	}
+
 	#[allow(unused_variables)]
 	pub fn apply_bootloaders<P: AsRef<Path>>(
 		&self,
 		rootfs: P,
 		loopdev: P,
 		binds: &[&str],
+		pm_data: &PartitionMapData,
 	) -> Result<()> {
 		if self.device.bootloaders.is_none() {
 			return Ok(());
@@ -215,8 +548,16 @@ impl ImageContext<'_> {
 			self.device.file_path.parent().context(
 				"Failed to reach the directory containing the device spec file",
 			)?;
-		for bl in *bl_list {
-			match bl {
+		let steps = sort_steps(bl_list)?;
+		self.info(format!(
+			"Order of the bootloader steps: {}",
+			steps.iter()
+				.map(|(i, s)| s.name(*i))
+				.collect::<Vec<_>>()
+				.join(" -> ")
+		));
+		for (_, step) in steps {
+			match &step.spec {
 				BootloaderSpec::Script { name } => {
 					BootloaderSpec::run_script(
 						rootfs,
@@ -224,21 +565,92 @@ impl ImageContext<'_> {
 						binds,
 					)?;
 				}
-				BootloaderSpec::FlashPartition { path, partition } => {
-					let partition = format!(
-						"{}p{}",
-						&loopdev.to_string_lossy(),
-						partition
-					);
-					BootloaderSpec::apply_to_partition(
-						path.as_path(),
+				BootloaderSpec::FlashPartition {
+					path,
+					partition,
+					source,
+				} => {
+					let partition =
+						get_partition_path(loopdev, *partition as u32);
+					let img = self.flash_file_path(
+						path.as_deref(),
+						source.as_ref(),
 						rootfs,
+						bl_list,
+					)?;
+					BootloaderSpec::apply_to_partition(
+						img,
 						Path::new(&partition),
 					)?;
 				}
-				BootloaderSpec::FlashOffset { path, offset } => {
-					BootloaderSpec::apply_offset(
-						path, *offset, rootfs, loopdev,
+				BootloaderSpec::FlashOffset {
+					path,
+					source,
+					offset,
+					reserved,
+				} => {
+					let name =
+						flash_file_name(path.as_deref(), source.as_ref());
+					let img = self.flash_file_path(
+						path.as_deref(),
+						source.as_ref(),
+						rootfs,
+						bl_list,
+					)?;
+					let len = img
+						.metadata()
+						.context(format!("Unable to find {}", name))?
+						.len();
+					self.device
+						.check_reserved_write(
+							*offset,
+							len,
+							reserved.as_deref(),
+						)
+						.context(format!("Unable to flash {}", name))?;
+					BootloaderSpec::apply_offset(img, *offset, loopdev)?;
+				}
+				BootloaderSpec::Config { template, path } => {
+					BootloaderSpec::write_config(
+						device_spec_dir.join(template),
+						path,
+						rootfs,
+						&self.device.template_vars(Some(pm_data))?,
+					)?;
+				}
+				BootloaderSpec::Extlinux(spec) => {
+					self.write_extlinux(spec, rootfs, pm_data)?;
+				}
+				BootloaderSpec::UbootScript(spec) => {
+					self.compile_uboot_script(
+						device_spec_dir,
+						spec,
+						rootfs,
+						pm_data,
+					)?;
+				}
+				BootloaderSpec::GrubEfi(spec) => {
+					self.install_grub_efi(spec, rootfs, binds, pm_data)?;
+				}
+				BootloaderSpec::SystemdBoot(spec) => {
+					self.install_systemd_boot(spec, rootfs, pm_data)?;
+				}
+				BootloaderSpec::Raw(spec) => {
+					self.write_raw(
+						device_spec_dir,
+						spec,
+						rootfs,
+						loopdev,
+						bl_list,
+					)?;
+				}
+				BootloaderSpec::Sunxi(spec) => {
+					self.write_sunxi(
+						device_spec_dir,
+						spec,
+						rootfs,
+						loopdev,
+						bl_list,
 					)?;
 				}
 			}
@@ -246,3 +658,228 @@ impl ImageContext<'_> {
 		Ok(())
 	}
 }
+
+impl BootloaderStep {
+	/// Name of the step for humans: its ID, or its position in the list.
+	pub fn name(&self, idx: usize) -> String {
+		match &self.id {
+			Some(id) => id.to_owned(),
+			None => format!("#{}", idx + 1),
+		}
+	}
+
+	/// IDs of the steps whose outputs are referenced by this step.
+	fn referenced_steps(&self) -> Vec<&str> {
+		let path = match &self.spec {
+			BootloaderSpec::FlashPartition { path, .. }
+			| BootloaderSpec::FlashOffset { path, .. } => path.as_deref(),
+			BootloaderSpec::Raw(spec) => spec.input.target_path(),
+			BootloaderSpec::Sunxi(spec) => spec.input.target_path(),
+			BootloaderSpec::Script { .. }
+			| BootloaderSpec::Config { .. }
+			| BootloaderSpec::Extlinux(_)
+			| BootloaderSpec::UbootScript(_)
+			| BootloaderSpec::GrubEfi(_)
+			| BootloaderSpec::SystemdBoot(_) => None,
+		};
+		let mut refs = Vec::new();
+		let mut rest = path.and_then(Path::to_str).unwrap_or_default();
+		while let Some(start) = rest.find(STEP_VAR_PREFIX) {
+			rest = &rest[start + STEP_VAR_PREFIX.len()..];
+			if let Some(end) = rest.find(STEP_VAR_SUFFIX) {
+				refs.push(&rest[..end]);
+				rest = &rest[end + STEP_VAR_SUFFIX.len()..];
+			}
+		}
+		refs
+	}
+}
+
+/// Sort the steps by their dependencies, returns the steps with their
+/// indices in the declaration order.
+///
+/// Steps not depending on each other keep their declaration order.
+pub fn sort_steps(steps: &[BootloaderStep]) -> Result<Vec<(usize, &BootloaderStep)>> {
+	let mut ids = HashMap::new();
+	for (idx, step) in steps.iter().enumerate() {
+		if let Some(id) = &step.id {
+			if ids.insert(id.as_str(), idx).is_some() {
+				bail!("Duplicate bootloader step ID '{}'", id);
+			}
+		}
+	}
+	let mut deps = Vec::with_capacity(steps.len());
+	for (idx, step) in steps.iter().enumerate() {
+		let mut step_deps = Vec::new();
+		for dep in step
+			.after
+			.iter()
+			.map(String::as_str)
+			.chain(step.referenced_steps())
+		{
+			let dep_idx = ids.get(dep).context(format!(
+				"Bootloader step {} depends on an unknown step '{}'",
+				step.name(idx),
+				dep
+			))?;
+			step_deps.push(*dep_idx);
+		}
+		deps.push(step_deps);
+	}
+	let mut done = vec![false; steps.len()];
+	let mut sorted = Vec::with_capacity(steps.len());
+	while sorted.len() < steps.len() {
+		// Always pick the first ready step to keep the declaration order.
+		let next = (0..steps.len()).find(|&i| !done[i] && deps[i].iter().all(|&d| done[d]));
+		if let Some(idx) = next {
+			done[idx] = true;
+			sorted.push((idx, &steps[idx]));
+		} else {
+			let cycle = (0..steps.len())
+				.filter(|&i| !done[i])
+				.map(|i| steps[i].name(i))
+				.collect::<Vec<_>>();
+			bail!(
+				"Dependency cycle detected among bootloader steps: {}",
+				cycle.join(", ")
+			);
+		}
+	}
+	Ok(sorted)
+}
+
+/// Replace `{{step:<id>:output}}` in the path with the output of the step.
+pub fn resolve_step_vars(path: &Path, steps: &[BootloaderStep]) -> Result<PathBuf> {
+	let mut path = path.to_string_lossy().to_string();
+	while let Some(start) = path.find(STEP_VAR_PREFIX) {
+		let rest = &path[start + STEP_VAR_PREFIX.len()..];
+		let end = rest
+			.find(STEP_VAR_SUFFIX)
+			.context(format!("Unterminated step variable in '{}'", path))?;
+		let id = &rest[..end];
+		let step = steps
+			.iter()
+			.find(|s| s.id.as_deref() == Some(id))
+			.context(format!("Bootloader step '{}' is not found", id))?;
+		let output = step.output.as_ref().context(format!(
+			"Bootloader step '{}' does not declare an output",
+			id
+		))?;
+		let var_end = start + STEP_VAR_PREFIX.len() + end + STEP_VAR_SUFFIX.len();
+		path.replace_range(start..var_end, &output.to_string_lossy());
+	}
+	Ok(PathBuf::from(path))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn step(id: Option<&str>, after: &[&str], spec: BootloaderSpec) -> BootloaderStep {
+		BootloaderStep {
+			id: id.map(str::to_owned),
+			after: after.iter().map(|x| x.to_string()).collect(),
+			output: None,
+			spec,
+		}
+	}
+
+	fn script(name: &str) -> BootloaderSpec {
+		BootloaderSpec::Script {
+			name: name.to_owned(),
+		}
+	}
+
+	#[test]
+	fn test_sort_steps() -> Result<()> {
+		let mut fit = step(Some("fit"), &["spl"], script("fit.sh"));
+		fit.output = Some(PathBuf::from("/usr/lib/u-boot/u-boot.itb"));
+		let steps = vec![
+			step(
+				None,
+				&[],
+				BootloaderSpec::FlashPartition {
+					path: Some(PathBuf::from("{{step:fit:output}}")),
+					partition: 2,
+					source: None,
+				},
+			),
+			step(Some("a"), &[], script("a.sh")),
+			fit,
+			step(Some("spl"), &[], script("spl.sh")),
+			step(None, &["a"], script("b.sh")),
+		];
+		let order = sort_steps(&steps)?
+			.iter()
+			.map(|(i, s)| s.name(*i))
+			.collect::<Vec<_>>();
+		assert_eq!(order, ["a", "spl", "fit", "#1", "#5"]);
+		Ok(())
+	}
+
+	#[test]
+	fn test_sort_steps_cycle() {
+		let steps = vec![
+			step(Some("a"), &["c"], script("a.sh")),
+			step(Some("b"), &["a"], script("b.sh")),
+			step(Some("c"), &["b"], script("c.sh")),
+			step(Some("d"), &[], script("d.sh")),
+		];
+		let err = sort_steps(&steps).unwrap_err().to_string();
+		assert!(err.contains("a, b, c"));
+		let steps = vec![step(Some("a"), &["a"], script("a.sh"))];
+		assert!(sort_steps(&steps).is_err());
+		let steps = vec![step(None, &["nonexistent"], script("a.sh"))];
+		assert!(sort_steps(&steps).is_err());
+	}
+
+	#[test]
+	fn test_resolve_step_vars() -> Result<()> {
+		let mut fit = step(Some("fit"), &[], script("fit.sh"));
+		fit.output = Some(PathBuf::from("/usr/lib/u-boot/u-boot.itb"));
+		let steps = vec![fit, step(Some("none"), &[], script("none.sh"))];
+		assert_eq!(
+			resolve_step_vars(Path::new("{{step:fit:output}}"), &steps)?,
+			Path::new("/usr/lib/u-boot/u-boot.itb")
+		);
+		assert_eq!(
+			resolve_step_vars(Path::new("/usr/lib/idbloader.img"), &steps)?,
+			Path::new("/usr/lib/idbloader.img")
+		);
+		assert!(resolve_step_vars(Path::new("{{step:none:output}}"), &steps).is_err());
+		assert!(resolve_step_vars(Path::new("{{step:what:output}}"), &steps).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_flash_file() -> Result<()> {
+		let steps = vec![step(Some("none"), &[], script("none.sh"))];
+		let source = BlobSource {
+			url: "https://example.org/fip/u-boot.bin.sd.bin".to_owned(),
+			sha256: Some("ab".repeat(32)),
+		};
+		let path = Path::new("/usr/lib/u-boot/u-boot.bin");
+		check_flash_file(Some(path), None, &steps)?;
+		check_flash_file(None, Some(&source), &steps)?;
+		assert!(check_flash_file(Some(path), Some(&source), &steps).is_err());
+		assert!(check_flash_file(None, None, &steps).is_err());
+		assert!(
+			check_flash_file(Some(Path::new("{{step:none:output}}")), None, &steps)
+				.is_err()
+		);
+		let unverified = BlobSource {
+			sha256: None,
+			..source
+		};
+		assert!(check_flash_file(None, Some(&unverified), &steps).is_err());
+		let spec = BootloaderSpec::FlashOffset {
+			path: None,
+			source: Some(unverified.clone()),
+			offset: 512,
+			reserved: None,
+		};
+		assert_eq!(spec.blob_source(), Some(&unverified));
+		assert_eq!(script("a.sh").blob_source(), None);
+		Ok(())
+	}
+}
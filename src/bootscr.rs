@@ -0,0 +1,281 @@
+//! Compiling the U-Boot boot scripts.
+//!
+//! U-Boot runs `boot.scr`, a script wrapped in a legacy image header, which
+//! is compiled from its text with `mkimage -C none -A <arch> -T script`. A
+//! `uboot_script` bootloader step does it on the host, so `mkimage` must be
+//! installed on the host instead of in the target:
+//!
+//! ```toml
+//! [[bootloader]]
+//! type = "uboot_script"
+//! # The script within the same directory as the device.toml file.
+//! source = "boot.cmd"
+//! # Where the compiled script is installed, within the target.
+//! path = "/boot/boot.scr"
+//! # Optional, the build fails if the compiled script is larger.
+//! max_size = "16KiB"
+//! ```
+//!
+//! The script can be inline as well:
+//!
+//! ```toml
+//! [[bootloader]]
+//! type = "uboot_script"
+//! script = """
+//! setenv bootargs "{KERNEL_CMDLINE}"
+//! load ${devtype} ${devnum}:${distro_bootpart} ${kernel_addr_r} /Image
+//! booti ${kernel_addr_r} - ${fdtcontroladdr}
+//! """
+//! path = "/boot/boot.scr"
+//! ```
+//!
+//! `{KERNEL_CMDLINE}` and the [placeholders] of the partitions are
+//! substituted, U-Boot variables (`${var}`) are left as is.
+//!
+//! The script is installed into the boot partition, i.e. the partition with
+//! `usage = "boot"`, or the root partition if there is no boot partition.
+//! `max_size` is a positive integer in bytes or a size with a unit, for the
+//! boards reading the script from a tiny boot area.
+//!
+//! [placeholders]: crate::cmdline
+use std::{
+	fs,
+	path::{Path, PathBuf},
+	process::Command,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+	cmdline::substitute,
+	context::ImageContext,
+	device::{DeviceArch, DeviceSpec, PartitionMapData},
+	partition::PartitionUsage,
+	size::{format_size, ByteSize},
+	utils::cmd_run_check_status,
+};
+
+/// Compile a U-Boot boot script, see the [module documentation](self).
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct UbootScriptSpec {
+	/// Path to the script, within the same directory as the device.toml file.
+	pub source: Option<String>,
+	/// The script itself.
+	pub script: Option<String>,
+	/// Path to the compiled script within the target.
+	pub path: PathBuf,
+	/// Maximum size of the compiled script.
+	pub max_size: Option<ByteSize>,
+}
+
+/// The architecture of the device as `mkimage -A` names it.
+fn mkimage_arch(arch: &DeviceArch) -> Option<&'static str> {
+	match arch {
+		DeviceArch::Amd64 => Some("x86_64"),
+		DeviceArch::I486 => Some("x86"),
+		DeviceArch::Arm64 => Some("arm64"),
+		DeviceArch::Armv7hf | DeviceArch::Armv6hf => Some("arm"),
+		DeviceArch::Riscv64 => Some("riscv"),
+		DeviceArch::Ppc64el | DeviceArch::Ppc64 => Some("powerpc"),
+		DeviceArch::Loongson3 | DeviceArch::Mips64r6el => Some("mips64"),
+		DeviceArch::LoongArch64 => None,
+	}
+}
+
+/// The command compiling the script at `src` into `dst`.
+fn mkimage_cmd(arch: &str, src: &Path, dst: &Path) -> Command {
+	let mut cmd = Command::new("mkimage");
+	cmd.args(["-C", "none", "-A", arch, "-T", "script", "-d"])
+		.arg(src)
+		.arg(dst);
+	cmd
+}
+
+impl DeviceSpec {
+	/// Mountpoint of the partition the boot scripts are installed into.
+	fn boot_script_mountpoint(&self) -> Option<&str> {
+		self.partitions
+			.iter()
+			.find(|p| p.usage == PartitionUsage::Boot && p.mountpoint.is_some())
+			.or_else(|| {
+				self.partitions
+					.iter()
+					.find(|p| p.usage == PartitionUsage::Rootfs)
+			})
+			.and_then(|p| p.mountpoint.as_deref())
+	}
+
+	/// Make sure the boot script can be compiled, see the [module documentation](crate::bootscr).
+	pub(crate) fn check_uboot_script(
+		&self,
+		dirname: &Path,
+		spec: &UbootScriptSpec,
+	) -> Result<()> {
+		if mkimage_arch(&self.arch).is_none() {
+			bail!("U-Boot scripts are not available for {:?}", self.arch);
+		}
+		let content = match (&spec.source, &spec.script) {
+			(Some(source), None) => {
+				let source_path = dirname.join(source);
+				if !source_path.is_file() {
+					bail!("Script '{}' not found within the same directory as the device.toml", source);
+				}
+				fs::read_to_string(&source_path).context(format!(
+					"Failed to read the script '{}'",
+					source
+				))?
+			}
+			(None, Some(script)) => script.to_owned(),
+			_ => bail!("A U-Boot script must have either source or script"),
+		};
+		substitute(&content, &self.template_vars(None)?)
+			.context("Invalid U-Boot script")?;
+		if spec.max_size == Some(ByteSize(0)) {
+			bail!("max_size of the U-Boot script can not be zero");
+		}
+		let mountpoint = self.boot_script_mountpoint().context(
+			"U-Boot scripts require a boot or a root partition with a mountpoint",
+		)?;
+		if !spec.path.is_absolute() || !spec.path.starts_with(mountpoint) {
+			bail!(
+				"The U-Boot script '{}' must be installed into the boot partition mounted at {}",
+				spec.path.display(),
+				mountpoint
+			);
+		}
+		Ok(())
+	}
+}
+
+impl ImageContext<'_> {
+	/// Compile the boot script and install it into the target, see the [module documentation](self).
+	pub(crate) fn compile_uboot_script(
+		&self,
+		dirname: &Path,
+		spec: &UbootScriptSpec,
+		root: &Path,
+		pm_data: &PartitionMapData,
+	) -> Result<()> {
+		let content = match (&spec.source, &spec.script) {
+			(Some(source), _) => fs::read_to_string(dirname.join(source))
+				.context(format!("Failed to read the script '{}'", source))?,
+			(None, Some(script)) => script.to_owned(),
+			(None, None) => bail!("A U-Boot script must have either source or script"),
+		};
+		let content = substitute(&content, &self.device.template_vars(Some(pm_data))?)
+			.context("Invalid U-Boot script")?;
+		let arch = mkimage_arch(&self.device.arch).context(format!(
+			"U-Boot scripts are not available for {:?}",
+			self.device.arch
+		))?;
+		self.info(format!(
+			"Compiling the U-Boot script {} ...",
+			spec.path.display()
+		));
+		let src = self.sketch_dir().join("boot.cmd");
+		let compiled = self.sketch_dir().join("boot.scr");
+		fs::write(&src, content)?;
+		let result = cmd_run_check_status(&mut mkimage_cmd(arch, &src, &compiled))
+			.context(format!(
+				"Failed to compile the U-Boot script {}",
+				spec.path.display()
+			))
+			.and_then(|_| {
+				let size = compiled.metadata()?.len();
+				match spec.max_size {
+					Some(ByteSize(max)) if size > max => bail!(
+						"The U-Boot script {} is {} bytes, it exceeds the limit of {}",
+						spec.path.display(),
+						size,
+						format_size(max)
+					),
+					_ => Ok(()),
+				}
+			})
+			.and_then(|_| {
+				let dst = root.join(spec.path.strip_prefix("/")?);
+				if let Some(parent) = dst.parent() {
+					fs::create_dir_all(parent)?;
+				}
+				fs::copy(&compiled, &dst).context(format!(
+					"Failed to install {}",
+					spec.path.display()
+				))?;
+				Ok(())
+			});
+		fs::remove_file(&src)?;
+		if compiled.exists() {
+			fs::remove_file(&compiled)?;
+		}
+		result
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		bootloader::{BootloaderSpec, BootloaderStep},
+		testutil::{load_device, TempDir},
+	};
+
+	const ROOT: &str = r#"partition_map = "gpt"
+
+[[partition]]
+num = 1
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+"#;
+
+	#[test]
+	fn test_mkimage_cmd() {
+		let cmd = mkimage_cmd(
+			mkimage_arch(&DeviceArch::Armv7hf).unwrap(),
+			Path::new("boot.cmd"),
+			Path::new("boot.scr"),
+		);
+		let args = std::iter::once(cmd.get_program())
+			.chain(cmd.get_args())
+			.map(|a| a.to_string_lossy().into_owned())
+			.collect::<Vec<_>>();
+		assert_eq!(
+			args,
+			[
+				"mkimage", "-C", "none", "-A", "arm", "-T", "script", "-d",
+				"boot.cmd", "boot.scr"
+			]
+		);
+		assert_eq!(mkimage_arch(&DeviceArch::Arm64), Some("arm64"));
+	}
+
+	#[test]
+	fn test_inline_script() -> Result<()> {
+		let dir = TempDir::new("bootscr")?;
+		// The example of the module documentation.
+		let script = "setenv bootargs \"{KERNEL_CMDLINE}\"\n\
+			load ${devtype} ${devnum}:${distro_bootpart} ${kernel_addr_r} /Image\n\
+			booti ${kernel_addr_r} - ${fdtcontroladdr}\n";
+		let device = load_device(
+			&dir,
+			&format!(
+				"{}\n[[bootloader]]\ntype = \"uboot_script\"\nscript = \"\"\"\n{}\"\"\"\npath = \"/boot/boot.scr\"\n",
+				ROOT, script
+			),
+		)?;
+		let steps = device.bootloaders.unwrap_or_default();
+		let [BootloaderStep {
+			spec: BootloaderSpec::UbootScript(spec),
+			..
+		}] = steps.as_slice()
+		else {
+			panic!("Expected a uboot_script step, got {:?}", steps);
+		};
+		assert_eq!(spec.script.as_deref(), Some(script));
+		assert_eq!(spec.path, Path::new("/boot/boot.scr"));
+		Ok(())
+	}
+}
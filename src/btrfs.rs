@@ -0,0 +1,499 @@
+//! Subvolumes of the btrfs partitions.
+//!
+//! A btrfs partition can declare its subvolumes, each mounted at its own
+//! mountpoint with its own mount options:
+//!
+//! ```toml
+//! [[partition]]
+//! num = 2
+//! type = "linux"
+//! usage = "rootfs"
+//! filesystem = "btrfs"
+//! mountpoint = "/"
+//! size = "rest"
+//! mount_opts = ["compress=zstd:3"]
+//! subvolumes = [
+//!     { name = "@", mountpoint = "/" },
+//!     { name = "@home", mountpoint = "/home", mount_opts = ["compress=zstd:1"] },
+//!     { name = "@snapshots", mountpoint = "/.snapshots" },
+//! ]
+//! # Optional, mounted if no subvol= option is given.
+//! default_subvolume = "@"
+//! # Optional, false by default.
+//! install_snapshot = true
+//! ```
+//!
+//! - The subvolumes are created in the top-level volume right after the
+//!   filesystem is created, and `default_subvolume` is set as the default
+//!   with `btrfs subvolume set-default`.
+//! - The subvolume with the mountpoint of the partition is mounted there, the
+//!   root filesystem is thus installed into it. Without one, the top-level
+//!   volume is mounted.
+//! - The kernel mounts the default subvolume of the root partition, so the
+//!   subvolume the root filesystem is installed into must be the
+//!   `default_subvolume`, or be passed with `rootflags=subvol=<name>` in
+//!   `kernel_cmdline`.
+//! - The other subvolumes with a mountpoint are mounted at it while the image
+//!   is built, and in the target by `/etc/fstab` with the `subvol=` option.
+//!   The ones without a mountpoint are only created.
+//! - The mount options of a subvolume default to the ones of the partition.
+//!   They apply while the image is built as well, so the installed files are
+//!   compressed with `compress=zstd:N`.
+//!
+//! With `install_snapshot = true`, a read-only snapshot of the subvolume at
+//! the mountpoint of the partition is taken as `@snapshots/install` once the
+//! bootloaders are applied, the pristine installation to roll back to. The
+//! `@snapshots` subvolume must be declared.
+use std::{
+	fs::{create_dir_all, remove_dir},
+	path::Path,
+	process::Command,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sys_mount::{unmount, Mount, UnmountFlags};
+
+use crate::{
+	context::ImageContext,
+	device::DeviceSpec,
+	filesystem::FilesystemType,
+	partition::{PartitionSpec, PartitionUsage},
+	utils::cmd_run_check_status,
+};
+
+/// Subvolume holding the snapshots, required by `install_snapshot`.
+pub const SNAPSHOTS_SUBVOLUME: &str = "@snapshots";
+/// Name of the snapshot of the pristine installation, within [`SNAPSHOTS_SUBVOLUME`].
+pub const INSTALL_SNAPSHOT: &str = "install";
+
+/// A subvolume of a btrfs partition, see the [module documentation](self).
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BtrfsSubvolume {
+	/// Name of the subvolume in the top-level volume, e.g. `@home`.
+	pub name: String,
+	/// Where the subvolume is mounted in the target.
+	pub mountpoint: Option<String>,
+	/// Mount options of the subvolume, the ones of the partition if not specified.
+	pub mount_opts: Option<Vec<String>>,
+}
+
+impl BtrfsSubvolume {
+	/// Mount options of the subvolume, with the `subvol=` option. `partition_opts` are the ones of the partition.
+	pub fn mount_opts_with(&self, partition_opts: Option<&Vec<String>>) -> Vec<String> {
+		let mut opts = self
+			.mount_opts
+			.as_ref()
+			.or(partition_opts)
+			.cloned()
+			.unwrap_or_else(|| vec!["defaults".to_owned()]);
+		opts.push(format!("subvol={}", self.name));
+		opts
+	}
+}
+
+impl PartitionSpec {
+	/// The subvolume mounted at the mountpoint of the partition.
+	pub fn main_subvolume(&self) -> Option<&BtrfsSubvolume> {
+		let mountpoint = self.mountpoint.as_ref()?;
+		self.subvolumes
+			.iter()
+			.find(|s| s.mountpoint.as_ref() == Some(mountpoint))
+	}
+
+	/// The other subvolumes mounted in the target, with their mountpoints.
+	pub fn mounted_subvolumes(&self) -> impl Iterator<Item = (&BtrfsSubvolume, &str)> {
+		self.subvolumes.iter().filter_map(|s| match &s.mountpoint {
+			Some(mp) if Some(mp) != self.mountpoint.as_ref() => Some((s, mp.as_str())),
+			_ => None,
+		})
+	}
+}
+
+impl DeviceSpec {
+	/// Whether the kernel command line mounts the subvolume `name` as the root filesystem.
+	fn passes_root_subvolume(&self, name: &str) -> bool {
+		let Some(cmdline) = &self.kernel_cmdline else {
+			return false;
+		};
+		// The last one wins.
+		cmdline.0
+			.iter()
+			.rev()
+			.find_map(|arg| arg.strip_prefix("rootflags="))
+			.is_some_and(|flags| {
+				flags.split(',').any(|o| {
+					o.strip_prefix("subvol=")
+						.is_some_and(|s| s.trim_start_matches('/') == name)
+				})
+			})
+	}
+
+	/// Make sure the subvolumes of the btrfs partitions are usable, see the [module documentation](self).
+	pub(crate) fn check_btrfs(&self) -> Result<()> {
+		for partition in &self.partitions {
+			let num = partition.num;
+			let declared = !partition.subvolumes.is_empty()
+				|| partition.default_subvolume.is_some()
+				|| partition.install_snapshot;
+			if !declared {
+				continue;
+			}
+			if partition.filesystem != FilesystemType::Btrfs {
+				bail!("Partition {} is not btrfs, it can not have subvolumes", num);
+			}
+			for (idx, subvol) in partition.subvolumes.iter().enumerate() {
+				let name = &subvol.name;
+				if name.is_empty()
+					|| name.contains('/') || name.contains(',')
+					|| name == "." || name == ".."
+				{
+					bail!(
+						"Partition {}: invalid subvolume name '{}', it must not be empty or contain '/' or ','",
+						num,
+						name
+					);
+				}
+				if partition.subvolumes[..idx].iter().any(|s| &s.name == name) {
+					bail!(
+						"Partition {}: subvolume {} is declared more than once",
+						num,
+						name
+					);
+				}
+				let Some(mp) = &subvol.mountpoint else {
+					continue;
+				};
+				if partition.mountpoint.is_none() {
+					bail!(
+						"Partition {}: subvolume {} has a mountpoint, the partition must have one as well",
+						num,
+						name
+					);
+				}
+				if !mp.starts_with('/') {
+					bail!(
+						"Partition {}: mountpoint {} of subvolume {} must be an absolute path",
+						num,
+						mp,
+						name
+					);
+				}
+				let taken = partition.subvolumes[..idx]
+					.iter()
+					.any(|s| s.mountpoint.as_ref() == Some(mp))
+					|| self.partitions.iter().any(|p| {
+						p.num != num && p.mountpoint.as_ref() == Some(mp)
+					});
+				if taken {
+					bail!(
+						"Partition {}: mountpoint {} of subvolume {} is already used",
+						num,
+						mp,
+						name
+					);
+				}
+			}
+			if let Some(default) = &partition.default_subvolume {
+				if !partition.subvolumes.iter().any(|s| &s.name == default) {
+					bail!(
+						"Partition {}: default subvolume {} is not declared",
+						num,
+						default
+					);
+				}
+			}
+			if let Some(main) = partition.main_subvolume() {
+				let is_default =
+					partition.default_subvolume.as_ref() == Some(&main.name);
+				if partition.usage == PartitionUsage::Rootfs
+					&& !is_default && !self.passes_root_subvolume(&main.name)
+				{
+					bail!(
+						"Partition {}: the root filesystem is installed into subvolume {}, but the kernel mounts the default subvolume. Set default_subvolume = \"{}\", or add rootflags=subvol={} to kernel_cmdline",
+						num,
+						main.name,
+						main.name,
+						main.name
+					);
+				}
+			}
+			if partition.install_snapshot {
+				if partition.main_subvolume().is_none() {
+					bail!("Partition {}: install_snapshot requires a subvolume mounted at the mountpoint of the partition", num);
+				}
+				if !partition
+					.subvolumes
+					.iter()
+					.any(|s| s.name == SNAPSHOTS_SUBVOLUME)
+				{
+					bail!(
+						"Partition {}: install_snapshot requires the {} subvolume",
+						num,
+						SNAPSHOTS_SUBVOLUME
+					);
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+impl ImageContext<'_> {
+	/// The subvolumes of the partition, none if it is not btrfs after `--fstype`.
+	pub(crate) fn subvolumes<'p>(&self, partition: &'p PartitionSpec) -> &'p [BtrfsSubvolume] {
+		if self.effective_fstype(partition) == FilesystemType::Btrfs {
+			&partition.subvolumes
+		} else {
+			&[]
+		}
+	}
+
+	/// Mount options of the subvolume while the image is built, `defaults` is left out.
+	pub(crate) fn subvolume_mount_data(
+		&self,
+		partition: &PartitionSpec,
+		subvol: &BtrfsSubvolume,
+	) -> String {
+		subvol.mount_opts_with(partition.mount_opts.as_ref())
+			.into_iter()
+			.filter(|o| o != "defaults")
+			.collect::<Vec<_>>()
+			.join(",")
+	}
+
+	/// The subvolume mounted at the mountpoint of the partition, if it is btrfs after `--fstype`.
+	pub(crate) fn main_subvolume<'p>(
+		&self,
+		partition: &'p PartitionSpec,
+	) -> Option<&'p BtrfsSubvolume> {
+		if self.subvolumes(partition).is_empty() {
+			return None;
+		}
+		partition.main_subvolume()
+	}
+
+	/// The other subvolumes mounted in the target, if the partition is btrfs after `--fstype`.
+	pub(crate) fn mounted_subvolumes<'p>(
+		&self,
+		partition: &'p PartitionSpec,
+	) -> Vec<(&'p BtrfsSubvolume, &'p str)> {
+		if self.subvolumes(partition).is_empty() {
+			return Vec::new();
+		}
+		partition.mounted_subvolumes().collect()
+	}
+
+	/// Mount the top-level volume of the btrfs filesystem on `dev` temporarily, and run `f` with its mountpoint.
+	fn with_top_level<T>(
+		&self,
+		dev: &Path,
+		num: u32,
+		f: impl FnOnce(&Path) -> Result<T>,
+	) -> Result<T> {
+		let mnt = self
+			.sketch_dir()
+			.join("mnt")
+			.join(format!("btrfs-p{}", num));
+		create_dir_all(&mnt)?;
+		Mount::builder()
+			.fstype("btrfs")
+			.data("subvolid=5")
+			.mount(dev, &mnt)
+			.context(format!(
+				"Failed to mount the top-level volume of partition {}",
+				num
+			))?;
+		let result = f(&mnt);
+		unmount(&mnt, UnmountFlags::empty())?;
+		remove_dir(&mnt)?;
+		result
+	}
+
+	/// Create the subvolumes on the freshly created btrfs filesystem on `dev`, and set the default one.
+	pub(crate) fn create_subvolumes(
+		&self,
+		partition: &PartitionSpec,
+		dev: &Path,
+	) -> Result<()> {
+		let subvolumes = self.subvolumes(partition);
+		if subvolumes.is_empty() {
+			return Ok(());
+		}
+		self.with_top_level(dev, partition.num, |top| {
+			for subvol in subvolumes {
+				self.info(format!(
+					"Creating subvolume {} on partition {}",
+					subvol.name, partition.num
+				));
+				cmd_run_check_status(
+					Command::new("btrfs")
+						.args(["subvolume", "create", "--"])
+						.arg(top.join(&subvol.name)),
+				)?;
+			}
+			if let Some(default) = &partition.default_subvolume {
+				cmd_run_check_status(
+					Command::new("btrfs")
+						.args(["subvolume", "set-default", "--"])
+						.arg(top.join(default)),
+				)
+				.context(format!(
+					"Failed to set the default subvolume to {}",
+					default
+				))?;
+			}
+			Ok(())
+		})
+	}
+
+	/// Take the read-only snapshots of the pristine installations, replacing the ones of an earlier run.
+	pub(crate) fn snapshot_installs(&self, loop_dev: &Path) -> Result<()> {
+		for partition in &self.device.partitions {
+			if !partition.install_snapshot || self.subvolumes(partition).is_empty() {
+				continue;
+			}
+			let Some(main) = partition.main_subvolume() else {
+				continue;
+			};
+			self.info(format!(
+				"Taking the snapshot {}/{} of subvolume {}",
+				SNAPSHOTS_SUBVOLUME, INSTALL_SNAPSHOT, main.name
+			));
+			let dev = self.device.filesystem_path(loop_dev, partition);
+			self.with_top_level(Path::new(&dev), partition.num, |top| {
+				let snapshot = top.join(SNAPSHOTS_SUBVOLUME).join(INSTALL_SNAPSHOT);
+				if snapshot.exists() {
+					cmd_run_check_status(
+						Command::new("btrfs")
+							.args(["subvolume", "delete", "--"])
+							.arg(&snapshot),
+					)?;
+				}
+				cmd_run_check_status(
+					Command::new("btrfs")
+						.args(["subvolume", "snapshot", "-r", "--"])
+						.arg(top.join(&main.name))
+						.arg(&snapshot),
+				)
+			})
+			.context(format!(
+				"Failed to snapshot the installation on partition {}",
+				partition.num
+			))?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::{load_device, TempDir};
+
+	/// The root partition, followed by its subvolumes.
+	const ROOT: &str = r#"partition_map = "gpt"
+
+[[partition]]
+num = 1
+type = "linux"
+usage = "rootfs"
+filesystem = "btrfs"
+mountpoint = "/"
+size = "rest"
+"#;
+
+	#[test]
+	fn test_check_btrfs() -> Result<()> {
+		let dir = TempDir::new("btrfs")?;
+		let check =
+			|spec: &str| load_device(&dir, &format!("{}{}", ROOT, spec))?.check_btrfs();
+		let err = |spec: &str| check(spec).unwrap_err().to_string();
+		let subvolumes = r#"subvolumes = [
+	{ name = "@", mountpoint = "/" },
+	{ name = "@home", mountpoint = "/home" },
+	{ name = "@snapshots" },
+]
+"#;
+		check("")?;
+		check(&format!(
+			"{}default_subvolume = \"@\"\ninstall_snapshot = true\n",
+			subvolumes
+		))?;
+		// The kernel would mount the top-level volume.
+		let e = err(subvolumes);
+		assert!(e.contains("installed into subvolume @"), "{}", e);
+		let e = err(&format!("{}default_subvolume = \"@home\"\n", subvolumes));
+		assert!(e.contains("installed into subvolume @"), "{}", e);
+		let with_cmdline = |cmdline: &str| {
+			let spec =
+				format!("kernel_cmdline = \"{}\"\n{}{}", cmdline, ROOT, subvolumes);
+			load_device(&dir, &spec)?.check_btrfs()
+		};
+		with_cmdline("rw rootflags=compress=zstd,subvol=/@")?;
+		// The last rootflags= wins.
+		let e = with_cmdline("rootflags=subvol=@ rootflags=compress=zstd")
+			.unwrap_err()
+			.to_string();
+		assert!(e.contains("installed into subvolume @"), "{}", e);
+		// Only the root partition is mounted by the kernel.
+		check(r#"
+[[partition]]
+num = 2
+type = "linux"
+usage = "data"
+filesystem = "btrfs"
+mountpoint = "/srv"
+size = "1GiB"
+subvolumes = [{ name = "@srv", mountpoint = "/srv" }]
+"#)?;
+		let e = err("subvolumes = [{ name = \"@\", mountpoint = \"/\" }, { name = \"@\" }]\ndefault_subvolume = \"@\"\n");
+		assert!(e.contains("declared more than once"), "{}", e);
+		let e = err("subvolumes = [{ name = \"a/b\" }]\n");
+		assert!(e.contains("invalid subvolume name"), "{}", e);
+		let e = err("subvolumes = [{ name = \"@\", mountpoint = \"/\" }]\ndefault_subvolume = \"@root\"\n");
+		assert!(
+			e.contains("default subvolume @root is not declared"),
+			"{}",
+			e
+		);
+		let e = err("subvolumes = [{ name = \"@\", mountpoint = \"/\" }, { name = \"@home\", mountpoint = \"home\" }]\ndefault_subvolume = \"@\"\n");
+		assert!(e.contains("must be an absolute path"), "{}", e);
+		let e = err("subvolumes = [{ name = \"@\", mountpoint = \"/\" }]\ndefault_subvolume = \"@\"\ninstall_snapshot = true\n");
+		assert!(e.contains("requires the @snapshots subvolume"), "{}", e);
+		let e = err("install_snapshot = true\n");
+		assert!(e.contains("requires a subvolume mounted"), "{}", e);
+		let e = load_device(
+			&dir,
+			&format!(
+				"{}subvolumes = [{{ name = \"@\" }}]\n",
+				ROOT.replace("btrfs", "ext4")
+			),
+		)?
+		.check_btrfs()
+		.unwrap_err()
+		.to_string();
+		assert!(e.contains("is not btrfs"), "{}", e);
+		Ok(())
+	}
+
+	#[test]
+	fn test_subvolume_mount_opts() {
+		let subvol = |name: &str, mountpoint: &str, opts: Option<&[&str]>| BtrfsSubvolume {
+			name: name.to_owned(),
+			mountpoint: Some(mountpoint.to_owned()),
+			mount_opts: opts.map(|o| o.iter().map(|s| s.to_string()).collect()),
+		};
+		let root = subvol("@", "/", None);
+		let home = subvol("@home", "/home", Some(&["compress=zstd:1"]));
+		assert_eq!(root.mount_opts_with(None), ["defaults", "subvol=@"]);
+		assert_eq!(
+			root.mount_opts_with(Some(&vec!["compress=zstd:3".to_owned()])),
+			["compress=zstd:3", "subvol=@"]
+		);
+		assert_eq!(
+			home.mount_opts_with(Some(&vec!["compress=zstd:3".to_owned()])),
+			["compress=zstd:1", "subvol=@home"]
+		);
+	}
+}
@@ -0,0 +1,211 @@
+//! Backends running commands inside the target.
+//!
+//! The backend is selected with `--chroot-backend`, and every command run
+//! inside the target (package installation, post installation and bootloader
+//! scripts) goes through [`command`]. The backends are made to look the same
+//! to the scripts:
+//!
+//! - `/proc`, `/sys` and `/dev` are available, as well as the block devices
+//!   passed as binds (the loop device and its partitions).
+//! - `/tmp` is the tmpfs mounted by mkrawimg, which contains `spec.sh`.
+//! - The host network is shared, and the host `/etc/resolv.conf` is bound
+//!   over the one in the target (if the target has a regular file there).
+//! - Nothing is registered to systemd-machined.
+use std::{path::Path, process::Command, sync::Mutex};
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+
+use crate::utils::find_program;
+
+/// Path to the host resolv.conf.
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// How the commands are run inside the target.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, strum::Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum ChrootBackend {
+	/// Plain chroot(8), the API filesystems are mounted by mkrawimg.
+	Chroot,
+	/// systemd-nspawn(1), isolates the target from the host processes.
+	#[default]
+	Nspawn,
+	/// bubblewrap, a lightweight sandbox without systemd.
+	Bwrap,
+}
+
+static BACKEND: Mutex<ChrootBackend> = Mutex::new(ChrootBackend::Nspawn);
+
+/// Set the backend used by the following commands.
+pub fn set_backend(backend: ChrootBackend) {
+	*BACKEND.lock().unwrap() = backend;
+}
+
+/// Get the backend in use.
+pub fn backend() -> ChrootBackend {
+	*BACKEND.lock().unwrap()
+}
+
+/// Whether the host resolv.conf should be bound into the target.
+pub fn bind_resolv_conf(root: &Path) -> bool {
+	Path::new(RESOLV_CONF).is_file()
+		&& std::fs::symlink_metadata(root.join("etc/resolv.conf"))
+			.is_ok_and(|m| m.is_file())
+}
+
+impl ChrootBackend {
+	/// The program implementing this backend.
+	pub fn program(&self) -> &'static str {
+		match self {
+			Self::Chroot => "chroot",
+			Self::Nspawn => "systemd-nspawn",
+			Self::Bwrap => "bwrap",
+		}
+	}
+
+	/// Make sure the backend is available on the host.
+	pub fn check(&self) -> Result<()> {
+		let program = self.program();
+		if find_program(program).is_none() {
+			bail!(
+				"{} is not found, which is required by the {} backend.\nPlease install it, or choose another backend with --chroot-backend.",
+				program,
+				self
+			);
+		}
+		Ok(())
+	}
+
+	/// Whether the API filesystems have to be mounted into the target by us.
+	pub fn needs_api_mounts(&self) -> bool {
+		*self == Self::Chroot
+	}
+
+	/// Build the command running `argv` inside `root`, with the block devices in `binds`.
+	pub fn command(&self, root: &Path, binds: &[&str], argv: &[&str]) -> Command {
+		let mut cmd = Command::new(self.program());
+		let root_str = root.to_string_lossy();
+		let resolv_conf = bind_resolv_conf(root);
+		match self {
+			// Binds and resolv.conf are mounted with the API filesystems.
+			Self::Chroot => {
+				cmd.arg(root_str.as_ref());
+			}
+			Self::Nspawn => {
+				cmd.args(["--quiet", "--register=no", "-D", &root_str]);
+				cmd.arg(if resolv_conf {
+					"--resolv-conf=bind-host"
+				} else {
+					"--resolv-conf=off"
+				});
+				for bind in binds {
+					cmd.args(["--bind", bind]);
+				}
+				cmd.arg("--");
+			}
+			Self::Bwrap => {
+				cmd.args(["--bind", &root_str, "/"])
+					.args(["--proc", "/proc"])
+					.args(["--dev", "/dev"])
+					.args(["--ro-bind", "/sys", "/sys"])
+					.args([
+						"--unshare-pid",
+						"--unshare-ipc",
+						"--die-with-parent",
+					]);
+				if resolv_conf {
+					cmd.args(["--ro-bind", RESOLV_CONF, RESOLV_CONF]);
+				}
+				for bind in binds {
+					cmd.args(["--dev-bind", bind, bind]);
+				}
+				cmd.arg("--");
+			}
+		}
+		cmd.args(argv);
+		cmd
+	}
+}
+
+/// Build the command running `argv` inside `root` with the selected backend.
+pub fn command(root: &Path, binds: &[&str], argv: &[&str]) -> Command {
+	backend().command(root, binds, argv)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn argv(cmd: &Command) -> Vec<String> {
+		std::iter::once(cmd.get_program())
+			.chain(cmd.get_args())
+			.map(|x| x.to_string_lossy().to_string())
+			.collect()
+	}
+
+	#[test]
+	fn test_backend_argv() {
+		// No resolv.conf in the target.
+		let root = Path::new("/nonexistent/target");
+		let binds = ["/dev/loop0", "/dev/loop0p1"];
+		let script = ["/bin/bash", "-c", "--", "true"];
+		assert_eq!(
+			argv(&ChrootBackend::Chroot.command(root, &binds, &script)),
+			[
+				"chroot",
+				"/nonexistent/target",
+				"/bin/bash",
+				"-c",
+				"--",
+				"true"
+			]
+		);
+		assert_eq!(
+			argv(&ChrootBackend::Nspawn.command(root, &binds, &script)),
+			[
+				"systemd-nspawn",
+				"--quiet",
+				"--register=no",
+				"-D",
+				"/nonexistent/target",
+				"--resolv-conf=off",
+				"--bind",
+				"/dev/loop0",
+				"--bind",
+				"/dev/loop0p1",
+				"--",
+				"/bin/bash",
+				"-c",
+				"--",
+				"true"
+			]
+		);
+		assert_eq!(
+			argv(&ChrootBackend::Bwrap.command(root, &binds[..1], &script)),
+			[
+				"bwrap",
+				"--bind",
+				"/nonexistent/target",
+				"/",
+				"--proc",
+				"/proc",
+				"--dev",
+				"/dev",
+				"--ro-bind",
+				"/sys",
+				"/sys",
+				"--unshare-pid",
+				"--unshare-ipc",
+				"--die-with-parent",
+				"--dev-bind",
+				"/dev/loop0",
+				"/dev/loop0",
+				"--",
+				"/bin/bash",
+				"-c",
+				"--",
+				"true"
+			]
+		);
+	}
+}
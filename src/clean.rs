@@ -0,0 +1,155 @@
+//! Cleaning up the working directory, with `mkrawimg clean`.
+//!
+//! The working directory contains:
+//!
+//! - `sketches`: The raw image and the mount points of each job, see
+//!   [`ImageContext::sketch_dir`](crate::context::ImageContext::sketch_dir).
+//!   They are removed after each build according to `--keep-workdir`, which
+//!   keeps the ones of the failed builds by default.
+//! - `bootstrap`: The bootstrapped system distributions, cached across
+//!   builds.
+//! - `downloads`: The bootloader files downloaded by their checksums, see
+//!   [`crate::fetch`]. They are never removed by `clean`.
+//!
+//! `clean` removes the sketch directories, and the bootstrapped system
+//! distributions with `--bootstrap`. A sketch directory whose partitions are
+//! still mounted, or whose raw image is still attached to a loop device, is
+//! refused unless `--force` is specified, in which case they are released
+//! first.
+use std::{
+	fs::{self, remove_dir_all},
+	os::unix::fs::MetadataExt,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use walkdir::WalkDir;
+
+use crate::utils::{loop_devices_backed_by, mounts_under, release_leftovers};
+
+/// Bytes allocated to the files under the directory, sparse files only count
+/// the allocated blocks.
+pub fn disk_usage<P: AsRef<Path>>(dir: P) -> u64 {
+	WalkDir::new(dir)
+		.into_iter()
+		.flatten()
+		.filter_map(|e| e.metadata().ok())
+		.map(|m| m.blocks() * 512)
+		.sum()
+}
+
+/// Format the size in bytes with a binary unit, e.g. `1.50 GiB`.
+pub fn format_size(size: u64) -> String {
+	const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+	if size < 1024 {
+		return format!("{} bytes", size);
+	}
+	let mut value = size as f64 / 1024.0;
+	let mut unit = UNITS[0];
+	for u in &UNITS[1..] {
+		if value < 1024.0 {
+			break;
+		}
+		value /= 1024.0;
+		unit = u;
+	}
+	format!("{:.2} {}", value, unit)
+}
+
+/// Remove the directory, releasing the mounts and the loop devices under it
+/// with `force`. Returns the bytes reclaimed.
+fn remove_dir(dir: &Path, force: bool) -> Result<u64> {
+	let mounts = mounts_under(dir)?;
+	let loop_devs = fs::read_dir(dir)
+		.map(|entries| {
+			entries.flatten()
+				.flat_map(|e| loop_devices_backed_by(e.path()))
+				.collect::<Vec<_>>()
+		})
+		.unwrap_or_default();
+	if !mounts.is_empty() || !loop_devs.is_empty() {
+		let leftovers = mounts
+			.iter()
+			.chain(&loop_devs)
+			.map(|p| p.display().to_string())
+			.collect::<Vec<_>>();
+		if !force {
+			bail!(
+				"{} is still in use by the following:\n\t{}\nIs a build running? Use --force to clean them up anyway.",
+				dir.display(),
+				leftovers.join("\n\t")
+			);
+		}
+		release_leftovers(&mounts, &loop_devs)?;
+	}
+	// Never remove the filesystems mounted under it.
+	if !mounts_under(dir)?.is_empty() {
+		bail!("Unable to unmount everything under {}", dir.display());
+	}
+	let size = disk_usage(dir);
+	remove_dir_all(dir).context(format!("Failed to remove {}", dir.display()))?;
+	Ok(size)
+}
+
+/// Remove the sketch directories, and the bootstrapped system distributions
+/// if `bootstrap`. Returns the bytes reclaimed.
+pub fn clean_workdir(workdir: &Path, bootstrap: bool, force: bool) -> Result<u64> {
+	let mut dirs: Vec<PathBuf> = Vec::new();
+	let sketches = workdir.join("sketches");
+	if let Ok(entries) = fs::read_dir(&sketches) {
+		let mut entries = entries.flatten().map(|e| e.path()).collect::<Vec<_>>();
+		entries.sort();
+		dirs.extend(entries);
+	}
+	let bootstrap_dir = workdir.join("bootstrap");
+	if bootstrap && bootstrap_dir.exists() {
+		dirs.push(bootstrap_dir);
+	}
+	let mut reclaimed = 0;
+	for dir in &dirs {
+		if !dir.is_dir() {
+			continue;
+		}
+		let size = remove_dir(dir, force)?;
+		info!("Removed {} ({})", dir.display(), format_size(size));
+		reclaimed += size;
+	}
+	// Empty ones only.
+	fs::remove_dir(&sketches).ok();
+	Ok(reclaimed)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	#[test]
+	fn test_format_size() {
+		assert_eq!(format_size(0), "0 bytes");
+		assert_eq!(format_size(1023), "1023 bytes");
+		assert_eq!(format_size(1536), "1.50 KiB");
+		assert_eq!(format_size(3 << 30), "3.00 GiB");
+		assert_eq!(format_size(5 << 40), "5.00 TiB");
+		assert_eq!(format_size(5 << 50), "5120.00 TiB");
+	}
+
+	#[test]
+	fn test_clean_workdir() -> Result<()> {
+		let workdir = TempDir::new("clean")?;
+		let sketch = workdir.join("sketches/rpi-5b-Base");
+		fs::create_dir_all(sketch.join("mnt/p1"))?;
+		fs::write(sketch.join("rawmedia.img"), vec![1u8; 8192])?;
+		fs::create_dir_all(workdir.join("bootstrap/arm64-base"))?;
+		fs::write(workdir.join("bootstrap/arm64-base/os-release"), "AOSC OS")?;
+		let reclaimed = clean_workdir(&workdir, false, false)?;
+		assert!(reclaimed >= 8192);
+		assert!(!workdir.join("sketches").exists());
+		assert!(workdir.join("bootstrap").exists());
+		assert!(clean_workdir(&workdir, true, false)? > 0);
+		assert!(!workdir.join("bootstrap").exists());
+		assert_eq!(clean_workdir(&workdir, true, false)?, 0);
+		Ok(())
+	}
+}
@@ -13,6 +13,13 @@
 //!
 //! - `pretty`: table format which contains basic information.
 //! - `simple`: simple column-based format splitted by tab character (`'\t'`).
+//! - `json`: JSON array of the devices, including their `[metadata]`.
+//!
+//! With `--long`, the `pretty` format also shows the documentation link, the
+//! flashing instructions and the maintainer of each device.
+//!
+//! With `--list-ids`, only the IDs and the aliases of the devices are
+//! printed, one per line, for the shell completions.
 //!
 //! ### Build images for one specific device
 //!
@@ -31,6 +38,10 @@
 //!   - Device alias (defined in `device.toml`).
 //!   - The path to the `device.toml` file.
 //!
+//!   If omitted on a terminal, the device is picked interactively.
+//!
+//! Deprecated devices are only built with `--allow-deprecated`.
+//!
 //! ### Build Images for All Devices (in the registry)
 //!
 //! <div class="warning">
@@ -41,18 +52,103 @@
 //! # ./target/release/mkrawimg build-all --variants VARIANTS
 //! ```
 //!
+//! Deprecated devices are skipped unless `--allow-deprecated` is given, the
+//! number of skipped devices is shown in the summary.
+//!
+//! Older images in the output directory can be pruned after the build with
+//! `--retention`, see [`RetentionPolicy`](crate::retention::RetentionPolicy).
+//!
+//! The images built by `build` and `build-all` are recorded in
+//! `build-report.json` in the output directory, see [`crate::report`].
+//!
+//! ### Partition an image file or a block device
+//!
+//! <div class="warning">
+//! Partitioning requires the root privileges.
+//! </div>
+//!
+//! ```shell
+//! # ./target/release/mkrawimg partition --format-fs -- DEVICE TARGET
+//! ```
+//!
 //! ### Check validity of the device specification files
 //!
 //! ```shell
 //! $ ./target/release/mkrawimg check
 //! ```
 //!
+//! With `--smoke`, the build pipeline is also run against a miniature image
+//! to catch the errors which only show up in a real build, see
+//! [`smoke`](crate::smoke) for what is covered. This requires the root
+//! privileges.
+//!
+//! ```shell
+//! # ./target/release/mkrawimg check --smoke [DEVICE]
+//! ```
+//!
+//! Unknown keys in the specifications (e.g. misspelled ones) are warned
+//! about. With `--strict`, the devices having unknown keys fail the check,
+//! see [`strict`](crate::strict).
+//!
+//! ```shell
+//! $ ./target/release/mkrawimg check --strict [DEVICE]
+//! ```
+//!
+//! ### Show the metadata of an image
+//!
+//! ```shell
+//! $ ./target/release/mkrawimg inspect IMAGE
+//! ```
+//!
+//! ### Show the resolved specification of a device
+//!
+//! ```shell
+//! $ ./target/release/mkrawimg inspect [-V VARIANT] [--format json] DEVICE
+//! ```
+//!
+//! ### Clean up the working directory
+//!
+//! ```shell
+//! # ./target/release/mkrawimg clean [--bootstrap]
+//! ```
+//!
+//! ### Show the configuration
+//!
+//! ```shell
+//! $ ./target/release/mkrawimg config --print
+//! ```
+//!
+//! ### Generate the shell completions and the man page
+//!
+//! ```shell
+//! $ ./target/release/mkrawimg generate-completions bash|zsh|fish
+//! $ ./target/release/mkrawimg generate-manpage
+//! ```
+//!
 //! For the advanced usage, please go to [`Cmdline`].
-use std::{path::PathBuf, vec};
+use std::{ops::RangeInclusive, path::PathBuf, vec};
+
+use anyhow::{bail, Result};
 
 use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use log::LevelFilter;
 
-use crate::context::ImageVariant;
+use crate::{
+	artifact::OverwritePolicy,
+	chroot::ChrootBackend,
+	completion::CompletionShell,
+	context::ImageVariant,
+	device::ImageSize,
+	filesystem::FilesystemType,
+	mirror::Mirror,
+	naming::{NameTemplate, Revision},
+	populate::PopulateBackend,
+	recipe::RecipeOverride,
+	retention::RetentionPolicy,
+	rootfs::RootfsSource,
+	stage::Stage,
+	sysroot::Sysroot,
+};
 
 /// Overrides the filesystem type of the root filesystem.
 ///
@@ -67,6 +163,16 @@ pub enum RootFsType {
 	Xfs,
 }
 
+impl From<RootFsType> for FilesystemType {
+	fn from(value: RootFsType) -> Self {
+		match value {
+			RootFsType::Ext4 => Self::Ext4,
+			RootFsType::Btrfs => Self::Btrfs,
+			RootFsType::Xfs => Self::Xfs,
+		}
+	}
+}
+
 /// Specifies the compression format for the output image.
 ///
 /// The default compression format is `xz`.
@@ -89,11 +195,38 @@ pub enum Compression {
 	None,
 }
 
+/// When to use colors in the output.
+#[derive(Copy, Debug, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ColorMode {
+	/// Use colors if the output is a terminal.
+	Auto,
+	Always,
+	Never,
+}
+
+/// When to keep the sketch directory of an image (the raw image and the mount points) after the build.
+#[derive(Copy, Debug, Clone, PartialEq, Eq, ValueEnum)]
+pub enum KeepWorkdir {
+	/// Always keep it.
+	Always,
+	/// Keep it if the build failed, for debugging.
+	OnFailure,
+	/// Always remove it.
+	Never,
+}
+
 #[derive(Clone, ValueEnum)]
 pub enum ListFormat {
 	Pretty,
 	Simple,
-	// Json,
+	Json,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum InspectFormat {
+	/// TOML, with the values derived from the defaults annotated.
+	Toml,
+	Json,
 }
 
 /// Command line usage
@@ -108,23 +241,57 @@ pub enum ListFormat {
 /// Global options
 /// ==============
 ///
-/// - `--debug`: Enables the debug output. Does not have a short option.
+/// - `-v`, `--verbose`: Enables the debug output, including the output of the external commands (rsync, aoscbootstrap, mkfs, etc.), which is hidden otherwise. Specify twice (`-vv`) for the trace output.
+/// - `--debug`: Same as `-v`, kept for compatibility.
+/// - `-q`, `--quiet`: Only shows the warnings, the errors and the summary of the built images. Conflicts with `-v` and `--debug`.
+/// - `--config` `PATH`: Reads the defaults of the options from `PATH` instead of `/etc/mkrawimg.toml`. Options on the command line take precedence. See [`crate::config`] for the format.
 /// - `-r`, `--registry`: Overrides the path to the [device registry].
 /// - `-D`, `--workdir`: Overrides the working directory path. The default path is `./work`.
 /// - `-O`, `--outdir`: Overrides the output directory path. The default path is `./out`.
-/// - `-m`, `--mirror`: Overrides the package repository mirror for package downloads. The default mirror is the AOSC OS upstream mirror.
+/// - `-m`, `--mirror` `[ARCH=]URL`: Overrides the package repository mirror for package downloads. The default mirror is the AOSC OS upstream mirror. With `ARCH=`, only overrides the mirror for `ARCH`, e.g. `loongarch64=https://mirror.example.org/debs`. Can be specified more than once, see [`crate::mirror`].
+/// - `--recipe` `ARCH.VARIANT=PATH`: Overrides the aoscbootstrap recipe used to bootstrap the `VARIANT` distribution for `ARCH`, e.g. `loongson3.desktop=/srv/recipes/desktop.lst`. Can be specified more than once. Takes precedence over the `[recipes]` in the device specification. See [`crate::recipe`].
+/// - `--sysroot` `ARCH.VARIANT=PATH`: Uses the existing system distribution at `PATH` as the `VARIANT` distribution for `ARCH`, instead of bootstrapping one. Can be specified more than once. The architecture of the distribution is checked before anything is built. See [`crate::sysroot`].
+/// - `--rootfs-source` `PATH`: Extracts the system distribution from the tarball (optionally compressed with gzip, xz or zstd) or the OCI image layout directory at `PATH` into the images, instead of bootstrapping one. Only a single variant can be built with it, and the architecture of the distribution must match every device. Conflicts with `--sysroot`. See [`crate::rootfs`].
+/// - `--force-bootstrap`: Wipes the cached system distributions and bootstraps them again. Does not affect the ones specified with `--sysroot`.
+/// - `--offline`: Never downloads the bootloader files specified with `source` in the device specifications, the build fails if one of them is not in the cache of the working directory. Does not affect bootstrapping, which still requires the mirror. See [`crate::fetch`].
 /// - `-U`, `--user`: Overrides the username of the built-in user. The default username is `aosc`.
 /// - `-P`, `--password`: Overrides the password of the built-in user. The default password is `anthon`.
+/// - `--no-user`: Creates no built-in user, e.g. for images provisioned on the first boot. Conflicts with `--user`, `--password`, `--password-hashed`, `--expire-password` and `--ssh-key`. Post installation scripts see an empty `DEFAULT_USER`, see [`DeviceSpec`](crate::device::DeviceSpec).
+/// - `--password-hashed`: Takes the password as a hash in the format of crypt(3), e.g. generated by `mkpasswd -m yescrypt`, so the plaintext never has to be on the command line. Accepts `$5$`, `$6$`, `$2b$` (and `$2a$`, `$2y$`), `$7$`, `$y$` and `$gy$` hashes. A hashed password is never considered the default one.
+/// - `--hostname` `HOSTNAME`: Overrides the hostname of the target. Takes precedence over the `hostname` in the device specification. The default is the device ID, lowercased and with anything other than letters and digits replaced by hyphens.
+/// - `--timezone` `TIMEZONE`: Sets the timezone of the target, e.g. `Asia/Shanghai`. Takes precedence over the `timezone` in the device specification. The timezone must exist in the zoneinfo database of the system distribution, which is checked before any image is built. If neither is specified, the timezone of the system distribution is kept.
+/// - `--ssh-key` `PATH_OR_KEY`: Installs the SSH public key, or the keys in the file at `PATH_OR_KEY`, into `~/.ssh/authorized_keys` of the built-in user. Can be specified more than once. Keys which are not valid OpenSSH public keys are refused before anything is built. See [`crate::sshkey`].
+/// - `--root-ssh-key` `PATH_OR_KEY`: Same as `--ssh-key`, for root.
+/// - `--customize-script` `PATH`: Runs the script in the target after the post installation script, e.g. to enable a service or drop a configuration file without changing the device registry. Can be specified more than once, the scripts run in the order given. See [`crate::customize`].
+/// - `--customize-dir` `DIR`: Runs every executable in `DIR` in lexical order, after the scripts given with `--customize-script`. Can be specified more than once.
+/// - `--expire-password` `true|false`: Force the built-in user to change the password on the first login. Enabled by default if the default password is used and no `--ssh-key` is given. The build fails if the default password neither expires nor is accompanied by an SSH key.
+/// - `--public-artifacts`: Images containing credentials (a password other than the default one, the default password which does not expire, or a Wi-Fi pre-shared key in `/etc/NetworkManager/system-connections`, `/etc/wpa_supplicant` or `/var/lib/iwd`) are created unreadable by others (mode `0640`), and a warning is shown if the output directory is accessible by everyone. This option keeps them readable by everyone, for public images. Only allowed with the default password.
+/// - `--overwrite` `POLICY`: What to do if an image to be built already exists in the output directory, can be `replace` or `error` (refuse to build anything). The default is `replace`. Jobs producing the same image are always refused.
+/// - `--name-template` `TEMPLATE`: Names the images after `TEMPLATE` instead of the default `aosc-os_{variant}_rawimg_{vendor}_{id}_{date}{revision}_{arch}`, e.g. `aosc-os_{id}_{variant}_{date}{revision}`. The extension is always appended. Supported placeholders are `{variant}`, `{vendor}`, `{id}`, `{date}`, `{revision}`, `{arch}` and `{compat}`, unknown ones are rejected. Takes precedence over the `name_template` in the device specification. See [`crate::naming`].
+/// - `--resume`: Skips the images which already exist in the output directory, e.g. to continue a `build-all` which died halfway. Images with the same name except for the date are skipped, so a build resumed on another day does not start over. The images are written to `NAME.part` and renamed once complete, so an image which was being written is never skipped.
+/// - `--force`: Builds every image even if `--resume` is specified. Also cleans up the leftovers of an earlier build of the same image which died halfway (mounted partitions, attached loop devices), which are refused otherwise.
+/// - `--skip-preflight`: Skips the check for the external programs the build runs and the free space in the working directory and the output directory, which is run before anything is built. See [`crate::preflight`].
+/// - `--keep-workdir` `WHEN`: When to keep the sketch directory of each image (the raw image and the mount points) after it is built, can be `always`, `on-failure` or `never`. The default is `on-failure`, so failed builds can be debugged. Leftovers of a failed build are released (unmounted, detached) before removal. See [`KeepWorkdir`].
+/// - `--stages` `STAGES`: Only run these stages of the build, separated by commas, e.g. `bootloader,compress` to apply the bootloaders again to the raw image left by an earlier build. The stages are `partition`, `format`, `populate`, `postinst`, `bootloader` and `compress`, and the selected ones must be consecutive. The sketch directory of such a build is kept with `--keep-workdir on-failure`. See [`crate::stage`].
 /// - `-c`, `--cleanup`: Clean up the sketch directories after building, to free some space.
 /// - `-C`, `--cleanup-bootstrap`: Clean up the bootstrapped system distributions after building, to free some space.
+/// - `--color` `WHEN`: When to use colors in the output, can be `auto`, `always` or `never`. The default is `auto`.
+/// - `--chroot-backend` `BACKEND`: How to run commands (package installation, scripts) inside the target, can be `nspawn` (systemd-nspawn), `bwrap` (bubblewrap) or `chroot`. The default is `nspawn`. See [`ChrootBackend`].
+/// - `--build-id` `ID`: Identifies this invocation in the logs and the artifacts. A [ULID](https://github.com/ulid/spec) is generated if not specified.
+/// - `--record` `DIR`: Record every external command being run (arguments, exit status, output, etc.) into `DIR`, to help debugging problems in the user's environment. The password is redacted from the recordings.
+/// - `--replay` `DIR`: For developers: do not run any external command, use the results recorded in `DIR` instead.
 ///
 /// Actions
 /// =======
 ///
 /// - `build`: Build images for one specific device.
 /// - `build-all`: Build images for all devices registered in the registry.
+/// - `partition`: Apply the partition layout of a device to an image file or a block device.
 /// - `check`: Check the validity of the device specification files.
 /// - `list`: List all of the devices registered in the registry.
+/// - `inspect`: Show the metadata embedded in an image, or the resolved specification of a device.
+/// - `clean`: Clean up the working directory.
+/// - `config`: Show the configuration file in use.
 ///
 /// Notes
 /// -----
@@ -156,6 +323,14 @@ pub enum ListFormat {
 ///
 ///   Possible values are: `xz`, `zstd`, `gzip`, `none`. The default is `xz`.
 ///
+/// - `--compress-level` `N`
+///
+///   Specify the compression level. Accepts `0` to `9` for `xz` and `gzip`, `1` to `22` for `zstd`. The default is `9`. Lower levels are much faster, e.g. for test builds of the large desktop images.
+///
+/// - `--compress-threads` `N`
+///
+///   Specify the number of threads to compress with. The default is the number of CPUs, up to 32. With `1`, `xz` uses the single-threaded encoder. `gzip` does not support more than one thread. The levels and the threads are refused with `--compression none`.
+///
 /// - `-V`, `--variants` `VARIANT [VARIANT...]`
 ///
 ///   Select distribution variants to build, must specify at least one variant.
@@ -166,6 +341,8 @@ pub enum ListFormat {
 ///
 ///   Use a positive integer as the revision of the image. The revision will be added to the filename of the output.
 ///
+///   With `auto`, the revision after the highest one of the existing images of the same device, variant and date in the output directory is used, e.g. `.3` if `.1` and `.2` exist, or `.1` if there is none. The image is created empty before the build starts, so concurrent builds never take the same revision. Requires `{revision}` in the name template, and can not be used with `--resume`. The revision is shown in the summary and written in the image metadata.
+///
 /// - `-p`, `--additional-packages` `PKG [PKG...]`
 ///
 ///   Supply a list of package names to install into the target system. This does not override the defined list.
@@ -174,10 +351,48 @@ pub enum ListFormat {
 ///
 ///   Enroll addition topic(s) during installation.
 ///
+/// - `--image-size` `SIZE`
+///
+///   Overrides the size of the variant being built in the device specification, e.g. when `--packages` does not fit in the default size. Accepts plain numbers in MiB, or numbers with the unit `M`, `G` or `T` (binary), e.g. `8G` or `10240M`. Sizes smaller than the partitions of fixed sizes are refused.
+///
+/// - `--round-to` `MIB`
+///
+///   Round the image size up to a multiple of `MIB` MiB (e.g. the erase block size of the eMMC). Overrides `image_size_round_to` in the device specification.
+///
+/// - `--trailing-pad` `MIB`
+///
+///   Leave `MIB` MiB of unpartitioned space at the end of the image. Overrides `trailing_pad` in the device specification.
+///
+/// - `--shrink`
+///
+///   Shrink the last partition and the image to the contents after the image is built, so the artifact is smaller and faster to flash. The image grows to fill the medium on the first boot, which requires `growpart` in the target. Only ext4 and btrfs can be shrunk. See [`crate::shrink`].
+///
+/// - `--no-fsck`
+///
+///   Skip checking the filesystems of the images after they are populated. By default, each filesystem is checked read-only (e.g. `e2fsck -fn`, `fsck.vfat -n`) before the loop device is detached, and the build fails if any of them has errors. See [`crate::fsck`].
+///
+/// - `--populate-backend` `BACKEND`
+///
+///   How to populate the filesystems, can be `mount` or `no-mount`. The default is `mount`, the filesystems are mounted and the distribution is installed into them. With `no-mount`, nothing is mounted: the distribution is installed into a staging directory, and the ext4 and FAT filesystems are built from it with `mkfs.ext4 -d` and `mcopy`, then written to the image. Other filesystems are refused. See [`crate::populate`].
+///
+/// - `--seed` `SEED`
+///
+///   Derive the disk GUID (or the disk signature of the MBR), the PARTUUIDs and the filesystem UUIDs from `SEED`, instead of generating random ones. Two builds with the same seed and inputs have identical partition tables. The identifiers pinned in the device specification are kept. See [`crate::seed`].
+///
+/// - `--dry-run`
+///
+///   Print the steps to build each image, with the parameters they would use, without building anything. No image is created and no disk is touched. The partition layout is still computed, so the command fails if the partitions do not fit in the image.
+///
+/// - `-j`, `--jobs` `N`
+///
+///   Build up to `N` images at once. The log records and the output of the commands are prefixed with the image they belong to, and the progress bar is disabled if `N` is greater than 1. Once an image fails, no more images are started, and the status of every image is reported after the running ones finish. The default is 1. Can not be used with `--record`.
+///
 /// Arguments for `build`
 /// ---------------------
 ///
-/// The `build` action takes exactly one argument: `DEVICE`.
+/// The `build` action takes one argument: `DEVICE`.
+///
+/// If `DEVICE` is omitted on a terminal, the device is picked interactively from the registry, with a fuzzy search over the IDs, the aliases and the names of the devices, see [`crate::picker`]. Otherwise it is required.
 ///
 /// `DEVICE` is a string identifying the target device. It can be one of the following:
 /// - A device ID defined in the device specification file.
@@ -231,18 +446,95 @@ pub enum ListFormat {
 ///
 /// The `build-all` action takes the same options as the `build` action. [See above](#options-for-build) for available options.
 ///
+/// Options for `build-all`
+/// -----------------------
+///
+/// In addition to the options of `build`:
+///
+/// - `--arch` `ARCH [ARCH...]`
+///
+///   Only build images for the devices of these architectures, e.g. `riscv64`. Case-insensitive.
+///
+/// - `--vendor` `VENDOR [VENDOR...]`
+///
+///   Only build images for the devices of these vendors, e.g. `raspberrypi`. Case-insensitive.
+///
+/// - `--tag` `TAG [TAG...]`
+///
+///   Only build images for the devices with all of these tags, e.g. `--tag sbc release`. Case-insensitive. See the `tags` field of [`DeviceSpec`](crate::device::DeviceSpec).
+///
+/// - `--any-tag`
+///
+///   Build images for the devices with any of the tags given by `--tag`, instead of all of them.
+///
+/// If more than one of them are specified, a device must match all of them. The number of devices skipped by the filters is printed.
+///
+/// - `--continue-on-error`
+///
+///   Keep building the other images if one fails, instead of starting no more jobs. The mounts and the loop device of a failed image are released before the next job starts, while the raw image is kept according to `--keep-workdir`. The images which failed are listed in the summary, and the exit status is non-zero if any of them failed. The retention policy is not applied then.
+///
 /// The `build-all` action takes no arguments.
 ///
+/// Action `partition`
+/// ==================
+///
+/// This action creates the partitions defined by a device on an image file or a block device, then exits.
+/// Nothing is bootstrapped or installed.
+///
+/// ```shell
+/// # ./target/release/mkrawimg [GLOBAL_OPTIONS] partition [OPTIONS] [--] DEVICE TARGET
+/// ```
+///
+/// If `TARGET` does not exist, a sparse image file is created. An existing image file is reused as is.
+/// Block devices which are mounted or used as swap are refused.
+///
+/// Options for `partition`
+/// -----------------------
+///
+/// - `-s`, `--size` `SIZE`
+///
+///   Size of the image file to create, in MiB. Defaults to the size of the `base` variant defined in the device specification.
+///
+/// - `--format-fs`
+///
+///   Also format the partitions with the filesystems defined in the device specification.
+///
+/// - `--seed` `SEED`
+///
+///   Same as the `--seed` option of the `build` action.
+///
+/// Arguments for `partition`
+/// -------------------------
+///
+/// - `DEVICE`: Same as the `DEVICE` argument of the `build` action.
+/// - `TARGET`: Path to the image file or the block device.
+///
 /// Action `check`
 /// ==============
 ///
 /// This action checks for the validity of the device specificatoin files.
 ///
 /// ```shell
-/// ./target/release/mkrawimg [GLOBAL_OPTIONS] check
+/// ./target/release/mkrawimg [GLOBAL_OPTIONS] check [OPTIONS] [DEVICE]
 /// ```
 ///
-/// `check` action does not take any options besides the global options, and it does not take any arguments.
+/// Every device specification file is checked, a broken one does not stop the others from being checked. The errors include the files which can not be parsed, IDs and aliases used more than once, invalid partition layouts and missing bootloader scripts. A PASS/FAIL table of the devices is printed at the end, followed by the errors of the failing ones.
+///
+/// When the whole registry is checked, the tags used by only one device are warned about, they are likely typos.
+///
+/// The exit status is the number of failing devices, capped at 125.
+///
+/// Options for `check`
+/// -------------------
+///
+/// - `--smoke`
+///
+///   Also run the build pipeline against a miniature image of each device, which catches the errors only showing up in a real build. Requires the root privileges. Refer to [`crate::smoke`] for the stages covered.
+///
+/// Arguments for `check`
+/// ---------------------
+///
+/// - `DEVICE`: Same as the `DEVICE` argument of the `build` action. All devices in the registry are checked if omitted.
 ///
 /// Action `list`
 /// =============
@@ -268,13 +560,122 @@ pub enum ListFormat {
 ///   - `pretty`: A table-like format which shows the basic information of devices.
 ///   - `simple`: A much simpler format which contains three colums splitted by tab character (`'\t'`), and one device per line.
 ///
+/// - `--arch` `ARCH [ARCH...]`, `--vendor` `VENDOR [VENDOR...]`, `--tag` `TAG [TAG...]`, `--any-tag`
+///
+///   Only list the devices of these architectures, vendors and tags, like [`build-all`](#options-for-build-all) does.
+///
+/// - `--list-ids`
+///
+///   Only print the IDs and the aliases of the devices, one per line, for the shell completions to complete the devices with.
+///
+/// Action `inspect`
+/// ================
+///
+/// This action shows the metadata embedded in an image built by this tool, e.g. the device and the build date.
+///
+/// ```shell
+/// ./target/release/mkrawimg inspect IMAGE
+/// ```
+///
+/// `IMAGE` can be a raw image, or an image compressed with xz, zstd or gzip. Only the beginning of a compressed image is decompressed.
+///
+/// Given a device instead, it shows the device specification with every default applied, as a build of the variant would use it: the size of the image in bytes after the padding and the rounding, the sectors each partition occupies, the filesystems after `--fstype`, the hostname and the partition labels. See [`crate::inspect`].
+///
+/// ```shell
+/// ./target/release/mkrawimg [--registry REGISTRY] inspect [OPTIONS] DEVICE
+/// ```
+///
+/// Options for `inspect`
+/// ---------------------
+///
+/// These options only apply to the devices.
+///
+/// - `-V`, `--variant` `VARIANT`
+///
+///   Resolve the sizes for `VARIANT`. Possible values are: `base`, `desktop`, `server`. The default is `base`.
+///
+/// - `-f`, `--fstype` `FSTYPE`, `--image-size` `SIZE`
+///
+///   Same as the ones of the `build` action.
+///
+/// - `--format` `FORMAT`
+///
+///   Possible values are:
+///   - `toml`: TOML, with the values derived from the defaults annotated. The default.
+///   - `json`: JSON.
+///
+/// Arguments for `inspect`
+/// -----------------------
+///
+/// - `TARGET`: Path to an image, or the same as the `DEVICE` argument of the `build` action. Files named `device.toml` and directories are devices.
+///
+/// Action `clean`
+/// ==============
+///
+/// This action removes the sketch directories (raw images and mount points) left in the working directory, and prints how much space is reclaimed. Requires the root privileges.
+///
+/// ```shell
+/// ./target/release/mkrawimg [GLOBAL_OPTIONS] clean [--bootstrap]
+/// ```
+///
+/// Sketch directories which are still in use (mounted partitions, attached loop devices) are refused, as a build may be running. With the global `--force`, they are unmounted and detached first. See [`crate::clean`].
+///
+/// Options for `clean`
+/// -------------------
+///
+/// - `--bootstrap`
+///
+///   Also remove the bootstrapped system distributions, which are otherwise cached across builds.
+///
+/// Action `config`
+/// ===============
+///
+/// This action shows the configuration file in use, see [`crate::config`].
+///
+/// ```shell
+/// ./target/release/mkrawimg [GLOBAL_OPTIONS] config [--print]
+/// ```
+///
+/// Options for `config`
+/// --------------------
+///
+/// - `--print`
+///
+///   Print the effective configuration, that is the global options merged from the command line, the configuration file and the defaults, with where each one comes from.
+///
+/// Actions `generate-completions` and `generate-manpage`
+/// =====================================================
+///
+/// These hidden actions print the shell completions and the man page to stdout, for the packagers, see [`crate::completion`].
+///
+/// ```shell
+/// ./target/release/mkrawimg generate-completions SHELL
+/// ./target/release/mkrawimg generate-manpage
+/// ```
+///
+/// Arguments for `generate-completions`
+/// ------------------------------------
+///
+/// - `SHELL`
+///
+///   The shell to generate the completions for, one of `bash`, `zsh` and `fish`.
+///
 /// [device registry]: crate::registry::DeviceRegistry
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cmdline {
-	/// Turns on debug output.
+	/// Turns on debug output, same as -v.
 	#[arg(long, action = ArgAction::SetTrue)]
 	pub debug: bool,
+	/// Show more output, -v for debug output, -vv for trace output
+	#[arg(short = 'v', long = "verbose", action = ArgAction::Count, conflicts_with = "quiet")]
+	pub verbose: u8,
+	/// Only show warnings, errors and the summary
+	#[arg(short = 'q', long, action = ArgAction::SetTrue, conflicts_with = "debug")]
+	pub quiet: bool,
+	/// Read the defaults of the options from this file instead of /etc/mkrawimg.toml
+	#[arg(long, value_name = "PATH")]
+	pub config: Option<PathBuf>,
 	/// Override path to the device registry
 	#[arg(short = 'r', long)]
 	pub registry: Option<PathBuf>,
@@ -284,21 +685,108 @@ pub struct Cmdline {
 	/// Output directory
 	#[arg(short = 'O', long, default_value = "./out")]
 	pub outdir: PathBuf,
-	/// The mirror to download packages from.
-	#[arg(short = 'm', long, default_value = "https://repo.aosc.io/debs")]
-	pub mirror: String,
+	/// The mirror to download packages from, or ARCH=URL for one architecture.
+	#[arg(short = 'm', long, value_name = "[ARCH=]URL")]
+	pub mirror: Vec<Mirror>,
+	/// Override the recipe to bootstrap a distribution, e.g. loongson3.desktop=PATH
+	#[arg(long, value_name = "ARCH.VARIANT=PATH")]
+	pub recipe: Vec<RecipeOverride>,
+	/// Use an existing system distribution instead of bootstrapping one, e.g. arm64.base=PATH
+	#[arg(long, value_name = "ARCH.VARIANT=PATH")]
+	pub sysroot: Vec<Sysroot>,
+	/// Extract the system distribution from a tarball or an OCI image instead of bootstrapping one
+	#[arg(long, value_name = "PATH", conflicts_with = "sysroot")]
+	pub rootfs_source: Option<RootfsSource>,
+	/// Bootstrap the system distributions again, even if they are cached
+	#[arg(long, action = clap::ArgAction::SetTrue)]
+	pub force_bootstrap: bool,
+	/// Never download the bootloader files, use the cached ones only
+	#[arg(long, action = ArgAction::SetTrue)]
+	pub offline: bool,
 	/// Specify username for the OS
 	#[arg(short = 'U', long, default_value = "aosc")]
 	pub user: String,
 	/// Specify password for the OS
 	#[arg(short = 'P', long, default_value = "anthon")]
 	pub password: String,
+	/// Run a script in the target after the post installation script
+	#[arg(long, value_name = "PATH")]
+	pub customize_script: Vec<PathBuf>,
+	/// Run every executable in the directory in the target, in lexical order
+	#[arg(long, value_name = "DIR")]
+	pub customize_dir: Vec<PathBuf>,
+	/// Do not create the built-in user
+	#[arg(
+		long,
+		action = clap::ArgAction::SetTrue,
+		conflicts_with_all = ["user", "password", "password_hashed", "expire_password", "ssh_key"]
+	)]
+	pub no_user: bool,
+	/// The password is hashed with crypt(3), e.g. $y$j9T$...
+	#[arg(long, action = clap::ArgAction::SetTrue)]
+	pub password_hashed: bool,
+	/// Hostname of the target, derived from the device ID if not specified
+	#[arg(long)]
+	pub hostname: Option<String>,
+	/// Timezone of the target, e.g. Asia/Shanghai
+	#[arg(long)]
+	pub timezone: Option<String>,
+	/// Install an SSH public key, or the keys in a file, for the built-in user
+	#[arg(long, value_name = "PATH_OR_KEY")]
+	pub ssh_key: Vec<String>,
+	/// Install an SSH public key, or the keys in a file, for root
+	#[arg(long, value_name = "PATH_OR_KEY")]
+	pub root_ssh_key: Vec<String>,
+	/// Force the built-in user to change the password on the first login.
+	/// Enabled by default if the default password is used without --ssh-key
+	#[arg(long, value_name = "BOOL")]
+	pub expire_password: Option<bool>,
+	/// Keep the images readable by everyone, even if they contain credentials.
+	/// Only allowed with the default password
+	#[arg(long, action = ArgAction::SetTrue)]
+	pub public_artifacts: bool,
+	/// What to do if an image to be built already exists
+	#[arg(long, value_enum, value_name = "POLICY", default_value_t = OverwritePolicy::Replace)]
+	pub overwrite: OverwritePolicy,
+	/// Template of the image names, e.g. "aosc-os_{id}_{variant}_{date}{revision}"
+	#[arg(long, value_name = "TEMPLATE")]
+	pub name_template: Option<NameTemplate>,
+	/// Skip the images which already exist in the output directory
+	#[arg(long, action = ArgAction::SetTrue)]
+	pub resume: bool,
+	/// Build every image even with --resume, and clean up the leftovers of earlier builds
+	#[arg(long, action = ArgAction::SetTrue)]
+	pub force: bool,
+	/// Skip checking for the required programs and the free space before building
+	#[arg(long, action = ArgAction::SetTrue)]
+	pub skip_preflight: bool,
+	/// When to keep the sketch directory of each image after building
+	#[arg(long, value_enum, value_name = "WHEN", default_value_t = KeepWorkdir::OnFailure)]
+	pub keep_workdir: KeepWorkdir,
+	/// Only run these stages of the build, consecutive and separated by commas
+	#[arg(long, value_enum, value_name = "STAGES", value_delimiter = ',')]
+	pub stages: Vec<Stage>,
 	/// Clean up the sketch directory after building
 	#[arg(short = 'c', long, action = clap::ArgAction::SetTrue)]
 	pub cleanup: bool,
 	/// Clean up the bootstrapped system distributions after building
 	#[arg(short = 'C', long, action = clap::ArgAction::SetTrue)]
 	pub cleanup_bootstrap: bool,
+	/// When to use colors in the output
+	#[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorMode::Auto)]
+	pub color: ColorMode,
+	/// How to run commands inside the target
+	#[arg(long, value_enum, value_name = "BACKEND", default_value_t = ChrootBackend::Nspawn)]
+	pub chroot_backend: ChrootBackend,
+	/// Identifier of this invocation, a ULID is generated if not specified
+	#[arg(long, value_name = "ID")]
+	pub build_id: Option<String>,
+	/// Record the external commands being run into a directory
+	#[arg(long, value_name = "DIR", conflicts_with = "replay")]
+	pub record: Option<PathBuf>,
+	/// Replay the external commands recorded in a directory instead of running them
+	#[arg(long, value_name = "DIR", hide = true)]
+	pub replay: Option<PathBuf>,
 	/// The action to take.
 	#[command(subcommand)]
 	pub action: Action,
@@ -316,13 +804,21 @@ pub enum Action {
 		#[arg(short = 'x', long, value_enum, default_value_t = Compression::Xz)]
 		compression: Compression,
 
+		/// Compression level, xz and gzip: 0-9, zstd: 1-22 (9 if not specified)
+		#[arg(long, value_name = "N")]
+		compress_level: Option<u32>,
+
+		/// Threads to compress with (the number of CPUs if not specified, up to 32)
+		#[arg(long, value_name = "N")]
+		compress_threads: Option<u32>,
+
 		/// Variants to generate (All if not specified)
 		#[arg(short = 'V', long, value_enum, num_args = 1.., default_values = vec!["base", "desktop", "server"])]
 		variants: Vec<ImageVariant>,
 
-		/// Revision of the image
-		#[arg(short, long)]
-		revision: Option<u32>,
+		/// Revision of the image, or "auto" for the next free one
+		#[arg(short, long, value_name = "REVISION")]
+		revision: Option<Revision>,
 
 		/// Additional packages to be installed
 		#[arg(short = 'p', long = "packages", num_args = 1..)]
@@ -332,6 +828,46 @@ pub enum Action {
 		#[arg(short = 'T', num_args = 1..)]
 		topics: Option<Vec<String>>,
 
+		/// Size of the images, overriding the size of the variant, e.g. 8G or 10240M
+		#[arg(long, value_name = "SIZE")]
+		image_size: Option<ImageSize>,
+
+		/// Round the image size up to a multiple of this size, in MiB
+		#[arg(long, value_name = "MIB")]
+		round_to: Option<u64>,
+
+		/// Unpartitioned space at the end of the image, in MiB
+		#[arg(long, value_name = "MIB")]
+		trailing_pad: Option<u64>,
+
+		/// Shrink the images to their contents, they are grown on the first boot
+		#[arg(long)]
+		shrink: bool,
+
+		/// Do not check the filesystems of the images before they are finished
+		#[arg(long)]
+		no_fsck: bool,
+
+		/// How to populate the filesystems
+		#[arg(long, value_enum, value_name = "BACKEND", default_value_t = PopulateBackend::Mount)]
+		populate_backend: PopulateBackend,
+
+		/// Derive the disk, partition and filesystem identifiers from this seed
+		#[arg(long, value_name = "SEED")]
+		seed: Option<String>,
+
+		/// Print the build plan without building anything.
+		#[arg(long)]
+		dry_run: bool,
+
+		/// Number of images to build at once
+		#[arg(short, long, value_name = "N", default_value_t = 1)]
+		jobs: usize,
+
+		/// Build the device even if it is deprecated.
+		#[arg(long)]
+		allow_deprecated: bool,
+
 		/// ID or alias of the target device.
 		///
 		/// Can be one of the following:
@@ -340,8 +876,10 @@ pub enum Action {
 		/// - One of the aliases for the device, defined in `device.toml`.
 		/// - Path to the directory containing a `device.toml`.
 		/// - Path to the `device.toml` itself.
+		///
+		/// Picked interactively if not specified on a terminal.
 		#[arg(verbatim_doc_comment)]
-		device: String,
+		device: Option<String>,
 	},
 	/// Build images for all devices.
 	BuildAll {
@@ -353,13 +891,21 @@ pub enum Action {
 		#[arg(short, long, value_enum, default_value_t = Compression::Xz)]
 		compression: Compression,
 
+		/// Compression level, xz and gzip: 0-9, zstd: 1-22 (9 if not specified)
+		#[arg(long, value_name = "N")]
+		compress_level: Option<u32>,
+
+		/// Threads to compress with (the number of CPUs if not specified, up to 32)
+		#[arg(long, value_name = "N")]
+		compress_threads: Option<u32>,
+
 		/// Variants to generate (All if not specified)
 		#[arg(short = 'V', long, value_enum, num_args = 1.., default_values = vec!["base", "desktop", "server"])]
 		variants: Vec<ImageVariant>,
 
-		/// Revision of the image
-		#[arg(short, long)]
-		revision: Option<u32>,
+		/// Revision of the image, or "auto" for the next free one
+		#[arg(short, long, value_name = "REVISION")]
+		revision: Option<Revision>,
 
 		/// Additional packages
 		#[arg(short = 'p', long = "packages", num_args = 1..)]
@@ -368,6 +914,104 @@ pub enum Action {
 		/// Topics to be enrolled
 		#[arg(short = 'T', num_args = 1..)]
 		topics: Option<Vec<String>>,
+
+		/// Size of the images, overriding the size of the variant, e.g. 8G or 10240M
+		#[arg(long, value_name = "SIZE")]
+		image_size: Option<ImageSize>,
+
+		/// Round the image size up to a multiple of this size, in MiB
+		#[arg(long, value_name = "MIB")]
+		round_to: Option<u64>,
+
+		/// Unpartitioned space at the end of the image, in MiB
+		#[arg(long, value_name = "MIB")]
+		trailing_pad: Option<u64>,
+
+		/// Shrink the images to their contents, they are grown on the first boot
+		#[arg(long)]
+		shrink: bool,
+
+		/// Do not check the filesystems of the images before they are finished
+		#[arg(long)]
+		no_fsck: bool,
+
+		/// How to populate the filesystems
+		#[arg(long, value_enum, value_name = "BACKEND", default_value_t = PopulateBackend::Mount)]
+		populate_backend: PopulateBackend,
+
+		/// Derive the disk, partition and filesystem identifiers from this seed
+		#[arg(long, value_name = "SEED")]
+		seed: Option<String>,
+
+		/// Print the build plan without building anything.
+		#[arg(long)]
+		dry_run: bool,
+
+		/// Number of images to build at once
+		#[arg(short, long, value_name = "N", default_value_t = 1)]
+		jobs: usize,
+
+		/// Prune older images in the output directory after a successful build,
+		/// e.g. `keep-last=5,keep-days=30`. See [`RetentionPolicy`].
+		#[arg(long, value_name = "SPEC")]
+		retention: Option<RetentionPolicy>,
+
+		/// Only list the images to be pruned by `--retention`.
+		#[arg(long, requires = "retention", action = ArgAction::SetTrue)]
+		retention_dry_run: bool,
+
+		/// Keep building the other images if one fails.
+		#[arg(long, action = ArgAction::SetTrue)]
+		continue_on_error: bool,
+
+		/// Also build the deprecated devices, which are skipped by default.
+		#[arg(long)]
+		allow_deprecated: bool,
+
+		/// Only include the devices of these architectures
+		#[arg(long, num_args = 1..)]
+		arch: Vec<String>,
+
+		/// Only include the devices of these vendors
+		#[arg(long, num_args = 1..)]
+		vendor: Vec<String>,
+
+		/// Only include the devices with all of these tags
+		#[arg(long, num_args = 1..)]
+		tag: Vec<String>,
+
+		/// Include the devices with any of the tags instead
+		#[arg(long, requires = "tag", action = ArgAction::SetTrue)]
+		any_tag: bool,
+	},
+	/// Apply the partition layout of a device to an image or a block device.
+	Partition {
+		/// Size of the image file in MiB, if it is going to be created.
+		/// Defaults to the size of the base variant.
+		#[arg(short, long)]
+		size: Option<u64>,
+
+		/// Format the partitions with the filesystems defined in the device specification.
+		#[arg(long, action = clap::ArgAction::SetTrue)]
+		format_fs: bool,
+
+		/// Derive the disk, partition and filesystem identifiers from this seed
+		#[arg(long, value_name = "SEED")]
+		seed: Option<String>,
+
+		/// ID or alias of the target device.
+		///
+		/// Can be one of the following:
+		///
+		/// - The exact ID of the device, defined in `device.toml`.
+		/// - One of the aliases for the device, defined in `device.toml`.
+		/// - Path to the directory containing a `device.toml`.
+		/// - Path to the `device.toml` itself.
+		#[arg(verbatim_doc_comment)]
+		device: String,
+
+		/// Path to the image file or the block device to be partitioned.
+		target: PathBuf,
 	},
 	/// Check for validity of the devices registry.
 	Check {
@@ -381,14 +1025,109 @@ pub enum Action {
 		/// - Path to the `device.toml` itself.
 		#[arg(verbatim_doc_comment)]
 		device: Option<String>,
+		/// Also run the build pipeline against a miniature image, requires root.
+		///
+		/// The image is partitioned, formatted and mounted, and the scripts
+		/// are checked for syntax errors. The image is discarded afterwards.
+		#[arg(long)]
+		smoke: bool,
+		/// Fail the devices having unknown keys in their specifications.
+		///
+		/// Without this option, the unknown keys are only warned about.
+		#[arg(long)]
+		strict: bool,
 	},
 	/// List all available devices
 	List {
 		#[arg(short, long, default_value = "pretty")]
 		format: ListFormat,
+
+		/// Only print the IDs and the aliases of the devices, one per line
+		#[arg(long, action = ArgAction::SetTrue, conflicts_with = "format")]
+		list_ids: bool,
+
+		/// Also show the documentation links and the maintainers, pretty format only
+		#[arg(short, long, action = ArgAction::SetTrue, conflicts_with = "list_ids")]
+		long: bool,
+
+		/// Only include the devices of these architectures
+		#[arg(long, num_args = 1..)]
+		arch: Vec<String>,
+
+		/// Only include the devices of these vendors
+		#[arg(long, num_args = 1..)]
+		vendor: Vec<String>,
+
+		/// Only include the devices with all of these tags
+		#[arg(long, num_args = 1..)]
+		tag: Vec<String>,
+
+		/// Include the devices with any of the tags instead
+		#[arg(long, requires = "tag", action = ArgAction::SetTrue)]
+		any_tag: bool,
+	},
+	/// Show the metadata embedded in an image, or the resolved specification of a device.
+	Inspect {
+		/// Variant to resolve the sizes for, devices only
+		#[arg(short = 'V', long, value_enum, default_value = "base")]
+		variant: ImageVariant,
+
+		/// Override the filesystem type of the root filesystem, devices only
+		#[arg(short, long)]
+		fstype: Option<RootFsType>,
+
+		/// Size of the image, overriding the size of the variant, e.g. 8G or 10240M
+		#[arg(long, value_name = "SIZE")]
+		image_size: Option<ImageSize>,
+
+		/// Output format, devices only
+		#[arg(long, value_enum, default_value = "toml")]
+		format: InspectFormat,
+
+		/// Path to the image (can be compressed), or the ID, alias or path of the device.
+		/// Files starting with a partition table, the image metadata or a compression
+		/// header are images.
+		target: String,
 	},
+	/// Clean up the working directory.
+	Clean {
+		/// Also remove the bootstrapped system distributions.
+		#[arg(long, action = ArgAction::SetTrue)]
+		bootstrap: bool,
+	},
+	/// Show the configuration file in use.
+	Config {
+		/// Print the effective configuration.
+		#[arg(long, action = ArgAction::SetTrue)]
+		print: bool,
+	},
+	/// Print the shell completions to stdout.
+	#[command(hide = true)]
+	GenerateCompletions {
+		/// The shell to generate the completions for.
+		#[arg(value_enum)]
+		shell: CompletionShell,
+	},
+	/// Print the man page to stdout.
+	#[command(hide = true)]
+	GenerateManpage,
+}
+
+impl Cmdline {
+	/// The level of the log records to be shown.
+	pub fn log_level(&self) -> LevelFilter {
+		match (self.quiet, self.verbose.max(self.debug as u8)) {
+			(true, _) => LevelFilter::Warn,
+			(false, 0) => LevelFilter::Info,
+			(false, 1) => LevelFilter::Debug,
+			(false, _) => LevelFilter::Trace,
+		}
+	}
 }
 
+/// Compression level used without `--compress-level`.
+pub const DEFAULT_COMPRESS_LEVEL: u32 = 9;
+
 #[doc(hidden)]
 impl Compression {
 	pub fn get_extension(&self) -> &'static str {
@@ -399,4 +1138,61 @@ impl Compression {
 			Compression::None => "",
 		}
 	}
+
+	/// Compression levels supported by the format.
+	pub fn levels(&self) -> Option<RangeInclusive<u32>> {
+		match self {
+			Compression::Xz | Compression::Gzip => Some(0..=9),
+			Compression::Zstd => Some(1..=22),
+			Compression::None => None,
+		}
+	}
+
+	/// Make sure the format supports `--compress-level` and `--compress-threads`.
+	pub fn check_options(&self, level: Option<u32>, threads: Option<u32>) -> Result<()> {
+		let name = format!("{:?}", self).to_lowercase();
+		match (self.levels(), level) {
+			(None, Some(_)) => {
+				bail!("--compress-level can not be used without compression.")
+			}
+			(Some(levels), Some(level)) if !levels.contains(&level) => bail!(
+				"Invalid compression level {} for {}, possible levels are {} to {}.",
+				level,
+				name,
+				levels.start(),
+				levels.end()
+			),
+			_ => (),
+		}
+		match (self, threads) {
+			(_, Some(0)) => bail!("--compress-threads must be at least 1."),
+			(Compression::None, Some(_)) => {
+				bail!("--compress-threads can not be used without compression.")
+			}
+			(Compression::Gzip, Some(n)) if n > 1 => {
+				bail!("gzip does not support compressing with more than one thread.")
+			}
+			_ => Ok(()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_compress_options() {
+		assert!(Compression::Xz.check_options(None, None).is_ok());
+		assert!(Compression::Xz.check_options(Some(0), Some(1)).is_ok());
+		assert!(Compression::Zstd.check_options(Some(22), Some(16)).is_ok());
+		let err = Compression::Xz.check_options(Some(19), None).unwrap_err();
+		assert!(err.to_string().contains("possible levels are 0 to 9"));
+		assert!(Compression::Zstd.check_options(Some(0), None).is_err());
+		assert!(Compression::Gzip.check_options(Some(6), Some(1)).is_ok());
+		assert!(Compression::Gzip.check_options(None, Some(4)).is_err());
+		assert!(Compression::Xz.check_options(None, Some(0)).is_err());
+		assert!(Compression::None.check_options(Some(1), None).is_err());
+		assert!(Compression::None.check_options(None, Some(2)).is_err());
+	}
 }
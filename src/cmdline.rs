@@ -0,0 +1,395 @@
+//! Kernel command line of the device, defined with the `kernel_cmdline` key.
+//!
+//! The command line is either a string or a list of arguments:
+//!
+//! ```toml
+//! kernel_cmdline = "rw console=ttyS0,115200 console=tty0"
+//! # Same as above
+//! kernel_cmdline = ["rw", "console=ttyS0,115200", "console=tty0"]
+//! ```
+//!
+//! It can refer to the partitions created during the build with the
+//! following placeholders:
+//!
+//! - `{ROOT_PARTUUID}`, `{ROOT_UUID}`: PARTUUID and filesystem UUID of the
+//!   root partition.
+//! - `{BOOT_PARTUUID}`, `{BOOT_UUID}`: Same for the boot partition, if any.
+//! - `{PARTn_PARTUUID}`, `{PARTn_UUID}`: Same for the partition `n`.
+//! - `{DISKUUID}`: UUID of the GPT, or the disk identifier of the MBR.
+//! - `{ROOT_LUKS_UUID}`, `{BOOT_LUKS_UUID}`, `{PARTn_LUKS_UUID}`: UUID of
+//!   the LUKS2 container of an encrypted partition, see [`crate::encryption`].
+//!
+//! The `UUID` placeholders are only available for partitions containing a
+//! filesystem, and the `PARTUUID` ones require a partition table. Unknown
+//! placeholders are reported by the `check` action.
+//!
+//! Unless the command line contains a `root=` argument, `root=UUID={ROOT_UUID}`
+//! (or `root=PARTUUID={ROOT_PARTUUID}` for `initrdless` devices) is
+//! prepended to it. If the root partition is encrypted, the initrd unlocks
+//! it with `rd.luks.uuid={ROOT_LUKS_UUID}` instead, and the root is
+//! `root=/dev/mapper/luks-{ROOT_LUKS_UUID}`, both prepended unless given.
+//!
+//! The resolved command line is logged, exported to the scripts as
+//! `$KERNEL_CMDLINE`, substituted for `{KERNEL_CMDLINE}` in the
+//! configuration files written by the `config` bootloader steps, and
+//! recorded in the build report.
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use toml::Value;
+
+use crate::{
+	device::{PartitionMapData, PartitionMapType},
+	filesystem::{FilesystemType, FsUuid},
+	partition::{PartitionSpec, PartitionUsage},
+};
+
+/// Kernel command line, as a list of arguments.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "Value")]
+pub struct KernelCmdline(pub Vec<String>);
+
+impl TryFrom<Value> for KernelCmdline {
+	type Error = anyhow::Error;
+
+	fn try_from(value: Value) -> Result<Self> {
+		match value {
+			Value::String(line) => {
+				Ok(Self(line.split_whitespace().map(str::to_owned).collect()))
+			}
+			Value::Array(args) => {
+				args.into_iter()
+					.map(|arg| {
+						match arg {
+					Value::String(arg) => Ok(arg),
+					v => bail!("Invalid kernel argument '{}', expected a string", v),
+				}
+					})
+					.collect::<Result<_>>()
+					.map(Self)
+			}
+			v => bail!(
+				"Invalid kernel command line '{}', expected a string or a list of strings",
+				v
+			),
+		}
+	}
+}
+
+impl KernelCmdline {
+	/// Make sure the arguments are valid, and the placeholders can be resolved.
+	pub fn check(&self, initrdless: bool, vars: &BTreeMap<String, String>) -> Result<()> {
+		for arg in &self.0 {
+			if arg.is_empty() || arg.contains(char::is_whitespace) {
+				bail!("Argument '{}' is empty or contains white spaces", arg);
+			}
+		}
+		self.resolve(initrdless, vars).map(|_| ())
+	}
+
+	/// Get the full command line, with the `root=` argument and the placeholders resolved.
+	pub fn resolve(&self, initrdless: bool, vars: &BTreeMap<String, String>) -> Result<String> {
+		let mut args = Vec::new();
+		let has = |prefix: &str| self.0.iter().any(|arg| arg.starts_with(prefix));
+		let encrypted = vars.contains_key("ROOT_LUKS_UUID");
+		if !has("root=") {
+			args.push(if initrdless {
+				"root=PARTUUID={ROOT_PARTUUID}"
+			} else if encrypted {
+				// The mapping crypttab names, see [`crate::encryption::target_mapping_name`].
+				"root=/dev/mapper/luks-{ROOT_LUKS_UUID}"
+			} else {
+				"root=UUID={ROOT_UUID}"
+			});
+		}
+		if encrypted && !has("rd.luks.uuid=") {
+			args.push("rd.luks.uuid={ROOT_LUKS_UUID}");
+		}
+		args.extend(self.0.iter().map(String::as_str));
+		substitute(&args.join(" "), vars)
+	}
+}
+
+/// Get the placeholders of the partitions, with the values from `pm_data`.
+///
+/// Without `pm_data` (i.e. before the partitions are created), the values are empty.
+pub fn partition_vars(
+	map: &PartitionMapType,
+	partitions: &[PartitionSpec],
+	pm_data: Option<&PartitionMapData>,
+) -> BTreeMap<String, String> {
+	let has_table = *map != PartitionMapType::None;
+	let mut vars = BTreeMap::new();
+	if has_table {
+		vars.insert(
+			"DISKUUID".to_string(),
+			pm_data.map(|d| d.uuid.clone()).unwrap_or_default(),
+		);
+	}
+	for partition in partitions {
+		let data = pm_data.and_then(|d| d.data.get(&partition.num));
+		let mut prefixes = vec![format!("PART{}", partition.num)];
+		match partition.usage {
+			PartitionUsage::Rootfs => prefixes.push("ROOT".to_string()),
+			PartitionUsage::Boot => prefixes.push("BOOT".to_string()),
+			_ => {}
+		}
+		for prefix in prefixes {
+			if has_table {
+				vars.insert(
+					format!("{}_PARTUUID", prefix),
+					data.map(|d| d.part_uuid.clone()).unwrap_or_default(),
+				);
+			}
+			if partition.filesystem != FilesystemType::None {
+				vars.insert(
+					format!("{}_UUID", prefix),
+					data.and_then(|d| d.fs_uuid.as_ref())
+						.map(FsUuid::to_string)
+						.unwrap_or_default(),
+				);
+			}
+			if partition.encryption.is_some() {
+				vars.insert(
+					format!("{}_LUKS_UUID", prefix),
+					data.and_then(|d| d.luks_uuid.clone()).unwrap_or_default(),
+				);
+			}
+		}
+	}
+	vars
+}
+
+/// Find the placeholders in the text, returns their ranges and names.
+///
+/// A placeholder is `{NAME}`, where `NAME` consists of uppercase letters,
+/// digits and underscores. Shell and GRUB variables (`${NAME}`) and other
+/// braces are left as is.
+fn find_placeholders(text: &str) -> Vec<(usize, usize, &str)> {
+	let mut found = Vec::new();
+	let mut pos = 0;
+	while let Some(start) = text[pos..].find('{').map(|i| pos + i) {
+		pos = start + 1;
+		let Some(len) = text[pos..].find('}') else {
+			break;
+		};
+		let name = &text[pos..pos + len];
+		if name.is_empty()
+			|| !name.chars()
+				.all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+			|| text[..start].ends_with('$')
+		{
+			continue;
+		}
+		pos += len + 1;
+		found.push((start, pos, name));
+	}
+	found
+}
+
+/// Replace the placeholders in the text with their values.
+pub fn substitute(text: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+	let mut result = String::with_capacity(text.len());
+	let mut last = 0;
+	for (start, end, name) in find_placeholders(text) {
+		let value = vars.get(name).context(format!(
+			"Unknown placeholder '{{{}}}', available placeholders are: {}",
+			name,
+			vars.keys()
+				.map(String::as_str)
+				.collect::<Vec<_>>()
+				.join(", ")
+		))?;
+		result += &text[last..start];
+		result += value;
+		last = end;
+	}
+	result += &text[last..];
+	Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::*;
+	use crate::{
+		device::PartitionData,
+		encryption::EncryptionSpec,
+		partition::{PartitionSize, PartitionType, SectorSize},
+	};
+
+	fn partition(num: u32, usage: PartitionUsage, filesystem: FilesystemType) -> PartitionSpec {
+		PartitionSpec {
+			num,
+			part_type: PartitionType::Linux,
+			start_sector: None,
+			align: None,
+			size_in_sectors: PartitionSize::Fixed(SectorSize::Rest),
+			label: None,
+			mountpoint: None,
+			filesystem,
+			mount_opts: None,
+			fs_label: None,
+			part_uuid: None,
+			fs_uuid: None,
+			fs_compression: None,
+			mkfs_options: None,
+			fstab_style: None,
+			ext4_reserved_percent: None,
+			ext4_default_mount_opts: None,
+			ext4_check_interval: None,
+			subvolumes: Vec::new(),
+			default_subvolume: None,
+			install_snapshot: false,
+			attributes: Default::default(),
+			content: None,
+			encryption: None,
+			usage,
+			slots: None,
+			empty_slot_b: false,
+			hybrid_mbr: false,
+			slot: None,
+		}
+	}
+
+	/// The volume ID of the FAT32 boot partition.
+	const BOOT_ID: &str = "1A2B-3C4D";
+	/// The filesystem UUID of the ext4 root partition.
+	const ROOT_UUID: &str = "0d9f8c7b-6a5e-4d3c-8b2a-1f0e9d8c7b6a";
+
+	fn pm_data() -> PartitionMapData {
+		let data = [
+			(1, "p1", Some(BOOT_ID)),
+			(2, "p2", None),
+			(3, "p3", Some(ROOT_UUID)),
+		]
+		.into_iter()
+		.map(|(num, part_uuid, fs_uuid)| {
+			let data = PartitionData {
+				num,
+				part_uuid: part_uuid.to_string(),
+				fs_uuid: fs_uuid.map(|id| id.parse::<FsUuid>().unwrap()),
+				fs_label: None,
+				luks_uuid: None,
+				fsck: None,
+			};
+			(num, data)
+		})
+		.collect::<HashMap<_, _>>();
+		PartitionMapData {
+			uuid: "disk".to_string(),
+			data,
+		}
+	}
+
+	fn vars(pm_data: Option<&PartitionMapData>) -> BTreeMap<String, String> {
+		let partitions = [
+			partition(1, PartitionUsage::Boot, FilesystemType::Fat32),
+			partition(2, PartitionUsage::Data, FilesystemType::None),
+			partition(3, PartitionUsage::Rootfs, FilesystemType::Ext4),
+		];
+		partition_vars(&PartitionMapType::GPT, &partitions, pm_data)
+	}
+
+	#[test]
+	fn test_parse_cmdline() -> Result<()> {
+		let expected = KernelCmdline(vec!["rw".into(), "console=ttyS0,115200".into()]);
+		let line = Value::String("  rw\tconsole=ttyS0,115200 ".into());
+		assert_eq!(KernelCmdline::try_from(line)?, expected);
+		let args = Value::Array(vec!["rw".into(), "console=ttyS0,115200".into()]);
+		assert_eq!(KernelCmdline::try_from(args)?, expected);
+		assert!(KernelCmdline::try_from(Value::Array(vec![Value::Integer(1)])).is_err());
+		assert!(KernelCmdline::try_from(Value::Boolean(true)).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_resolve_cmdline() -> Result<()> {
+		let pm_data = pm_data();
+		let vars = vars(Some(&pm_data));
+		let cmdline =
+			KernelCmdline(vec!["rw".into(), "resume=PARTUUID={PART2_PARTUUID}".into()]);
+		assert_eq!(
+			cmdline.resolve(false, &vars)?,
+			format!("root=UUID={} rw resume=PARTUUID=p2", ROOT_UUID)
+		);
+		assert_eq!(
+			cmdline.resolve(true, &vars)?,
+			"root=PARTUUID=p3 rw resume=PARTUUID=p2"
+		);
+		let cmdline = KernelCmdline(vec!["root=/dev/mmcblk0p{PART3_PARTUUID}".into()]);
+		assert_eq!(cmdline.resolve(false, &vars)?, "root=/dev/mmcblk0pp3");
+		let cmdline =
+			KernelCmdline(vec!["boot={BOOT_UUID}".into(), "disk={DISKUUID}".into()]);
+		assert_eq!(
+			cmdline.resolve(false, &vars)?,
+			format!("root=UUID={} boot={} disk=disk", ROOT_UUID, BOOT_ID)
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_resolve_encrypted_root() -> Result<()> {
+		let mut root = partition(3, PartitionUsage::Rootfs, FilesystemType::Ext4);
+		root.encryption = Some(EncryptionSpec::Luks2 {
+			passphrase_file: "passphrase.txt".into(),
+			key_slot: None,
+			pbkdf: None,
+			iter_time: None,
+		});
+		let partitions = [
+			partition(1, PartitionUsage::Boot, FilesystemType::Fat32),
+			root,
+		];
+		let mut pm_data = pm_data();
+		pm_data.data.get_mut(&3).unwrap().luks_uuid = Some("luks3".into());
+		let vars = partition_vars(&PartitionMapType::GPT, &partitions, Some(&pm_data));
+		assert_eq!(vars["ROOT_LUKS_UUID"], "luks3");
+		assert_eq!(vars["PART3_LUKS_UUID"], "luks3");
+		assert!(!vars.contains_key("BOOT_LUKS_UUID"));
+		let cmdline = KernelCmdline(vec!["rw".into()]);
+		assert_eq!(
+			cmdline.resolve(false, &vars)?,
+			"root=/dev/mapper/luks-luks3 rd.luks.uuid=luks3 rw"
+		);
+		let cmdline = KernelCmdline(vec![
+			"root=UUID={ROOT_UUID}".into(),
+			"rd.luks.uuid=luks-{ROOT_LUKS_UUID}".into(),
+		]);
+		assert_eq!(
+			cmdline.resolve(false, &vars)?,
+			format!("root=UUID={} rd.luks.uuid=luks-luks3", ROOT_UUID)
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_cmdline() {
+		let vars = vars(None);
+		let check = |args: &[&str]| {
+			KernelCmdline(args.iter().map(|&s| s.to_string()).collect())
+				.check(false, &vars)
+		};
+		assert!(check(&["rw", "{ROOT_PARTUUID}", "{PART1_UUID}"]).is_ok());
+		// Partition 2 has no filesystem
+		assert!(check(&["resume=UUID={PART2_UUID}"]).is_err());
+		assert!(check(&["{PART4_PARTUUID}"]).is_err());
+		assert!(check(&["{ROOT}"]).is_err());
+		assert!(check(&["console=tty0 rw"]).is_err());
+	}
+
+	#[test]
+	fn test_substitute() -> Result<()> {
+		let mut vars = BTreeMap::new();
+		vars.insert("KERNEL_CMDLINE".to_string(), "root=UUID=1 rw".to_string());
+		let template = "menuentry 'AOSC OS' {\n\tlinux /vmlinuz {KERNEL_CMDLINE} ${extra}\n\tset a=${ROOT}\n}\n";
+		assert_eq!(
+			substitute(template, &vars)?,
+			"menuentry 'AOSC OS' {\n\tlinux /vmlinuz root=UUID=1 rw ${extra}\n\tset a=${ROOT}\n}\n"
+		);
+		assert!(substitute("{ROOT_UUID}", &vars).is_err());
+		Ok(())
+	}
+}
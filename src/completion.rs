@@ -0,0 +1,59 @@
+//! Shell completions and the man page, for the packagers.
+//!
+//! Both are generated from the command line definition and printed to
+//! stdout:
+//!
+//! ```shell
+//! $ mkrawimg generate-completions bash > /usr/share/bash-completion/completions/mkrawimg
+//! $ mkrawimg generate-completions zsh > /usr/share/zsh/site-functions/_mkrawimg
+//! $ mkrawimg generate-completions fish > /usr/share/fish/vendor_completions.d/mkrawimg.fish
+//! $ mkrawimg generate-manpage > /usr/share/man/man1/mkrawimg.1
+//! ```
+//!
+//! The generated completions do not know the devices in the registry. The
+//! completion scripts can get them from `mkrawimg -q list --list-ids`, which
+//! prints the IDs and the aliases of the devices, one per line.
+use std::io::{self, Write};
+
+use anyhow::Result;
+use clap::{CommandFactory, ValueEnum};
+use clap_complete::Shell;
+use clap_mangen::Man;
+
+use crate::cli::Cmdline;
+
+/// Shells to generate the completions for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CompletionShell {
+	Bash,
+	Zsh,
+	Fish,
+}
+
+impl From<CompletionShell> for Shell {
+	fn from(value: CompletionShell) -> Self {
+		match value {
+			CompletionShell::Bash => Shell::Bash,
+			CompletionShell::Zsh => Shell::Zsh,
+			CompletionShell::Fish => Shell::Fish,
+		}
+	}
+}
+
+/// Print the completions for the shell to stdout.
+pub fn generate_completions(shell: CompletionShell) -> Result<()> {
+	let mut cmd = Cmdline::command();
+	let name = cmd.get_name().to_owned();
+	let mut stdout = io::stdout().lock();
+	clap_complete::generate(Shell::from(shell), &mut cmd, name, &mut stdout);
+	stdout.flush()?;
+	Ok(())
+}
+
+/// Print the man page to stdout.
+pub fn generate_manpage() -> Result<()> {
+	let mut stdout = io::stdout().lock();
+	Man::new(Cmdline::command()).render(&mut stdout)?;
+	stdout.flush()?;
+	Ok(())
+}
@@ -0,0 +1,426 @@
+//! Defaults of the command line options, read from a configuration file.
+//!
+//! The configuration file is read from `--config PATH`, or
+//! [`DEFAULT_CONFIG`] if it exists. The keys are the names of the fields of
+//! [`Cmdline`], and the options of an action are placed in a table named
+//! after the action:
+//!
+//! ```toml
+//! workdir = "/srv/mkrawimg/work"
+//! outdir = "/srv/mkrawimg/out"
+//! mirror = ["https://mirrors.example.org/anthon/debs"]
+//! user = "aosc"
+//! keep_workdir = "never"
+//!
+//! [build-all]
+//! compression = "zstd"
+//! jobs = 4
+//! ```
+//!
+//! Options specified on the command line take precedence over the ones in
+//! the configuration file, including the ones conflicting with them, e.g.
+//! `--no-user` drops `user` in the configuration file. Lists are replaced,
+//! not merged. Unknown keys are rejected.
+//!
+//! `mkrawimg config --print` prints the effective configuration, with the
+//! passwords redacted.
+use std::{
+	ffi::OsString,
+	fs,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{
+	parser::ValueSource, Arg, ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches,
+};
+
+use crate::{cli::Cmdline, runner::REDACTED};
+
+/// The configuration file read if `--config` is not specified.
+pub const DEFAULT_CONFIG: &str = "/etc/mkrawimg.toml";
+
+/// Options which can not be set in the configuration file.
+const EXCLUDED: &[&str] = &["help", "version", "config"];
+/// Options redacted from the effective configuration.
+const SECRETS: &[&str] = &["password", "password_hashed"];
+
+/// The configuration file in use, and the command line merged with it.
+pub struct Config {
+	pub path: Option<PathBuf>,
+	/// Options of the actions, by the name of the action.
+	actions: toml::Table,
+	/// Global options taken from the configuration file.
+	applied: Vec<String>,
+	matches: ArgMatches,
+}
+
+/// Parse the command line, merged with the configuration file.
+pub fn parse_cmdline<I: IntoIterator<Item = OsString>>(args: I) -> Result<(Cmdline, Config)> {
+	let args = args.into_iter().collect::<Vec<_>>();
+	let mut cmd = Cmdline::command();
+	cmd.build();
+	let matches = cmd.clone().try_get_matches_from(&args)?;
+	let path = match matches.get_one::<PathBuf>("config") {
+		Some(path) => Some(path.to_owned()),
+		None => Some(PathBuf::from(DEFAULT_CONFIG)).filter(|p| p.exists()),
+	};
+	let Some(path) = path else {
+		let cmdline = Cmdline::from_arg_matches(&matches)?;
+		return Ok((
+			cmdline,
+			Config {
+				path: None,
+				actions: toml::Table::new(),
+				applied: Vec::new(),
+				matches,
+			},
+		));
+	};
+	let content = fs::read_to_string(&path).context(format!(
+		"Unable to read the configuration file {}",
+		path.display()
+	))?;
+	let table: toml::Table = toml::from_str(&content)
+		.context(format!("Invalid configuration file {}", path.display()))?;
+	let mut global_args = Vec::new();
+	let mut applied = Vec::new();
+	let mut actions = toml::Table::new();
+	for (key, value) in &table {
+		let err = |e: anyhow::Error| config_error(&path, &content, None, key, e);
+		if let (Some(sub), toml::Value::Table(options)) = (cmd.find_subcommand(key), value)
+		{
+			for (opt, value) in options {
+				option_args(sub, opt, value).map_err(|e| {
+					config_error(&path, &content, Some(key), opt, e)
+				})?;
+			}
+			actions.insert(key.to_owned(), value.clone());
+			continue;
+		}
+		let args = option_args(&cmd, key, value).map_err(err)?;
+		if !overridden(&cmd, &matches, key) {
+			global_args.extend(args);
+			applied.push(key.to_owned());
+		}
+	}
+	let mut action_args = Vec::new();
+	if let Some((name, sub_matches)) = matches.subcommand() {
+		let sub = cmd.find_subcommand(name).unwrap();
+		if let Some(toml::Value::Table(options)) = actions.get(name) {
+			for (opt, value) in options {
+				if !overridden(sub, sub_matches, opt) {
+					action_args.extend(option_args(sub, opt, value)?);
+				}
+			}
+		}
+	}
+	let mut merged = vec![args[0].clone()];
+	merged.extend(global_args);
+	match subcommand_index(&cmd, &args) {
+		Some(idx) => {
+			merged.extend_from_slice(&args[1..=idx]);
+			merged.extend(action_args);
+			merged.extend_from_slice(&args[idx + 1..]);
+		}
+		None => merged.extend_from_slice(&args[1..]),
+	}
+	let matches = cmd.try_get_matches_from(merged).map_err(|e| {
+		anyhow!(e).context(format!(
+			"Invalid options with the configuration file {}",
+			path.display()
+		))
+	})?;
+	let cmdline = Cmdline::from_arg_matches(&matches)?;
+	Ok((
+		cmdline,
+		Config {
+			path: Some(path),
+			actions,
+			applied,
+			matches,
+		},
+	))
+}
+
+/// Whether the option, or one conflicting with it, is specified on the command line.
+fn overridden(cmd: &Command, matches: &ArgMatches, id: &str) -> bool {
+	let Some(arg) = cmd.get_arguments().find(|a| a.get_id().as_str() == id) else {
+		return false;
+	};
+	cmd.get_arguments()
+		.filter(|a| {
+			matches.value_source(a.get_id().as_str()) == Some(ValueSource::CommandLine)
+		})
+		.any(|a| {
+			a.get_id() == arg.get_id()
+				|| cmd.get_arg_conflicts_with(a)
+					.iter()
+					.any(|c| c.get_id() == arg.get_id())
+				|| cmd.get_arg_conflicts_with(arg)
+					.iter()
+					.any(|c| c.get_id() == a.get_id())
+		})
+}
+
+/// Turn an option in the configuration file into command line arguments.
+fn option_args(cmd: &Command, key: &str, value: &toml::Value) -> Result<Vec<OsString>> {
+	let arg = cmd
+		.get_arguments()
+		.find(|a| a.get_id().as_str() == key && !EXCLUDED.contains(&key))
+		.ok_or_else(|| anyhow!("Unknown key"))?;
+	if arg.is_positional() {
+		bail!("Arguments can not be set in the configuration file");
+	}
+	let flag = match (arg.get_long(), arg.get_short()) {
+		(Some(long), _) => format!("--{}", long),
+		(None, Some(short)) => format!("-{}", short),
+		(None, None) => bail!("Unknown key"),
+	};
+	let scalar = |value: &toml::Value| -> Result<String> {
+		match value {
+			toml::Value::String(s) => Ok(s.to_owned()),
+			toml::Value::Integer(i) => Ok(i.to_string()),
+			toml::Value::Float(f) => Ok(f.to_string()),
+			toml::Value::Boolean(b) => Ok(b.to_string()),
+			_ => bail!("Expected a string, a number or a boolean"),
+		}
+	};
+	let values = match (arg.get_action(), value) {
+		(ArgAction::SetTrue, toml::Value::Boolean(true)) => return Ok(vec![flag.into()]),
+		(ArgAction::SetTrue, toml::Value::Boolean(false)) => return Ok(vec![]),
+		(ArgAction::SetTrue, _) => bail!("Expected a boolean"),
+		(ArgAction::Count, toml::Value::Integer(n)) if (0..=u8::MAX as i64).contains(n) => {
+			return Ok(vec![flag.into(); *n as usize])
+		}
+		(ArgAction::Count, _) => bail!("Expected a number"),
+		(ArgAction::Append, toml::Value::Array(values)) => {
+			values.iter().map(scalar).collect::<Result<Vec<_>>>()?
+		}
+		(_, toml::Value::Array(_)) => bail!("Expected a single value"),
+		(_, value) => vec![scalar(value)?],
+	};
+	Ok(values
+		.into_iter()
+		.map(|v| match arg.get_long() {
+			Some(_) => format!("{}={}", flag, v).into(),
+			None => format!("{}{}", flag, v).into(),
+		})
+		.collect())
+}
+
+/// Index of the action in the arguments, skipping the global options and their values.
+fn subcommand_index(cmd: &Command, args: &[OsString]) -> Option<usize> {
+	let takes_value = |arg: Option<&Arg>| arg.is_some_and(|a| a.get_action().takes_values());
+	let mut idx = 1;
+	while idx < args.len() {
+		let token = args[idx].to_string_lossy();
+		if token == "--" {
+			return None;
+		}
+		if let Some(long) = token.strip_prefix("--") {
+			if !long.contains('=')
+				&& takes_value(
+					cmd.get_arguments().find(|a| a.get_long() == Some(long)),
+				) {
+				idx += 1;
+			}
+		} else if let Some(shorts) = token.strip_prefix('-').filter(|s| !s.is_empty()) {
+			// The rest of the cluster is the value of the first option taking one.
+			for (pos, c) in shorts.char_indices() {
+				if takes_value(
+					cmd.get_arguments().find(|a| a.get_short() == Some(c)),
+				) {
+					if pos + c.len_utf8() == shorts.len() {
+						idx += 1;
+					}
+					break;
+				}
+			}
+		} else {
+			return Some(idx);
+		}
+		idx += 1;
+	}
+	None
+}
+
+/// Line number of the key in the configuration file, `table` for the options of an action.
+fn key_line(content: &str, table: Option<&str>, key: &str) -> Option<usize> {
+	let mut current = None;
+	for (idx, line) in content.lines().enumerate() {
+		let line = line.split('#').next().unwrap_or_default().trim();
+		if let Some(header) = line.strip_prefix('[') {
+			current = Some(header.trim_end_matches(']').trim().trim_matches('"'));
+			continue;
+		}
+		let name = line
+			.split('=')
+			.next()
+			.unwrap_or_default()
+			.trim()
+			.trim_matches('"');
+		if current == table && name == key {
+			return Some(idx + 1);
+		}
+	}
+	None
+}
+
+fn config_error(
+	path: &Path,
+	content: &str,
+	table: Option<&str>,
+	key: &str,
+	e: anyhow::Error,
+) -> anyhow::Error {
+	let key = match table {
+		Some(table) => format!("{}.{}", table, key),
+		None => key.to_owned(),
+	};
+	let location = match key_line(content, table, key.rsplit('.').next().unwrap()) {
+		Some(line) => format!("{}:{}", path.display(), line),
+		None => path.display().to_string(),
+	};
+	e.context(format!("Invalid key '{}' at {}", key, location))
+}
+
+impl Config {
+	/// Show the configuration file in use, and the effective configuration with `print`.
+	pub fn show(&self, print: bool) -> Result<()> {
+		match &self.path {
+			Some(path) => println!("# Configuration file: {}", path.display()),
+			None => println!("# No configuration file in use."),
+		}
+		if print {
+			print!("{}", self.effective()?);
+		}
+		Ok(())
+	}
+
+	/// The effective configuration, in the format of the configuration file.
+	fn effective(&self) -> Result<String> {
+		let mut result = String::new();
+		let cmd = Cmdline::command();
+		for arg in cmd.get_arguments() {
+			let id = arg.get_id().as_str();
+			if EXCLUDED.contains(&id) || arg.is_positional() {
+				continue;
+			}
+			let value = match arg.get_action() {
+				ArgAction::SetTrue => {
+					toml::Value::Boolean(self.matches.get_flag(id))
+				}
+				ArgAction::Count => {
+					toml::Value::Integer(self.matches.get_count(id) as i64)
+				}
+				action => {
+					let Some(raw) = self.matches.get_raw(id) else {
+						continue;
+					};
+					let mut values = raw
+						.map(|v| {
+							toml::Value::String(
+								v.to_string_lossy().into_owned(),
+							)
+						})
+						.collect::<Vec<_>>();
+					if matches!(action, ArgAction::Append) {
+						toml::Value::Array(values)
+					} else if let Some(value) = values.pop() {
+						value
+					} else {
+						continue;
+					}
+				}
+			};
+			let value = if SECRETS.contains(&id) {
+				toml::Value::String(REDACTED.to_owned())
+			} else {
+				value
+			};
+			let source = match self.matches.value_source(id) {
+				_ if self.applied.iter().any(|k| k == id) => "configuration file",
+				Some(ValueSource::CommandLine) => "command line",
+				_ => "default",
+			};
+			result += &format!("{} = {} # {}\n", id, value, source);
+		}
+		for (name, options) in &self.actions {
+			result += &format!(
+				"\n[{}]\n{}\n",
+				name,
+				toml::to_string(options)?.trim_end()
+			);
+		}
+		Ok(result)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	#[test]
+	fn test_key_line() {
+		let content = "workdir = \"/srv/work\"\n# comment\nuser=\"aosc\"\n\n[build-all] # options\njobs = 4\n\"user\" = 1\n";
+		assert_eq!(key_line(content, None, "workdir"), Some(1));
+		assert_eq!(key_line(content, None, "user"), Some(3));
+		assert_eq!(key_line(content, None, "jobs"), None);
+		assert_eq!(key_line(content, Some("build-all"), "jobs"), Some(6));
+		assert_eq!(key_line(content, Some("build-all"), "user"), Some(7));
+	}
+
+	#[test]
+	fn test_parse_cmdline() -> Result<()> {
+		let dir = TempDir::new("config")?;
+		let config = dir.join("mkrawimg.toml");
+		fs::write(
+			&config,
+			"workdir = \"/srv/work\"\nuser = \"builder\"\nverbose = 1\nmirror = [\"https://a.example.org/debs\"]\n\n[build]\ncompression = \"zstd\"\n",
+		)?;
+		let args = |args: &[&str]| {
+			["mkrawimg", "--config", config.to_str().unwrap()]
+				.iter()
+				.chain(args)
+				.map(OsString::from)
+				.collect::<Vec<_>>()
+		};
+		let (cmdline, _) = parse_cmdline(args(&["-D", "/tmp/work", "build", "rpi-5b"]))?;
+		assert_eq!(cmdline.workdir, Path::new("/tmp/work"));
+		assert_eq!(cmdline.user, "builder");
+		assert_eq!(cmdline.verbose, 1);
+		assert_eq!(cmdline.mirror.len(), 1);
+		match cmdline.action {
+			crate::cli::Action::Build {
+				compression,
+				device,
+				..
+			} => {
+				assert_eq!(compression, crate::cli::Compression::Zstd);
+				assert_eq!(device.as_deref(), Some("rpi-5b"));
+			}
+			_ => panic!("Expected the build action"),
+		}
+		// Conflicting options on the command line win.
+		let (cmdline, _) = parse_cmdline(args(&["--no-user", "-q", "list"]))?;
+		assert!(cmdline.no_user && cmdline.quiet);
+		assert_eq!(cmdline.verbose, 0);
+		let (_, effective) = parse_cmdline(args(&["-P", "s3cret", "list"]))?;
+		let effective = effective.effective()?;
+		assert!(effective.contains("workdir = \"/srv/work\" # configuration file\n"));
+		assert!(effective.contains("password = \"<redacted>\" # command line\n"));
+		assert!(!effective.contains("s3cret"));
+		fs::write(
+			&config,
+			"workdir = \"/srv/work\"\n\n[build]\ncompresion = \"zstd\"\n",
+		)?;
+		let err = parse_cmdline(args(&["list"])).err().unwrap();
+		assert!(format!("{:#}", err).contains(&format!(
+			"Invalid key 'build.compresion' at {}:4",
+			config.display()
+		)));
+		Ok(())
+	}
+}
@@ -0,0 +1,284 @@
+//! Raw contents of the partitions without a filesystem.
+//!
+//! Some devices need a partition holding a raw blob instead of a
+//! filesystem, e.g. the environment of a vendor bootloader or a FPGA
+//! bitstream. Such a partition has no filesystem, and names the file to be
+//! written to it with `content`:
+//!
+//! ```toml
+//! [[partition]]
+//! num = 2
+//! type = "linux"
+//! usage = "other"
+//! filesystem = "none"
+//! size = "8MiB"
+//! # Relative to the directory of device.toml
+//! content = { type = "raw", path = "env.img" }
+//! ```
+//!
+//! With `from_target = true`, the path is an absolute path within the
+//! target root filesystem instead, for the blobs installed by packages:
+//!
+//! ```toml
+//! content = { type = "raw", path = "/usr/lib/fpga/bitstream.bin", from_target = true }
+//! ```
+//!
+//! Files within the target can also be the outputs of the bootloader steps
+//! (see [`BootloaderStep`]), e.g. a signed kernel:
+//!
+//! ```toml
+//! content = { type = "raw", path = "{{step:vbutil:output}}", from_target = true }
+//! ```
+//!
+//! The file is written to the start of the partition, and the rest of the
+//! partition is zeroed. The files from the directory of `device.toml` are
+//! written right after the partitions are formatted, and the ones from the
+//! target after the bootloaders are applied.
+//!
+//! Such partitions are neither formatted nor mounted, and have no
+//! filesystem UUID. `check` makes sure the files from the directory of
+//! `device.toml` exist within it and fit in the partitions, the files from
+//! the target are checked when they are written.
+//!
+//! [`BootloaderStep`]: crate::bootloader::BootloaderStep
+use std::{
+	fs::File,
+	io::{self, copy, BufReader, Read, Seek, SeekFrom, Write},
+	path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	bootloader::{resolve_step_vars, BootloaderStep, STEP_VAR_PREFIX},
+	context::ImageContext,
+};
+
+/// Contents written to a partition instead of a filesystem, see the [module documentation](self).
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PartitionContent {
+	/// A file written as is, followed by zeros.
+	Raw {
+		path: PathBuf,
+		/// Whether the path is within the target root filesystem, rather than
+		/// relative to the directory of `device.toml`.
+		#[serde(default)]
+		from_target: bool,
+	},
+}
+
+impl PartitionContent {
+	/// Whether the file is installed in the target.
+	pub fn is_from_target(&self) -> bool {
+		match self {
+			Self::Raw { from_target, .. } => *from_target,
+		}
+	}
+
+	/// Path to the file, either in the directory of `device.toml` or in the target root filesystem.
+	///
+	/// The outputs of the bootloader `steps` are resolved in the paths within the target.
+	pub fn source(
+		&self,
+		spec_dir: &Path,
+		root: &Path,
+		steps: &[BootloaderStep],
+	) -> Result<PathBuf> {
+		let Self::Raw { path, from_target } = self;
+		if !*from_target {
+			return Ok(spec_dir.join(path));
+		}
+		let path = resolve_step_vars(path, steps)?;
+		// Joining paths with a leading slash replaces the whole path
+		Ok(root.join(path.to_string_lossy().trim_start_matches('/')))
+	}
+
+	/// Make sure the file exists and fits in the partition of `capacity` bytes, if known.
+	///
+	/// Files from the target can not be checked before the build, except
+	/// that the outputs of the bootloader `steps` they refer to are declared.
+	pub fn check(
+		&self,
+		spec_dir: &Path,
+		capacity: Option<u64>,
+		steps: &[BootloaderStep],
+	) -> Result<()> {
+		let Self::Raw { path, from_target } = self;
+		if !*from_target && path.to_string_lossy().contains(STEP_VAR_PREFIX) {
+			bail!("The outputs of the bootloader steps are within the target, set from_target = true to use them");
+		}
+		if *from_target {
+			let path = resolve_step_vars(path, steps)?;
+			if !path.is_absolute() {
+				bail!(
+					"'{}' must be an absolute path within the target",
+					path.display()
+				);
+			}
+			return Ok(());
+		}
+		// The file must not be read from outside of the directory.
+		if path.is_absolute() {
+			bail!(
+				"'{}' must be relative to the directory of the device.toml, set from_target = true for the files within the target",
+				path.display()
+			);
+		}
+		if path.components().any(|c| c == Component::ParentDir) {
+			bail!("'{}' can not contain '..'", path.display());
+		}
+		let src = spec_dir.join(path);
+		let len = src
+			.metadata()
+			.ok()
+			.filter(|m| m.is_file())
+			.context(format!(
+				"'{}' is not found within the same directory as the device.toml",
+				path.display()
+			))?
+			.len();
+		if let Some(capacity) = capacity {
+			if len > capacity {
+				bail!(
+					"'{}' ({} bytes) does not fit in the partition ({} bytes)",
+					path.display(),
+					len,
+					capacity
+				);
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Write the file to the start of the partition, and zero the rest of it.
+pub fn write_raw(src: &Path, partition: &Path) -> Result<()> {
+	let src_fd = File::open(src).context(format!("Unable to open {}", src.display()))?;
+	let len = src_fd.metadata()?.len();
+	let mut dst_fd = File::options()
+		.write(true)
+		.truncate(false)
+		.append(false)
+		.open(partition)?;
+	// The size of a block device is only known by seeking to its end.
+	let capacity = dst_fd.seek(SeekFrom::End(0))?;
+	if len > capacity {
+		bail!(
+			"{} ({} bytes) does not fit in {} ({} bytes)",
+			src.display(),
+			len,
+			partition.display(),
+			capacity
+		);
+	}
+	dst_fd.seek(SeekFrom::Start(0))?;
+	copy(&mut BufReader::new(src_fd), &mut dst_fd)?;
+	copy(&mut io::repeat(0).take(capacity - len), &mut dst_fd)?;
+	dst_fd.flush()?;
+	dst_fd.sync_all()?;
+	Ok(())
+}
+
+impl ImageContext<'_> {
+	/// Write the raw contents of the partitions.
+	///
+	/// Without `root`, the files from the directory of `device.toml` are
+	/// written, otherwise the ones from the target root filesystem.
+	pub fn write_partition_contents(&self, loopdev: &Path, root: Option<&Path>) -> Result<()> {
+		let spec_dir =
+			self.device.file_path.parent().context(
+				"Failed to reach the directory containing the device spec file",
+			)?;
+		let steps = self.device.bootloaders.as_deref().unwrap_or_default();
+		for partition in &self.device.partitions {
+			let Some(content) = &partition.content else {
+				continue;
+			};
+			let src = match root {
+				Some(root) if content.is_from_target() => {
+					content.source(spec_dir, root, steps)?
+				}
+				None if !content.is_from_target() => {
+					content.source(spec_dir, Path::new("/"), steps)?
+				}
+				_ => continue,
+			};
+			let dst = self.device.partition_path(loopdev, partition.num);
+			self.info(format!(
+				"Writing {} to partition {} ...",
+				src.display(),
+				partition.num
+			));
+			write_raw(&src, Path::new(&dst)).context(format!(
+				"Failed to write the contents of partition {}",
+				partition.num
+			))?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use super::*;
+	use crate::testutil::TempDir;
+
+	#[test]
+	fn test_write_raw() -> Result<()> {
+		let dir = TempDir::new("content")?;
+		let src = dir.join("env.img");
+		let partition = dir.join("partition");
+		fs::write(&src, [0x5a; 1000])?;
+		// Leftovers of an earlier build
+		fs::write(&partition, [0xff; 4096])?;
+		write_raw(&src, &partition)?;
+		let written = fs::read(&partition)?;
+		assert_eq!(written.len(), 4096);
+		assert!(written[..1000].iter().all(|&b| b == 0x5a));
+		assert!(written[1000..].iter().all(|&b| b == 0));
+		fs::write(&partition, [0; 512])?;
+		let err = write_raw(&src, &partition).unwrap_err();
+		assert!(err.to_string().contains("does not fit"));
+		let content = PartitionContent::Raw {
+			path: "env.img".into(),
+			from_target: false,
+		};
+		assert!(content.check(&dir, Some(1000), &[]).is_ok());
+		assert!(content.check(&dir, Some(999), &[]).is_err());
+		assert!(content.check(&dir, None, &[]).is_ok());
+		assert!(content.check(Path::new("/nonexistent"), None, &[]).is_err());
+		// Files outside of the directory
+		fs::create_dir(dir.join("bsp"))?;
+		for path in [dir.join("env.img"), "bsp/../env.img".into()] {
+			let content = PartitionContent::Raw {
+				path,
+				from_target: false,
+			};
+			assert!(content.check(&dir, None, &[]).is_err());
+		}
+		let content = PartitionContent::Raw {
+			path: "usr/lib/fpga/bitstream.bin".into(),
+			from_target: true,
+		};
+		assert!(content.check(&dir, None, &[]).is_err());
+		// Outputs of the bootloader steps are only found within the target
+		let content = PartitionContent::Raw {
+			path: "{{step:vbutil:output}}".into(),
+			from_target: false,
+		};
+		assert!(content.check(&dir, None, &[]).is_err());
+		let content = PartitionContent::Raw {
+			path: "usr/lib/fpga/bitstream.bin".into(),
+			from_target: true,
+		};
+		assert_eq!(
+			content.source(&dir, Path::new("/mnt/root"), &[])?,
+			Path::new("/mnt/root/usr/lib/fpga/bitstream.bin")
+		);
+		Ok(())
+	}
+}
@@ -2,29 +2,45 @@ use core::time;
 use std::{
 	fs::{create_dir_all, File},
 	io::{copy, BufReader, BufWriter, Write},
+	os::unix::fs::{FileTypeExt, OpenOptionsExt},
 	path::{Path, PathBuf},
+	sync::Mutex,
 	thread,
 	time::{Duration, Instant},
 };
 
 use crate::{
-	cli::Compression,
-	device::{DeviceArch, DeviceSpec, PartitionMapData, PartitionMapType},
-	filesystem::FilesystemType,
-	partition::PartitionUsage,
+	chroot,
+	clean::format_size,
+	cli::{Compression, KeepWorkdir, DEFAULT_COMPRESS_LEVEL},
+	device::{pad_image_size, DeviceArch, DeviceSpec, PartitionMapData, PartitionMapType},
+	filesystem::{FilesystemType, FsUuid},
+	interrupt,
+	joblog::JobLogger,
+	partition::{PartitionUsage, SECTOR_SIZE},
+	plan::plan_layout,
 	pm::{Distro, Oma, PackageManager, APT},
+	populate::PopulateBackend,
+	report::ImageRecord,
+	rootfs::RootfsSource,
+	sshkey::SshPublicKey,
+	stage::{Stage, StageMarker, StageTiming},
 	topics::{save_topics, Topic},
 	utils::{
-		add_user, create_sparse_file, refresh_partition_table, restore_term, rsync_sysroot,
-		run_script_with_chroot, set_locale, setup_scroll_region, sync_filesystem,
+		add_user, check_block_device_unused, copy_sparse, create_sparse_file,
+		expire_password, find_wifi_psk, get_partition_path, is_password_expired,
+		loop_devices_backed_by, mounts_under, progress_bar_enabled,
+		refresh_partition_table, release_leftovers, restore_term, restrict_artifact,
+		rsync_sysroot, run_script_with_chroot, set_locale, set_timezone,
+		setup_scroll_region, sync_filesystem, DEFAULT_PASSWORD,
 	},
 };
 use anyhow::{bail, Context, Result};
 use clap::ValueEnum;
-use log::{debug, info, warn};
-use loopdev::LoopControl;
+use log::{debug, info};
+use loopdev::{LoopControl, LoopDevice};
 use strum::{Display, VariantArray};
-use sys_mount::{unmount, Mount, UnmountFlags};
+use sys_mount::{unmount, Mount, MountFlags, UnmountFlags};
 use termsize::Size;
 
 #[derive(Copy, Clone, Debug, Display, PartialEq, Eq, PartialOrd, Ord, ValueEnum, VariantArray)]
@@ -43,47 +59,184 @@ pub struct ImageContext<'a> {
 	pub variant: &'a ImageVariant,
 	pub workdir: &'a Path,
 	pub outdir: &'a Path,
-	pub user: &'a str,
+	/// The built-in user, `None` with `--no-user`.
+	pub user: Option<&'a str>,
 	pub password: &'a str,
+	/// Whether the password is hashed with crypt(3).
+	pub password_hashed: bool,
+	/// Overrides the hostname of the device.
+	pub hostname: Option<&'a str>,
+	/// Overrides the timezone of the device.
+	pub timezone: Option<&'a str>,
+	/// SSH public keys of the built-in user.
+	pub ssh_keys: &'a [SshPublicKey],
+	/// SSH public keys of root.
+	pub root_ssh_keys: &'a [SshPublicKey],
+	/// Scripts to run in the target after the post installation step, in order.
+	pub customize_scripts: &'a [PathBuf],
+	/// Whether the user has to change the password on the first login.
+	pub expire_password: bool,
+	/// Keep the artifacts readable by everyone, even if they contain credentials.
+	pub public_artifacts: bool,
 	// Filename can not be a ref unless there's another thing that
 	// holds the (rather unique) filename during execution, since
 	// the filename is combined with several pieces.
 	pub filename: String,
+	/// Revision of the image, if any.
+	pub revision: Option<u32>,
+	/// Whether the image has been created empty by `--revision auto`, so it
+	/// is removed if the build fails.
+	pub reserved: bool,
 	pub base_dist: PathBuf,
+	/// Archive the distribution is extracted from, instead of being copied from `base_dist`.
+	pub rootfs_source: Option<&'a RootfsSource>,
+	/// Forbids downloading the bootloader files, see [`crate::fetch`].
+	pub offline: bool,
 	pub override_rootfs_fstype: &'a Option<FilesystemType>,
 	pub additional_packages: &'a Option<Vec<String>>,
 	pub compress: &'a Compression,
+	/// Overrides the compression level.
+	pub compress_level: Option<u32>,
+	/// Overrides the number of threads to compress with.
+	pub compress_threads: Option<u32>,
 	pub topics: Option<&'a Vec<Topic>>,
+	/// Overrides the size of the variant, in MiB.
+	pub image_size: Option<u64>,
+	/// Overrides `image_size_round_to` of the device, in MiB.
+	pub image_size_round_to: Option<u64>,
+	/// Overrides `trailing_pad` of the device, in MiB.
+	pub trailing_pad: Option<u64>,
+	/// Identifier of this invocation.
+	pub build_id: &'a str,
+	/// Prefixes the log records of this job.
+	pub logger: JobLogger,
+	/// Clean up the leftovers of an earlier build of this image, even if they are still in use.
+	pub force: bool,
+	/// Derives the identifiers not pinned in the specification, see [`crate::seed`].
+	pub seed: Option<&'a str>,
+	/// When to keep the sketch directory after the build.
+	pub keep_workdir: KeepWorkdir,
+	/// Stages of the pipeline to run, consecutive and in order.
+	pub stages: &'a [Stage],
+	/// Shrink the image to its contents before compressing it.
+	pub shrink: bool,
+	/// Check the filesystems before the loop device is detached.
+	pub fsck: bool,
+	/// How the filesystems are populated.
+	pub populate_backend: PopulateBackend,
 }
 
 pub type ImageContextQueue<'a> = Vec<ImageContext<'a>>;
 
+/// Held while a loop device is being allocated, so parallel jobs never get the same one.
+static LOOP_ALLOC: Mutex<()> = Mutex::new(());
+/// Suffix of the images being written to the output directory.
+const PARTIAL_SUFFIX: &str = ".part";
+/// How often the progress of copying an uncompressed image is reported.
+const COPY_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// Left in the sketch directory if the target contains a Wi-Fi pre-shared key.
+const WIFI_PSK_MARKER: &str = "wifi-psk";
+
 impl ImageContext<'_> {
+	/// Size of the unpartitioned space at the end of the image, in bytes.
+	pub(crate) fn get_trailing_pad(&self) -> u64 {
+		self.trailing_pad.or(self.device.trailing_pad).unwrap_or(0) * (1 << 20)
+	}
+
+	/// Nominal size of the image in MiB, `--image-size` or the size of the variant.
+	pub(crate) fn nominal_image_size(&self) -> u64 {
+		self.image_size
+			.unwrap_or(self.device.size.get_variant_size(self.variant))
+	}
+
+	/// Make sure the partitions of fixed sizes fit in the image of `--image-size`.
+	pub fn check_image_size(&self) -> Result<()> {
+		let Some(nominal) = self.image_size else {
+			return Ok(());
+		};
+		let size = self.padded_image_size(nominal);
+		let fixed = self
+			.device
+			.partitions
+			.iter()
+			.filter_map(|p| p.sectors(self.variant).map(|size| (p.num, size)))
+			.collect::<Vec<_>>();
+		let needed = fixed.iter().map(|(_, size)| size).sum::<u64>() * 512;
+		if needed > size.saturating_sub(self.get_trailing_pad()) {
+			bail!(
+				"Image size of {} MiB is too small for {}: the partitions of fixed sizes need {} MiB: {}",
+				nominal,
+				&self.device.id,
+				needed.div_ceil(1 << 20),
+				fixed.iter()
+					.map(|(num, size)| format!(
+						"p{} ({} MiB)",
+						num,
+						(size * 512).div_ceil(1 << 20)
+					))
+					.collect::<Vec<_>>()
+					.join(", ")
+			);
+		}
+		plan_layout(
+			self.device.partition_map,
+			&self.device.partitions,
+			&self.device.reserved,
+			self.device
+				.first_partition_offset
+				.map(|o| o.sectors(SECTOR_SIZE)),
+			self.variant,
+			size,
+			self.get_trailing_pad(),
+		)
+		.context(format!(
+			"Image size of {} MiB is too small for {}",
+			nominal, &self.device.id
+		))?;
+		Ok(())
+	}
+
+	/// Get the final size of the image in bytes, with the nominal size in MiB, without logging.
+	pub(crate) fn padded_image_size(&self, nominal: u64) -> u64 {
+		let round_to = self
+			.image_size_round_to
+			.or(self.device.image_size_round_to)
+			.unwrap_or(0);
+		pad_image_size(
+			nominal * (1 << 20),
+			round_to * (1 << 20),
+			self.get_trailing_pad(),
+		)
+	}
+
+	/// Get the final size of the image in bytes, with the nominal size in MiB.
+	pub(crate) fn get_image_size(&self, nominal: u64) -> u64 {
+		let size = self.padded_image_size(nominal);
+		if size != nominal * (1 << 20) {
+			self.info(format!(
+				"Image size: {} MiB nominal, {} bytes padded",
+				nominal, size
+			));
+		}
+		size
+	}
+
 	pub(crate) fn info<S: AsRef<str>>(&self, content: S) {
-		let content = content.as_ref();
-		info!(
-			"[{} {}] {}",
-			&self.device.id,
-			&self.variant.to_string().to_lowercase(),
-			content
-		);
+		self.logger.info(content);
 	}
 	pub(crate) fn warn<S: AsRef<str>>(&self, content: S) {
-		let content = content.as_ref();
-		warn!(
-			"[{} {}] {}",
-			&self.device.id,
-			&self.variant.to_string().to_lowercase(),
-			content
-		);
+		self.logger.warn(content);
 	}
 
 	#[inline]
 	fn partition_image<P: AsRef<Path>>(&self, dev: P) -> Result<PartitionMapData> {
 		let disk_path = dev.as_ref();
 		let pm_data = match &self.device.partition_map {
-			PartitionMapType::GPT => self.partition_gpt(disk_path),
+			PartitionMapType::GPT | PartitionMapType::Hybrid => {
+				self.partition_gpt(disk_path)
+			}
 			PartitionMapType::MBR => self.partition_mbr(disk_path),
+			PartitionMapType::None => return Ok(self.partitionless_data()),
 			// _ => {
 			// 	bail!("Unsupported partition map");
 			// }
@@ -95,11 +248,164 @@ impl ImageContext<'_> {
 		// FIXME: BLKRRPART ioctl call EINVALs on loop devices.
 		// For now we call partprobe to tell the kernel to reread the partition table.
 		// gptman::linux::reread_partition_table(&mut File::options().read(true).write(true).open(disk_path)?)?;
-		refresh_partition_table(dev)?;
+		// Image files are partitioned without a loop device, see [`crate::populate`].
+		if disk_path.metadata()?.file_type().is_block_device() {
+			refresh_partition_table(dev)?;
+		}
 		Ok(pm_data)
 	}
 
-	fn mount_partitions<P: AsRef<Path>>(
+	/// Attach the image file to an available loop device.
+	pub(crate) fn attach_loop_device<P: AsRef<Path>>(img: P) -> Result<(LoopDevice, PathBuf)> {
+		let img = img.as_ref();
+		let _lock = LOOP_ALLOC.lock().unwrap();
+		debug!("Getting fd on /dev/loop-control ...");
+		let loop_ctl = LoopControl::open()?;
+		debug!("Finding available loop device ...");
+		let loop_dev = loop_ctl
+			.next_free()
+			.context("No available loop device found")?;
+		loop_dev.attach_file(img)?;
+		let loop_dev_path = loop_dev
+			.path()
+			.context("Unable to get the path of the loop device")?;
+		debug!(
+			"Attacthed raw image file {} to {}",
+			&img.display(),
+			&loop_dev_path.display()
+		);
+		Ok((loop_dev, loop_dev_path))
+	}
+
+	/// Detach the loop device, or copy the partitions into the image if it is built without one.
+	fn release_disk(&self, loop_dev: Option<LoopDevice>, rawimg_path: &Path) -> Result<()> {
+		match loop_dev {
+			Some(loop_dev) => {
+				self.info("Detaching the loop device ...");
+				loop_dev.detach()?;
+			}
+			None => self.join_partitions(rawimg_path)?,
+		}
+		Ok(())
+	}
+
+	/// Create the partitions on the disk, and optionally format them.
+	pub(crate) fn partition_disk(&self, disk: &Path, format: bool) -> Result<PartitionMapData> {
+		self.info("Creating partitions ...");
+		let mut pm_data = self
+			.partition_image(disk)
+			.context("Failed to partition the image")?;
+		if format {
+			self.info("Formating partitions ...");
+			self.format_partitions(&disk, &mut pm_data)?;
+		}
+		Ok(pm_data)
+	}
+
+	fn print_partition_table(&self, disk: &Path, pm_data: &PartitionMapData) {
+		println!(
+			"Partition table on {} ({}, {}):",
+			disk.display(),
+			self.device.partition_map.to_string().to_lowercase(),
+			&pm_data.uuid
+		);
+		println!("No.   Path                            FS      PARTUUID                                UUID");
+		for partition in &self.device.partitions {
+			if let Some(data) = pm_data.data.get(&partition.num) {
+				println!(
+					"{:<6}{:<32}{:<8}{:<40}{}",
+					partition.num,
+					self.device.partition_path(disk, partition.num),
+					partition.filesystem.get_os_fstype().unwrap_or("-"),
+					&data.part_uuid,
+					data.fs_uuid.as_ref().map_or("-".into(), FsUuid::to_string)
+				);
+			}
+		}
+	}
+
+	/// Summary of the partitions in the image, for people setting up netboot or custom boot entries.
+	fn partition_summary(&self, pm_data: &PartitionMapData) -> String {
+		let mut summary = format!(
+			"Partitions in the image ({}, {}):\nNo.   Label             FS      FSCK     UUID                                    PARTUUID",
+			self.device.partition_map.to_string().to_lowercase(),
+			&pm_data.uuid
+		);
+		for partition in &self.device.partitions {
+			if let Some(data) = pm_data.data.get(&partition.num) {
+				let label = partition.get_label().or(partition.fs_label.clone());
+				let fstype = self.effective_fstype(partition);
+				summary += &format!(
+					"\n{:<6}{:<18}{:<8}{:<9}{:<40}{}",
+					partition.num,
+					label.as_deref().unwrap_or("-"),
+					fstype.get_os_fstype().unwrap_or("-"),
+					data.fsck.map_or("-".into(), |s| s.to_string()),
+					data.fs_uuid.as_ref().map_or("-".into(), FsUuid::to_string),
+					&data.part_uuid
+				);
+			}
+		}
+		summary
+	}
+
+	/// Apply the partition layout to an image file or a block device, without
+	/// installing anything.
+	///
+	/// The image file is created with the given size (in MiB) if it does not exist.
+	pub fn partition_target(
+		&self,
+		target: &Path,
+		size: Option<u64>,
+		format: bool,
+	) -> Result<()> {
+		let _job = self.logger.enter();
+		if target.exists() && target.metadata()?.file_type().is_block_device() {
+			if size.is_some() {
+				self.warn("Target is a block device, ignoring the specified size.");
+			}
+			check_block_device_unused(target)?;
+			let result = self.partition_disk(target, format);
+			if result.is_err() {
+				self.release_mappings(target);
+			}
+			self.print_partition_table(target, &result?);
+			return Ok(());
+		}
+		if target.is_file() {
+			if size.is_some() {
+				self.warn(
+					"Image file already exists, ignoring the specified size.",
+				);
+			}
+			self.info(format!("Reusing image file {}", target.display()));
+		} else if target.exists() {
+			bail!(
+				"{} is neither a regular file nor a block device.",
+				target.display()
+			);
+		} else {
+			let size = size.unwrap_or(self.nominal_image_size());
+			self.info(format!(
+				"Creating image file {} ({} MiB)",
+				target.display(),
+				size
+			));
+			create_sparse_file(target, self.get_image_size(size))?;
+		}
+		let _interrupt = interrupt::register(None, target);
+		let (loop_dev, loop_dev_path) = Self::attach_loop_device(target)?;
+		let result = self.partition_disk(&loop_dev_path, format);
+		match &result {
+			Ok(pm_data) => self.print_partition_table(&loop_dev_path, pm_data),
+			Err(_) => self.release_mappings(target),
+		}
+		self.info("Detaching the loop device ...");
+		loop_dev.detach()?;
+		result.map(|_| ())
+	}
+
+	pub(crate) fn mount_partitions<P: AsRef<Path>>(
 		&self,
 		loop_dev: P,
 		mntdir_base: P,
@@ -112,20 +418,30 @@ impl ImageContext<'_> {
 		let loop_dev = loop_dev.as_ref();
 		debug!("Base directory for mountpoints: {}", mntdir_base.display());
 		for partition in &self.device.partitions {
-			if partition.filesystem == FilesystemType::None {
+			if !partition.filesystem.is_mountable() {
 				continue;
 			}
-			let src_dir = format!("{}p{}", loop_dev.to_string_lossy(), partition.num);
+			let src_dir = self.device.filesystem_path(loop_dev, partition);
 			let src_dir = Path::new(&src_dir);
 			let dst_dir = mntdir_base.join(format!("p{}", partition.num));
 			create_dir_all(&dst_dir)?;
+			// The staged partitions are populated in the directory, and built afterwards.
+			if !self.mounts() || self.is_staged(partition) {
+				continue;
+			}
 			debug!(
 				"Mounting {} to {}",
 				src_dir.display(),
 				dst_dir.as_path().display()
 			);
 			// Shoud we handle standard options like ro, nosuid, noexec, etc?
-			if let Some(opts) = partition.mount_opts.as_ref() {
+			if let Some(subvol) = self.main_subvolume(partition) {
+				let opts = self.subvolume_mount_data(partition, subvol);
+				let mount = Mount::builder()
+					.fstype(partition.filesystem.get_os_fstype()?)
+					.data(&opts);
+				mount.mount(src_dir, &dst_dir)?;
+			} else if let Some(opts) = partition.mount_opts.as_ref() {
 				// "defaults" in mount options are ignored.
 				let opts: Vec<_> = opts
 					.iter()
@@ -147,7 +463,7 @@ impl ImageContext<'_> {
 		Ok(())
 	}
 
-	fn mount_partitions_in_root<P: AsRef<Path>>(
+	pub(crate) fn mount_partitions_in_root<P: AsRef<Path>>(
 		&self,
 		loop_dev: P,
 		rootdir: P,
@@ -156,21 +472,34 @@ impl ImageContext<'_> {
 		let loop_dev = loop_dev.as_ref();
 		let rootdir = rootdir.as_ref();
 		for partition in &self.device.partitions {
-			if partition.filesystem == FilesystemType::None {
+			if !partition.filesystem.is_mountable() {
 				continue;
 			}
-			if partition.usage == PartitionUsage::Rootfs {
-				continue;
+			let src_dir = self.device.filesystem_path(loop_dev, partition);
+			let src_dir = Path::new(&src_dir);
+			let mut mounts = self
+				.mounted_subvolumes(partition)
+				.into_iter()
+				.map(|(subvol, mp)| (mp, Some(subvol)))
+				.collect::<Vec<_>>();
+			if partition.usage != PartitionUsage::Rootfs {
+				if let Some(mp) = &partition.mountpoint {
+					mounts.insert(0, (mp, self.main_subvolume(partition)));
+				}
 			}
-			if let Some(mp) = &partition.mountpoint {
-				let src_dir =
-					format!("{}p{}", loop_dev.to_string_lossy(), partition.num);
-				let src_dir = Path::new(&src_dir);
+			for (mp, subvol) in mounts {
 				// Joining paths with a leading slash replaces the whole path
 				let dst_dir = rootdir.join(mp.trim_start_matches('/'));
 				create_dir_all(&dst_dir)?;
-				let mount = Mount::builder()
+				if !self.mounts() {
+					continue;
+				}
+				let opts = subvol.map(|s| self.subvolume_mount_data(partition, s));
+				let mut mount = Mount::builder()
 					.fstype(partition.filesystem.get_os_fstype()?);
+				if let Some(opts) = &opts {
+					mount = mount.data(opts);
+				}
 				mount.mount(src_dir, &dst_dir)?;
 				stack.push(dst_dir.to_string_lossy().to_string());
 			}
@@ -179,7 +508,7 @@ impl ImageContext<'_> {
 	}
 
 	#[inline]
-	fn umount_stack(stack: &mut Vec<String>) -> Result<()> {
+	pub(crate) fn umount_stack(stack: &mut Vec<String>) -> Result<()> {
 		loop {
 			let cur = stack.pop();
 			if let Some(s) = cur {
@@ -197,46 +526,105 @@ impl ImageContext<'_> {
 		Ok(())
 	}
 
-	fn setup_chroot_mounts<P: AsRef<Path>>(
+	pub(crate) fn setup_chroot_mounts<P: AsRef<Path>>(
 		&self,
 		rootdir: P,
 		stack: &mut Vec<String>,
 	) -> Result<()> {
 		let rootdir = rootdir.as_ref();
+		// The backend sets up the API filesystems, see [`crate::populate`].
+		if !self.mounts() {
+			return Ok(());
+		}
 		let dst = rootdir.join("tmp");
 		debug!("Mounting tmpfs to {} ...", &dst.display());
 		let mount = Mount::builder().fstype("tmpfs");
 		mount.mount("tmpfs", &dst)?;
 		stack.push(dst.to_string_lossy().to_string());
+		if !chroot::backend().needs_api_mounts() {
+			return Ok(());
+		}
+		// Other backends set up these by themselves.
+		for (src, fstype, dst) in [
+			("proc", Some("proc"), "proc"),
+			("sysfs", Some("sysfs"), "sys"),
+			("/dev", None, "dev"),
+			("/dev/pts", None, "dev/pts"),
+		] {
+			let dst = rootdir.join(dst);
+			debug!("Mounting {} to {} ...", src, &dst.display());
+			create_dir_all(&dst)?;
+			let mount = match fstype {
+				Some(f) => Mount::builder().fstype(f),
+				None => Mount::builder().flags(MountFlags::BIND),
+			};
+			mount.mount(src, &dst)?;
+			stack.push(dst.to_string_lossy().to_string());
+		}
+		if chroot::bind_resolv_conf(rootdir) {
+			let dst = rootdir.join("etc/resolv.conf");
+			Mount::builder()
+				.flags(MountFlags::BIND)
+				.mount(chroot::RESOLV_CONF, &dst)?;
+			stack.push(dst.to_string_lossy().to_string());
+		}
 		Ok(())
 	}
 
-	fn postinst_step<P: AsRef<Path>>(&self, rootdir: P, binds: &[&str]) -> Result<()> {
-		let rootdir = rootdir.as_ref();
-		self.info("Setting up the user and locale ...");
-		add_user(
-			rootdir,
-			&self.user,
-			&self.password,
-			Some("Default User"),
-			None,
-			None,
-		)?;
-		set_locale(rootdir, "en_US.UTF-8")?;
-		self.set_hostname(&rootdir)?;
-
+	/// Find the post installation script next to the device specification.
+	pub(crate) fn find_postinst_script(&self) -> Result<Option<PathBuf>> {
 		let postinst_script_dir =
 			self.device.file_path.parent().context(
 				"Unable to find the directory containing the device spec",
 			)?;
-		let mut postinst_script_path = postinst_script_dir.join("postinst.bash");
-		if !postinst_script_path.is_file() {
-			postinst_script_path = postinst_script_dir.join("postinst.sh");
-		};
-		if !postinst_script_path.is_file() {
-			postinst_script_path = postinst_script_dir.join("postinst");
+		Ok(["postinst.bash", "postinst.sh", "postinst"]
+			.iter()
+			.map(|name| postinst_script_dir.join(name))
+			.find(|path| path.is_file()))
+	}
+
+	fn postinst_step<P: AsRef<Path>>(&self, rootdir: P, binds: &[&str]) -> Result<()> {
+		let rootdir = rootdir.as_ref();
+		if let Some(user) = self.user {
+			self.info("Setting up the user and locale ...");
+			add_user(
+				rootdir,
+				user,
+				self.password,
+				self.password_hashed,
+				Some("Default User"),
+				None,
+				None,
+			)?;
+			self.update_exfat_owner(&rootdir, user)?;
+			if self.expire_password {
+				self.info(format!(
+					"User {} will be asked to change the password on the first login.",
+					user
+				));
+				expire_password(rootdir, user)?;
+			}
+			// Logging in with an SSH key does not need the password.
+			if self.password == DEFAULT_PASSWORD
+				&& self.ssh_keys.is_empty() && !is_password_expired(rootdir, user)?
+			{
+				bail!(
+					"User {} uses the well-known default password, and it does not expire on the first login",
+					user
+				);
+			}
+		} else {
+			self.info("Setting up the locale, no user is created ...");
+		}
+		self.install_ssh_keys(rootdir)?;
+		set_locale(rootdir, "en_US.UTF-8")?;
+		if let Some(timezone) = self.timezone() {
+			self.info(format!("Setting the timezone to {} ...", timezone));
+			set_timezone(rootdir, timezone)?;
 		}
-		if postinst_script_path.is_file() {
+		self.set_hostname(&rootdir)?;
+
+		if let Some(postinst_script_path) = self.find_postinst_script()? {
 			self.info("Running post installation script ...");
 			debug!(
 				"Copying {} to {} ...",
@@ -263,17 +651,85 @@ impl ImageContext<'_> {
 		Ok(())
 	}
 
+	/// Whether the image contains credentials, i.e. anything other than the
+	/// default password which has to be changed on the first login, or a
+	/// Wi-Fi pre-shared key found in the target.
+	pub(crate) fn contains_secrets(&self) -> bool {
+		(self.user.is_some()
+			&& (self.password != DEFAULT_PASSWORD || !self.expire_password))
+			|| self.sketch_dir().join(WIFI_PSK_MARKER).exists()
+	}
+
+	/// Remember that the target contains a Wi-Fi pre-shared key, for the later stages.
+	fn scan_wifi_psk(&self, rootdir: &Path) -> Result<()> {
+		let marker = self.sketch_dir().join(WIFI_PSK_MARKER);
+		match find_wifi_psk(rootdir)? {
+			Some(path) => {
+				self.info(format!(
+					"Found a Wi-Fi pre-shared key in {}, the image contains credentials.",
+					path.strip_prefix(rootdir).unwrap_or(&path).display()
+				));
+				File::create(marker)?;
+			}
+			None if marker.exists() => std::fs::remove_file(marker)?,
+			None => (),
+		}
+		Ok(())
+	}
+
+	/// The accounts which have to change the password on the first login.
+	pub(crate) fn expired_accounts(&self) -> Vec<String> {
+		self.user
+			.filter(|_| self.expire_password)
+			.map(str::to_owned)
+			.into_iter()
+			.collect()
+	}
+
+	/// Whether the artifact is made unreadable by others.
+	fn restricts_artifact(&self) -> bool {
+		self.contains_secrets() && !self.public_artifacts
+	}
+
+	/// Keep the artifact away from others if it contains credentials.
+	fn protect_artifact(&self, artifact: &Path) -> Result<()> {
+		if !self.contains_secrets() {
+			return Ok(());
+		}
+		if self.public_artifacts {
+			self.warn("The image contains credentials, but it is kept readable by everyone as --public-artifacts is specified.");
+			return Ok(());
+		}
+		self.info("The image contains credentials, making it unreadable by others ...");
+		for dir in restrict_artifact(artifact, self.outdir)? {
+			self.warn(format!(
+				"{} is accessible by everyone, but it contains images with credentials.",
+				dir.display()
+			));
+		}
+		Ok(())
+	}
+
 	fn compress_image<P: AsRef<Path>>(&self, from: P, to: P) -> Result<()> {
 		let from = from.as_ref();
 		let to = to.as_ref();
 		let from_fd = File::options().read(true).open(from)?;
-		let to_fd = File::options()
-			.write(true)
-			.create(true)
-			.truncate(true)
-			.open(to)?;
+		let mut options = File::options();
+		options.write(true).create(true).truncate(true);
+		// Never readable by others, not even while it is being written.
+		if self.restricts_artifact() {
+			options.mode(0o640);
+		}
+		let to_fd = options.open(to)?;
 
-		let num_cpus = num_cpus::get().clamp(1, 32) as u32;
+		let level = self.compress_level.unwrap_or(DEFAULT_COMPRESS_LEVEL);
+		let threads = match self.compress {
+			Compression::Gzip => 1,
+			_ => self
+				.compress_threads
+				.unwrap_or(num_cpus::get().clamp(1, 32) as u32),
+		};
+		let settings = format!("level {}, {} thread(s)", level, threads);
 
 		let start: Instant;
 		let duration: Duration;
@@ -284,31 +740,35 @@ impl ImageContext<'_> {
 			}
 			_ => {
 				self.info(format!(
-					"Compressing the raw image to {} using {:?} ...",
+					"Compressing the raw image to {} using {:?} ({}) ...",
 					&to.display(),
-					&self.compress
+					&self.compress,
+					&settings
 				));
-				if self.compress != &Compression::Gzip {
-					self.info(format!(
-						"Using {} threads for compression",
-						num_cpus
-					));
-				}
 			}
 		}
 		match &self.compress {
 			Compression::Xz => {
 				let mut bufreader = BufReader::with_capacity(1048576, from_fd);
 				let mut xz_filter = xz2::stream::Filters::new();
-				let mut xz_options = xz2::stream::LzmaOptions::new_preset(9)?;
-				xz_options.nice_len(273);
+				let mut xz_options = xz2::stream::LzmaOptions::new_preset(level)?;
+				if level == DEFAULT_COMPRESS_LEVEL {
+					xz_options.nice_len(273);
+				}
 				xz_filter.lzma2(&xz_options);
-				let encoder = xz2::stream::MtStreamBuilder::new()
-					.filters(xz_filter)
-					.threads(num_cpus)
-					.block_size(1048576)
-					.check(xz2::stream::Check::Crc32)
-					.encoder()?;
+				let encoder = if threads > 1 {
+					xz2::stream::MtStreamBuilder::new()
+						.filters(xz_filter)
+						.threads(threads)
+						.block_size(1048576)
+						.check(xz2::stream::Check::Crc32)
+						.encoder()?
+				} else {
+					xz2::stream::Stream::new_stream_encoder(
+						&xz_filter,
+						xz2::stream::Check::Crc32,
+					)?
+				};
 				let mut writer = xz2::write::XzEncoder::new_stream(to_fd, encoder);
 				start = Instant::now();
 				copy(&mut bufreader, &mut writer)?;
@@ -318,8 +778,10 @@ impl ImageContext<'_> {
 			Compression::Zstd => {
 				// zstd::stream::copy_encode(from_fd, to_fd, 9)?;
 				let mut bufreader = BufReader::with_capacity(1048576, from_fd);
-				let mut writer = zstd::stream::Encoder::new(to_fd, 9)?;
-				writer.multithread(num_cpus)?;
+				let mut writer = zstd::stream::Encoder::new(to_fd, level as i32)?;
+				if threads > 1 {
+					writer.multithread(threads)?;
+				}
 				start = Instant::now();
 				copy(&mut bufreader, &mut writer)?;
 				writer.finish()?.flush()?;
@@ -330,7 +792,7 @@ impl ImageContext<'_> {
 				let bufreader = BufReader::with_capacity(1048576, from_fd);
 				let mut encoder = flate2::bufread::GzEncoder::new(
 					bufreader,
-					flate2::Compression::new(9),
+					flate2::Compression::new(level),
 				);
 				let mut bufwriter = BufWriter::with_capacity(1048576, to_fd);
 				start = Instant::now();
@@ -338,19 +800,39 @@ impl ImageContext<'_> {
 				duration = start.elapsed();
 			}
 			Compression::None => {
-				// Using std::fs::copy.
+				// Keep the holes, the raw image is mostly empty.
 				self.info("No compression specified, copying file directly.");
 				// Close the files first.
 				drop(from_fd);
 				drop(to_fd);
-				std::fs::copy(from, to)?;
-				self.info("Done copying the raw image.");
+				// Only the data is copied, which is a fraction of the apparent size.
+				let mut last = Instant::now();
+				let copied = copy_sparse(from, to, |copied, size| {
+					if last.elapsed() >= COPY_PROGRESS_INTERVAL {
+						last = Instant::now();
+						self.info(format!(
+							"Copied {} of data, the raw image is {} ...",
+							format_size(copied),
+							format_size(size)
+						));
+					}
+				})?;
+				self.info(format!(
+					"Done copying the raw image, {} of data copied.",
+					format_size(copied)
+				));
 				return Ok(());
 			}
 		}
+		let raw_size = std::fs::metadata(from)?.len();
+		let size = std::fs::metadata(to)?.len();
 		self.info(format!(
-			"Compression finished in {:.2} seconds.",
-			duration.as_secs_f64()
+			"Compression finished in {:.2} seconds ({}): {} to {} ({:.2}% of the raw image).",
+			duration.as_secs_f64(),
+			&settings,
+			format_size(raw_size),
+			format_size(size),
+			size as f64 * 100.0 / raw_size.max(1) as f64
 		));
 		Ok(())
 	}
@@ -374,8 +856,201 @@ impl ImageContext<'_> {
 		Ok(())
 	}
 
-	pub fn execute(self, num: usize, len: usize) -> Result<()> {
+	/// The directory containing the raw image and the mount points of this job.
+	pub fn sketch_dir(&self) -> PathBuf {
+		self.workdir
+			.join(format!("sketches/{}-{}", &self.device.id, &self.variant))
+	}
+
+	/// Deal with the leftovers of an earlier build of this image which died halfway.
+	///
+	/// A stale raw image is removed. If it is still attached to a loop
+	/// device, or its partitions are still mounted, it is only cleaned up
+	/// with `--force`.
+	fn clean_sketch_dir(&self, rawimg_path: &Path) -> Result<()> {
+		self.release_sketch_dir(rawimg_path)?;
+		if rawimg_path.is_file() {
+			self.warn("Raw image file already exists in the workbench - removing it first.");
+			std::fs::remove_file(rawimg_path)?;
+		}
+		let psk_marker = self.sketch_dir().join(WIFI_PSK_MARKER);
+		if psk_marker.exists() {
+			std::fs::remove_file(psk_marker)?;
+		}
+		StageMarker::remove(&self.sketch_dir())
+	}
+
+	/// Release the mounts and the loop devices an earlier build of this
+	/// image left, only with `--force`.
+	fn release_sketch_dir(&self, rawimg_path: &Path) -> Result<()> {
+		let mounts = mounts_under(self.sketch_dir())?;
+		let loop_devs = loop_devices_backed_by(rawimg_path);
+		if !mounts.is_empty() || !loop_devs.is_empty() {
+			let leftovers = mounts
+				.iter()
+				.chain(&loop_devs)
+				.map(|p| p.display().to_string())
+				.collect::<Vec<_>>();
+			if !self.force {
+				bail!(
+					"An earlier build of this image left the following in use:\n\t{}\nUse --force to clean them up.",
+					leftovers.join("\n\t")
+				);
+			}
+			self.warn(format!(
+				"Cleaning up the leftovers of an earlier build:\n\t{}",
+				leftovers.join("\n\t")
+			));
+			release_leftovers(&mounts, &loop_devs)?;
+		}
+		Ok(())
+	}
+
+	/// The directory containing the output.
+	///
+	/// Follows the directory hierarchy of AOSC OS releases.
+	pub fn output_dir(&self) -> PathBuf {
+		self.outdir.join(format!(
+			"os-{}/{}/rawimg/{}",
+			&self.device.arch.to_string().to_lowercase(),
+			&self.variant.to_string().to_lowercase(),
+			&self.device.vendor
+		))
+	}
+
+	/// Remove the sketch directory, releasing whatever a failed build left in use.
+	fn remove_sketch_dir(&self) -> Result<()> {
+		let sketch_dir = self.sketch_dir();
+		if !sketch_dir.exists() {
+			return Ok(());
+		}
+		let mounts = mounts_under(&sketch_dir)?;
+		let loop_devs = loop_devices_backed_by(sketch_dir.join("rawmedia.img"));
+		release_leftovers(&mounts, &loop_devs)?;
+		// Never remove the filesystems mounted under it.
+		if !mounts_under(&sketch_dir)?.is_empty() {
+			bail!(
+				"Unable to unmount everything under {}",
+				sketch_dir.display()
+			);
+		}
+		std::fs::remove_dir_all(&sketch_dir)?;
+		Ok(())
+	}
+
+	/// Build the image, then remove the sketch directory according to `--keep-workdir`.
+	///
+	/// Returns the record of the image for the build report, if the compress
+	/// stage is run.
+	pub fn execute(self, num: usize, len: usize) -> Result<Option<ImageRecord>> {
+		let result = self.build(num, len);
+		// The mappings are opened with the passphrases, never leave them open.
+		if result.is_err() {
+			self.release_mappings(&self.sketch_dir().join("rawmedia.img"));
+		}
+		// Free the reserved name.
+		if result.is_err() && self.reserved {
+			std::fs::remove_file(self.artifact().path).ok();
+		}
+		let keep = match self.keep_workdir {
+			KeepWorkdir::Always => true,
+			// The raw image of a partial build is used by the later stages.
+			KeepWorkdir::OnFailure => result.is_err() || self.is_partial(),
+			KeepWorkdir::Never => false,
+		};
+		if keep {
+			if result.is_err() {
+				self.info(format!(
+					"Keeping {} for debugging.",
+					self.sketch_dir().display()
+				));
+			}
+		} else if let Err(e) = self.remove_sketch_dir() {
+			self.warn(format!(
+				"Unable to remove {}: {:#}\nYou have to remove it manually.",
+				self.sketch_dir().display(),
+				e
+			));
+		}
+		result
+	}
+
+	/// Install the system distribution, the BSP packages and the kernel.
+	fn populate_stage(&self, rootdir: &Path) -> Result<()> {
+		self.info("Installing BSP packages ...");
+		self.install_bsp_packages(rootdir)?;
+		let kernel = self.select_kernel(rootdir)?;
+		self.install_dtbs(rootdir, kernel.as_ref())?;
+		self.setup_services(rootdir)?;
+		self.setup_swap(rootdir)?;
+		if self.shrink {
+			self.setup_growfs(rootdir)?;
+		}
+		Ok(())
+	}
+
+	/// Set up the user, run the post installation script and the customize scripts.
+	fn postinst_stage(&self, rootdir: &Path, binds: &[&str]) -> Result<()> {
+		self.info("Running post installation step ...");
+		self.postinst_step(rootdir, binds)?;
+		self.setup_paths(rootdir)?;
+		self.run_customize_scripts(rootdir, binds)
+	}
+
+	/// Apply the bootloaders and write the metadata.
+	fn bootloader_stage(
+		&self,
+		rootdir: &Path,
+		loop_dev_path: &Path,
+		binds: &[&str],
+		size: u64,
+		pm_data: &PartitionMapData,
+	) -> Result<()> {
+		self.apply_bootloaders(rootdir, loop_dev_path, binds, pm_data)?;
+		// The contents may be produced by the bootloader steps.
+		self.write_partition_contents(loop_dev_path, Some(rootdir))?;
+		self.scan_wifi_psk(rootdir)?;
+		self.write_metadata(rootdir, loop_dev_path, size)
+	}
+
+	/// Shrink the raw image if instructed, then copy or compress it to the output directory.
+	///
+	/// The image is written with the [`PARTIAL_SUFFIX`] first, and renamed once
+	/// it is complete, so an interrupted one is never taken for a built image,
+	/// e.g. by `--resume`.
+	fn compress_stage(&self, rawimg_path: &Path, outfile_path: &Path) -> Result<()> {
+		if self.shrink {
+			self.shrink_image(rawimg_path)?;
+		}
+		let mut partial = outfile_path.as_os_str().to_owned();
+		partial.push(PARTIAL_SUFFIX);
+		let partial = PathBuf::from(partial);
+		// The mode only applies to a new file, a leftover keeps its own.
+		if partial.exists() {
+			std::fs::remove_file(&partial)?;
+		}
+		let result = self
+			.compress_image(rawimg_path, &partial)
+			.and_then(|_| self.protect_artifact(&partial))
+			.and_then(|_| {
+				std::fs::rename(&partial, outfile_path).context(format!(
+					"Failed to rename {} to {}",
+					partial.display(),
+					outfile_path.display()
+				))
+			});
+		if result.is_err() {
+			std::fs::remove_file(&partial).ok();
+		}
+		result
+	}
+
+	fn build(&self, num: usize, len: usize) -> Result<Option<ImageRecord>> {
+		let start = Instant::now();
 		let draw_progressbar = |content: &str| {
+			if !progress_bar_enabled() {
+				return;
+			}
 			// we don't want to screw up the terminal.
 			let size = termsize::get().unwrap_or(Size { rows: 25, cols: 80 });
 			eprint!("\x1b7\x1b[{};0f\x1b[42m\x1b[0K\x1b[2K", size.rows);
@@ -386,44 +1061,32 @@ impl ImageContext<'_> {
 			eprint!("\x1b8");
 		};
 
+		// Prefix the output of the external commands with this job.
+		let _job = self.logger.enter();
+
 		// Set up the scroll region for progressbar.
 		setup_scroll_region();
 
 		// Various paths being used
 		// The path which used specifically for this task
 		// Contains the raw image and the mount points
-		let workdir_base = self
-			.workdir
-			.join(format!("sketches/{}-{}", &self.device.id, &self.variant));
+		let workdir_base = self.sketch_dir();
 		// The path containing the output
-		// Follows the directory hierarchy of AOSC OS releases
-		let outdir_base = self.outdir.join(format!(
-			"os-{}/{}/rawimg/{}",
-			&self.device.arch.to_string().to_lowercase(),
-			&self.variant.to_string().to_lowercase(),
-			&self.device.vendor
-		));
+		let outdir_base = self.output_dir();
 		// The full path to the output file
 		let outfile_path = outdir_base.join(&self.filename);
 		// Base directory for temporary mount points
 		let mountdir_base = workdir_base.join("mnt");
-		// Total image size
-		let size = self.device.size.get_variant_size(self.variant) * (1 << 20);
-		// A stack which remembers all of the active mountpoints
-		// These mountpoints must be umounted before this function ends!
-		let mut mountpoint_stack: Vec<String> = Vec::new();
-		// The index of the partition which contains the root filesystem, in the partition table.
-		let mut root_dev_num = None;
-		for p in &self.device.partitions {
-			if p.usage == PartitionUsage::Rootfs {
-				root_dev_num = Some(p.num);
-			}
-		}
-		// If you don't have one, then where the hell do you store the OS?
-		if root_dev_num.is_none() {
-			bail!("Unable to find a root filesystem");
-		}
-		let root_dev_num = root_dev_num.unwrap();
+		// The raw image
+		let rawimg_path = workdir_base.join("rawmedia.img");
+		// Released on Ctrl-C
+		let interrupt = interrupt::register(Some(&workdir_base), &rawimg_path);
+		// The first and the last stage to run
+		let (first, last) = match self.stages {
+			[first, .., last] => (*first, *last),
+			[stage] => (*stage, *stage),
+			[] => bail!("No stage to run"),
+		};
 
 		// Begin to produce the image
 		self.info(format!(
@@ -431,6 +1094,9 @@ impl ImageContext<'_> {
 			&self.device.name, &self.device.id, &self.variant
 		));
 		self.info(format!("Output file:\n\t{}", &self.filename));
+		if self.is_partial() {
+			self.info(format!("Stages:\n\t{}", Stage::join(self.stages)));
+		}
 
 		self.info("Initializing image ...");
 		draw_progressbar("Initializing image");
@@ -447,41 +1113,140 @@ impl ImageContext<'_> {
 		);
 		create_dir_all(&outdir_base)?;
 		create_dir_all(&mountdir_base)?;
-		let rawimg_path = workdir_base.join("rawmedia.img");
-		if rawimg_path.is_file() {
-			self.warn("Raw image file already exists in the workbench - removing it first.");
-			std::fs::remove_file(&rawimg_path)?;
+		let log_path = outdir_base.join(format!("{}.log", &self.filename));
+		self.logger.open_log_file(&log_path)?;
+		self.info(format!("Log file:\n\t{}", log_path.display()));
+		let resumed = if first == Stage::Partition {
+			self.clean_sketch_dir(&rawimg_path)?;
+			// Total image size, padded and rounded
+			let size = self.get_image_size(self.nominal_image_size());
+			create_sparse_file(&rawimg_path, size)?;
+			None
+		} else {
+			self.release_sketch_dir(&rawimg_path)?;
+			Some(self.resume_stages(first)?)
+		};
+		let size = std::fs::metadata(&rawimg_path)?.len();
+		let pm_data = if first < Stage::Compress {
+			self.build_image(
+				&rawimg_path,
+				&mountdir_base,
+				size,
+				resumed,
+				&draw_progressbar,
+			)?
+		} else {
+			resumed.context("No partition data to resume")?
+		};
+
+		let record = if self.runs(Stage::Compress) {
+			let stage_start = Instant::now();
+			self.info("Finishing up ...");
+			draw_progressbar("Finishing up");
+			interrupt.start_writing(&outfile_path);
+			self.compress_stage(&rawimg_path, &outfile_path)?;
+			interrupt.finish_writing();
+			self.finish_stage(
+				StageTiming::since(Stage::Compress, stage_start),
+				&pm_data,
+			)?;
+			Some(self.image_record(
+				&rawimg_path,
+				&outfile_path,
+				&pm_data,
+				start.elapsed(),
+			)?)
+		} else {
+			None
+		};
+		restore_term();
+		sync_filesystem(&rawimg_path)?;
+		self.info(self.partition_summary(&pm_data));
+		if last == Stage::Compress {
+			info!("Done! image finished.");
+		} else {
+			info!(
+				"Done! Stage '{}' finished, the raw image is at {}.",
+				last.to_string().to_lowercase(),
+				rawimg_path.display()
+			);
 		}
-		create_sparse_file(&rawimg_path, size)?;
+		Ok(record)
+	}
 
-		// Attach to a loop device
-		debug!("Getting fd on /dev/loop-control ...");
-		let loop_ctl = LoopControl::open()?;
-		debug!("Finding available loop device ...");
-		let loop_dev = loop_ctl
-			.next_free()
-			.context("No available loop device found")?;
-		loop_dev.attach_file(&rawimg_path)?;
-		let loop_dev_path = loop_dev
-			.path()
-			.context("Unable to get the path of the loop device")?;
-		debug!(
-			"Attacthed raw image file {} to {}",
-			&rawimg_path.display(),
-			&loop_dev_path.display()
-		);
+	/// Run the stages working on the raw image attached to a loop device,
+	/// from partitioning to applying the bootloaders. Without mounting, the
+	/// partitions are files instead, see [`crate::populate`].
+	///
+	/// Without `resumed`, the image is partitioned first.
+	fn build_image(
+		&self,
+		rawimg_path: &Path,
+		mountdir_base: &Path,
+		size: u64,
+		resumed: Option<PartitionMapData>,
+		draw_progressbar: &dyn Fn(&str),
+	) -> Result<PartitionMapData> {
+		// A stack which remembers all of the active mountpoints
+		// These mountpoints must be umounted before this function ends!
+		let mut mountpoint_stack: Vec<String> = Vec::new();
+		// The index of the partition which contains the root filesystem, in the partition table.
+		let mut root_dev_num = None;
+		for p in &self.device.partitions {
+			if p.usage == PartitionUsage::Rootfs {
+				root_dev_num = Some(p.num);
+			}
+		}
+		// If you don't have one, then where the hell do you store the OS?
+		if root_dev_num.is_none() {
+			bail!("Unable to find a root filesystem");
+		}
+		let root_dev_num = root_dev_num.unwrap();
 
-		self.info("Creating partitions ...");
-		let mut pm_data = self
-			.partition_image(&loop_dev_path)
-			.context("Failed to partition the image")?;
+		// Attach to a loop device, without mounting the image file is
+		// partitioned itself and the partitions are files next to it.
+		let (loop_dev, loop_dev_path) = if self.mounts() {
+			let (loop_dev, loop_dev_path) = Self::attach_loop_device(rawimg_path)?;
+			(Some(loop_dev), loop_dev_path)
+		} else {
+			(None, rawimg_path.to_owned())
+		};
 
-		self.info("Formating partitions ...");
-		self.format_partitions(&loop_dev_path, &mut pm_data)?;
+		let mut pm_data = match resumed {
+			Some(pm_data) => pm_data,
+			None => {
+				let stage_start = Instant::now();
+				let pm_data = self.partition_disk(&loop_dev_path, false)?;
+				self.finish_stage(
+					StageTiming::since(Stage::Partition, stage_start),
+					&pm_data,
+				)?;
+				pm_data
+			}
+		};
+		if loop_dev.is_none() {
+			self.split_partitions(rawimg_path)?;
+		}
+		if self.runs(Stage::Format) {
+			let stage_start = Instant::now();
+			self.info("Formatting partitions ...");
+			self.format_partitions(&loop_dev_path, &mut pm_data)?;
+			self.finish_stage(
+				StageTiming::since(Stage::Format, stage_start),
+				&pm_data,
+			)?;
+		}
+		if !self.stages.iter().any(|s| {
+			[Stage::Populate, Stage::Postinst, Stage::Bootloader].contains(s)
+		}) {
+			self.release_disk(loop_dev, rawimg_path)?;
+			return Ok(pm_data);
+		}
 
-		// Bind mounts to be passed to systemd-nspawn(1).
+		// Bind mounts to be passed to the chroot backend.
 		// Switching to systemd-nspawn completely eliminates /dev,
-		// /sys and /proc bind mounts, but we have to bind mount the
+		// /sys and /proc bind mounts (they are only needed by the
+		// plain chroot backend), but we have to bind mount the
 		// loop device the target image is attached to, and all of
 		// its partitions to the target, for post installtion and
 		// bootloader scripts to access them.
@@ -490,70 +1255,116 @@ impl ImageContext<'_> {
 		// command line.
 		let mut binds = Vec::new();
 		binds.push(loop_dev_path.to_string_lossy().to_string());
+		if self.device.partition_map != PartitionMapType::None {
+			for partition in &self.device.partitions {
+				binds.push(get_partition_path(&loop_dev_path, partition.num));
+			}
+		}
 		for partition in &self.device.partitions {
-			binds.push(format!(
-				"{}p{}",
-				loop_dev_path.to_string_lossy(),
-				partition.num
-			));
+			if partition.encryption.is_some() && partition.filesystem.is_mountable() {
+				binds.push(self.device.filesystem_path(&loop_dev_path, partition));
+			}
 		}
 		let binds = binds.iter().map(|x| x.as_str()).collect::<Vec<_>>();
 		let binds = binds.as_slice();
 
 		// The path to the block device which contains the root filesystem.
-		let rootpart_dev = format!("{}p{}", &loop_dev_path.to_string_lossy(), root_dev_num);
+		let rootpart_dev = self.device.partition_path(&loop_dev_path, root_dev_num);
+		// Mounting the partitions counts for the first stage run.
+		let mut stage_start = Instant::now();
+		self.open_mappings(&loop_dev_path)?;
 		self.info("Mounting partitions ...");
-		self.mount_partitions(&loop_dev_path, &mountdir_base, &mut mountpoint_stack)?;
+		self.mount_partitions(
+			loop_dev_path.as_path(),
+			mountdir_base,
+			&mut mountpoint_stack,
+		)?;
 		let rootfs_mount = mountdir_base
 			.join(format!("p{}", root_dev_num))
 			.canonicalize()
 			.context("Failed to canonicalize the path of root filesystem mountpoint")?;
 		debug!("Root filesystem mountpoint: {:?}", rootfs_mount);
 
-		self.info("Installing system distribution ...");
-		draw_progressbar("Installing base distribution");
-		rsync_sysroot(&self.base_dist, &rootfs_mount)?;
+		let mut strategy = None;
+		if self.runs(Stage::Populate) {
+			if self.erofs_root().is_some() || !self.mounts() {
+				self.clear_staging(&rootfs_mount)?;
+			}
+			self.info("Installing system distribution ...");
+			draw_progressbar("Installing base distribution");
+			match self.rootfs_source {
+				Some(source) => self.extract_rootfs(source, &rootfs_mount)?,
+				None => {
+					strategy =
+						Some(rsync_sysroot(&self.base_dist, &rootfs_mount)?)
+				}
+			}
+		}
 		self.mount_partitions_in_root(
 			&loop_dev_path,
 			&rootfs_mount,
 			&mut mountpoint_stack,
 		)?;
-		self.info("Generating fstab ...");
-		self.generate_fstab(&pm_data, &rootfs_mount)?;
+		if self.runs(Stage::Populate) {
+			self.info("Generating fstab ...");
+			self.generate_fstab(&pm_data, &rootfs_mount)?;
+			self.generate_crypttab(&pm_data, &rootfs_mount)?;
+			self.prepare_overlay_dirs(&rootfs_mount)?;
+		}
 
 		self.info("Setting up bind mounts ...");
 		self.setup_chroot_mounts(&rootfs_mount, &mut mountpoint_stack)?;
 
+		// Written on every run, the loop device may differ from the earlier stages.
 		self.write_spec_script(&loop_dev_path, &rootpart_dev, &rootfs_mount, &pm_data)?;
 
-		self.info("Installing BSP packages ...");
-		draw_progressbar("Installing packages");
-		// Eh we have to "convert" Vec<String> to Vec<&str>.
-		let pkgs = &self
-			.device
-			.bsp_packages
-			.iter()
-			.map(String::as_str)
-			.collect::<Vec<&str>>();
-		self.install_packages(pkgs.as_slice(), &rootfs_mount)?;
-
-		self.info("Running post installation step ...");
-		draw_progressbar("Post installation step");
-		self.postinst_step(&rootfs_mount, binds)?;
-
-		self.apply_bootloaders(&rootfs_mount, &loop_dev_path, binds)?;
+		if self.runs(Stage::Populate) {
+			draw_progressbar("Installing packages");
+			self.populate_stage(&rootfs_mount)?;
+			let timing = StageTiming {
+				strategy,
+				..StageTiming::since(Stage::Populate, stage_start)
+			};
+			self.finish_stage(timing, &pm_data)?;
+			stage_start = Instant::now();
+		}
+		if self.runs(Stage::Postinst) {
+			draw_progressbar("Post installation step");
+			self.postinst_stage(&rootfs_mount, binds)?;
+			self.finish_stage(
+				StageTiming::since(Stage::Postinst, stage_start),
+				&pm_data,
+			)?;
+			stage_start = Instant::now();
+		}
+		if self.runs(Stage::Bootloader) {
+			draw_progressbar("Applying bootloaders");
+			self.bootloader_stage(
+				&rootfs_mount,
+				&loop_dev_path,
+				binds,
+				size,
+				&pm_data,
+			)?;
+			self.finish_stage(
+				StageTiming::since(Stage::Bootloader, stage_start),
+				&pm_data,
+			)?;
+		}
 
-		self.info("Finishing up ...");
-		draw_progressbar("Finishing up");
 		self.info("Unmounting filesystems ...");
 		ImageContext::<'_>::umount_stack(&mut mountpoint_stack)?;
-		self.info("Detaching the loop device ...");
-		loop_dev.detach()?;
-		// fs::remove_file(rawimg_path)?;
-		self.compress_image(&rawimg_path, &outfile_path)?;
-		restore_term();
-		sync_filesystem(&rawimg_path)?;
-		info!("Done! image finished.");
-		Ok(())
+		if self.runs(Stage::Bootloader) {
+			self.snapshot_installs(&loop_dev_path)?;
+		}
+		if !self.staged_partitions().is_empty() {
+			draw_progressbar("Building the staged filesystems");
+			self.build_staged_partitions(&rootfs_mount, &loop_dev_path, &mut pm_data)?;
+		}
+		draw_progressbar("Checking filesystems");
+		self.check_filesystems(&loop_dev_path, &mut pm_data)?;
+		self.close_mappings(&loop_dev_path)?;
+		self.release_disk(loop_dev, rawimg_path)?;
+		Ok(pm_data)
 	}
 }
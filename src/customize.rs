@@ -0,0 +1,121 @@
+//! One-off customizations of the images, with `--customize-script` and `--customize-dir`.
+//!
+//! The scripts run in the target after the post installation script and the
+//! declared paths, before the bootloaders are applied and the filesystems are
+//! unmounted. Like the post installation script, they are sourced by bash
+//! after the spec script (see [`DeviceSpec`](crate::device::DeviceSpec) for
+//! the available variables), so the shebang is not interpreted.
+//!
+//! The scripts given with `--customize-script` run in the order given,
+//! followed by the executables in each `--customize-dir` in lexical order.
+//! Files without the executable bit are skipped, similar to run-parts(8).
+//!
+//! Each script is copied into `/tmp` of the target, and removed after it has
+//! been run. A failing script aborts the build.
+use std::{
+	fs,
+	os::unix::fs::PermissionsExt,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{context::ImageContext, utils::run_script_with_chroot};
+
+/// Collect the scripts to run, in order.
+pub fn collect_customize_scripts(scripts: &[PathBuf], dirs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+	let mut collected = Vec::new();
+	for script in scripts {
+		if !script.is_file() {
+			bail!("Customize script {} is not a file", script.display());
+		}
+		collected.push(script.to_owned());
+	}
+	for dir in dirs {
+		let mut entries = fs::read_dir(dir)
+			.context(format!(
+				"Failed to read the customize directory {}",
+				dir.display()
+			))?
+			.map(|e| e.map(|e| e.path()))
+			.collect::<Result<Vec<_>, _>>()?;
+		entries.sort();
+		for path in entries {
+			let metadata = fs::metadata(&path)?;
+			if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 {
+				collected.push(path);
+			}
+		}
+	}
+	Ok(collected)
+}
+
+impl ImageContext<'_> {
+	/// Run the customize scripts in the target.
+	pub fn run_customize_scripts(&self, rootdir: &Path, binds: &[&str]) -> Result<()> {
+		let len = self.customize_scripts.len();
+		for (idx, script) in self.customize_scripts.iter().enumerate() {
+			let filename = script
+				.file_name()
+				.context("Unable to get the basename of the script")?
+				.to_string_lossy();
+			self.info(format!(
+				"Running customize script {}/{}: {} ...",
+				idx + 1,
+				len,
+				script.display()
+			));
+			// Scripts from different directories may share the name.
+			let target = Path::new("/tmp").join(format!(
+				"customize-{}-{}",
+				idx + 1,
+				filename
+			));
+			let copied = rootdir.join(target.strip_prefix("/")?);
+			fs::copy(script, &copied).context(format!(
+				"Failed to copy the customize script {}",
+				script.display()
+			))?;
+			let result = run_script_with_chroot(rootdir, &target, binds, None);
+			// A failure of the script comes first.
+			let removed = fs::remove_file(&copied)
+				.context(format!("Failed to remove {}", copied.display()));
+			result.context(format!("Customize script {} failed", script.display()))?;
+			removed?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	#[test]
+	fn test_collect_customize_scripts() -> Result<()> {
+		let base = TempDir::new("customize")?;
+		let dir = base.join("customize.d");
+		fs::create_dir_all(&dir)?;
+		for (name, mode) in [("20-ssh", 0o755), ("10-motd", 0o700), ("README", 0o644)] {
+			fs::write(dir.join(name), "true\n")?;
+			fs::set_permissions(dir.join(name), fs::Permissions::from_mode(mode))?;
+		}
+		fs::create_dir(dir.join("30-subdir"))?;
+		fs::set_permissions(dir.join("30-subdir"), fs::Permissions::from_mode(0o755))?;
+		let script = base.join("oneoff.sh");
+		fs::write(&script, "true\n")?;
+		let scripts = collect_customize_scripts(
+			std::slice::from_ref(&script),
+			std::slice::from_ref(&dir),
+		)?;
+		assert_eq!(
+			scripts,
+			vec![script, dir.join("10-motd"), dir.join("20-ssh")]
+		);
+		let err = collect_customize_scripts(&[base.join("missing.sh")], &[]).unwrap_err();
+		assert!(err.to_string().contains("missing.sh is not a file"));
+		assert!(collect_customize_scripts(&[], &[base.join("missing.d")]).is_err());
+		Ok(())
+	}
+}
@@ -38,30 +38,69 @@
 //! [device specification file]: crate::device::DeviceSpec
 
 use std::{
-	collections::HashMap,
+	collections::{BTreeMap, HashMap},
 	ffi::OsStr,
 	fs::{self, File},
-	io::Write,
+	io::{Seek, SeekFrom, Write},
 	path::{Path, PathBuf},
+	str::FromStr,
 };
 
 use crate::{
-	bootloader::BootloaderSpec,
+	bootloader::{check_flash_file, sort_steps, BootloaderSpec, BootloaderStep},
+	cmdline::{partition_vars, substitute, KernelCmdline},
 	context::{ImageContext, ImageVariant},
-	filesystem::FilesystemType,
-	partition::{PartitionSpec, PartitionType, PartitionUsage},
-	pm::Distro,
+	dtb::{check_dtb_overlays, check_dtb_path},
+	encryption::{luks_uuid, target_mapping_name},
+	erofs::INITRD_MOUNT_OPT,
+	extends::load_spec_table,
+	filesystem::{FilesystemType, FsUuid},
+	fsck::FsckStatus,
+	fstab::FstabStyle,
+	kernel::KernelSpec,
+	media::check_media_size,
+	metadata::{DEFAULT_METADATA_OFFSET, METADATA_SIZE},
+	naming::NameTemplate,
+	partition::{
+		find_vendor_part_type, PartitionSpec, PartitionType, PartitionUsage, SectorOffset,
+		CROS_PRIORITY_SHIFT, CROS_SUCCESSFUL_BIT, DEFAULT_ALIGN, GPT_ATTRIBUTE_LEGACY_BOOT,
+		SECTOR_SIZE,
+	},
+	paths::PathSpec,
+	plan::{plan_layout, plan_layout_in, PlannedPartition},
+	pm::{BspPackages, Distro},
+	recipe::RecipeSpec,
+	reserved::{skip_reserved, take_reserved, ReservedRegion},
+	services::ServicesSpec,
+	size::{parse_size, MIB},
+	slots::expand_slots,
+	sshkey::{lookup_user, PASSWD_PATH},
+	strict::find_unknown_keys,
+	swap::SwapSpec,
+	utils::{
+		check_hostname, check_timezone_name, get_partition_path, sanitize_hostname,
+		sector_size, set_hosts_entry, DEFAULT_USER_ID,
+	},
+	verify::{verify_gpt, verify_mbr},
 };
 use anyhow::{bail, Context, Result};
 use clap::ValueEnum;
 use gptman::{GPTPartitionEntry, GPT};
-use log::debug;
-use mbrman::{MBRPartitionEntry, CHS, MBR};
+use log::{debug, info, warn};
+use mbrman::{LogicalPartition, MBRPartitionEntry, CHS, MBR};
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
+use strum::VariantArray;
+use toml::Value;
 use uuid::Uuid;
 
 const FORBIDDEN_CHARS: &[char] = &['\'', '"', '\\', '/', '{', '}', '[', ']', '!', '`', '*', '&'];
 
+/// Number of the first logical partition of a MBR, whatever the number of the primary ones.
+pub const MBR_FIRST_LOGICAL: u32 = 5;
+/// Type of the extended partition holding the logical partitions (W95 Ext'd, LBA).
+const MBR_EXTENDED_TYPE: u8 = 0x0f;
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, strum::Display)]
 #[serde(rename_all = "lowercase")]
 // It is strange to see MBR as Mbr, GPT as Gpt.
@@ -70,6 +109,17 @@ pub enum PartitionMapType {
 	#[serde(alias = "dos")]
 	MBR,
 	GPT,
+	/// GPT, with a hybrid MBR mirroring some of the partitions.
+	Hybrid,
+	/// No partition table, the only filesystem occupies the whole image.
+	None,
+}
+
+impl PartitionMapType {
+	/// Whether the partitions are recorded in a GPT, including the hybrid one.
+	pub fn is_gpt(&self) -> bool {
+		matches!(self, Self::GPT | Self::Hybrid)
+	}
 }
 
 #[derive(
@@ -93,6 +143,15 @@ pub enum DeviceArch {
 	Riscv64,
 	/// 64-Bit MIPS Release 6
 	Mips64r6el,
+	// Retro architectures, not covered by the mainline recipes
+	/// ARMv7 with hardware floating point
+	Armv7hf,
+	/// ARMv6 with hardware floating point (Raspberry Pi 1 and Zero)
+	Armv6hf,
+	/// 32-bit x86, i486 and up
+	I486,
+	/// IBM POWER (Big Endian)
+	Ppc64,
 }
 
 /// Device Specification
@@ -118,6 +177,26 @@ pub enum DeviceArch {
 ///
 /// The device specification uses the TOML format.
 ///
+/// A specification can inherit the keys of another one with `extends`, and override some of them, see [`crate::extends`]:
+///
+/// ```toml
+/// extends = "../base-rk3588.toml"
+/// id = "opi-5-plus"
+/// name = "Orange Pi 5 Plus"
+/// ```
+///
+/// String values, except for the scripts and the kernel command line, can refer to the variables defined in the `[variables]` table, and to the built-in ones like `${ARCH}` and `${DEVICE_ID}`, see [`crate::variables`]:
+///
+/// ```toml
+/// [variables]
+/// SOC = "rk3588"
+///
+/// [[bootloaders]]
+/// type = "flash_offset"
+/// path = "/usr/lib/u-boot/${SOC}/idbloader.img"
+/// offset = 64
+/// ```
+///
 /// Fields
 /// ======
 ///
@@ -139,6 +218,15 @@ pub enum DeviceArch {
 /// alias = ["pi9", "pi9b"]
 /// ```
 ///
+/// `tags` - Device Tags (Optional)
+/// -------------------------------
+///
+/// A list of strings to select groups of devices with, e.g. in `build-all --tag sbc release`. Tags follow the same naming restrictions, and can not contain white spaces. `check` warns about the tags used by only one device in the registry, which are likely typos.
+///
+/// ```toml
+/// tags = ["sbc", "release"]
+/// ```
+///
 /// `vendor` - Device Vendor
 /// ------------------------
 ///
@@ -161,6 +249,14 @@ pub enum DeviceArch {
 /// - `"riscv64"`: 64-Bit RISC-V CPU.
 /// - `"ppc64el"`: IBM POWER 8 and up, little-endian.
 /// - `"loongson3"`: MIPS Loongson-III CPU.
+/// - `"mips64r6el"`: 64-Bit MIPS Release 6 CPU.
+///
+/// The following retro architectures are not covered by the mainline recipes of aoscbootstrap, the devices using them must define the recipes of every variant in `[recipes]` (see below):
+///
+/// - `"armv7hf"`: ARMv7 CPU with hardware floating point.
+/// - `"armv6hf"`: ARMv6 CPU with hardware floating point, e.g. Raspberry Pi 1 and Zero.
+/// - `"i486"`: 32-bit x86 CPU, i486 and up.
+/// - `"ppc64"`: IBM POWER, big-endian.
 ///
 /// ```toml
 /// arch = "arm64"
@@ -175,8 +271,8 @@ pub enum DeviceArch {
 /// name = "Raspberry Pi 9 Model B"
 /// ```
 ///
-/// `of_compatible` - `compatible` Property in the Device Tree (Optional)
-/// ---------------------------------------------------------------------
+/// `compatible` - `compatible` Property in the Device Tree (Optional)
+/// ------------------------------------------------------------------
 ///
 /// The most relevant string in the `/compatible` property defined in the root of the device tree file. Typically it is the first value of the entry.
 ///
@@ -192,7 +288,29 @@ pub enum DeviceArch {
 ///
 /// The value used here would be `"raspberrypi,9-model-b"`.
 /// ```toml
-/// of_compatible = "raspberrypi,9-model-b"
+/// compatible = "raspberrypi,9-model-b"
+/// ```
+///
+/// `hostname` - Hostname of the Device (Optional)
+/// -----------------------------------------------
+///
+/// The hostname written to `/etc/hostname`, with a matching `127.0.1.1` entry in `/etc/hosts`. Must be a valid hostname (letters, digits and hyphens, separated by dots). `--hostname` on the command line takes precedence.
+///
+/// If not specified, the device ID is used, lowercased and with anything other than letters and digits replaced by hyphens, e.g. `VisionFive_2` becomes `visionfive-2`.
+///
+/// ```toml
+/// hostname = "rpi5"
+/// ```
+///
+/// `timezone` - Timezone of the Device (Optional)
+/// -----------------------------------------------
+///
+/// The timezone of the target, as a name in the zoneinfo database, e.g. `Asia/Shanghai`. `/etc/localtime` is made a relative symbolic link to the zone, and the name is written to `/etc/timezone`. The zone must exist in the system distribution, which is checked before any image is built. `--timezone` on the command line takes precedence.
+///
+/// If not specified, the timezone of the system distribution is kept.
+///
+/// ```toml
+/// timezone = "Asia/Shanghai"
 /// ```
 ///
 /// `bsp_packages` -  List of mandatory BSP packages
@@ -200,6 +318,8 @@ pub enum DeviceArch {
 ///
 /// A list of package names to be installed in addition to the standard system distribution.
 ///
+/// Alternatively, a table of lists keyed by `common`, `base`, `desktop` and `server`. The packages of a variant are the `common` ones followed by its own. Refer to [`BspPackages`] for details.
+///
 /// Installation of BSP packages will be performed after all mountable partitions in this device are mounted, so that scripts in the packages can access these partitions.
 ///
 /// <div class="warning">
@@ -210,6 +330,13 @@ pub enum DeviceArch {
 /// bsp_packages = ["linux+kernel+rpi64+rpi9", "rpi-firmware-boot"]
 /// ```
 ///
+/// ```toml
+/// [bsp_packages]
+/// common = ["linux+kernel+sdm845", "qcom-firmware"]
+/// # Only installed in the desktop variant.
+/// desktop = ["phosh"]
+/// ```
+///
 /// `initrdless` -  Booting without Init Ramdisk (Optional)
 /// -------------------------------------------------------
 ///
@@ -217,22 +344,32 @@ pub enum DeviceArch {
 ///
 /// Default is `false`, can be skipped. If set to `true`, then the following thing will happen:
 ///
-/// - The filesystem table `/etc/fstab` will be generated using the unique identifiers of the partition (`PARTUUID`), rather than unique identifiers of the filesystem (`UUID`).
+/// - The filesystem table `/etc/fstab` will be generated using the unique identifiers of the partition (`PARTUUID`), rather than unique identifiers of the filesystem (`UUID`), unless `fstab_style` says otherwise.
 ///
 /// ```toml
 /// initrdless = true
 /// ```
 ///
+/// `fstab_style`, `disk_device` - References in `/etc/fstab` (Optional)
+/// --------------------------------------------------------------------
+///
+/// How the partitions are referred to in `/etc/fstab`: `uuid`, `partuuid`, `label` or `path`. Each partition can override it with its own `fstab_style`. Defaults to `partuuid` for `initrdless` devices, `uuid` otherwise. The `label` style requires a unique `fs_label` on the partition, the `path` style refers to the partitions on `disk_device`, the disk as seen by the device. See [`crate::fstab`] for details.
+///
+/// ```toml
+/// fstab_style = "path"
+/// disk_device = "/dev/mmcblk0"
+/// ```
+///
 /// `kernel_cmdline` - Kernel command line (Optional)
 /// -------------------------------------------------
 ///
-/// List of strings representing the kernel command line. Can not contain white spaces, and cannot contain `root=` argument.
+/// The kernel command line, either a string or a list of strings (the arguments, which can not contain white spaces).
 ///
-/// The `root=` command line is automatically generated using either `PARTUUID` or `UUID`, depending on whether the device boots without an initrd image.
+/// The arguments can refer to the partitions created during the build with placeholders, e.g. `{ROOT_PARTUUID}`, `{ROOT_UUID}` or `{PART2_PARTUUID}`. Refer to [`crate::cmdline`] for the full list.
 ///
-/// The final kernel command line will be `root=` argument concatenated with rest of the arguments.
+/// Unless a `root=` argument is present, it is automatically generated using either `PARTUUID` or `UUID`, depending on whether the device boots without an initrd image, and prepended to the rest of the arguments.
 ///
-/// If this field is defined, the post installation script and any bootloader scripts will be able to reference it with `$KERNEL_CMDLINE`.
+/// If this field is defined, the post installation script and any bootloader scripts will be able to reference it with `$KERNEL_CMDLINE`, and the `config` bootloader steps can substitute it for `{KERNEL_CMDLINE}` in the configuration files they write (e.g. `grub.cfg`), and the `extlinux` steps use it as their default `append` line. The resolved command line is also logged and recorded in the build report.
 ///
 /// If you want to generate the kernel command line yourself with a script, please skip this field.
 ///
@@ -240,6 +377,54 @@ pub enum DeviceArch {
 /// # The final command line $KERNEL_CMDLINE:
 /// # "root=PARTUUID=01234567-89ab-cdef-0123-456789abcdef console=ttyS0,115200 console=tty0 rw fsck.repair=yes"
 /// kernel_cmdline = ["console=ttyS0,115200", "console=tty0", "rw", "fsck.repair=yes"]
+/// # Same as above
+/// kernel_cmdline = "console=ttyS0,115200 console=tty0 rw fsck.repair=yes"
+/// # With an explicit root= argument
+/// kernel_cmdline = "root=PARTUUID={ROOT_PARTUUID} rootwait resume=PARTUUID={PART3_PARTUUID}"
+/// ```
+///
+/// `dtb`, `dtb_overlays` - Device Tree Blob and Overlays (Optional)
+/// ----------------------------------------------------------------
+///
+/// Paths to the device tree blob and overlays used by the device, relative to the directory of the blobs shipped with the kernel (e.g. `/boot/dtbs/<version>`).
+///
+/// After the BSP packages are installed, the blob is copied to the root of the boot partition, and the overlays to its `overlays` directory. The build fails if any of them is not found. If `compatible` is defined, it must match the blob. Refer to [`crate::dtb`] for details.
+///
+/// A partition with `usage = "boot"` and a `mountpoint` is required.
+///
+/// ```toml
+/// dtb = "rockchip/rk3588-rock-5b.dtb"
+/// dtb_overlays = ["rockchip/overlay/rk3588-uart7-m2.dtbo"]
+/// ```
+///
+/// `deprecated`, `deprecation_reason` - Deprecation (Optional)
+/// ------------------------------------------------------------
+///
+/// Marks the device as no longer supported. `deprecation_reason` is required if `deprecated` is set, and is not allowed otherwise.
+///
+/// Deprecated devices are marked in the device list. `build` refuses to build them unless `--allow-deprecated` is given, and `build-all` skips them by default.
+///
+/// ```toml
+/// deprecated = true
+/// deprecation_reason = "The vendor kernel is no longer maintained"
+/// ```
+///
+/// `[metadata]` - Documentation and Contacts (Optional)
+/// ----------------------------------------------------
+///
+/// Where the users can find the installation notes of the device, and who maintains it:
+///
+/// - `wiki_url`: Link to the documentation of the device.
+/// - `flash_instructions`: How to write the image to the device, either a short text or a link.
+/// - `maintainer`: Who maintains the device specification, e.g. `Name <email>`.
+///
+/// They are shown with `mkrawimg list --long`, and included in the JSON output of `list` and `inspect`. The links must be valid `http` or `https` URLs. The devices without a maintainer are logged by `check` at the debug level, unless they are deprecated.
+///
+/// ```toml
+/// [metadata]
+/// wiki_url = "https://wiki.aosc.io/aosc-os/installation/arm64-rpi/"
+/// flash_instructions = "Write the image to a microSD card with dd or Raspberry Pi Imager"
+/// maintainer = "AOSC OS Maintainers <maintainers@aosc.io>"
 /// ```
 ///
 /// `[sizes]` - Image sizes for each variant
@@ -253,11 +438,51 @@ pub enum DeviceArch {
 ///
 /// The images will be automatically expanded to the size of the medium during the first boot.
 ///
+/// The sizes are integers in MiB, or strings with a unit (see [`crate::size`]) which must be a multiple of 1 MiB.
+///
 /// ```toml
 /// [sizes]
 /// base = 6144
-/// desktop = 22500
-/// server = 6144
+/// desktop = "22GiB"
+/// server = "6GiB"
+/// ```
+///
+/// `image_size_round_to` - Round the image size (Optional)
+/// -------------------------------------------------------
+///
+/// A positive integer in MiB. The image size (plus `trailing_pad`) is rounded up to a multiple of this value. Some eMMC flashing tools require the image size to be a multiple of the erase block (typically 4 MiB).
+///
+/// ```toml
+/// image_size_round_to = 4
+/// ```
+///
+/// `trailing_pad` - Unpartitioned space at the end of the image (Optional)
+/// -----------------------------------------------------------------------
+///
+/// A positive integer in MiB. This amount of space is added to the end of the image and left unpartitioned, so that the image still fits on media slightly smaller than nominal after the root filesystem is expanded.
+///
+/// ```toml
+/// trailing_pad = 8
+/// ```
+///
+/// `target_media_capacity` - Capacity of the target medium (Optional)
+/// -------------------------------------------------------------------
+///
+/// A positive integer in GB (10⁹ bytes, as marketed), the capacity of the SD cards or eMMC modules the images are made for. A warning is shown if an image is larger than the usable capacity of the medium (about 93% of the marketed capacity). Without this, images are compared against the medium their size looks like to be made for, e.g. an image of `32000` MiB is warned about not fitting on 32 GB cards.
+///
+/// ```toml
+/// target_media_capacity = 32
+/// ```
+///
+/// `name_template` - Template of the Image Names (Optional)
+/// --------------------------------------------------------
+///
+/// Names the images of this device after a template instead of the default one, `aosc-os_{variant}_rawimg_{vendor}_{id}_{date}{revision}_{arch}`. The extension (e.g. `.img.xz`) is always appended. `--name-template` on the command line takes precedence.
+///
+/// Supported placeholders are `{variant}`, `{vendor}`, `{id}`, `{date}`, `{revision}` (`.N` with `--revision N`, empty otherwise), `{arch}` and `{compat}` (the `compatible` field, which must be defined to use it). Unknown placeholders are rejected when the specification is parsed. See [`crate::naming`] for details.
+///
+/// ```toml
+/// name_template = "aosc-os_{id}_{variant}_{date}{revision}"
 /// ```
 ///
 /// `partition_map` - Partition Table Type
@@ -267,8 +492,10 @@ pub enum DeviceArch {
 ///
 /// Possible values:
 ///
-/// - `mbr` or `dos`: MBR Partition Table. Can have up to 4 partitions.
+/// - `mbr` or `dos`: MBR Partition Table. Can have up to 4 primary partitions, or up to 3 primary partitions followed by any number of logical partitions, numbered from 5. An extended partition holding the logical partitions is created in the slot after the last primary partition. Only the primary partitions can be marked bootable.
 /// - `gpt`: GUID Partition Table. Can have up to 128 partitions. Most bootloaders supports GPT.
+/// - `hybrid`: GPT, with a hybrid MBR mirroring up to 3 partitions marked with `hybrid_mbr = true`, for the boot ROMs only reading the MBR. See [`crate::hybrid`].
+/// - `none`: No partition table. The only partition (which must be the root partition) is formatted on the whole image, and mounted through the loop device itself. Intended for devices booting from a bare filesystem, e.g. flashed to eMMC or NAND by a vendor tool. Only `script`, `config`, `extlinux` and `uboot_script` bootloaders are allowed, and `initrdless` is not supported since there is no PARTUUID.
 ///
 /// ```toml
 /// partition_map = "gpt"
@@ -277,10 +504,40 @@ pub enum DeviceArch {
 /// `num_partitions` - Number of the partitions
 /// -------------------------------------------
 ///
-/// A positive integer. Defines the number of the partitions in the OS image.
+/// A positive integer. Defines the number of the partitions in the OS image. Optional, derived from the list of partitions if omitted.
+///
+/// If specified, it must match the number of the partitions, and the partitions must be numbered from 1 to `num_partitions` without gaps. The logical partitions of a MBR are the exception, they are numbered from 5 after the primary partitions, e.g. `1, 2, 3, 5, 6, 7` for six partitions.
+///
+/// ```toml
+/// num_partitions = 2
+/// ```
+///
+/// `disk_uuid` - Pinned Disk Identifier (Optional)
+/// -----------------------------------------------
+///
+/// The GUID of the GPT, or the disk signature of the MBR as 8 hexadecimal digits. Randomly generated for each image if omitted, or derived from `--seed` if given (see [`crate::seed`]).
+///
+/// `disk_guid` (GPT) and `disk_signature` (MBR) are accepted as aliases.
+///
+/// Since the PARTUUIDs of the MBR partitions are derived from the disk signature, pinning it also pins them. On GPT, the PARTUUIDs are pinned with `part_uuid` of each partition, see [`PartitionSpec`].
+///
+/// ```toml
+/// partition_map = "mbr"
+/// # PARTUUID of the partition 2 is 5452574f-02
+/// disk_uuid = "5452574f"
+/// # Or
+/// disk_signature = "0x5452574f"
+/// ```
+///
+/// `first_partition_offset` - Gap before the first partition (Optional)
+/// -------------------------------------------------------------------
+///
+/// Where the first partition starts, in 512-byte sectors, or as a size with a unit (see [`crate::size`]). Defaults to the alignment of the first partition (1MiB).
+///
+/// The gap before the first partition is reserved for the bootloaders written with `flash_offset`, which must start within it. The partitions without a starting position never take this space, and the ones with it must not start within it. For example, Rockchip SoCs need 16MiB for idbloader and U-Boot:
 ///
 /// ```toml
-/// num_partitions: 2
+/// first_partition_offset = "16MiB"
 /// ```
 ///
 /// `[[partition]]` - List of Partitions
@@ -314,6 +571,8 @@ pub enum DeviceArch {
 ///
 /// A list of objects describes bootloaders to be applied onto the OS image. Refer to [`BootloaderSpec`] for details.
 ///
+/// The entries are applied in order, unless they declare dependencies on each other. Refer to [`BootloaderStep`] for details.
+///
 /// ```toml
 /// [[bootloader]]
 /// type = "flash_partition"
@@ -330,6 +589,61 @@ pub enum DeviceArch {
 /// name = "finish-bootloaders.sh"
 /// ```
 ///
+/// `[services]` - systemd units to be enabled, disabled or masked (Optional)
+/// -------------------------------------------------------------------------
+///
+/// An object describes the systemd units to be enabled, disabled or masked in the OS image. Refer to [`ServicesSpec`] for details.
+///
+/// ```toml
+/// [services]
+/// enable = ["fake-hwclock"]
+/// mask = ["rfkill-state"]
+/// ```
+///
+/// `[kernel]` - Kernel selection (Optional)
+/// ----------------------------------------
+///
+/// An object selects which kernel to use if the BSP packages install more than one kernel. Refer to [`KernelSpec`] for details.
+///
+/// ```toml
+/// [kernel]
+/// flavor = "linux-kernel-rockchip"
+/// ```
+///
+/// `[swap]` - Swap space (Optional)
+/// --------------------------------
+///
+/// An object describes the swap space to be set up in the OS image, either a zram device or a swap file on the root filesystem. Refer to [`SwapSpec`] for details.
+///
+/// ```toml
+/// [swap]
+/// type = "file"
+/// size = 1024
+/// ```
+///
+/// `[[paths]]` - Paths to be created (Optional)
+/// --------------------------------------------
+///
+/// A list of objects describes the directories, files and symbolic links to be created in the OS image, e.g. mount points for removable media with specific ownership. Refer to [`PathSpec`] for details.
+///
+/// ```toml
+/// [[paths]]
+/// path = "/media/usb0"
+/// type = "d"
+/// mode = 0o750
+/// owner = "kiosk"
+/// ```
+///
+/// `[recipes]` - aoscbootstrap recipes (Optional)
+/// -----------------------------------------------
+///
+/// Paths to the recipes (lists of packages) used to bootstrap the system distribution of each variant, relative to the directory containing the `device.toml`. Intended for ports whose package set diverges from the mainline. The default recipes from aoscbootstrap are used for the variants not listed. Refer to [`RecipeSpec`] for details.
+///
+/// ```toml
+/// [recipes]
+/// desktop = "desktop.lst"
+/// ```
+///
 /// Process of building images
 /// ==========================
 ///
@@ -340,9 +654,11 @@ pub enum DeviceArch {
 /// 5. Filesystems with a mountpoint will be mounted.
 /// 6. The standard system distribution is installed to the target filesystem, and `/etc/fstab` is generated.
 /// 7. BSP packages is installed.
-/// 8. The [post-installation script](#post-installation) is run.
-/// 9. The [bootloaders] will be applied, if defined in the spec file.
-/// 10. The image is unmounted, detached from the loop device, and is compressed to the output directory.
+/// 8. The [services] are enabled, disabled or masked, and the [swap] space is set up, if defined in the spec file.
+/// 9. The [post-installation script](#post-installation) is run.
+/// 10. The declared [paths] are created, if defined in the spec file.
+/// 11. The [bootloaders] will be applied, if defined in the spec file.
+/// 12. The image is unmounted, detached from the loop device, and is compressed to the output directory.
 ///
 /// Post Installation
 /// =================
@@ -367,7 +683,15 @@ pub enum DeviceArch {
 /// There are a few variables pre-defined in the environment to aid your setup process:
 ///
 /// - `DEVICE_ID`: Device ID.
-/// - `DEVICE_COMPATIBLE`: `of_compatible` field defined in the device specification. Empty if not defined.
+/// - `BUILD_ID`: Identifier of this invocation of mkrawimg.
+/// - `DEFAULT_USER`: Name of the built-in user. Empty if no user is created (`--no-user`), in which case anything set up for the user (sudoers drop-ins, autologin, etc.) should be skipped:
+///
+///   ```bash
+///   if [ -n "$DEFAULT_USER" ]; then
+///   	echo "$DEFAULT_USER ALL=(ALL) NOPASSWD: ALL" > /etc/sudoers.d/"$DEFAULT_USER"
+///   fi
+///   ```
+/// - `DEVICE_COMPATIBLE`: `compatible` field defined in the device specification. Empty if not defined.
 /// - `LOOPDEV`: The loop device this OS image is attached on.
 /// - `NUM_PARTITIONS`: Number of the partitions.
 /// - `ROOTPART`: The index of the root partition.
@@ -384,6 +708,7 @@ pub enum DeviceArch {
 ///   Same as the output of `blkid`, can be used directly with `root=UUID=` argument. Empty if this partition does not contain a filesystem.
 /// - `BOOT_PARTUUID`, `BOOT_FSUUID`: Partition and Filesystem UUID for the boot partition, if one is found.
 /// - `ROOT_PARTUUID`, `ROOT_FSUUID`: Partition and Filesystem UUID for the root partition.
+/// - `KERNEL_VERSION`, `KERNEL_IMAGE`, `KERNEL_MODULES`: Version, image path and modules directory of the selected kernel (see [`KernelSpec`]). Not defined if no kernel is installed.
 ///
 /// Examples
 /// ========
@@ -392,6 +717,9 @@ pub enum DeviceArch {
 ///
 /// [device registry]: crate::registry::DeviceRegistry
 /// [bootloaders]: crate::bootloader::BootloaderSpec
+/// [services]: crate::services::ServicesSpec
+/// [swap]: crate::swap::SwapSpec
+/// [paths]: crate::paths::PathSpec
 /// [bootloader scripts]: crate::bootloader::BootloaderSpec#usage
 #[derive(Clone, Debug, Deserialize)]
 #[allow(dead_code)]
@@ -400,6 +728,9 @@ pub struct DeviceSpec {
 	pub id: String,
 	/// Optional aliases to identify the exact device. Can be any combination of letters, digits, hyphen `"-"` and underscore (`"_"`).
 	pub aliases: Option<Vec<String>>,
+	/// Optional tags to select groups of devices with.
+	#[serde(default)]
+	pub tags: Vec<String>,
 	/// The distribution wich will be installed on this device.
 	///
 	/// Possible values:
@@ -420,9 +751,15 @@ pub struct DeviceSpec {
 	/// - `ppc64el`
 	/// - `riscv64`
 	/// - `mips64r6el`
+	/// - `armv7hf`
+	/// - `armv6hf`
+	/// - `i486`
+	/// - `ppc64`
 	pub arch: DeviceArch,
-	/// Vendor of the SoC platform, optional, currently not used.
+	/// Vendor of the SoC platform, optional.
 	/// The name must present in arch/$ARCH/boot/dts in the kernel tree.
+	///
+	/// Used to check if the vendor partition types match the device.
 	pub soc_vendor: Option<String>,
 	/// Full name of the device for humans.
 	pub name: String,
@@ -440,9 +777,13 @@ pub struct DeviceSpec {
 	/// In this case, the value would be `"raspberrypi,5-model-b"`.
 	#[serde(rename = "compatible")]
 	pub of_compatible: Option<String>,
-	/// List of BSP packages to be installed.
-	/// Must be a list of valid package names, no checks are performed.
-	pub bsp_packages: Vec<String>,
+	/// Hostname of the device, derived from the ID if not specified.
+	pub hostname: Option<String>,
+	/// Timezone of the device, e.g. `Asia/Shanghai`.
+	pub timezone: Option<String>,
+	/// BSP packages to be installed, a list or a table of lists for each variant.
+	/// Must be valid package names, no checks are performed.
+	pub bsp_packages: BspPackages,
 	/// Whether the device boots without an initrd image.
 	/// Useful for embedded systems (most of devices targeted by this
 	/// project are embedded systems, aren't they).
@@ -453,17 +794,43 @@ pub struct DeviceSpec {
 	///   device if initrd is not being used.
 	#[serde(default)]
 	pub initrdless: bool,
-	/// Kernel command line.
-	/// Must be a list of strings, and `root=` must not present in this list (it is automatically generated).
-	pub kernel_cmdline: Option<Vec<String>>,
+	/// How the partitions are referred to in `/etc/fstab`, unless they override it.
+	pub fstab_style: Option<FstabStyle>,
+	/// Path of the disk on the device, e.g. `/dev/mmcblk0`, for `fstab_style = "path"`.
+	pub disk_device: Option<String>,
+	/// Kernel command line, a string or a list of strings.
+	/// `root=` is automatically generated if not present, and the placeholders are resolved
+	/// with the partitions created during the build.
+	pub kernel_cmdline: Option<KernelCmdline>,
+	/// Whether the device is no longer supported, e.g. it does not boot with current kernels.
+	#[serde(default)]
+	pub deprecated: bool,
+	/// Why the device is deprecated, required if `deprecated` is set.
+	pub deprecation_reason: Option<String>,
+	/// Documentation links and contacts of the device.
+	#[serde(default)]
+	pub metadata: DeviceMetadata,
+	/// Path to the device tree blob, relative to the directory of the blobs.
+	pub dtb: Option<PathBuf>,
+	/// Paths to the device tree overlays, relative to the directory of the blobs.
+	#[serde(default)]
+	pub dtb_overlays: Vec<PathBuf>,
 	/// The partition map used for the image.
 	///
 	/// Possible values:
 	///
 	/// - `mbr` or `dos`
 	/// - `gpt`
+	/// - `hybrid`: GPT plus a hybrid MBR mirroring up to 3 partitions with `hybrid_mbr = true`, the protective 0xEE entry comes last
+	/// - `none`: the only filesystem occupies the whole image
 	pub partition_map: PartitionMapType,
-	/// Number of the partitions.
+	/// Pinned GUID of the GPT, or disk signature of the MBR.
+	#[serde(alias = "disk_guid", alias = "disk_signature")]
+	pub disk_uuid: Option<String>,
+	/// Number of the partitions, optional.
+	///
+	/// Derived from the partitions if omitted. If specified, it must match the number of partitions.
+	#[serde(default)]
 	pub num_partitions: u32,
 	/// Size of the image for each variant, in MiB.
 	///
@@ -480,6 +847,8 @@ pub struct DeviceSpec {
 	///
 	/// Due to how lists of objects are represented in TOML, the singular "partition" is explicitly allowed.
 	///
+	/// The list can also hold the [reserved regions](crate::reserved) of the disk, which are not partitions.
+	///
 	/// ### Example
 	///
 	/// ```toml
@@ -500,7 +869,7 @@ pub struct DeviceSpec {
 	// Can be `[[partition]]` to avoid awkwardness.
 	#[serde(alias = "partition")]
 	pub partitions: Vec<PartitionSpec>,
-	/// Actions to apply bootloaders. Refer to [`BootloaderSpec`] for details.
+	/// Actions to apply bootloaders. Refer to [`BootloaderSpec`] and [`BootloaderStep`] for details.
 	///
 	/// Due to how lists of objects are represented in TOML, the singular "bootloader" is explicitly allowed.
 	///
@@ -516,7 +885,84 @@ pub struct DeviceSpec {
 	/// script = "apply-bootloader2.sh"
 	/// ```
 	#[serde(alias = "bootloader")]
-	pub bootloaders: Option<Vec<BootloaderSpec>>,
+	pub bootloaders: Option<Vec<BootloaderStep>>,
+	/// systemd units to be enabled, disabled or masked. Refer to [`ServicesSpec`] for details.
+	///
+	/// ### Example
+	///
+	/// ```toml
+	/// [services]
+	/// enable = ["fake-hwclock"]
+	/// mask = ["rfkill-state"]
+	/// ```
+	pub services: Option<ServicesSpec>,
+	/// Selects which kernel to use if more than one is installed. Refer to [`KernelSpec`] for details.
+	///
+	/// ### Example
+	///
+	/// ```toml
+	/// [kernel]
+	/// flavor = "linux-kernel-rockchip"
+	/// ```
+	pub kernel: Option<KernelSpec>,
+	/// Swap space to be set up in the image. Refer to [`SwapSpec`] for details.
+	///
+	/// ### Example
+	///
+	/// ```toml
+	/// [swap]
+	/// type = "zram"
+	/// ram_fraction = 0.5
+	/// ```
+	pub swap: Option<SwapSpec>,
+	/// Paths to be created in the image, e.g. mount points. Refer to [`PathSpec`] for details.
+	///
+	/// ### Example
+	///
+	/// ```toml
+	/// [[paths]]
+	/// path = "/media/usb0"
+	/// type = "d"
+	/// owner = "kiosk"
+	/// ```
+	pub paths: Option<Vec<PathSpec>>,
+	/// aoscbootstrap recipes overriding the default ones. Refer to [`RecipeSpec`] for details.
+	///
+	/// ### Example
+	///
+	/// ```toml
+	/// [recipes]
+	/// desktop = "desktop.lst"
+	/// ```
+	pub recipes: Option<RecipeSpec>,
+	/// Round the image size up to a multiple of this size, in MiB.
+	///
+	/// Some eMMC flashing tools require the image size to be a multiple of the erase block.
+	pub image_size_round_to: Option<u64>,
+	/// Where the first partition starts, the gap before it is reserved for the bootloaders.
+	pub first_partition_offset: Option<SectorOffset>,
+	/// Regions of the disk no partition may be placed in, declared in the partition list with `type = "reserved"`.
+	///
+	/// Refer to [`crate::reserved`] for details.
+	#[serde(skip_deserializing)]
+	pub reserved: Vec<ReservedRegion>,
+	/// Offset of the image metadata, in bytes. Refer to [`crate::metadata`] for details.
+	///
+	/// Must be aligned to 512 bytes, the default is just after the primary GPT (`0x4400`).
+	/// The metadata is not written if this area collides with the bootloaders.
+	pub metadata_offset: Option<u64>,
+	/// Unpartitioned space at the end of the image, in MiB.
+	///
+	/// The space is added to the image size, and is not used by the max sized partition.
+	pub trailing_pad: Option<u64>,
+	/// Capacity of the medium the images are made for, in GB (10⁹ bytes), e.g. `32` for 32 GB SD cards.
+	///
+	/// Images larger than the usable capacity of the medium are warned about. Refer to [`crate::media`] for details.
+	pub target_media_capacity: Option<u64>,
+	/// Template of the image names, overridden by `--name-template`.
+	///
+	/// Refer to [`crate::naming`] for details.
+	pub name_template: Option<NameTemplate>,
 	/// Path to the device.toml.
 	///
 	/// This field is ignored during deserialization, and is automatically filled.
@@ -524,25 +970,271 @@ pub struct DeviceSpec {
 	pub file_path: PathBuf,
 }
 
+/// Documentation links and contacts of a device, the `[metadata]` table.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct DeviceMetadata {
+	pub wiki_url: Option<String>,
+	pub flash_instructions: Option<String>,
+	pub maintainer: Option<String>,
+}
+
+impl DeviceMetadata {
+	pub fn is_empty(&self) -> bool {
+		self == &Self::default()
+	}
+
+	/// Make sure the links are valid URLs.
+	pub fn check(&self) -> Result<()> {
+		if let Some(url) = &self.wiki_url {
+			check_url(url).context("Invalid wiki_url")?;
+		}
+		// Either a short text or a link.
+		if let Some(text) = &self.flash_instructions {
+			if text.starts_with("http://") || text.starts_with("https://") {
+				check_url(text).context("Invalid flash_instructions")?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Make sure the URL parses, and is a link to a web page.
+pub(crate) fn check_url(url: &str) -> Result<()> {
+	let parsed = Url::parse(url).context(format!("'{}' is not a valid URL", url))?;
+	if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+		bail!("'{}' must be a http or https link", url);
+	}
+	Ok(())
+}
+
+/// Image sizes of the variants, in MiB.
 #[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "Value")]
 pub struct ImageVariantSizes {
 	pub base: u64,
 	pub desktop: u64,
 	pub server: u64,
 }
 
+/// Parse an image size, an integer in MiB or a string with a unit.
+fn parse_image_size(value: Value) -> Result<u64> {
+	let size = match value {
+		Value::Integer(n) if n > 0 => n as u64,
+		Value::String(s) => {
+			let bytes = parse_size(&s)?;
+			if bytes % MIB != 0 {
+				bail!("Image size '{}' must be a multiple of 1 MiB", s);
+			}
+			bytes / MIB
+		}
+		v => bail!(
+			"Invalid image size '{}', expected a positive integer in MiB or a size with a unit",
+			v
+		),
+	};
+	if size == 0 {
+		bail!("Image size can not be zero");
+	}
+	Ok(size)
+}
+
+impl TryFrom<Value> for ImageVariantSizes {
+	type Error = anyhow::Error;
+
+	fn try_from(value: Value) -> Result<Self> {
+		let Value::Table(mut table) = value else {
+			bail!("Image sizes must be a table with the base, desktop and server keys");
+		};
+		let mut get = |key: &str| {
+			table.remove(key)
+				.context(format!("Image size of the {} variant is missing", key))
+				.and_then(parse_image_size)
+				.context(format!("Invalid image size of the {} variant", key))
+		};
+		let sizes = Self {
+			base: get("base")?,
+			desktop: get("desktop")?,
+			server: get("server")?,
+		};
+		if let Some(key) = table.keys().next() {
+			bail!(
+				"Unknown variant '{}' in the image sizes, possible variants are base, desktop and server",
+				key
+			);
+		}
+		Ok(sizes)
+	}
+}
+
+/// Size of an image given on the command line, in MiB.
+///
+/// Accepts plain numbers in MiB, or numbers with a binary unit, e.g. `8G`, `10240M` or `8GiB`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageSize(pub u64);
+
+impl FromStr for ImageSize {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		let s = s.trim();
+		match s.parse::<i64>() {
+			Ok(n) => parse_image_size(Value::Integer(n)),
+			Err(_) => parse_image_size(Value::String(s.to_owned())),
+		}
+		.map(Self)
+	}
+}
+
 #[allow(dead_code)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PartitionMapData {
 	pub uuid: String,
 	/// Data for each partition
 	pub data: HashMap<u32, PartitionData>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PartitionData {
 	pub num: u32,
 	pub part_uuid: String,
-	pub fs_uuid: Option<String>,
+	pub fs_uuid: Option<FsUuid>,
+	/// Label applied to the filesystem.
+	#[serde(default)]
+	pub fs_label: Option<String>,
+	/// UUID of the LUKS2 container, if the partition is encrypted.
+	#[serde(default)]
+	pub luks_uuid: Option<String>,
+	/// Result of the check of the filesystem.
+	#[serde(default)]
+	pub fsck: Option<FsckStatus>,
+}
+
+/// Make sure the partition numbers are exactly 1..=N in order, and N matches `num_partitions`.
+///
+/// On MBR, the logical partitions are numbered from 5 after the primary ones,
+/// the slot of partition 4 (or the one after the last primary partition) holds
+/// the extended partition.
+pub fn check_partition_nums(
+	nums: &[u32],
+	num_partitions: u32,
+	map: &PartitionMapType,
+) -> Result<()> {
+	if num_partitions as usize != nums.len() {
+		bail!(
+			"num_partitions is {}, but {} partitions are defined",
+			num_partitions,
+			nums.len()
+		);
+	}
+	let logical = *map == PartitionMapType::MBR && nums.iter().any(|&n| n >= MBR_FIRST_LOGICAL);
+	if logical && nums.contains(&(MBR_FIRST_LOGICAL - 1)) {
+		bail!("Partition 4 can not be defined along with logical partitions, its slot holds the extended partition. Use at most 3 primary partitions, and number the logical partitions from 5.");
+	}
+	let primary = nums.iter().filter(|&&n| n < MBR_FIRST_LOGICAL).count();
+	for (idx, &num) in nums.iter().enumerate() {
+		let expected = if logical && idx >= primary {
+			(idx - primary) as u32 + MBR_FIRST_LOGICAL
+		} else {
+			idx as u32 + 1
+		};
+		if num == expected {
+			continue;
+		}
+		if nums[..idx].contains(&num) {
+			bail!("Duplicate partition number: {}", num);
+		}
+		if num == 0 {
+			bail!("Partition numbers should start from 1.");
+		}
+		if num < expected {
+			bail!("Please keep the partitions in order");
+		}
+		bail!(
+			"Partition numbers must not have gaps: expected {}, got {}",
+			expected,
+			num
+		);
+	}
+	Ok(())
+}
+
+/// Parse the disk signature of a MBR, 8 hexadecimal digits with an optional `0x` prefix.
+pub fn parse_disk_signature(s: &str) -> Result<u32> {
+	let digits = s.strip_prefix("0x").unwrap_or(s);
+	if digits.len() != 8 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+		bail!(
+			"Invalid disk signature '{}', expected 8 hexadecimal digits",
+			s
+		);
+	}
+	// Safe to unwrap, 8 hexadecimal digits always fit.
+	Ok(u32::from_str_radix(digits, 16).unwrap())
+}
+
+/// PARTUUID of a MBR partition, rendered the same way as the kernel and blkid do.
+pub fn mbr_partuuid(disk_signature: u32, num: u32) -> String {
+	format!("{:08x}-{:02x}", disk_signature, num)
+}
+
+/// Pad the image size and round it up to a multiple of `round_to`, all in bytes.
+pub fn pad_image_size(nominal: u64, round_to: u64, pad: u64) -> u64 {
+	let size = nominal + pad;
+	if round_to == 0 {
+		size
+	} else {
+		size.div_ceil(round_to) * round_to
+	}
+}
+
+/// What marks the partition bootable: the legacy BIOS bootable attribute, or
+/// a type booted by the firmware. The active flag of a MBR is only set on the
+/// boot partition.
+fn boot_markers(partition: &PartitionSpec) -> Vec<&'static str> {
+	let mut markers = Vec::new();
+	if partition.attributes.contains(GPT_ATTRIBUTE_LEGACY_BOOT) {
+		markers.push("the legacy-boot attribute");
+	}
+	match partition.part_type {
+		PartitionType::EFI => markers.push("the ESP type"),
+		PartitionType::ChromeosKernel { .. } => markers.push("the ChromeOS kernel type"),
+		_ => (),
+	}
+	markers
+}
+
+/// Firmware partitions are never formatted, `filesystem = "none"` is implied.
+fn imply_firmware_filesystem(table: &mut toml::Table) {
+	for key in ["partitions", "partition"] {
+		let Some(Value::Array(entries)) = table.get_mut(key) else {
+			continue;
+		};
+		for entry in entries.iter_mut().filter_map(Value::as_table_mut) {
+			if entry.get("usage").and_then(Value::as_str) == Some("firmware")
+				&& !entry.contains_key("filesystem")
+			{
+				entry.insert("filesystem".into(), "none".into());
+			}
+		}
+	}
+}
+
+/// The planned place of partition `num`, the extended partition of a MBR included.
+fn planned(layout: &[PlannedPartition], num: u32) -> Result<&PlannedPartition> {
+	layout.iter()
+		.find(|p| p.num == num)
+		.context(format!("Partition {} is not in the planned layout", num))
+}
+
+/// The starting sector and the size of a partition in a MBR.
+fn mbr_sectors(planned: &PlannedPartition) -> Result<(u32, u32)> {
+	Ok((
+		planned.start
+			.try_into()
+			.context("Partition start exceeds the limit of MBR")?,
+		planned.size
+			.try_into()
+			.context("Partition size exceeds the limit of MBR")?,
+	))
 }
 
 impl Default for ImageVariantSizes {
@@ -563,26 +1255,91 @@ impl DeviceSpec {
 				file.display()
 			)
 		};
-		let content = fs::read_to_string(file)
-			.context(format!("Unable to read file '{}'", &file.to_string_lossy()))?;
-		let mut device: DeviceSpec = toml::from_str(&content).context(format!(
+		let mut table = load_spec_table(file)?;
+		imply_firmware_filesystem(&mut table);
+		let reserved = take_reserved(&mut table)
+			.context(format!("Invalid reserved regions in '{}'", file.display()))?;
+		let mut device: DeviceSpec = Value::Table(table).try_into().context(format!(
 			"Unable to treat '{}' as an entry of the registry",
 			&file.to_string_lossy()
 		))?;
 		device.file_path = file.canonicalize()?;
+		device.reserved = reserved;
+		device.partitions = expand_slots(device.partitions)
+			.context(format!("Invalid partitions in '{}'", file.display()))?;
+		// Derive num_partitions if omitted.
+		if device.num_partitions == 0 {
+			device.num_partitions = device.partitions.len() as u32;
+		}
+		let nums = device.partitions.iter().map(|p| p.num).collect::<Vec<_>>();
+		check_partition_nums(&nums, device.num_partitions, &device.partition_map)
+			.context(format!("Invalid partitions in '{}'", file.display()))?;
 		Ok(device)
 	}
 
-	pub fn check(&self) -> Result<()> {
-		let path: &Path = self.file_path.as_ref();
-		let dirname = path
-			.parent()
-			.context("Failed to get the directory containing the device spec file")?;
-		let mut strs_to_chk = vec![&self.id, &self.vendor];
-		if let Some(aliases) = &self.aliases {
-			aliases.iter().for_each(|s| strs_to_chk.push(s));
+	/// Describe why the device is deprecated.
+	pub fn deprecation(&self) -> &str {
+		self.deprecation_reason
+			.as_deref()
+			.unwrap_or("no reason given")
+	}
+
+	/// Refuse to build a deprecated device unless `allow_deprecated`, warn if allowed.
+	pub fn check_deprecated(&self, allow_deprecated: bool) -> Result<()> {
+		if !self.deprecated {
+			return Ok(());
 		}
-		if let Some(c) = &self.of_compatible {
+		if !allow_deprecated {
+			bail!(
+				"Device {} is deprecated: {}\nUse --allow-deprecated to build it anyway.",
+				self.id,
+				self.deprecation()
+			);
+		}
+		warn!("Device {} is deprecated: {}", self.id, self.deprecation());
+		Ok(())
+	}
+
+	/// Warn about the unknown keys in the specification and its bases, or fail if `strict`.
+	pub fn check_unknown_keys(&self, strict: bool) -> Result<()> {
+		let unknown = find_unknown_keys(&self.file_path)?;
+		if unknown.is_empty() {
+			return Ok(());
+		}
+		if strict {
+			bail!(
+				"Unknown keys in the specification:\n{}",
+				unknown.iter()
+					.map(ToString::to_string)
+					.collect::<Vec<_>>()
+					.join("\n")
+			);
+		}
+		for key in unknown {
+			warn!("{}, ignored", key);
+		}
+		Ok(())
+	}
+
+	pub fn check(&self) -> Result<()> {
+		let path: &Path = self.file_path.as_ref();
+		let dirname = path
+			.parent()
+			.context("Failed to get the directory containing the device spec file")?;
+		let mut strs_to_chk = vec![&self.id, &self.vendor];
+		if let Some(aliases) = &self.aliases {
+			aliases.iter().for_each(|s| strs_to_chk.push(s));
+		}
+		for (idx, tag) in self.tags.iter().enumerate() {
+			if tag.is_empty() || tag.contains(char::is_whitespace) {
+				bail!("Tag '{}' must not be empty or contain white spaces", tag);
+			}
+			if self.tags[..idx].contains(tag) {
+				bail!("Tag '{}' is declared more than once", tag);
+			}
+			strs_to_chk.push(tag);
+		}
+		if let Some(c) = &self.of_compatible {
 			strs_to_chk.push(c)
 		}
 		for field in &strs_to_chk {
@@ -610,53 +1367,60 @@ impl DeviceSpec {
 				);
 			}
 		}
+		if let Some(hostname) = &self.hostname {
+			check_hostname(hostname)?;
+		}
+		if let Some(timezone) = &self.timezone {
+			check_timezone_name(timezone)?;
+		}
 		if self.partitions.is_empty() {
 			bail!("No partition defined for this device");
 		}
 		// Check consistency
-		if self.num_partitions != self.partitions.len() as u32 {
-			bail!(
-				"Please update the num_partitions field: should be {}, got {}",
-				self.partitions.len(),
-				self.num_partitions
-			);
-		}
+		let nums = self.partitions.iter().map(|p| p.num).collect::<Vec<_>>();
+		check_partition_nums(&nums, self.num_partitions, &self.partition_map)?;
 		// Can't have too many partitions
 		let len = self.partitions.len();
-		match self.partition_map {
-			PartitionMapType::MBR => {
-				if len > 4 {
-					bail!("MBR partition map can contain up to 4 partitions");
-				}
-			}
-			PartitionMapType::GPT => {
-				if len > 128 {
-					bail!("Too many partitions for GPT");
-				}
-			}
+		// On MBR, partitions beyond the 4 slots are logical ones, checked with the numbers.
+		if self.partition_map.is_gpt() && len > 128 {
+			bail!("Too many partitions for GPT");
+		}
+		if self.partition_map == PartitionMapType::None {
+			self.check_partitionless()?;
 		}
 		// Some devices may not have a boot partition.
 		// Some devices may use MBR partition map.
 		// Let's make the root partition the only requirement here.
 		let mut root_part = None;
-		let mut last_partition_num = 0;
 		for partition in &self.partitions {
-			if let Some(start) = partition.start_sector {
-				if self.partition_map == PartitionMapType::GPT && start <= 33 {
+			if let Some(start) = partition.start() {
+				if self.partition_map.is_gpt() && start <= 33 {
 					bail!("Starting sector of partition {} overlaps the partition table itself.", partition.num);
 				}
 			}
-			if partition.part_type == PartitionType::Swap {
-				bail!("Swap partitions are not allowed on raw images.");
-			}
-			if partition.num == 0 {
-				bail!("Partition numbers should start from 1.");
+			self.check_swap_partition(partition)?;
+			self.check_content(dirname, partition)?;
+			self.check_encryption(dirname, partition)?;
+			if self.partition_map == PartitionMapType::MBR
+				&& partition.num >= MBR_FIRST_LOGICAL
+				&& partition.usage == PartitionUsage::Boot
+			{
+				bail!("Partition {} is a logical partition, only the primary partitions can be marked bootable. Make the boot partition one of the partitions 1 to 3.", partition.num);
 			}
-			if partition.num < last_partition_num {
-				bail!("Please keep the partitions in order");
+			match &partition.part_type {
+				PartitionType::Vendor { alias } => {
+					self.check_vendor_part_type(partition.num, alias)?
+				}
+				PartitionType::Discoverable { .. } | PartitionType::Uuid { .. } => {
+					self.check_part_type(partition)?
+				}
+				PartitionType::ChromeosKernel { .. } => {
+					self.check_chromeos_kernel(partition)?
+				}
+				_ => (),
 			}
-			if partition.num == last_partition_num {
-				bail!("Duplicate partition number: {}", partition.num);
+			if partition.usage == PartitionUsage::Firmware {
+				self.check_firmware_partition(partition)?;
 			}
 			if partition.usage == PartitionUsage::Rootfs {
 				if root_part.is_some() {
@@ -666,6 +1430,9 @@ impl DeviceSpec {
 				if partition.mountpoint != Some("/".to_owned()) {
 					bail!("Sorry, but for now root partition must have a mountpoint '/'.")
 				}
+				if partition.filesystem == FilesystemType::Exfat {
+					bail!("exFAT does not support the ownership and permissions of files, it can not be the root filesystem");
+				}
 			}
 			if let Some(l) = &partition.label {
 				if self.partition_map == PartitionMapType::MBR {
@@ -675,24 +1442,149 @@ impl DeviceSpec {
 					bail!("Label for partition {} exceeds the 35-character limit", partition.num);
 				}
 			}
-			last_partition_num = partition.num;
-			partition.filesystem.check(&partition.fs_label)?;
+			partition
+				.filesystem
+				.check(&partition.fs_label)
+				.context(format!(
+					"Invalid fs_label of partition {}",
+					partition.num
+				))?;
+			if let Some(options) = &partition.mkfs_options {
+				partition
+					.filesystem
+					.check_mkfs_options(
+						options,
+						partition.fs_label.is_some(),
+						partition.fs_uuid.is_some(),
+					)
+					.context(format!("Partition {}", partition.num))?;
+			}
+			self.check_attributes(partition)?;
 		}
 		if root_part.is_none() {
 			bail!("No root partition defined");
 		}
+		self.check_pinned_uuids()?;
+		if let Some(services) = &self.services {
+			services.check()?;
+		}
+		if let Some(swap) = &self.swap {
+			let mountpoints = self
+				.partitions
+				.iter()
+				.filter(|p| p.usage != PartitionUsage::Rootfs)
+				.filter_map(|p| p.mountpoint.as_deref())
+				.collect::<Vec<_>>();
+			swap.check(&mountpoints)?;
+		}
+		if let Some(paths) = &self.paths {
+			for (idx, path) in paths.iter().enumerate() {
+				path.check()?;
+				if paths[..idx].iter().any(|p| p.path == path.path) {
+					bail!(
+						"Path '{}' is declared more than once",
+						path.path.display()
+					);
+				}
+			}
+		}
+		if !self.arch.is_mainline() {
+			let missing = ImageVariant::VARIANTS
+				.iter()
+				.filter(|v| self.recipes.as_ref().and_then(|r| r.get(v)).is_none())
+				.map(|v| v.to_string().to_lowercase())
+				.collect::<Vec<_>>();
+			if !missing.is_empty() {
+				bail!(
+					"{} is not supported by the mainline recipes of aoscbootstrap. Define the recipes of the {} variants in [recipes], e.g. {} = \"{}-{}.lst\"",
+					self.arch.to_string().to_lowercase(),
+					missing.join(", "),
+					missing[0],
+					missing[0],
+					self.arch.to_string().to_lowercase()
+				);
+			}
+		}
+		if let Some(recipes) = &self.recipes {
+			for recipe in [&recipes.base, &recipes.desktop, &recipes.server]
+				.into_iter()
+				.flatten()
+			{
+				if !dirname.join(recipe).is_file() {
+					bail!(
+						"Recipe '{}' not found within the same directory as the device.toml",
+						recipe.display()
+					);
+				}
+			}
+		}
+		self.check_first_partition_offset()?;
+		self.check_reserved()?;
+		self.check_hybrid_mbr()?;
+		self.check_erofs()?;
+		self.check_btrfs()?;
+		self.check_ext4()?;
+		self.check_fstab_style()?;
+		self.check_metadata_offset()?;
+		self.check_image_size();
+		if self.dtb.is_some() || !self.dtb_overlays.is_empty() {
+			for path in self.dtb.iter().chain(&self.dtb_overlays) {
+				check_dtb_path(path)?;
+			}
+			check_dtb_overlays(&self.dtb_overlays)?;
+			let boot = self
+				.partitions
+				.iter()
+				.find(|p| p.usage == PartitionUsage::Boot)
+				.context("dtb and dtb_overlays require a partition with usage = \"boot\"")?;
+			if boot.filesystem == FilesystemType::None || boot.mountpoint.is_none() {
+				bail!("The boot partition must have a filesystem and a mountpoint to install the device tree blobs");
+			}
+		}
+		match (
+			self.deprecated,
+			self.deprecation_reason.as_deref().map(str::trim),
+		) {
+			(true, None | Some("")) => {
+				bail!("A deprecated device must have a deprecation_reason")
+			}
+			(false, Some(_)) => {
+				bail!("deprecation_reason is set, but the device is not deprecated")
+			}
+			_ => (),
+		}
+		self.metadata.check()?;
+		if self.metadata.maintainer.is_none() && !self.deprecated {
+			debug!("Device {} has no maintainer defined in [metadata]", self.id);
+		}
+		if let Some(cmdline) = &self.kernel_cmdline {
+			cmdline.check(self.initrdless, &self.template_vars(None)?)
+				.context("Invalid kernel_cmdline")?;
+		}
 		if let Some(bootloaders) = &self.bootloaders {
+			sort_steps(bootloaders)?;
 			for bl in bootloaders {
-				match bl {
+				match &bl.spec {
 					BootloaderSpec::Script { name } => {
 						let script_path = dirname.join(name);
 						if !script_path.is_file() {
 							bail!("Script '{}' not found within the same directory as the device.toml", &name);
 						}
 					}
-					BootloaderSpec::FlashPartition { path: _, partition } => {
-						if let Some(p) =
-							self.partitions.get(*partition as usize)
+					BootloaderSpec::FlashPartition {
+						path,
+						partition,
+						source,
+					} => {
+						check_flash_file(
+							path.as_deref(),
+							source.as_ref(),
+							bootloaders,
+						)?;
+						if let Some(p) = self
+							.partitions
+							.iter()
+							.find(|p| p.num as u64 == *partition)
 						{
 							if p.filesystem != FilesystemType::None {
 								bail!("A bootloader tries to write to partition {} which already contains an active filesystem.", p.num);
@@ -701,52 +1593,625 @@ impl DeviceSpec {
 							bail!("Partition {} specified by a bootloader is not found.", partition);
 						}
 					}
-					BootloaderSpec::FlashOffset { path: _, offset } => {
+					BootloaderSpec::FlashOffset {
+						path,
+						source,
+						offset,
+						..
+					} => {
+						check_flash_file(
+							path.as_deref(),
+							source.as_ref(),
+							bootloaders,
+						)?;
 						// Anything must start from at least LBA 34.
-						if self.partition_map == PartitionMapType::GPT
-							&& *offset < 512 * 34
+						if self.partition_map.is_gpt() && *offset < 512 * 34
 						{
 							bail!("A bootloader tries to overlap the partition table. It must start from at least 0x4400 (17408), or LBA 34.");
 						}
 					}
+					BootloaderSpec::Config { template, path } => {
+						let template_path = dirname.join(template);
+						if !template_path.is_file() {
+							bail!("Template '{}' not found within the same directory as the device.toml", &template);
+						}
+						if !path.is_absolute() {
+							bail!("The configuration file '{}' must be an absolute path", path.display());
+						}
+						let content = fs::read_to_string(&template_path)
+							.context(format!(
+								"Failed to read the template '{}'",
+								template
+							))?;
+						substitute(&content, &self.template_vars(None)?)
+							.context(format!(
+								"Invalid template '{}'",
+								template
+							))?;
+					}
+					BootloaderSpec::Extlinux(spec) => {
+						self.check_extlinux(spec)?;
+					}
+					BootloaderSpec::UbootScript(spec) => {
+						self.check_uboot_script(dirname, spec)?;
+					}
+					BootloaderSpec::GrubEfi(spec) => {
+						self.check_grub_efi(spec)?;
+					}
+					BootloaderSpec::SystemdBoot(spec) => {
+						self.check_systemd_boot(spec)?;
+					}
+					BootloaderSpec::Raw(spec) => {
+						self.check_raw(dirname, spec, bootloaders)?;
+					}
+					BootloaderSpec::Sunxi(spec) => {
+						self.check_sunxi(dirname, spec, bootloaders)?;
+					}
 				}
 			}
 		}
 		Ok(())
 	}
 
-	pub fn gen_kernel_cmdline(&self, pm_data: &PartitionMapData) -> Result<String> {
-		let str = if let Some(cmdline) = self.kernel_cmdline.as_ref() {
-			let mut str = String::new();
-			let root_part = self.partitions.iter().find(|x| x.usage == PartitionUsage::Rootfs).context("Unable to find a root filesystem to generate kernel command line")?;
-			let root_param = if self.initrdless {
-				format!(
-					"root=PARTUUID={} ",
-					&pm_data.data
-						.get(&root_part.num)
-						.as_ref()
-						.unwrap()
-						.part_uuid
-				)
-			} else {
-				format!(
-					"root=UUID={} ",
-					&pm_data.data
-						.get(&root_part.num)
-						.as_ref()
-						.unwrap()
-						.fs_uuid
-						.as_ref()
-						.unwrap()
-				)
-			};
-			str += &root_param;
-			str += &cmdline.join(" ");
-			str
+	/// Partitions with raw contents have no filesystem, and the files must fit in them.
+	fn check_content(&self, dirname: &Path, partition: &PartitionSpec) -> Result<()> {
+		let Some(content) = &partition.content else {
+			return Ok(());
+		};
+		let num = partition.num;
+		if partition.filesystem != FilesystemType::None {
+			bail!(
+				"Partition {} has raw contents, its filesystem must be \"none\"",
+				num
+			);
+		}
+		if partition.mountpoint.is_some() {
+			bail!(
+				"Partition {} has raw contents, it can not have a mountpoint",
+				num
+			);
+		}
+		if matches!(
+			partition.usage,
+			PartitionUsage::Rootfs | PartitionUsage::Boot
+		) {
+			bail!("Partition {} has raw contents, it can not be the root or the boot partition", num);
+		}
+		let flashed = self.bootloaders.iter().flatten().any(|bl| {
+			matches!(bl.spec, BootloaderSpec::FlashPartition { partition, .. } if partition == num as u64)
+		});
+		if flashed {
+			bail!("Partition {} has raw contents, a bootloader can not be flashed to it", num);
+		}
+		// The smallest size among the variants, unless it fills the rest of the image.
+		let capacity = ImageVariant::VARIANTS
+			.iter()
+			.filter_map(|v| partition.sectors(v))
+			.min()
+			.map(|sectors| sectors * SECTOR_SIZE);
+		let steps = self.bootloaders.as_deref().unwrap_or_default();
+		content.check(dirname, capacity, steps)
+			.context(format!("Partition {}: invalid content", num))
+	}
+
+	/// Encrypted partitions hold a filesystem, and the boot partition is never encrypted.
+	fn check_encryption(&self, dirname: &Path, partition: &PartitionSpec) -> Result<()> {
+		let Some(encryption) = &partition.encryption else {
+			return Ok(());
+		};
+		let num = partition.num;
+		if partition.usage == PartitionUsage::Boot {
+			bail!("Partition {} is the boot partition, it can not be encrypted, the firmware and the bootloaders have to read it", num);
+		}
+		if !partition.filesystem.is_mountable() {
+			bail!(
+				"Partition {} is encrypted, it must have a filesystem other than swap",
+				num
+			);
+		}
+		if partition.usage == PartitionUsage::Rootfs && self.initrdless {
+			bail!("Partition {} is an encrypted root partition, it has to be unlocked by the initrd, which initrdless devices do not have", num);
+		}
+		if partition.usage == PartitionUsage::Rootfs && self.kernel_cmdline.is_none() {
+			bail!("Partition {} is an encrypted root partition, kernel_cmdline must be defined to tell the initrd to unlock it", num);
+		}
+		encryption
+			.check(dirname)
+			.context(format!("Partition {}: invalid encryption", num))
+	}
+
+	/// Swap partitions go along with the swap filesystem, and are not mounted.
+	fn check_swap_partition(&self, partition: &PartitionSpec) -> Result<()> {
+		let num = partition.num;
+		let is_swap = partition.usage == PartitionUsage::Swap;
+		if is_swap != (partition.filesystem == FilesystemType::Swap) {
+			bail!(
+				"Partition {}: usage \"swap\" and filesystem \"swap\" must be used together",
+				num
+			);
+		}
+		if partition.part_type == PartitionType::Swap && !is_swap {
+			bail!(
+				"Partition {} has the swap partition type, but it is not a swap partition",
+				num
+			);
+		}
+		if !is_swap {
+			return Ok(());
+		}
+		if partition.mountpoint.is_some() {
+			bail!(
+				"Partition {} is a swap partition, it can not have a mountpoint",
+				num
+			);
+		}
+		let markers = boot_markers(partition);
+		if !markers.is_empty() {
+			warn!(
+				"Swap partition {} is marked bootable by {}, it can not be booted from",
+				num,
+				markers.join(" and ")
+			);
+		}
+		Ok(())
+	}
+
+	/// Make sure the GPT attribute bits are valid, and warn if `legacy-boot` has no effect.
+	fn check_attributes(&self, partition: &PartitionSpec) -> Result<()> {
+		if partition.attributes.0.is_empty() {
+			return Ok(());
+		}
+		if !self.partition_map.is_gpt() {
+			bail!(
+				"Partition {}: attributes are only available on GPT",
+				partition.num
+			);
+		}
+		partition
+			.attributes
+			.check()
+			.context(format!("Partition {}: invalid attributes", partition.num))?;
+		let writes_pmbr = self.bootloaders.iter().flatten().any(|bl| {
+			matches!(bl.spec, BootloaderSpec::FlashOffset { offset: 0, .. })
+				|| matches!(&bl.spec, BootloaderSpec::Raw(raw) if raw.offset.0 == 0)
+		});
+		if partition.attributes.contains(GPT_ATTRIBUTE_LEGACY_BOOT) && !writes_pmbr {
+			warn!("Partition {} is marked legacy-boot, but no bootloader writes the boot code to the protective MBR (a flash_offset or raw step at offset 0), BIOSes will not boot it", partition.num);
+		}
+		Ok(())
+	}
+
+	/// Make sure the pinned UUIDs are valid and unique within the device.
+	fn check_pinned_uuids(&self) -> Result<()> {
+		// PARTUUIDs and the disk GUID share the same namespace on GPT.
+		let mut guids = HashMap::new();
+		if let Some(disk_uuid) = &self.disk_uuid {
+			match self.partition_map {
+				_ if self.partition_map.is_gpt() => {
+					let uuid = Uuid::parse_str(disk_uuid).context(format!(
+						"Invalid disk GUID '{}'",
+						disk_uuid
+					))?;
+					guids.insert(uuid, "the disk".to_owned());
+				}
+				PartitionMapType::MBR => {
+					parse_disk_signature(disk_uuid)?;
+				}
+				_ => bail!("disk_uuid is not available without a partition table"),
+			}
+		}
+		let mut fs_uuids = HashMap::new();
+		for partition in &self.partitions {
+			let num = partition.num;
+			if let Some(part_uuid) = &partition.part_uuid {
+				if !self.partition_map.is_gpt() {
+					bail!("Partition {}: part_uuid is only available on GPT, pin disk_uuid instead", num);
+				}
+				let uuid = Uuid::parse_str(part_uuid).context(format!(
+					"Partition {}: invalid part_uuid '{}'",
+					num, part_uuid
+				))?;
+				if let Some(other) =
+					guids.insert(uuid, format!("partition {}", num))
+				{
+					bail!(
+						"Partition {}: part_uuid {} is already used by {}",
+						num,
+						uuid,
+						other
+					);
+				}
+			}
+			if let Some(fs_uuid) = &partition.fs_uuid {
+				let uuid = partition
+					.filesystem
+					.check_uuid(fs_uuid)
+					.context(format!("Partition {}: invalid fs_uuid", num))?;
+				if let Some(other) = fs_uuids.insert(uuid.clone(), num) {
+					bail!("Partition {}: fs_uuid {} is already used by partition {}", num, uuid, other);
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Devices without a partition table can only have one filesystem, which fills the image.
+	fn check_partitionless(&self) -> Result<()> {
+		let partition = match self.partitions.as_slice() {
+			[p] => p,
+			_ => bail!("Exactly one partition is allowed without a partition table"),
+		};
+		if partition.usage != PartitionUsage::Rootfs {
+			bail!("The only partition must be the root partition without a partition table");
+		}
+		if partition.filesystem == FilesystemType::None {
+			bail!("The only partition must contain a filesystem without a partition table");
+		}
+		if partition.part_type == PartitionType::EFI {
+			bail!("ESP is not allowed without a partition table");
+		}
+		if partition.start_sector.is_some()
+			|| partition.size_in_sectors.max_sectors().is_some()
+		{
+			bail!("The filesystem occupies the whole image without a partition table, start_sector and size_in_sectors can not be specified");
+		}
+		if self.initrdless {
+			bail!("initrdless requires a partition table, since the kernel can only find the root partition by PARTUUID");
+		}
+		if self.first_partition_offset.is_some() {
+			bail!("first_partition_offset is not available without a partition table");
+		}
+		if self.metadata_offset.is_some() {
+			bail!("metadata_offset is not available without a partition table");
+		}
+		for bl in self.bootloaders.iter().flatten() {
+			if !matches!(
+				bl.spec,
+				BootloaderSpec::Script { .. }
+					| BootloaderSpec::Config { .. } | BootloaderSpec::Extlinux(_)
+					| BootloaderSpec::UbootScript(_)
+			) {
+				bail!("Only script, config, extlinux and uboot_script bootloaders are allowed without a partition table");
+			}
+		}
+		Ok(())
+	}
+
+	/// Get the path to the block device of a partition on the disk.
+	///
+	/// Without a partition table, the filesystem is on the disk itself.
+	pub fn partition_path<P: AsRef<Path>>(&self, disk: P, num: u32) -> String {
+		if self.partition_map == PartitionMapType::None {
+			disk.as_ref().to_string_lossy().to_string()
 		} else {
-			String::new()
+			get_partition_path(disk, num)
+		}
+	}
+
+	/// Make sure the partition type can be recorded in the partition table of the device.
+	fn check_part_type(&self, partition: &PartitionSpec) -> Result<()> {
+		match self.partition_map {
+			_ if self.partition_map.is_gpt() => {
+				partition.part_type.to_uuid(&self.arch).map(|_| ())
+			}
+			PartitionMapType::MBR => partition.part_type.to_byte().map(|_| ()),
+			_ => Ok(()),
+		}
+		.context(format!(
+			"Partition {}: invalid partition type",
+			partition.num
+		))
+	}
+
+	/// Make sure a ChromeOS kernel partition only holds a raw kernel, and its boot flags are not overridden.
+	fn check_chromeos_kernel(&self, partition: &PartitionSpec) -> Result<()> {
+		let num = partition.num;
+		if !self.partition_map.is_gpt() {
+			bail!("Partition {} is a ChromeOS kernel partition, which is only available on GPT.", num);
+		}
+		if partition.filesystem != FilesystemType::None || partition.mountpoint.is_some() {
+			bail!("Partition {} is a ChromeOS kernel partition, it can not have a filesystem or a mountpoint", num);
+		}
+		if matches!(
+			partition.usage,
+			PartitionUsage::Boot | PartitionUsage::Rootfs | PartitionUsage::Swap
+		) {
+			bail!(
+				"Partition {} is a ChromeOS kernel partition, it can not be used as {:?}",
+				num,
+				partition.usage
+			);
+		}
+		if let Some(bit) = partition.attributes.0.iter().find(|&&b| {
+			(CROS_PRIORITY_SHIFT as i64..=CROS_SUCCESSFUL_BIT as i64).contains(&b)
+		}) {
+			bail!("Partition {}: attribute bit {} is reserved for the boot flags of ChromeOS kernel partitions, set priority, tries and successful instead", num, bit);
+		}
+		if partition.content.is_none() {
+			warn!("Partition {} is a ChromeOS kernel partition without content, it will not be bootable", num);
+		}
+		Ok(())
+	}
+
+	/// Make sure a firmware partition is never formatted nor mounted, and warn if nothing is written to it.
+	fn check_firmware_partition(&self, partition: &PartitionSpec) -> Result<()> {
+		let num = partition.num;
+		if partition.filesystem != FilesystemType::None || partition.mountpoint.is_some() {
+			bail!("Partition {} is a firmware partition, it can not have a filesystem or a mountpoint", num);
+		}
+		if partition.fs_label.is_some()
+			|| partition.fs_uuid.is_some()
+			|| partition.mount_opts.is_some()
+			|| partition.encryption.is_some()
+		{
+			bail!("Partition {} is a firmware partition, it can not have fs_label, fs_uuid, mount_opts or encryption", num);
+		}
+		// Scripts may write to it as well.
+		let written = self.bootloaders.iter().flatten().any(|bl| match &bl.spec {
+			BootloaderSpec::FlashPartition { partition, .. } => {
+				*partition == num as u64
+			}
+			BootloaderSpec::Script { .. } => true,
+			_ => false,
+		});
+		if partition.content.is_none() && !written {
+			warn!("Partition {} is a firmware partition, but neither its content nor a bootloader step writes to it", num);
+		}
+		Ok(())
+	}
+
+	/// Resolve the vendor partition type alias, and warn if it does not belong to the SoC vendor.
+	fn check_vendor_part_type(&self, num: u32, alias: &str) -> Result<()> {
+		if !self.partition_map.is_gpt() {
+			bail!("Partition {} uses vendor partition type '{}', which is only available on GPT.", num, alias);
+		}
+		let vendor = find_vendor_part_type(alias).context(format!(
+			"Partition {}: unknown vendor partition type '{}'",
+			num, alias
+		))?;
+		info!(
+			"Partition {}: vendor partition type '{}' resolves to {} (default label '{}')",
+			num, alias, vendor.guid, vendor.label
+		);
+		if let Some(family) = vendor.family {
+			if self.soc_vendor.as_deref() != Some(family) {
+				warn!(
+					"Partition {}: vendor partition type '{}' is meant for {} SoCs, but the SoC vendor of this device is {}",
+					num,
+					alias,
+					family,
+					self.soc_vendor.as_deref().unwrap_or("not defined")
+				);
+			}
+		}
+		Ok(())
+	}
+
+	/// Make sure the metadata area does not collide with the partition table, partitions and bootloaders.
+	fn check_metadata_offset(&self) -> Result<()> {
+		let offset = match self.metadata_offset {
+			Some(o) => o,
+			None => return Ok(()),
 		};
-		Ok(str)
+		let end = offset + METADATA_SIZE;
+		if offset % 512 != 0 {
+			bail!("metadata_offset must be aligned to 512 bytes");
+		}
+		if self.partition_map.is_gpt() && offset < DEFAULT_METADATA_OFFSET {
+			bail!(
+				"metadata_offset overlaps the GPT, it must be at least {:#x}",
+				DEFAULT_METADATA_OFFSET
+			);
+		}
+		if offset < 512 {
+			bail!("metadata_offset overlaps the MBR");
+		}
+		for partition in &self.partitions {
+			let start = partition.start().unwrap_or(self.reserved_sectors()) * 512;
+			let size = partition.size_in_sectors.max_sectors().unwrap_or(1);
+			if offset < start + size * 512 && start < end {
+				bail!("metadata_offset overlaps partition {}", partition.num);
+			}
+		}
+		for bl in self.bootloaders.iter().flatten() {
+			let bl_offset = match &bl.spec {
+				BootloaderSpec::FlashOffset {
+					offset: bl_offset, ..
+				} => Some(*bl_offset),
+				BootloaderSpec::Raw(raw) => Some(raw.offset.0),
+				BootloaderSpec::Sunxi(sunxi) => Some(sunxi.spl_offset()?),
+				_ => None,
+			};
+			if let Some(bl_offset) = bl_offset {
+				if (offset..end).contains(&bl_offset) {
+					bail!(
+						"metadata_offset overlaps the bootloader at {:#x}",
+						bl_offset
+					);
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Sectors before the first partition if it does not define its starting position.
+	pub fn reserved_sectors_in(&self, sector_size: u64) -> u64 {
+		match (self.first_partition_offset, self.partitions.first()) {
+			(Some(offset), _) => offset.sectors(sector_size),
+			(None, Some(first)) => first.align_in(sector_size),
+			(None, None) => DEFAULT_ALIGN / sector_size,
+		}
+	}
+
+	/// Sectors before the first partition in 512-byte sectors.
+	pub fn reserved_sectors(&self) -> u64 {
+		self.reserved_sectors_in(SECTOR_SIZE)
+	}
+
+	/// End of the gap before the partitions in 512-byte sectors, i.e. where the lowest partition starts.
+	pub fn reserved_gap_end(&self) -> u64 {
+		let reserved = self.reserved_sectors();
+		self.partitions
+			.iter()
+			.map(|p| p.start().unwrap_or(reserved))
+			.min()
+			.unwrap_or(reserved)
+	}
+
+	/// Make sure the gap before the first partition is valid, and the bootloaders written with `flash_offset` start within it.
+	fn check_first_partition_offset(&self) -> Result<()> {
+		if self.partition_map == PartitionMapType::None {
+			return Ok(());
+		}
+		let table_end = if self.partition_map.is_gpt() { 34 } else { 1 };
+		for partition in &self.partitions {
+			if partition.align.is_some_and(|a| a.sectors(SECTOR_SIZE) == 0) {
+				bail!("Partition {}: align must not be zero", partition.num);
+			}
+		}
+		if self.first_partition_offset.is_some() {
+			let reserved = self.reserved_sectors();
+			if reserved < table_end {
+				bail!(
+					"first_partition_offset (sector {}) overlaps the partition table, it must be at least sector {}",
+					reserved,
+					table_end
+				);
+			}
+			if let Some(p) = self
+				.partitions
+				.iter()
+				.find(|p| p.start().is_some_and(|start| start < reserved))
+			{
+				bail!(
+					"Partition {} starts at sector {}, within the gap reserved by first_partition_offset (sector {})",
+					p.num,
+					p.start().unwrap_or_default(),
+					reserved
+				);
+			}
+		}
+		let gap_end = self.reserved_gap_end();
+		for bl in self.bootloaders.iter().flatten() {
+			let BootloaderSpec::FlashOffset {
+				offset,
+				reserved: None,
+				..
+			} = &bl.spec
+			else {
+				// Reserved regions are kept out of the partitions anywhere.
+				continue;
+			};
+			// The partitions start at or after the end of the gap.
+			if *offset >= gap_end * 512 {
+				bail!(
+					"The bootloader at {:#x} overlaps the partitions, it must start within the gap before the first partition, which ends at {:#x} (sector {})",
+					offset,
+					gap_end * 512,
+					gap_end
+				);
+			}
+		}
+		Ok(())
+	}
+
+	/// Size of the image of the variant in bytes, and the partition layout in it.
+	pub(crate) fn variant_layout(
+		&self,
+		variant: &ImageVariant,
+	) -> Result<(u64, Vec<PlannedPartition>)> {
+		let round_to = self.image_size_round_to.unwrap_or(0) * (1 << 20);
+		let pad = self.trailing_pad.unwrap_or(0) * (1 << 20);
+		let nominal = self.size.get_variant_size(variant) * (1 << 20);
+		let size = pad_image_size(nominal, round_to, pad);
+		let layout = plan_layout(
+			self.partition_map,
+			&self.partitions,
+			&self.reserved,
+			self.first_partition_offset.map(|o| o.sectors(SECTOR_SIZE)),
+			variant,
+			size,
+			pad,
+		)?;
+		Ok((size, layout))
+	}
+
+	/// Make sure the partitions of each variant can be laid out in the image of the variant.
+	pub fn check_layout(&self) -> Result<()> {
+		for variant in ImageVariant::VARIANTS {
+			self.variant_layout(variant).context(format!(
+				"The partitions do not fit in the {} image",
+				variant.to_string().to_lowercase()
+			))?;
+		}
+		Ok(())
+	}
+
+	/// Warn if the declared partitions do not fit in the (padded and rounded) image.
+	fn check_image_size(&self) {
+		if self.partition_map == PartitionMapType::None {
+			return;
+		}
+		let round_to = self.image_size_round_to.unwrap_or(0) * (1 << 20);
+		let pad = self.trailing_pad.unwrap_or(0) * (1 << 20);
+		for variant in ImageVariant::VARIANTS {
+			// The gap before the first partition, plus the backup GPT at the end.
+			let mut end = self.reserved_sectors();
+			for partition in &self.partitions {
+				let start = partition
+					.start()
+					.unwrap_or(end.next_multiple_of(partition.align()));
+				// The max sized partition needs at least 1MiB.
+				end = start + partition.sectors(variant).unwrap_or(0).max(2048);
+			}
+			if self.partition_map.is_gpt() {
+				end += 33;
+			}
+			let nominal = self.size.get_variant_size(variant) * (1 << 20);
+			let size = pad_image_size(nominal, round_to, pad);
+			if end * 512 + pad > size {
+				warn!(
+					"{}: partitions need at least {} bytes, but the {} image is only {} bytes",
+					self.id,
+					end * 512 + pad,
+					variant.to_string().to_lowercase(),
+					size
+				);
+			}
+			if let Some(w) = self
+				.target_media_capacity
+				.and_then(|target| check_media_size(size, Some(target)))
+			{
+				warn!(
+					"{}: the {} image of {}",
+					self.id,
+					variant.to_string().to_lowercase(),
+					w
+				);
+			}
+		}
+	}
+
+	/// Get the placeholders for the kernel command line and the configuration templates.
+	///
+	/// Without `pm_data` (i.e. before the partitions are created), the values are empty.
+	pub fn template_vars(
+		&self,
+		pm_data: Option<&PartitionMapData>,
+	) -> Result<BTreeMap<String, String>> {
+		let mut vars = partition_vars(&self.partition_map, &self.partitions, pm_data);
+		if let Some(cmdline) = &self.kernel_cmdline {
+			let resolved = cmdline.resolve(self.initrdless, &vars)?;
+			vars.insert("KERNEL_CMDLINE".to_string(), resolved);
+		}
+		Ok(vars)
+	}
+
+	/// Get the resolved kernel command line, empty if not defined.
+	pub fn gen_kernel_cmdline(&self, pm_data: &PartitionMapData) -> Result<String> {
+		Ok(self.template_vars(Some(pm_data))?
+			.remove("KERNEL_CMDLINE")
+			.unwrap_or_default())
 	}
 }
 
@@ -776,10 +2241,49 @@ impl DeviceArch {
 			}
 			"riscv64" => Some(&Self::Riscv64),
 			// TODO ppc64el needs work.
-			"powerpc64" => Some(&Self::Ppc64el),
+			"powerpc64" => {
+				if cfg!(target_endian = "big") {
+					Some(&Self::Ppc64)
+				} else {
+					Some(&Self::Ppc64el)
+				}
+			}
+			"arm" => {
+				if cfg!(target_feature = "v7") {
+					Some(&Self::Armv7hf)
+				} else {
+					Some(&Self::Armv6hf)
+				}
+			}
+			"x86" => Some(&Self::I486),
 			_ => None,
 		}
 	}
+
+	/// Name of the architecture in the Discoverable Partitions Specification, if it is defined.
+	pub fn dps_name(&self) -> Option<&'static str> {
+		match self {
+			Self::Amd64 => Some("x86-64"),
+			Self::Arm64 => Some("arm64"),
+			Self::LoongArch64 => Some("loongarch64"),
+			Self::Ppc64el => Some("ppc64-le"),
+			Self::Loongson3 => Some("mips64-le"),
+			Self::Riscv64 => Some("riscv64"),
+			// Release 6 is not compatible with the older MIPS64 releases.
+			Self::Mips64r6el => None,
+			Self::Armv7hf | Self::Armv6hf => Some("arm"),
+			Self::I486 => Some("x86"),
+			Self::Ppc64 => Some("ppc64"),
+		}
+	}
+
+	/// Whether the mainline recipes of aoscbootstrap cover the architecture.
+	pub fn is_mainline(&self) -> bool {
+		!matches!(
+			self,
+			Self::Armv7hf | Self::Armv6hf | Self::I486 | Self::Ppc64
+		)
+	}
 	pub fn is_native(&self) -> bool {
 		if let Some(a) = Self::get_native_arch() {
 			if a == self {
@@ -798,24 +2302,52 @@ impl DeviceArch {
 			Self::Loongson3 => "qemu-mips64el",
 			Self::Riscv64 => "qemu-riscv64",
 			Self::Mips64r6el => "qemu-mips64el",
+			Self::Armv7hf | Self::Armv6hf => "qemu-arm",
+			Self::I486 => "qemu-i386",
+			Self::Ppc64 => "qemu-ppc64",
 		}
 	}
 }
 
 impl ImageContext<'_> {
+	/// The partition layout in the opened image, in sectors of `sector_size` bytes.
+	pub(crate) fn layout_in(
+		&self,
+		fd: &mut File,
+		sector_size: u64,
+	) -> Result<Vec<PlannedPartition>> {
+		let image_size = fd.seek(SeekFrom::End(0))?;
+		plan_layout_in(
+			self.device.partition_map,
+			&self.device.partitions,
+			&self.device.reserved,
+			self.device
+				.first_partition_offset
+				.map(|o| o.sectors(sector_size)),
+			self.variant,
+			image_size,
+			self.get_trailing_pad(),
+			sector_size,
+		)
+		.context(format!(
+			"The partitions do not fit in the {} bytes image",
+			image_size
+		))
+	}
+
 	pub fn partition_gpt(&self, img: &Path) -> Result<PartitionMapData> {
 		// The device must be opened write-only to write partition tables
 		// Otherwise EBADF will be throwed
 		let mut fd = File::options().write(true).open(img)?;
 		// Use ioctl() to get sector size of the loop device
 		// NOTE sector sizes can not be assumed
-		let sector_size = gptman::linux::get_sector_size(&mut fd)?;
+		let sector_size = sector_size(&mut fd)?;
 		debug!(
-			"Got sector size of the loop device '{}': {} bytes",
+			"Got sector size of the disk '{}': {} bytes",
 			img.display(),
 			sector_size
 		);
-		let rand_uuid = Uuid::new_v4();
+		let disk_uuid = self.disk_guid()?.unwrap_or_else(Uuid::new_v4);
 		// NOTE UUIDs in GPT are like structs, they are "Mixed-endian."
 		// The first three components are little-endian, and the last two are big-endian.
 		// e.g. 01020304-0506-0708-090A-0B0C0D0E0F10 must be written as:
@@ -826,58 +2358,47 @@ impl ImageContext<'_> {
 		//       ^^^^^^^^^^^^^^^^^^^^^^^
 		//              Big Endian
 		// Uuid::to_bytes_le() produces the correct byte array.
-		let disk_guid = rand_uuid.to_bytes_le();
+		let disk_guid = disk_uuid.to_bytes_le();
 		let mut new_table = GPT::new_from(&mut fd, sector_size, disk_guid)
 			.context("Unable to create a new partition table")?;
 		let mut parts_data: HashMap<u32, PartitionData> = HashMap::new();
 		assert!(new_table.header.disk_guid == disk_guid);
-		// 1MB aligned
-		new_table.align = 1048576 / sector_size;
+		let layout = self.layout_in(&mut fd, sector_size)?;
 		self.info(format!(
 			"Created new GPT partition table on {}:",
 			img.display()
 		));
 		let size_in_lba = new_table.header.last_usable_lba;
-		self.info(format!("UUID: {}", &rand_uuid));
+		self.info(format!("UUID: {}", &disk_uuid));
 		self.info(format!("Total LBA: {}", size_in_lba));
-		let num_partitions = self.device.num_partitions;
+		// Partition numbers are validated to be 1..=num_partitions while parsing.
 		for partition in &self.device.partitions {
-			if partition.num == 0 {
-				bail!("Partition number must start from 1.");
+			if new_table[partition.num].is_used() {
+				bail!("Partition {} is defined more than once.", partition.num);
 			}
-			let rand_part_uuid = Uuid::new_v4();
-			let unique_partition_guid = rand_part_uuid.to_bytes_le();
-			let free_blocks = new_table.find_free_sectors();
-			debug!("Free blocks remaining: {:#?}", &free_blocks);
-			let last_free = free_blocks
-				.last()
-				.context("No more free space available for new partitions")?;
-			let size = if partition.size_in_sectors != 0 {
-				partition.size_in_sectors
-			} else {
-				if partition.num != num_partitions {
-					bail!("Max sized partition must stay at the end of the table.");
-				}
-				if last_free.1 < 1048576 / sector_size {
-					bail!("Not enough free space to create a partition");
-				}
-				last_free.1 - 1
-			};
-
-			let partition_type_guid = partition.part_type.to_uuid()?.to_bytes_le();
-			let starting_lba = if let Some(start) = partition.start_sector {
-				start
-			} else if partition.num == 1 {
-				// 1MB grain size to reserve some space for bootloaders
-				1048576 / sector_size as u64
-			} else {
-				new_table.find_first_place(size).context(format!(
-					"No suitable space found for partition:\n{:?}.",
-					&partition
-				))?
-			};
+			let part_uuid =
+				self.partition_guid(partition)?.unwrap_or_else(Uuid::new_v4);
+			let unique_partition_guid = part_uuid.to_bytes_le();
+			let partition_type_guid = partition
+				.part_type
+				.to_uuid(&self.device.arch)?
+				.to_bytes_le();
+			let planned = planned(&layout, partition.num)?;
+			let (starting_lba, size) = (planned.start, planned.size);
 			let ending_lba = starting_lba + size - 1;
-			let name = if let Some(name) = partition.label.to_owned() {
+			if starting_lba < new_table.header.first_usable_lba
+				|| ending_lba > new_table.header.last_usable_lba
+			{
+				bail!(
+					"Partition {} (sectors {}-{}) is out of the usable sectors of the GPT ({}-{})",
+					partition.num,
+					starting_lba,
+					ending_lba,
+					new_table.header.first_usable_lba,
+					new_table.header.last_usable_lba
+				);
+			}
+			let name = if let Some(name) = partition.get_label() {
 				name
 			} else {
 				"".into()
@@ -885,7 +2406,7 @@ impl ImageContext<'_> {
 			let partition_name = name.as_str();
 			self.info(format!(
 				"Creating an {:?} partition with PARTUUID {}:",
-				partition.part_type, rand_part_uuid
+				partition.part_type, part_uuid
 			));
 			self.info(format!(
 				"Size in LBA: {}, Start = {}, End = {}",
@@ -896,7 +2417,8 @@ impl ImageContext<'_> {
 				unique_partition_guid,
 				starting_lba,
 				ending_lba,
-				attribute_bits: 0,
+				attribute_bits: partition.attributes.to_bits()
+					| partition.part_type.attribute_bits(),
 				partition_name: partition_name.into(),
 			};
 			new_table[partition.num] = part;
@@ -904,8 +2426,11 @@ impl ImageContext<'_> {
 				partition.num,
 				PartitionData {
 					num: partition.num,
-					part_uuid: rand_part_uuid.to_string(),
+					part_uuid: part_uuid.to_string(),
 					fs_uuid: None,
+					fs_label: None,
+					luks_uuid: None,
+					fsck: None,
 				},
 			);
 		}
@@ -916,8 +2441,18 @@ impl ImageContext<'_> {
 		GPT::write_protective_mbr_into(&mut fd, sector_size)?;
 		new_table.write_into(&mut fd)?;
 		fd.sync_all()?;
+		self.info("Verifying the partition table ...");
+		verify_gpt(img, &new_table)
+			.context("The partition table is not written correctly")?;
+		if self.device.partition_map == PartitionMapType::Hybrid {
+			self.info("Writing the hybrid MBR ...");
+			// The disk signature is taken from the disk GUID.
+			let disk_signature =
+				[disk_guid[0], disk_guid[1], disk_guid[2], disk_guid[3]];
+			self.write_hybrid_mbr(img, &mut fd, &new_table, disk_signature)?;
+		}
 		let pm_data = PartitionMapData {
-			uuid: rand_uuid.to_string(),
+			uuid: disk_uuid.to_string(),
 			data: parts_data,
 		};
 		Ok(pm_data)
@@ -925,60 +2460,47 @@ impl ImageContext<'_> {
 
 	pub fn partition_mbr(&self, img: &Path) -> Result<PartitionMapData> {
 		let mut fd = File::options().write(true).open(img)?;
-		let sector_size =
-			TryInto::<u32>::try_into(gptman::linux::get_sector_size(&mut fd)?)
-				.unwrap_or(512);
-		let random_id: u32 = rand::random();
-		let disk_signature = random_id.to_le_bytes();
-		let disk_signature_str = format!("{:08x}", random_id);
+		let sector_size = TryInto::<u32>::try_into(sector_size(&mut fd)?).unwrap_or(512);
+		let disk_id = self.disk_signature()?.unwrap_or_else(rand::random);
+		let disk_signature = disk_id.to_le_bytes();
+		let disk_signature_str = format!("{:08x}", disk_id);
 		let mut new_table = MBR::new_from(&mut fd, sector_size, disk_signature)?;
 		let mut parts_data: HashMap<u32, PartitionData> = HashMap::new();
 		self.info(format!("Created a MBR table on {}:", img.display()));
 		// Human readable format
 		self.info(format!(
 			"Disk signature: {:X}-{:X}",
-			(random_id >> 16) as u16,
-			(random_id & 0xffff) as u16
+			(disk_id >> 16) as u16,
+			(disk_id & 0xffff) as u16
 		));
+		let layout = self.layout_in(&mut fd, sector_size as u64)?;
+		// Partition numbers are validated while parsing, the logical partitions follow the primary ones.
 		for partition in &self.device.partitions {
-			if partition.num == 0 {
-				bail!("Partition number must start from 1.");
-			}
-			if partition.num > 4 {
-				bail!("Extended and logical partitions are not supported.");
+			if partition.num >= MBR_FIRST_LOGICAL {
+				self.create_logical_partition(&mut new_table, partition, &layout)?;
+				parts_data.insert(
+					partition.num,
+					PartitionData {
+						num: partition.num,
+						part_uuid: mbr_partuuid(disk_id, partition.num),
+						fs_uuid: None,
+						fs_label: None,
+						luks_uuid: None,
+						fsck: None,
+					},
+				);
+				continue;
 			}
-			let free_blocks = new_table.find_free_sectors();
-			debug!("Free blocks remaining: {:#?}", &free_blocks);
-			let last_free = free_blocks
-				.last()
-				.context("No more free space available for new partitions")?;
 			let idx = TryInto::<usize>::try_into(partition.num)
 				.context("Partition number exceeds the limit")?;
-			let sectors = if partition.size_in_sectors != 0 {
-				TryInto::<u32>::try_into(partition.size_in_sectors)
-					.context("Partition size exceeds the limit of MBR")?
-			} else {
-				// Make sure it is the last partition.
-				if partition.num != self.device.num_partitions {
-					bail!("Max sized partition must stay at the end of the table.");
-				}
-				last_free.1 - 1
-			};
+			if new_table[idx].is_used() {
+				bail!("Partition {} is defined more than once.", partition.num);
+			}
+			let (starting_lba, sectors) =
+				mbr_sectors(planned(&layout, partition.num)?)?;
 			if sectors < 1048576 / sector_size {
 				bail!("Not enough free space to create a partition");
 			}
-			let starting_lba = if let Some(start) = partition.start_sector {
-				TryInto::<u32>::try_into(start)
-					.context("Partition size exceeds the limit of MBR")?
-			} else if partition.num == 1 {
-				// 1MB grain size to reserve some space for bootloaders
-				1048576 / sector_size as u32
-			} else {
-				new_table.find_first_place(sectors).context(format!(
-					"No suitable free space found for partition: {:?}",
-					&partition
-				))?
-			};
 			let boot = if partition.usage == PartitionUsage::Boot {
 				mbrman::BOOT_ACTIVE
 			} else {
@@ -1005,14 +2527,20 @@ impl ImageContext<'_> {
 				partition.num,
 				PartitionData {
 					num: partition.num,
-					part_uuid: format!("{}-{:02x}", &disk_signature_str, idx),
+					part_uuid: mbr_partuuid(disk_id, partition.num),
 					fs_uuid: None,
+					fs_label: None,
+					luks_uuid: None,
+					fsck: None,
 				},
 			);
 		}
 		self.info("Writing the partition table ...");
 		new_table.write_into(&mut fd)?;
 		fd.sync_all()?;
+		self.info("Verifying the partition table ...");
+		verify_mbr(img, &new_table)
+			.context("The partition table is not written correctly")?;
 		let pm_data = PartitionMapData {
 			uuid: disk_signature_str,
 			data: parts_data,
@@ -1020,6 +2548,140 @@ impl ImageContext<'_> {
 		Ok(pm_data)
 	}
 
+	/// Create a logical partition of the MBR, preceded by its EBR.
+	///
+	/// The extended partition is created along with the first logical
+	/// partition, in the slot after the last primary partition. It spans the
+	/// rest of the disk, except the trailing padding.
+	fn create_logical_partition(
+		&self,
+		table: &mut MBR,
+		partition: &PartitionSpec,
+		layout: &[PlannedPartition],
+	) -> Result<()> {
+		let sector_size = table.sector_size;
+		let align = partition.align_in(sector_size as u64) as u32;
+		let slot = match (1..=4).find(|&idx| table[idx].is_extended()) {
+			Some(slot) => slot,
+			None => {
+				let slot = (1..=4)
+					.find(|&idx| table[idx].is_unused())
+					.context("No free slot left for the extended partition")?;
+				let (starting_lba, sectors) =
+					mbr_sectors(planned(layout, slot as u32)?)?;
+				self.info(format!("Creating an extended partition {}:", slot));
+				self.info(format!(
+					"Size in LBA: {}, Start = {}, End = {}",
+					sectors,
+					starting_lba,
+					starting_lba + sectors - 1
+				));
+				table[slot] = MBRPartitionEntry {
+					boot: mbrman::BOOT_INACTIVE,
+					first_chs: CHS::empty(),
+					sys: MBR_EXTENDED_TYPE,
+					last_chs: CHS::empty(),
+					starting_lba,
+					sectors,
+				};
+				slot
+			}
+		};
+		let extended_end = table[slot].starting_lba + table[slot].sectors;
+		// The EBR takes the first sector after the previous logical partition, aligned like the partition.
+		let ebr = match table.logical_partitions.last() {
+			Some(prev) => (prev.partition.starting_lba + prev.partition.sectors)
+				.next_multiple_of(align),
+			None => table[slot].starting_lba,
+		};
+		// Neither the EBR nor the partition is placed in the reserved regions.
+		let ebr = match partition.start_in(sector_size as u64) {
+			Some(_) => ebr,
+			None => {
+				let size = partition.sectors_in(self.variant, sector_size as u64);
+				skip_reserved(
+					&self.device.reserved_in(sector_size as u64),
+					ebr as u64,
+					(align as u64) + size.unwrap_or(1),
+					align as u64,
+				) as u32
+			}
+		};
+		let (starting_lba, sectors) = mbr_sectors(planned(layout, partition.num)?)?;
+		if starting_lba <= ebr {
+			bail!(
+				"Logical partition {} starts at sector {}, which overlaps its EBR at sector {}",
+				partition.num,
+				starting_lba,
+				ebr
+			);
+		}
+		if sectors < 1048576 / sector_size {
+			bail!("Not enough free space to create a partition");
+		}
+		if starting_lba + sectors > extended_end {
+			bail!(
+				"Logical partition {} ends at sector {}, beyond the extended partition (sector {})",
+				partition.num,
+				starting_lba + sectors,
+				extended_end
+			);
+		}
+		let sys = partition.part_type.to_byte()?;
+		self.info(format!(
+			"Creating an {:?} logical partition:",
+			&partition.part_type
+		));
+		self.info(format!(
+			"Size in LBA: {}, Start = {}, End = {}, EBR = {}",
+			sectors,
+			starting_lba,
+			starting_lba + sectors - 1,
+			ebr
+		));
+		table.logical_partitions.push(LogicalPartition {
+			partition: MBRPartitionEntry {
+				boot: mbrman::BOOT_INACTIVE,
+				first_chs: CHS::empty(),
+				sys,
+				last_chs: CHS::empty(),
+				starting_lba,
+				sectors,
+			},
+			absolute_ebr_lba: ebr,
+			// From the EBR to the end of the partition, for the link in the previous EBR.
+			ebr_sectors: Some(starting_lba + sectors - ebr),
+			ebr_first_chs: CHS::empty(),
+			ebr_last_chs: None,
+			bootstrap_code: [0; 446],
+		});
+		Ok(())
+	}
+
+	/// Without a partition table there is nothing to write, the filesystem
+	/// is formatted on the loop device itself, which is attached without
+	/// partition scanning.
+	pub fn partitionless_data(&self) -> PartitionMapData {
+		let mut data = HashMap::new();
+		for partition in &self.device.partitions {
+			data.insert(
+				partition.num,
+				PartitionData {
+					num: partition.num,
+					part_uuid: String::new(),
+					fs_uuid: None,
+					fs_label: None,
+					luks_uuid: None,
+					fsck: None,
+				},
+			);
+		}
+		PartitionMapData {
+			uuid: String::new(),
+			data,
+		}
+	}
+
 	pub fn write_spec_script(
 		&self,
 		loopdev: &dyn AsRef<Path>,
@@ -1027,6 +2689,10 @@ impl ImageContext<'_> {
 		container: &dyn AsRef<Path>,
 		pm_data: &PartitionMapData,
 	) -> Result<()> {
+		let cmdline = self.device.gen_kernel_cmdline(pm_data)?;
+		if !cmdline.is_empty() {
+			self.info(format!("Kernel command line: {}", cmdline));
+		}
 		let mut script = format!(
 			r#"DEVICE_ID='{0}'
 DEVICE_COMPATIBLE='{1}'
@@ -1036,6 +2702,8 @@ ROOTPART='{4}'
 DISKLABEL='{5}'
 DISKUUID='{6}'
 KERNEL_CMDLINE='{7}'
+BUILD_ID='{8}'
+DEFAULT_USER='{9}'
 "#,
 			self.device.id,
 			&self.device.of_compatible.clone().unwrap_or("".to_string()),
@@ -1044,7 +2712,9 @@ KERNEL_CMDLINE='{7}'
 			rootpart.as_ref().to_string_lossy(),
 			&self.device.partition_map.to_string().to_lowercase(),
 			&pm_data.uuid,
-			&self.device.gen_kernel_cmdline(pm_data)?
+			&cmdline,
+			self.build_id,
+			self.user.unwrap_or_default()
 		);
 		for part in &self.device.partitions {
 			let part_data = pm_data.data.get(&part.num).context(format!(
@@ -1111,28 +2781,48 @@ KERNEL_CMDLINE='{7}'
 		self.info("Generating /etc/fstab ...");
 		let mut content = String::from("\n# ---- Auto generated by mkrawimg ----\n");
 		for partition in &self.device.partitions {
-			if let Some(mountpoint) = &partition.mountpoint {
+			let mountpoint = match &partition.mountpoint {
+				// Swap partitions are activated, not mounted.
+				_ if partition.filesystem == FilesystemType::Swap => Some("none"),
+				mountpoint => mountpoint.as_deref(),
+			};
+			if let Some(mountpoint) = mountpoint {
 				let part_data =
 					pm_data.data.get(&partition.num).context(format!(
 						"Unable to get partition data for partition {}",
 						partition.num
 					))?;
-				let src = if self.device.initrdless {
-					format!("PARTUUID=\"{0}\"", &part_data.part_uuid)
+				let src = if partition.encryption.is_some() {
+					let uuid = luks_uuid(pm_data, partition)?;
+					format!("/dev/mapper/{}", target_mapping_name(uuid))
 				} else {
-					format!("UUID=\"{0}\"", &part_data.fs_uuid.as_ref().context("Partition with a mountpoint must have a valid filesystem")?)
+					self.fstab_source(partition, part_data)?
 				};
 				// dst = mountpoint
 				// `genfstab(8)` uses the options field in `/proc/mounts`, which is the expanded result from `defaults`.
-				let options = if let Some(opts) = partition.mount_opts.as_ref() {
+				let options = if let Some(subvol) = self.main_subvolume(partition) {
+					subvol.mount_opts_with(partition.mount_opts.as_ref())
+						.join(",")
+				} else if let Some(opts) = partition.mount_opts.as_ref() {
 					opts.join(",")
 				} else {
-					"defaults".to_owned()
+					// Files on exFAT are owned by the built-in user, if any.
+					let owner = self.user.map(|_| DEFAULT_USER_ID);
+					partition.filesystem.default_mount_opts(owner, owner)
 				};
-				let fsck_passno = if partition.usage == PartitionUsage::Rootfs {
-					1
-				} else {
-					2
+				// The overlays on /etc and /var are mounted by the initramfs.
+				let options = match partition.usage {
+					PartitionUsage::Overlay => {
+						format!("{},{}", options, INITRD_MOUNT_OPT)
+					}
+					_ => options,
+				};
+				let fsck_passno = match partition.usage {
+					// EROFS is read-only, there is nothing to check.
+					_ if partition.filesystem == FilesystemType::Erofs => 0,
+					PartitionUsage::Rootfs => 1,
+					PartitionUsage::Swap => 0,
+					_ => 2,
 				};
 				let entry = format!(
 					"{0}\t{1}\t{2}\t{3}\t{4}\t{5}\n",
@@ -1144,11 +2834,25 @@ KERNEL_CMDLINE='{7}'
 					fsck_passno
 				);
 				content += &entry;
+				// The filesystem is checked by the entry above.
+				for (subvol, mountpoint) in self.mounted_subvolumes(partition) {
+					content += &format!(
+						"{0}\t{1}\tbtrfs\t{2}\t0\t0\n",
+						&src,
+						mountpoint,
+						subvol.mount_opts_with(
+							partition.mount_opts.as_ref()
+						)
+						.join(",")
+					);
+				}
 			} else {
 				// We can not generate fstab entry for partitions without a mountpoint
 				continue;
 			}
 		}
+		// Mounted after the overlay partition.
+		content += &self.overlay_fstab_entries();
 		let fstab_path = container.as_ref().join("etc/fstab");
 		let mut fstab_fd = File::options()
 			.truncate(false)
@@ -1160,13 +2864,64 @@ KERNEL_CMDLINE='{7}'
 		Ok(())
 	}
 
+	/// Give the exFAT filesystems in `/etc/fstab` to the IDs `user` actually got.
+	///
+	/// The fstab is generated before the user is created, assuming
+	/// [`DEFAULT_USER_ID`], which is taken if the base system already has a user.
+	pub fn update_exfat_owner(&self, container: &dyn AsRef<Path>, user: &str) -> Result<()> {
+		let mountpoints = self
+			.device
+			.partitions
+			.iter()
+			.filter(|p| p.filesystem == FilesystemType::Exfat && p.mount_opts.is_none())
+			.filter_map(|p| p.mountpoint.as_deref())
+			.collect::<Vec<_>>();
+		if mountpoints.is_empty() {
+			return Ok(());
+		}
+		let root = container.as_ref();
+		let passwd = fs::read_to_string(root.join(PASSWD_PATH))?;
+		let (uid, gid, _) = lookup_user(&passwd, user)
+			.context(format!("User '{}' is not found in the target", user))?;
+		if (uid, gid) == (DEFAULT_USER_ID, DEFAULT_USER_ID) {
+			return Ok(());
+		}
+		let assumed = FilesystemType::Exfat
+			.default_mount_opts(Some(DEFAULT_USER_ID), Some(DEFAULT_USER_ID));
+		let actual = FilesystemType::Exfat.default_mount_opts(Some(uid), Some(gid));
+		let fstab_path = root.join("etc/fstab");
+		let mut content = String::new();
+		for line in fs::read_to_string(&fstab_path)?.lines() {
+			let mut fields = line.split('\t').collect::<Vec<_>>();
+			if fields.len() > 3
+				&& mountpoints.contains(&fields[1])
+				&& fields[2] == "exfat" && fields[3] == assumed
+			{
+				fields[3] = &actual;
+			}
+			content += &fields.join("\t");
+			content += "\n";
+		}
+		fs::write(&fstab_path, content)?;
+		Ok(())
+	}
+
+	/// The hostname of the target, see [`DeviceSpec::hostname`].
+	pub fn hostname(&self) -> String {
+		match self.hostname.or(self.device.hostname.as_deref()) {
+			Some(hostname) => hostname.to_owned(),
+			None => sanitize_hostname(&self.device.id),
+		}
+	}
+
+	/// The timezone of the target if any, see [`DeviceSpec::timezone`].
+	pub fn timezone(&self) -> Option<&str> {
+		self.timezone.or(self.device.timezone.as_deref())
+	}
+
 	pub fn set_hostname(&self, container: &dyn AsRef<Path>) -> Result<()> {
 		self.info("Setting up hostname ...");
-		let rand_id: u32 = rand::random();
-		let hostname = format!(
-			"{:?}-{}-{:08x}",
-			&self.device.distro, &self.device.id, rand_id
-		);
+		let hostname = self.hostname();
 		self.info(format!("Hostname: {}", &hostname));
 		let hostname_path = container.as_ref().join("etc/hostname");
 		let mut hostname_fd = File::options()
@@ -1174,15 +2929,13 @@ KERNEL_CMDLINE='{7}'
 			.write(true)
 			.create(true)
 			.open(hostname_path)?;
-		hostname_fd.write_all(hostname.as_bytes())?;
+		hostname_fd.write_all(format!("{}\n", hostname).as_bytes())?;
 		hostname_fd.flush()?;
 		hostname_fd.sync_all()?;
-		let hosts_entries = format!("\n127.0.0.1\t{0}\n::1\t{0}\n", hostname);
-		let hosts_fd = container.as_ref().join("etc/hosts");
-		let mut hosts_fd = File::options().append(true).create(true).open(hosts_fd)?;
-		hosts_fd.write_all(hosts_entries.as_bytes())?;
-		hosts_fd.flush()?;
-		hosts_fd.sync_all()?;
+		let hosts_path = container.as_ref().join("etc/hosts");
+		let hosts = std::fs::read_to_string(&hosts_path).unwrap_or_default();
+		std::fs::write(&hosts_path, set_hosts_entry(&hosts, &hostname))
+			.context("Failed to write /etc/hosts")?;
 		Ok(())
 	}
 }
@@ -1190,6 +2943,10 @@ KERNEL_CMDLINE='{7}'
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::{
+		partition::PartitionAttributes,
+		testutil::{load_device, TempDir},
+	};
 	use log::info;
 	use owo_colors::OwoColorize;
 
@@ -1212,4 +2969,414 @@ mod tests {
 		}
 		Ok(())
 	}
+
+	#[test]
+	fn test_pad_image_size() {
+		const MIB: u64 = 1 << 20;
+		assert_eq!(pad_image_size(6144 * MIB, 0, 0), 6144 * MIB);
+		assert_eq!(pad_image_size(6143 * MIB, 4 * MIB, 0), 6144 * MIB);
+		assert_eq!(pad_image_size(6144 * MIB, 4 * MIB, 0), 6144 * MIB);
+		assert_eq!(pad_image_size(6144 * MIB, 4 * MIB, MIB), 6148 * MIB);
+		assert_eq!(pad_image_size(6144 * MIB, 0, 8 * MIB), 6152 * MIB);
+	}
+
+	#[test]
+	fn test_partition_nums() {
+		let gpt = &PartitionMapType::GPT;
+		let mbr = &PartitionMapType::MBR;
+		assert!(check_partition_nums(&[1, 2, 3], 3, gpt).is_ok());
+		// Mismatched num_partitions
+		assert!(check_partition_nums(&[1, 2, 3], 2, gpt).is_err());
+		// Duplicate numbers used to overwrite earlier GPT entries.
+		let err = check_partition_nums(&[1, 2, 2], 3, gpt).unwrap_err();
+		assert_eq!(err.to_string(), "Duplicate partition number: 2");
+		// Gaps used to pass silently.
+		let err = check_partition_nums(&[1, 2, 4], 3, gpt).unwrap_err();
+		assert!(err.to_string().contains("gaps"));
+		assert!(check_partition_nums(&[0, 1], 2, gpt).is_err());
+		assert!(check_partition_nums(&[2, 1], 2, gpt).is_err());
+		// Logical partitions of a MBR are numbered from 5.
+		assert!(check_partition_nums(&[1, 2, 3, 4], 4, mbr).is_ok());
+		assert!(check_partition_nums(&[1, 2, 3, 5, 6, 7], 6, mbr).is_ok());
+		assert!(check_partition_nums(&[1, 5, 6], 3, mbr).is_ok());
+		assert!(check_partition_nums(&[1, 2, 3, 5, 6, 7], 6, gpt).is_err());
+		let err = check_partition_nums(&[1, 2, 3, 4, 5, 6], 6, mbr).unwrap_err();
+		assert!(err.to_string().contains("extended partition"));
+		let err = check_partition_nums(&[1, 2, 5, 7], 4, mbr).unwrap_err();
+		assert!(err.to_string().contains("gaps"));
+		assert!(check_partition_nums(&[1, 2, 6], 3, mbr).is_err());
+	}
+
+	#[test]
+	fn test_image_size() -> Result<()> {
+		assert_eq!("10240".parse::<ImageSize>()?, ImageSize(10240));
+		assert_eq!("10240M".parse::<ImageSize>()?, ImageSize(10240));
+		assert_eq!("8G".parse::<ImageSize>()?, ImageSize(8192));
+		assert_eq!("8GiB".parse::<ImageSize>()?, ImageSize(8192));
+		assert_eq!("1T".parse::<ImageSize>()?, ImageSize(1 << 20));
+		assert!("8X".parse::<ImageSize>().is_err());
+		assert!("G".parse::<ImageSize>().is_err());
+		assert!("0".parse::<ImageSize>().is_err());
+		assert!("-1G".parse::<ImageSize>().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_encryption() -> Result<()> {
+		let dir = TempDir::new("check-encryption")?;
+		fs::write(dir.join("passphrase.txt"), "correct horse\n")?;
+		let root = r#"partition_map = "gpt"
+
+[[partition]]
+num = 1
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+encryption = { type = "luks2", passphrase_file = "passphrase.txt" }
+"#;
+		let check = |header: &str| -> Result<()> {
+			let device = load_device(&dir, &format!("{}{}", header, root))?;
+			device.check_encryption(&dir, &device.partitions[0])
+		};
+		check("kernel_cmdline = \"rw\"\n")?;
+		let err = check("").unwrap_err().to_string();
+		assert!(err.contains("kernel_cmdline must be defined"), "{}", err);
+		let err = check("initrdless = true\nkernel_cmdline = \"rw\"\n")
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("initrdless"), "{}", err);
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_pinned_uuids() -> Result<()> {
+		let dir = TempDir::new("check-pinned-uuids")?;
+		let check = |map: &str, disk: &str, boot: &str, root: &str| -> Result<()> {
+			let spec = format!(
+				r#"partition_map = "{}"
+{}
+[[partition]]
+num = 1
+type = "linux"
+usage = "boot"
+filesystem = "ext4"
+mountpoint = "/boot"
+size = 300
+{}
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+{}
+"#,
+				map, disk, boot, root
+			);
+			load_device(&dir, &spec)?.check_pinned_uuids()
+		};
+		const DISK: &str = "disk_uuid = \"5a3b2c1d-0000-4000-8000-000000000001\"";
+		const PART1: &str = "part_uuid = \"5a3b2c1d-0000-4000-8000-000000000002\"";
+		const PART2: &str = "part_uuid = \"5a3b2c1d-0000-4000-8000-000000000003\"";
+		const FS: &str = "fs_uuid = \"5a3b2c1d-0000-4000-8000-000000000004\"";
+		check("gpt", DISK, PART1, &format!("{}\n{}", PART2, FS))?;
+		check("mbr", "disk_uuid = \"5452574f\"", "", FS)?;
+		let err = check("gpt", DISK, PART1, PART1).unwrap_err().to_string();
+		assert!(err.contains("already used by partition 1"), "{}", err);
+		// The disk GUID shares the namespace of the PARTUUIDs.
+		let err = check(
+			"gpt",
+			"disk_uuid = \"5a3b2c1d-0000-4000-8000-000000000002\"",
+			PART1,
+			"",
+		)
+		.unwrap_err()
+		.to_string();
+		assert!(err.contains("already used by the disk"), "{}", err);
+		let err = check("gpt", "", "part_uuid = \"5a3b2c1d\"", "")
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("invalid part_uuid"), "{}", err);
+		let err = check("gpt", "disk_uuid = \"5452574f\"", "", "")
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("Invalid disk GUID"), "{}", err);
+		let err = check("mbr", "", "", PART2).unwrap_err().to_string();
+		assert!(err.contains("only available on GPT"), "{}", err);
+		let err = check("gpt", "", "", "fs_uuid = \"not-a-uuid\"")
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("invalid fs_uuid"), "{}", err);
+		let err = check("gpt", "", FS, FS).unwrap_err().to_string();
+		assert!(err.contains("fs_uuid 5a3b2c1d-0000-4000-8000-000000000004 is already used by partition 1"), "{}", err);
+		Ok(())
+	}
+
+	#[test]
+	fn test_deprecation() -> Result<()> {
+		let dir = TempDir::new("deprecation")?;
+		let root = r#"partition_map = "gpt"
+
+[[partition]]
+num = 1
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+"#;
+		let load = |keys: &str| load_device(&dir, &format!("{}{}", keys, root));
+		let device = load("")?;
+		device.check()?;
+		device.check_deprecated(false)?;
+		let device = load(
+			"deprecated = true\ndeprecation_reason = \"The vendor kernel is gone\"\n",
+		)?;
+		device.check()?;
+		let err = device.check_deprecated(false).unwrap_err().to_string();
+		assert!(err.contains("The vendor kernel is gone"), "{}", err);
+		assert!(err.contains("--allow-deprecated"), "{}", err);
+		device.check_deprecated(true)?;
+		let err = load("deprecated = true\n")?
+			.check()
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("must have a deprecation_reason"), "{}", err);
+		let err = load("deprecated = true\ndeprecation_reason = \" \"\n")?
+			.check()
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("must have a deprecation_reason"), "{}", err);
+		let err = load("deprecation_reason = \"EOL\"\n")?
+			.check()
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("the device is not deprecated"), "{}", err);
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_url() {
+		assert!(check_url("https://wiki.aosc.io/en/hardware/rpi-5b").is_ok());
+		assert!(check_url("http://example.com").is_ok());
+		assert!(check_url("wiki.aosc.io/hardware").is_err());
+		assert!(check_url("ftp://example.com/flash.txt").is_err());
+		assert!(check_url("mailto:maintainer@aosc.io").is_err());
+		assert!(check_url("https://").is_err());
+	}
+
+	#[test]
+	fn test_check_metadata() -> Result<()> {
+		let metadata = DeviceMetadata {
+			wiki_url: Some("https://wiki.aosc.io/en/hardware/rpi-5b".into()),
+			flash_instructions: Some("Hold the BOOTSEL button while powering on".into()),
+			maintainer: Some("AOSC OS Maintainers <maintainers@aosc.io>".into()),
+		};
+		metadata.check()?;
+		DeviceMetadata::default().check()?;
+		let err = DeviceMetadata {
+			wiki_url: Some("wiki.aosc.io".into()),
+			..metadata.clone()
+		}
+		.check()
+		.unwrap_err();
+		assert!(
+			format!("{:#}", err).contains("Invalid wiki_url"),
+			"{:#}",
+			err
+		);
+		// Only the links are checked.
+		let err = DeviceMetadata {
+			flash_instructions: Some("https://".into()),
+			..metadata.clone()
+		}
+		.check()
+		.unwrap_err();
+		assert!(
+			format!("{:#}", err).contains("Invalid flash_instructions"),
+			"{:#}",
+			err
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_firmware_partition() -> Result<()> {
+		let dir = TempDir::new("firmware-partition")?;
+		let load = |firmware: &str, target: u32| {
+			load_device(
+				&dir,
+				&format!(
+					r#"partition_map = "gpt"
+
+[[partition]]
+num = 1
+type = "efi"
+usage = "boot"
+filesystem = "fat32"
+mountpoint = "/efi"
+size = "64MiB"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "firmware"
+size = "4MiB"
+{}
+
+[[partition]]
+num = 3
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+
+[[bootloader]]
+type = "flash_partition"
+path = "/usr/lib/u-boot/u-boot.itb"
+partition = {}
+"#,
+					firmware, target
+				),
+			)
+		};
+		// Not formatted without filesystem = "none".
+		let device = load("", 2)?;
+		assert_eq!(device.partitions[1].filesystem, FilesystemType::None);
+		device.check()?;
+		load("filesystem = \"none\"", 2)?.check()?;
+		for keys in [
+			"filesystem = \"ext4\"",
+			"mountpoint = \"/boot/firmware\"",
+			"fs_label = \"UBOOT\"",
+			"mount_opts = [\"ro\"]",
+		] {
+			let device = load(keys, 2)?;
+			let err = device
+				.check_firmware_partition(&device.partitions[1])
+				.unwrap_err()
+				.to_string();
+			assert!(err.contains("is a firmware partition"), "{}", err);
+		}
+		// The step is matched by the partition number, the third partition
+		// used to be looked up by the index 2.
+		let err = load("", 1)?.check().unwrap_err().to_string();
+		assert!(
+			err.contains(
+				"write to partition 1 which already contains an active filesystem"
+			),
+			"{}",
+			err
+		);
+		let err = load("", 4)?.check().unwrap_err().to_string();
+		assert!(
+			err.contains("Partition 4 specified by a bootloader is not found"),
+			"{}",
+			err
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_swap_partition() -> Result<()> {
+		let dir = TempDir::new("swap-partition")?;
+		let load = |swap: &str| {
+			load_device(
+				&dir,
+				&format!(
+					r#"partition_map = "gpt"
+
+[[partition]]
+num = 1
+usage = "swap"
+size = "1GiB"
+{}
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+"#,
+					swap
+				),
+			)
+		};
+		let check = |swap: &str| -> Result<()> {
+			let device = load(swap)?;
+			device.check_swap_partition(&device.partitions[0])
+		};
+		let err = |swap: &str| check(swap).unwrap_err().to_string();
+		check("type = \"swap\"\nfilesystem = \"swap\"")?;
+		let e = err("type = \"swap\"\nfilesystem = \"ext4\"");
+		assert!(e.contains("must be used together"), "{}", e);
+		let e = err("type = \"swap\"\nfilesystem = \"swap\"\nmountpoint = \"/swap\"");
+		assert!(e.contains("can not have a mountpoint"), "{}", e);
+		let device = load("type = \"swap\"\nfilesystem = \"swap\"")?;
+		let mut data = device.partitions[1].clone();
+		data.usage = PartitionUsage::Data;
+		data.filesystem = FilesystemType::Ext4;
+		data.part_type = PartitionType::Swap;
+		let e = device.check_swap_partition(&data).unwrap_err().to_string();
+		assert!(e.contains("has the swap partition type"), "{}", e);
+		Ok(())
+	}
+
+	#[test]
+	fn test_boot_markers() -> Result<()> {
+		let dir = TempDir::new("boot-markers")?;
+		let device = load_device(
+			&dir,
+			r#"partition_map = "gpt"
+
+[[partition]]
+num = 1
+type = "swap"
+usage = "swap"
+filesystem = "swap"
+size = "1GiB"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+"#,
+		)?;
+		let mut swap = device.partitions[0].clone();
+		assert!(boot_markers(&swap).is_empty());
+		swap.attributes = PartitionAttributes(vec![GPT_ATTRIBUTE_LEGACY_BOOT]);
+		assert_eq!(boot_markers(&swap), ["the legacy-boot attribute"]);
+		swap.part_type = PartitionType::EFI;
+		assert_eq!(
+			boot_markers(&swap),
+			["the legacy-boot attribute", "the ESP type"]
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_mbr_partuuid() {
+		// Same as what blkid reports.
+		assert_eq!(mbr_partuuid(0x5452574f, 2), "5452574f-02");
+		assert_eq!(mbr_partuuid(0x0000beef, 1), "0000beef-01");
+	}
+
+	#[test]
+	fn test_parse_disk_signature() {
+		assert_eq!(parse_disk_signature("5452574f").unwrap(), 0x5452574f);
+		assert_eq!(parse_disk_signature("0x0000BEEF").unwrap(), 0x0000beef);
+		assert!(parse_disk_signature("5452-574f").is_err());
+		assert!(parse_disk_signature("5452574f0").is_err());
+		assert!(parse_disk_signature("g452574f").is_err());
+	}
 }
@@ -0,0 +1,341 @@
+//! Module copying the device tree blobs into the boot partition.
+//!
+//! Devices booting with a device tree can name the blob and the overlays
+//! they need with the `dtb` and `dtb_overlays` keys, relative to the
+//! directory of the device tree blobs shipped with the kernel:
+//!
+//! ```toml
+//! dtb = "rockchip/rk3588-rock-5b.dtb"
+//! dtb_overlays = ["rockchip/overlay/rk3588-uart7-m2.dtbo"]
+//! ```
+//!
+//! After the BSP packages are installed, the blobs are searched in the
+//! following directories of the target, in order:
+//!
+//! - `/boot/dtbs/<version>`
+//! - `/usr/lib/linux-image-<version>`
+//! - `/boot/dtbs`
+//! - `/usr/lib/dtbs`
+//!
+//! Where `<version>` is the version of the selected kernel (see
+//! [`KernelSpec`]). The build fails if a blob is not found.
+//!
+//! The blob is copied to the root of the boot partition, i.e. the partition
+//! with `usage = "boot"`, and the overlays to its `overlays` directory.
+//! The overlays are installed by their file names, two overlays with the
+//! same file name are rejected.
+//!
+//! If `compatible` is defined, it must be one of the `compatible`
+//! strings in the root node of the blob.
+//!
+//! [`KernelSpec`]: crate::kernel::KernelSpec
+use std::{
+	fs,
+	path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+	context::ImageContext, device::DeviceSpec, kernel::KernelInfo, partition::PartitionUsage,
+};
+
+/// Prefixes of the directories of the device tree blobs followed by the kernel version, within the target.
+const VERSIONED_DTB_DIRS: &[&str] = &["boot/dtbs/", "usr/lib/linux-image-"];
+/// Directories of the device tree blobs without the kernel version, within the target.
+const DTB_DIRS: &[&str] = &["boot/dtbs", "usr/lib/dtbs"];
+/// Directory of the overlays in the boot partition.
+pub(crate) const OVERLAYS_DIR: &str = "overlays";
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+/// Get the directories which may contain the device tree blobs, in the order of preference.
+pub fn dtb_dirs(kernel: Option<&KernelInfo>) -> Vec<PathBuf> {
+	let mut dirs = Vec::new();
+	if let Some(kernel) = kernel {
+		for prefix in VERSIONED_DTB_DIRS {
+			dirs.push(PathBuf::from(format!("{}{}", prefix, kernel.version)));
+		}
+	}
+	dirs.extend(DTB_DIRS.iter().map(PathBuf::from));
+	dirs
+}
+
+/// Make sure the path of a blob is relative and stays in the directory of the blobs.
+pub fn check_dtb_path(path: &Path) -> Result<()> {
+	if path.file_name().is_none()
+		|| !path.components().all(|c| matches!(c, Component::Normal(_)))
+	{
+		bail!(
+			"'{}' must be a path relative to the directory of the device tree blobs",
+			path.display()
+		);
+	}
+	Ok(())
+}
+
+/// Make sure no two overlays have the same file name, as they are all copied into [`OVERLAYS_DIR`].
+pub fn check_dtb_overlays(overlays: &[PathBuf]) -> Result<()> {
+	for (idx, overlay) in overlays.iter().enumerate() {
+		if let Some(other) = overlays[..idx]
+			.iter()
+			.find(|o| o.file_name() == overlay.file_name())
+		{
+			bail!(
+				"Device tree overlays '{}' and '{}' would both be installed as /{}/{}",
+				other.display(),
+				overlay.display(),
+				OVERLAYS_DIR,
+				overlay.file_name().unwrap_or_default().to_string_lossy()
+			);
+		}
+	}
+	Ok(())
+}
+
+/// Find the blob in the first directory containing it, returns its path within the target.
+pub(crate) fn find_dtb(root: &Path, dirs: &[PathBuf], name: &Path) -> Result<PathBuf> {
+	match dirs.iter().map(|d| d.join(name)).find(|p| root.join(p).is_file()) {
+		Some(path) => Ok(path),
+		None => bail!(
+			"Device tree blob '{}' is not found in any of the installed packages (searched in {}). Make sure the package shipping it is listed in bsp_packages.",
+			name.display(),
+			dirs.iter()
+				.map(|d| format!("/{}", d.display()))
+				.collect::<Vec<_>>()
+				.join(", ")
+		),
+	}
+}
+
+fn align4(n: usize) -> usize {
+	(n + 3) & !3
+}
+
+/// Read the `compatible` property of the root node of a flattened device tree.
+///
+/// Returns `None` if the blob is malformed or the property is not present.
+pub fn read_compatible(blob: &[u8]) -> Option<Vec<String>> {
+	let be32 = |off: usize| {
+		blob.get(off..off + 4)
+			.map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+	};
+	if be32(0)? != FDT_MAGIC {
+		return None;
+	}
+	let strings = blob.get(be32(12)? as usize..)?;
+	let mut pos = be32(8)? as usize;
+	let mut in_root = false;
+	loop {
+		let token = be32(pos)?;
+		pos += 4;
+		match token {
+			// The properties of a node precede its children.
+			FDT_BEGIN_NODE if in_root => return None,
+			FDT_BEGIN_NODE => {
+				in_root = true;
+				let len = blob.get(pos..)?.iter().position(|&b| b == 0)?;
+				pos = align4(pos + len + 1);
+			}
+			FDT_PROP => {
+				let len = be32(pos)? as usize;
+				let name = strings.get(be32(pos + 4)? as usize..)?;
+				let name = &name[..name.iter().position(|&b| b == 0)?];
+				let value = blob.get(pos + 8..pos + 8 + len)?;
+				pos = align4(pos + 8 + len);
+				if name == b"compatible" {
+					return Some(value
+						.split(|&b| b == 0)
+						.filter(|s| !s.is_empty())
+						.map(|s| String::from_utf8_lossy(s).into_owned())
+						.collect());
+				}
+			}
+			FDT_NOP => {}
+			_ => return None,
+		}
+	}
+}
+
+impl DeviceSpec {
+	/// Mountpoint of the partition the device tree blobs are installed into.
+	pub(crate) fn dtb_mountpoint(&self) -> Result<&str> {
+		self.partitions
+			.iter()
+			.find(|p| p.usage == PartitionUsage::Boot)
+			.and_then(|p| p.mountpoint.as_deref())
+			.context("Device tree blobs require a boot partition with a mountpoint")
+	}
+}
+
+impl ImageContext<'_> {
+	/// Copy the device tree blob and the overlays into the boot partition.
+	pub fn install_dtbs(&self, root: &Path, kernel: Option<&KernelInfo>) -> Result<()> {
+		if self.device.dtb.is_none() && self.device.dtb_overlays.is_empty() {
+			return Ok(());
+		}
+		let boot_mp = self.device.dtb_mountpoint()?;
+		let boot_dir = root.join(boot_mp.trim_start_matches('/'));
+		let dirs = dtb_dirs(kernel);
+		if let Some(dtb) = &self.device.dtb {
+			let src = find_dtb(root, &dirs, dtb)?;
+			let blob = fs::read(root.join(&src))?;
+			if let Some(c) = &self.device.of_compatible {
+				match read_compatible(&blob) {
+					Some(compatible) if !compatible.contains(c) => bail!(
+						"Device tree blob /{} is compatible with {}, but the device is {}",
+						src.display(),
+						compatible.join(", "),
+						c
+					),
+					Some(_) => {}
+					None => self.warn(format!(
+						"Unable to read the compatible property of /{}, skipping the check",
+						src.display()
+					)),
+				}
+			}
+			// Safe to unwrap, checked by check_dtb_path().
+			let dst = boot_dir.join(dtb.file_name().unwrap());
+			self.info(format!(
+				"Installing device tree blob /{} ...",
+				src.display()
+			));
+			fs::write(&dst, &blob).context(format!(
+				"Failed to copy the device tree blob to {}",
+				dst.display()
+			))?;
+		}
+		if !self.device.dtb_overlays.is_empty() {
+			fs::create_dir_all(boot_dir.join(OVERLAYS_DIR))?;
+		}
+		for overlay in &self.device.dtb_overlays {
+			let src = find_dtb(root, &dirs, overlay)?;
+			let dst = boot_dir
+				.join(OVERLAYS_DIR)
+				.join(overlay.file_name().unwrap());
+			self.info(format!(
+				"Installing device tree overlay /{} ...",
+				src.display()
+			));
+			fs::copy(root.join(&src), &dst).context(format!(
+				"Failed to copy the device tree overlay to {}",
+				dst.display()
+			))?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	/// Build a device tree with the compatible strings, and a child node.
+	fn fdt(compatible: &[&str]) -> Vec<u8> {
+		let mut dt_struct = Vec::new();
+		let mut push = |v: u32| dt_struct.extend_from_slice(&v.to_be_bytes());
+		push(FDT_BEGIN_NODE);
+		push(0);
+		push(FDT_NOP);
+		let value = compatible
+			.iter()
+			.map(|c| format!("{}\0", c))
+			.collect::<String>();
+		push(FDT_PROP);
+		push(value.len() as u32);
+		// "model" precedes "compatible" in the strings block
+		push(6);
+		dt_struct.extend_from_slice(value.as_bytes());
+		dt_struct.resize(align4(dt_struct.len()), 0);
+		for v in [FDT_BEGIN_NODE, u32::from_be_bytes(*b"cpus"), 0, 2, 2, 9] {
+			dt_struct.extend_from_slice(&v.to_be_bytes());
+		}
+		let strings = b"model\0compatible\0";
+		let mut blob = Vec::new();
+		let off_struct = 40u32;
+		let off_strings = off_struct + dt_struct.len() as u32;
+		for v in [
+			FDT_MAGIC,
+			off_strings + strings.len() as u32,
+			off_struct,
+			off_strings,
+		] {
+			blob.extend_from_slice(&v.to_be_bytes());
+		}
+		blob.resize(off_struct as usize, 0);
+		blob.extend_from_slice(&dt_struct);
+		blob.extend_from_slice(strings);
+		blob
+	}
+
+	#[test]
+	fn test_read_compatible() {
+		let blob = fdt(&["radxa,rock-5b", "rockchip,rk3588"]);
+		assert_eq!(
+			read_compatible(&blob),
+			Some(vec![
+				"radxa,rock-5b".to_string(),
+				"rockchip,rk3588".to_string()
+			])
+		);
+		assert_eq!(read_compatible(&blob[..blob.len() - 20]), None);
+		assert_eq!(read_compatible(b"not a device tree"), None);
+	}
+
+	#[test]
+	fn test_find_dtb() -> Result<()> {
+		let root = TempDir::new("dtb")?;
+		let kernel = KernelInfo {
+			version: "6.1.75-rockchip".into(),
+			image: "/boot/vmlinuz-6.1.75-rockchip".into(),
+			modules: "/usr/lib/modules/6.1.75-rockchip".into(),
+		};
+		for dir in [
+			"usr/lib/linux-image-6.1.75-rockchip/rockchip",
+			"boot/dtbs/rockchip",
+		] {
+			fs::create_dir_all(root.join(dir))?;
+			fs::write(root.join(dir).join("rk3588-rock-5b.dtb"), "")?;
+		}
+		fs::write(root.join("boot/dtbs/rockchip/rk3588-rock-5b-plus.dtb"), "")?;
+		let dirs = dtb_dirs(Some(&kernel));
+		assert_eq!(
+			find_dtb(&root, &dirs, Path::new("rockchip/rk3588-rock-5b.dtb"))?,
+			Path::new(
+				"usr/lib/linux-image-6.1.75-rockchip/rockchip/rk3588-rock-5b.dtb"
+			)
+		);
+		assert_eq!(
+			find_dtb(&root, &dirs, Path::new("rockchip/rk3588-rock-5b-plus.dtb"))?,
+			Path::new("boot/dtbs/rockchip/rk3588-rock-5b-plus.dtb")
+		);
+		assert!(
+			find_dtb(&root, &dirs, Path::new("rockchip/rk3588-nanopc-t6.dtb")).is_err()
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_dtb_path() {
+		assert!(check_dtb_path(Path::new("rockchip/rk3588-rock-5b.dtb")).is_ok());
+		assert!(check_dtb_path(Path::new("/boot/dtbs/rk3588-rock-5b.dtb")).is_err());
+		assert!(check_dtb_path(Path::new("../rk3588-rock-5b.dtb")).is_err());
+		assert!(check_dtb_path(Path::new("")).is_err());
+		let overlays = [
+			PathBuf::from("rockchip/overlay/rk3588-uart7-m2.dtbo"),
+			PathBuf::from("rockchip/overlay/rk3588-i2c8-m2.dtbo"),
+		];
+		assert!(check_dtb_overlays(&overlays).is_ok());
+		let err = check_dtb_overlays(&[
+			overlays[0].clone(),
+			PathBuf::from("vendor/rk3588-uart7-m2.dtbo"),
+		])
+		.unwrap_err();
+		assert!(err.to_string().contains("/overlays/rk3588-uart7-m2.dtbo"));
+	}
+}
@@ -0,0 +1,473 @@
+//! LUKS2 encryption of the partitions.
+//!
+//! A partition declaring `encryption` is formatted as a LUKS2 container with
+//! `cryptsetup`, and its filesystem is created within the container:
+//!
+//! ```toml
+//! [[partition]]
+//! num = 3
+//! type = "linux"
+//! size = "rest"
+//! filesystem = "ext4"
+//! mountpoint = "/home"
+//! usage = "data"
+//! # Relative to the directory of device.toml
+//! encryption = { type = "luks2", passphrase_file = "passphrase.txt" }
+//! ```
+//!
+//! The passphrase is the content of the file, without the trailing newline,
+//! so it can be typed on the first boot. The key slot and the key
+//! derivation can be chosen as well:
+//!
+//! ```toml
+//! encryption = { type = "luks2", passphrase_file = "passphrase.txt", key_slot = 1, pbkdf = "pbkdf2", iter_time = 1000 }
+//! ```
+//!
+//! While the image is built, the container is opened as a dm-crypt mapping
+//! named after the partition (e.g. `mkrawimg-loop0p3`), which is formatted,
+//! mounted and populated like the partition itself would be. The mappings
+//! are closed before the loop device is detached, or after the filesystems
+//! on them are unmounted if the build fails. The leftovers of a build which
+//! died halfway are closed along with the loop devices they are on.
+//!
+//! In the target, every encrypted partition with a mountpoint gets an entry
+//! in `/etc/crypttab`, unlocking the container by its LUKS UUID to
+//! `/dev/mapper/luks-<UUID>`, which the `/etc/fstab` entry mounts. The
+//! passphrase is asked for on boot. The ones without a mountpoint, e.g. slot
+//! B of the A/B slots, are left locked.
+//!
+//! The boot partition can not be encrypted, the firmware and the
+//! bootloaders read it. An encrypted root partition has to be unlocked by
+//! the initrd, thus it is not available to `initrdless` devices. The
+//! initrd is told so on the kernel command line, which must be defined:
+//! `rd.luks.uuid=` and `root=/dev/mapper/luks-<UUID>` are added to it, see
+//! [`crate::cmdline`].
+use std::{
+	ffi::OsString,
+	fs::{self, File},
+	io::Write,
+	os::unix::fs::FileTypeExt,
+	path::{Path, PathBuf},
+	process::Command,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	context::ImageContext,
+	device::{DeviceSpec, PartitionMapData},
+	partition::PartitionSpec,
+	runner,
+	utils::{
+		cmd_run_check_status, get_fsuuid, loop_devices_backed_by, mounts_under,
+		release_leftovers,
+	},
+};
+
+/// Prefix of the dm-crypt mappings opened during the builds.
+const MAPPING_PREFIX: &str = "mkrawimg-";
+
+/// Number of the key slots in a LUKS2 header.
+const LUKS2_KEY_SLOTS: u32 = 32;
+
+/// Key derivation function of a key slot.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Pbkdf {
+	Argon2id,
+	Argon2i,
+	Pbkdf2,
+}
+
+impl Pbkdf {
+	fn as_str(&self) -> &'static str {
+		match self {
+			Self::Argon2id => "argon2id",
+			Self::Argon2i => "argon2i",
+			Self::Pbkdf2 => "pbkdf2",
+		}
+	}
+}
+
+/// Encryption of a partition, see the [module documentation](self).
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EncryptionSpec {
+	/// A LUKS2 container unlocked by a passphrase.
+	Luks2 {
+		/// File containing the passphrase, relative to the directory of `device.toml`.
+		passphrase_file: PathBuf,
+		/// Key slot holding the passphrase, the first free one by default.
+		key_slot: Option<u32>,
+		/// Key derivation function, the default of cryptsetup if not specified.
+		pbkdf: Option<Pbkdf>,
+		/// Time spent on the key derivation, in milliseconds.
+		iter_time: Option<u32>,
+	},
+}
+
+impl EncryptionSpec {
+	/// Make sure the key slot options are valid, and the passphrase file exists.
+	pub fn check(&self, spec_dir: &Path) -> Result<()> {
+		let Self::Luks2 {
+			passphrase_file,
+			key_slot,
+			iter_time,
+			..
+		} = self;
+		if key_slot.is_some_and(|slot| slot >= LUKS2_KEY_SLOTS) {
+			bail!("key_slot must be within 0 to {}", LUKS2_KEY_SLOTS - 1);
+		}
+		if *iter_time == Some(0) {
+			bail!("iter_time can not be zero");
+		}
+		let passphrase = read_passphrase(&spec_dir.join(passphrase_file))?;
+		if passphrase.is_empty() {
+			bail!("The passphrase in '{}' is empty", passphrase_file.display());
+		}
+		Ok(())
+	}
+
+	/// Arguments of `cryptsetup luksFormat`, reading the passphrase from the standard input.
+	fn format_args(&self, partition: &Path, uuid: Option<&str>) -> Vec<OsString> {
+		let Self::Luks2 {
+			key_slot,
+			pbkdf,
+			iter_time,
+			..
+		} = self;
+		let mut args: Vec<OsString> = vec![
+			"luksFormat".into(),
+			"--type".into(),
+			"luks2".into(),
+			"--batch-mode".into(),
+			"--key-file=-".into(),
+		];
+		if let Some(slot) = key_slot {
+			args.push(format!("--key-slot={}", slot).into());
+		}
+		if let Some(pbkdf) = pbkdf {
+			args.push(format!("--pbkdf={}", pbkdf.as_str()).into());
+		}
+		if let Some(ms) = iter_time {
+			args.push(format!("--iter-time={}", ms).into());
+		}
+		if let Some(uuid) = uuid {
+			args.push(format!("--uuid={}", uuid).into());
+		}
+		args.push(partition.into());
+		args
+	}
+
+	fn passphrase(&self, spec_dir: &Path) -> Result<String> {
+		let Self::Luks2 {
+			passphrase_file, ..
+		} = self;
+		let passphrase = read_passphrase(&spec_dir.join(passphrase_file))?;
+		runner::register_secret(&passphrase);
+		Ok(passphrase)
+	}
+}
+
+/// Read the passphrase from the file, without the trailing newline.
+fn read_passphrase(path: &Path) -> Result<String> {
+	let content = fs::read_to_string(path).context(format!(
+		"Unable to read the passphrase file '{}'",
+		path.display()
+	))?;
+	Ok(content
+		.strip_suffix('\n')
+		.map(|s| s.strip_suffix('\r').unwrap_or(s))
+		.unwrap_or(&content)
+		.to_owned())
+}
+
+/// Name of the mapping opened for the partition during the build, e.g. `mkrawimg-loop0p3`.
+pub fn mapping_name(partition: &str) -> String {
+	let name = Path::new(partition)
+		.file_name()
+		.map(|n| n.to_string_lossy().to_string())
+		.unwrap_or_else(|| partition.to_owned());
+	format!("{}{}", MAPPING_PREFIX, name)
+}
+
+/// Path to the opened mapping of the partition.
+pub fn mapping_path(partition: &str) -> PathBuf {
+	Path::new("/dev/mapper").join(mapping_name(partition))
+}
+
+/// Whether the block device `name` is the disk `disk` or one of its partitions, e.g. `loop0p3` of `loop0`.
+fn is_on_disk(name: &str, disk: &str) -> bool {
+	match name.strip_prefix(disk) {
+		Some("") => true,
+		// Disks ending with a digit have a `p` before the partition number.
+		Some(rest) => {
+			let rest = if disk.ends_with(|c: char| c.is_ascii_digit()) {
+				rest.strip_prefix('p').unwrap_or_default()
+			} else {
+				rest
+			};
+			!rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit())
+		}
+		None => false,
+	}
+}
+
+/// Find the mappings opened by mkrawimg on the disk or its partitions.
+pub fn mappings_on<P: AsRef<Path>>(disk: P) -> Vec<String> {
+	let disk = disk.as_ref();
+	let Some(disk) = disk.file_name().map(|n| n.to_string_lossy().to_string()) else {
+		return Vec::new();
+	};
+	let entries = match fs::read_dir("/sys/block") {
+		Ok(e) => e,
+		Err(_) => return Vec::new(),
+	};
+	let mut result = Vec::new();
+	for entry in entries.flatten() {
+		let on_disk = fs::read_dir(entry.path().join("slaves"))
+			.map(|slaves| {
+				slaves.flatten().any(|s| {
+					is_on_disk(&s.file_name().to_string_lossy(), &disk)
+				})
+			})
+			.unwrap_or(false);
+		if !on_disk {
+			continue;
+		}
+		let name = fs::read_to_string(entry.path().join("dm/name")).unwrap_or_default();
+		let name = name.trim_end();
+		if name.starts_with(MAPPING_PREFIX) {
+			result.push(name.to_owned());
+		}
+	}
+	result.sort();
+	result
+}
+
+/// Close and remove the mapping.
+pub fn close_mapping(name: &str) -> Result<()> {
+	let mut cmd = Command::new("cryptsetup");
+	cmd.args(["close", name]);
+	cmd_run_check_status(&mut cmd).context(format!("Failed to close the mapping {}", name))
+}
+
+impl ImageContext<'_> {
+	fn spec_dir(&self) -> Result<&Path> {
+		self.device
+			.file_path
+			.parent()
+			.context("Failed to reach the directory containing the device spec file")
+	}
+
+	/// Format the partition as a LUKS2 container, returning its UUID.
+	pub(crate) fn encrypt_partition(
+		&self,
+		encryption: &EncryptionSpec,
+		partition: &str,
+		uuid: Option<&str>,
+	) -> Result<String> {
+		let passphrase = encryption.passphrase(self.spec_dir()?)?;
+		let mut cmd = Command::new("cryptsetup");
+		cmd.args(encryption.format_args(Path::new(partition), uuid));
+		let status = runner::run_with_input(&mut cmd, passphrase.as_bytes())?;
+		if !status.success() {
+			bail!("Failed to format {} as a LUKS2 container", partition);
+		}
+		get_fsuuid(&partition).map(|uuid| uuid.to_string())
+	}
+
+	/// Open the LUKS2 container of the partition, see [`mapping_path`].
+	pub(crate) fn open_mapping(
+		&self,
+		encryption: &EncryptionSpec,
+		partition: &str,
+	) -> Result<PathBuf> {
+		let passphrase = encryption.passphrase(self.spec_dir()?)?;
+		let mut cmd = Command::new("cryptsetup");
+		cmd.args(["open", "--type", "luks2", "--key-file=-", partition])
+			.arg(mapping_name(partition));
+		let status = runner::run_with_input(&mut cmd, passphrase.as_bytes())?;
+		if !status.success() {
+			bail!("Failed to open the LUKS2 container on {}", partition);
+		}
+		Ok(mapping_path(partition))
+	}
+
+	/// Open the containers of the encrypted partitions with a filesystem.
+	pub(crate) fn open_mappings(&self, loop_dev: &Path) -> Result<()> {
+		for partition in &self.device.partitions {
+			let Some(encryption) = &partition.encryption else {
+				continue;
+			};
+			if !partition.filesystem.is_mountable() {
+				continue;
+			}
+			let dev = self.device.partition_path(loop_dev, partition.num);
+			self.info(format!("Opening the LUKS2 container on {} ...", dev));
+			self.open_mapping(encryption, &dev)?;
+		}
+		Ok(())
+	}
+
+	/// Close the mappings opened on the loop device.
+	pub(crate) fn close_mappings(&self, loop_dev: &Path) -> Result<()> {
+		for name in mappings_on(loop_dev) {
+			self.info(format!("Closing the mapping {} ...", name));
+			close_mapping(&name)?;
+		}
+		Ok(())
+	}
+
+	/// Unmount the filesystems of a failed build, and close the mappings on `image`.
+	///
+	/// `image` is an image file, attached to loop devices, or a block device.
+	/// The loop devices are kept, along with the sketch directory if it is
+	/// kept for debugging. The mappings are opened with the passphrases, so
+	/// the failures are reported to close them manually.
+	pub(crate) fn release_mappings(&self, image: &Path) {
+		let mut disks = loop_devices_backed_by(image);
+		if image.metadata()
+			.is_ok_and(|m| m.file_type().is_block_device())
+		{
+			disks.push(image.to_owned());
+		}
+		if disks.iter().all(|dev| mappings_on(dev).is_empty()) {
+			return;
+		}
+		let result = mounts_under(self.sketch_dir())
+			.and_then(|mounts| release_leftovers(&mounts, &[]))
+			.and_then(|_| disks.iter().try_for_each(|dev| self.close_mappings(dev)));
+		if let Err(e) = result {
+			self.warn(format!(
+				"Unable to close the LUKS2 mappings: {:#}\nClose them with `cryptsetup close` manually.",
+				e
+			));
+		}
+	}
+
+	/// Append the entries of the encrypted partitions to `/etc/crypttab`.
+	pub(crate) fn generate_crypttab(
+		&self,
+		pm_data: &PartitionMapData,
+		container: &dyn AsRef<Path>,
+	) -> Result<()> {
+		let mut content = String::new();
+		for partition in &self.device.partitions {
+			if partition.encryption.is_none() || partition.mountpoint.is_none() {
+				continue;
+			}
+			let uuid = luks_uuid(pm_data, partition)?;
+			content += &format!(
+				"{}\tUUID={}\tnone\tluks\n",
+				target_mapping_name(uuid),
+				uuid
+			);
+		}
+		if content.is_empty() {
+			return Ok(());
+		}
+		self.info("Generating /etc/crypttab ...");
+		let crypttab_path = container.as_ref().join("etc/crypttab");
+		let mut crypttab_fd = File::options()
+			.create(true)
+			.append(true)
+			.open(&crypttab_path)?;
+		crypttab_fd.write_all(
+			format!("\n# ---- Auto generated by mkrawimg ----\n{}", content).as_bytes(),
+		)?;
+		crypttab_fd.flush()?;
+		crypttab_fd.sync_all()?;
+		Ok(())
+	}
+}
+
+impl DeviceSpec {
+	/// Path to the block device holding the filesystem of the partition, the mapping if it is encrypted.
+	pub fn filesystem_path<P: AsRef<Path>>(
+		&self,
+		disk: P,
+		partition: &PartitionSpec,
+	) -> String {
+		let dev = self.partition_path(disk, partition.num);
+		match partition.encryption {
+			Some(_) => mapping_path(&dev).to_string_lossy().to_string(),
+			None => dev,
+		}
+	}
+}
+
+/// UUID of the LUKS2 container of the partition.
+pub fn luks_uuid<'a>(pm_data: &'a PartitionMapData, partition: &PartitionSpec) -> Result<&'a str> {
+	pm_data.data
+		.get(&partition.num)
+		.and_then(|data| data.luks_uuid.as_deref())
+		.context(format!(
+			"Unable to get the LUKS UUID of partition {}",
+			partition.num
+		))
+}
+
+/// Name of the mapping unlocked in the target, as the systemd convention.
+pub fn target_mapping_name(luks_uuid: &str) -> String {
+	format!("luks-{}", luks_uuid)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	#[test]
+	fn test_encryption() -> Result<()> {
+		let dir = TempDir::new("encryption")?;
+		fs::write(dir.join("passphrase.txt"), "correct horse\n")?;
+		fs::write(dir.join("empty.txt"), "\n")?;
+		assert_eq!(
+			read_passphrase(&dir.join("passphrase.txt"))?,
+			"correct horse"
+		);
+		let spec = |file: &str, key_slot| EncryptionSpec::Luks2 {
+			passphrase_file: file.into(),
+			key_slot,
+			pbkdf: Some(Pbkdf::Pbkdf2),
+			iter_time: Some(1000),
+		};
+		assert!(spec("passphrase.txt", Some(1)).check(&dir).is_ok());
+		assert!(spec("passphrase.txt", Some(32)).check(&dir).is_err());
+		assert!(spec("empty.txt", None).check(&dir).is_err());
+		assert!(spec("missing.txt", None).check(&dir).is_err());
+		assert_eq!(
+			spec("passphrase.txt", Some(1))
+				.format_args(Path::new("/dev/loop0p3"), None),
+			[
+				"luksFormat",
+				"--type",
+				"luks2",
+				"--batch-mode",
+				"--key-file=-",
+				"--key-slot=1",
+				"--pbkdf=pbkdf2",
+				"--iter-time=1000",
+				"/dev/loop0p3"
+			]
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_mapping_names() {
+		assert_eq!(mapping_name("/dev/loop0p3"), "mkrawimg-loop0p3");
+		assert_eq!(
+			mapping_path("/dev/sdb2"),
+			Path::new("/dev/mapper/mkrawimg-sdb2")
+		);
+		assert!(is_on_disk("loop0p3", "loop0"));
+		assert!(is_on_disk("loop0", "loop0"));
+		assert!(!is_on_disk("loop01", "loop0"));
+		assert!(!is_on_disk("loop1p3", "loop0"));
+		assert!(is_on_disk("sdb2", "sdb"));
+		assert!(!is_on_disk("sdb", "sd"));
+	}
+}
@@ -0,0 +1,406 @@
+//! Read-only EROFS root filesystem, with a writable overlay partition.
+//!
+//! Kiosks and appliances want an immutable, compressed root filesystem. With
+//! `filesystem = "erofs"`, the root partition is not formatted and mounted
+//! like the others. Instead, the distribution is installed into a staging
+//! directory in the working directory, and once the post installation and
+//! the bootloader steps are done, the EROFS image is built from it with
+//! `mkfs.erofs` and written to the partition:
+//!
+//! ```toml
+//! [[partition]]
+//! num = 2
+//! type = "linux"
+//! usage = "rootfs"
+//! filesystem = "erofs"
+//! mountpoint = "/"
+//! size = "4GiB"
+//! # Optional, lz4hc by default. An algorithm of mkfs.erofs, and optionally the level.
+//! fs_compression = "zstd,9"
+//! ```
+//!
+//! - The image must fit in the partition, an EROFS root can not be grown on
+//!   the first boot.
+//! - Only the root filesystem (and slot B of it, which is left empty) can be
+//!   EROFS.
+//! - The filesystem UUID is decided when the partitions are formatted, so
+//!   the kernel command line and `/etc/fstab` can refer to it as usual.
+//!
+//! A partition with `usage = "overlay"` holds the writable upper layers of
+//! the overlays mounted on `/etc` and `/var`:
+//!
+//! ```toml
+//! [[partition]]
+//! num = 3
+//! type = "linux"
+//! usage = "overlay"
+//! filesystem = "ext4"
+//! mountpoint = "/overlay"
+//! size = "rest"
+//! ```
+//!
+//! The upper and the work directories of each overlay (e.g.
+//! `/overlay/upper/etc` and `/overlay/work/etc`) are created while the
+//! image is populated, and `/etc/fstab` gets an `overlay` entry for each of
+//! them, mounted after the overlay partition with
+//! `x-systemd.requires-mounts-for`. Only one overlay partition is allowed,
+//! it requires an EROFS root filesystem.
+//!
+//! `/etc` is already in use once the root filesystem is booted, so the
+//! overlay partition and the overlays are mounted by the initramfs with
+//! `x-initrd.mount`, below `/sysroot`. This requires a systemd based
+//! initramfs, e.g. one generated by dracut.
+use std::{
+	fs::{self, create_dir_all},
+	path::Path,
+	process::Command,
+};
+
+use anyhow::{bail, Context, Result};
+use uuid::Uuid;
+
+use crate::{
+	content::write_raw,
+	context::ImageContext,
+	device::{DeviceSpec, PartitionMapData},
+	filesystem::FilesystemType,
+	partition::{PartitionSpec, PartitionUsage},
+	utils::cmd_run_check_status,
+};
+
+/// Compression of the EROFS images, if `fs_compression` is not specified.
+pub const DEFAULT_COMPRESSION: &str = "lz4hc";
+/// Compression algorithms accepted by mkfs.erofs.
+const COMPRESSION_ALGORITHMS: &[&str] = &["lz4", "lz4hc", "lzma", "deflate", "zstd"];
+/// Directories made writable by the overlays.
+pub const OVERLAY_DIRS: &[&str] = &["/etc", "/var"];
+/// Where the initramfs mounts the root filesystem.
+const INITRD_SYSROOT: &str = "/sysroot";
+/// Mount option of the entries mounted by the initramfs.
+pub const INITRD_MOUNT_OPT: &str = "x-initrd.mount";
+
+/// Make sure the compression is an algorithm of mkfs.erofs, optionally followed by `,<level>`.
+pub fn check_compression(compression: &str) -> Result<()> {
+	let (algorithm, level) = match compression.split_once(',') {
+		Some((algorithm, level)) => (algorithm, Some(level)),
+		None => (compression, None),
+	};
+	if !COMPRESSION_ALGORITHMS.contains(&algorithm) {
+		bail!(
+			"Unknown EROFS compression algorithm '{}', expected one of {}",
+			algorithm,
+			COMPRESSION_ALGORITHMS.join(", ")
+		);
+	}
+	if level.is_some_and(|l| l.parse::<u32>().is_err()) {
+		bail!("Invalid EROFS compression level in '{}'", compression);
+	}
+	Ok(())
+}
+
+/// The upper and the work directory of the overlay on `dir`, relative to the overlay partition.
+fn overlay_layers(dir: &str) -> (String, String) {
+	let name = dir.trim_start_matches('/');
+	(format!("upper/{}", name), format!("work/{}", name))
+}
+
+impl DeviceSpec {
+	/// The partition holding the upper layers of the overlays.
+	pub fn overlay_partition(&self) -> Option<&PartitionSpec> {
+		self.partitions
+			.iter()
+			.find(|p| p.usage == PartitionUsage::Overlay)
+	}
+
+	/// Make sure the EROFS filesystems and the overlay partition are usable, see the [module documentation](self).
+	pub(crate) fn check_erofs(&self) -> Result<()> {
+		for partition in &self.partitions {
+			let num = partition.num;
+			if let Some(compression) = &partition.fs_compression {
+				if partition.filesystem != FilesystemType::Erofs {
+					bail!(
+						"Partition {}: fs_compression is only supported by EROFS",
+						num
+					);
+				}
+				check_compression(compression)
+					.context(format!("Partition {}", num))?;
+			}
+			if partition.filesystem == FilesystemType::Erofs
+				&& !self.follows_rootfs_fstype(partition)
+			{
+				bail!("Partition {} is EROFS, which is only supported for the root filesystem", num);
+			}
+		}
+		let mut overlays = self
+			.partitions
+			.iter()
+			.filter(|p| p.usage == PartitionUsage::Overlay);
+		let Some(overlay) = overlays.next() else {
+			return Ok(());
+		};
+		let num = overlay.num;
+		if let Some(other) = overlays.next() {
+			bail!(
+				"Partitions {} and {} are both overlay partitions, only one is allowed",
+				num,
+				other.num
+			);
+		}
+		let erofs_root = self.partitions.iter().any(|p| {
+			p.usage == PartitionUsage::Rootfs && p.filesystem == FilesystemType::Erofs
+		});
+		if !erofs_root {
+			bail!(
+				"Partition {} is an overlay partition, which requires an EROFS root filesystem",
+				num
+			);
+		}
+		if !matches!(
+			overlay.filesystem,
+			FilesystemType::Ext4 | FilesystemType::Xfs | FilesystemType::Btrfs
+		) {
+			bail!("Partition {} is an overlay partition, it must be ext4, xfs or btrfs to hold the ownership, permissions and extended attributes of the files", num);
+		}
+		let mountpoint = overlay.mountpoint.as_deref().context(format!(
+			"Partition {} is an overlay partition, it must have a mountpoint",
+			num
+		))?;
+		let hidden = OVERLAY_DIRS.iter().any(|dir| {
+			Path::new(mountpoint).starts_with(dir)
+				|| Path::new(dir).starts_with(mountpoint)
+		});
+		if hidden {
+			bail!(
+				"Partition {} is an overlay partition, its mountpoint {} can not contain or be within {}",
+				num,
+				mountpoint,
+				OVERLAY_DIRS.join(" or ")
+			);
+		}
+		Ok(())
+	}
+}
+
+impl ImageContext<'_> {
+	/// The root partition, if it is EROFS after `--fstype`.
+	pub(crate) fn erofs_root(&self) -> Option<&PartitionSpec> {
+		self.device.partitions.iter().find(|p| {
+			p.usage == PartitionUsage::Rootfs
+				&& self.effective_fstype(p) == FilesystemType::Erofs
+		})
+	}
+
+	/// UUID of the EROFS filesystem of the partition, pinned, derived from the seed or random.
+	pub(crate) fn erofs_uuid(&self, partition: &PartitionSpec) -> Result<Uuid> {
+		match self.filesystem_uuid(partition, &FilesystemType::Erofs) {
+			Some(uuid) => Uuid::parse_str(&uuid)
+				.context(format!("Invalid filesystem UUID '{}'", uuid)),
+			None => Ok(Uuid::new_v4()),
+		}
+	}
+
+	/// Create the upper and the work directories of the overlays, in the overlay partition mounted in `rootdir`.
+	pub(crate) fn prepare_overlay_dirs(&self, rootdir: &Path) -> Result<()> {
+		let Some(mountpoint) = self
+			.device
+			.overlay_partition()
+			.and_then(|p| p.mountpoint.as_deref())
+		else {
+			return Ok(());
+		};
+		let base = rootdir.join(mountpoint.trim_start_matches('/'));
+		for dir in OVERLAY_DIRS {
+			let (upper, work) = overlay_layers(dir);
+			for layer in [upper, work] {
+				create_dir_all(base.join(&layer)).context(format!(
+					"Failed to create {}/{}",
+					mountpoint, layer
+				))?;
+			}
+		}
+		Ok(())
+	}
+
+	/// The `/etc/fstab` entries of the overlays, mounted by the initramfs.
+	///
+	/// The paths in the options are not relocated to `/sysroot` by systemd.
+	pub(crate) fn overlay_fstab_entries(&self) -> String {
+		let Some(mountpoint) = self
+			.device
+			.overlay_partition()
+			.and_then(|p| p.mountpoint.as_deref())
+		else {
+			return String::new();
+		};
+		let base = format!("{}{}", INITRD_SYSROOT, mountpoint.trim_end_matches('/'));
+		let mut entries = String::new();
+		for dir in OVERLAY_DIRS {
+			let (upper, work) = overlay_layers(dir);
+			entries += &format!(
+				"overlay\t{0}\toverlay\tlowerdir={1}{0},upperdir={2}/{3},workdir={2}/{4},{5},x-systemd.requires-mounts-for={2}\t0\t0\n",
+				dir, INITRD_SYSROOT, base, upper, work, INITRD_MOUNT_OPT
+			);
+		}
+		entries
+	}
+
+	/// Build the EROFS image from the staging directory, and write it to the root partition.
+	pub(crate) fn build_erofs_root(
+		&self,
+		partition: &PartitionSpec,
+		staging: &Path,
+		loop_dev: &Path,
+		pm_data: &PartitionMapData,
+	) -> Result<()> {
+		let num = partition.num;
+		if !staging.join("usr").is_dir() {
+			bail!(
+				"The staging directory {} of the EROFS root is not populated, run the populate stage first",
+				staging.display()
+			);
+		}
+		let uuid = pm_data
+			.data
+			.get(&num)
+			.and_then(|d| d.fs_uuid.as_ref())
+			.context(format!("No filesystem UUID of partition {}", num))?;
+		let compression = partition
+			.fs_compression
+			.as_deref()
+			.unwrap_or(DEFAULT_COMPRESSION);
+		let image = self.sketch_dir().join(format!("p{}.erofs", num));
+		self.info(format!(
+			"Building the EROFS root filesystem ({}) ...",
+			compression
+		));
+		let mut cmd = Command::new("mkfs.erofs");
+		cmd.arg(format!("-z{}", compression))
+			.args(["-U", &uuid.to_string()]);
+		if let Some(label) = &partition.fs_label {
+			cmd.args(["-L", label]);
+		}
+		cmd.arg("--").arg(&image).arg(staging);
+		cmd_run_check_status(&mut cmd)
+			.context("Failed to build the EROFS root filesystem")?;
+		let dev = self.device.filesystem_path(loop_dev, partition);
+		self.info(format!(
+			"Writing the EROFS root filesystem to partition {} ...",
+			num
+		));
+		let result = write_raw(&image, Path::new(&dev)).context(format!(
+			"The EROFS root filesystem does not fit in partition {}, enlarge it or choose a stronger fs_compression",
+			num
+		));
+		fs::remove_file(&image)?;
+		result
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::{load_device, test_context, TempDir};
+
+	const GPT: &str = "partition_map = \"gpt\"\n";
+
+	const EROFS_ROOT: &str = r#"
+[[partition]]
+num = 1
+type = "linux"
+usage = "rootfs"
+filesystem = "erofs"
+mountpoint = "/"
+size = "2GiB"
+"#;
+
+	const OVERLAY: &str = r#"
+[[partition]]
+num = 2
+type = "linux"
+usage = "overlay"
+filesystem = "ext4"
+mountpoint = "/overlay"
+size = "rest"
+"#;
+
+	#[test]
+	fn test_check_erofs() -> Result<()> {
+		let dir = TempDir::new("erofs")?;
+		let check =
+			|spec: &str| load_device(&dir, &format!("{}{}", GPT, spec))?.check_erofs();
+		let err = |spec: &str| check(spec).unwrap_err().to_string();
+		check(&format!(
+			"{}fs_compression = \"zstd,9\"\n{}",
+			EROFS_ROOT, OVERLAY
+		))?;
+		let e = err(&format!("{}fs_compression = \"gzip\"\n", EROFS_ROOT));
+		assert!(e.contains("Partition 1"), "{}", e);
+		let ext4_root = EROFS_ROOT.replace("erofs", "ext4");
+		let e = err(&format!("{}fs_compression = \"lz4\"\n", ext4_root));
+		assert!(e.contains("only supported by EROFS"), "{}", e);
+		let e = err(&format!("{}{}", ext4_root, OVERLAY));
+		assert!(e.contains("requires an EROFS root filesystem"), "{}", e);
+		let e = err(&format!(
+			"{}{}",
+			EROFS_ROOT,
+			OVERLAY.replace("ext4", "fat32")
+		));
+		assert!(e.contains("must be ext4, xfs or btrfs"), "{}", e);
+		let e = err(&format!(
+			"{}{}",
+			EROFS_ROOT,
+			OVERLAY.replace("/overlay", "/var/overlay")
+		));
+		assert!(e.contains("can not contain or be within"), "{}", e);
+		let e = err(&format!(
+			"{}{}{}",
+			EROFS_ROOT,
+			OVERLAY,
+			OVERLAY.replace("num = 2", "num = 3")
+				.replace("/overlay", "/overlay2")
+		));
+		assert!(e.contains("only one is allowed"), "{}", e);
+		let data = EROFS_ROOT
+			.replace("num = 1", "num = 2")
+			.replace("rootfs", "data")
+			.replace("\"/\"", "\"/data\"");
+		let e = err(&format!("{}{}", ext4_root, data));
+		assert!(
+			e.contains("only supported for the root filesystem"),
+			"{}",
+			e
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_overlay_fstab_entries() -> Result<()> {
+		let dir = TempDir::new("erofs-fstab")?;
+		let device = load_device(&dir, &format!("{}{}{}", GPT, EROFS_ROOT, OVERLAY))?;
+		let context = test_context(&device, &dir);
+		let entries = context.overlay_fstab_entries();
+		assert_eq!(
+			entries.lines().next(),
+			Some("overlay\t/etc\toverlay\tlowerdir=/sysroot/etc,upperdir=/sysroot/overlay/upper/etc,workdir=/sysroot/overlay/work/etc,x-initrd.mount,x-systemd.requires-mounts-for=/sysroot/overlay\t0\t0")
+		);
+		assert_eq!(entries.lines().count(), OVERLAY_DIRS.len());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_compression() {
+		assert!(check_compression("lz4hc").is_ok());
+		assert!(check_compression("zstd,9").is_ok());
+		assert!(check_compression("gzip").is_err());
+		assert!(check_compression("lzma,max").is_err());
+	}
+
+	#[test]
+	fn test_overlay_layers() {
+		assert_eq!(
+			overlay_layers("/etc"),
+			("upper/etc".to_owned(), "work/etc".to_owned())
+		);
+	}
+}
@@ -0,0 +1,207 @@
+//! Tuning of the ext4 filesystems.
+//!
+//! The defaults of `mke2fs` do not suit every partition: 5% of the blocks
+//! are reserved for root, 3 GiB on a 64 GiB data partition, and a periodic
+//! check may be forced at the worst time. An ext4 partition can be tuned
+//! with `tune2fs` right after it is created:
+//!
+//! ```toml
+//! [[partition]]
+//! num = 3
+//! type = "linux"
+//! usage = "data"
+//! filesystem = "ext4"
+//! mountpoint = "/srv"
+//! size = "rest"
+//! # tune2fs -m 1
+//! ext4_reserved_percent = 1
+//! # tune2fs -o acl,^user_xattr
+//! ext4_default_mount_opts = ["acl", "^user_xattr"]
+//! # tune2fs -i 0
+//! ext4_check_interval = "0"
+//! ```
+//!
+//! - `ext4_reserved_percent`: Percentage of the blocks reserved for root,
+//!   from 0 to 50.
+//! - `ext4_default_mount_opts`: Default mount options stored in the
+//!   superblock, one of `tune2fs -o`. Prefixed with `^`, the option is
+//!   cleared.
+//! - `ext4_check_interval`: Maximum time between two forced checks, a number
+//!   of days, or followed by `d` (days), `w` (weeks), `m` (months of 30
+//!   days) or `s` (seconds). At most 365 days, `0` disables the checks.
+//!
+//! The settings are dropped if `--fstype` replaces the filesystem of the root
+//! partition.
+use std::{path::Path, process::Command};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+	context::ImageContext, device::DeviceSpec, filesystem::FilesystemType,
+	partition::PartitionSpec, utils::cmd_run_check_status,
+};
+
+/// Maximum percentage of the blocks reserved for root, accepted by `tune2fs -m`.
+const MAX_RESERVED_PERCENT: u8 = 50;
+/// Maximum interval between two forced checks, accepted by `tune2fs -i`, in seconds.
+const MAX_CHECK_INTERVAL: u64 = 365 * 86400;
+/// Default mount options accepted by `tune2fs -o`.
+const DEFAULT_MOUNT_OPTS: &[&str] = &[
+	"debug",
+	"bsdgroups",
+	"user_xattr",
+	"acl",
+	"uid16",
+	"journal_data",
+	"journal_data_ordered",
+	"journal_data_writeback",
+	"nobarrier",
+	"block_validity",
+	"discard",
+	"nodelalloc",
+];
+
+/// Parse the interval between two forced checks the way `tune2fs -i` does, returns the seconds.
+fn parse_check_interval(interval: &str) -> Result<u64> {
+	let split = interval
+		.find(|c: char| !c.is_ascii_digit())
+		.unwrap_or(interval.len());
+	let (number, unit) = interval.split_at(split);
+	let number: u64 = number
+		.parse()
+		.context(format!("Invalid check interval '{}'", interval))?;
+	let unit = match unit {
+		"" | "d" | "D" => 86400,
+		"w" | "W" => 86400 * 7,
+		"m" | "M" => 86400 * 30,
+		"s" => 1,
+		_ => bail!(
+			"Invalid check interval '{}', the unit must be d, w, m or s",
+			interval
+		),
+	};
+	let seconds = number.saturating_mul(unit);
+	if seconds > MAX_CHECK_INTERVAL {
+		bail!(
+			"Check interval '{}' exceeds the limit of 365 days",
+			interval
+		);
+	}
+	Ok(seconds)
+}
+
+impl PartitionSpec {
+	/// Whether the partition tunes its ext4 filesystem.
+	fn tunes_ext4(&self) -> bool {
+		self.ext4_reserved_percent.is_some()
+			|| self.ext4_default_mount_opts.is_some()
+			|| self.ext4_check_interval.is_some()
+	}
+}
+
+impl DeviceSpec {
+	/// Make sure the ext4 settings are valid, see the [module documentation](crate::ext4).
+	pub(crate) fn check_ext4(&self) -> Result<()> {
+		for partition in &self.partitions {
+			let num = partition.num;
+			if !partition.tunes_ext4() {
+				continue;
+			}
+			if partition.filesystem != FilesystemType::Ext4 {
+				bail!("Partition {} is not ext4, it can not be tuned with the ext4_* options", num);
+			}
+			if let Some(percent) = partition.ext4_reserved_percent {
+				if percent > MAX_RESERVED_PERCENT {
+					bail!(
+						"Partition {}: ext4_reserved_percent must be from 0 to {}, got {}",
+						num,
+						MAX_RESERVED_PERCENT,
+						percent
+					);
+				}
+			}
+			if let Some(opts) = &partition.ext4_default_mount_opts {
+				if opts.is_empty() {
+					bail!(
+						"Partition {}: ext4_default_mount_opts is empty",
+						num
+					);
+				}
+				for opt in opts {
+					if !DEFAULT_MOUNT_OPTS
+						.contains(&opt.trim_start_matches('^'))
+					{
+						bail!(
+							"Partition {}: unknown default mount option '{}', it must be one of {}",
+							num,
+							opt,
+							DEFAULT_MOUNT_OPTS.join(", ")
+						);
+					}
+				}
+			}
+			if let Some(interval) = &partition.ext4_check_interval {
+				parse_check_interval(interval)
+					.context(format!("Partition {}", num))?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl ImageContext<'_> {
+	/// Arguments of `tune2fs` tuning the partition, empty if it is not tuned.
+	pub(crate) fn tune2fs_args(&self, partition: &PartitionSpec) -> Vec<String> {
+		let mut args = Vec::new();
+		if self.effective_fstype(partition) != partition.filesystem {
+			return args;
+		}
+		if let Some(percent) = partition.ext4_reserved_percent {
+			args.extend(["-m".to_owned(), percent.to_string()]);
+		}
+		if let Some(opts) = &partition.ext4_default_mount_opts {
+			args.extend(["-o".to_owned(), opts.join(",")]);
+		}
+		if let Some(interval) = &partition.ext4_check_interval {
+			args.extend(["-i".to_owned(), interval.clone()]);
+		}
+		args
+	}
+
+	/// Tune the ext4 filesystem of the partition at `dev`, see the [module documentation](self).
+	pub(crate) fn tune_ext4(&self, partition: &PartitionSpec, dev: &Path) -> Result<()> {
+		let args = self.tune2fs_args(partition);
+		if args.is_empty() {
+			return Ok(());
+		}
+		self.info(format!(
+			"Tuning the ext4 filesystem of partition {}: tune2fs {}",
+			partition.num,
+			args.join(" ")
+		));
+		let mut cmd = Command::new("tune2fs");
+		cmd.args(&args).arg(dev);
+		cmd_run_check_status(&mut cmd).context(format!(
+			"Failed to tune the filesystem of partition {}",
+			partition.num
+		))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_check_interval() {
+		assert_eq!(parse_check_interval("0").unwrap(), 0);
+		assert_eq!(parse_check_interval("180").unwrap(), 180 * 86400);
+		assert_eq!(parse_check_interval("2w").unwrap(), 14 * 86400);
+		assert_eq!(parse_check_interval("6m").unwrap(), 180 * 86400);
+		assert_eq!(parse_check_interval("3600s").unwrap(), 3600);
+		assert!(parse_check_interval("13m").is_err());
+		assert!(parse_check_interval("1y").is_err());
+		assert!(parse_check_interval("").is_err());
+		assert!(parse_check_interval("-1").is_err());
+	}
+}
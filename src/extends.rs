@@ -0,0 +1,416 @@
+//! Inheritance of the device specifications, with the `extends` key.
+//!
+//! Devices which only differ in a few keys, e.g. the variants of a board,
+//! can share the rest in a base specification:
+//!
+//! ```toml
+//! # devices/orangepi/opi-5-plus/device.toml
+//! extends = "../base-rk3588.toml"
+//! id = "opi-5-plus"
+//! name = "Orange Pi 5 Plus"
+//!
+//! [[partitions]]
+//! num = 1
+//! ...
+//! ```
+//!
+//! `extends` is either a path to the base relative to the directory of the
+//! specification, which must end with `.toml` or contain a `/`, or the ID
+//! or an alias of a device in the same registry (the directory two levels
+//! above, `REGISTRY/VENDOR/DEVICE/device.toml`). A base can extend another
+//! one, and does not have to be complete on its own.
+//!
+//! The keys of the specification override the ones of the base:
+//!
+//! - Tables, e.g. `[size]`, are merged key by key.
+//! - Arrays, e.g. `partitions` and `bsp_packages`, are replaced wholesale,
+//!   unless their key is listed in `merge`. Arrays of tables with a `num`
+//!   key (`partitions`) are then merged by `num`, other arrays are appended
+//!   to the ones of the base. The arrays in a table listed in `merge`, e.g.
+//!   the per-variant `bsp_packages`, are merged the same way.
+//!
+//! ```toml
+//! extends = "rock-5b"
+//! id = "rock-5b-plus"
+//! name = "Radxa ROCK 5B+"
+//! merge = ["bsp_packages"]
+//! # Installed in addition to the packages of rock-5b.
+//! bsp_packages = ["rtl8852be-firmware"]
+//! ```
+//!
+//! The keys identifying a device (`id`, `aliases`, `hostname`,
+//! `compatible`, `deprecated` and `deprecation_reason`) are never inherited,
+//! so the specification must have its own `id`.
+//!
+//! The scripts (the post installation script and the bootloader scripts)
+//! are always found in the directory of the specification itself, use
+//! symbolic links to share them.
+//!
+//! Bases which do not exist and cycles are refused, naming both the
+//! specification and the base.
+use std::{
+	fs,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use toml::{Table, Value};
+
+use crate::{registry::DeviceRegistry, variables::expand_variables};
+
+/// Key naming the base specification.
+pub const EXTENDS_KEY: &str = "extends";
+/// Key listing the arrays to be merged with the ones of the base.
+pub const MERGE_KEY: &str = "merge";
+/// Keys identifying a device, which are not inherited from the base.
+const IDENTITY_KEYS: &[&str] = &[
+	"id",
+	"aliases",
+	"hostname",
+	"compatible",
+	"deprecated",
+	"deprecation_reason",
+];
+
+/// Read the specification as a table, with the bases applied and the variables expanded.
+pub fn load_spec_table(file: &Path) -> Result<Table> {
+	let mut table = load_chain(file, &mut Vec::new())?;
+	expand_variables(&mut table)
+		.context(format!("Invalid variables in '{}'", file.display()))?;
+	Ok(table)
+}
+
+/// List the specification and its bases, starting from the specification itself.
+pub fn spec_files(file: &Path) -> Result<Vec<PathBuf>> {
+	let mut files = vec![file.to_owned()];
+	let mut canonical = vec![file.canonicalize()?];
+	loop {
+		let file = &files[files.len() - 1];
+		let base = match read_table(file)?.remove(EXTENDS_KEY) {
+			Some(Value::String(base)) => resolve_base(file, &base)?,
+			_ => return Ok(files),
+		};
+		let base_canonical = base.canonicalize()?;
+		if canonical.contains(&base_canonical) {
+			bail!(
+				"Cycle in the base specifications of '{}'",
+				files[0].display()
+			);
+		}
+		canonical.push(base_canonical);
+		files.push(base);
+	}
+}
+
+fn read_table(file: &Path) -> Result<Table> {
+	let content = fs::read_to_string(file)
+		.context(format!("Unable to read file '{}'", &file.to_string_lossy()))?;
+	toml::from_str(&content).context(format!(
+		"Unable to treat '{}' as an entry of the registry",
+		&file.to_string_lossy()
+	))
+}
+
+fn load_chain(file: &Path, chain: &mut Vec<PathBuf>) -> Result<Table> {
+	let canonical = file
+		.canonicalize()
+		.context(format!("Unable to find '{}'", file.display()))?;
+	if let Some(pos) = chain.iter().position(|p| *p == canonical) {
+		let cycle = chain[pos..]
+			.iter()
+			.chain(std::iter::once(&canonical))
+			.map(|p| p.display().to_string())
+			.collect::<Vec<_>>();
+		bail!(
+			"Cycle in the base specifications: {}",
+			cycle.join(" extends ")
+		);
+	}
+	chain.push(canonical);
+	let mut table = read_table(file)?;
+	let base = match table.remove(EXTENDS_KEY) {
+		Some(Value::String(base)) => base,
+		Some(_) => bail!("'{}' in '{}' must be a string", EXTENDS_KEY, file.display()),
+		None => {
+			table.remove(MERGE_KEY);
+			return Ok(table);
+		}
+	};
+	let base_file = resolve_base(file, &base)?;
+	let mut base_table = load_chain(&base_file, chain).context(format!(
+		"Unable to load the base specification '{}' of '{}'",
+		base_file.display(),
+		file.display()
+	))?;
+	for key in IDENTITY_KEYS {
+		base_table.remove(*key);
+	}
+	let merged = match table.remove(MERGE_KEY) {
+		None => Vec::new(),
+		Some(Value::Array(keys)) => keys
+			.iter()
+			.map(|k| k.as_str().map(str::to_owned))
+			.collect::<Option<Vec<_>>>()
+			.context(format!(
+				"'{}' in '{}' must be a list of keys",
+				MERGE_KEY,
+				file.display()
+			))?,
+		Some(_) => bail!(
+			"'{}' in '{}' must be a list of keys",
+			MERGE_KEY,
+			file.display()
+		),
+	};
+	Ok(merge_tables(base_table, table, &merged))
+}
+
+/// Find the file of the base specification.
+fn resolve_base(file: &Path, base: &str) -> Result<PathBuf> {
+	let dir = file.parent().unwrap_or(Path::new("."));
+	if base.ends_with(".toml") || base.contains('/') {
+		let path = dir.join(base);
+		if !path.is_file() {
+			bail!(
+				"Base specification '{}' of '{}' does not exist",
+				path.display(),
+				file.display()
+			);
+		}
+		return Ok(path);
+	}
+	// REGISTRY/VENDOR/DEVICE/device.toml
+	let registry_dir = dir
+		.canonicalize()?
+		.ancestors()
+		.nth(2)
+		.map(Path::to_owned)
+		.context(format!(
+			"Unable to find the registry of '{}'",
+			file.display()
+		))?;
+	for spec in DeviceRegistry::find_spec_files(&registry_dir)? {
+		let Ok(table) = read_table(&spec) else {
+			continue;
+		};
+		let names = table
+			.get("id")
+			.into_iter()
+			.chain(table
+				.get("aliases")
+				.and_then(Value::as_array)
+				.into_iter()
+				.flatten())
+			.filter_map(Value::as_str);
+		if names.into_iter().any(|n| n == base) {
+			return Ok(spec);
+		}
+	}
+	bail!(
+		"Base device '{}' of '{}' is not found in the registry at {}",
+		base,
+		file.display(),
+		registry_dir.display()
+	)
+}
+
+/// Merge the specification into the base, see the [module documentation](self).
+///
+/// `merged` lists the top-level arrays to be merged instead of replaced.
+pub fn merge_tables(mut base: Table, table: Table, merged: &[String]) -> Table {
+	for (key, value) in table {
+		let merge = merged.contains(&key);
+		let value = match (base.remove(&key), value) {
+			(Some(Value::Table(b)), Value::Table(t)) => {
+				// The arrays in a merged table are merged as well.
+				let nested = match merge {
+					true => t.keys().cloned().collect(),
+					false => Vec::new(),
+				};
+				Value::Table(merge_tables(b, t, &nested))
+			}
+			(Some(Value::Array(b)), Value::Array(t)) if merge => {
+				Value::Array(merge_arrays(b, t))
+			}
+			(_, value) => value,
+		};
+		base.insert(key, value);
+	}
+	base
+}
+
+/// Merge arrays of tables by `num`, append other arrays.
+fn merge_arrays(mut base: Vec<Value>, array: Vec<Value>) -> Vec<Value> {
+	let num = |v: &Value| {
+		v.as_table()
+			.and_then(|t| t.get("num"))
+			.and_then(Value::as_integer)
+	};
+	for value in array {
+		match num(&value).and_then(|n| base.iter().position(|b| num(b) == Some(n))) {
+			Some(idx) => {
+				let Value::Table(t) = value else {
+					unreachable!()
+				};
+				let Value::Table(b) = base.remove(idx) else {
+					unreachable!()
+				};
+				base.insert(idx, Value::Table(merge_tables(b, t, &[])));
+			}
+			None if base.contains(&value) => (),
+			None => base.push(value),
+		}
+	}
+	base
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	fn table(pairs: &[(&str, Value)]) -> Table {
+		pairs.iter()
+			.map(|(k, v)| (k.to_string(), v.clone()))
+			.collect()
+	}
+
+	fn partition(num: i64, fs: &str) -> Value {
+		Value::Table(table(&[
+			("num", Value::Integer(num)),
+			("filesystem", fs.into()),
+		]))
+	}
+
+	fn strings(values: &[&str]) -> Value {
+		Value::Array(values.iter().map(|&s| s.into()).collect())
+	}
+
+	#[test]
+	fn test_merge_tables() {
+		let base = table(&[
+			("id", "rock-5b".into()),
+			("vendor", "radxa".into()),
+			(
+				"size",
+				Value::Table(table(&[
+					("base", Value::Integer(6144)),
+					("desktop", Value::Integer(25000)),
+				])),
+			),
+			(
+				"bsp_packages",
+				strings(&["u-boot-rk3588", "linux+kernel+rk3588"]),
+			),
+			(
+				"partitions",
+				Value::Array(vec![partition(1, "fat32"), partition(2, "ext4")]),
+			),
+		]);
+		let spec = table(&[
+			("id", "rock-5b-plus".into()),
+			(
+				"size",
+				Value::Table(table(&[("base", Value::Integer(8192))])),
+			),
+			(
+				"bsp_packages",
+				strings(&["u-boot-rk3588", "rtl8852be-firmware"]),
+			),
+			("partitions", Value::Array(vec![partition(2, "btrfs")])),
+		]);
+		let merged = merge_tables(base.clone(), spec.clone(), &[]);
+		assert_eq!(merged["id"], "rock-5b-plus".into());
+		assert_eq!(merged["vendor"], "radxa".into());
+		let size = merged["size"].as_table().unwrap();
+		assert_eq!(size["base"], Value::Integer(8192));
+		assert_eq!(size["desktop"], Value::Integer(25000));
+		// Replaced wholesale.
+		assert_eq!(
+			merged["partitions"],
+			Value::Array(vec![partition(2, "btrfs")])
+		);
+		assert_eq!(
+			merged["bsp_packages"],
+			strings(&["u-boot-rk3588", "rtl8852be-firmware"])
+		);
+		let merged = merge_tables(
+			base,
+			spec,
+			&["partitions".to_string(), "bsp_packages".to_string()],
+		);
+		assert_eq!(
+			merged["partitions"],
+			Value::Array(vec![partition(1, "fat32"), partition(2, "btrfs")])
+		);
+		assert_eq!(
+			merged["bsp_packages"],
+			strings(&["u-boot-rk3588", "linux+kernel+rk3588", "rtl8852be-firmware"])
+		);
+	}
+
+	#[test]
+	fn test_identity_not_inherited() -> Result<()> {
+		let dir = TempDir::new("extends")?;
+		fs::write(
+			dir.join("base.toml"),
+			"id = \"rock-5b\"\naliases = [\"rock5b\"]\nvendor = \"radxa\"\nhostname = \"rock-5b\"\ncompatible = \"radxa,rock-5b\"\ndeprecated = true\ndeprecation_reason = \"EOL\"\n",
+		)?;
+		let spec = dir.join("device.toml");
+		fs::write(&spec, "extends = \"base.toml\"\nid = \"rock-5b-plus\"\n")?;
+		let table = load_chain(&spec, &mut Vec::new())?;
+		assert_eq!(table["id"], "rock-5b-plus".into());
+		assert_eq!(table["vendor"], "radxa".into());
+		for key in [
+			"aliases",
+			"hostname",
+			"compatible",
+			"deprecated",
+			"deprecation_reason",
+		] {
+			assert!(!table.contains_key(key), "{} is inherited", key);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_broken_chain() -> Result<()> {
+		let dir = TempDir::new("extends")?;
+		let device_dir = dir.join("radxa").join("rock-5b-plus");
+		fs::create_dir_all(&device_dir)?;
+		let spec = device_dir.join("device.toml");
+		let err = |content: &str| -> Result<String> {
+			fs::write(&spec, content)?;
+			let err = load_chain(&spec, &mut Vec::new()).err().unwrap();
+			Ok(format!("{:#}", err))
+		};
+		assert!(err("extends = \"missing.toml\"\n")?.contains("does not exist"));
+		assert!(err("extends = \"rock-5b\"\n")?.contains("Base device 'rock-5b' of"));
+		fs::write(device_dir.join("a.toml"), "extends = \"b.toml\"\n")?;
+		fs::write(device_dir.join("b.toml"), "extends = \"a.toml\"\n")?;
+		assert!(err("extends = \"a.toml\"\n")?.contains("Cycle in the base specifications"));
+		Ok(())
+	}
+
+	#[test]
+	fn test_merge_nested_arrays() {
+		let packages = |common: &[&str], desktop: &[&str]| {
+			Value::Table(table(&[
+				("common", strings(common)),
+				("desktop", strings(desktop)),
+			]))
+		};
+		let base = table(&[("bsp_packages", packages(&["linux+kernel"], &["phosh"]))]);
+		let spec = table(&[(
+			"bsp_packages",
+			packages(&["firmware"], &["phosh", "squeekboard"]),
+		)]);
+		let merged = merge_tables(base.clone(), spec.clone(), &[]);
+		assert_eq!(merged["bsp_packages"], spec["bsp_packages"]);
+		let merged = merge_tables(base, spec, &["bsp_packages".to_string()]);
+		assert_eq!(
+			merged["bsp_packages"],
+			packages(&["linux+kernel", "firmware"], &["phosh", "squeekboard"])
+		);
+	}
+}
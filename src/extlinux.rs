@@ -0,0 +1,662 @@
+//! Generation of `extlinux.conf`.
+//!
+//! Most devices running U-Boot boot with the generic distro boot command,
+//! which reads `/extlinux/extlinux.conf` or `/boot/extlinux/extlinux.conf`
+//! from the bootable partition. Instead of writing it with a script or a
+//! template, an `extlinux` bootloader step generates it:
+//!
+//! ```toml
+//! [[bootloader]]
+//! type = "extlinux"
+//! ```
+//!
+//! By default, the file is written to the partition with `usage = "boot"`,
+//! or to the root partition if there is no boot partition. Another partition
+//! with a mountpoint can be selected with `partition`. The file is
+//! `<mountpoint>/extlinux/extlinux.conf`, or `/boot/extlinux/extlinux.conf`
+//! in the root partition.
+//!
+//! The paths are within the target, and must be within the mountpoint of the
+//! partition. They are written relative to the root of the partition, e.g.
+//! `/boot/vmlinuz-6.12.3` becomes `/vmlinuz-6.12.3` in a boot partition
+//! mounted at `/boot`. Unless specified, they are filled in from the kernel
+//! selected in the target (see [`KernelSpec`]):
+//!
+//! - `kernel`: The image of the kernel.
+//! - `initrd`: `/boot/initrd.img-<version>`, `/boot/initramfs-<version>.img`,
+//!   `/boot/initrd-<version>` or `/usr/lib/modules/<version>/initrd`,
+//!   whichever exists. Never set for `initrdless` devices.
+//! - `fdt`: The device tree blob installed by `dtb`, see [`crate::dtb`]. The
+//!   overlays of `dtb_overlays` are added with `fdtoverlays`. They are
+//!   installed into the boot partition, which must be the selected partition
+//!   or mounted within it.
+//! - `fdtdir`: Without `dtb`, the first directory of the device tree blobs
+//!   of the kernel within the partition, if any.
+//! - `append`: `{KERNEL_CMDLINE}`.
+//!
+//! The placeholders in `append` are substituted as in the `config` steps,
+//! e.g. `{ROOT_PARTUUID}`, see [`crate::cmdline`].
+//!
+//! With `entries`, the menu has more than one entry. The first one is booted
+//! by default, and the fields not specified by an entry are inherited from
+//! the step:
+//!
+//! ```toml
+//! [[bootloader]]
+//! type = "extlinux"
+//! menu_title = "AOSC OS"
+//! # In 1/10 seconds.
+//! timeout = 30
+//! fdtdir = "/boot/dtbs"
+//!
+//! [[bootloader.entries]]
+//! label = "aosc"
+//! menu_label = "AOSC OS"
+//!
+//! [[bootloader.entries]]
+//! label = "recovery"
+//! menu_label = "AOSC OS (single user mode)"
+//! append = "{KERNEL_CMDLINE} single"
+//! ```
+//!
+//! [`KernelSpec`]: crate::kernel::KernelSpec
+use std::{
+	collections::{BTreeMap, HashSet},
+	fmt::Write,
+	fs,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+	cmdline::substitute,
+	context::ImageContext,
+	device::{DeviceSpec, PartitionMapData},
+	dtb::{dtb_dirs, OVERLAYS_DIR},
+	kernel::KernelInfo,
+	partition::{PartitionSpec, PartitionUsage},
+};
+
+/// Label of the only entry, if the step has no `entries`.
+const DEFAULT_LABEL: &str = "linux";
+
+/// Generate `extlinux.conf`, see the [module documentation](self).
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct ExtlinuxSpec {
+	/// The partition to write `extlinux.conf` to, the boot partition by default.
+	pub partition: Option<u32>,
+	/// Title of the menu.
+	pub menu_title: Option<String>,
+	/// Time to wait before booting the default entry, in 1/10 seconds.
+	pub timeout: Option<u32>,
+	#[serde(flatten)]
+	pub boot: ExtlinuxBoot,
+	/// Entries of the menu, a single entry with the fields above if empty.
+	#[serde(default)]
+	pub entries: Vec<ExtlinuxEntry>,
+}
+
+/// An entry of the menu.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct ExtlinuxEntry {
+	pub label: String,
+	/// Label shown in the menu.
+	pub menu_label: Option<String>,
+	#[serde(flatten)]
+	pub boot: ExtlinuxBoot,
+}
+
+/// What an entry boots, the paths are within the target.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct ExtlinuxBoot {
+	pub kernel: Option<PathBuf>,
+	pub initrd: Option<PathBuf>,
+	pub fdt: Option<PathBuf>,
+	pub fdtdir: Option<PathBuf>,
+	pub append: Option<String>,
+}
+
+/// A resolved entry, the paths are relative to the root of the partition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry {
+	label: String,
+	menu_label: Option<String>,
+	kernel: PathBuf,
+	initrd: Option<PathBuf>,
+	fdt: Option<PathBuf>,
+	fdtdir: Option<PathBuf>,
+	fdtoverlays: Vec<PathBuf>,
+	append: String,
+}
+
+/// Get the path relative to the root of the partition mounted at `mountpoint`.
+fn partition_relative(path: &Path, mountpoint: &str) -> Result<PathBuf> {
+	if !path.is_absolute() {
+		bail!("'{}' must be an absolute path", path.display());
+	}
+	let rest = path.strip_prefix(mountpoint).ok().context(format!(
+		"'{}' is not within the partition mounted at {}",
+		path.display(),
+		mountpoint
+	))?;
+	Ok(Path::new("/").join(rest))
+}
+
+/// Path to `extlinux.conf` within the target, in the partition mounted at `mountpoint`.
+fn config_path(mountpoint: &str) -> PathBuf {
+	if mountpoint == "/" {
+		PathBuf::from("/boot/extlinux/extlinux.conf")
+	} else {
+		Path::new(mountpoint).join("extlinux/extlinux.conf")
+	}
+}
+
+/// Render `extlinux.conf`.
+fn render(menu_title: Option<&str>, timeout: Option<u32>, entries: &[Entry]) -> String {
+	let mut content = "# Generated by mkrawimg.\n".to_owned();
+	if let Some(title) = menu_title {
+		writeln!(content, "menu title {}", title).unwrap();
+	}
+	if let Some(timeout) = timeout {
+		writeln!(content, "timeout {}", timeout).unwrap();
+	}
+	if let Some(entry) = entries.first() {
+		writeln!(content, "default {}", entry.label).unwrap();
+	}
+	for entry in entries {
+		writeln!(content, "\nlabel {}", entry.label).unwrap();
+		if let Some(menu_label) = &entry.menu_label {
+			writeln!(content, "\tmenu label {}", menu_label).unwrap();
+		}
+		writeln!(content, "\tlinux {}", entry.kernel.display()).unwrap();
+		if let Some(initrd) = &entry.initrd {
+			writeln!(content, "\tinitrd {}", initrd.display()).unwrap();
+		}
+		if let Some(fdt) = &entry.fdt {
+			writeln!(content, "\tfdt {}", fdt.display()).unwrap();
+		}
+		if let Some(fdtdir) = &entry.fdtdir {
+			writeln!(content, "\tfdtdir {}", fdtdir.display()).unwrap();
+		}
+		if !entry.fdtoverlays.is_empty() {
+			let overlays = entry
+				.fdtoverlays
+				.iter()
+				.map(|p| p.display().to_string())
+				.collect::<Vec<_>>();
+			writeln!(content, "\tfdtoverlays {}", overlays.join(" ")).unwrap();
+		}
+		writeln!(content, "\tappend {}", entry.append).unwrap();
+	}
+	content
+}
+
+impl ExtlinuxSpec {
+	/// The entries of the menu, with the fields of the step they inherit.
+	fn entries(&self) -> Vec<(String, Option<String>, ExtlinuxBoot)> {
+		if self.entries.is_empty() {
+			return vec![(DEFAULT_LABEL.to_owned(), None, self.boot.clone())];
+		}
+		self.entries
+			.iter()
+			.map(|e| {
+				// fdt and fdtdir are inherited together, they exclude each other.
+				let fdt_from = if e.boot.fdt.is_some() || e.boot.fdtdir.is_some() {
+					&e.boot
+				} else {
+					&self.boot
+				};
+				let boot = ExtlinuxBoot {
+					kernel: e.boot.kernel.clone().or(self.boot.kernel.clone()),
+					initrd: e.boot.initrd.clone().or(self.boot.initrd.clone()),
+					fdt: fdt_from.fdt.clone(),
+					fdtdir: fdt_from.fdtdir.clone(),
+					append: e.boot.append.clone().or(self.boot.append.clone()),
+				};
+				(e.label.clone(), e.menu_label.clone(), boot)
+			})
+			.collect()
+	}
+}
+
+impl DeviceSpec {
+	/// The partition `extlinux.conf` is written to.
+	pub(crate) fn extlinux_partition(&self, spec: &ExtlinuxSpec) -> Result<&PartitionSpec> {
+		let partition = match spec.partition {
+			Some(num) => self.partitions.iter().find(|p| p.num == num).context(
+				format!("Partition {} specified by the extlinux bootloader is not found.", num),
+			)?,
+			None => self
+				.partitions
+				.iter()
+				.find(|p| p.usage == PartitionUsage::Boot && p.mountpoint.is_some())
+				.or_else(|| self.partitions.iter().find(|p| p.usage == PartitionUsage::Rootfs))
+				.context("The extlinux bootloader requires a boot or a root partition")?,
+		};
+		if partition.mountpoint.is_none() || !partition.filesystem.is_mountable() {
+			bail!(
+				"The extlinux bootloader can not write to partition {}, it has no mountpoint",
+				partition.num
+			);
+		}
+		Ok(partition)
+	}
+
+	/// Path to `extlinux.conf` within the target.
+	pub(crate) fn extlinux_config_path(&self, spec: &ExtlinuxSpec) -> Result<PathBuf> {
+		let partition = self.extlinux_partition(spec)?;
+		// Safe to unwrap, checked by extlinux_partition().
+		Ok(config_path(partition.mountpoint.as_deref().unwrap()))
+	}
+
+	/// Make sure `extlinux.conf` can be generated, see the [module documentation](crate::extlinux).
+	pub(crate) fn check_extlinux(&self, spec: &ExtlinuxSpec) -> Result<()> {
+		let partition = self.extlinux_partition(spec)?;
+		let mountpoint = partition.mountpoint.as_deref().unwrap();
+		let vars = self.template_vars(None)?;
+		let mut labels = HashSet::new();
+		for (label, _, boot) in spec.entries() {
+			if label.is_empty() || label.contains(char::is_whitespace) {
+				bail!(
+					"Invalid extlinux label '{}', it must be a single word",
+					label
+				);
+			}
+			if !labels.insert(label.clone()) {
+				bail!("Duplicate extlinux label '{}'", label);
+			}
+			if boot.fdt.is_some() && boot.fdtdir.is_some() {
+				bail!(
+					"extlinux entry {}: fdt and fdtdir can not be both set",
+					label
+				);
+			}
+			if boot.initrd.is_some() && self.initrdless {
+				bail!("extlinux entry {}: the device is initrdless, initrd can not be set", label);
+			}
+			for path in [&boot.kernel, &boot.initrd, &boot.fdt, &boot.fdtdir]
+				.into_iter()
+				.flatten()
+			{
+				partition_relative(path, mountpoint)
+					.context(format!("extlinux entry {}", label))?;
+			}
+			substitute(boot.append.as_deref().unwrap_or("{KERNEL_CMDLINE}"), &vars)
+				.context(format!(
+					"extlinux entry {}: invalid append line",
+					label
+				))?;
+		}
+		if self.dtb.is_some() || !self.dtb_overlays.is_empty() {
+			partition_relative(Path::new(self.dtb_mountpoint()?), mountpoint).context(
+				format!(
+					"The device tree blobs are not installed into partition {}",
+					partition.num
+				),
+			)?;
+		}
+		Ok(())
+	}
+}
+
+impl ImageContext<'_> {
+	/// Resolve an entry, filling in the paths from the kernel in the target.
+	fn resolve_extlinux_entry(
+		&self,
+		root: &Path,
+		mountpoint: &str,
+		kernel: Option<&KernelInfo>,
+		(label, menu_label, boot): (String, Option<String>, ExtlinuxBoot),
+		vars: &BTreeMap<String, String>,
+	) -> Result<Entry> {
+		let relative = |path: &Path| partition_relative(path, mountpoint);
+		let kernel_image = match (&boot.kernel, kernel) {
+			(Some(path), _) => path.clone(),
+			(None, Some(kernel)) => kernel.image.clone(),
+			(None, None) => {
+				bail!("No kernel is found in the target, specify it with kernel")
+			}
+		};
+		let initrd = match (&boot.initrd, kernel) {
+			(Some(path), _) => Some(path.clone()),
+			_ if self.device.initrdless => None,
+			(None, Some(kernel)) => {
+				let initrd = kernel.initrd(root);
+				if initrd.is_none() {
+					self.warn(format!(
+						"No initramfs of kernel {} is found in /boot",
+						kernel.version
+					));
+				}
+				initrd
+			}
+			(None, None) => None,
+		};
+		let (fdt, fdtdir) = match (&boot.fdt, &boot.fdtdir, &self.device.dtb) {
+			(None, None, Some(dtb)) => {
+				// Safe to unwrap, checked by check_dtb_path().
+				(
+					Some(Path::new(self.device.dtb_mountpoint()?)
+						.join(dtb.file_name().unwrap())),
+					None,
+				)
+			}
+			(None, None, None) => {
+				let dir = dtb_dirs(kernel)
+					.into_iter()
+					.map(|d| Path::new("/").join(d))
+					.filter(|d| relative(d).is_ok())
+					.find(|d| root.join(d.strip_prefix("/").unwrap()).is_dir());
+				(None, dir)
+			}
+			(fdt, fdtdir, _) => (fdt.clone(), fdtdir.clone()),
+		};
+		let fdtoverlays = self
+			.device
+			.dtb_overlays
+			.iter()
+			.map(|o| {
+				relative(
+					&Path::new(self.device.dtb_mountpoint()?)
+						.join(OVERLAYS_DIR)
+						.join(o.file_name().unwrap()),
+				)
+			})
+			.collect::<Result<Vec<_>>>()?;
+		let append =
+			substitute(boot.append.as_deref().unwrap_or("{KERNEL_CMDLINE}"), vars)?;
+		Ok(Entry {
+			label,
+			menu_label,
+			kernel: relative(&kernel_image)?,
+			initrd: initrd.as_deref().map(relative).transpose()?,
+			fdt: fdt.as_deref().map(relative).transpose()?,
+			fdtdir: fdtdir.as_deref().map(relative).transpose()?,
+			fdtoverlays,
+			append,
+		})
+	}
+
+	/// Write `extlinux.conf`, see the [module documentation](self).
+	pub(crate) fn write_extlinux(
+		&self,
+		spec: &ExtlinuxSpec,
+		root: &Path,
+		pm_data: &PartitionMapData,
+	) -> Result<()> {
+		let partition = self.device.extlinux_partition(spec)?;
+		let mountpoint = partition.mountpoint.as_deref().unwrap();
+		let kernel = self.find_kernel(root)?;
+		let vars = self.device.template_vars(Some(pm_data))?;
+		let entries = spec
+			.entries()
+			.into_iter()
+			.map(|e| {
+				let label = e.0.clone();
+				self.resolve_extlinux_entry(
+					root,
+					mountpoint,
+					kernel.as_ref(),
+					e,
+					&vars,
+				)
+				.context(format!("Unable to generate the extlinux entry {}", label))
+			})
+			.collect::<Result<Vec<_>>>()?;
+		let path = config_path(mountpoint);
+		self.info(format!(
+			"Writing {} to partition {} ...",
+			path.display(),
+			partition.num
+		));
+		let dst = root.join(path.strip_prefix("/")?);
+		if let Some(parent) = dst.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		fs::write(
+			&dst,
+			render(spec.menu_title.as_deref(), spec.timeout, &entries),
+		)
+		.context(format!("Failed to write {}", path.display()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::{load_device, TempDir};
+
+	const PARTITIONS: &str = r#"partition_map = "gpt"
+
+[[partition]]
+num = 1
+type = "esp"
+usage = "boot"
+filesystem = "fat32"
+mountpoint = "/boot"
+size = "256MiB"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+"#;
+
+	#[test]
+	fn test_partition_relative() -> Result<()> {
+		assert_eq!(
+			partition_relative(Path::new("/boot/vmlinuz-6.12.3"), "/boot")?,
+			Path::new("/vmlinuz-6.12.3")
+		);
+		assert_eq!(
+			partition_relative(Path::new("/boot/vmlinuz-6.12.3"), "/")?,
+			Path::new("/boot/vmlinuz-6.12.3")
+		);
+		assert!(partition_relative(Path::new("/usr/lib/dtbs"), "/boot").is_err());
+		assert!(partition_relative(Path::new("/bootloader/Image"), "/boot").is_err());
+		assert!(partition_relative(Path::new("boot/Image"), "/").is_err());
+		assert_eq!(config_path("/"), Path::new("/boot/extlinux/extlinux.conf"));
+		assert_eq!(
+			config_path("/boot/efi"),
+			Path::new("/boot/efi/extlinux/extlinux.conf")
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_entries() {
+		let spec = ExtlinuxSpec {
+			boot: ExtlinuxBoot {
+				fdtdir: Some(PathBuf::from("/boot/dtbs")),
+				append: Some("{KERNEL_CMDLINE}".to_owned()),
+				..Default::default()
+			},
+			entries: vec![
+				ExtlinuxEntry {
+					label: "aosc".to_owned(),
+					..Default::default()
+				},
+				ExtlinuxEntry {
+					label: "recovery".to_owned(),
+					boot: ExtlinuxBoot {
+						append: Some("{KERNEL_CMDLINE} single".to_owned()),
+						..Default::default()
+					},
+					..Default::default()
+				},
+			],
+			..Default::default()
+		};
+		let entries = spec.entries();
+		assert_eq!(entries.len(), 2);
+		assert_eq!(entries[0].2.fdtdir, Some(PathBuf::from("/boot/dtbs")));
+		assert_eq!(entries[0].2.append.as_deref(), Some("{KERNEL_CMDLINE}"));
+		assert_eq!(entries[1].2.fdtdir, Some(PathBuf::from("/boot/dtbs")));
+		assert_eq!(
+			entries[1].2.append.as_deref(),
+			Some("{KERNEL_CMDLINE} single")
+		);
+		let single = ExtlinuxSpec::default().entries();
+		assert_eq!(single.len(), 1);
+		assert_eq!(single[0].0, DEFAULT_LABEL);
+	}
+
+	#[test]
+	fn test_check_extlinux() -> Result<()> {
+		let dir = TempDir::new("extlinux")?;
+		let device = load_device(
+			&dir,
+			&format!(
+				"dtb = \"rockchip/rk3588-rock-5b.dtb\"\nkernel_cmdline = \"rw\"\n{}",
+				PARTITIONS
+			),
+		)?;
+		let entry = |label: &str, boot: ExtlinuxBoot| ExtlinuxEntry {
+			label: label.to_owned(),
+			boot,
+			..Default::default()
+		};
+		let spec = ExtlinuxSpec {
+			entries: vec![
+				entry("aosc", ExtlinuxBoot::default()),
+				entry(
+					"recovery",
+					ExtlinuxBoot {
+						kernel: Some("/boot/vmlinuz-6.12.3".into()),
+						append: Some("{KERNEL_CMDLINE} single".to_owned()),
+						..Default::default()
+					},
+				),
+			],
+			..Default::default()
+		};
+		device.check_extlinux(&spec)?;
+		assert_eq!(device.extlinux_partition(&spec)?.num, 1);
+		assert_eq!(
+			device.extlinux_config_path(&spec)?,
+			Path::new("/boot/extlinux/extlinux.conf")
+		);
+		let duplicate = ExtlinuxSpec {
+			entries: vec![
+				entry("aosc", ExtlinuxBoot::default()),
+				entry("aosc", ExtlinuxBoot::default()),
+			],
+			..Default::default()
+		};
+		assert!(device.check_extlinux(&duplicate).is_err());
+		let invalid_label = ExtlinuxSpec {
+			entries: vec![entry("aosc os", ExtlinuxBoot::default())],
+			..Default::default()
+		};
+		assert!(device.check_extlinux(&invalid_label).is_err());
+		let both = ExtlinuxSpec {
+			boot: ExtlinuxBoot {
+				fdt: Some("/boot/rk3588-rock-5b.dtb".into()),
+				fdtdir: Some("/boot/dtbs".into()),
+				..Default::default()
+			},
+			..Default::default()
+		};
+		assert!(device.check_extlinux(&both).is_err());
+		// Outside of the boot partition.
+		let outside = ExtlinuxSpec {
+			boot: ExtlinuxBoot {
+				kernel: Some("/usr/lib/vmlinuz".into()),
+				..Default::default()
+			},
+			..Default::default()
+		};
+		assert!(device.check_extlinux(&outside).is_err());
+		// The root partition contains the boot partition, and the blob installed into it.
+		let root = ExtlinuxSpec {
+			partition: Some(2),
+			..outside.clone()
+		};
+		device.check_extlinux(&root)?;
+		let missing = ExtlinuxSpec {
+			partition: Some(3),
+			..Default::default()
+		};
+		assert!(device.check_extlinux(&missing).is_err());
+
+		let initrdless = load_device(&dir, &format!("initrdless = true\n{}", PARTITIONS))?;
+		let initrd = ExtlinuxSpec {
+			boot: ExtlinuxBoot {
+				initrd: Some("/boot/initrd.img-6.12.3".into()),
+				..Default::default()
+			},
+			..Default::default()
+		};
+		assert!(initrdless.check_extlinux(&initrd).is_err());
+		device.check_extlinux(&initrd)?;
+
+		// The blob is installed into /boot/efi, out of the selected partition.
+		let nested = load_device(
+			&dir,
+			&format!(
+				"dtb = \"rockchip/rk3588-rock-5b.dtb\"\n{}",
+				PARTITIONS
+					.replace("mountpoint = \"/boot\"", "mountpoint = \"/boot/efi\"")
+					.replace(
+						"size = \"rest\"\n",
+						"size = \"1GiB\"\n\n[[partition]]\nnum = 3\ntype = \"linux\"\nusage = \"data\"\nfilesystem = \"ext4\"\nmountpoint = \"/boot\"\nsize = \"rest\"\n"
+					)
+			),
+		)?;
+		let boot = ExtlinuxSpec {
+			partition: Some(3),
+			..Default::default()
+		};
+		assert!(nested.check_extlinux(&boot).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_render() {
+		let entry = Entry {
+			label: "aosc".to_owned(),
+			menu_label: Some("AOSC OS".to_owned()),
+			kernel: PathBuf::from("/vmlinuz-6.12.3"),
+			initrd: Some(PathBuf::from("/initrd.img-6.12.3")),
+			fdt: Some(PathBuf::from("/rk3588-rock-5b.dtb")),
+			fdtdir: None,
+			fdtoverlays: vec![PathBuf::from("/overlays/rk3588-uart7-m2.dtbo")],
+			append: "root=PARTUUID=1234 rw".to_owned(),
+		};
+		let recovery = Entry {
+			label: "recovery".to_owned(),
+			menu_label: None,
+			initrd: None,
+			fdt: None,
+			fdtdir: Some(PathBuf::from("/dtbs")),
+			fdtoverlays: Vec::new(),
+			..entry.clone()
+		};
+		assert_eq!(
+			render(Some("AOSC OS"), Some(30), &[entry, recovery]),
+			"# Generated by mkrawimg.
+menu title AOSC OS
+timeout 30
+default aosc
+
+label aosc
+	menu label AOSC OS
+	linux /vmlinuz-6.12.3
+	initrd /initrd.img-6.12.3
+	fdt /rk3588-rock-5b.dtb
+	fdtoverlays /overlays/rk3588-uart7-m2.dtbo
+	append root=PARTUUID=1234 rw
+
+label recovery
+	linux /vmlinuz-6.12.3
+	fdtdir /dtbs
+	append root=PARTUUID=1234 rw
+"
+		);
+	}
+}
@@ -0,0 +1,198 @@
+//! Downloading the bootloader blobs which can not be redistributed.
+//!
+//! Some devices require vendor firmware (e.g. the FIPs of Amlogic SoCs, or
+//! proprietary DDR blobs) which can not be placed in the device registry.
+//! The `raw`, `sunxi`, `flash_offset` and `flash_partition` bootloader steps
+//! can download such a file instead of reading it from the device directory
+//! or the target, with `source`:
+//!
+//! ```toml
+//! [[bootloader]]
+//! type = "raw"
+//! source = { url = "https://example.org/fip/u-boot.bin.sd.bin", sha256 = "9f86d0...0f00a08" }
+//! offset = 512
+//! ```
+//!
+//! The SHA-256 checksum is mandatory. The files are downloaded into
+//! `downloads` of the working directory, named after their checksums, and
+//! are reused by the later builds (after verifying them again). A download
+//! goes to a temporary file first, which is only moved into place once its
+//! checksum matches, so a partially downloaded or tampered file is never
+//! used: the build fails instead.
+//!
+//! The files are downloaded by the preflight check, before anything is
+//! built, so a broken link does not fail a build in the bootloader stage.
+//! With `--offline`, nothing is downloaded, and the build fails if a file
+//! is not found in the cache.
+use std::{
+	fs::{self, File},
+	io,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use reqwest::blocking::Client;
+use serde::Deserialize;
+
+use crate::{context::ImageContext, device::check_url, report::sha256_file};
+
+/// Directory of the downloaded files, within the working directory.
+const DOWNLOADS_DIR: &str = "downloads";
+/// Timeout of a whole download, the firmware blobs are up to a few hundred MiB.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// Timeout of connecting to the server.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A file downloaded from `url`, see the [module documentation](self).
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct BlobSource {
+	/// Where the file is downloaded from.
+	pub url: String,
+	/// SHA-256 checksum of the file in hex.
+	pub sha256: Option<String>,
+}
+
+impl BlobSource {
+	/// Make sure the URL is valid and the checksum is present, returns the checksum in lowercase.
+	pub fn check(&self) -> Result<String> {
+		check_url(&self.url)?;
+		let sha256 = self.sha256.as_deref().context(format!(
+			"No sha256 checksum is specified for {}, downloads must be verified",
+			self.url
+		))?;
+		if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+			bail!(
+				"Invalid sha256 checksum '{}' of {}, expected 64 hex digits",
+				sha256,
+				self.url
+			);
+		}
+		Ok(sha256.to_ascii_lowercase())
+	}
+
+	/// Name of the file for humans, the last segment of the URL.
+	pub fn name(&self) -> &str {
+		self.url.trim_end_matches('/')
+			.rsplit('/')
+			.next()
+			.unwrap_or(&self.url)
+	}
+
+	/// Path to the file in the cache, downloads it into `cache_dir` if it is
+	/// not cached, unless `offline`.
+	pub fn fetch(&self, cache_dir: &Path, offline: bool) -> Result<PathBuf> {
+		let sha256 = self.check()?;
+		let path = cache_dir.join(&sha256);
+		if path.is_file() {
+			if sha256_file(&path)? == sha256 {
+				return Ok(path);
+			}
+			// The cache is never written without verification, someone else touched it.
+			fs::remove_file(&path)?;
+		}
+		if offline {
+			bail!(
+				"{} is not downloaded yet, and downloads are forbidden by --offline",
+				self.url
+			);
+		}
+		fs::create_dir_all(cache_dir)?;
+		let part = cache_dir.join(format!("{}.{:08x}.part", sha256, rand::random::<u32>()));
+		let result = download(&self.url, &part).and_then(|_| {
+			let actual = sha256_file(&part)?;
+			if actual != sha256 {
+				bail!(
+					"Checksum mismatch of {}: expected {}, got {}",
+					self.url,
+					sha256,
+					actual
+				);
+			}
+			fs::rename(&part, &path)?;
+			Ok(())
+		});
+		if let Err(e) = result {
+			fs::remove_file(&part).ok();
+			return Err(e.context(format!("Failed to download {}", self.url)));
+		}
+		Ok(path)
+	}
+}
+
+/// Download `url` into `dst`.
+fn download(url: &str, dst: &Path) -> Result<()> {
+	let client = Client::builder()
+		.user_agent(concat!("mkrawimg/", env!("CARGO_PKG_VERSION")))
+		.connect_timeout(CONNECT_TIMEOUT)
+		.timeout(DOWNLOAD_TIMEOUT)
+		.build()?;
+	let mut response = client.get(url).send()?.error_for_status()?;
+	let mut file = File::create(dst)?;
+	io::copy(&mut response, &mut file)?;
+	file.sync_all()?;
+	Ok(())
+}
+
+impl ImageContext<'_> {
+	/// Path to the downloaded file, see the [module documentation](self).
+	pub(crate) fn fetch_blob(&self, source: &BlobSource) -> Result<PathBuf> {
+		let cache_dir = self.workdir.join(DOWNLOADS_DIR);
+		let cached = cache_dir.join(source.check()?);
+		if !cached.is_file() && !self.offline {
+			self.info(format!("Downloading {} ...", source.url));
+		}
+		source.fetch(&cache_dir, self.offline)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	const SHA256_FOO: &str = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+
+	fn source(url: &str, sha256: Option<&str>) -> BlobSource {
+		BlobSource {
+			url: url.to_owned(),
+			sha256: sha256.map(str::to_owned),
+		}
+	}
+
+	#[test]
+	fn test_check() -> Result<()> {
+		let url = "https://example.org/fip/u-boot.bin.sd.bin";
+		assert_eq!(source(url, Some(SHA256_FOO)).check()?, SHA256_FOO);
+		assert_eq!(
+			source(url, Some(&SHA256_FOO.to_ascii_uppercase())).check()?,
+			SHA256_FOO
+		);
+		assert_eq!(source(url, Some(SHA256_FOO)).name(), "u-boot.bin.sd.bin");
+		assert!(source(url, None).check().is_err());
+		assert!(source(url, Some(&SHA256_FOO[1..])).check().is_err());
+		assert!(source(url, Some(&SHA256_FOO.replace('a', "g")))
+			.check()
+			.is_err());
+		assert!(source("u-boot.bin", Some(SHA256_FOO)).check().is_err());
+		assert!(source("file:///etc/passwd", Some(SHA256_FOO))
+			.check()
+			.is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_fetch_cached() -> Result<()> {
+		let dir = TempDir::new("fetch")?;
+		let blob = source("https://example.org/blob.bin", Some(SHA256_FOO));
+		// Offline, and not cached.
+		assert!(blob.fetch(&dir, true).is_err());
+		fs::write(dir.join(SHA256_FOO), "foo")?;
+		assert_eq!(blob.fetch(&dir, true)?, dir.join(SHA256_FOO));
+		// A corrupted cache is never used.
+		fs::write(dir.join(SHA256_FOO), "bar")?;
+		assert!(blob.fetch(&dir, true).is_err());
+		assert!(!dir.join(SHA256_FOO).exists());
+		Ok(())
+	}
+}
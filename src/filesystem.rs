@@ -1,12 +1,15 @@
 use anyhow::{anyhow, bail, Context, Ok, Result};
 use serde::{Deserialize, Serialize};
-use std::{path::Path, process::Command};
+use std::{fmt::Display, path::Path, process::Command, str::FromStr};
+use uuid::Uuid;
 
 use crate::{
 	context::ImageContext,
 	device::PartitionMapData,
-	partition::PartitionUsage,
-	utils::{cmd_run_check_status, get_fsuuid},
+	encryption::{close_mapping, mapping_name},
+	partition::PartitionSpec,
+	slots::Slot,
+	utils::{cmd_run_check_status, get_fslabel, get_fsuuid},
 };
 
 /// Speifies which filesystem to be formatted to a partition.
@@ -23,17 +26,49 @@ pub enum FilesystemType {
 	Fat16,
 	/// FAT32.
 	Fat32,
+	/// exFAT, for the data partitions shared with other operating systems.
+	Exfat,
+	/// Read-only EROFS, built from the populated root filesystem.
+	Erofs,
+	/// Swap space, initialized by mkswap.
+	Swap,
 	/// Not to be formatted.
 	None,
 }
 
 impl FilesystemType {
+	/// Roughly the smallest size of the filesystem in MiB that mkfs accepts.
+	pub fn min_size(&self) -> u64 {
+		match self {
+			Self::Ext4 | Self::Fat16 | Self::Exfat => 16,
+			Self::Fat32 => 64,
+			Self::Btrfs => 128,
+			Self::Xfs => 320,
+			Self::Erofs | Self::Swap | Self::None => 1,
+		}
+	}
+
+	/// Whether the filesystem can be mounted, i.e. it is neither a swap space nor unformatted.
+	pub fn is_mountable(&self) -> bool {
+		!matches!(self, Self::Swap | Self::None)
+	}
+
 	/// Check validaty of the filesystem parameters.
 	pub fn check<S: AsRef<str>>(&self, label: &Option<S>) -> Result<()> {
 		let label = label.as_ref();
 		// Check for validity of the filesystem labels.
 		if let Some(l) = label {
 			let l = l.as_ref();
+			if l.is_empty() {
+				bail!("Filesystem labels can not be empty.");
+			}
+			let limit =
+				|name: &str, max: usize| {
+					if l.len() > max {
+						bail!("{} labels can not be longer than {} bytes.", name, max);
+					}
+					Ok(())
+				};
 			match self {
 				Self::Fat16 | Self::Fat32 => {
 					if !l.is_ascii() {
@@ -43,54 +78,191 @@ impl FilesystemType {
 						bail!("FAT Volume labels can not be longer than 11 characters.");
 					}
 				}
-				_ => {
-					if l.as_bytes().len() > 63 {
-						bail!("Filesystem labels are limited to up to 63 bytes.");
+				Self::Exfat => {
+					if l.encode_utf16().count() > 11 {
+						bail!("exFAT volume labels can not be longer than 11 UTF-16 characters.");
 					}
 				}
+				Self::Ext4 => limit("ext4", 16)?,
+				Self::Xfs => limit("XFS", 12)?,
+				Self::Btrfs => limit("Btrfs", 255)?,
+				Self::Erofs => limit("EROFS", 16)?,
+				Self::Swap => limit("Swap", 16)?,
+				Self::None => {
+					bail!("A partition not to be formatted can not have a filesystem label.")
+				}
 			};
 		}
 		Ok(())
 	}
 
+	/// The label applied to the filesystem, FAT volume labels are uppercase.
+	pub fn applied_label(&self, label: &str) -> String {
+		match self {
+			Self::Fat16 | Self::Fat32 => label.to_ascii_uppercase(),
+			_ => label.to_owned(),
+		}
+	}
+
+	/// Check the pinned UUID of the filesystem, returns it as accepted by mkfs.
+	///
+	/// FAT and exFAT filesystems have a 32-bit volume ID instead, written as
+	/// `XXXX-XXXX` or 8 hexadecimal digits.
+	pub fn check_uuid(&self, uuid: &str) -> Result<String> {
+		match self {
+			Self::Fat16 | Self::Fat32 | Self::Exfat => {
+				let id = match uuid.split_once('-') {
+					Some((high, low)) if high.len() == 4 && low.len() == 4 => {
+						format!("{}{}", high, low)
+					}
+					_ => uuid.to_owned(),
+				};
+				if id.len() != 8 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+					bail!("Invalid volume ID '{}', expected XXXX-XXXX", uuid);
+				}
+				Ok(id.to_uppercase())
+			}
+			Self::None => bail!(
+				"A partition not to be formatted can not have a filesystem UUID"
+			),
+			_ => Uuid::parse_str(uuid)
+				.map(|u| u.to_string())
+				.context(format!("Invalid filesystem UUID '{}'", uuid)),
+		}
+	}
+
+	/// The mkfs options setting the label of the filesystem.
+	fn label_options(&self) -> &'static [&'static str] {
+		match self {
+			Self::Ext4 | Self::Xfs => &["-L"],
+			Self::Btrfs | Self::Swap => &["-L", "--label"],
+			Self::Exfat => &["-L", "--volume-label"],
+			Self::Fat16 | Self::Fat32 => &["-n"],
+			Self::Erofs | Self::None => &[],
+		}
+	}
+
+	/// The mkfs options setting the UUID of the filesystem.
+	///
+	/// The UUID of XFS is set with the `uuid` suboption of `-m`, and the volume
+	/// ID of exFAT is set by tune.exfat, after the mkfs options are applied.
+	fn uuid_options(&self) -> &'static [&'static str] {
+		match self {
+			Self::Ext4 => &["-U"],
+			Self::Btrfs | Self::Swap => &["-U", "--uuid"],
+			Self::Fat16 | Self::Fat32 => &["-i"],
+			Self::Xfs | Self::Exfat | Self::Erofs | Self::None => &[],
+		}
+	}
+
+	/// Make sure the extra mkfs options do not set the label or the UUID also set by mkrawimg.
+	pub fn check_mkfs_options(
+		&self,
+		options: &[String],
+		label: bool,
+		uuid: bool,
+	) -> Result<()> {
+		let Some(program) = self.mkfs_program() else {
+			bail!(
+				"{:?} is not created by mkfs, it does not accept mkfs_options",
+				self
+			);
+		};
+		// -L, -Lxxx, --label and --label=xxx all set the label.
+		let sets = |option: &str, flag: &str| {
+			option == flag
+				|| if flag.starts_with("--") {
+					option.starts_with(&format!("{}=", flag))
+				} else {
+					option.starts_with(flag)
+				}
+		};
+		for (idx, option) in options.iter().enumerate() {
+			if option == "--" {
+				bail!("mkfs option '--' ends the options of {}, it can not be used", program);
+			}
+			if label && self.label_options().iter().any(|f| sets(option, f)) {
+				bail!(
+					"mkfs option '{}' conflicts with the label of the partition, which is also set by mkrawimg",
+					option
+				);
+			}
+			let sets_xfs_uuid = *self == Self::Xfs
+				&& match option.strip_prefix("-m") {
+					Some("") => options.get(idx + 1).map(String::as_str),
+					value => value,
+				}
+				.is_some_and(|value| {
+					value.split(',').any(|o| o.starts_with("uuid="))
+				});
+			if uuid && (sets_xfs_uuid
+				|| self.uuid_options().iter().any(|f| sets(option, f)))
+			{
+				bail!(
+					"mkfs option '{}' conflicts with the filesystem UUID of the partition, which is also set by mkrawimg",
+					option
+				);
+			}
+		}
+		Ok(())
+	}
+
 	pub fn get_os_fstype(&self) -> Result<&'static str> {
 		match self {
 			FilesystemType::Ext4 => Ok("ext4"),
 			FilesystemType::Xfs => Ok("xfs"),
 			FilesystemType::Btrfs => Ok("btrfs"),
 			FilesystemType::Fat16 | FilesystemType::Fat32 => Ok("vfat"),
+			FilesystemType::Exfat => Ok("exfat"),
+			FilesystemType::Erofs => Ok("erofs"),
+			FilesystemType::Swap => Ok("swap"),
 			FilesystemType::None => {
 				Err(anyhow!("It is instructed to not being formatted"))
 			}
 		}
 	}
 
+	/// The program creating the filesystem, `None` if not to be formatted.
+	///
+	/// EROFS is not formatted, but built after the root filesystem is populated.
+	pub fn mkfs_program(&self) -> Option<&'static str> {
+		match self {
+			Self::Ext4 => Some("mkfs.ext4"),
+			Self::Btrfs => Some("mkfs.btrfs"),
+			Self::Xfs => Some("mkfs.xfs"),
+			Self::Fat16 | Self::Fat32 => Some("mkfs.vfat"),
+			Self::Exfat => Some("mkfs.exfat"),
+			Self::Swap => Some("mkswap"),
+			Self::Erofs | Self::None => None,
+		}
+	}
+
 	pub fn get_mkfs_cmdline(
 		&self,
 		path: &dyn AsRef<Path>,
 		label: Option<String>,
+		uuid: Option<&str>,
+		options: &[String],
 	) -> Result<Command> {
 		if self == &Self::None {
 			bail!("Instructed to not being formatted");
 		}
+		if self == &Self::Erofs {
+			bail!("EROFS is built from the populated root filesystem, it can not be formatted");
+		}
 		let path = path.as_ref();
 		self.check(&label)?;
+		self.check_mkfs_options(options, label.is_some(), uuid.is_some())?;
 		// Decide which command to use.
-		let mut mkfs_command = Command::new(match self {
-			Self::Ext4 => "mkfs.ext4",
-			Self::Btrfs => "mkfs.btrfs",
-			Self::Xfs => "mkfs.xfs",
-			Self::Fat16 | Self::Fat32 => "mkfs.vfat",
-			_ => {
-				unreachable!();
-			}
-		});
+		let mut mkfs_command = Command::new(self.mkfs_program().unwrap());
 
 		if let Some(l) = label {
 			mkfs_command.arg(match self {
 				Self::Ext4 => "-L",
 				Self::Xfs => "-L",
 				Self::Btrfs => "-L",
+				Self::Swap => "-L",
+				Self::Exfat => "-L",
 				Self::Fat16 | Self::Fat32 => "-n",
 				_ => {
 					unreachable!()
@@ -98,19 +270,152 @@ impl FilesystemType {
 			});
 			mkfs_command.arg(l);
 		}
+		if let Some(uuid) = uuid {
+			let uuid = self.check_uuid(uuid)?;
+			match self {
+				Self::Ext4 | Self::Btrfs | Self::Swap => {
+					mkfs_command.args(["-U", &uuid])
+				}
+				Self::Xfs => mkfs_command.args(["-m", &format!("uuid={}", uuid)]),
+				Self::Fat16 | Self::Fat32 => mkfs_command.args(["-i", &uuid]),
+				// mkfs.exfat can not set the volume serial, see [`FilesystemType::format`].
+				Self::Exfat => &mut mkfs_command,
+				_ => unreachable!(),
+			};
+		}
+		mkfs_command.args(options);
 		mkfs_command.arg("--");
 		mkfs_command.arg(path);
 		Ok(mkfs_command)
 	}
 
-	pub fn format(&self, path: &dyn AsRef<Path>, label: Option<String>) -> Result<()> {
+	pub fn format(
+		&self,
+		path: &dyn AsRef<Path>,
+		label: Option<String>,
+		uuid: Option<&str>,
+		options: &[String],
+	) -> Result<()> {
 		let dev = path.as_ref();
-		let mut cmd = self.get_mkfs_cmdline(&dev, label)?;
-		cmd_run_check_status(&mut cmd)
+		let mut cmd = self.get_mkfs_cmdline(&dev, label, uuid, options)?;
+		cmd_run_check_status(&mut cmd)?;
+		if let (Self::Exfat, Some(uuid)) = (self, uuid) {
+			let serial = format!("0x{}", self.check_uuid(uuid)?);
+			let mut cmd = Command::new("tune.exfat");
+			cmd.args(["-I", &serial, "--"]).arg(dev);
+			cmd_run_check_status(&mut cmd)?;
+		}
+		Ok(())
+	}
+
+	/// The default mount options in fstab, `uid` and `gid` are the owner of the
+	/// files on the filesystems without permissions.
+	pub fn default_mount_opts(&self, uid: Option<u32>, gid: Option<u32>) -> String {
+		match self {
+			Self::Swap => "sw".to_owned(),
+			Self::Erofs => "ro".to_owned(),
+			Self::Exfat => {
+				let mut opts = vec!["defaults".to_owned()];
+				opts.extend(uid.map(|uid| format!("uid={}", uid)));
+				opts.extend(gid.map(|gid| format!("gid={}", gid)));
+				opts.push("umask=0022".to_owned());
+				opts.join(",")
+			}
+			_ => "defaults".to_owned(),
+		}
+	}
+}
+
+/// Identifier of a filesystem, as reported by blkid.
+///
+/// FAT and exFAT filesystems have a 32-bit volume serial instead of a UUID,
+/// which is reported as `XXXX-XXXX`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum FsUuid {
+	Uuid(Uuid),
+	VolumeId(String),
+}
+
+impl FromStr for FsUuid {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		if let std::result::Result::Ok(uuid) = Uuid::parse_str(s) {
+			return Ok(Self::Uuid(uuid));
+		}
+		match s.split_once('-') {
+			Some((high, low))
+				if [high, low].iter().all(|h| {
+					h.len() == 4 && h.chars().all(|c| c.is_ascii_hexdigit())
+				}) =>
+			{
+				Ok(Self::VolumeId(s.to_uppercase()))
+			}
+			_ => bail!("Invalid filesystem UUID or volume ID '{}'", s),
+		}
+	}
+}
+
+impl TryFrom<String> for FsUuid {
+	type Error = anyhow::Error;
+
+	fn try_from(value: String) -> Result<Self> {
+		value.parse()
+	}
+}
+
+impl From<FsUuid> for String {
+	fn from(value: FsUuid) -> Self {
+		value.to_string()
+	}
+}
+
+impl Display for FsUuid {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Uuid(uuid) => uuid.fmt(f),
+			Self::VolumeId(id) => f.write_str(id),
+		}
 	}
 }
 
 impl ImageContext<'_> {
+	/// The extra mkfs options of the partition.
+	///
+	/// They are meant for the declared filesystem, and dropped if `--fstype`
+	/// replaces it.
+	pub(crate) fn mkfs_options<'a>(&self, partition: &'a PartitionSpec) -> &'a [String] {
+		match &partition.mkfs_options {
+			Some(options)
+				if self.effective_fstype(partition) == partition.filesystem =>
+			{
+				options
+			}
+			_ => &[],
+		}
+	}
+
+	/// Make sure the extra mkfs options do not set the UUIDs derived from `--seed`.
+	///
+	/// The pinned UUIDs are already checked with the device.
+	pub(crate) fn check_mkfs_options(&self) -> Result<()> {
+		for partition in &self.device.partitions {
+			let options = self.mkfs_options(partition);
+			if options.is_empty() {
+				continue;
+			}
+			let fs = self.effective_fstype(partition);
+			fs.check_mkfs_options(
+				options,
+				partition.fs_label.is_some(),
+				self.filesystem_uuid(partition, &fs).is_some(),
+			)
+			.context(format!("Partition {} of {}", partition.num, self.device.id))?;
+		}
+		Ok(())
+	}
+
 	pub fn format_partitions(
 		&self,
 		loopdev: &dyn AsRef<Path>,
@@ -121,30 +426,252 @@ impl ImageContext<'_> {
 			if partition.filesystem == FilesystemType::None {
 				continue;
 			}
-			let filesystem = if partition.usage == PartitionUsage::Rootfs {
-				if let Some(fstype) = self.override_rootfs_fstype {
-					fstype
-				} else {
-					&partition.filesystem
-				}
-			} else {
-				&partition.filesystem
-			};
+			let filesystem = &self.effective_fstype(partition);
 			self.info(format!(
 				"Formatting partition {} ({:?})",
 				partition.num, filesystem
 			));
 			let num = partition.num;
-			let part_path = format!("{}p{}", loopdev.to_string_lossy(), num);
-			let label = &partition.label;
-			filesystem.format(&part_path, label.to_owned())?;
-			let fsuuid = get_fsuuid(&part_path)?;
+			let part_path = self.device.partition_path(loopdev, num);
+			let label = partition
+				.fs_label
+				.as_deref()
+				.map(|l| filesystem.applied_label(l));
+			let fs_uuid = self.filesystem_uuid(partition, filesystem);
+			let mkfs_options = self.mkfs_options(partition);
+			if !mkfs_options.is_empty() {
+				self.info(format!(
+					"Extra mkfs options of partition {}: {}",
+					num,
+					mkfs_options.join(" ")
+				));
+			}
+			if *filesystem == FilesystemType::Erofs && partition.slot == Some(Slot::B) {
+				// Left empty, an EROFS filesystem is only built for the root.
+				continue;
+			}
+			// The UUID and the label are read back from the filesystem.
+			let format = |dev: &dyn AsRef<Path>| {
+				if *filesystem == FilesystemType::Erofs {
+					// Built after the root is populated, see [`crate::erofs`].
+					return Ok((
+						FsUuid::Uuid(self.erofs_uuid(partition)?),
+						label.clone(),
+					));
+				}
+				filesystem.format(
+					dev,
+					label.clone(),
+					fs_uuid.as_deref(),
+					mkfs_options,
+				)?;
+				self.tune_ext4(partition, dev.as_ref())?;
+				self.create_subvolumes(partition, dev.as_ref())?;
+				Ok((get_fsuuid(dev)?, get_fslabel(dev)?))
+			};
+			let ((fsuuid, fslabel), luks_uuid) = match &partition.encryption {
+				Some(encryption) => {
+					self.info(format!(
+						"Encrypting partition {} with LUKS2",
+						num
+					));
+					let luks_uuid = self.encrypt_partition(
+						encryption,
+						&part_path,
+						self.container_uuid(partition).as_deref(),
+					)?;
+					let mapping = self.open_mapping(encryption, &part_path)?;
+					let result = format(&mapping);
+					// Closed even if the formatting fails.
+					let closed = close_mapping(&mapping_name(&part_path));
+					let fsuuid = result?;
+					closed?;
+					(fsuuid, Some(luks_uuid))
+				}
+				None => (format(&part_path)?, None),
+			};
 			let part_data = pm_data.data.get_mut(&num).context(format!(
 				"Unable to get partition data for partition {}",
 				num
 			))?;
 			part_data.fs_uuid = Some(fsuuid);
+			part_data.fs_label = fslabel;
+			part_data.luks_uuid = luks_uuid;
 		}
+		self.write_partition_contents(loopdev, None)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::{load_device, test_context, TempDir};
+
+	#[test]
+	fn test_check_uuid() -> Result<()> {
+		let uuid = "0D9F8C7B-6A5E-4D3C-8B2A-1F0E9D8C7B6A";
+		assert_eq!(
+			FilesystemType::Ext4.check_uuid(uuid)?,
+			"0d9f8c7b-6a5e-4d3c-8b2a-1f0e9d8c7b6a"
+		);
+		assert!(FilesystemType::Btrfs.check_uuid("0D9F8C7B").is_err());
+		assert_eq!(FilesystemType::Fat32.check_uuid("1a2b-3C4D")?, "1A2B3C4D");
+		assert_eq!(FilesystemType::Fat16.check_uuid("1a2b3c4d")?, "1A2B3C4D");
+		assert!(FilesystemType::Fat32.check_uuid(uuid).is_err());
+		assert!(FilesystemType::Fat32.check_uuid("1a2b-3c4g").is_err());
+		assert!(FilesystemType::None.check_uuid("1a2b3c4d").is_err());
+		assert_eq!(FilesystemType::Exfat.check_uuid("1a2b-3c4d")?, "1A2B3C4D");
+		Ok(())
+	}
+
+	#[test]
+	fn test_fs_uuid() -> Result<()> {
+		let uuid = "0d9f8c7b-6a5e-4d3c-8b2a-1f0e9d8c7b6a";
+		assert_eq!(
+			uuid.parse::<FsUuid>()?,
+			FsUuid::Uuid(Uuid::parse_str(uuid)?)
+		);
+		let serial = "1a2b-3C4D".parse::<FsUuid>()?;
+		assert_eq!(serial, FsUuid::VolumeId("1A2B-3C4D".into()));
+		assert_eq!(serial.to_string(), "1A2B-3C4D");
+		assert!("1a2b3c4d".parse::<FsUuid>().is_err());
+		assert!("1a2b-3c4g".parse::<FsUuid>().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_default_mount_opts() {
+		assert_eq!(
+			FilesystemType::Exfat.default_mount_opts(Some(1000), Some(1000)),
+			"defaults,uid=1000,gid=1000,umask=0022"
+		);
+		assert_eq!(
+			FilesystemType::Exfat.default_mount_opts(None, None),
+			"defaults,umask=0022"
+		);
+		assert_eq!(
+			FilesystemType::Ext4.default_mount_opts(Some(1000), None),
+			"defaults"
+		);
+		assert_eq!(FilesystemType::Swap.default_mount_opts(None, None), "sw");
+	}
+
+	#[test]
+	fn test_check_label() {
+		let check = |fs: FilesystemType, label: &str| fs.check(&Some(label));
+		assert!(check(FilesystemType::Fat32, "Boot").is_ok());
+		assert!(check(FilesystemType::Fat32, "Boot Partition").is_err());
+		assert!(check(FilesystemType::Ext4, "AOSC OS").is_ok());
+		assert!(check(FilesystemType::Ext4, "AOSC OS Desktop 1").is_err());
+		assert!(check(FilesystemType::Xfs, "AOSC OS Home").is_ok());
+		assert!(check(FilesystemType::Xfs, "AOSC OS Homes").is_err());
+		assert!(check(FilesystemType::Btrfs, &"a".repeat(255)).is_ok());
+		assert!(check(FilesystemType::Btrfs, &"a".repeat(256)).is_err());
+		assert!(check(FilesystemType::None, "Data").is_err());
+		assert_eq!(FilesystemType::Fat32.applied_label("Boot"), "BOOT");
+		assert_eq!(FilesystemType::Ext4.applied_label("Boot"), "Boot");
+	}
+
+	#[test]
+	fn test_check_mkfs_options() {
+		let options = |o: &[&str]| o.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+		let ext4 = FilesystemType::Ext4;
+		assert!(ext4
+			.check_mkfs_options(&options(&["-O", "^has_journal"]), true, true)
+			.is_ok());
+		assert!(ext4
+			.check_mkfs_options(&options(&["-L", "root"]), false, false)
+			.is_ok());
+		assert!(ext4
+			.check_mkfs_options(&options(&["-Lroot"]), true, false)
+			.is_err());
+		assert!(ext4
+			.check_mkfs_options(&options(&["-U", "random"]), false, true)
+			.is_err());
+		assert!(ext4
+			.check_mkfs_options(&options(&["--", "-q"]), false, false)
+			.is_err());
+		let btrfs = FilesystemType::Btrfs;
+		assert!(btrfs
+			.check_mkfs_options(&options(&["--label=root"]), true, false)
+			.is_err());
+		assert!(btrfs
+			.check_mkfs_options(&options(&["--labels"]), true, false)
+			.is_ok());
+		let xfs = FilesystemType::Xfs;
+		assert!(xfs
+			.check_mkfs_options(&options(&["-m", "bigtime=1"]), false, true)
+			.is_ok());
+		assert!(xfs
+			.check_mkfs_options(&options(&["-m", "bigtime=1,uuid=0"]), false, true)
+			.is_err());
+		assert!(xfs
+			.check_mkfs_options(&options(&["-muuid=0"]), false, true)
+			.is_err());
+		assert!(FilesystemType::Fat32
+			.check_mkfs_options(&options(&["-S", "4096"]), true, true)
+			.is_ok());
+		assert!(FilesystemType::Erofs
+			.check_mkfs_options(&options(&["-x"]), false, false)
+			.is_err());
+	}
+
+	#[test]
+	fn test_mkfs_uuid_args() -> Result<()> {
+		let args = |fs: FilesystemType, uuid: &str| -> Result<Vec<String>> {
+			Ok(fs.get_mkfs_cmdline(&"/dev/loop0p1", None, Some(uuid), &[])?
+				.get_args()
+				.map(|a| a.to_string_lossy().into_owned())
+				.collect())
+		};
+		let uuid = "0d9f8c7b-6a5e-4d3c-8b2a-1f0e9d8c7b6a";
+		assert_eq!(
+			args(FilesystemType::Ext4, uuid)?,
+			["-U", uuid, "--", "/dev/loop0p1"]
+		);
+		assert_eq!(
+			args(FilesystemType::Xfs, uuid)?,
+			["-m", &format!("uuid={}", uuid), "--", "/dev/loop0p1"]
+		);
+		assert_eq!(
+			args(FilesystemType::Fat32, "1a2b-3c4d")?,
+			["-i", "1A2B3C4D", "--", "/dev/loop0p1"]
+		);
+		assert_eq!(
+			args(FilesystemType::Exfat, "1a2b-3c4d")?,
+			["--", "/dev/loop0p1"]
+		);
+		assert_eq!(
+			args(FilesystemType::Swap, uuid)?,
+			["-U", uuid, "--", "/dev/loop0p1"]
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_seeded_mkfs_options() -> Result<()> {
+		let dir = TempDir::new("mkfs-options")?;
+		let device = load_device(
+			&dir,
+			r#"partition_map = "gpt"
+
+[[partition]]
+num = 1
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+mkfs_options = ["-U", "time"]
+"#,
+		)?;
+		// Nothing sets the UUID without a seed.
+		device.check()?;
+		let mut context = test_context(&device, &dir);
+		context.check_mkfs_options()?;
+		context.seed = Some("fleet");
+		let e = format!("{:#}", context.check_mkfs_options().unwrap_err());
+		assert!(e.contains("conflicts with the filesystem UUID"), "{}", e);
 		Ok(())
 	}
 }
@@ -0,0 +1,318 @@
+//! Checking the filesystems of the built images.
+//!
+//! An interrupted copy can leave a filesystem subtly corrupt, which goes
+//! unnoticed until the device refuses to boot. Once the image is populated
+//! and the filesystems are unmounted, but before the loop device is
+//! detached, each filesystem is checked without being repaired:
+//!
+//! | Filesystem | Checker                  |
+//! |------------|--------------------------|
+//! | ext4       | `e2fsck -fn`             |
+//! | xfs        | `xfs_repair -n`          |
+//! | btrfs      | `btrfs check --readonly` |
+//! | fat16/32   | `fsck.vfat -n`           |
+//! | exfat      | `fsck.exfat -n`          |
+//! | erofs      | `fsck.erofs`             |
+//!
+//! The build fails if any of them reports errors, after every filesystem is
+//! checked. The result of each partition is shown in the partition summary
+//! at the end of the build. Swap spaces and the partitions without a
+//! filesystem are not checked.
+//!
+//! Checking a large XFS filesystem takes a while, `--no-fsck` skips the
+//! checks entirely.
+use std::{fmt::Display, path::Path, process::Command};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	context::ImageContext, device::PartitionMapData, filesystem::FilesystemType, runner,
+	slots::Slot,
+};
+
+/// Lines of the output of a failed check shown in the log.
+const FSCK_OUTPUT_LINES: usize = 20;
+
+/// Result of the check of a filesystem.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FsckStatus {
+	Clean,
+	Errors,
+	/// Not checked, because of `--no-fsck`.
+	Skipped,
+}
+
+impl Display for FsckStatus {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			Self::Clean => "clean",
+			Self::Errors => "errors",
+			Self::Skipped => "skipped",
+		})
+	}
+}
+
+impl FilesystemType {
+	/// The program checking the filesystem, `None` if it is not checked.
+	pub fn fsck_program(&self) -> Option<&'static str> {
+		match self {
+			Self::Ext4 => Some("e2fsck"),
+			Self::Xfs => Some("xfs_repair"),
+			Self::Btrfs => Some("btrfs"),
+			Self::Fat16 | Self::Fat32 => Some("fsck.vfat"),
+			Self::Exfat => Some("fsck.exfat"),
+			Self::Erofs => Some("fsck.erofs"),
+			Self::Swap | Self::None => None,
+		}
+	}
+
+	/// The command checking the filesystem without repairing it.
+	pub fn get_fsck_cmdline(&self, path: &dyn AsRef<Path>) -> Option<Command> {
+		let mut cmd = Command::new(self.fsck_program()?);
+		match self {
+			Self::Ext4 => cmd.arg("-fn"),
+			Self::Btrfs => cmd.args(["check", "--readonly"]),
+			Self::Xfs | Self::Fat16 | Self::Fat32 | Self::Exfat => cmd.arg("-n"),
+			_ => &mut cmd,
+		};
+		cmd.arg(path.as_ref());
+		Some(cmd)
+	}
+}
+
+/// The last lines of the output of the checker.
+fn output_tail(stdout: &[u8], stderr: &[u8]) -> String {
+	let output = [stdout, stderr]
+		.iter()
+		.map(|o| String::from_utf8_lossy(o).trim().to_owned())
+		.filter(|o| !o.is_empty())
+		.collect::<Vec<_>>()
+		.join("\n");
+	let lines = output.lines().collect::<Vec<_>>();
+	lines[lines.len().saturating_sub(FSCK_OUTPUT_LINES)..].join("\n")
+}
+
+impl ImageContext<'_> {
+	/// Check the filesystems of the image, see the [module documentation](self).
+	///
+	/// The encrypted partitions must be unlocked.
+	pub(crate) fn check_filesystems(
+		&self,
+		loop_dev: &Path,
+		pm_data: &mut PartitionMapData,
+	) -> Result<()> {
+		let mut failed = Vec::new();
+		for partition in &self.device.partitions {
+			let num = partition.num;
+			let filesystem = self.effective_fstype(partition);
+			if filesystem == FilesystemType::Erofs && partition.slot == Some(Slot::B) {
+				// Left empty.
+				continue;
+			}
+			let dev = self.device.filesystem_path(loop_dev, partition);
+			let Some(mut cmd) = filesystem.get_fsck_cmdline(&dev) else {
+				continue;
+			};
+			let status = if !self.fsck {
+				FsckStatus::Skipped
+			} else {
+				self.info(format!(
+					"Checking the filesystem of partition {} ...",
+					num
+				));
+				let output = runner::output(&mut cmd)?;
+				if output.status.success() {
+					FsckStatus::Clean
+				} else {
+					self.warn(format!(
+						"{} found errors on partition {}:\n{}",
+						filesystem.fsck_program().unwrap_or_default(),
+						num,
+						output_tail(&output.stdout, &output.stderr)
+					));
+					failed.push(num.to_string());
+					FsckStatus::Errors
+				}
+			};
+			let part_data = pm_data.data.get_mut(&num).context(format!(
+				"Unable to get partition data for partition {}",
+				num
+			))?;
+			part_data.fsck = Some(status);
+		}
+		if !failed.is_empty() {
+			bail!(
+				"The filesystems of partition {} have errors, the image is corrupt",
+				failed.join(", ")
+			);
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::HashMap, fs};
+
+	use super::*;
+	use crate::{
+		device::PartitionData,
+		runner::RunnerMode,
+		testutil::{load_device, test_context, TempDir},
+	};
+
+	const PARTITIONS: &str = r#"partition_map = "gpt"
+
+[[partition]]
+num = 1
+type = "efi"
+usage = "boot"
+filesystem = "fat32"
+mountpoint = "/boot/efi"
+size = "256MiB"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "2GiB"
+
+[[partition]]
+num = 3
+type = "swap"
+usage = "swap"
+filesystem = "swap"
+size = "1GiB"
+"#;
+
+	/// Record the checker of partition `num` exiting with `code`.
+	fn record(dir: &Path, num: u32, program: &str, args: &[&str], code: i32) -> Result<()> {
+		let args = args
+			.iter()
+			.chain(&[format!("/dev/loop0p{}", num).as_str()])
+			.map(|a| format!("\"{}\"", a))
+			.collect::<Vec<_>>()
+			.join(", ");
+		fs::write(
+			dir.join(format!("{:05}.json", num)),
+			format!(
+				r#"{{"seq": {}, "program": "{}", "args": [{}], "env": {{}}, "cwd": null,
+"code": {}, "signal": null, "stdout": "{:05}.stdout", "stderr": null, "duration_ms": 1}}"#,
+				num, program, args, code, num
+			),
+		)?;
+		fs::write(
+			dir.join(format!("{:05}.stdout", num)),
+			format!("{} on partition {}\n", program, num),
+		)?;
+		Ok(())
+	}
+
+	/// The partitions of [`PARTITIONS`], not checked yet.
+	fn partition_data() -> PartitionMapData {
+		let data = (1..=3)
+			.map(|num| {
+				let data = PartitionData {
+					num,
+					part_uuid: format!("partuuid-{}", num),
+					fs_uuid: None,
+					fs_label: None,
+					luks_uuid: None,
+					fsck: None,
+				};
+				(num, data)
+			})
+			.collect::<HashMap<_, _>>();
+		PartitionMapData {
+			uuid: "disk".to_owned(),
+			data,
+		}
+	}
+
+	#[test]
+	fn test_fsck_cmdline() {
+		let args = |fs: FilesystemType| {
+			fs.get_fsck_cmdline(&"/dev/loop0p1").map(|cmd| {
+				std::iter::once(cmd.get_program())
+					.chain(cmd.get_args())
+					.map(|a| a.to_string_lossy().into_owned())
+					.collect::<Vec<_>>()
+			})
+		};
+		assert_eq!(
+			args(FilesystemType::Ext4).unwrap(),
+			["e2fsck", "-fn", "/dev/loop0p1"]
+		);
+		assert_eq!(
+			args(FilesystemType::Btrfs).unwrap(),
+			["btrfs", "check", "--readonly", "/dev/loop0p1"]
+		);
+		assert_eq!(
+			args(FilesystemType::Fat32).unwrap(),
+			["fsck.vfat", "-n", "/dev/loop0p1"]
+		);
+		assert_eq!(
+			args(FilesystemType::Erofs).unwrap(),
+			["fsck.erofs", "/dev/loop0p1"]
+		);
+		assert!(args(FilesystemType::Swap).is_none());
+	}
+
+	#[test]
+	fn test_output_tail() {
+		let stdout = (1..=30)
+			.map(|n| format!("line {}\n", n))
+			.collect::<String>();
+		let tail = output_tail(stdout.as_bytes(), b"");
+		assert_eq!(tail.lines().count(), FSCK_OUTPUT_LINES);
+		assert!(tail.ends_with("line 30"));
+		assert_eq!(
+			output_tail(b"fsck.fat 4.2\n", b"error\n"),
+			"fsck.fat 4.2\nerror"
+		);
+	}
+
+	#[test]
+	fn test_check_filesystems() -> Result<()> {
+		let dir = TempDir::new("check-filesystems")?;
+		let device = load_device(&dir, PARTITIONS)?;
+		let mut context = test_context(&device, &dir);
+		let loop_dev = Path::new("/dev/loop0");
+		let status = |pm_data: &PartitionMapData| {
+			(1..=3).map(|num| pm_data.data[&num].fsck)
+				.collect::<Vec<_>>()
+		};
+		// Nothing is run with --no-fsck.
+		let records = dir.join("records");
+		fs::create_dir(&records)?;
+		let mut pm_data = partition_data();
+		{
+			let _runner = runner::scoped(RunnerMode::Replay(records.clone()))?;
+			context.check_filesystems(loop_dev, &mut pm_data)?;
+			runner::finish()?;
+		}
+		let skipped = Some(FsckStatus::Skipped);
+		assert_eq!(status(&pm_data), [skipped, skipped, None]);
+		// Every filesystem is checked, even after one has errors.
+		context.fsck = true;
+		record(&records, 1, "fsck.vfat", &["-n"], 0)?;
+		record(&records, 2, "e2fsck", &["-fn"], 4)?;
+		let mut pm_data = partition_data();
+		let _runner = runner::scoped(RunnerMode::Replay(records))?;
+		let e = context
+			.check_filesystems(loop_dev, &mut pm_data)
+			.unwrap_err()
+			.to_string();
+		runner::finish()?;
+		assert!(e.contains("partition 2 have errors"), "{}", e);
+		assert_eq!(
+			status(&pm_data),
+			[Some(FsckStatus::Clean), Some(FsckStatus::Errors), None]
+		);
+		Ok(())
+	}
+}
@@ -0,0 +1,414 @@
+//! How the partitions are referred to in `/etc/fstab`.
+//!
+//! By default, the entries of `/etc/fstab` refer to the filesystems by their
+//! UUID, or to the partitions by their PARTUUID on `initrdless` devices.
+//! Neither fits every device: the A/B slots are better told apart by
+//! PARTUUID, and some firmwares regenerate the volume ID of the FAT boot
+//! partitions. `fstab_style` chooses the reference of the whole device, and
+//! each partition can override it:
+//!
+//! ```toml
+//! fstab_style = "partuuid"
+//! # Required by fstab_style = "path".
+//! disk_device = "/dev/mmcblk0"
+//!
+//! [[partition]]
+//! num = 1
+//! type = "efi"
+//! filesystem = "fat32"
+//! fs_label = "BOOT"
+//! mountpoint = "/boot"
+//! usage = "boot"
+//! size = "256MiB"
+//! fstab_style = "label"
+//! ```
+//!
+//! - `uuid`: `UUID=`, the UUID (or the volume ID) of the filesystem.
+//! - `partuuid`: `PARTUUID=`, the unique identifier of the partition. Not
+//!   available with `partition_map = "none"`.
+//! - `label`: `LABEL=`, the `fs_label` of the partition, which must be
+//!   unique among the partitions.
+//! - `path`: The path of the partition on `disk_device`, e.g.
+//!   `/dev/mmcblk0p1`.
+//!
+//! The identifiers are the ones read back from the image: the UUIDs and the
+//! labels are probed after the filesystems are created, and the PARTUUIDs
+//! are checked when the partition table is verified. The encrypted
+//! partitions are always referred to by their mapping.
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	context::ImageContext,
+	device::{DeviceSpec, PartitionData, PartitionMapType},
+	filesystem::FilesystemType,
+	partition::PartitionSpec,
+};
+
+/// How a partition is referred to in `/etc/fstab`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FstabStyle {
+	/// `UUID=`, the UUID of the filesystem.
+	Uuid,
+	/// `PARTUUID=`, the unique identifier of the partition.
+	PartUuid,
+	/// `LABEL=`, the label of the filesystem.
+	Label,
+	/// The path of the partition on the disk of the device.
+	Path,
+}
+
+/// Escape the white spaces of a field of `/etc/fstab`, e.g. `\040` for spaces.
+fn escape_field(field: &str) -> String {
+	field.chars()
+		.map(|c| match c {
+			' ' | '\t' | '\n' | '\\' => format!("\\{:03o}", c as u32),
+			c => c.to_string(),
+		})
+		.collect()
+}
+
+impl DeviceSpec {
+	/// How the partition is referred to in `/etc/fstab`.
+	pub fn fstab_style(&self, partition: &PartitionSpec) -> FstabStyle {
+		partition
+			.fstab_style
+			.or(self.fstab_style)
+			.unwrap_or(if self.initrdless {
+				FstabStyle::PartUuid
+			} else {
+				FstabStyle::Uuid
+			})
+	}
+
+	/// Make sure the partitions in `/etc/fstab` can be referred to in their styles.
+	pub(crate) fn check_fstab_style(&self) -> Result<()> {
+		for partition in &self.partitions {
+			let num = partition.num;
+			if partition.encryption.is_some() {
+				if partition.fstab_style.is_some() {
+					bail!("Partition {} is encrypted, it is referred to by its mapping, fstab_style can not be used", num);
+				}
+				continue;
+			}
+			// Not in /etc/fstab.
+			if partition.mountpoint.is_none()
+				&& partition.filesystem != FilesystemType::Swap
+			{
+				continue;
+			}
+			match self.fstab_style(partition) {
+				FstabStyle::Uuid => (),
+				FstabStyle::PartUuid => {
+					if self.partition_map == PartitionMapType::None {
+						bail!("Partition {}: fstab_style = \"partuuid\" requires a partition table", num);
+					}
+				}
+				FstabStyle::Label => {
+					let label =
+						partition.fs_label.as_deref().context(format!(
+						"Partition {}: fstab_style = \"label\" requires fs_label",
+						num
+					))?;
+					if let Some(other) = self.partitions.iter().find(|p| {
+						p.num != num
+							&& p.fs_label.as_deref().is_some_and(|l| {
+								l.eq_ignore_ascii_case(label)
+							})
+					}) {
+						bail!(
+							"Partition {}: fstab_style = \"label\" requires a unique fs_label, but '{}' is also the label of partition {}",
+							num,
+							label,
+							other.num
+						);
+					}
+				}
+				FstabStyle::Path => {
+					let Some(disk) = &self.disk_device else {
+						bail!("Partition {}: fstab_style = \"path\" requires disk_device", num);
+					};
+					if !disk.starts_with("/dev/") {
+						bail!("disk_device must be a path in /dev, got '{}'", disk);
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+impl ImageContext<'_> {
+	/// The source of the partition in `/etc/fstab`, see the [module documentation](self).
+	pub(crate) fn fstab_source(
+		&self,
+		partition: &PartitionSpec,
+		part_data: &PartitionData,
+	) -> Result<String> {
+		let num = partition.num;
+		Ok(match self.device.fstab_style(partition) {
+			FstabStyle::Uuid => format!(
+				"UUID=\"{}\"",
+				part_data.fs_uuid.as_ref().context(
+					"Partition with a mountpoint must have a valid filesystem"
+				)?
+			),
+			FstabStyle::PartUuid => format!("PARTUUID=\"{}\"", &part_data.part_uuid),
+			FstabStyle::Label => format!(
+				"LABEL={}",
+				escape_field(part_data.fs_label.as_deref().context(format!(
+					"No filesystem label found on partition {}",
+					num
+				))?)
+			),
+			FstabStyle::Path => {
+				let disk =
+					self.device.disk_device.as_deref().context(
+						"fstab_style = \"path\" requires disk_device",
+					)?;
+				self.device.partition_path(disk, num)
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::HashMap, fs};
+
+	use uuid::Uuid;
+
+	use super::*;
+	use crate::{
+		device::PartitionMapData,
+		filesystem::FsUuid,
+		testutil::{load_device, test_context, TempDir},
+	};
+
+	const GPT: &str = "partition_map = \"gpt\"\n";
+
+	const ROOT: &str = r#"
+[[partition]]
+num = 1
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "2GiB"
+"#;
+
+	/// A data partition mounted on `mountpoint`, with `extra` keys.
+	fn data(num: u32, mountpoint: &str, extra: &str) -> String {
+		format!(
+			"\n[[partition]]\nnum = {}\ntype = \"linux\"\nusage = \"data\"\nfilesystem = \"ext4\"\nmountpoint = \"{}\"\nsize = \"1GiB\"\n{}",
+			num, mountpoint, extra
+		)
+	}
+
+	#[test]
+	fn test_escape_field() {
+		assert_eq!(escape_field("AOSC OS"), "AOSC\\040OS");
+		assert_eq!(escape_field("BOOT"), "BOOT");
+	}
+
+	#[test]
+	fn test_fstab_style() -> Result<()> {
+		let dir = TempDir::new("fstab-style")?;
+		let spec = format!("{}{}", ROOT, data(2, "/srv", "fstab_style = \"label\"\n"));
+		let device = load_device(&dir, &format!("{}{}", GPT, spec))?;
+		assert_eq!(device.fstab_style(&device.partitions[0]), FstabStyle::Uuid);
+		assert_eq!(device.fstab_style(&device.partitions[1]), FstabStyle::Label);
+		let device = load_device(&dir, &format!("{}initrdless = true\n{}", GPT, spec))?;
+		assert_eq!(
+			device.fstab_style(&device.partitions[0]),
+			FstabStyle::PartUuid
+		);
+		// The partition overrides the device.
+		let device =
+			load_device(&dir, &format!("{}fstab_style = \"path\"\n{}", GPT, spec))?;
+		assert_eq!(device.fstab_style(&device.partitions[0]), FstabStyle::Path);
+		assert_eq!(device.fstab_style(&device.partitions[1]), FstabStyle::Label);
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_fstab_style() -> Result<()> {
+		let dir = TempDir::new("fstab-check")?;
+		let check = |spec: &str| load_device(&dir, spec)?.check_fstab_style();
+		let err = |spec: &str| check(spec).unwrap_err().to_string();
+		let label = "fs_label = \"DATA\"\nfstab_style = \"label\"\n";
+		check(&format!("{}{}{}", GPT, ROOT, data(2, "/srv", label)))?;
+		let e = err(&format!(
+			"{}{}{}",
+			GPT,
+			ROOT,
+			data(2, "/srv", "fstab_style = \"label\"\n")
+		));
+		assert!(e.contains("requires fs_label"), "{}", e);
+		// Labels are told apart regardless of the case.
+		let e = err(&format!(
+			"{}{}{}{}",
+			GPT,
+			ROOT,
+			data(2, "/srv", label),
+			data(3, "/home", "fs_label = \"data\"\n")
+		));
+		assert!(e.contains("also the label of partition 3"), "{}", e);
+		let e = err(&format!("{}fstab_style = \"path\"\n{}", GPT, ROOT));
+		assert!(e.contains("requires disk_device"), "{}", e);
+		let e = err(&format!(
+			"{}fstab_style = \"path\"\ndisk_device = \"mmcblk0\"\n{}",
+			GPT, ROOT
+		));
+		assert!(e.contains("must be a path in /dev"), "{}", e);
+		check(&format!(
+			"{}fstab_style = \"path\"\ndisk_device = \"/dev/mmcblk0\"\n{}",
+			GPT, ROOT
+		))?;
+		let e = err(&format!(
+			"partition_map = \"none\"\nfstab_style = \"partuuid\"\n{}",
+			ROOT
+		));
+		assert!(e.contains("requires a partition table"), "{}", e);
+		Ok(())
+	}
+
+	#[test]
+	fn test_mixed_fstab() -> Result<()> {
+		let dir = TempDir::new("fstab-mixed")?;
+		let spec = format!(
+			"{}fstab_style = \"partuuid\"\ndisk_device = \"/dev/mmcblk0\"\n{}fstab_style = \"uuid\"\n{}{}{}",
+			GPT,
+			ROOT,
+			data(2, "/boot", "fs_label = \"BOOT\"\nfstab_style = \"label\"\n"),
+			data(3, "/home", ""),
+			data(4, "/srv", "fstab_style = \"path\"\n")
+		);
+		let device = load_device(&dir, &spec)?;
+		device.check_fstab_style()?;
+		let context = test_context(&device, &dir);
+		let root_uuid = Uuid::from_u128(0x0d9f8c7b_6a5e_4d3c_8b2a_1f0e9d8c7b6a);
+		let data = (1..=4)
+			.map(|num| {
+				let data = PartitionData {
+					num,
+					part_uuid: format!("partuuid-{}", num),
+					fs_uuid: Some(FsUuid::Uuid(root_uuid)),
+					fs_label: (num == 2).then(|| "BOOT".to_owned()),
+					luks_uuid: None,
+					fsck: None,
+				};
+				(num, data)
+			})
+			.collect::<HashMap<_, _>>();
+		let pm_data = PartitionMapData {
+			uuid: "disk".to_owned(),
+			data,
+		};
+		let root = dir.join("root");
+		fs::create_dir_all(root.join("etc"))?;
+		fs::write(root.join("etc/fstab"), "")?;
+		context.generate_fstab(&pm_data, &root)?;
+		let fstab = fs::read_to_string(root.join("etc/fstab"))?;
+		for source in [
+			format!("UUID=\"{}\"\t/\t", root_uuid),
+			"LABEL=BOOT\t/boot\t".to_owned(),
+			"PARTUUID=\"partuuid-3\"\t/home\t".to_owned(),
+			"/dev/mmcblk0p4\t/srv\t".to_owned(),
+		] {
+			assert!(fstab.contains(&source), "{} is not in:\n{}", source, fstab);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_swap_fstab() -> Result<()> {
+		let dir = TempDir::new("fstab-swap")?;
+		let spec = format!(
+			"{}{}\n[[partition]]\nnum = 2\ntype = \"swap\"\nusage = \"swap\"\nfilesystem = \"swap\"\nsize = \"1GiB\"\n",
+			GPT, ROOT
+		);
+		let device = load_device(&dir, &spec)?;
+		device.check()?;
+		let context = test_context(&device, &dir);
+		let uuid =
+			|num: u128| Uuid::from_u128(0x0d9f8c7b_6a5e_4d3c_8b2a_1f0e9d8c7b60 + num);
+		let data = (1..=2)
+			.map(|num| {
+				let data = PartitionData {
+					num,
+					part_uuid: format!("partuuid-{}", num),
+					fs_uuid: Some(FsUuid::Uuid(uuid(num as u128))),
+					fs_label: None,
+					luks_uuid: None,
+					fsck: None,
+				};
+				(num, data)
+			})
+			.collect::<HashMap<_, _>>();
+		let pm_data = PartitionMapData {
+			uuid: "disk".to_owned(),
+			data,
+		};
+		let root = dir.join("root");
+		fs::create_dir_all(root.join("etc"))?;
+		fs::write(root.join("etc/fstab"), "")?;
+		context.generate_fstab(&pm_data, &root)?;
+		let fstab = fs::read_to_string(root.join("etc/fstab"))?;
+		let swap = format!("UUID=\"{}\"\tnone\tswap\tsw\t0\t0\n", uuid(2));
+		assert!(fstab.contains(&swap), "{} is not in:\n{}", swap, fstab);
+		assert_eq!(fstab.matches("\tswap\t").count(), 1);
+		Ok(())
+	}
+
+	#[test]
+	fn test_exfat_owner() -> Result<()> {
+		let dir = TempDir::new("fstab-exfat")?;
+		let spec = format!(
+			"{}{}\n[[partition]]\nnum = 2\ntype = \"basic\"\nusage = \"data\"\nfilesystem = \"exfat\"\nmountpoint = \"/srv/share\"\nsize = \"1GiB\"\n",
+			GPT, ROOT
+		);
+		let device = load_device(&dir, &spec)?;
+		let context = test_context(&device, &dir);
+		let fs_uuid = ["0d9f8c7b-6a5e-4d3c-8b2a-1f0e9d8c7b6a", "1A2B-3C4D"];
+		let data = (1..=2)
+			.map(|num| {
+				let data = PartitionData {
+					num,
+					part_uuid: format!("partuuid-{}", num),
+					fs_uuid: Some(fs_uuid[num as usize - 1].parse()?),
+					fs_label: None,
+					luks_uuid: None,
+					fsck: None,
+				};
+				Ok((num, data))
+			})
+			.collect::<Result<HashMap<_, _>>>()?;
+		let pm_data = PartitionMapData {
+			uuid: "disk".to_owned(),
+			data,
+		};
+		let root = dir.join("root");
+		fs::create_dir_all(root.join("etc"))?;
+		fs::write(root.join("etc/fstab"), "")?;
+		context.generate_fstab(&pm_data, &root)?;
+		let exfat = |id: u32| {
+			format!(
+				"UUID=\"1A2B-3C4D\"\t/srv/share\texfat\tdefaults,uid={0},gid={0},umask=0022\t0\t2\n",
+				id
+			)
+		};
+		let fstab = fs::read_to_string(root.join("etc/fstab"))?;
+		assert!(fstab.contains(&exfat(1000)), "{}", fstab);
+		// The base system already has a user, so the user is not 1000.
+		fs::write(
+			root.join("etc/passwd"),
+			"root:x:0:0::/root:/bin/bash\nalice:x:1000:1000::/home/alice:/bin/bash\naosc:x:1001:1001::/home/aosc:/bin/bash\n",
+		)?;
+		context.update_exfat_owner(&root, "aosc")?;
+		let updated = fs::read_to_string(root.join("etc/fstab"))?;
+		assert_eq!(updated, fstab.replace(&exfat(1000), &exfat(1001)));
+		Ok(())
+	}
+}
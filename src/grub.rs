@@ -0,0 +1,381 @@
+//! Installing GRUB on the EFI system partition.
+//!
+//! The devices booting with UEFI (most amd64 devices and the generic arm64
+//! images) boot GRUB from the EFI system partition. A `grub_efi`
+//! bootloader step runs `grub-install` and `grub-mkconfig` inside the
+//! target, with `/dev`, `/proc`, `/sys` and the partitions of the image
+//! available, and the ESP mounted at its mountpoint:
+//!
+//! ```toml
+//! [[bootloader]]
+//! type = "grub_efi"
+//! # Optional, defaults to the target of the architecture, e.g. x86_64-efi.
+//! target = "x86_64-efi"
+//! # Optional, the number of the EFI system partition, defaults to the
+//! # partition with type = "esp".
+//! esp = 1
+//! # Optional, also install to the removable media path, e.g.
+//! # /EFI/BOOT/BOOTX64.EFI, for the firmware without a boot entry.
+//! removable = true
+//! # Optional, the directory under /EFI, defaults to the one of grub-install.
+//! bootloader_id = "aosc"
+//! ```
+//!
+//! The ESP must be a FAT partition with a mountpoint. `grub-install` never
+//! writes to the NVRAM of the host (`--no-nvram`), and `grub.cfg` is
+//! generated at `/boot/grub/grub.cfg`, unless `config` says otherwise.
+//!
+//! `grub-mkconfig` probes the root filesystem inside the target. The build
+//! fails if the generated `grub.cfg` does not refer to the root filesystem
+//! by the UUID it was created with, e.g. if the probe saw the host instead.
+//! Thus GRUB can not be installed with `--populate-backend no-mount`, nor
+//! for an encrypted or an EROFS root filesystem, which `grub-mkconfig` can
+//! not refer to by its UUID.
+//!
+//! The kernel command line of the device (without `root=`, which
+//! `grub-mkconfig` generates) is set as `GRUB_CMDLINE_LINUX` in
+//! `/etc/default/grub` of the target, so it is kept when `grub.cfg` is
+//! generated again on the device.
+//!
+//! `grub-install` and `grub-mkconfig` come from the target, they are
+//! usually installed with the BSP packages.
+use std::{
+	fs,
+	io::ErrorKind,
+	path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+	context::ImageContext,
+	device::{DeviceArch, DeviceSpec, PartitionMapData},
+	filesystem::FilesystemType,
+	partition::{PartitionSpec, PartitionType, PartitionUsage},
+	utils::run_str_script_with_chroot,
+};
+
+/// Path to grub.cfg within the target, by default.
+const DEFAULT_GRUB_CFG: &str = "/boot/grub/grub.cfg";
+/// Defaults of grub-mkconfig within the target.
+const GRUB_DEFAULTS: &str = "etc/default/grub";
+/// The variable of the kernel command line in [`GRUB_DEFAULTS`].
+const GRUB_CMDLINE_VAR: &str = "GRUB_CMDLINE_LINUX";
+
+/// Install GRUB to the EFI system partition, see the [module documentation](self).
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct GrubEfiSpec {
+	/// Target platform of `grub-install`, e.g. `x86_64-efi`.
+	pub target: Option<String>,
+	/// Number of the EFI system partition.
+	pub esp: Option<u32>,
+	/// Also install to the removable media path.
+	#[serde(default)]
+	pub removable: bool,
+	/// Name of the directory under `/EFI` in the ESP.
+	pub bootloader_id: Option<String>,
+	/// Path to grub.cfg within the target.
+	pub config: Option<PathBuf>,
+}
+
+/// The target platform of `grub-install` for the architecture.
+fn grub_target(arch: &DeviceArch) -> Option<&'static str> {
+	match arch {
+		DeviceArch::Amd64 => Some("x86_64-efi"),
+		DeviceArch::I486 => Some("i386-efi"),
+		DeviceArch::Arm64 => Some("arm64-efi"),
+		DeviceArch::Armv7hf => Some("arm-efi"),
+		DeviceArch::Riscv64 => Some("riscv64-efi"),
+		DeviceArch::LoongArch64 => Some("loongarch64-efi"),
+		_ => None,
+	}
+}
+
+/// Whether the string can be passed to `grub-install` without quoting.
+pub(crate) fn is_plain(s: &str) -> bool {
+	!s.is_empty()
+		&& s.chars()
+			.all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Whether the path can be passed to `grub-mkconfig` without quoting.
+fn is_plain_path(path: &Path) -> bool {
+	path.components().all(|c| match c {
+		Component::RootDir => true,
+		Component::Normal(name) => name.to_str().is_some_and(is_plain),
+		_ => false,
+	})
+}
+
+/// Quote the string for the shell with double quotes, as `/etc/default/grub` is sourced.
+fn shell_double_quote(s: &str) -> String {
+	let mut quoted = String::from('"');
+	for c in s.chars() {
+		if matches!(c, '"' | '\\' | '$' | '`') {
+			quoted.push('\\');
+		}
+		quoted.push(c);
+	}
+	quoted.push('"');
+	quoted
+}
+
+/// Replace the kernel command line in the content of `/etc/default/grub`,
+/// the `root=` arguments are left to `grub-mkconfig`.
+fn set_grub_cmdline(content: &str, cmdline: &str) -> String {
+	let args = cmdline
+		.split_whitespace()
+		.filter(|arg| !arg.starts_with("root="))
+		.collect::<Vec<_>>()
+		.join(" ");
+	let prefix = format!("{}=", GRUB_CMDLINE_VAR);
+	let mut result = content
+		.lines()
+		.filter(|line| !line.trim_start().starts_with(&prefix))
+		.map(|line| format!("{}\n", line))
+		.collect::<String>();
+	result += &format!("{}{}\n", prefix, shell_double_quote(&args));
+	result
+}
+
+/// The script installing GRUB and generating grub.cfg, run inside the target.
+fn grub_script(target: &str, efi_dir: &str, spec: &GrubEfiSpec, config: &Path) -> String {
+	let mut install = format!(
+		"grub-install --target={} --efi-directory={} --no-nvram",
+		target, efi_dir
+	);
+	if spec.removable {
+		install += " --removable";
+	}
+	if let Some(id) = &spec.bootloader_id {
+		install += &format!(" --bootloader-id={}", id);
+	}
+	format!(
+		"set -e\n{}\ngrub-mkconfig -o '{}'\n",
+		install,
+		config.display()
+	)
+}
+
+impl GrubEfiSpec {
+	/// Path to grub.cfg within the target.
+	pub fn config_path(&self) -> PathBuf {
+		self.config
+			.clone()
+			.unwrap_or_else(|| PathBuf::from(DEFAULT_GRUB_CFG))
+	}
+}
+
+impl DeviceSpec {
+	/// The EFI system partition GRUB is installed to.
+	pub(crate) fn grub_esp(&self, spec: &GrubEfiSpec) -> Result<&PartitionSpec> {
+		self.efi_partition(spec.esp, "grub_efi")
+	}
+
+	/// The EFI system partition of number `num` (the one with `type = "esp"` by default) the bootloader `kind` is installed to.
+	pub(crate) fn efi_partition(&self, num: Option<u32>, kind: &str) -> Result<&PartitionSpec> {
+		let esp = match num {
+			Some(num) => {
+				let msg = format!(
+					"Partition {} specified by the {} bootloader is not found.",
+					num, kind
+				);
+				self.partitions.iter().find(|p| p.num == num).context(msg)?
+			}
+			None => self
+				.partitions
+				.iter()
+				.find(|p| p.part_type == PartitionType::EFI)
+				.context(format!(
+					"The {} bootloader requires an EFI system partition",
+					kind
+				))?,
+		};
+		if !matches!(
+			esp.filesystem,
+			FilesystemType::Fat16 | FilesystemType::Fat32
+		) {
+			bail!("The EFI system partition {} must be FAT", esp.num);
+		}
+		if esp.mountpoint.is_none() {
+			bail!(
+				"The EFI system partition {} must have a mountpoint to install {}",
+				esp.num,
+				kind
+			);
+		}
+		Ok(esp)
+	}
+
+	/// The target platform of `grub-install`.
+	pub(crate) fn grub_target<'a>(&self, spec: &'a GrubEfiSpec) -> Result<&'a str> {
+		match &spec.target {
+			Some(target) => Ok(target),
+			None => grub_target(&self.arch).context(format!(
+				"GRUB EFI is not available for {:?}, specify the target",
+				self.arch
+			)),
+		}
+	}
+
+	/// Make sure GRUB can be installed, see the [module documentation](crate::grub).
+	pub(crate) fn check_grub_efi(&self, spec: &GrubEfiSpec) -> Result<()> {
+		self.grub_esp(spec)?;
+		let target = self.grub_target(spec)?;
+		if !is_plain(target) || !target.ends_with("-efi") {
+			bail!("Invalid GRUB target '{}', expected e.g. x86_64-efi", target);
+		}
+		if let Some(id) = &spec.bootloader_id {
+			if !is_plain(id) {
+				bail!("Invalid bootloader_id '{}', it may only contain letters, digits, '-', '_' and '.'", id);
+			}
+		}
+		let config = spec.config_path();
+		if !config.is_absolute() {
+			bail!(
+				"The GRUB configuration file '{}' must be an absolute path",
+				config.display()
+			);
+		}
+		if !is_plain_path(&config) {
+			bail!(
+				"Invalid GRUB configuration file '{}', its components may only contain letters, digits, '-', '_' and '.'",
+				config.display()
+			);
+		}
+		let rootfs = self
+			.partitions
+			.iter()
+			.find(|p| p.usage == PartitionUsage::Rootfs)
+			.context("The grub_efi bootloader requires a root partition")?;
+		if rootfs.encryption.is_some() {
+			bail!("The grub_efi bootloader can not boot the encrypted root partition {}, grub-mkconfig can not refer to it by its UUID", rootfs.num);
+		}
+		if rootfs.filesystem == FilesystemType::Erofs {
+			bail!("The grub_efi bootloader can not boot the EROFS root partition {}, grub-mkconfig can not refer to it by its UUID", rootfs.num);
+		}
+		Ok(())
+	}
+}
+
+impl ImageContext<'_> {
+	/// Install GRUB and generate grub.cfg, see the [module documentation](self).
+	pub(crate) fn install_grub_efi(
+		&self,
+		spec: &GrubEfiSpec,
+		root: &Path,
+		binds: &[&str],
+		pm_data: &PartitionMapData,
+	) -> Result<()> {
+		let esp = self.device.grub_esp(spec)?;
+		let target = self.device.grub_target(spec)?;
+		// Safe to unwrap, checked by grub_esp().
+		let efi_dir = esp.mountpoint.as_deref().unwrap();
+		let config = spec.config_path();
+		// The root filesystem type can be overridden, check_grub_efi() only sees the specification.
+		if let Some(p) = self.erofs_root() {
+			bail!(
+				"The grub_efi bootloader can not boot the EROFS root partition {}",
+				p.num
+			);
+		}
+		let defaults = root.join(GRUB_DEFAULTS);
+		let content = match fs::read_to_string(&defaults) {
+			Ok(content) => content,
+			Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+			Err(e) => {
+				return Err(e)
+					.context(format!("Failed to read {}", defaults.display()))
+			}
+		};
+		let cmdline = self.device.gen_kernel_cmdline(pm_data)?;
+		if let Some(dir) = defaults.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		fs::write(&defaults, set_grub_cmdline(&content, &cmdline))
+			.context(format!("Failed to write {}", defaults.display()))?;
+		self.info(format!(
+			"Installing GRUB ({}) to the EFI system partition {} ...",
+			target, esp.num
+		));
+		run_str_script_with_chroot(
+			&root,
+			&grub_script(target, efi_dir, spec, &config),
+			binds,
+			None,
+		)
+		.context("Failed to install GRUB")?;
+		let rootfs = self
+			.device
+			.partitions
+			.iter()
+			.find(|p| p.usage == PartitionUsage::Rootfs)
+			.context("No root partition")?;
+		let uuid = pm_data
+			.data
+			.get(&rootfs.num)
+			.and_then(|d| d.fs_uuid.as_ref())
+			.context("No filesystem UUID of the root partition")?;
+		let content = fs::read_to_string(root.join(config.strip_prefix("/")?))
+			.context(format!("Failed to read {}", config.display()))?;
+		if !content.contains(&format!("root=UUID={}", uuid)) {
+			bail!(
+				"{} does not refer to the root filesystem by its UUID {}, grub-mkconfig probed the wrong device",
+				config.display(),
+				uuid
+			);
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_grub_script() {
+		let spec = GrubEfiSpec::default();
+		assert_eq!(
+			grub_script("x86_64-efi", "/efi", &spec, Path::new(DEFAULT_GRUB_CFG)),
+			"set -e
+grub-install --target=x86_64-efi --efi-directory=/efi --no-nvram
+grub-mkconfig -o '/boot/grub/grub.cfg'
+"
+		);
+		let spec = GrubEfiSpec {
+			removable: true,
+			bootloader_id: Some("aosc".to_owned()),
+			..Default::default()
+		};
+		assert!(grub_script("arm64-efi", "/boot/efi", &spec, &spec.config_path())
+			.contains("--efi-directory=/boot/efi --no-nvram --removable --bootloader-id=aosc\n"));
+	}
+
+	#[test]
+	fn test_is_plain() {
+		assert!(is_plain("x86_64-efi"));
+		assert!(is_plain("aosc"));
+		assert!(!is_plain(""));
+		assert!(!is_plain("aosc os"));
+		assert!(!is_plain("$(reboot)"));
+		assert!(is_plain_path(Path::new(DEFAULT_GRUB_CFG)));
+		assert!(is_plain_path(Path::new("/boot/efi/EFI/aosc/grub.cfg")));
+		assert!(!is_plain_path(Path::new("/boot/grub/grub'; reboot; '.cfg")));
+		assert!(!is_plain_path(Path::new("/boot/../etc/grub.cfg")));
+	}
+
+	#[test]
+	fn test_set_grub_cmdline() {
+		let content = "GRUB_DEFAULT=0\nGRUB_CMDLINE_LINUX=\"quiet\"\nGRUB_TIMEOUT=5\n";
+		assert_eq!(
+			set_grub_cmdline(content, "root=UUID=1234 rw console=ttyS0,115200 rootwait"),
+			"GRUB_DEFAULT=0\nGRUB_TIMEOUT=5\nGRUB_CMDLINE_LINUX=\"rw console=ttyS0,115200 rootwait\"\n"
+		);
+		assert_eq!(
+			set_grub_cmdline("", "rw init=\"$x\""),
+			"GRUB_CMDLINE_LINUX=\"rw init=\\\"\\$x\\\"\"\n"
+		);
+	}
+}
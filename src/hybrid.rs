@@ -0,0 +1,249 @@
+//! Hybrid MBR of the GPT disks.
+//!
+//! The boot ROMs of some SoCs (e.g. older Allwinner and some MediaTek ones)
+//! only read the MBR, and ignore the GPT entirely. With
+//! `partition_map = "hybrid"`, the image has a GPT as usual, but the
+//! protective MBR is replaced with a hybrid MBR mirroring up to three of the
+//! partitions, marked with `hybrid_mbr = true`:
+//!
+//! ```toml
+//! partition_map = "hybrid"
+//!
+//! [[partition]]
+//! num = 1
+//! type = "efi"
+//! filesystem = "fat32"
+//! mountpoint = "/boot"
+//! usage = "boot"
+//! size = "256MiB"
+//! hybrid_mbr = true
+//! ```
+//!
+//! - The mirrored partitions take the MBR entries 1 to 3 in the order of
+//!   their numbers, with the MBR type of their partition types. The boot
+//!   partition is marked active.
+//! - The protective entry (type 0xEE) comes last, covering the GPT from
+//!   sector 1 up to the first mirrored partition.
+//! - The mirrored partitions must be within the first 2 TiB of the disk,
+//!   which the MBR can address.
+//!
+//! The GPT is still the authoritative partition table for the OS.
+use std::{fs::File, path::Path};
+
+use anyhow::{bail, Context, Result};
+use gptman::GPT;
+use mbrman::{MBRPartitionEntry, CHS, MBR};
+
+use crate::{
+	context::ImageContext,
+	device::{DeviceSpec, PartitionMapType},
+	partition::{PartitionSpec, PartitionUsage},
+	verify::verify_mbr,
+};
+
+/// MBR type of the protective entry of GPT.
+const MBR_PROTECTIVE_TYPE: u8 = 0xee;
+/// The MBR has four entries, one of them is the protective entry.
+const MAX_HYBRID_ENTRIES: usize = 3;
+
+/// A partition mirrored in the hybrid MBR, in sectors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Mirrored {
+	start: u64,
+	sectors: u64,
+	sys: u8,
+	boot: bool,
+}
+
+/// The entries of the hybrid MBR, the mirrored partitions followed by the protective entry.
+fn hybrid_entries(mirrored: &[Mirrored]) -> Result<Vec<MBRPartitionEntry>> {
+	let first = mirrored
+		.iter()
+		.map(|m| m.start)
+		.min()
+		.context("No partition to mirror in the hybrid MBR")?;
+	let lba = |n: u64| {
+		TryInto::<u32>::try_into(n)
+			.context("Partition exceeds the limit of MBR, it can not be mirrored")
+	};
+	let mut entries = Vec::with_capacity(mirrored.len() + 1);
+	for m in mirrored {
+		entries.push(MBRPartitionEntry {
+			boot: if m.boot {
+				mbrman::BOOT_ACTIVE
+			} else {
+				mbrman::BOOT_INACTIVE
+			},
+			first_chs: CHS::empty(),
+			sys: m.sys,
+			last_chs: CHS::empty(),
+			starting_lba: lba(m.start)?,
+			sectors: lba(m.sectors)?,
+		});
+	}
+	entries.push(MBRPartitionEntry {
+		boot: mbrman::BOOT_INACTIVE,
+		first_chs: CHS::empty(),
+		sys: MBR_PROTECTIVE_TYPE,
+		last_chs: CHS::empty(),
+		starting_lba: 1,
+		sectors: lba(first - 1)?,
+	});
+	Ok(entries)
+}
+
+impl DeviceSpec {
+	/// The partitions mirrored in the hybrid MBR.
+	pub fn hybrid_partitions(&self) -> impl Iterator<Item = &PartitionSpec> {
+		self.partitions.iter().filter(|p| p.hybrid_mbr)
+	}
+
+	/// Make sure the partitions mirrored in the hybrid MBR can be recorded in it.
+	///
+	/// The partitions beyond 2 TiB are found while they are laid out.
+	pub(crate) fn check_hybrid_mbr(&self) -> Result<()> {
+		let mirrored = self.hybrid_partitions().collect::<Vec<_>>();
+		if self.partition_map != PartitionMapType::Hybrid {
+			if let Some(p) = mirrored.first() {
+				bail!(
+					"Partition {} is mirrored in the hybrid MBR, which requires partition_map = \"hybrid\"",
+					p.num
+				);
+			}
+			return Ok(());
+		}
+		if mirrored.is_empty() {
+			bail!("A hybrid MBR must mirror at least one partition, mark it with hybrid_mbr = true");
+		}
+		if mirrored.len() > MAX_HYBRID_ENTRIES {
+			bail!(
+				"A hybrid MBR can mirror at most {} partitions, {} are marked with hybrid_mbr = true",
+				MAX_HYBRID_ENTRIES,
+				mirrored.len()
+			);
+		}
+		for partition in mirrored {
+			partition.part_type.to_byte().context(format!(
+				"Partition {} can not be mirrored in the hybrid MBR",
+				partition.num
+			))?;
+		}
+		Ok(())
+	}
+}
+
+impl ImageContext<'_> {
+	/// Replace the protective MBR of the image with the hybrid MBR, see the [module documentation](self).
+	pub(crate) fn write_hybrid_mbr(
+		&self,
+		img: &Path,
+		fd: &mut File,
+		gpt: &GPT,
+		disk_signature: [u8; 4],
+	) -> Result<()> {
+		let sector_size = gpt.sector_size;
+		let mut mirrored = Vec::new();
+		for partition in self.device.hybrid_partitions() {
+			let entry = &gpt[partition.num];
+			mirrored.push(Mirrored {
+				start: entry.starting_lba,
+				sectors: entry.ending_lba + 1 - entry.starting_lba,
+				sys: partition.part_type.to_byte()?,
+				boot: partition.usage == PartitionUsage::Boot,
+			});
+		}
+		let entries = hybrid_entries(&mirrored)?;
+		let mut mbr = MBR::new_from(fd, sector_size as u32, disk_signature)?;
+		for (idx, entry) in entries.into_iter().enumerate() {
+			self.info(format!(
+				"Hybrid MBR entry {}: type {:#04x}, Start = {}, Sectors = {}",
+				idx + 1,
+				entry.sys,
+				entry.starting_lba,
+				entry.sectors
+			));
+			mbr[idx + 1] = entry;
+		}
+		mbr.write_into(fd)?;
+		fd.sync_all()?;
+		verify_mbr(img, &mbr).context("The hybrid MBR is not written correctly")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::{load_device, TempDir};
+
+	/// A partition of `num` with the type `part_type`, mirrored if `hybrid`.
+	fn partition(num: u32, part_type: &str, hybrid: bool) -> String {
+		format!(
+			"\n[[partition]]\nnum = {}\n{}\nusage = \"data\"\nfilesystem = \"none\"\nsize = \"64MiB\"\nhybrid_mbr = {}\n",
+			num, part_type, hybrid
+		)
+	}
+
+	#[test]
+	fn test_hybrid_entries() -> Result<()> {
+		let boot = Mirrored {
+			start: 2048,
+			sectors: 524288,
+			sys: 0xef,
+			boot: true,
+		};
+		let entries = hybrid_entries(&[boot])?;
+		assert_eq!(entries.len(), 2);
+		assert_eq!(entries[0].boot, mbrman::BOOT_ACTIVE);
+		assert_eq!(entries[0].sys, 0xef);
+		assert_eq!(
+			(entries[0].starting_lba, entries[0].sectors),
+			(2048, 524288)
+		);
+		// The protective entry covers the GPT, up to the first mirrored partition.
+		assert_eq!(entries[1].sys, MBR_PROTECTIVE_TYPE);
+		assert_eq!((entries[1].starting_lba, entries[1].sectors), (1, 2047));
+		let huge = Mirrored {
+			start: 1 << 32,
+			..boot
+		};
+		assert!(hybrid_entries(&[boot, huge]).is_err());
+		assert!(hybrid_entries(&[]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_hybrid_mbr() -> Result<()> {
+		let dir = TempDir::new("check-hybrid")?;
+		let check = |spec: &str| load_device(&dir, spec)?.check_hybrid_mbr();
+		let err = |spec: &str| check(spec).unwrap_err().to_string();
+		let gpt = "partition_map = \"gpt\"\n";
+		let hybrid = "partition_map = \"hybrid\"\n";
+		let efi = partition(1, "type = \"efi\"", true);
+		let linux = partition(2, "type = \"linux\"", false);
+		check(&format!("{}{}{}", hybrid, efi, linux))?;
+		let root = partition(1, "type = \"linux\"", false);
+		check(&format!("{}{}", gpt, root))?;
+		let e = err(&format!("{}{}{}", gpt, efi, linux));
+		assert!(e.contains("requires partition_map = \"hybrid\""), "{}", e);
+		let e = err(&format!("{}{}", hybrid, root));
+		assert!(e.contains("at least one partition"), "{}", e);
+		let e = err(&format!(
+			"{}{}{}{}{}",
+			hybrid,
+			efi,
+			partition(2, "type = \"linux\"", true),
+			partition(3, "type = \"basic\"", true),
+			partition(4, "type = \"swap\"", true)
+		));
+		assert!(e.contains("at most 3 partitions, 4 are marked"), "{}", e);
+		// The partition type must have an MBR type.
+		let e = err(&format!(
+			"{}{}{}",
+			hybrid,
+			efi,
+			partition(2, "type = \"933ac7e1-2eb4-4f13-b844-0e14e2aef915\"", true)
+		));
+		assert!(e.contains("Partition 2 can not be mirrored"), "{}", e);
+		Ok(())
+	}
+}
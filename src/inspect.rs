@@ -0,0 +1,361 @@
+//! Resolved device specifications, with `mkrawimg inspect DEVICE`.
+//!
+//! A [`ResolvedSpec`] is the device specification with every default
+//! applied, as a build of the variant would use it:
+//!
+//! - The size of the image, before and after the padding and the rounding.
+//! - The sectors each partition occupies, computed the same way the
+//!   partitioning does (see [`plan_layout`]).
+//! - The filesystems, after `--fstype`.
+//! - The hostname, the partition labels and the offset of the metadata.
+//! - The BSP packages of the variant.
+//!
+//! It is printed as TOML, with the values derived from the defaults
+//! annotated, or as JSON with `--format json`:
+//!
+//! ```shell
+//! mkrawimg inspect -V desktop --fstype btrfs rpi-5b
+//! ```
+use std::{fmt::Write, path::PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+	content::PartitionContent,
+	context::ImageVariant,
+	device::{pad_image_size, DeviceMetadata, DeviceSpec, PartitionMapType},
+	filesystem::FilesystemType,
+	metadata::DEFAULT_METADATA_OFFSET,
+	partition::{PartitionAttributes, PartitionSize, PartitionType, PartitionUsage},
+	plan::plan_layout,
+	size::format_size,
+	slots::Slot,
+	utils::sanitize_hostname,
+};
+
+/// A partition of a [`ResolvedSpec`].
+#[derive(Clone, Debug, Serialize)]
+pub struct ResolvedPartition {
+	pub num: u32,
+	#[serde(flatten)]
+	pub part_type: PartitionType,
+	pub label: Option<String>,
+	/// First sector of the partition.
+	pub start_sector: u64,
+	/// Last sector of the partition, inclusive.
+	pub end_sector: u64,
+	pub size_in_sectors: u64,
+	/// Size of the partition, in bytes.
+	pub size: u64,
+	pub filesystem: FilesystemType,
+	pub fs_label: Option<String>,
+	/// Pinned PARTUUID, randomly generated if `None`.
+	pub part_uuid: Option<String>,
+	/// Pinned filesystem UUID, randomly generated if `None`.
+	pub fs_uuid: Option<String>,
+	/// GPT attribute bits.
+	pub attributes: PartitionAttributes,
+	/// File written to the partition instead of a filesystem.
+	pub content: Option<PartitionContent>,
+	pub mountpoint: Option<String>,
+	pub mount_opts: Option<Vec<String>>,
+	pub usage: PartitionUsage,
+	/// A/B slot, if the partition is expanded from `slots = 2`.
+	pub slot: Option<Slot>,
+}
+
+/// A device specification with every default applied, for one variant.
+#[derive(Clone, Debug, Serialize)]
+pub struct ResolvedSpec {
+	pub id: String,
+	pub name: String,
+	pub file: PathBuf,
+	pub vendor: String,
+	pub arch: String,
+	pub variant: String,
+	pub hostname: String,
+	/// BSP packages installed in the variant.
+	pub bsp_packages: Vec<String>,
+	pub partition_map: String,
+	/// Nominal size of the image, in MiB.
+	pub nominal_size: u64,
+	/// In MiB.
+	pub image_size_round_to: u64,
+	/// In MiB.
+	pub trailing_pad: u64,
+	/// Final size of the image, in bytes.
+	pub image_size: u64,
+	pub metadata_offset: Option<u64>,
+	/// The `[metadata]` table of the device, as is.
+	#[serde(skip_serializing_if = "DeviceMetadata::is_empty")]
+	pub metadata: DeviceMetadata,
+	pub partitions: Vec<ResolvedPartition>,
+	/// Where the values come from, by the path of the key.
+	#[serde(skip)]
+	notes: Vec<(String, String)>,
+}
+
+impl ResolvedSpec {
+	/// Apply the defaults to the device specification.
+	///
+	/// `image_size` (in MiB) overrides the size of the variant, like `--image-size`.
+	pub fn resolve(
+		device: &DeviceSpec,
+		variant: &ImageVariant,
+		fstype: Option<FilesystemType>,
+		image_size: Option<u64>,
+	) -> Result<Self> {
+		let variant_name = variant.to_string().to_lowercase();
+		let mut notes = Vec::new();
+		let mut note = |key: String, value: String| notes.push((key, value));
+		let nominal = match image_size {
+			Some(size) => {
+				note("nominal_size".into(), "MiB, from --image-size".into());
+				size
+			}
+			None => {
+				note(
+					"nominal_size".into(),
+					format!("MiB, size of the {} variant", variant_name),
+				);
+				device.size.get_variant_size(variant)
+			}
+		};
+		let round_to = device.image_size_round_to.unwrap_or(0);
+		let pad = device.trailing_pad.unwrap_or(0);
+		note("image_size_round_to".into(), "MiB".into());
+		note("trailing_pad".into(), "MiB".into());
+		let size = pad_image_size(nominal << 20, round_to << 20, pad << 20);
+		note(
+			"image_size".into(),
+			if size == nominal << 20 {
+				"bytes".into()
+			} else {
+				"bytes, padded and rounded".into()
+			},
+		);
+		if device.hostname.is_none() {
+			note("hostname".into(), "derived from the ID".into());
+		}
+		if device.bsp_packages.per_variant() {
+			note(
+				"bsp_packages".into(),
+				format!("common and {} packages", variant_name),
+			);
+		}
+		let metadata_offset = match device.partition_map {
+			PartitionMapType::None => None,
+			_ => {
+				if device.metadata_offset.is_none() {
+					note("metadata_offset".into(), "default".into());
+				}
+				Some(device.metadata_offset.unwrap_or(DEFAULT_METADATA_OFFSET))
+			}
+		};
+		let layout = plan_layout(
+			device.partition_map,
+			&device.partitions,
+			&device.reserved,
+			device.first_partition_offset.map(|o| o.sectors(512)),
+			variant,
+			size,
+			pad << 20,
+		)
+		.context("Invalid partition layout")?;
+		// The extended partition of a MBR is not declared.
+		let layout = layout
+			.iter()
+			.filter(|p| device.partitions.iter().any(|s| s.num == p.num));
+		let mut partitions = Vec::new();
+		for (idx, (p, spec)) in layout.zip(&device.partitions).enumerate() {
+			let key = |name: &str| format!("partitions.{}.{}", idx, name);
+			if spec.start_sector.is_none() {
+				let note_text = if p.num == 1
+					&& device.first_partition_offset.is_some()
+				{
+					"from first_partition_offset".into()
+				} else {
+					format!("aligned to {}", format_size(spec.align() * 512))
+				};
+				note(key("start_sector"), note_text);
+			}
+			match (spec.sectors(variant), spec.size_in_sectors) {
+				(None, _) => {
+					note(key("size_in_sectors"), "the rest of the image".into())
+				}
+				(Some(_), PartitionSize::Variants { .. }) => note(
+					key("size_in_sectors"),
+					format!("size of the {} variant", variant_name),
+				),
+				_ => (),
+			}
+			note(key("size"), "bytes".into());
+			if let Some(slot) = spec.slot {
+				note(
+					key("slot"),
+					format!(
+						"expanded from slots = 2 of partition {}",
+						match slot {
+							Slot::A => p.num,
+							Slot::B => p.num - 1,
+						}
+					),
+				);
+			}
+			if spec.label.is_none() && spec.get_label().is_some() {
+				note(key("label"), "of the vendor partition type".into());
+			}
+			let filesystem = device.effective_fstype(spec, fstype);
+			if fstype.is_some() && device.follows_rootfs_fstype(spec) {
+				note(key("filesystem"), "from --fstype".into());
+			}
+			partitions.push(ResolvedPartition {
+				num: p.num,
+				part_type: spec.part_type.clone(),
+				label: spec.get_label(),
+				start_sector: p.start,
+				end_sector: p.start + p.size - 1,
+				size_in_sectors: p.size,
+				size: p.size * 512,
+				filesystem,
+				fs_label: spec.fs_label.clone(),
+				part_uuid: spec.part_uuid.clone(),
+				fs_uuid: spec.fs_uuid.clone(),
+				attributes: spec.attributes.clone(),
+				content: spec.content.clone(),
+				mountpoint: spec.mountpoint.clone(),
+				mount_opts: spec.mount_opts.clone(),
+				usage: spec.usage.clone(),
+				slot: spec.slot,
+			});
+		}
+		Ok(Self {
+			id: device.id.clone(),
+			name: device.name.clone(),
+			file: device.file_path.clone(),
+			vendor: device.vendor.clone(),
+			arch: device.arch.to_string().to_lowercase(),
+			variant: variant_name,
+			hostname: device
+				.hostname
+				.clone()
+				.unwrap_or_else(|| sanitize_hostname(&device.id)),
+			bsp_packages: device
+				.bsp_packages
+				.get(variant)
+				.into_iter()
+				.map(str::to_owned)
+				.collect(),
+			partition_map: device.partition_map.to_string().to_lowercase(),
+			nominal_size: nominal,
+			image_size_round_to: round_to,
+			trailing_pad: pad,
+			image_size: size,
+			metadata_offset,
+			metadata: device.metadata.clone(),
+			partitions,
+			notes,
+		})
+	}
+
+	fn write_table(&self, out: &mut String, table: &toml::Table, prefix: &str) -> Result<()> {
+		for (key, value) in table {
+			// Tables and arrays of tables follow the keys.
+			if key == "partitions" || key == "metadata" {
+				continue;
+			}
+			let path = format!("{}{}", prefix, key);
+			match self.notes.iter().find(|(k, _)| *k == path) {
+				Some((_, note)) => writeln!(out, "{} = {} # {}", key, value, note)?,
+				None => writeln!(out, "{} = {}", key, value)?,
+			}
+		}
+		Ok(())
+	}
+
+	/// Format as TOML, with the values derived from the defaults annotated.
+	pub fn to_toml(&self) -> Result<String> {
+		let value = toml::Value::try_from(self)?;
+		let table = value.as_table().context("Not a table")?;
+		let mut out = format!(
+			"# Resolved specification of {} ({}), with the defaults applied.\n",
+			&self.id, &self.variant
+		);
+		self.write_table(&mut out, table, "")?;
+		if let Some(metadata) = table.get("metadata").and_then(|m| m.as_table()) {
+			out += "\n[metadata]\n";
+			self.write_table(&mut out, metadata, "metadata.")?;
+		}
+		let partitions = table.get("partitions").and_then(|p| p.as_array());
+		for (idx, p) in partitions.into_iter().flatten().enumerate() {
+			let p = p.as_table().context("Not a table")?;
+			out += "\n[[partitions]]\n";
+			self.write_table(&mut out, p, &format!("partitions.{}.", idx))?;
+		}
+		Ok(out)
+	}
+
+	/// Format as JSON.
+	pub fn to_json(&self) -> Result<String> {
+		Ok(serde_json::to_string_pretty(self)?)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::{load_device, TempDir};
+
+	#[test]
+	fn test_resolve_spec() -> Result<()> {
+		let dir = TempDir::new("resolve-spec")?;
+		let device = load_device(
+			&dir,
+			r#"partition_map = "gpt"
+
+[[partition]]
+num = 1
+type = "efi"
+usage = "boot"
+filesystem = "fat32"
+mountpoint = "/boot/efi"
+size = "300MiB"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+"#,
+		)?;
+		let spec = ResolvedSpec::resolve(
+			&device,
+			&ImageVariant::Desktop,
+			Some(FilesystemType::Btrfs),
+			None,
+		)?;
+		assert_eq!(spec.nominal_size, 1024);
+		assert_eq!(spec.image_size, 1024 << 20);
+		assert_eq!(spec.hostname, "fixture");
+		assert_eq!(spec.metadata_offset, Some(DEFAULT_METADATA_OFFSET));
+		let [boot, root] = &spec.partitions[..] else {
+			panic!("Expected two partitions");
+		};
+		assert_eq!((boot.start_sector, boot.end_sector), (2048, 616447));
+		assert_eq!(boot.filesystem, FilesystemType::Fat32);
+		assert_eq!(root.start_sector, 616448);
+		assert_eq!(root.end_sector, 1024 * 2048 - 34);
+		assert_eq!(root.filesystem, FilesystemType::Btrfs);
+		let spec = ResolvedSpec::resolve(&device, &ImageVariant::Base, None, Some(8192))?;
+		assert_eq!(spec.image_size, 8192 << 20);
+		assert_eq!(spec.partitions[1].filesystem, FilesystemType::Ext4);
+		let toml = spec.to_toml()?;
+		assert!(toml.contains("nominal_size = 8192 # MiB, from --image-size"));
+		assert!(toml.contains("# the rest of the image"));
+		assert_eq!(toml.matches("[[partitions]]").count(), 2);
+		Ok(())
+	}
+}
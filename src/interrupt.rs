@@ -0,0 +1,194 @@
+//! Cleaning up after Ctrl-C (SIGINT) and SIGTERM.
+//!
+//! A build interrupted halfway leaves the partitions of the raw image
+//! mounted and the raw image attached to a loop device, which would make
+//! the next build of the image fail until they are released.
+//!
+//! Each job registers the directory its filesystems are mounted under and
+//! the file it attaches to a loop device, with [`register`]. The
+//! registration lasts as long as the returned [`InterruptGuard`]. On
+//! interruption, for every registered job:
+//!
+//! 1. The filesystems mounted under the directory are unmounted, the
+//!    deepest first.
+//! 2. The loop devices the file is attached to are detached, after the
+//!    LUKS2 mappings on them are closed.
+//! 3. The output file being written, if any, is removed, so no truncated
+//!    image is left in the output directory.
+//!
+//! The mounts and the loop devices are found in `/proc/self/mounts` and
+//! `/sys/block`, like the leftovers of a build which died are (see
+//! [`release_leftovers`]). The raw image and the stage marker are kept, the
+//! finished stages can still be resumed with `--stages`.
+use std::{
+	fs,
+	path::{Path, PathBuf},
+	sync::{Mutex, MutexGuard},
+};
+
+use sys_mount::{unmount, UnmountFlags};
+
+use crate::utils::{loop_devices_backed_by, mounts_under, release_leftovers};
+
+/// What to clean up for a running job.
+struct ActiveJob {
+	key: usize,
+	/// The filesystems under this directory are unmounted.
+	mount_dir: Option<PathBuf>,
+	/// The loop devices this file is attached to are detached.
+	image: PathBuf,
+	/// The output file being written.
+	partial: Option<PathBuf>,
+}
+
+struct Registry {
+	next_key: usize,
+	jobs: Vec<ActiveJob>,
+}
+
+impl Registry {
+	/// Add a job, returns its key.
+	fn add(&mut self, mount_dir: Option<PathBuf>, image: PathBuf) -> usize {
+		let key = self.next_key;
+		self.next_key += 1;
+		self.jobs.push(ActiveJob {
+			key,
+			mount_dir,
+			image,
+			partial: None,
+		});
+		key
+	}
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+	next_key: 0,
+	jobs: Vec::new(),
+});
+
+/// Lock the registry, even if a job panicked while holding it.
+fn lock() -> MutexGuard<'static, Registry> {
+	REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Keeps the job registered for the cleanup, until dropped.
+pub struct InterruptGuard {
+	key: usize,
+}
+
+impl InterruptGuard {
+	fn with_job(&self, f: impl FnOnce(&mut ActiveJob)) {
+		if let Some(job) = lock().jobs.iter_mut().find(|j| j.key == self.key) {
+			f(job)
+		}
+	}
+
+	/// Remove the file on interruption, until [`Self::finish_writing`].
+	pub fn start_writing<P: AsRef<Path>>(&self, path: P) {
+		let path = path.as_ref().to_owned();
+		self.with_job(|j| j.partial = Some(path));
+	}
+
+	/// The file is complete, keep it.
+	pub fn finish_writing(&self) {
+		self.with_job(|j| j.partial = None);
+	}
+}
+
+impl Drop for InterruptGuard {
+	fn drop(&mut self) {
+		lock().jobs.retain(|j| j.key != self.key);
+	}
+}
+
+/// Register a job for the cleanup, see the [module documentation](self).
+pub fn register<P: AsRef<Path>>(mount_dir: Option<P>, image: P) -> InterruptGuard {
+	let key = lock().add(
+		mount_dir.map(|p| p.as_ref().to_owned()),
+		image.as_ref().to_owned(),
+	);
+	InterruptGuard { key }
+}
+
+/// Clean up after the registered jobs, called by the signal handler.
+///
+/// Errors are printed and skipped, to release as much as possible.
+pub fn cleanup() {
+	cleanup_jobs(&lock().jobs);
+}
+
+/// Clean up after `jobs`, see [`cleanup`].
+fn cleanup_jobs(jobs: &[ActiveJob]) {
+	for job in jobs {
+		let mounts = match &job.mount_dir {
+			Some(dir) => mounts_under(dir).unwrap_or_default(),
+			None => Vec::new(),
+		};
+		for mountpoint in &mounts {
+			eprintln!("Unmounting {} ...", mountpoint.display());
+			if let Err(e) = unmount(mountpoint, UnmountFlags::DETACH) {
+				eprintln!("Failed to unmount {}: {}", mountpoint.display(), e);
+			}
+		}
+		for dev in loop_devices_backed_by(&job.image) {
+			eprintln!("Detaching {} ...", dev.display());
+			if let Err(e) = release_leftovers(&[], &[dev]) {
+				eprintln!("{:#}", e);
+			}
+		}
+		if let Some(path) = &job.partial {
+			eprintln!("Removing the partially written {} ...", path.display());
+			fs::remove_file(path).ok();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	/// The file being written by the job of `guard`.
+	fn partial(guard: &InterruptGuard) -> Option<Option<PathBuf>> {
+		lock().jobs
+			.iter()
+			.find(|j| j.key == guard.key)
+			.map(|j| j.partial.clone())
+	}
+
+	#[test]
+	fn test_interrupt_guard() {
+		// Other tests register their jobs in parallel, only look at ours.
+		let guard = register(None, Path::new("rawmedia.img"));
+		assert_eq!(partial(&guard), Some(None));
+		guard.start_writing("image.img.xz");
+		assert_eq!(partial(&guard), Some(Some("image.img.xz".into())));
+		guard.finish_writing();
+		assert_eq!(partial(&guard), Some(None));
+		let key = guard.key;
+		drop(guard);
+		assert!(lock().jobs.iter().all(|j| j.key != key));
+	}
+
+	#[test]
+	fn test_interrupt_cleanup() -> anyhow::Result<()> {
+		let dir = TempDir::new("interrupt")?;
+		let image = dir.join("rawmedia.img");
+		let partial = dir.join("image.img.xz");
+		fs::write(&image, "")?;
+		fs::write(&partial, "")?;
+		// Clean up a registry of our own, the global one has the jobs of the other tests.
+		let mut registry = Registry {
+			next_key: 0,
+			jobs: Vec::new(),
+		};
+		let key = registry.add(Some(dir.to_path_buf()), image.clone());
+		registry.jobs[key].partial = Some(partial.clone());
+		// Not writing anything.
+		registry.add(None, image.clone());
+		cleanup_jobs(&registry.jobs);
+		assert!(!partial.exists());
+		assert!(image.exists());
+		Ok(())
+	}
+}
@@ -0,0 +1,247 @@
+//! Per-job log prefixes.
+//!
+//! Every [`ImageContext`](crate::context::ImageContext) owns a [`JobLogger`],
+//! which prefixes every log record it emits with the job it belongs to, e.g.
+//! `[rpi-5b/desktop]`. The prefix is painted with a color derived from the
+//! device ID, so the same device always gets the same color.
+//!
+//! While a job is active on the current thread (see [`JobLogger::enter`]),
+//! the output of the external commands is forwarded line by line with the
+//! same prefix (unpainted) by the [runner](crate::runner), if several jobs
+//! are running at the same time (see [`set_prefix_output`]). Otherwise the
+//! commands keep the terminal, so interactive ones still work.
+//!
+//! Each image being built also has a log file next to it,
+//! `<image file>.log`, receiving the log records of the job and the output
+//! of the commands it runs, prefixed and unpainted. The output shown
+//! directly on the terminal (a single job with `-v`) does not reach it.
+use std::{
+	cell::RefCell,
+	fs::File,
+	io::{self, Write},
+	path::Path,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex,
+	},
+};
+
+use anyhow::{Context, Result};
+
+use log::Level;
+use owo_colors::{AnsiColors, OwoColorize, Stream};
+
+use crate::context::ImageVariant;
+
+/// Colors of the prefixes. Red is reserved for errors.
+const PALETTE: &[AnsiColors] = &[
+	AnsiColors::Cyan,
+	AnsiColors::Magenta,
+	AnsiColors::Yellow,
+	AnsiColors::Green,
+	AnsiColors::Blue,
+	AnsiColors::BrightCyan,
+	AnsiColors::BrightMagenta,
+	AnsiColors::BrightYellow,
+	AnsiColors::BrightGreen,
+	AnsiColors::BrightBlue,
+];
+
+thread_local! {
+	/// The job active on this thread.
+	static CURRENT_JOB: RefCell<Option<JobLogger>> = const { RefCell::new(None) };
+}
+/// Whether the output of the commands is prefixed with the job.
+static PREFIX_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// The log file of a job, shared by the threads forwarding the output.
+///
+/// Writing to it does nothing until it is opened.
+#[derive(Clone, Debug, Default)]
+pub struct LogFile(Arc<Mutex<Option<File>>>);
+
+impl Write for LogFile {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if let Some(file) = self.0.lock().unwrap().as_mut() {
+			file.write_all(buf)?;
+		}
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match self.0.lock().unwrap().as_mut() {
+			Some(file) => file.flush(),
+			None => Ok(()),
+		}
+	}
+}
+
+/// Logger wrapper which prefixes the log records of a job.
+#[derive(Clone, Debug)]
+pub struct JobLogger {
+	prefix: String,
+	color: AnsiColors,
+	log_file: LogFile,
+}
+
+/// Keeps the job active on the current thread until dropped.
+pub struct JobGuard {
+	last: Option<JobLogger>,
+}
+
+impl JobLogger {
+	pub fn new(device_id: &str, variant: &ImageVariant) -> Self {
+		// FNV-1a, the color must not change between runs.
+		let hash = device_id.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+			(h ^ b as u64).wrapping_mul(0x100000001b3)
+		});
+		Self {
+			prefix: format!("[{}/{}]", device_id, variant.to_string().to_lowercase()),
+			color: PALETTE[(hash % PALETTE.len() as u64) as usize],
+			log_file: LogFile::default(),
+		}
+	}
+
+	/// Append the log records of the job and the output of its commands to the file.
+	pub fn open_log_file(&self, path: &Path) -> Result<()> {
+		let file = File::options()
+			.create(true)
+			.append(true)
+			.open(path)
+			.context(format!("Failed to open the log file '{}'", path.display()))?;
+		*self.log_file.0.lock().unwrap() = Some(file);
+		Ok(())
+	}
+
+	/// Writer appending the output of a command to the log file, with the prefix.
+	pub fn log_writer(&self) -> PrefixWriter<LogFile> {
+		PrefixWriter::new(self.log_file.clone(), self.prefix())
+	}
+
+	/// The unpainted prefix, e.g. `[rpi-5b/desktop]`.
+	pub fn prefix(&self) -> &str {
+		&self.prefix
+	}
+
+	/// The prefix painted with the color of the job, if colors are enabled.
+	pub fn painted(&self) -> String {
+		self.prefix
+			.if_supports_color(Stream::Stderr, |t| t.color(self.color))
+			.to_string()
+	}
+
+	pub fn log<S: AsRef<str>>(&self, level: Level, content: S) {
+		log::log!(level, "{} {}", self.painted(), content.as_ref());
+		// The log file is best effort, the record is shown anyway.
+		let _ = writeln!(self.log_writer(), "{}", content.as_ref());
+	}
+
+	pub fn info<S: AsRef<str>>(&self, content: S) {
+		self.log(Level::Info, content);
+	}
+
+	pub fn warn<S: AsRef<str>>(&self, content: S) {
+		self.log(Level::Warn, content);
+	}
+
+	/// Mark the job as active on the current thread, so the output of
+	/// external commands is prefixed.
+	pub fn enter(&self) -> JobGuard {
+		let last = CURRENT_JOB.with(|j| j.replace(Some(self.clone())));
+		JobGuard { last }
+	}
+}
+
+impl Drop for JobGuard {
+	fn drop(&mut self) {
+		CURRENT_JOB.with(|j| *j.borrow_mut() = self.last.take());
+	}
+}
+
+/// Get the prefix of the job active on the current thread.
+pub fn current_prefix() -> Option<String> {
+	CURRENT_JOB.with(|j| j.borrow().as_ref().map(|j| j.prefix().to_owned()))
+}
+
+/// Get the writer appending to the log file of the job active on the current thread.
+pub fn current_log_writer() -> Option<PrefixWriter<LogFile>> {
+	CURRENT_JOB.with(|j| j.borrow().as_ref().map(JobLogger::log_writer))
+}
+
+/// Prefix the output of the commands with the job, if several jobs are running.
+pub fn set_prefix_output(enabled: bool) {
+	PREFIX_OUTPUT.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether the output of the commands is prefixed with the job.
+pub fn prefix_output() -> bool {
+	PREFIX_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Writer which inserts the prefix at the start of every line.
+pub struct PrefixWriter<W: Write> {
+	inner: W,
+	prefix: String,
+	at_line_start: bool,
+}
+
+impl<W: Write> PrefixWriter<W> {
+	pub fn new<S: AsRef<str>>(inner: W, prefix: S) -> Self {
+		let prefix = prefix.as_ref();
+		Self {
+			inner,
+			// Pass through if there is no prefix.
+			prefix: if prefix.is_empty() {
+				String::new()
+			} else {
+				format!("{} ", prefix)
+			},
+			at_line_start: true,
+		}
+	}
+}
+
+impl<W: Write> Write for PrefixWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		for line in buf.split_inclusive(|&b| b == b'\n') {
+			if self.at_line_start {
+				self.inner.write_all(self.prefix.as_bytes())?;
+			}
+			self.inner.write_all(line)?;
+			self.at_line_start = line.ends_with(b"\n");
+		}
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_job_prefix() -> io::Result<()> {
+		let a = JobLogger::new("rpi-5b", &ImageVariant::Desktop);
+		let b = JobLogger::new("rpi-5b", &ImageVariant::Base);
+		assert_eq!(a.prefix(), "[rpi-5b/desktop]");
+		assert_eq!(a.color, b.color);
+		let mut buf = Vec::new();
+		let mut w = PrefixWriter::new(&mut buf, a.prefix());
+		w.write_all(b"one\ntw")?;
+		w.write_all(b"o\n\nthree")?;
+		assert_eq!(
+			String::from_utf8_lossy(&buf),
+			"[rpi-5b/desktop] one\n[rpi-5b/desktop] two\n[rpi-5b/desktop] \n[rpi-5b/desktop] three"
+		);
+		assert_eq!(current_prefix(), None);
+		{
+			let _job = a.enter();
+			assert_eq!(current_prefix().as_deref(), Some("[rpi-5b/desktop]"));
+		}
+		assert_eq!(current_prefix(), None);
+		Ok(())
+	}
+}
@@ -0,0 +1,401 @@
+//! Module handling the discovery and selection of installed kernels.
+//!
+//! Some BSPs install more than one kernel (e.g. mainline and a vendor
+//! kernel). The kernel to be used can be selected with [`KernelSpec`], and
+//! the selected kernel is exported to the post installation script and
+//! bootloader scripts.
+use std::{
+	cmp::Ordering,
+	fs::{self, File},
+	io::Write,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use serde::Deserialize;
+
+use crate::context::ImageContext;
+
+const MODULES_DIR: &str = "usr/lib/modules";
+const BOOT_DIR: &str = "boot";
+const DPKG_INFO_DIR: &str = "var/lib/dpkg/info";
+/// Prefixes of kernel images under `/boot`, in the order of preference.
+const IMAGE_PREFIXES: &[&str] = &["vmlinuz-", "vmlinux-", "Image-"];
+/// Names of kernel images in the modules directory, in the order of preference.
+const MODULES_IMAGE_NAMES: &[&str] = &["vmlinuz", "vmlinux", "Image"];
+/// Prefixes and suffixes of the initramfs under `/boot`, around the kernel version.
+const INITRD_NAMES: &[(&str, &str)] =
+	&[("initrd.img-", ""), ("initramfs-", ".img"), ("initrd-", "")];
+/// Name of the initramfs in the modules directory.
+const MODULES_INITRD_NAME: &str = "initrd";
+
+/// Selects which kernel to use if more than one kernel is installed.
+///
+/// If not specified, the kernel with the highest version is used.
+/// The build fails if none of the installed kernels matches.
+///
+/// Usage
+/// -----
+///
+/// Select the kernel shipped by a specific package:
+///
+/// ```toml
+/// [kernel]
+/// flavor = "linux-kernel-rockchip"
+/// ```
+///
+/// Or select the kernel by its version, `*` and `?` are supported:
+///
+/// ```toml
+/// [kernel]
+/// version = "6.1.*-rockchip"
+/// ```
+///
+/// Both can be specified, in that case both must match.
+///
+/// The selected kernel is available to the post installation script and
+/// bootloader scripts as `KERNEL_VERSION`, `KERNEL_IMAGE` and `KERNEL_MODULES`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct KernelSpec {
+	/// Name of the package which ships the kernel.
+	pub flavor: Option<String>,
+	/// Glob pattern of the kernel version.
+	pub version: Option<String>,
+}
+
+/// A kernel installed in the target.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KernelInfo {
+	pub version: String,
+	/// Path to the kernel image, within the target.
+	pub image: PathBuf,
+	/// Path to the modules directory, within the target.
+	pub modules: PathBuf,
+}
+
+/// Match the string against a glob pattern which supports `*` and `?`.
+fn glob_match(pattern: &str, s: &str) -> bool {
+	let p = pattern.chars().collect::<Vec<_>>();
+	let s = s.chars().collect::<Vec<_>>();
+	let (mut pi, mut si) = (0, 0);
+	// Position of the last `*`, and where it started matching in s.
+	let mut star = None;
+	while si < s.len() {
+		if pi < p.len() && (p[pi] == '?' || p[pi] == s[si]) {
+			pi += 1;
+			si += 1;
+		} else if pi < p.len() && p[pi] == '*' {
+			star = Some((pi, si));
+			pi += 1;
+		} else if let Some((sp, ss)) = star {
+			pi = sp + 1;
+			si = ss + 1;
+			star = Some((sp, ss + 1));
+		} else {
+			return false;
+		}
+	}
+	p[pi..].iter().all(|&c| c == '*')
+}
+
+/// Compare two kernel versions, numeric parts are compared as numbers.
+fn version_cmp(a: &str, b: &str) -> Ordering {
+	let split = |s: &str| {
+		let mut parts = Vec::new();
+		let mut cur = String::new();
+		for c in s.chars() {
+			if !cur.is_empty()
+				&& cur.chars().last().unwrap().is_ascii_digit()
+					!= c.is_ascii_digit()
+			{
+				parts.push(std::mem::take(&mut cur));
+			}
+			cur.push(c);
+		}
+		parts.push(cur);
+		parts
+	};
+	let (a, b) = (split(a), split(b));
+	for (x, y) in a.iter().zip(b.iter()) {
+		let ord = match (x.parse::<u64>(), y.parse::<u64>()) {
+			(Ok(x), Ok(y)) => x.cmp(&y),
+			_ => x.cmp(y),
+		};
+		if ord != Ordering::Equal {
+			return ord;
+		}
+	}
+	a.len().cmp(&b.len())
+}
+
+/// Find all kernels installed in the target root, sorted by their versions.
+///
+/// A kernel is a pair of `/usr/lib/modules/<version>` and `/boot/vmlinu*-<version>`,
+/// or `/usr/lib/modules/<version>/vmlinu*` if the image is not in `/boot`.
+pub fn discover_kernels(root: &Path) -> Result<Vec<KernelInfo>> {
+	let modules_dir = root.join(MODULES_DIR);
+	let mut kernels = Vec::new();
+	if !modules_dir.is_dir() {
+		return Ok(kernels);
+	}
+	for entry in fs::read_dir(&modules_dir)? {
+		let entry = entry?;
+		if !entry.file_type()?.is_dir() {
+			continue;
+		}
+		let version = entry.file_name().to_string_lossy().to_string();
+		let image = IMAGE_PREFIXES
+			.iter()
+			.map(|p| Path::new(BOOT_DIR).join(format!("{}{}", p, version)))
+			.chain(MODULES_IMAGE_NAMES
+				.iter()
+				.map(|n| Path::new(MODULES_DIR).join(&version).join(n)))
+			.find(|p| root.join(p).is_file());
+		if let Some(image) = image {
+			kernels.push(KernelInfo {
+				image: Path::new("/").join(image),
+				modules: Path::new("/").join(MODULES_DIR).join(&version),
+				version,
+			});
+		} else {
+			debug!("Kernel {} has no image, skipping", version);
+		}
+	}
+	kernels.sort_by(|a, b| version_cmp(&a.version, &b.version));
+	Ok(kernels)
+}
+
+/// Get the kernel versions shipped by the package, from the dpkg database.
+fn package_kernel_versions(root: &Path, package: &str) -> Result<Vec<String>> {
+	let info_dir = root.join(DPKG_INFO_DIR);
+	let list = fs::read_dir(&info_dir)
+		.context("Failed to read the dpkg database")?
+		.flatten()
+		.map(|e| e.path())
+		.find(|p| {
+			let name = p.file_name().unwrap_or_default().to_string_lossy();
+			name == format!("{}.list", package)
+				|| (name.starts_with(&format!("{}:", package))
+					&& name.ends_with(".list"))
+		})
+		.context(format!("Package '{}' is not installed", package))?;
+	let content = fs::read_to_string(&list)?;
+	let prefix = format!("/{}/", MODULES_DIR);
+	let mut versions = content
+		.lines()
+		.filter_map(|l| l.strip_prefix(&prefix))
+		.filter_map(|l| l.split('/').next())
+		.filter(|l| !l.is_empty())
+		.map(str::to_owned)
+		.collect::<Vec<_>>();
+	versions.dedup();
+	Ok(versions)
+}
+
+impl KernelSpec {
+	/// Select the kernel matching this spec, the one with the highest version wins.
+	pub fn select(&self, root: &Path, kernels: &[KernelInfo]) -> Result<KernelInfo> {
+		let versions = if let Some(flavor) = &self.flavor {
+			Some(package_kernel_versions(root, flavor)?)
+		} else {
+			None
+		};
+		let selected = kernels.iter().rev().find(|k| {
+			if let Some(v) = &versions {
+				if !v.contains(&k.version) {
+					return false;
+				}
+			}
+			if let Some(pattern) = &self.version {
+				if !glob_match(pattern, &k.version) {
+					return false;
+				}
+			}
+			true
+		});
+		match selected {
+			Some(k) => Ok(k.clone()),
+			None => bail!(
+				"None of the installed kernels ({}) matches the kernel selection {:?}",
+				kernels.iter().map(|k| k.version.as_str()).collect::<Vec<_>>().join(", "),
+				self
+			),
+		}
+	}
+}
+
+impl KernelInfo {
+	/// Find the initramfs of the kernel in the target, returns its path within the target.
+	pub fn initrd(&self, root: &Path) -> Option<PathBuf> {
+		INITRD_NAMES
+			.iter()
+			.map(|(prefix, suffix)| {
+				Path::new(BOOT_DIR)
+					.join(format!("{}{}{}", prefix, self.version, suffix))
+			})
+			.chain([self
+				.modules
+				.strip_prefix("/")
+				.unwrap_or(&self.modules)
+				.join(MODULES_INITRD_NAME)])
+			.find(|p| root.join(p).is_file())
+			.map(|p| Path::new("/").join(p))
+	}
+
+	/// Make sure the referenced files exist in the target.
+	pub fn verify(&self, root: &Path) -> Result<()> {
+		for path in [&self.image, &self.modules] {
+			let full_path = root.join(path.strip_prefix("/")?);
+			if !full_path.exists() {
+				bail!("Kernel {}: {} does not exist", self.version, path.display());
+			}
+		}
+		Ok(())
+	}
+}
+
+impl ImageContext<'_> {
+	/// Find the kernel to be used in the target, `None` if no kernel is installed.
+	pub(crate) fn find_kernel(&self, root: &Path) -> Result<Option<KernelInfo>> {
+		let kernels = discover_kernels(root)?;
+		let kernel = match &self.device.kernel {
+			Some(spec) => spec.select(root, &kernels)?,
+			None => match kernels.last() {
+				Some(k) => k.clone(),
+				None => return Ok(None),
+			},
+		};
+		kernel.verify(root)?;
+		Ok(Some(kernel))
+	}
+
+	/// Select the kernel to be used, and export it to the spec script.
+	pub fn select_kernel<P: AsRef<Path>>(&self, root: P) -> Result<Option<KernelInfo>> {
+		let root = root.as_ref();
+		let Some(kernel) = self.find_kernel(root)? else {
+			debug!("No kernel found in the target");
+			return Ok(None);
+		};
+		self.info(format!("Using kernel {}", &kernel.version));
+		let script = format!(
+			"KERNEL_VERSION='{}'\nKERNEL_IMAGE='{}'\nKERNEL_MODULES='{}'\n",
+			&kernel.version,
+			&kernel.image.display(),
+			&kernel.modules.display()
+		);
+		let mut fd = File::options()
+			.append(true)
+			.open(root.join("tmp/spec.sh"))
+			.context("Failed to open the spec script")?;
+		fd.write_all(script.as_bytes())?;
+		fd.sync_all()?;
+		Ok(Some(kernel))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	fn fake_root(name: &str) -> Result<TempDir> {
+		let root = TempDir::new(name)?;
+		for (version, image) in [
+			("6.12.3-aosc-main", "vmlinuz"),
+			("6.1.75-rockchip", "vmlinux"),
+			("6.1.9-rockchip", "Image"),
+			("5.15.0-orphan", ""),
+		] {
+			fs::create_dir_all(root.join(MODULES_DIR).join(version).join("kernel"))?;
+			fs::create_dir_all(root.join(BOOT_DIR))?;
+			if !image.is_empty() {
+				fs::write(
+					root.join(BOOT_DIR).join(format!("{}-{}", image, version)),
+					"",
+				)?;
+			}
+		}
+		fs::create_dir_all(root.join(DPKG_INFO_DIR))?;
+		fs::write(
+			root.join(DPKG_INFO_DIR).join("linux-kernel-rockchip:arm64.list"),
+			"/.\n/usr/lib/modules\n/usr/lib/modules/6.1.9-rockchip\n/usr/lib/modules/6.1.9-rockchip/kernel\n/boot/Image-6.1.9-rockchip\n",
+		)?;
+		Ok(root)
+	}
+
+	#[test]
+	fn test_glob_and_version() {
+		assert!(glob_match("6.1.*-rockchip", "6.1.75-rockchip"));
+		assert!(glob_match("6.?.9-*", "6.1.9-rockchip"));
+		assert!(!glob_match("6.1.*", "6.12.3-aosc-main"));
+		assert_eq!(version_cmp("6.1.75", "6.1.9"), Ordering::Greater);
+		assert_eq!(version_cmp("6.12.3", "6.1.75"), Ordering::Greater);
+	}
+
+	#[test]
+	fn test_select_kernel() -> Result<()> {
+		let root = fake_root("kernel")?;
+		let kernels = discover_kernels(&root)?;
+		let versions = kernels
+			.iter()
+			.map(|k| k.version.as_str())
+			.collect::<Vec<_>>();
+		assert_eq!(
+			versions,
+			["6.1.9-rockchip", "6.1.75-rockchip", "6.12.3-aosc-main"]
+		);
+		// The newest is selected by default.
+		let k = KernelSpec::default().select(&root, &kernels)?;
+		assert_eq!(k.version, "6.12.3-aosc-main");
+		assert_eq!(k.image, Path::new("/boot/vmlinuz-6.12.3-aosc-main"));
+		let k = KernelSpec {
+			version: Some("6.1.*".into()),
+			..Default::default()
+		}
+		.select(&root, &kernels)?;
+		assert_eq!(k.version, "6.1.75-rockchip");
+		let k = KernelSpec {
+			flavor: Some("linux-kernel-rockchip".into()),
+			..Default::default()
+		}
+		.select(&root, &kernels)?;
+		assert_eq!(k.version, "6.1.9-rockchip");
+		assert_eq!(k.image, Path::new("/boot/Image-6.1.9-rockchip"));
+		k.verify(&root)?;
+		let none = KernelSpec {
+			flavor: Some("linux-kernel-rockchip".into()),
+			version: Some("6.12.*".into()),
+		};
+		assert!(none.select(&root, &kernels).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_modules_image_and_initrd() -> Result<()> {
+		let root = TempDir::new("kernel-modules")?;
+		let modules = root.join(MODULES_DIR).join("6.6.0-fedora");
+		fs::create_dir_all(&modules)?;
+		fs::create_dir_all(root.join(BOOT_DIR))?;
+		fs::write(modules.join("vmlinuz"), "")?;
+		let kernels = discover_kernels(&root)?;
+		assert_eq!(kernels.len(), 1);
+		assert_eq!(
+			kernels[0].image,
+			Path::new("/usr/lib/modules/6.6.0-fedora/vmlinuz")
+		);
+		assert_eq!(kernels[0].initrd(&root), None);
+		fs::write(modules.join("initrd"), "")?;
+		assert_eq!(
+			kernels[0].initrd(&root),
+			Some(PathBuf::from("/usr/lib/modules/6.6.0-fedora/initrd"))
+		);
+		// The one in /boot is preferred.
+		fs::write(root.join(BOOT_DIR).join("initramfs-6.6.0-fedora.img"), "")?;
+		assert_eq!(
+			kernels[0].initrd(&root),
+			Some(PathBuf::from("/boot/initramfs-6.6.0-fedora.img"))
+		);
+		Ok(())
+	}
+}
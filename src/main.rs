@@ -21,6 +21,7 @@
 //! - `useradd` from shadow: For adding user to the target container.
 //! - `chpasswd` from shadow: For changing user passwords.
 //! - `partprobe`: For updating the in-kernel partition table cache.
+//! - `mkswap`, `chattr`: For creating swap files, if defined in the device specification.
 //!
 //! ### `binfmt_misc` support and respective binary interpreters
 //!
@@ -119,33 +120,153 @@
 // I have some sample code from the Linux kernel in my docstrings.
 // Clippy warns me about the tabs, this is denial!
 #![allow(clippy::tabs_in_doc_comments)]
+/// Module checking the images to be produced.
+mod artifact;
 mod bootloader;
+/// Module compiling the U-Boot boot scripts.
+mod bootscr;
+/// Module creating the subvolumes of the btrfs partitions.
+mod btrfs;
+/// Module running commands inside the target.
+mod chroot;
+/// Module cleaning up the working directory.
+mod clean;
 mod cli;
+/// Module resolving the kernel command line.
+mod cmdline;
+/// Module generating the shell completions and the man page.
+mod completion;
+/// Module reading the defaults of the options from a configuration file.
+mod config;
+/// Module writing the raw contents of the partitions without a filesystem.
+mod content;
 /// Module handling the actual generation jobs.
 #[doc(hidden)]
 mod context;
+/// Module running the customize scripts.
+mod customize;
 mod device;
+/// Module copying the device tree blobs into the boot partition.
+mod dtb;
+/// Module encrypting the partitions with LUKS2.
+mod encryption;
+/// Module building the EROFS root filesystems, and their overlays.
+mod erofs;
+/// Module tuning the ext4 filesystems.
+mod ext4;
+/// Module resolving the inheritance of the device specifications.
+mod extends;
+/// Module generating the extlinux.conf for U-Boot.
+mod extlinux;
+/// Module downloading the bootloader blobs.
+mod fetch;
 /// Module handling the filesystems.
 #[doc(hidden)]
 mod filesystem;
+/// Module checking the filesystems of the images.
+mod fsck;
+/// Module referring to the partitions in `/etc/fstab`.
+mod fstab;
+/// Module installing GRUB for the EFI devices.
+mod grub;
+/// Module writing the hybrid MBR of the GPT disks.
+mod hybrid;
+/// Module resolving the device specifications for `inspect`.
+mod inspect;
+/// Module cleaning up after Ctrl-C.
+mod interrupt;
+/// Module prefixing the log records of the jobs.
+#[doc(hidden)]
+mod joblog;
+/// Module handling the installed kernels.
+mod kernel;
+/// Module handling the capacities of the storage media.
+mod media;
+/// Module handling the image metadata.
+mod metadata;
+/// Module mapping the architectures to the mirrors.
+mod mirror;
+/// Module naming the images.
+mod naming;
 /// Module handling the partitions.
 mod partition;
+/// Module handling the paths to be created.
+mod paths;
+/// Module picking the device interactively.
+mod picker;
+/// Module printing the build plans.
+mod plan;
 /// Module handling the package installation.
 #[doc(hidden)]
 mod pm;
+/// Module populating the filesystems without mounting them.
+mod populate;
+/// Module checking the host before building.
+mod preflight;
+/// Module executing the job queue.
+mod queue;
+/// Module writing the raw bootloader images to the image.
+mod raw;
+/// Module resolving the recipes of the bootstrapped distributions.
+mod recipe;
 mod registry;
+/// Module writing the build report of the images.
+mod report;
+/// Module keeping the reserved regions out of the partition layout.
+mod reserved;
+/// Module pruning the old images in the output directory.
+mod retention;
+/// Module installing the system distributions from tarballs and OCI images.
+mod rootfs;
+/// Module running the external commands.
+#[doc(hidden)]
+mod runner;
+/// Module installing systemd-boot for the EFI devices.
+mod sdboot;
+/// Module deriving the identifiers of the images from a seed.
+mod seed;
+/// Module handling the systemd services.
+mod services;
+/// Module shrinking the images to their contents.
+mod shrink;
+/// Module parsing the sizes with units.
+mod size;
+/// Module expanding the A/B slots of the partitions.
+mod slots;
+/// Module running the smoke tests of the device specifications.
+mod smoke;
+/// Module installing the SSH public keys.
+mod sshkey;
+/// Module running only part of the build pipeline.
+mod stage;
+/// Module finding the unknown keys in the device specifications.
+mod strict;
+/// Module writing the SPL of the Allwinner SoCs.
+mod sunxi;
+/// Module handling the swap space.
+mod swap;
+/// Module handling the system distributions built elsewhere.
+mod sysroot;
 #[doc(hidden)]
 mod tests;
+/// Module sharing the fixtures of the tests.
+#[doc(hidden)]
+mod testutil;
 #[doc(hidden)]
 mod topics;
 /// Module containing various utility functions.
 #[doc(hidden)]
 mod utils;
+/// Module expanding the variables in the device specifications.
+mod variables;
+/// Module verifying the partition tables written to the images.
+mod verify;
 
 pub use cli::Cmdline;
 pub use device::DeviceSpec;
 use topics::{fetch_topics, filter_topics};
 
+use artifact::check_artifacts;
 use core::time;
 use std::{
 	env::var,
@@ -157,15 +278,38 @@ use std::{
 use anyhow::bail;
 use anyhow::{anyhow, Context, Result};
 use chrono::Utc;
-use clap::Parser;
+use clean::{clean_workdir, format_size};
 use cli::Action;
-use cli::RootFsType;
-use context::{ImageContext, ImageContextQueue};
+use cli::ColorMode;
+use cli::Compression;
+use cli::InspectFormat;
+use cli::KeepWorkdir;
+use completion::{generate_completions, generate_manpage};
+use context::{ImageContext, ImageContextQueue, ImageVariant};
+use customize::collect_customize_scripts;
 use filesystem::FilesystemType;
+use inspect::ResolvedSpec;
+use joblog::JobLogger;
 use log::{debug, error, info, warn};
+use metadata::{is_image, ImageMetadata};
+use mirror::Mirror;
+use naming::{find_built_image, image_name, next_revision, NameTemplate, Revision};
 use owo_colors::colored::*;
-use registry::DeviceRegistry;
-use utils::{bootstrap_distribution, check_binfmt, restore_term, return_ownership_recursive};
+use populate::PopulateBackend;
+use queue::{execute_queue, SUMMARY_TARGET};
+use recipe::{BootstrapRecipe, AB_DIR};
+use registry::{skip_deprecated, DeviceFilter, DeviceRegistry};
+use report::BuildReport;
+use runner::RunnerMode;
+use sshkey::load_ssh_keys;
+use stage::Stage;
+use strum::VariantArray;
+use sysroot::{arch_matches, Sysroot};
+use utils::{
+	bootstrap_distribution, check_binfmt, check_build_id, check_hostname, check_password_hash,
+	check_timezone, check_timezone_name, expire_password_by_default, generate_build_id,
+	restore_term, return_ownership_recursive, set_progress_bar, DEFAULT_PASSWORD,
+};
 
 #[doc(hidden)]
 enum BuildMode {
@@ -183,7 +327,8 @@ const DISTRO_REGISTRY_DIR: &str = match option_env!("DISTRO_REGISTRY_DIR") {
 fn main() -> Result<()> {
 	ctrlc::set_handler(move || {
 		restore_term();
-		eprintln!("\nReceived Ctrl-C, exiting.");
+		eprintln!("\nInterrupted, cleaning up ...");
+		interrupt::cleanup();
 
 		std::process::exit(1);
 	})
@@ -192,27 +337,64 @@ fn main() -> Result<()> {
 	std::env::set_var("LANG", "C");
 	std::env::set_var("LC_ALL", "C");
 
-	// Parse the command line
-	let cmdline = Cmdline::try_parse()?;
+	// Parse the command line, merged with the configuration file
+	let (cmdline, config) = config::parse_cmdline(std::env::args_os())?;
 	match &cmdline.action {
-		Action::Build { .. } | Action::BuildAll { .. } => {
+		Action::Config { print } => return config.show(*print),
+		Action::GenerateCompletions { shell } => return generate_completions(*shell),
+		Action::GenerateManpage => return generate_manpage(),
+		_ => (),
+	}
+	match &cmdline.action {
+		Action::Build { dry_run: false, .. }
+		| Action::BuildAll { dry_run: false, .. }
+		| Action::Partition { .. }
+		| Action::Check { smoke: true, .. }
+		| Action::Clean { .. } => {
 			if unsafe { utils::geteuid() } != 0 {
 				bail!("Please run me as root!");
 			}
 		}
 		_ => (),
 	}
-	let mut logger = colog::basic_builder();
-	if cmdline.debug {
-		logger.filter(None, log::LevelFilter::Debug);
-	} else {
-		logger.filter(None, log::LevelFilter::Info);
+	match cmdline.color {
+		ColorMode::Auto => (),
+		ColorMode::Always => {
+			owo_colors::set_override(true);
+			std::env::set_var("CLICOLOR_FORCE", "1");
+		}
+		ColorMode::Never => {
+			owo_colors::set_override(false);
+			std::env::set_var("NO_COLOR", "1");
+		}
 	}
+	let level = cmdline.log_level();
+	let mut logger = colog::basic_builder();
+	logger.filter(None, level);
+	logger.filter(Some(SUMMARY_TARGET), level.max(log::LevelFilter::Info));
 	logger.init();
-	if cmdline.debug {
-		debug!("Debug output enabled.");
+	debug!("Debug output enabled.");
+	let build_id = match &cmdline.build_id {
+		Some(id) => {
+			check_build_id(id)?;
+			id.to_owned()
+		}
+		None => generate_build_id(),
+	};
+	runner::set_build_id(&build_id);
+	chroot::set_backend(cmdline.chroot_backend);
+	runner::register_secret(&cmdline.password);
+	if let Some(dir) = &cmdline.record {
+		info!("Recording external commands into '{}'.", dir.display());
+		runner::set_mode(RunnerMode::Record(dir.to_owned()))?;
+	} else if let Some(dir) = &cmdline.replay {
+		warn!(
+			"Replaying external commands recorded in '{}'.",
+			dir.display()
+		);
+		runner::set_mode(RunnerMode::Replay(dir.to_owned()))?;
 	}
-	if let Err(e) = try_main(cmdline) {
+	if let Err(e) = try_main(cmdline, &build_id).and_then(|_| runner::finish()) {
 		// Recover the terminal
 		restore_term();
 		// Use logger to pretty-print errors
@@ -227,16 +409,33 @@ fn main() -> Result<()> {
 		if !str_buf.is_empty() {
 			error!("{}", str_buf);
 		}
-		error!("Exiting now.");
+		error!("Build {} failed. Exiting now.", &build_id);
 		std::process::exit(1);
 	}
+	info!("Build {} finished.", &build_id);
 	Ok(())
 }
 
 #[doc(hidden)]
-fn try_main(cmdline: Cmdline) -> Result<()> {
+fn try_main(cmdline: Cmdline, build_id: &str) -> Result<()> {
 	// Say hi
 	info!("Welcome to mkrawimg!");
+	info!("Build ID: {}", build_id.bright_cyan());
+	// Inspecting an image does not need the registry.
+	if let cli::Action::Inspect { target, .. } = &cmdline.action {
+		let path = Path::new(target);
+		if is_image(path)? {
+			let metadata = ImageMetadata::read_from(path)?;
+			println!("{}", metadata);
+			return Ok(());
+		}
+	}
+	if let cli::Action::Clean { bootstrap } = &cmdline.action {
+		info!("Cleaning up {} ...", cmdline.workdir.display());
+		let reclaimed = clean_workdir(&cmdline.workdir, *bootstrap, cmdline.force)?;
+		info!("Done! {} reclaimed.", format_size(reclaimed));
+		return Ok(());
+	}
 	// Operation mode: build, buildall, test.
 	let action = cmdline.action;
 	let mut buildmode = BuildMode::None;
@@ -273,18 +472,109 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 			registry_dir.unwrap_err().bright_red()
 		));
 	};
+	if let cli::Action::Check {
+		device,
+		smoke: false,
+		strict,
+	} = &action
+	{
+		info!("Checking validity of the registry ...");
+		let results = match device {
+			Some(d) if Path::new(d).exists() || registry_dir.join(d).exists() => {
+				let path = if Path::new(d).exists() {
+					PathBuf::from(d)
+				} else {
+					registry_dir.join(d)
+				};
+				let file = if path.is_dir() {
+					path.join("device.toml")
+				} else {
+					path
+				};
+				DeviceRegistry::check_files(&[file], *strict)
+			}
+			Some(d) => {
+				// Check the whole registry for the collisions of IDs and aliases.
+				let files = DeviceRegistry::find_spec_files(&registry_dir)?;
+				let results = DeviceRegistry::check_files(&files, *strict);
+				let failed = results.iter().filter(|r| r.device.is_none()).count();
+				let results = results
+					.into_iter()
+					.filter(|r| r.matches(d))
+					.collect::<Vec<_>>();
+				if results.is_empty() {
+					bail!(
+						"Can't find a device with provided ID or alias '{}' ({} files can not be parsed)",
+						d,
+						failed
+					);
+				}
+				results
+			}
+			None => {
+				let files = DeviceRegistry::find_spec_files(&registry_dir)?;
+				let results = DeviceRegistry::check_files(&files, *strict);
+				for (tag, id) in DeviceRegistry::single_use_tags(&results) {
+					warn!(
+						"Tag '{}' of {} is not used by any other device, is it a typo?",
+						tag, id
+					);
+				}
+				results
+			}
+		};
+		if results.is_empty() {
+			bail!("Device registry contains no device.");
+		}
+		let failed = DeviceRegistry::print_check_report(&results);
+		if device.is_some() {
+			for d in results
+				.iter()
+				.filter(|r| r.errors.is_empty())
+				.filter_map(|r| r.device.as_ref())
+			{
+				for variant in ImageVariant::VARIANTS {
+					info!(
+						"BSP packages of the {} variant of {}: {}",
+						variant.to_string().to_lowercase(),
+						d.id,
+						d.bsp_packages.get(variant).join(" ")
+					);
+				}
+			}
+		}
+		if failed > 0 {
+			std::process::exit(failed.min(125) as i32);
+		}
+		return Ok(());
+	}
 	let device_str = match &action {
 		cli::Action::Build { ref device, .. } => {
 			buildmode = BuildMode::BuildOne;
-			Some(device.to_owned())
+			match device {
+				Some(device) => Some(device.to_owned()),
+				None if picker::interactive() => {
+					let entries = DeviceRegistry::list_entries(&registry_dir)?;
+					Some(picker::pick_device(&entries)?)
+				}
+				None => bail!("No device specified. Specify the ID, alias or path of the device to build images for."),
+			}
 		}
 		cli::Action::BuildAll { .. } => {
 			warn!("Attempting to build images for all devices. Make sure this is what you want to do.");
 			buildmode = BuildMode::BuildAll;
 			None
 		}
-		cli::Action::Check { device } => device.as_ref().map(|d| d.to_owned()),
+		cli::Action::Partition { ref device, .. } => Some(device.to_owned()),
+		cli::Action::Check { device, .. } => device.as_ref().map(|d| d.to_owned()),
 		cli::Action::List { .. } => None,
+		cli::Action::Inspect { ref target, .. } => Some(target.to_owned()),
+		cli::Action::Clean { .. }
+		| cli::Action::Config { .. }
+		| cli::Action::GenerateCompletions { .. }
+		| cli::Action::GenerateManpage => {
+			unreachable!("Handled above")
+		}
 	};
 	let registry = if let Some(device_str) = &device_str {
 		let try_path = Path::new(&device_str);
@@ -300,36 +590,125 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 	} else {
 		DeviceRegistry::scan(registry_dir)?
 	};
+	let filter = match &action {
+		cli::Action::BuildAll {
+			arch,
+			vendor,
+			tag,
+			any_tag,
+			..
+		} => DeviceFilter {
+			arch: arch.clone(),
+			vendor: vendor.clone(),
+			tags: tag.clone(),
+			any_tag: *any_tag,
+		},
+		_ => DeviceFilter::default(),
+	};
+	let continue_on_error = matches!(
+		&action,
+		cli::Action::BuildAll {
+			continue_on_error: true,
+			..
+		}
+	);
+	let retention = match &action {
+		cli::Action::BuildAll {
+			retention: Some(policy),
+			retention_dry_run,
+			..
+		} => Some((policy.clone(), *retention_dry_run)),
+		_ => None,
+	};
 	match action {
 		cli::Action::Build {
 			fstype,
 			compression: compress,
+			compress_level,
+			compress_threads,
 			variants,
 			revision,
 			additional_packages,
 			topics,
+			image_size,
+			round_to,
+			trailing_pad,
+			shrink,
+			no_fsck,
+			populate_backend,
+			seed,
+			dry_run,
+			jobs,
+			allow_deprecated,
 			..
 		}
 		| cli::Action::BuildAll {
 			fstype,
 			compression: compress,
+			compress_level,
+			compress_threads,
 			variants,
 			revision,
 			additional_packages,
 			topics,
+			image_size,
+			round_to,
+			trailing_pad,
+			shrink,
+			no_fsck,
+			populate_backend,
+			seed,
+			dry_run,
+			jobs,
+			allow_deprecated,
+			..
 		} => {
-			let fstype = match fstype {
-				Some(RootFsType::Ext4) => Some(FilesystemType::Ext4),
-				Some(RootFsType::Btrfs) => Some(FilesystemType::Btrfs),
-				Some(RootFsType::Xfs) => Some(FilesystemType::Xfs),
-				_ => None,
-			};
+			let fstype = fstype.map(FilesystemType::from);
+			if jobs == 0 {
+				bail!("--jobs must be at least 1.");
+			}
+			compress.check_options(compress_level, compress_threads)?;
+			// The recordings must be replayed in order.
+			if jobs > 1 {
+				if cmdline.record.is_some() {
+					bail!("--jobs can not be used with --record, the commands must run one at a time.");
+				}
+				if cmdline.replay.is_some() {
+					bail!("--jobs can not be used with --replay, the commands must run one at a time.");
+				}
+			}
 			let date = Utc::now();
-			let date_str = date.format("%Y%m%d");
+			let date_str = date.format("%Y%m%d").to_string();
+			let default_template = NameTemplate::default();
+			let mut deprecated_skipped = 0;
 			let devices = match buildmode {
-				BuildMode::BuildAll => registry.get_all()?,
+				BuildMode::BuildAll => {
+					let (devices, skipped) = registry.get_filtered(&filter)?;
+					if skipped > 0 {
+						info!(
+							"{} devices are skipped by the filter.",
+							skipped.bright_cyan()
+						);
+					}
+					let (devices, deprecated) =
+						skip_deprecated(devices, allow_deprecated);
+					for d in &deprecated {
+						info!(
+							"Skipping deprecated device {}: {}",
+							d.id,
+							d.deprecation()
+						);
+					}
+					deprecated_skipped = deprecated.len();
+					if devices.is_empty() {
+						bail!("All of the matching devices are deprecated, use --allow-deprecated to build them.");
+					}
+					devices
+				}
 				BuildMode::BuildOne => {
-					let v = vec![registry.get(device_str.as_ref().unwrap())?];
+					let device = registry.get(device_str.as_ref().unwrap())?;
+					device.check_deprecated(allow_deprecated)?;
+					let v = vec![device];
 					// Since we need to try to get a device with that name first.
 					info!(
 						"Going to build images for device '{}'.",
@@ -341,6 +720,9 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 					panic!("Should not go here");
 				}
 			};
+			for device in &devices {
+				device.check_unknown_keys(false)?;
+			}
 			let topics = if let Some(topics) = topics.as_ref() {
 				let all_topics = fetch_topics()?;
 				let filtered_topics = filter_topics(topics, all_topics)?;
@@ -351,99 +733,409 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 			let topics = topics.as_ref();
 			// Prepare to build
 			info!("Preparing build ...");
-			std::fs::create_dir_all(&cmdline.workdir)?;
-			std::fs::create_dir_all(&cmdline.outdir)?;
+			let backend = chroot::backend();
+			info!(
+				"Running commands inside the target with {}.",
+				backend.program()
+			);
+			// Nothing is run while replaying.
+			if cmdline.replay.is_none() && !dry_run {
+				backend.check()?;
+			}
+			if !dry_run {
+				std::fs::create_dir_all(&cmdline.workdir)?;
+				std::fs::create_dir_all(&cmdline.outdir)?;
+			}
+			let variants = variants.as_slice();
+			// Make sure aoscbootstrap has everything, before anything is built.
+			let mut recipes: Vec<BootstrapRecipe> = Vec::new();
+			let mut sysroots: Vec<&Sysroot> = Vec::new();
+			if let Some(source) = &cmdline.rootfs_source {
+				if variants.len() != 1 {
+					bail!("--rootfs-source holds a single variant, select it with --variants.");
+				}
+				info!(
+					"Using {} as the {} distribution.",
+					source.path.display(),
+					variants[0].to_string().to_lowercase()
+				);
+				// Nothing is read while replaying.
+				if cmdline.replay.is_none() {
+					let arch = source.probe_arch()?;
+					if let Some(device) =
+						devices.iter().find(|d| !arch_matches(arch, d.arch))
+					{
+						bail!(
+							"Rootfs source {} is a distribution for {}, but {} is a {} device.",
+							source.path.display(),
+							arch.to_string().to_lowercase(),
+							device.id,
+							device.arch.to_string().to_lowercase()
+						);
+					}
+				}
+			}
+			for variant in variants {
+				for device in devices.as_slice() {
+					if cmdline.rootfs_source.is_some() {
+						continue;
+					}
+					if let Some(sysroot) = Sysroot::find(
+						&cmdline.sysroot,
+						device.arch,
+						variant,
+					) {
+						if !sysroots.contains(&sysroot) {
+							sysroots.push(sysroot);
+						}
+						continue;
+					}
+					let recipe = BootstrapRecipe::resolve(
+						AB_DIR,
+						device,
+						variant,
+						&cmdline.recipe,
+					)?;
+					if recipes.iter().all(|r| r.key() != recipe.key()) {
+						recipes.push(recipe);
+					}
+				}
+			}
+			for recipe in &recipes {
+				info!(
+					"Recipe of {}: {} (from the {})",
+					recipe.key(),
+					recipe.recipe.display(),
+					recipe.source
+				);
+				if cmdline.replay.is_none() && !dry_run {
+					recipe.check()?;
+				}
+			}
+			for sysroot in &sysroots {
+				info!(
+					"Using {} as the {} distribution for {}.",
+					sysroot.path().display(),
+					sysroot.variant.to_string().to_lowercase(),
+					sysroot.arch.to_string().to_lowercase()
+				);
+				if cmdline.replay.is_none() {
+					sysroot.check()?;
+				}
+			}
 			// build image contexts
 			let mut queue = ImageContextQueue::new();
-			let variants = variants.as_slice();
-			let user = &cmdline.user;
+			let user = (!cmdline.no_user).then_some(cmdline.user.as_str());
 			let password = &cmdline.password;
+			let ssh_keys = load_ssh_keys(&cmdline.ssh_key)?;
+			let expire_password = cmdline.expire_password.unwrap_or_else(|| {
+				expire_password_by_default(password, !ssh_keys.is_empty())
+			});
+			if user.is_some()
+				&& password == DEFAULT_PASSWORD
+				&& !expire_password && ssh_keys.is_empty()
+			{
+				bail!("The built-in user would keep the well-known default password after the first login. Use another password, --ssh-key, or drop --expire-password false.");
+			}
+			let public_artifacts = cmdline.public_artifacts;
+			if public_artifacts && password != DEFAULT_PASSWORD {
+				bail!("--public-artifacts can not be used with a custom password.");
+			}
+			if cmdline.password_hashed {
+				check_password_hash(password)
+					.context("--password-hashed requires a password hashed with crypt(3)")?;
+			}
+			if let Some(hostname) = &cmdline.hostname {
+				check_hostname(hostname)?;
+			}
+			if let Some(timezone) = &cmdline.timezone {
+				check_timezone_name(timezone)?;
+			}
+			let customize_scripts = collect_customize_scripts(
+				&cmdline.customize_script,
+				&cmdline.customize_dir,
+			)?;
+			let root_ssh_keys = load_ssh_keys(&cmdline.root_ssh_key)?;
+			let stages = Stage::normalize(&cmdline.stages)?;
+			if stages.last() != Some(&Stage::Compress)
+				&& (cmdline.keep_workdir == KeepWorkdir::Never || cmdline.cleanup)
+			{
+				bail!("The raw images are discarded with --keep-workdir never or --cleanup, unless the stages end with 'compress'.");
+			}
+			if cmdline.resume && revision == Some(Revision::Auto) {
+				bail!("--resume can not be used with --revision auto, which always makes new images.");
+			}
+			let mut resumed = 0;
 			for device in devices.as_slice() {
-				check_binfmt(&device.arch)?;
+				if !dry_run {
+					check_binfmt(&device.arch)?;
+				}
 				for variant in variants {
-					let variant_str = variant.to_string().to_lowercase();
 					// aosc-os_desktop_rawimg_raspberrypi_rpi-5b_20241108{.1}.img.xz
-					let base_dist = Path::new(&cmdline.workdir).join(format!(
-						"bootstrap/{}-{}",
-						&variant_str,
-						&device.arch.to_string().to_lowercase()
-					));
-					let filename = format!(
-						"aosc-os_{0}_rawimg_{1}_{2}_{3}{4}_{5}.img{6}",
-						&variant.to_string().to_lowercase(),
-						&device.vendor.clone(),
-						&device.id.clone(),
-						&date_str,
-						match revision {
-							Some(x) => {
-								format!(".{}", x)
-							}
-							_ => "".to_string(),
-						},
-						&device.arch.to_string().to_ascii_lowercase(),
-						compress.get_extension()
+					let sysroot = Sysroot::find(
+						&cmdline.sysroot,
+						device.arch,
+						variant,
 					);
-					queue.push(ImageContext {
+					let base_dist = match (&cmdline.rootfs_source, sysroot) {
+						(Some(source), _) => source.path.clone(),
+						(None, Some(sysroot)) => sysroot.path().to_owned(),
+						(None, None) => BootstrapRecipe::resolve(
+							AB_DIR,
+							device,
+							variant,
+							&cmdline.recipe,
+						)?
+						.path(&cmdline.workdir),
+					};
+					let template = cmdline
+						.name_template
+						.as_ref()
+						.or(device.name_template.as_ref())
+						.unwrap_or(&default_template);
+					let mut j = ImageContext {
 						device,
 						variant,
 						workdir: &cmdline.workdir,
 						outdir: &cmdline.outdir,
 						user,
 						password,
-						filename,
+						password_hashed: cmdline.password_hashed,
+						hostname: cmdline.hostname.as_deref(),
+						timezone: cmdline.timezone.as_deref(),
+						ssh_keys: &ssh_keys,
+						root_ssh_keys: &root_ssh_keys,
+						customize_scripts: &customize_scripts,
+						keep_workdir: cmdline.keep_workdir,
+						stages: &stages,
+						shrink,
+						fsck: !no_fsck,
+						populate_backend,
+						expire_password,
+						public_artifacts,
+						filename: String::new(),
+						revision: None,
+						reserved: false,
 						override_rootfs_fstype: &fstype,
 						additional_packages: &additional_packages,
 						compress: &compress,
+						compress_level,
+						compress_threads,
 						base_dist,
+						rootfs_source: cmdline.rootfs_source.as_ref(),
+						offline: cmdline.offline,
 						topics,
-					});
+						image_size: image_size.map(|s| s.0),
+						image_size_round_to: round_to,
+						trailing_pad,
+						build_id,
+						logger: JobLogger::new(&device.id, variant),
+						force: cmdline.force,
+						seed: seed.as_deref(),
+					};
+					j.revision = match revision {
+						Some(Revision::Number(n)) => Some(n),
+						Some(Revision::Auto) => Some(next_revision(
+							&j.output_dir(),
+							template,
+							device,
+							variant,
+							&date_str,
+						)
+						.context(format!(
+							"Unable to pick the revision of {}",
+							&device.id
+						))?),
+						None => None,
+					};
+					let name = image_name(
+						template, device, variant, &date_str, j.revision,
+					)
+					.context(format!(
+						"Unable to name the images of {}",
+						&device.id
+					))?;
+					j.filename =
+						format!("{}.img{}", name, compress.get_extension());
+					if cmdline.resume && !cmdline.force {
+						if let Some(path) = find_built_image(
+							&j.output_dir(),
+							template,
+							device,
+							variant,
+							j.revision,
+							compress.get_extension(),
+						)? {
+							info!(
+								"{} already exists, skipping {} ({}).",
+								path.display(),
+								&device.id,
+								variant.to_string().to_lowercase()
+							);
+							resumed += 1;
+							continue;
+						}
+					}
+					queue.push(j);
+				}
+			}
+			if resumed > 0 {
+				info!(
+					"Resuming: {} of {} images already exist.",
+					resumed,
+					queue.len() + resumed
+				);
+			}
+			for j in &queue {
+				j.check_image_size()?;
+				if j.shrink {
+					j.check_shrink()?;
 				}
+				j.check_populate_backend()?;
+				j.check_mkfs_options()?;
 			}
+			let media_warnings = queue
+				.iter()
+				.filter_map(|j| j.check_media_size())
+				.collect::<Vec<_>>();
+			for w in &media_warnings {
+				warn!("{}", w);
+			}
+			let artifacts = queue.iter().map(|j| j.artifact()).collect::<Vec<_>>();
+			check_artifacts(&artifacts, cmdline.overwrite)?;
 			info!(
 				"Job queue contains {} images for {} devices.",
 				queue.len().bright_cyan(),
 				devices.len().bright_cyan()
 			);
+			if dry_run {
+				let len = queue.len();
+				for (idx, j) in queue.iter().enumerate() {
+					j.plan(idx + 1, len)?;
+				}
+				info!("Dry run finished, nothing has been built.");
+				return Ok(());
+			}
+			// The distributions are only installed by the populate stage.
+			if !stages.contains(&Stage::Populate) {
+				recipes.clear();
+			}
+			// Nothing is run while replaying.
+			if !cmdline.skip_preflight && cmdline.replay.is_none() {
+				let mut requirements = preflight::collect(&queue, jobs);
+				if recipes.iter().any(|r| {
+					cmdline.force_bootstrap || !r.is_cached(&cmdline.workdir)
+				}) {
+					requirements.require(
+						"aoscbootstrap",
+						"bootstrapping the distributions",
+					);
+				}
+				preflight::check(
+					&requirements,
+					&queue,
+					&cmdline.workdir,
+					&cmdline.outdir,
+				)?;
+			}
 			info!("Bootstrapping releases...");
-			for variant in variants {
-				let variant_str = variant.to_string().to_lowercase();
-				for device in devices.as_slice() {
-					let arch = device.arch;
-					let bootstrap_path =
-						Path::new(&cmdline.workdir).join(format!(
-							"bootstrap/{}-{}",
-							&variant_str,
-							arch.to_string().to_lowercase()
-						));
-					if !bootstrap_path.is_dir()
-						|| !(bootstrap_path.join("etc/os-release")).exists()
-					{
-						bootstrap_distribution(
-							variant,
-							bootstrap_path,
-							arch,
-							&cmdline.mirror,
-						)?;
+			for recipe in &recipes {
+				let bootstrap_path = recipe.path(&cmdline.workdir);
+				if !cmdline.force_bootstrap && recipe.is_cached(&cmdline.workdir) {
+					continue;
+				}
+				// Built from other files, or forced to bootstrap again.
+				if bootstrap_path.join("etc/os-release").exists()
+					|| (cmdline.force_bootstrap && bootstrap_path.exists())
+				{
+					info!("Removing {} ...", bootstrap_path.display());
+					remove_dir_all(&bootstrap_path)?;
+				}
+				bootstrap_distribution(
+					recipe,
+					&bootstrap_path,
+					Mirror::find(&cmdline.mirror, recipe.arch),
+				)?;
+				recipe.write_stamp(&cmdline.workdir)?;
+			}
+			// The zoneinfo database is only known once the distributions are there.
+			for j in queue
+				.iter()
+				.filter(|j| j.runs(Stage::Populate) && j.rootfs_source.is_none())
+			{
+				if let Some(timezone) = j.timezone() {
+					check_timezone(&j.base_dist, timezone)?;
+				}
+			}
+			// Nothing is produced without the compress stage.
+			let mut reserved = Vec::new();
+			if revision == Some(Revision::Auto) && stages.contains(&Stage::Compress) {
+				for j in queue.iter_mut() {
+					let template = cmdline
+						.name_template
+						.as_ref()
+						.or(j.device.name_template.as_ref())
+						.unwrap_or(&default_template);
+					if let Err(e) = j.reserve_revision(template, &date_str) {
+						for path in &reserved {
+							std::fs::remove_file(path).ok();
+						}
+						return Err(e);
 					}
+					reserved.push(j.artifact().path);
 				}
 			}
-			let mut count: usize = 0;
 			let len = queue.len();
 			info!("Begin to generate images ...");
 			std::thread::sleep(time::Duration::from_secs(2));
-			info!("Executing the queue ...");
+			info!("Executing the queue with {} job(s) ...", jobs.min(len));
+			set_progress_bar(jobs == 1 && !cmdline.quiet);
+			joblog::set_prefix_output(jobs.min(len) > 1);
 			let start = Instant::now();
-			for j in queue {
-				info!("{} images pending.", len - count);
-				count += 1;
-				j.execute(count, len)?;
-			}
+			let report = execute_queue(queue, jobs, continue_on_error);
 			let duration = start.elapsed();
+			// The images of the jobs not run are still empty.
+			for path in &reserved {
+				if std::fs::metadata(path).is_ok_and(|m| m.len() == 0) {
+					std::fs::remove_file(path).ok();
+				}
+			}
+			BuildReport::append(&cmdline.outdir, &report.records)
+				.context("Failed to update the build report")?;
+			if report.failed() > 0 {
+				error!(
+					target: SUMMARY_TARGET,
+					"{} of {} image(s) failed in {:.03} seconds:\n{}",
+					report.failed(),
+					len,
+					duration.as_secs_f32(),
+					report
+				);
+				bail!("Failed to build {} image(s).", report.failed());
+			}
 			info!(
-				"Done! {} image(s) in {:.03} seconds.",
+				target: SUMMARY_TARGET,
+				"Done! {} image(s) in {:.03} seconds:\n{}",
 				len,
-				duration.as_secs_f32()
+				duration.as_secs_f32(),
+				report
 			);
+			if deprecated_skipped > 0 {
+				info!(
+					target: SUMMARY_TARGET,
+					"{} deprecated device(s) skipped, use --allow-deprecated to build them.",
+					deprecated_skipped
+				);
+			}
+			if !media_warnings.is_empty() {
+				warn!("The following images may not fit on the media they are made for:");
+				for w in &media_warnings {
+					warn!("{}", w);
+				}
+			}
+			if let Some((policy, dry_run)) = &retention {
+				policy.apply(&cmdline.outdir, *dry_run)?;
+			}
 			if cmdline.cleanup {
 				info!("Cleaning up the sketch directories ...");
 				let sketch_dir = cmdline.workdir.join("sketches");
@@ -498,15 +1190,131 @@ fn try_main(cmdline: Cmdline) -> Result<()> {
 			info!("Output directory: {}", &cmdline.outdir.display());
 			info!("Program finished successfully. Exiting.");
 		}
-		cli::Action::Check { .. } => {
-			info!("Checking validity of the registry ...");
-			registry.check_validity()?;
+		cli::Action::Partition {
+			device,
+			target,
+			size,
+			format_fs,
+			seed,
+		} => {
+			let device = &registry.get(&device)?;
+			let ctx = ImageContext {
+				device,
+				variant: &ImageVariant::Base,
+				workdir: &cmdline.workdir,
+				outdir: &cmdline.outdir,
+				user: Some(&cmdline.user),
+				password: &cmdline.password,
+				password_hashed: cmdline.password_hashed,
+				hostname: cmdline.hostname.as_deref(),
+				timezone: cmdline.timezone.as_deref(),
+				ssh_keys: &[],
+				root_ssh_keys: &[],
+				customize_scripts: &[],
+				keep_workdir: KeepWorkdir::Always,
+				stages: &Stage::ALL,
+				shrink: false,
+				fsck: false,
+				populate_backend: PopulateBackend::Mount,
+				expire_password: false,
+				public_artifacts: false,
+				filename: String::new(),
+				revision: None,
+				reserved: false,
+				base_dist: PathBuf::new(),
+				rootfs_source: None,
+				offline: cmdline.offline,
+				override_rootfs_fstype: &None,
+				additional_packages: &None,
+				compress: &Compression::None,
+				compress_level: None,
+				compress_threads: None,
+				topics: None,
+				image_size: None,
+				image_size_round_to: None,
+				trailing_pad: None,
+				build_id,
+				logger: JobLogger::new(&device.id, &ImageVariant::Base),
+				force: cmdline.force,
+				seed: seed.as_deref(),
+			};
+			if format_fs {
+				ctx.check_mkfs_options()?;
+			}
+			ctx.partition_target(&target, size, format_fs)?;
+			return Ok(());
+		}
+		cli::Action::Check { device, strict, .. } => {
+			let devices = match &device {
+				Some(d) => vec![registry.get(d)?],
+				None => registry.get_all()?,
+			};
+			for d in &devices {
+				d.check()
+					.and_then(|_| d.check_unknown_keys(strict))
+					.context(format!(
+						"Sanity check failed for device '{}' at {}:",
+						&d.id,
+						&d.file_path.display()
+					))?;
+			}
+			smoke::smoke_test_devices(
+				&devices,
+				&cmdline.workdir,
+				&cmdline.outdir,
+				build_id,
+			)?;
+			return Ok(());
+		}
+		cli::Action::List {
+			format,
+			list_ids,
+			long,
+			arch,
+			vendor,
+			tag,
+			any_tag,
+		} => {
+			let filter = DeviceFilter {
+				arch,
+				vendor,
+				tags: tag,
+				any_tag,
+			};
+			if list_ids {
+				registry.list_ids(&filter)?;
+			} else {
+				registry.list_devices(format, long, &filter)?;
+			}
 			return Ok(());
 		}
-		cli::Action::List { format } => {
-			registry.list_devices(format)?;
+		cli::Action::Inspect {
+			variant,
+			fstype,
+			image_size,
+			format,
+			target,
+		} => {
+			let device = registry.get(&target)?;
+			device.check()?;
+			let spec = ResolvedSpec::resolve(
+				&device,
+				&variant,
+				fstype.map(FilesystemType::from),
+				image_size.map(|s| s.0),
+			)?;
+			match format {
+				InspectFormat::Toml => print!("{}", spec.to_toml()?),
+				InspectFormat::Json => println!("{}", spec.to_json()?),
+			}
 			return Ok(());
 		}
+		cli::Action::Clean { .. }
+		| cli::Action::Config { .. }
+		| cli::Action::GenerateCompletions { .. }
+		| cli::Action::GenerateManpage => {
+			unreachable!("Handled above")
+		}
 	};
 	Ok(())
 }
@@ -0,0 +1,117 @@
+//! Capacities of the common storage media.
+//!
+//! Media are sold in decimal gigabytes (10⁹ bytes), while the image sizes
+//! are in MiB (2²⁰ bytes). A "32 GB" SD card holds about 29.8 GiB, and
+//! some of it is usually reserved by the controller, so an image of
+//! `32000` MiB does not fit on it. The usable capacity of a medium is taken
+//! as [`USABLE_PERCENT`] of its marketed capacity.
+//!
+//! Images whose size looks like a medium (read in binary units) that they
+//! do not actually fit on are warned about, naming the smallest standard
+//! medium they fit on. Devices can declare the medium they are built for
+//! with `target_media_capacity`.
+use crate::context::ImageContext;
+
+/// Standard capacities of SD cards and eMMC modules, in GB.
+pub const MEDIA_CAPACITIES: &[u64] = &[2, 4, 8, 16, 32, 64, 128, 256, 512];
+/// How much of the marketed capacity is usable, in percent.
+pub const USABLE_PERCENT: u64 = 93;
+
+const GB: u64 = 1_000_000_000;
+const GIB: u64 = 1 << 30;
+
+/// Usable bytes of a medium marketed as `gb` GB.
+pub fn usable_bytes(gb: u64) -> u64 {
+	gb * GB / 100 * USABLE_PERCENT
+}
+
+/// The smallest standard medium an image of `size` bytes fits on, in GB.
+pub fn smallest_medium(size: u64) -> Option<u64> {
+	MEDIA_CAPACITIES
+		.iter()
+		.copied()
+		.find(|&gb| size <= usable_bytes(gb))
+}
+
+/// The medium an image of `size` bytes looks like to be made for, in GB.
+///
+/// That is the smallest standard medium whose capacity, mistaken for GiB,
+/// holds the image.
+pub fn implied_medium(size: u64) -> Option<u64> {
+	MEDIA_CAPACITIES
+		.iter()
+		.copied()
+		.find(|&gb| size <= gb * GIB)
+}
+
+/// Check whether an image of `size` bytes fits on the medium it is made
+/// for, which is `target` GB if specified, returns the warning if not.
+pub fn check_media_size(size: u64, target: Option<u64>) -> Option<String> {
+	let target = target.or_else(|| implied_medium(size))?;
+	if size <= usable_bytes(target) {
+		return None;
+	}
+	let fits = match smallest_medium(size) {
+		Some(gb) => format!("the smallest standard medium it fits on is {} GB", gb),
+		None => "it is larger than any standard medium".to_owned(),
+	};
+	Some(format!(
+		"{} bytes ({:.2} GiB) does not fit on {} GB media (about {} bytes usable), {}",
+		size,
+		size as f64 / GIB as f64,
+		target,
+		usable_bytes(target),
+		fits
+	))
+}
+
+impl ImageContext<'_> {
+	/// Check whether the image fits on the medium it is made for, returns the warning if not.
+	pub fn check_media_size(&self) -> Option<String> {
+		let size = self.padded_image_size(self.nominal_image_size());
+		check_media_size(size, self.device.target_media_capacity).map(|w| {
+			format!(
+				"{} ({}): {}",
+				&self.device.id,
+				self.variant.to_string().to_lowercase(),
+				w
+			)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const MIB: u64 = 1 << 20;
+
+	#[test]
+	fn test_media_capacity() {
+		// 32 GB is 29.8 GiB, 27.7 GiB usable.
+		assert_eq!(usable_bytes(32), 29_760_000_000);
+		assert!(usable_bytes(32) < 28 * GIB);
+		assert!(usable_bytes(32) > 27 * GIB);
+		assert_eq!(smallest_medium(0), Some(2));
+		assert_eq!(smallest_medium(usable_bytes(8)), Some(8));
+		assert_eq!(smallest_medium(usable_bytes(8) + 1), Some(16));
+		assert_eq!(smallest_medium(1 << 50), None);
+		// Thought to be 32 GB, but only fits on 64 GB.
+		assert_eq!(implied_medium(32000 * MIB), Some(32));
+		assert_eq!(smallest_medium(32000 * MIB), Some(64));
+		let warning = check_media_size(32000 * MIB, None).unwrap();
+		assert!(warning.contains("does not fit on 32 GB media"));
+		assert!(warning.contains("fits on is 64 GB"));
+		// The default sizes are fine.
+		assert_eq!(check_media_size(6144 * MIB, None), None);
+		assert_eq!(check_media_size(22528 * MIB, None), None);
+		assert_eq!(implied_medium(22528 * MIB), Some(32));
+		// Explicit target
+		assert!(check_media_size(22528 * MIB, Some(16)).is_some());
+		assert_eq!(check_media_size(14000 * MIB, Some(16)), None);
+		assert!(check_media_size(1 << 50, None).is_none());
+		assert!(check_media_size(1 << 50, Some(512))
+			.unwrap()
+			.contains("larger than any standard medium"));
+	}
+}
@@ -0,0 +1,414 @@
+//! Image metadata embedded in the gap before the first partition.
+//!
+//! A small area ([`METADATA_SIZE`] bytes) is reserved at a fixed offset,
+//! which is just after the primary GPT by default (see
+//! [`DEFAULT_METADATA_OFFSET`]), and can be changed with `metadata_offset` in
+//! the device specification. It contains the following, all integers are
+//! little-endian:
+//!
+//! | Offset | Size | Content                                  |
+//! |--------|------|------------------------------------------|
+//! | 0      | 8    | Magic, `MKRAWIMG`                        |
+//! | 8      | 4    | Format version, currently `1`            |
+//! | 12     | 4    | Length of the payload                    |
+//! | 16     | 4    | CRC32 of the payload                     |
+//! | 20     | *    | Payload, [`ImageMetadata`] in JSON       |
+//!
+//! The rest of the area is filled with zeros.
+//!
+//! The metadata can be read without decompressing the whole image, since
+//! only the first [`SCAN_SIZE`] bytes of the image are needed.
+//!
+//! `mkrawimg inspect` tells an image from a device by the content of the
+//! file, see [`is_image`].
+use std::{
+	fs::File,
+	io::{BufReader, Read, Seek, SeekFrom, Write},
+	path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	bootloader::{flash_file_name, BootloaderSpec},
+	chroot,
+	context::ImageContext,
+	device::PartitionMapType,
+	rootfs::{decompress, GZIP_MAGIC, XZ_MAGIC, ZSTD_MAGIC},
+};
+
+pub const METADATA_MAGIC: &[u8; 8] = b"MKRAWIMG";
+pub const METADATA_VERSION: u32 = 1;
+/// Size of the reserved area, in bytes.
+pub const METADATA_SIZE: u64 = 4096;
+/// Just after the primary GPT header and the partition entries (LBA 34).
+pub const DEFAULT_METADATA_OFFSET: u64 = 512 * 34;
+/// How many bytes from the start of the image are searched for the metadata.
+pub const SCAN_SIZE: u64 = 1 << 20;
+const HEADER_SIZE: usize = 20;
+/// Signature at the end of the first sector of a MBR, including the protective one of GPT.
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+/// Metadata of an image, shown by flashers before flashing.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ImageMetadata {
+	pub device_id: String,
+	pub device_name: String,
+	pub variant: String,
+	/// Build date in RFC 3339.
+	pub build_date: String,
+	pub build_id: String,
+	/// Revision of the image, if any.
+	#[serde(default)]
+	pub revision: Option<u32>,
+	pub tool_version: String,
+	/// Size of the raw image, in bytes.
+	pub image_size: u64,
+	/// Whether the image contains credentials, so it should not be published.
+	#[serde(default)]
+	pub contains_secrets: bool,
+	/// Backend which ran the commands inside the target.
+	#[serde(default)]
+	pub chroot_backend: Option<String>,
+	/// Accounts which have to change the password on the first login.
+	#[serde(default)]
+	pub expired_accounts: Vec<String>,
+}
+
+fn crc32(data: &[u8]) -> u32 {
+	let mut crc = flate2::Crc::new();
+	crc.update(data);
+	crc.sum()
+}
+
+impl ImageMetadata {
+	/// Encode the metadata into a [`METADATA_SIZE`] bytes long block.
+	pub fn encode(&self) -> Result<Vec<u8>> {
+		let payload = serde_json::to_vec(self)?;
+		if payload.len() + HEADER_SIZE > METADATA_SIZE as usize {
+			bail!("Image metadata is too large");
+		}
+		let mut block = Vec::with_capacity(METADATA_SIZE as usize);
+		block.extend_from_slice(METADATA_MAGIC);
+		block.extend_from_slice(&METADATA_VERSION.to_le_bytes());
+		block.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+		block.extend_from_slice(&crc32(&payload).to_le_bytes());
+		block.extend_from_slice(&payload);
+		block.resize(METADATA_SIZE as usize, 0);
+		Ok(block)
+	}
+
+	/// Decode the metadata from a block starting with the magic.
+	pub fn decode(block: &[u8]) -> Result<Self> {
+		if block.len() < HEADER_SIZE || &block[..8] != METADATA_MAGIC {
+			bail!("Image metadata not found");
+		}
+		let field = |i: usize| u32::from_le_bytes(block[i..i + 4].try_into().unwrap());
+		let (version, len, crc) = (field(8), field(12) as usize, field(16));
+		if version != METADATA_VERSION {
+			bail!("Unsupported image metadata version {}", version);
+		}
+		let payload = block
+			.get(HEADER_SIZE..HEADER_SIZE + len)
+			.context("Image metadata is truncated")?;
+		if crc32(payload) != crc {
+			bail!("Image metadata is corrupted: CRC mismatch");
+		}
+		Ok(serde_json::from_slice(payload)?)
+	}
+
+	/// Find the metadata in the first bytes of an image.
+	pub fn scan(head: &[u8]) -> Result<Self> {
+		match find_magic(head) {
+			Some(offset) => Self::decode(&head[offset..]),
+			None => bail!("Image metadata not found"),
+		}
+	}
+
+	/// Read the metadata from an image, which can be compressed.
+	///
+	/// Compressed images are only decompressed until the metadata area.
+	pub fn read_from<P: AsRef<Path>>(path: P) -> Result<Self> {
+		let path = path.as_ref();
+		let fd = BufReader::new(
+			File::open(path).context(format!("Failed to open {}", path.display()))?,
+		);
+		let reader = decompress(fd)?;
+		let mut head = Vec::new();
+		reader.take(SCAN_SIZE).read_to_end(&mut head)?;
+		Self::scan(&head)
+			.context(format!("Failed to read the metadata of {}", path.display()))
+	}
+}
+
+/// Offset of the metadata magic in the first bytes of an image.
+fn find_magic(head: &[u8]) -> Option<usize> {
+	(0..head.len())
+		.step_by(512)
+		.find(|&offset| head[offset..].starts_with(METADATA_MAGIC))
+}
+
+/// Whether the file is an image rather than a device specification: either
+/// compressed, or starting with a partition table or the metadata.
+pub fn is_image(path: &Path) -> Result<bool> {
+	if !path.is_file() {
+		return Ok(false);
+	}
+	let mut head = Vec::new();
+	File::open(path)
+		.context(format!("Failed to open {}", path.display()))?
+		.take(SCAN_SIZE)
+		.read_to_end(&mut head)?;
+	Ok([GZIP_MAGIC, XZ_MAGIC, ZSTD_MAGIC]
+		.iter()
+		.any(|magic| head.starts_with(magic))
+		|| head.get(510..512) == Some(&BOOT_SIGNATURE[..])
+		|| find_magic(&head).is_some())
+}
+
+impl std::fmt::Display for ImageMetadata {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		writeln!(f, "Device:       {} ({})", self.device_name, self.device_id)?;
+		writeln!(f, "Variant:      {}", self.variant)?;
+		writeln!(f, "Build date:   {}", self.build_date)?;
+		writeln!(f, "Build ID:     {}", self.build_id)?;
+		if let Some(revision) = self.revision {
+			writeln!(f, "Revision:     {}", revision)?;
+		}
+		writeln!(f, "Tool version: {}", self.tool_version)?;
+		writeln!(f, "Image size:   {} bytes", self.image_size)?;
+		if let Some(backend) = &self.chroot_backend {
+			writeln!(f, "Chroot:       {}", backend)?;
+		}
+		if !self.expired_accounts.is_empty() {
+			writeln!(f, "Expired:      {}", self.expired_accounts.join(", "))?;
+		}
+		write!(
+			f,
+			"Credentials:  {}",
+			if self.contains_secrets { "yes" } else { "no" }
+		)
+	}
+}
+
+impl ImageContext<'_> {
+	/// Find out whether the metadata area is safe to write, returns the reason if not.
+	fn check_metadata_area<P: AsRef<Path>>(
+		&self,
+		rootfs: P,
+		offset: u64,
+	) -> Result<Option<String>> {
+		let end = offset + METADATA_SIZE;
+		let table_end = match self.device.partition_map {
+			PartitionMapType::GPT | PartitionMapType::Hybrid => DEFAULT_METADATA_OFFSET,
+			PartitionMapType::MBR => 512,
+			PartitionMapType::None => {
+				return Ok(Some("the filesystem occupies the whole image".into()))
+			}
+		};
+		if offset < table_end {
+			return Ok(Some("it overlaps the partition table".into()));
+		}
+		let first_start = self.device.reserved_gap_end() * 512;
+		if end > first_start {
+			return Ok(Some("it overlaps the first partition".into()));
+		}
+		if let Some(region) = self.device.reserved.iter().find(|r| {
+			let (start, end_sector) = r.sectors_in(512);
+			offset < end_sector * 512 && start * 512 < end
+		}) {
+			return Ok(Some(format!(
+				"it overlaps the reserved region '{}'",
+				region.name
+			)));
+		}
+		let bootloaders = match &self.device.bootloaders {
+			Some(b) => b,
+			None => return Ok(None),
+		};
+		let dirname =
+			self.device.file_path.parent().context(
+				"Failed to reach the directory containing the device spec file",
+			)?;
+		for step in bootloaders {
+			let (name, bl_offset, len) = match &step.spec {
+				BootloaderSpec::FlashOffset {
+					path,
+					source,
+					offset: bl_offset,
+					..
+				} => {
+					let full_path = self.flash_file_path(
+						path.as_deref(),
+						source.as_ref(),
+						rootfs.as_ref(),
+						bootloaders,
+					)?;
+					let len = full_path.metadata()?.len();
+					let name =
+						flash_file_name(path.as_deref(), source.as_ref());
+					(name, *bl_offset, len)
+				}
+				BootloaderSpec::Raw(raw) => {
+					let full_path = self.bootloader_file_path(
+						&raw.input,
+						dirname,
+						rootfs.as_ref(),
+						bootloaders,
+					)?;
+					let len = raw.write_len(full_path.metadata()?.len())?;
+					(raw.input.name(), raw.offset.0, len)
+				}
+				BootloaderSpec::Sunxi(sunxi) => {
+					let full_path = self.bootloader_file_path(
+						&sunxi.input,
+						dirname,
+						rootfs.as_ref(),
+						bootloaders,
+					)?;
+					let len = full_path.metadata()?.len();
+					(sunxi.input.name(), sunxi.spl_offset()?, len)
+				}
+				_ => continue,
+			};
+			if bl_offset < end && offset < bl_offset + len {
+				return Ok(Some(format!(
+					"it overlaps the bootloader {} at {:#x}",
+					name, bl_offset
+				)));
+			}
+		}
+		Ok(None)
+	}
+
+	/// Write the metadata into the image, after the bootloaders are applied.
+	///
+	/// Skipped if there is no safe gap for it.
+	pub fn write_metadata<P: AsRef<Path>>(
+		&self,
+		rootfs: P,
+		loopdev: P,
+		image_size: u64,
+	) -> Result<()> {
+		let offset = self
+			.device
+			.metadata_offset
+			.unwrap_or(DEFAULT_METADATA_OFFSET);
+		if let Some(reason) = self.check_metadata_area(rootfs, offset)? {
+			self.warn(format!(
+				"Not writing the image metadata at {:#x}: {}.",
+				offset, reason
+			));
+			return Ok(());
+		}
+		let mut fd = File::options()
+			.read(true)
+			.write(true)
+			.open(loopdev.as_ref())?;
+		// Bootloader scripts may have written here as well.
+		let mut area = vec![0u8; METADATA_SIZE as usize];
+		fd.seek(SeekFrom::Start(offset))?;
+		fd.read_exact(&mut area)?;
+		if area.iter().any(|&b| b != 0) {
+			self.warn(format!(
+				"Not writing the image metadata at {:#x}: the area is not empty.",
+				offset
+			));
+			return Ok(());
+		}
+		let metadata = ImageMetadata {
+			device_id: self.device.id.clone(),
+			device_name: self.device.name.clone(),
+			variant: self.variant.to_string().to_lowercase(),
+			build_date: chrono::Utc::now().to_rfc3339(),
+			build_id: self.build_id.to_owned(),
+			revision: self.revision,
+			tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+			image_size,
+			contains_secrets: self.contains_secrets(),
+			chroot_backend: Some(chroot::backend().to_string()),
+			expired_accounts: self.expired_accounts(),
+		};
+		self.info(format!("Writing the image metadata at {:#x} ...", offset));
+		fd.seek(SeekFrom::Start(offset))?;
+		fd.write_all(&metadata.encode()?)?;
+		fd.sync_all()?;
+		Ok(())
+	}
+}
+
+/// Record the new size of the image in the metadata at `offset`, if there is one.
+pub fn update_image_size(img: &Path, offset: u64, image_size: u64) -> Result<()> {
+	let mut fd = File::options().read(true).write(true).open(img)?;
+	let mut block = vec![0u8; METADATA_SIZE as usize];
+	fd.seek(SeekFrom::Start(offset))?;
+	fd.read_exact(&mut block)?;
+	if !block.starts_with(METADATA_MAGIC) {
+		return Ok(());
+	}
+	let mut metadata = ImageMetadata::decode(&block)?;
+	metadata.image_size = image_size;
+	fd.seek(SeekFrom::Start(offset))?;
+	fd.write_all(&metadata.encode()?)?;
+	fd.sync_all()?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	#[test]
+	fn test_metadata_roundtrip() -> Result<()> {
+		let metadata = ImageMetadata {
+			device_id: "rpi-5b".into(),
+			device_name: "Raspberry Pi 5 Model B".into(),
+			variant: "desktop".into(),
+			build_date: "2024-11-08T00:00:00+00:00".into(),
+			build_id: "01JC4ZQ3V8X4Q8N5X4Y4M2K7QZ".into(),
+			revision: Some(2),
+			tool_version: "0.1.0".into(),
+			image_size: 22528 << 20,
+			contains_secrets: true,
+			chroot_backend: Some("bwrap".into()),
+			expired_accounts: vec!["aosc".into()],
+		};
+		let block = metadata.encode()?;
+		assert_eq!(block.len() as u64, METADATA_SIZE);
+		let mut head = vec![0u8; DEFAULT_METADATA_OFFSET as usize];
+		head.extend_from_slice(&block);
+		assert_eq!(ImageMetadata::scan(&head)?, metadata);
+		// Corrupted payload
+		let mut bad = block.clone();
+		bad[HEADER_SIZE + 2] ^= 0xff;
+		assert!(ImageMetadata::decode(&bad).is_err());
+		assert!(ImageMetadata::scan(&[0u8; 4096]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_is_image() -> Result<()> {
+		let dir = TempDir::new("is-image")?;
+		let file = |name: &str, content: &[u8]| -> Result<bool> {
+			let path = dir.join(name);
+			std::fs::write(&path, content)?;
+			is_image(&path)
+		};
+		// Devices are told by the content, rather than the name.
+		assert!(!file("rpi-5b", b"id = \"rpi-5b\"\n")?);
+		assert!(!file("device.toml", b"id = \"rpi-5b\"\n")?);
+		let mut mbr = vec![0u8; 4096];
+		mbr[510..512].copy_from_slice(&BOOT_SIGNATURE);
+		assert!(file("rpi-5b.img", &mbr)?);
+		let mut partitionless = vec![0u8; 4096];
+		partitionless[1024..1032].copy_from_slice(METADATA_MAGIC);
+		assert!(file("rpi-5b.raw", &partitionless)?);
+		assert!(file("rpi-5b.img.xz", XZ_MAGIC)?);
+		assert!(file("image", ZSTD_MAGIC)?);
+		assert!(!is_image(&dir)?);
+		assert!(!is_image(&dir.join("missing.img"))?);
+		Ok(())
+	}
+}
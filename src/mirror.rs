@@ -0,0 +1,118 @@
+//! Mirrors to bootstrap the system distributions from, with `--mirror`.
+//!
+//! `--mirror URL` sets the mirror for every architecture, while
+//! `--mirror ARCH=URL` sets the one for `ARCH` only, e.g. when the trees of
+//! some architectures live on another mirror. Both can be specified more
+//! than once, the last one wins:
+//!
+//! ```shell
+//! mkrawimg --mirror https://mirrors.example.org/anthon/debs \
+//! 	--mirror loongarch64=https://loongarch.example.org/debs build-all
+//! ```
+//!
+//! Architectures without a mirror of their own use the last bare URL, or
+//! [`DEFAULT_MIRROR`] if there is none.
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+
+use crate::device::DeviceArch;
+
+/// The AOSC OS upstream mirror.
+pub const DEFAULT_MIRROR: &str = "https://repo.aosc.io/debs";
+
+/// A mirror specified on the command line, in the form of `URL` or `ARCH=URL`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mirror {
+	/// `None` for every architecture.
+	pub arch: Option<DeviceArch>,
+	pub url: String,
+}
+
+impl FromStr for Mirror {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		// URLs may contain '=' in the query, but never before the scheme.
+		let (arch, url) = match s.split_once('=') {
+			Some((key, url)) if !key.contains([':', '/']) => {
+				let arch = DeviceArch::value_variants()
+					.iter()
+					.find(|a| a.to_string().eq_ignore_ascii_case(key));
+				match arch {
+					Some(arch) => (Some(*arch), url),
+					None => bail!(
+						"Invalid architecture '{}', possible architectures are: {}",
+						key,
+						DeviceArch::value_variants()
+							.iter()
+							.map(|a| a.to_string().to_lowercase())
+							.collect::<Vec<_>>()
+							.join(", ")
+					),
+				}
+			}
+			_ => (None, s),
+		};
+		if url.is_empty() {
+			bail!("Empty mirror URL in '{}'", s);
+		}
+		Ok(Self {
+			arch,
+			url: url.to_owned(),
+		})
+	}
+}
+
+impl Mirror {
+	/// Find the mirror for the architecture, the last one wins.
+	pub fn find(mirrors: &[Mirror], arch: DeviceArch) -> &str {
+		mirrors.iter()
+			.rev()
+			.find(|m| m.arch == Some(arch))
+			.or_else(|| mirrors.iter().rev().find(|m| m.arch.is_none()))
+			.map(|m| m.url.as_str())
+			.unwrap_or(DEFAULT_MIRROR)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_find_mirror() -> Result<()> {
+		assert_eq!(Mirror::find(&[], DeviceArch::Arm64), DEFAULT_MIRROR);
+		let mirrors: Vec<Mirror> = [
+			"https://mirror.example.org/debs",
+			"loongarch64=https://loongarch.example.org/debs",
+			"https://repo.example.org/debs?token=abc",
+			"riscv64=https://riscv.example.org/debs",
+		]
+		.iter()
+		.map(|s| s.parse())
+		.collect::<Result<_>>()?;
+		assert_eq!(mirrors[2].arch, None);
+		assert_eq!(mirrors[2].url, "https://repo.example.org/debs?token=abc");
+		assert_eq!(
+			Mirror::find(&mirrors, DeviceArch::LoongArch64),
+			"https://loongarch.example.org/debs"
+		);
+		assert_eq!(
+			Mirror::find(&mirrors, DeviceArch::Riscv64),
+			"https://riscv.example.org/debs"
+		);
+		assert_eq!(
+			Mirror::find(&mirrors, DeviceArch::Arm64),
+			"https://repo.example.org/debs?token=abc"
+		);
+		let mirrors = ["amd64=https://amd64.example.org/debs".parse()?];
+		assert_eq!(Mirror::find(&mirrors, DeviceArch::Arm64), DEFAULT_MIRROR);
+		let err = "arm=https://example.org".parse::<Mirror>().unwrap_err();
+		assert!(err.to_string().contains("Invalid architecture 'arm'"));
+		assert!("arm64=".parse::<Mirror>().is_err());
+		assert!("".parse::<Mirror>().is_err());
+		Ok(())
+	}
+}
@@ -0,0 +1,476 @@
+//! Names of the images, made from a template.
+//!
+//! The name of an image is made from a [`NameTemplate`], in order of
+//! precedence:
+//!
+//! 1. `--name-template` on the command line.
+//! 2. `name_template` in the device specification.
+//! 3. [`DEFAULT_NAME_TEMPLATE`].
+//!
+//! The extension (`.img` followed by the one of the compression format) is
+//! always appended, so it matches the content. Placeholders are checked when
+//! the template is parsed, a typo is rejected before anything is built.
+//!
+//! Note that the [retention policy](crate::retention) only considers the
+//! images named after the default template.
+//!
+//! With `--revision auto`, each image takes the revision after the highest
+//! one of the existing images of the same device, variant and date in the
+//! output directory, where an image without a revision counts as `0`. The
+//! image is created exclusively before anything is built, so two builds
+//! running at once never take the same name: the one coming second takes
+//! the next revision instead. The template must contain `{revision}`.
+//!
+//! With `--resume`, an image is skipped if the output directory has one
+//! with the same name except for the date, so a build resumed on another
+//! day does not start over.
+use std::{
+	fmt::Display,
+	fs::{self, OpenOptions},
+	io::ErrorKind,
+	path::{Path, PathBuf},
+	str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde::Deserialize;
+
+use crate::{
+	context::{ImageContext, ImageVariant},
+	device::DeviceSpec,
+};
+
+/// The name of the images if no template is specified.
+pub const DEFAULT_NAME_TEMPLATE: &str =
+	"aosc-os_{variant}_rawimg_{vendor}_{id}_{date}{revision}_{arch}";
+
+/// Placeholders supported in the templates:
+///
+/// - `{variant}`: The variant, e.g. `desktop`.
+/// - `{vendor}`: The vendor of the device, e.g. `raspberrypi`.
+/// - `{id}`: The ID of the device, e.g. `rpi-5b`.
+/// - `{date}`: The date of the build, e.g. `20241108`.
+/// - `{revision}`: `.N` with `--revision N` (or the revision picked by
+///   `--revision auto`), empty otherwise.
+/// - `{arch}`: The architecture, e.g. `arm64`.
+/// - `{compat}`: The `compatible` of the device, e.g. `raspberrypi,5-model-b`.
+///   Devices without one can not use it.
+pub const PLACEHOLDERS: &[&str] = &[
+	"variant", "vendor", "id", "date", "revision", "arch", "compat",
+];
+
+/// Revision of the images, with `--revision`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Revision {
+	/// The next free one in the output directory.
+	Auto,
+	Number(u32),
+}
+
+impl FromStr for Revision {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		if s == "auto" {
+			return Ok(Self::Auto);
+		}
+		match s.parse() {
+			Ok(n) => Ok(Self::Number(n)),
+			Err(_) => bail!("Invalid revision '{}', expected a number or 'auto'", s),
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+	Literal(String),
+	Placeholder(&'static str),
+}
+
+/// A validated template of the image names, e.g. `aosc-os_{variant}_{id}_{date}`.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct NameTemplate {
+	segments: Vec<Segment>,
+}
+
+impl FromStr for NameTemplate {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		if s.is_empty() {
+			bail!("Empty name template");
+		}
+		if s.contains('/') {
+			bail!("Name template '{}' can not contain '/'", s);
+		}
+		let mut segments = Vec::new();
+		let mut rest = s;
+		while let Some(start) = rest.find(['{', '}']) {
+			if rest[start..].starts_with('}') {
+				bail!("Unmatched '}}' in name template '{}'", s);
+			}
+			if start > 0 {
+				segments.push(Segment::Literal(rest[..start].to_owned()));
+			}
+			let end = rest[start..]
+				.find('}')
+				.map(|e| start + e)
+				.filter(|&e| !rest[start + 1..e].contains('{'));
+			let end = match end {
+				Some(e) => e,
+				None => bail!("Unclosed '{{' in name template '{}'", s),
+			};
+			let name = &rest[start + 1..end];
+			match PLACEHOLDERS.iter().find(|&&p| p == name) {
+				Some(p) => segments.push(Segment::Placeholder(p)),
+				None => bail!(
+					"Unknown placeholder '{{{}}}' in name template '{}', possible placeholders are: {}",
+					name,
+					s,
+					PLACEHOLDERS
+						.iter()
+						.map(|p| format!("{{{}}}", p))
+						.collect::<Vec<_>>()
+						.join(", ")
+				),
+			}
+			rest = &rest[end + 1..];
+		}
+		if !rest.is_empty() {
+			segments.push(Segment::Literal(rest.to_owned()));
+		}
+		Ok(Self { segments })
+	}
+}
+
+impl TryFrom<String> for NameTemplate {
+	type Error = anyhow::Error;
+
+	fn try_from(value: String) -> Result<Self> {
+		value.parse()
+	}
+}
+
+impl Default for NameTemplate {
+	fn default() -> Self {
+		DEFAULT_NAME_TEMPLATE.parse().unwrap()
+	}
+}
+
+impl Display for NameTemplate {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		for segment in &self.segments {
+			match segment {
+				Segment::Literal(s) => write!(f, "{}", s)?,
+				Segment::Placeholder(p) => write!(f, "{{{}}}", p)?,
+			}
+		}
+		Ok(())
+	}
+}
+
+impl NameTemplate {
+	/// Whether the template contains the placeholder.
+	pub fn has_placeholder(&self, name: &str) -> bool {
+		self.segments
+			.iter()
+			.any(|s| matches!(s, Segment::Placeholder(p) if *p == name))
+	}
+
+	/// Fill in the placeholders with the values returned by `value`.
+	///
+	/// Values which are `None` (e.g. `{compat}` of a device without one) are errors.
+	pub fn render<F>(&self, value: F) -> Result<String>
+	where
+		F: Fn(&str) -> Option<String>,
+	{
+		let mut name = String::new();
+		for segment in &self.segments {
+			match segment {
+				Segment::Literal(s) => name += s,
+				Segment::Placeholder(p) => {
+					match value(p) {
+						Some(v) if v.contains('/') => {
+							bail!("Value of {{{}}} '{}' can not contain '/'", p, v)
+						}
+						Some(v) => name += &v,
+						None => bail!(
+						"Name template '{}' uses {{{}}}, which is not available",
+						self,
+						p
+					),
+					}
+				}
+			}
+		}
+		if name.is_empty() || name.starts_with('.') {
+			bail!("Name template '{}' makes an invalid name '{}'", self, name);
+		}
+		Ok(name)
+	}
+}
+
+/// Make the name of an image of the device, without the extension.
+pub fn image_name(
+	template: &NameTemplate,
+	device: &DeviceSpec,
+	variant: &ImageVariant,
+	date: &str,
+	revision: Option<u32>,
+) -> Result<String> {
+	let revision = revision.map(|r| format!(".{}", r)).unwrap_or_default();
+	render_image_name(template, device, variant, date, &revision)
+}
+
+fn render_image_name(
+	template: &NameTemplate,
+	device: &DeviceSpec,
+	variant: &ImageVariant,
+	date: &str,
+	revision: &str,
+) -> Result<String> {
+	template.render(|p| match p {
+		"variant" => Some(variant.to_string().to_lowercase()),
+		"vendor" => Some(device.vendor.clone()),
+		"id" => Some(device.id.clone()),
+		"date" => Some(date.to_owned()),
+		"revision" => Some(revision.to_owned()),
+		"arch" => Some(device.arch.to_string().to_lowercase()),
+		"compat" => device.of_compatible.clone(),
+		_ => None,
+	})
+}
+
+/// Find the revision of an image named `name`, which is made from `prefix`,
+/// the revision and `suffix`, followed by the extension of an image.
+fn parse_revision(name: &str, prefix: &str, suffix: &str) -> Option<u32> {
+	let rest = name.strip_prefix(prefix)?;
+	let (revision, rest) = match rest.strip_prefix('.') {
+		Some(rest) => {
+			let len = rest.bytes().take_while(u8::is_ascii_digit).count();
+			(rest[..len].parse().ok()?, &rest[len..])
+		}
+		None => (0, rest),
+	};
+	rest.strip_prefix(suffix)?
+		.starts_with(".img")
+		.then_some(revision)
+}
+
+/// Length of the date in the names, e.g. `20241108`.
+const DATE_LEN: usize = 8;
+
+/// Whether `name` is `pattern` with each NUL replaced by the same date.
+fn matches_but_date(name: &str, pattern: &str) -> bool {
+	let mut parts = pattern.split('\0');
+	// Safe to unwrap, split() always yields something.
+	let Some(mut rest) = name.strip_prefix(parts.next().unwrap()) else {
+		return false;
+	};
+	let mut date = None;
+	for part in parts {
+		let Some(this) = rest.as_bytes().get(..DATE_LEN) else {
+			return false;
+		};
+		if !this.iter().all(u8::is_ascii_digit) || date.is_some_and(|d| d != this) {
+			return false;
+		}
+		date = Some(this);
+		let Some(next) = rest[DATE_LEN..].strip_prefix(part) else {
+			return false;
+		};
+		rest = next;
+	}
+	rest.is_empty()
+}
+
+/// An existing image in `dir` with the same name as the one to build except
+/// for the date, e.g. built by an earlier invocation which died halfway.
+pub fn find_built_image(
+	dir: &Path,
+	template: &NameTemplate,
+	device: &DeviceSpec,
+	variant: &ImageVariant,
+	revision: Option<u32>,
+	extension: &str,
+) -> Result<Option<PathBuf>> {
+	// A value which can never be a part of the other values.
+	let name = image_name(template, device, variant, "\0", revision)?;
+	let pattern = format!("{}.img{}", name, extension);
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+		Err(e) => return Err(e).context(format!("Failed to read {}", dir.display())),
+	};
+	for entry in entries {
+		let entry = entry?;
+		if matches_but_date(&entry.file_name().to_string_lossy(), &pattern) {
+			return Ok(Some(entry.path()));
+		}
+	}
+	Ok(None)
+}
+
+/// The revision after the highest one of the existing images in `dir`,
+/// having the same name except for the revision.
+pub fn next_revision(
+	dir: &Path,
+	template: &NameTemplate,
+	device: &DeviceSpec,
+	variant: &ImageVariant,
+	date: &str,
+) -> Result<u32> {
+	if !template.has_placeholder("revision") {
+		bail!(
+			"--revision auto requires {{revision}} in the name template, but '{}' does not have it",
+			template
+		);
+	}
+	// A value which can never be a part of the other values.
+	let name = render_image_name(template, device, variant, date, "\0")?;
+	let (prefix, suffix) = name.split_once('\0').unwrap();
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == ErrorKind::NotFound => return Ok(1),
+		Err(e) => return Err(e).context(format!("Failed to read {}", dir.display())),
+	};
+	let mut next = 1;
+	for entry in entries {
+		let name = entry?.file_name();
+		if let Some(revision) = parse_revision(&name.to_string_lossy(), prefix, suffix) {
+			next = next.max(revision + 1);
+		}
+	}
+	Ok(next)
+}
+
+impl ImageContext<'_> {
+	/// Reserve the image of `--revision auto` by creating it exclusively,
+	/// taking the next revision if it already exists.
+	pub fn reserve_revision(&mut self, template: &NameTemplate, date: &str) -> Result<()> {
+		let dir = self.output_dir();
+		fs::create_dir_all(&dir)?;
+		let mut revision = self.revision.unwrap_or(1);
+		loop {
+			let name = image_name(
+				template,
+				self.device,
+				self.variant,
+				date,
+				Some(revision),
+			)?;
+			let filename = format!("{}.img{}", name, self.compress.get_extension());
+			let path = dir.join(&filename);
+			match OpenOptions::new().write(true).create_new(true).open(&path) {
+				Ok(_) => {
+					if self.revision != Some(revision) {
+						info!(
+							"Revision {} of {} was taken, using revision {}.",
+							self.revision.unwrap_or_default(),
+							self.artifact().job,
+							revision
+						);
+					}
+					self.revision = Some(revision);
+					self.filename = filename;
+					self.reserved = true;
+					return Ok(());
+				}
+				Err(e) if e.kind() == ErrorKind::AlreadyExists => revision += 1,
+				Err(e) => {
+					return Err(e).context(format!(
+						"Failed to create {}",
+						path.display()
+					))
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn value(name: &str) -> Option<String> {
+		match name {
+			"variant" => Some("desktop".into()),
+			"vendor" => Some("raspberrypi".into()),
+			"id" => Some("rpi-5b".into()),
+			"date" => Some("20241108".into()),
+			"revision" => Some(".1".into()),
+			"arch" => Some("arm64".into()),
+			_ => None,
+		}
+	}
+
+	#[test]
+	fn test_name_template() -> Result<()> {
+		let default = NameTemplate::default();
+		assert_eq!(default.to_string(), DEFAULT_NAME_TEMPLATE);
+		assert_eq!(
+			default.render(value)?,
+			"aosc-os_desktop_rawimg_raspberrypi_rpi-5b_20241108.1_arm64"
+		);
+		let t: NameTemplate = "{id}-{variant}-{date}".parse()?;
+		assert_eq!(t.render(value)?, "rpi-5b-desktop-20241108");
+		let t: NameTemplate = "{id}_{compat}".parse()?;
+		let err = t.render(value).unwrap_err().to_string();
+		assert!(err.contains("uses {compat}"));
+		let err = "{id}_{varaint}"
+			.parse::<NameTemplate>()
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("Unknown placeholder '{varaint}'"));
+		assert!("{id".parse::<NameTemplate>().is_err());
+		assert!("{id{variant}}".parse::<NameTemplate>().is_err());
+		assert!("id}".parse::<NameTemplate>().is_err());
+		assert!("images/{id}".parse::<NameTemplate>().is_err());
+		assert!("".parse::<NameTemplate>().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_parse_revision() -> Result<()> {
+		let revision = |name: &str| parse_revision(name, "rpi-5b_20241108", "_arm64");
+		assert_eq!(revision("rpi-5b_20241108_arm64.img.xz"), Some(0));
+		assert_eq!(revision("rpi-5b_20241108.3_arm64.img"), Some(3));
+		assert_eq!(revision("rpi-5b_20241108.12_arm64.img.zst"), Some(12));
+		assert_eq!(revision("rpi-5b_20241108.1_arm64.img.sha256sum"), Some(1));
+		assert_eq!(revision("rpi-5b_20241108.x_arm64.img"), None);
+		assert_eq!(revision("rpi-5b_20241109.1_arm64.img"), None);
+		assert_eq!(revision("rpi-5b_20241108.1_arm64.log"), None);
+		assert_eq!("auto".parse::<Revision>()?, Revision::Auto);
+		assert_eq!("2".parse::<Revision>()?, Revision::Number(2));
+		assert!("-1".parse::<Revision>().is_err());
+		assert!(NameTemplate::default().has_placeholder("revision"));
+		let t: NameTemplate = "{id}_{date}".parse()?;
+		assert!(!t.has_placeholder("revision"));
+		Ok(())
+	}
+
+	#[test]
+	fn test_matches_but_date() {
+		let pattern = "rpi-5b_\0.1_arm64.img.xz";
+		assert!(matches_but_date("rpi-5b_20241108.1_arm64.img.xz", pattern));
+		assert!(matches_but_date("rpi-5b_20250101.1_arm64.img.xz", pattern));
+		assert!(!matches_but_date("rpi-5b_20241108.2_arm64.img.xz", pattern));
+		assert!(!matches_but_date("rpi-5b_2024110.1_arm64.img.xz", pattern));
+		assert!(!matches_but_date("rpi-5b_2024110x.1_arm64.img.xz", pattern));
+		// Left by a build which died while compressing.
+		assert!(!matches_but_date(
+			"rpi-5b_20241108.1_arm64.img.xz.part",
+			pattern
+		));
+		assert!(!matches_but_date("rpi-5b_", pattern));
+		// The same date everywhere.
+		let pattern = "\0-rpi-5b_\0.img";
+		assert!(matches_but_date("20241108-rpi-5b_20241108.img", pattern));
+		assert!(!matches_but_date("20241108-rpi-5b_20241109.img", pattern));
+		// Without a date
+		assert!(matches_but_date("rpi-5b.img", "rpi-5b.img"));
+		assert!(!matches_but_date("rpi-5b.img.part", "rpi-5b.img"));
+	}
+}
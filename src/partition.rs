@@ -1,20 +1,311 @@
-use crate::{device::PartitionMapType, filesystem::FilesystemType};
-use anyhow::{anyhow, Result};
+use crate::{
+	btrfs::BtrfsSubvolume,
+	content::PartitionContent,
+	context::ImageVariant,
+	device::{DeviceArch, PartitionMapType},
+	encryption::EncryptionSpec,
+	filesystem::FilesystemType,
+	fstab::FstabStyle,
+	size::{bytes_to_sectors, format_size, parse_size, MIB},
+	slots::Slot,
+};
+use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
+use strum::VariantArray;
+use toml::Value;
 use uuid::{uuid, Uuid};
 
 pub const PARTTYPE_EFI_UUID: Uuid = uuid!("C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
 pub const PARTTYPE_LINUX_UUID: Uuid = uuid!("0FC63DAF-8483-4772-8E79-3D69D8477DE4");
 pub const PARTTYPE_SWAP_UUID: Uuid = uuid!("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F");
 pub const PARTTYPE_BASIC_UUID: Uuid = uuid!("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7");
+pub const PARTTYPE_CROS_KERNEL_UUID: Uuid = uuid!("FE3A2A5D-4F32-41A7-B725-ACCC3285A309");
+
+/// Android bootloader partition, shared by the vendor bootloader aliases.
+pub const PARTTYPE_ANDROID_BOOTLOADER_GUID: &str = "2568845D-2332-4675-BC39-8FA5A4748D15";
+/// U-Boot environment partition, shared by the vendor environment aliases.
+pub const PARTTYPE_UBOOT_ENV_GUID: &str = "3DE21764-95BD-54BD-A5C3-4ABE786F38A8";
 
 pub const PARTTYPE_EFI_BYTE: u8 = 0xEF;
 pub const PARTTYPE_LINUX_BYTE: u8 = 0x83;
 pub const PARTTYPE_SWAP_BYTE: u8 = 0x82;
 pub const PARTTYPE_BASIC_BYTE: u8 = 0x07;
+/// Extended boot loader partition of the Boot Loader Specification.
+pub const PARTTYPE_XBOOTLDR_BYTE: u8 = 0xEA;
+
+/// A partition type alias used by vendor boot flows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VendorPartType {
+	/// Name of the alias, used in the device specification.
+	pub name: &'static str,
+	/// Vendor family of the SoC, matched against `soc_vendor` of the device.
+	/// `None` means the alias is not specific to any vendor.
+	pub family: Option<&'static str>,
+	/// Partition type GUID.
+	pub guid: &'static str,
+	/// Default partition label, used if the partition does not define one.
+	pub label: &'static str,
+}
+
+/// Curated table of vendor partition type aliases.
+///
+/// Boot ROMs and vendor bootloaders look for these partitions by their type GUIDs
+/// and/or labels. Please double check with the vendor documentation before adding
+/// new entries.
+///
+/// Several aliases may share a GUID on purpose: the vendor bootloaders use the
+/// Android bootloader ([`PARTTYPE_ANDROID_BOOTLOADER_GUID`]) and U-Boot
+/// environment ([`PARTTYPE_UBOOT_ENV_GUID`]) types, and tell the partitions
+/// apart by their labels. Such aliases only differ in the vendor family and the
+/// default label, any other shared GUID is a mistake.
+pub const VENDOR_PART_TYPES: &[VendorPartType] = &[
+	// Generic
+	VendorPartType {
+		name: "uboot-env",
+		family: None,
+		guid: PARTTYPE_UBOOT_ENV_GUID,
+		label: "u-boot-env",
+	},
+	VendorPartType {
+		name: "android-boot",
+		family: None,
+		guid: "49A4D17F-93A3-45C1-A0DE-F50B2EBE2599",
+		label: "boot",
+	},
+	VendorPartType {
+		name: "android-misc",
+		family: None,
+		guid: "EF32A33B-A409-486C-9141-9FFB711F6266",
+		label: "misc",
+	},
+	// Amlogic
+	VendorPartType {
+		name: "aml-bootloader",
+		family: Some("amlogic"),
+		guid: PARTTYPE_ANDROID_BOOTLOADER_GUID,
+		label: "bootloader",
+	},
+	VendorPartType {
+		name: "aml-env",
+		family: Some("amlogic"),
+		guid: PARTTYPE_UBOOT_ENV_GUID,
+		label: "env",
+	},
+	// Qualcomm
+	VendorPartType {
+		name: "qcom-xbl",
+		family: Some("qcom"),
+		guid: "DEA0BA2C-CBDD-4805-B4F9-F428251C3E98",
+		label: "xbl",
+	},
+	VendorPartType {
+		name: "qcom-xbl-config",
+		family: Some("qcom"),
+		guid: "5A325AE4-4276-B66D-0ADD-3494DF27706A",
+		label: "xbl_config",
+	},
+	VendorPartType {
+		name: "qcom-abl",
+		family: Some("qcom"),
+		guid: "BD6928A1-4CE0-A038-4F3A-1495E3EDDFFB",
+		label: "abl",
+	},
+	VendorPartType {
+		name: "qcom-tz",
+		family: Some("qcom"),
+		guid: "A053AA7F-40B8-4B1C-BA08-2F68AC71A4F4",
+		label: "tz",
+	},
+	VendorPartType {
+		name: "qcom-rpm",
+		family: Some("qcom"),
+		guid: "098DF793-D712-413D-9D4E-89D711772228",
+		label: "rpm",
+	},
+	VendorPartType {
+		name: "qcom-hyp",
+		family: Some("qcom"),
+		guid: "E1A6A689-0C8D-4CC6-B4E8-55A4320FBD8A",
+		label: "hyp",
+	},
+	// Samsung
+	VendorPartType {
+		name: "exynos-bootloader",
+		family: Some("exynos"),
+		guid: PARTTYPE_ANDROID_BOOTLOADER_GUID,
+		label: "bootloader",
+	},
+];
+
+/// Find a vendor partition type alias by its name.
+pub fn find_vendor_part_type(name: &str) -> Option<&'static VendorPartType> {
+	VENDOR_PART_TYPES.iter().find(|v| v.name == name)
+}
+
+/// Root partition types of the [Discoverable Partitions Specification], by the architecture names of systemd-repart.
+///
+/// [Discoverable Partitions Specification]: https://uapi-group.org/specifications/specs/discoverable_partitions_specification/
+pub const DPS_ROOT_TYPES: &[(&str, &str)] = &[
+	("arm", "69DAD710-2CE4-4E3C-B16C-21A1D49ABED3"),
+	("arm64", "B921B045-1DF0-41C3-AF44-4C6F280D3FAE"),
+	("loongarch64", "77055800-792C-4F94-B39A-98C91B762BB6"),
+	("mips64-le", "700BDA43-7A34-4507-B179-EEB93D7A7CA3"),
+	("ppc64", "912ADE1D-A839-4913-8964-A10EEE08FBD2"),
+	("ppc64-le", "C31C45E6-3F39-412E-80FB-4809C4980599"),
+	("riscv64", "72EC70A6-CF74-40E6-BD49-4BDA08E8F224"),
+	("x86", "44479540-F297-41B2-9AF7-D131D5F0458A"),
+	("x86-64", "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709"),
+];
+
+/// `/usr` partition types of the Discoverable Partitions Specification, by architecture.
+pub const DPS_USR_TYPES: &[(&str, &str)] = &[
+	("arm", "7D0359A3-02B3-4F0A-865C-654403E70625"),
+	("arm64", "B0E01050-EE5F-4390-949A-9101B17104E9"),
+	("loongarch64", "E611C702-575C-4CBE-9A46-434FA0BF7E3F"),
+	("mips64-le", "C97C1F32-BA06-40B4-9F22-236061B08AA8"),
+	("ppc64", "15BB03AF-77E7-4D4A-B12B-C0D084F7491C"),
+	("ppc64-le", "EE2B9983-21E8-4153-86D9-B6901A54D1CE"),
+	("riscv64", "BEAEC34B-8442-439B-A40B-984381ED097D"),
+	("x86", "75250D76-8CC6-458E-BD66-BD47CC81A812"),
+	("x86-64", "8484680C-9521-48C6-9C11-B0720656F69E"),
+];
+
+/// Partition types of the Discoverable Partitions Specification not specific to any architecture.
+pub const DPS_OTHER_TYPES: &[(&str, &str)] = &[
+	("home", "933AC7E1-2EB4-4F13-B844-0E14E2AEF915"),
+	("srv", "3B8F8425-20E0-4F3B-907F-1A25A76F98E8"),
+	("var", "4D21B016-B534-45C2-A9FB-5C16E091FD2D"),
+	("xbootldr", "BC13C2FF-59E6-4262-A352-B275FD6F7172"),
+];
+
+/// Whether the name is a partition type of the Discoverable Partitions Specification.
+pub fn is_discoverable_part_type(name: &str) -> bool {
+	let per_arch = |prefix: &str, types: &[(&str, &str)]| {
+		name.strip_prefix(prefix)
+			.is_some_and(|arch| types.iter().any(|(a, _)| *a == arch))
+	};
+	matches!(name, "linux-root" | "linux-usr" | "usr")
+		|| per_arch("root-", DPS_ROOT_TYPES)
+		|| per_arch("usr-", DPS_USR_TYPES)
+		|| DPS_OTHER_TYPES.iter().any(|(n, _)| *n == name)
+}
+
+/// Resolve a partition type of the Discoverable Partitions Specification.
+///
+/// `linux-root` and `linux-usr` (or `usr`) are resolved with the architecture of the device.
+pub fn find_discoverable_part_type(name: &str, arch: &DeviceArch) -> Result<Uuid> {
+	let lookup = |types: &[(&str, &'static str)], arch: &str| {
+		types.iter()
+			.find(|(a, _)| *a == arch)
+			.map(|(_, guid)| *guid)
+	};
+	let device_arch = || {
+		arch.dps_name().context(format!(
+			"The Discoverable Partitions Specification does not define the partition types of {}, use type = \"uuid\" instead",
+			arch.to_string().to_lowercase()
+		))
+	};
+	let guid = match name {
+		"linux-root" => lookup(DPS_ROOT_TYPES, device_arch()?),
+		"linux-usr" | "usr" => lookup(DPS_USR_TYPES, device_arch()?),
+		_ => {
+			if let Some(arch) = name.strip_prefix("root-") {
+				lookup(DPS_ROOT_TYPES, arch)
+			} else if let Some(arch) = name.strip_prefix("usr-") {
+				lookup(DPS_USR_TYPES, arch)
+			} else {
+				lookup(DPS_OTHER_TYPES, name)
+			}
+		}
+	}
+	.context(format!("Unknown discoverable partition type '{}'", name))?;
+	Ok(Uuid::parse_str(guid)?)
+}
+
+/// Bits of the boot priority in the attributes of a ChromeOS kernel partition, 4 bits wide.
+pub const CROS_PRIORITY_SHIFT: u64 = 48;
+/// Bits of the remaining tries, 4 bits wide.
+pub const CROS_TRIES_SHIFT: u64 = 52;
+/// Bit of the successful flag.
+pub const CROS_SUCCESSFUL_BIT: u64 = 56;
+
+/// Partition type as written in the specification, converted to [`PartitionType`].
+#[derive(Deserialize)]
+struct RawPartitionType {
+	#[serde(rename = "type")]
+	part_type: String,
+	uuid: Option<Uuid>,
+	byte: Option<u8>,
+	alias: Option<String>,
+	name: Option<String>,
+	table_type: Option<PartitionMapType>,
+	#[serde(default)]
+	partitions: Vec<PartitionSpec>,
+	priority: Option<u8>,
+	tries: Option<u8>,
+	successful: Option<bool>,
+}
+
+impl TryFrom<RawPartitionType> for PartitionType {
+	type Error = anyhow::Error;
+
+	fn try_from(raw: RawPartitionType) -> Result<Self> {
+		let required = |key: &str| {
+			format!(
+				"Partition type '{}' requires an additional field '{}'",
+				raw.part_type, key
+			)
+		};
+		let part_type = match raw.part_type.as_str() {
+			"efi" | "esp" => Self::EFI,
+			"linux" => Self::Linux,
+			"swap" => Self::Swap,
+			"basic" => Self::Basic,
+			"uuid" => Self::Uuid {
+				uuid: raw.uuid.context(required("uuid"))?,
+			},
+			"byte" => Self::Byte {
+				byte: raw.byte.context(required("byte"))?,
+			},
+			"vendor" => Self::Vendor {
+				alias: raw.alias.context(required("alias"))?,
+			},
+			"discoverable" => Self::Discoverable {
+				name: raw.name.context(required("name"))?,
+			},
+			"chromeos_kernel" => {
+				let priority = raw.priority.unwrap_or(10);
+				let tries = raw.tries.unwrap_or(5);
+				if priority > 15 || tries > 15 {
+					bail!("priority and tries of a ChromeOS kernel partition must be within 0-15");
+				}
+				Self::ChromeosKernel {
+					priority,
+					tries,
+					successful: raw.successful.unwrap_or(true),
+				}
+			}
+			"nested" => Self::Nested {
+				table_type: raw.table_type.context(required("table_type"))?,
+				partitions: raw.partitions,
+			},
+			name if is_discoverable_part_type(name) => Self::Discoverable {
+				name: name.to_owned(),
+			},
+			name => match Uuid::parse_str(name) {
+				Ok(uuid) => Self::Uuid { uuid },
+				Err(_) => bail!(
+					"Unknown partition type '{}', expected one of efi, linux, swap, basic, uuid, byte, vendor, a discoverable partition type or a GUID",
+					name
+				),
+			},
+		};
+		Ok(part_type)
+	}
+}
 
 #[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
-#[serde(tag = "type", rename_all = "snake_case")]
+#[serde(tag = "type", rename_all = "snake_case", try_from = "RawPartitionType")]
 #[allow(clippy::upper_case_acronyms)]
 /// Partition type recorded in the partition table.
 ///
@@ -25,6 +316,8 @@ pub const PARTTYPE_BASIC_BYTE: u8 = 0x07;
 /// [[partition]]
 /// # other fields
 /// type = "efi"
+/// # A GUID is the same as type = "uuid"
+/// type = "0FC63DAF-8483-4772-8E79-3D69D8477DE4"
 /// ```
 pub enum PartitionType {
 	// Common types
@@ -51,9 +344,7 @@ pub enum PartitionType {
 	Linux,
 	/// Swap partition
 	///
-	/// <div class="warning">
-	/// Swap partitions are not allowed due to various reasons. The tool will throw an error if a swap partition is encountered.
-	/// </div>
+	/// Only for the partitions with `usage = "swap"`.
 	///
 	/// - MBR: `0x82`
 	/// - GPT: `0657FD6D-A4AB-43C4-84E5-0933C84B4F4F`
@@ -87,6 +378,30 @@ pub enum PartitionType {
 		/// Arbitary UUID can be specified here.
 		uuid: Uuid,
 	},
+	/// Partition types of the [Discoverable Partitions Specification], which
+	/// let systemd-gpt-auto-generator find and mount the partitions without
+	/// fstab.
+	///
+	/// - `linux-root`: Root partition of the architecture of the device.
+	/// - `linux-usr` (or `usr`): `/usr` partition of the architecture of the device.
+	/// - `root-<arch>`, `usr-<arch>`: Same for a specific architecture, see [`DPS_ROOT_TYPES`] and [`DPS_USR_TYPES`].
+	/// - `home`, `srv`, `var`, `xbootldr`: See [`DPS_OTHER_TYPES`].
+	///
+	/// On a MBR partition table, the root and data partitions are `0x83`,
+	/// and the extended boot loader partition is `0xea`.
+	///
+	/// ```toml
+	/// [[partition]]
+	/// type = "linux-root"
+	/// # or
+	/// type = "root-arm64"
+	/// ```
+	///
+	/// [Discoverable Partitions Specification]: https://uapi-group.org/specifications/specs/discoverable_partitions_specification/
+	Discoverable {
+		/// Name of the partition type.
+		name: String,
+	},
 	/// Arbitary MBR partition types.
 	///
 	/// If being used on a GPT partition table, the program will throw an error.
@@ -100,6 +415,48 @@ pub enum PartitionType {
 		/// Arbitary MBR partition type can be specified here.
 		byte: u8,
 	},
+	/// Partition types used by vendor boot flows, see [`VENDOR_PART_TYPES`]
+	/// for the available aliases.
+	///
+	/// If being used on a MBR partition table, the program will throw an error.
+	/// If the partition does not define a label, the default label of the alias is used.
+	///
+	/// ```toml
+	/// [[partition]]
+	/// type = "vendor"
+	/// alias = "aml-bootloader"
+	/// ```
+	Vendor {
+		/// Name of the vendor partition type alias.
+		alias: String,
+	},
+	/// ChromeOS kernel partition, booted by depthcharge on Chromebooks and the boards derived from them.
+	///
+	/// It contains a kernel signed with `vbutil_kernel` instead of a
+	/// filesystem, usually written with `content` from the output of a
+	/// bootloader step. depthcharge boots the kernel partition with the
+	/// highest `priority` (0-15, 0 is not bootable) which is either
+	/// `successful` or has `tries` (0-15) left, these are recorded in the
+	/// attribute bits 48-56 (see [`CROS_PRIORITY_SHIFT`]).
+	///
+	/// - GPT: `FE3A2A5D-4F32-41A7-B725-ACCC3285A309`
+	/// - MBR: Not available.
+	///
+	/// ```toml
+	/// [[partition]]
+	/// type = "chromeos_kernel"
+	/// # Defaults, the same as `cgpt add -P 10 -T 5 -S 1`
+	/// priority = 10
+	/// tries = 5
+	/// successful = true
+	/// filesystem = "none"
+	/// content = { type = "raw", path = "{{step:vbutil:output}}", from_target = true }
+	/// ```
+	ChromeosKernel {
+		priority: u8,
+		tries: u8,
+		successful: bool,
+	},
 	/// Nested partition table. Will not implemented, so being here is just for fun.
 	/// Who the hell in this world wants to use this anyway?
 	Nested {
@@ -132,6 +489,8 @@ pub enum PartitionType {
 ///
 /// Index of the partition in the partition table.
 ///
+/// On MBR, partitions numbered from 5 are logical partitions, placed in an extended partition created in the slot after the primary partitions (so at most 3 primary partitions can be defined along with them). The numbers match the device names, e.g. partition 5 of `/dev/loop0` is `/dev/loop0p5`.
+///
 /// ```toml
 /// [[partition]]
 /// num = 1
@@ -146,10 +505,14 @@ pub enum PartitionType {
 ///
 /// - [`"efi"`]: EFI System Partition.
 /// - [`"linux"`]: Linux filesystem.
-/// - [`"swap"`]: Swap partition (not allowed to be used).
+/// - [`"swap"`]: Swap partition, see `usage` below.
 /// - [`"basic"`]: Basic data partition.
 /// - [`"uuid"`]: Arbitrary UUID value. An additional field `uuid` is required to specify the UUID value.
 /// - [`"byte"`]: Arbitrary byte value. An additional field `byte` is required to specify the byte value.
+/// - [`"vendor"`]: Vendor partition type alias. An additional field `alias` is required to specify the alias.
+/// - [Discoverable partition types], e.g. `"linux-root"` for the root partition of the architecture of the device, or `"root-arm64"`.
+/// - [`"chromeos_kernel"`]: ChromeOS kernel partition, with optional fields `priority`, `tries` and `successful`.
+/// - A GUID, same as `"uuid"` with the GUID.
 ///
 /// ```toml
 /// [[partition]]
@@ -167,31 +530,56 @@ pub enum PartitionType {
 /// # Or an arbitrary byte value
 /// type = "byte"
 /// byte = 0x0c
+/// # Or a vendor partition type alias
+/// type = "vendor"
+/// alias = "qcom-xbl"
+/// # Or a discoverable partition type
+/// type = "linux-root"
+/// # Or a GUID
+/// type = "01234567-89AB-CDEF-0123-456789ABCDEF"
 /// ```
 ///
-/// `start_sector` - Starting position (Optional)
-/// ---------------------------------------------
+/// `start_sector` (or `start`) - Starting position (Optional)
+/// -----------------------------------------------------------
 ///
-/// Defines where the partition starts in the partition table, in 512-byte sectors.
+/// Defines where the partition starts in the partition table, in 512-byte sectors, or as a size with a unit (see [`crate::size`]). Either is converted to the sector size of the disk while partitioning, e.g. sector `2048` is sector `256` on a disk with 4096-byte sectors.
 ///
-/// If not defined, then this partition will immidiately follow the previous partition, or starts at sector `2048`` if this is the first partition, leaving ~1MB empty space before it.
+/// If not defined, then this partition will immidiately follow the previous partition, or starts at sector `2048`` if this is the first partition, leaving ~1MB empty space before it. The gap before the first partition can be changed with `first_partition_offset` of the [Device Specification File].
 ///
 /// For example, your device requires a bootloader partition to be present at 32KB from start, then the value would be:
 ///
 /// ```toml
 /// # other fields
 /// start_sector = 64
+/// # Same as above
+/// start = "32KiB"
 /// ```
 ///
-/// `size_in_sectors` - Partition size
-/// -----------------------
+/// `align` - Alignment (Optional)
+/// -------------------------------
+///
+/// Defines the alignment of the starting position of the partition if it is not defined, in 512-byte sectors, or as a size with a unit (see [`crate::size`]). Defaults to 1MiB.
+///
+/// Logical partitions of a MBR are aligned the same way, and so are their EBRs. Without `first_partition_offset`, the first partition starts at its alignment.
+///
+/// For example, some eMMC layouts want the partitions aligned to 4MiB:
+///
+/// ```toml
+/// # other fields
+/// align = "4MiB"
+/// ```
+///
+/// `size_in_sectors` (or `size`) - Partition size
+/// ------------------------------------------------
 ///
 /// Defines the size of the partition, in 512-byte sectors.
 ///
-/// Use `0` if you want to fill the partition all the way to the end - only for the last partition.
+/// Use `"rest"` (or `"100%"`) if you want to fill the partition all the way to the end - only for the last partition. `0` is still accepted for the same purpose, but `"rest"` is preferred. Other percentages are not supported.
 ///
 /// For example, for a 300MiB partition, the value would be `300 * 1024 * 2 = 614400` (1 KiB = 2 sectors).
 ///
+/// The size can also be a string with a unit, e.g. `"300MiB"` (see [`crate::size`]). Either is converted to the sector size of the disk while partitioning, rounded up to a whole sector.
+///
 /// A partition can not be smaller than 512B (1 sector).
 ///
 /// ```toml
@@ -201,8 +589,19 @@ pub enum PartitionType {
 /// size = 2048
 /// # 1GiB partition
 /// size = 2097152
+/// # Same as above
+/// size = "1GiB"
 /// # Max available free space
-/// size = 0
+/// size = "rest"
+/// ```
+///
+/// The size can also be defined for each variant, with a table containing all of the `base`, `desktop` and `server` keys. Each variant is laid out, and checked to fit, in the image size of the variant:
+///
+/// ```toml
+/// [[partition]]
+/// # other fields ...
+/// # 4GiB on the base image, the rest of the desktop and server images
+/// size = { base = 8388608, desktop = "rest", server = "rest" }
 /// ```
 ///
 /// `label` - Partition label (GPT Only, Optional)
@@ -230,6 +629,9 @@ pub enum PartitionType {
 /// - `xfs`: XFS from Sun Microsystems.
 /// - `fat16`: FAT16 filesystem, can not be used as the root filesystem.
 /// - `fat32`: FAT32 filesystem, can not be used as the root filesystem.
+/// - `erofs`: Read-only EROFS, only for the root filesystem. It is built from the populated root filesystem after the bootloaders are applied, instead of being formatted. See [`crate::erofs`] for details.
+/// - `exfat`: exFAT filesystem, for the data partitions shared with Windows or macOS hosts, e.g. over USB mass storage. Can not be used as the root filesystem. Unless `mount_opts` is set, it is mounted with the files owned by the built-in user (`uid=1000,gid=1000,umask=0022`).
+/// - `swap`: Swap space, initialized by `mkswap`. Only for the partitions with `usage = "swap"`.
 /// - `none`: Not to be formatted.
 ///
 /// ```toml
@@ -241,7 +643,15 @@ pub enum PartitionType {
 /// `fs_label` - Filesystem label (Optional)
 /// ----------------------------------------
 ///
-/// Label of the filesystem. Its limitation varies by filesystem type.
+/// Label of the filesystem, passed to mkfs, so the filesystem can be referred to with `LABEL=` in `/etc/fstab` or the boot scripts. Its limit varies by filesystem type, and is checked along with the specification:
+///
+/// - `fat16`, `fat32`: 11 ASCII characters, uppercased when applied.
+/// - `exfat`: 11 UTF-16 characters.
+/// - `ext4`, `erofs`, `swap`: 16 bytes.
+/// - `xfs`: 12 bytes.
+/// - `btrfs`: 255 bytes.
+///
+/// The labels applied are recorded in the build report.
 ///
 /// ```toml
 /// [[partition]]
@@ -249,6 +659,161 @@ pub enum PartitionType {
 /// fs_label = "AOSC OS"
 /// ```
 ///
+/// `fs_compression` - EROFS Compression (Optional)
+/// -----------------------------------------------
+///
+/// Compression of an EROFS filesystem, an algorithm of `mkfs.erofs` (`lz4`, `lz4hc`, `lzma`, `deflate` or `zstd`), optionally followed by the level. Defaults to `lz4hc`.
+///
+/// ```toml
+/// [[partition]]
+/// # other fields ...
+/// filesystem = "erofs"
+/// fs_compression = "lzma,6"
+/// ```
+///
+/// `mkfs_options` - Extra mkfs Options (Optional)
+/// ----------------------------------------------
+///
+/// Options appended verbatim to the command line of mkfs, e.g. `-O ^has_journal` for ext4 or `-S 4096` for FAT on 4Kn media. They can not set the label or the UUID of the filesystem if the partition also declares them. Dropped if `--fstype` replaces the filesystem of the root partition.
+///
+/// ```toml
+/// [[partition]]
+/// # other fields ...
+/// filesystem = "ext4"
+/// mkfs_options = ["-O", "^has_journal"]
+/// ```
+///
+/// `fstab_style` - Reference in `/etc/fstab` (Optional)
+/// ----------------------------------------------------
+///
+/// How the partition is referred to in `/etc/fstab`, overriding `fstab_style` of the device: `uuid`, `partuuid`, `label` (requires a unique `fs_label`) or `path`. Can not be used on the encrypted partitions. See [`crate::fstab`] for details.
+///
+/// ```toml
+/// [[partition]]
+/// # other fields ...
+/// fs_label = "BOOT"
+/// fstab_style = "label"
+/// ```
+///
+/// `ext4_reserved_percent`, `ext4_default_mount_opts`, `ext4_check_interval` - ext4 Tuning (Optional)
+/// --------------------------------------------------------------------------------------------------
+///
+/// Applied with `tune2fs` after the ext4 filesystem is created: the percentage of the blocks reserved for root (0 to 50, `-m`), the default mount options stored in the superblock (`-o`, `^` clears an option), and the maximum interval between two forced checks (`-i`, e.g. `0` to disable them or `6m`). Dropped if `--fstype` replaces the filesystem of the root partition. See [`crate::ext4`] for details.
+///
+/// ```toml
+/// [[partition]]
+/// # other fields ...
+/// filesystem = "ext4"
+/// ext4_reserved_percent = 1
+/// ext4_default_mount_opts = ["acl"]
+/// ext4_check_interval = "0"
+/// ```
+///
+/// `subvolumes`, `default_subvolume`, `install_snapshot` - Btrfs Subvolumes (Optional)
+/// -----------------------------------------------------------------------------------
+///
+/// Subvolumes created on a btrfs filesystem, each with a `name`, and optionally a `mountpoint` and `mount_opts` (the ones of the partition by default). The subvolume with the mountpoint of the partition is mounted there, and the root filesystem is installed into it. `default_subvolume` is set as the default subvolume, and `install_snapshot = true` takes a read-only snapshot of the installation as `@snapshots/install`. See [`crate::btrfs`] for details.
+///
+/// ```toml
+/// [[partition]]
+/// # other fields ...
+/// filesystem = "btrfs"
+/// mountpoint = "/"
+/// mount_opts = ["compress=zstd:3"]
+/// subvolumes = [
+///     { name = "@", mountpoint = "/" },
+///     { name = "@home", mountpoint = "/home" },
+///     { name = "@snapshots", mountpoint = "/.snapshots" },
+/// ]
+/// default_subvolume = "@"
+/// install_snapshot = true
+/// ```
+///
+/// `content` - Raw Contents (Optional)
+/// -----------------------------------
+///
+/// A file written to the partition instead of a filesystem, e.g. the environment of a vendor bootloader or a FPGA bitstream. The partition must have `filesystem = "none"` and no mountpoint. The path is relative to the directory of `device.toml`, or an absolute path within the target with `from_target = true`. The rest of the partition is zeroed. See [`crate::content`] for details.
+///
+/// ```toml
+/// [[partition]]
+/// # other fields ...
+/// filesystem = "none"
+/// content = { type = "raw", path = "env.img" }
+/// ```
+///
+/// `encryption` - LUKS2 Encryption (Optional)
+/// -------------------------------------------
+///
+/// Format the partition as a LUKS2 container with the passphrase in `passphrase_file` (relative to the directory of `device.toml`), and create the filesystem within it. The key slot, the key derivation function and its time can be chosen with `key_slot`, `pbkdf` (`argon2id`, `argon2i` or `pbkdf2`) and `iter_time` (in milliseconds). The container is unlocked on boot by `/etc/crypttab`. The boot partition can not be encrypted. See [`crate::encryption`] for details.
+///
+/// ```toml
+/// [[partition]]
+/// # other fields ...
+/// filesystem = "ext4"
+/// mountpoint = "/home"
+/// encryption = { type = "luks2", passphrase_file = "passphrase.txt" }
+/// ```
+///
+/// `attributes` - GPT Partition Attributes (GPT Only, Optional)
+/// --------------------------------------------------------------
+///
+/// Attribute bits of the partition entry, required by some boot flows (e.g. depthcharge, or `legacy-boot` for BIOSes booting GPT disks). Either:
+///
+/// - A non-negative integer, the raw value of the attribute field. Bit 63 can only be set with a list.
+/// - A list of bit numbers (0-63), and the names of the common bits: `required` (0), `no-block-io` (1) and `legacy-boot` (2).
+///
+/// `legacy-boot` only matters if a bootloader writes the boot code to the protective MBR (a `flash_offset` step at offset 0), `check` warns otherwise.
+///
+/// ```toml
+/// [[partition]]
+/// # other fields ...
+/// attributes = ["legacy-boot", 48, 56]
+/// # Same as above
+/// attributes = 0x0101000000000004
+/// ```
+///
+/// `slots`, `empty_slot_b` - A/B Slots (Optional)
+/// ---------------------------------------------
+///
+/// With `slots = 2`, the partition is expanded into two identical partitions for A/B updates, slot A numbered `num` and slot B numbered `num + 1`. The labels get the suffixes `_a` and `_b`. Only slot A is mounted and populated, slot B is formatted but left empty, or not formatted at all with `empty_slot_b = true`. See [`crate::slots`] for details.
+///
+/// ```toml
+/// [[partition]]
+/// # other fields ...
+/// label = "root"
+/// size = "4GiB"
+/// slots = 2
+/// ```
+///
+/// `hybrid_mbr` - Mirrored in the Hybrid MBR (Hybrid Only, Optional)
+/// ------------------------------------------------------------------
+///
+/// With `partition_map = "hybrid"`, up to 3 partitions marked with `hybrid_mbr = true` are mirrored in the MBR, for the boot ROMs only reading the MBR. The partition type must have a MBR type, and the partition must be within the first 2 TiB of the disk. See [`crate::hybrid`] for details.
+///
+/// ```toml
+/// [[partition]]
+/// # other fields ...
+/// usage = "boot"
+/// hybrid_mbr = true
+/// ```
+///
+/// `part_uuid`, `fs_uuid` - Pinned UUIDs (Optional)
+/// ------------------------------------------------
+///
+/// By default, the PARTUUID and the filesystem UUID are randomly generated for each image. They can be pinned for tools expecting well-known UUIDs, e.g. A/B updates referring to the root partition.
+///
+/// - `part_uuid`: Unique partition GUID, GPT only. The PARTUUIDs on MBR are derived from the disk signature, pin `disk_uuid` of the device instead.
+/// - `fs_uuid`: UUID of the filesystem. FAT and exFAT filesystems have a 32-bit volume ID instead, written as `XXXX-XXXX`.
+///
+/// A pinned UUID must be unique within the device.
+///
+/// ```toml
+/// [[partition]]
+/// # other fields ...
+/// part_uuid = "5B1B7E3C-2D0A-4F5E-9C1D-3A8E6F4B2C10"
+/// fs_uuid = "0D9F8C7B-6A5E-4D3C-8B2A-1F0E9D8C7B6A"
+/// ```
+///
 /// `mountpoint` - Mount point of the filesystem
 /// --------------------------------------------
 ///
@@ -288,7 +853,10 @@ pub enum PartitionType {
 ///
 /// - `boot`: Boot partition. Only one boot partition is allowed, and will be marked as active if MBR is used.
 /// - `rootfs`: Root filesystem. Only one root partition is allowed.
+/// - `swap`: Swap partition. It must have `filesystem = "swap"` and no mountpoint, the swap space is initialized by `mkswap` and activated by a `/etc/fstab` entry (`none swap sw`, or `mount_opts` if specified). Prefer `type = "swap"` for the partition type. Since the partition following the root partition prevents it from being expanded, place the swap partition before the root partition, or use [swap] instead.
 /// - `data`: Data partition.
+/// - `firmware`: Boot firmware partition, e.g. `uboot` of Rockchip SoCs or `bootloader` of Amlogic SoCs. It is recorded in the partition table with its type (the type GUID on GPT, or the system byte on MBR), but never formatted, mounted or populated, and it is neither listed in `/etc/fstab` nor given a filesystem UUID. Its `filesystem` is `none` if left out, and it can not have a mountpoint, its contents are written raw by a `flash_partition` bootloader step or [`content`](#content---raw-contents-optional).
+/// - `overlay`: Writable partition holding the upper layers of the overlays mounted on `/etc` and `/var`, with an EROFS root filesystem. It must be ext4, xfs or btrfs, and have a mountpoint outside of them. Only one overlay partition is allowed. See [`crate::erofs`] for details.
 /// - `Other`: Other uses.
 ///
 /// Examples
@@ -315,7 +883,7 @@ pub enum PartitionType {
 /// [[partition]]
 /// num = 2
 /// type = "linux"
-/// size_in_sectors = 0
+/// size_in_sectors = "rest"
 /// mountpoint = "/"
 /// filesystem = "ext4"
 /// usage = "rootfs"
@@ -361,7 +929,7 @@ pub enum PartitionType {
 /// num = 4
 /// type = "linux"
 /// label = "Root"
-/// size_in_sectors = 0
+/// size_in_sectors = "rest"
 /// filesystem = "btrfs"
 /// mount_opts = ["compress=zstd"]
 /// mountpoint = "/"
@@ -375,6 +943,10 @@ pub enum PartitionType {
 /// [`"basic"`]: PartitionType::Basic
 /// [`"uuid"`]: PartitionType::Uuid
 /// [`"byte"`]: PartitionType::Byte
+/// [`"vendor"`]: PartitionType::Vendor
+/// [Discoverable partition types]: PartitionType::Discoverable
+/// [`"chromeos_kernel"`]: PartitionType::ChromeosKernel
+/// [swap]: crate::swap::SwapSpec
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct PartitionSpec {
@@ -382,14 +954,394 @@ pub struct PartitionSpec {
 	pub num: u32,
 	#[serde(rename = "type", flatten)]
 	pub part_type: PartitionType,
-	pub start_sector: Option<u64>,
-	pub size_in_sectors: u64,
+	#[serde(alias = "start")]
+	pub start_sector: Option<SectorOffset>,
+	/// Alignment of the starting position, if it is not defined.
+	pub align: Option<SectorOffset>,
+	#[serde(alias = "size")]
+	pub size_in_sectors: PartitionSize,
 	pub label: Option<String>,
 	pub mountpoint: Option<String>,
 	pub filesystem: FilesystemType,
 	pub mount_opts: Option<Vec<String>>,
 	pub fs_label: Option<String>,
+	/// Pinned PARTUUID, GPT only.
+	pub part_uuid: Option<String>,
+	/// Pinned UUID of the filesystem, or the volume ID of a FAT filesystem.
+	pub fs_uuid: Option<String>,
+	/// Compression of an EROFS filesystem.
+	pub fs_compression: Option<String>,
+	/// Extra options appended to the mkfs command line.
+	pub mkfs_options: Option<Vec<String>>,
+	/// How the partition is referred to in `/etc/fstab`, overriding the one of the device.
+	pub fstab_style: Option<FstabStyle>,
+	/// Percentage of the blocks of an ext4 filesystem reserved for root.
+	pub ext4_reserved_percent: Option<u8>,
+	/// Default mount options stored in the superblock of an ext4 filesystem.
+	pub ext4_default_mount_opts: Option<Vec<String>>,
+	/// Maximum interval between two forced checks of an ext4 filesystem.
+	pub ext4_check_interval: Option<String>,
+	/// Subvolumes of a btrfs filesystem.
+	#[serde(default)]
+	pub subvolumes: Vec<BtrfsSubvolume>,
+	/// Default subvolume of a btrfs filesystem.
+	pub default_subvolume: Option<String>,
+	/// Snapshot the installation as `@snapshots/install`, on a btrfs filesystem.
+	#[serde(default)]
+	pub install_snapshot: bool,
+	/// GPT partition attribute bits.
+	#[serde(default)]
+	pub attributes: PartitionAttributes,
+	/// File written to the partition instead of a filesystem.
+	pub content: Option<PartitionContent>,
+	/// LUKS2 encryption of the partition.
+	pub encryption: Option<EncryptionSpec>,
 	pub usage: PartitionUsage,
+	/// Number of the A/B slots, expanded when the specification is loaded.
+	pub slots: Option<u32>,
+	/// Leave slot B without a filesystem, instead of formatting it empty.
+	#[serde(default)]
+	pub empty_slot_b: bool,
+	/// Mirror the partition in the hybrid MBR.
+	#[serde(default)]
+	pub hybrid_mbr: bool,
+	/// Slot of an expanded partition.
+	#[serde(skip)]
+	pub slot: Option<Slot>,
+}
+
+impl PartitionSpec {
+	/// Get the partition label, falls back to the default label of the vendor partition type.
+	pub fn get_label(&self) -> Option<String> {
+		if let Some(label) = &self.label {
+			return Some(label.to_owned());
+		}
+		self.part_type
+			.vendor_part_type()
+			.ok()
+			.map(|v| v.label.to_owned())
+	}
+
+	/// Size of the partition in the variant, `None` if it fills the rest of the image.
+	pub fn sectors(&self, variant: &ImageVariant) -> Option<u64> {
+		self.size_in_sectors.sectors(variant)
+	}
+
+	/// Size of the partition in the variant, in sectors of `sector_size` bytes.
+	pub fn sectors_in(&self, variant: &ImageVariant, sector_size: u64) -> Option<u64> {
+		self.size_in_sectors.sectors_in(variant, sector_size)
+	}
+
+	/// Starting sector of the partition, if defined.
+	pub fn start(&self) -> Option<u64> {
+		self.start_in(SECTOR_SIZE)
+	}
+
+	/// Starting sector of the partition in sectors of `sector_size` bytes, if defined.
+	pub fn start_in(&self, sector_size: u64) -> Option<u64> {
+		self.start_sector.map(|start| start.sectors(sector_size))
+	}
+
+	/// Alignment of the partition in sectors, defaults to 1MiB.
+	pub fn align(&self) -> u64 {
+		self.align_in(SECTOR_SIZE)
+	}
+
+	/// Alignment of the partition in sectors of `sector_size` bytes, at least one sector.
+	pub fn align_in(&self, sector_size: u64) -> u64 {
+		self.align
+			.map(|align| align.sectors(sector_size))
+			.unwrap_or(DEFAULT_ALIGN / sector_size)
+			.max(1)
+	}
+}
+
+/// Size of the sectors the sizes are counted in while checking and planning the layout.
+///
+/// The sizes, in these sectors or with units, are converted to the actual sector size of the disk while partitioning.
+pub const SECTOR_SIZE: u64 = 512;
+
+/// Default alignment of the partitions, and the default gap before the first partition, in bytes.
+pub const DEFAULT_ALIGN: u64 = MIB;
+
+/// Starting position of a partition, or an offset or alignment, in 512-byte sectors, or in bytes if it is given with a unit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "Value", into = "Value")]
+pub enum SectorOffset {
+	/// In sectors of [`SECTOR_SIZE`] bytes, whatever the sector size of the disk is.
+	Sectors(u64),
+	Bytes(u64),
+}
+
+impl SectorOffset {
+	/// The position in sectors of `sector_size` bytes, rounded up to the sector boundary.
+	pub fn sectors(&self, sector_size: u64) -> u64 {
+		match self {
+			Self::Sectors(n) => {
+				bytes_to_sectors(n.saturating_mul(SECTOR_SIZE), sector_size)
+			}
+			Self::Bytes(n) => bytes_to_sectors(*n, sector_size),
+		}
+	}
+}
+
+impl TryFrom<Value> for SectorOffset {
+	type Error = anyhow::Error;
+
+	fn try_from(value: Value) -> Result<Self> {
+		match value {
+			Value::Integer(n) if n >= 0 => Ok(Self::Sectors(n as u64)),
+			Value::String(s) => Ok(Self::Bytes(parse_size(&s)?)),
+			v => bail!(
+				"Invalid position '{}', expected a sector number or a size with a unit",
+				v
+			),
+		}
+	}
+}
+
+impl From<SectorOffset> for Value {
+	fn from(value: SectorOffset) -> Self {
+		match value {
+			SectorOffset::Sectors(n) => Value::Integer(n as i64),
+			SectorOffset::Bytes(n) => Value::String(format_size(n)),
+		}
+	}
+}
+
+/// Size of a partition, in 512-byte sectors, or in bytes if it is given with a unit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "Value", into = "Value")]
+pub enum SectorSize {
+	/// In sectors of [`SECTOR_SIZE`] bytes, whatever the sector size of the disk is.
+	Sectors(u64),
+	Bytes(u64),
+	/// Fills the rest of the image.
+	Rest,
+}
+
+impl SectorSize {
+	/// The size in sectors of `sector_size` bytes, rounded up to the sector boundary.
+	pub fn sectors(&self, sector_size: u64) -> Option<u64> {
+		match self {
+			Self::Sectors(n) => {
+				Some(bytes_to_sectors(n.saturating_mul(SECTOR_SIZE), sector_size))
+			}
+			Self::Bytes(n) => Some(bytes_to_sectors(*n, sector_size)),
+			Self::Rest => None,
+		}
+	}
+}
+
+impl TryFrom<Value> for SectorSize {
+	type Error = anyhow::Error;
+
+	fn try_from(value: Value) -> Result<Self> {
+		match value {
+			// The magic number used before "rest".
+			Value::Integer(0) => Ok(Self::Rest),
+			Value::Integer(n) if n > 0 => Ok(Self::Sectors(n as u64)),
+			Value::String(s) if s == "rest" || s == "100%" => Ok(Self::Rest),
+			Value::String(s) if s.ends_with('%') => bail!(
+				"Invalid partition size '{}', only 100% (the rest of the image) is supported",
+				s
+			),
+			Value::String(s) => match parse_size(&s)? {
+				0 => bail!("Partition size can not be zero"),
+				n => Ok(Self::Bytes(n)),
+			},
+			v => bail!(
+				"Invalid partition size '{}', expected a number of sectors, a size with a unit or \"rest\"",
+				v
+			),
+		}
+	}
+}
+
+impl From<SectorSize> for Value {
+	fn from(value: SectorSize) -> Self {
+		match value {
+			SectorSize::Sectors(n) => Value::Integer(n as i64),
+			SectorSize::Bytes(n) => Value::String(format_size(n)),
+			SectorSize::Rest => Value::String("rest".into()),
+		}
+	}
+}
+
+/// Size of a partition, either the same in every variant or one for each variant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "Value", into = "Value")]
+pub enum PartitionSize {
+	Fixed(SectorSize),
+	Variants {
+		base: SectorSize,
+		desktop: SectorSize,
+		server: SectorSize,
+	},
+}
+
+impl PartitionSize {
+	/// The size in the variant in 512-byte sectors, `None` if it fills the rest of the image.
+	pub fn sectors(&self, variant: &ImageVariant) -> Option<u64> {
+		self.sectors_in(variant, SECTOR_SIZE)
+	}
+
+	/// The size in the variant in sectors of `sector_size` bytes, `None` if it fills the rest of the image.
+	pub fn sectors_in(&self, variant: &ImageVariant, sector_size: u64) -> Option<u64> {
+		let size = match (self, variant) {
+			(Self::Fixed(size), _) => size,
+			(Self::Variants { base, .. }, ImageVariant::Base) => base,
+			(Self::Variants { desktop, .. }, ImageVariant::Desktop) => desktop,
+			(Self::Variants { server, .. }, ImageVariant::Server) => server,
+		};
+		size.sectors(sector_size)
+	}
+
+	/// Whether the partition fills the rest of the image in any variant.
+	pub fn is_rest(&self) -> bool {
+		ImageVariant::VARIANTS
+			.iter()
+			.any(|v| self.sectors(v).is_none())
+	}
+
+	/// The largest fixed size among the variants, `None` if it fills the rest of the image in every variant.
+	pub fn max_sectors(&self) -> Option<u64> {
+		ImageVariant::VARIANTS
+			.iter()
+			.filter_map(|v| self.sectors(v))
+			.max()
+	}
+}
+
+impl TryFrom<Value> for PartitionSize {
+	type Error = anyhow::Error;
+
+	fn try_from(value: Value) -> Result<Self> {
+		let mut table = match value {
+			Value::Table(t) => t,
+			v => return Ok(Self::Fixed(SectorSize::try_from(v)?)),
+		};
+		let mut get = |key: &str| {
+			table.remove(key)
+				.context(format!(
+					"Partition size of the {} variant is missing",
+					key
+				))
+				.and_then(SectorSize::try_from)
+		};
+		let size = Self::Variants {
+			base: get("base")?,
+			desktop: get("desktop")?,
+			server: get("server")?,
+		};
+		if let Some(key) = table.keys().next() {
+			bail!(
+				"Unknown variant '{}' in the partition size, possible variants are base, desktop and server",
+				key
+			);
+		}
+		Ok(size)
+	}
+}
+
+impl From<PartitionSize> for Value {
+	fn from(value: PartitionSize) -> Self {
+		match value {
+			PartitionSize::Fixed(size) => size.into(),
+			PartitionSize::Variants {
+				base,
+				desktop,
+				server,
+			} => Value::Table(
+				[("base", base), ("desktop", desktop), ("server", server)]
+					.into_iter()
+					.map(|(k, v)| (k.to_owned(), v.into()))
+					.collect(),
+			),
+		}
+	}
+}
+
+/// Named GPT partition attribute bits.
+pub const GPT_ATTRIBUTE_NAMES: &[(&str, i64)] =
+	&[("required", 0), ("no-block-io", 1), ("legacy-boot", 2)];
+/// Bit of the "legacy BIOS bootable" attribute.
+pub const GPT_ATTRIBUTE_LEGACY_BOOT: i64 = 2;
+
+/// GPT partition attribute bits, as a list of bit numbers.
+///
+/// The bits are only checked to be within 0-63 by [`PartitionAttributes::check`],
+/// so the error can name the partition.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "Value", into = "Value")]
+pub struct PartitionAttributes(pub Vec<i64>);
+
+impl PartitionAttributes {
+	/// Make sure the bits are within 0-63.
+	pub fn check(&self) -> Result<()> {
+		if let Some(bit) = self.0.iter().find(|b| !(0..64).contains(*b)) {
+			bail!("GPT attribute bit {} is out of range 0-63", bit);
+		}
+		Ok(())
+	}
+
+	pub fn contains(&self, bit: i64) -> bool {
+		self.0.contains(&bit)
+	}
+
+	/// The value of the attribute field of the partition entry.
+	pub fn to_bits(&self) -> u64 {
+		self.0.iter()
+			.filter(|b| (0..64).contains(*b))
+			.fold(0, |bits, b| bits | 1 << b)
+	}
+}
+
+impl TryFrom<Value> for PartitionAttributes {
+	type Error = anyhow::Error;
+
+	fn try_from(value: Value) -> Result<Self> {
+		let mut bits = match value {
+			Value::Integer(n) if n >= 0 => {
+				(0..63).filter(|b| n & 1 << b != 0).collect()
+			}
+			Value::Array(values) => values
+				.into_iter()
+				.map(|v| {
+					match v {
+					Value::Integer(bit) => Ok(bit),
+					Value::String(name) => GPT_ATTRIBUTE_NAMES
+						.iter()
+						.find(|(n, _)| *n == name)
+						.map(|(_, bit)| *bit)
+						.context(format!(
+							"Unknown GPT attribute '{}', possible values are: {}",
+							name,
+							GPT_ATTRIBUTE_NAMES
+								.iter()
+								.map(|(n, _)| *n)
+								.collect::<Vec<_>>()
+								.join(", ")
+						)),
+					v => bail!("Invalid GPT attribute '{}', expected a bit number or a name", v),
+				}
+				})
+				.collect::<Result<Vec<_>>>()?,
+			v => bail!(
+				"Invalid GPT attributes '{}', expected a non-negative integer or a list of bits",
+				v
+			),
+		};
+		bits.sort();
+		bits.dedup();
+		Ok(Self(bits))
+	}
+}
+
+impl From<PartitionAttributes> for Value {
+	fn from(value: PartitionAttributes) -> Self {
+		Value::Array(value.0.into_iter().map(Value::Integer).collect())
+	}
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -399,6 +1351,10 @@ pub enum PartitionUsage {
 	Rootfs,
 	Swap,
 	Data,
+	/// Boot firmware, written raw by the bootloader steps or `content`.
+	Firmware,
+	/// Upper layers of the overlays on an EROFS root filesystem.
+	Overlay,
 	Other,
 }
 
@@ -415,22 +1371,75 @@ impl PartitionType {
 			| Self::Byte { byte: 0x85 }
 			| Self::Byte { byte: 0x0f } => Err(anyhow!("Extended partitions are not allowed.")),
 			Self::Byte { byte } => Ok(*byte),
-			Self::Uuid { .. } => {
-				Err(anyhow!("Can not convert an arbitrary byte to UUID."))
+			Self::Uuid { uuid } => {
+				Err(anyhow!("Partition type {} is only available on GPT.", uuid))
+			}
+			Self::Discoverable { name } if name == "xbootldr" => {
+				Ok(PARTTYPE_XBOOTLDR_BYTE)
 			}
+			Self::Discoverable { name } => {
+				if !is_discoverable_part_type(name) {
+					bail!("Unknown discoverable partition type '{}'.", name);
+				}
+				Ok(PARTTYPE_LINUX_BYTE)
+			}
+			Self::Vendor { alias } => Err(anyhow!(
+				"Vendor partition type '{}' is only available on GPT.",
+				alias
+			)),
+			Self::ChromeosKernel { .. } => Err(anyhow!(
+				"ChromeOS kernel partitions are only available on GPT."
+			)),
 			Self::Nested { .. } => {
 				unimplemented!("Nested partition tables are not supported.")
 			}
 		}
 	}
-	pub fn to_uuid(&self) -> Result<Uuid> {
+	/// Attribute bits implied by the partition type, i.e. the boot flags of a ChromeOS kernel partition.
+	pub fn attribute_bits(&self) -> u64 {
+		match self {
+			Self::ChromeosKernel {
+				priority,
+				tries,
+				successful,
+			} => {
+				(*priority as u64) << CROS_PRIORITY_SHIFT
+					| (*tries as u64) << CROS_TRIES_SHIFT
+					| (*successful as u64) << CROS_SUCCESSFUL_BIT
+			}
+			_ => 0,
+		}
+	}
+	/// Get the vendor partition type alias, if this is a vendor partition type.
+	pub fn vendor_part_type(&self) -> Result<&'static VendorPartType> {
+		match self {
+			Self::Vendor { alias } => find_vendor_part_type(alias).ok_or_else(|| {
+				anyhow!("Unknown vendor partition type '{}'.", alias)
+			}),
+			_ => Err(anyhow!("Not a vendor partition type.")),
+		}
+	}
+	/// Get the partition type GUID, the discoverable partition types are resolved with the architecture of the device.
+	pub fn to_uuid(&self, arch: &DeviceArch) -> Result<Uuid> {
 		match self {
 			Self::EFI => Ok(PARTTYPE_EFI_UUID),
 			Self::Linux => Ok(PARTTYPE_LINUX_UUID),
 			Self::Swap => Ok(PARTTYPE_SWAP_UUID),
 			Self::Basic => Ok(PARTTYPE_BASIC_UUID),
 			Self::Uuid { uuid } => Ok(*uuid),
+			Self::Discoverable { name } => find_discoverable_part_type(name, arch),
+			Self::ChromeosKernel { .. } => Ok(PARTTYPE_CROS_KERNEL_UUID),
 			Self::Byte { .. } => Err(anyhow!("Can not convert an MBR type to UUID.")),
+			Self::Vendor { alias } => {
+				let vendor = self.vendor_part_type()?;
+				Uuid::parse_str(vendor.guid).map_err(|e| {
+					anyhow!(
+						"Invalid GUID for vendor partition type '{}': {}",
+						alias,
+						e
+					)
+				})
+			}
 			Self::Nested { .. } => {
 				Err(anyhow!("Nested partition tables are not supported."))
 			}
@@ -448,6 +1457,8 @@ mod tests {
 	const TEST_UUID: &str = "type = \"uuid\"\nuuid = \"933AC7E1-2EB4-4F13-B844-0E14E2AEF915\"";
 	const TEST_BYTE: &str = "type = \"byte\"\nbyte = 0x0c";
 	const TEST_EXTENDED: &str = "type = \"byte\"\nbyte = 0x05";
+	const TEST_DISCOVERABLE: &str = r#"type = "linux-root""#;
+	const TEST_GUID: &str = r#"type = "3de21764-95bd-54bd-a5c3-4abe786f38a8""#;
 
 	use super::*;
 	use toml;
@@ -477,6 +1488,255 @@ mod tests {
 				.to_string(),
 			String::from("Extended partitions are not allowed.")
 		);
+		assert_eq!(
+			get!(TEST_DISCOVERABLE),
+			Ok(PartitionType::Discoverable {
+				name: "linux-root".into()
+			})
+		);
+		assert_eq!(
+			get!(TEST_GUID),
+			Ok(PartitionType::Uuid {
+				uuid: uuid!("3DE21764-95BD-54BD-A5C3-4ABE786F38A8")
+			})
+		);
+		assert!(toml::from_str::<PartitionType>("type = \"root-vax\"").is_err());
+		assert!(toml::from_str::<PartitionType>("type = \"uuid\"").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_vendor_part_types() -> Result<()> {
+		let mut names = Vec::new();
+		let mut guids = Vec::new();
+		for v in VENDOR_PART_TYPES {
+			assert!(
+				Uuid::parse_str(v.guid).is_ok(),
+				"Invalid GUID for vendor partition type {}",
+				v.name
+			);
+			assert!(!v.label.is_empty() && v.label.len() <= 35);
+			assert!(!names.contains(&v.name), "Duplicate alias {}", v.name);
+			names.push(v.name);
+			// Only the documented shared types may be reused.
+			if ![PARTTYPE_ANDROID_BOOTLOADER_GUID, PARTTYPE_UBOOT_ENV_GUID]
+				.contains(&v.guid)
+			{
+				assert!(!guids.contains(&v.guid), "Duplicate GUID for {}", v.name);
+			}
+			guids.push(v.guid);
+		}
+		let t = toml::from_str::<PartitionType>("type = \"vendor\"\nalias = \"qcom-xbl\"")?;
+		assert_eq!(
+			t.to_uuid(&DeviceArch::Arm64)?,
+			uuid!("DEA0BA2C-CBDD-4805-B4F9-F428251C3E98")
+		);
+		assert!(t.to_byte().is_err());
+		let t = PartitionType::Vendor {
+			alias: "whatever".into(),
+		};
+		assert!(t.to_uuid(&DeviceArch::Arm64).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_discoverable_part_types() -> Result<()> {
+		for (_, guid) in DPS_ROOT_TYPES
+			.iter()
+			.chain(DPS_USR_TYPES)
+			.chain(DPS_OTHER_TYPES)
+		{
+			assert!(Uuid::parse_str(guid).is_ok(), "Invalid GUID {}", guid);
+		}
+		let t = PartitionType::Discoverable {
+			name: "linux-root".into(),
+		};
+		assert_eq!(
+			t.to_uuid(&DeviceArch::Arm64)?,
+			uuid!("B921B045-1DF0-41C3-AF44-4C6F280D3FAE")
+		);
+		assert_eq!(
+			t.to_uuid(&DeviceArch::Riscv64)?,
+			uuid!("72EC70A6-CF74-40E6-BD49-4BDA08E8F224")
+		);
+		assert!(t.to_uuid(&DeviceArch::Mips64r6el).is_err());
+		assert_eq!(t.to_byte()?, PARTTYPE_LINUX_BYTE);
+		assert_eq!(
+			find_discoverable_part_type("root-arm64", &DeviceArch::Amd64)?,
+			uuid!("B921B045-1DF0-41C3-AF44-4C6F280D3FAE")
+		);
+		assert_eq!(
+			find_discoverable_part_type("usr", &DeviceArch::Amd64)?,
+			uuid!("8484680C-9521-48C6-9C11-B0720656F69E")
+		);
+		assert!(find_discoverable_part_type("root-vax", &DeviceArch::Amd64).is_err());
+		let t = PartitionType::Discoverable {
+			name: "xbootldr".into(),
+		};
+		assert_eq!(t.to_byte()?, PARTTYPE_XBOOTLDR_BYTE);
+		assert!(is_discoverable_part_type("var"));
+		assert!(is_discoverable_part_type("usr-loongarch64"));
+		assert!(!is_discoverable_part_type("root-vax"));
+		assert!(!is_discoverable_part_type("linux"));
+		Ok(())
+	}
+
+	#[test]
+	fn test_chromeos_kernel() -> Result<()> {
+		let raw = |priority, tries| RawPartitionType {
+			part_type: "chromeos_kernel".into(),
+			uuid: None,
+			byte: None,
+			alias: None,
+			name: None,
+			table_type: None,
+			partitions: Vec::new(),
+			priority,
+			tries,
+			successful: None,
+		};
+		let t = PartitionType::try_from(raw(None, None))?;
+		assert_eq!(
+			t,
+			PartitionType::ChromeosKernel {
+				priority: 10,
+				tries: 5,
+				successful: true
+			}
+		);
+		// cgpt add -P 10 -T 5 -S 1
+		assert_eq!(t.attribute_bits(), 0x015a_0000_0000_0000);
+		assert_eq!(t.to_uuid(&DeviceArch::Arm64)?, PARTTYPE_CROS_KERNEL_UUID);
+		assert!(t.to_byte().is_err());
+		let t = PartitionType::try_from(raw(Some(15), Some(0)))?;
+		assert_eq!(t.attribute_bits(), 0x010f_0000_0000_0000);
+		assert!(PartitionType::try_from(raw(Some(16), None)).is_err());
+		assert!(PartitionType::try_from(raw(None, Some(16))).is_err());
+		assert_eq!(PartitionType::Linux.attribute_bits(), 0);
+		Ok(())
+	}
+
+	#[test]
+	fn test_partition_size() -> Result<()> {
+		let size = PartitionSize::try_from(Value::Integer(2048))?;
+		assert_eq!(size, PartitionSize::Fixed(SectorSize::Sectors(2048)));
+		assert_eq!(size.sectors(&ImageVariant::Desktop), Some(2048));
+		assert!(!size.is_rest());
+		let rest = PartitionSize::try_from(Value::from("rest"))?;
+		assert_eq!(rest, PartitionSize::try_from(Value::Integer(0))?);
+		assert_eq!(rest.sectors(&ImageVariant::Base), None);
+		assert!(PartitionSize::try_from(Value::from("all")).is_err());
+		assert!(PartitionSize::try_from(Value::Integer(-1)).is_err());
+		let table = |pairs: &[(&str, Value)]| {
+			Value::Table(
+				pairs.iter()
+					.map(|(k, v)| (k.to_string(), v.clone()))
+					.collect(),
+			)
+		};
+		let size = PartitionSize::try_from(table(&[
+			("base", Value::Integer(8388608)),
+			("desktop", "rest".into()),
+			("server", Value::Integer(16777216)),
+		]))?;
+		assert_eq!(size.sectors(&ImageVariant::Base), Some(8388608));
+		assert_eq!(size.sectors(&ImageVariant::Desktop), None);
+		assert_eq!(size.max_sectors(), Some(16777216));
+		assert!(size.is_rest());
+		assert_eq!(PartitionSize::try_from(Value::from(size))?, size);
+		let err = PartitionSize::try_from(table(&[("base", Value::Integer(2048))]))
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("desktop variant is missing"));
+		let err = PartitionSize::try_from(table(&[
+			("base", "rest".into()),
+			("desktop", "rest".into()),
+			("server", "rest".into()),
+			("minimal", "rest".into()),
+		]))
+		.unwrap_err()
+		.to_string();
+		assert!(err.contains("'minimal'"));
+		Ok(())
+	}
+
+	#[test]
+	fn test_partition_size_units() -> Result<()> {
+		let size = PartitionSize::try_from(Value::from("300MiB"))?;
+		assert_eq!(size, PartitionSize::Fixed(SectorSize::Bytes(300 << 20)));
+		assert_eq!(size.sectors(&ImageVariant::Base), Some(614400));
+		assert_eq!(size.sectors_in(&ImageVariant::Base, 4096), Some(76800));
+		// Rounded up to a whole sector
+		let size = PartitionSize::try_from(Value::from("1025B"))?;
+		assert_eq!(size.sectors(&ImageVariant::Base), Some(3));
+		assert_eq!(size.sectors_in(&ImageVariant::Base, 4096), Some(1));
+		let size = PartitionSize::Fixed(SectorSize::Sectors(614400));
+		assert_eq!(size.sectors_in(&ImageVariant::Base, 4096), Some(76800));
+		assert_eq!(
+			PartitionSize::try_from(Value::from("100%"))?,
+			PartitionSize::Fixed(SectorSize::Rest)
+		);
+		assert!(PartitionSize::try_from(Value::from("50%")).is_err());
+		assert!(PartitionSize::try_from(Value::from("0MiB")).is_err());
+		assert!(PartitionSize::try_from(Value::from("300")).is_err());
+		let size = PartitionSize::Fixed(SectorSize::Bytes(2 << 30));
+		assert_eq!(Value::from(size), Value::from("2GiB"));
+		assert_eq!(PartitionSize::try_from(Value::from(size))?, size);
+		Ok(())
+	}
+
+	#[test]
+	fn test_sector_offset() -> Result<()> {
+		let start = SectorOffset::try_from(Value::from("1MiB"))?;
+		assert_eq!(start.sectors(512), 2048);
+		assert_eq!(start.sectors(4096), 256);
+		assert_eq!(
+			SectorOffset::try_from(Value::from("32KiB"))?.sectors(512),
+			64
+		);
+		assert_eq!(
+			SectorOffset::try_from(Value::from("1000B"))?.sectors(512),
+			2
+		);
+		// Bare numbers are 512-byte sectors on any disk.
+		assert_eq!(SectorOffset::try_from(Value::Integer(64))?.sectors(4096), 8);
+		assert_eq!(SectorOffset::Sectors(1).sectors(4096), 1);
+		assert!(SectorOffset::try_from(Value::Integer(-1)).is_err());
+		assert!(SectorOffset::try_from(Value::from("rest")).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_partition_attributes() -> Result<()> {
+		let attrs = PartitionAttributes::try_from(Value::Array(vec![
+			"legacy-boot".into(),
+			Value::Integer(56),
+			Value::Integer(48),
+			Value::Integer(2),
+		]))?;
+		assert_eq!(attrs, PartitionAttributes(vec![2, 48, 56]));
+		assert_eq!(attrs.to_bits(), 0x0101000000000004);
+		assert!(attrs.check().is_ok());
+		assert_eq!(
+			PartitionAttributes::try_from(Value::Integer(0x0101000000000004))?,
+			attrs
+		);
+		assert_eq!(
+			PartitionAttributes::try_from(Value::Array(vec![Value::Integer(63)]))?
+				.to_bits(),
+			1 << 63
+		);
+		let attrs = PartitionAttributes::try_from(Value::Array(vec![Value::Integer(64)]))?;
+		assert!(attrs.check().is_err());
+		assert!(
+			PartitionAttributes::try_from(Value::Array(vec!["bootable".into()]))
+				.is_err()
+		);
+		assert!(PartitionAttributes::try_from(Value::Integer(-1)).is_err());
+		assert_eq!(
+			PartitionAttributes::try_from(Value::from(attrs.clone()))?,
+			attrs
+		);
 		Ok(())
 	}
 }
@@ -0,0 +1,342 @@
+//! Module handling the paths declared to be created in the image.
+//!
+//! For details please go to [`PathSpec`].
+use std::{
+	fs::{self, File},
+	io::Write,
+	os::unix::fs::{lchown, symlink, PermissionsExt},
+	path::{Component, Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::context::ImageContext;
+
+const PASSWD_PATH: &str = "etc/passwd";
+const GROUP_PATH: &str = "etc/group";
+
+/// Type of the path, named after the types used in tmpfiles.d(5).
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+pub enum PathType {
+	/// A directory.
+	#[serde(rename = "d")]
+	Directory,
+	/// A regular file.
+	#[serde(rename = "f")]
+	File,
+	/// A symbolic link.
+	#[serde(rename = "L")]
+	Symlink,
+}
+
+/// A path to be created in the image, similar to a tmpfiles.d(5) entry.
+///
+/// Useful for mount points and data directories which must exist with
+/// specific ownership. The paths are created after the post installation
+/// script, so users and groups created there can be used.
+///
+/// Owners and groups are resolved against `/etc/passwd` and `/etc/group` of
+/// the target, numeric IDs are also accepted. Existing paths of the same type
+/// are updated, while existing paths of a different type are an error.
+///
+/// Fields
+/// ------
+///
+/// - `path`: Absolute path in the target.
+/// - `type`: `"d"` for directories, `"f"` for regular files, `"L"` for symbolic links.
+/// - `mode` (Optional): Permission bits, default is `0o755` for directories and `0o644` for files. Not allowed on symbolic links.
+/// - `owner`, `group` (Optional): Owner and group, default is `root`.
+/// - `target`: Target of the symbolic link, required by `"L"` only.
+/// - `content` (Optional): Content of the file, only allowed on `"f"`. Existing files are overwritten if specified.
+///
+/// ```toml
+/// [[paths]]
+/// path = "/media/usb0"
+/// type = "d"
+/// mode = 0o750
+/// owner = "kiosk"
+/// group = "plugdev"
+///
+/// [[paths]]
+/// path = "/srv/data"
+/// type = "L"
+/// target = "/media/usb0"
+///
+/// [[paths]]
+/// path = "/etc/kiosk/url"
+/// type = "f"
+/// mode = 0o640
+/// group = "kiosk"
+/// content = "https://aosc.io\n"
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct PathSpec {
+	pub path: PathBuf,
+	#[serde(rename = "type")]
+	pub path_type: PathType,
+	pub mode: Option<u32>,
+	pub owner: Option<String>,
+	pub group: Option<String>,
+	pub target: Option<PathBuf>,
+	pub content: Option<String>,
+}
+
+/// Resolve a user or group name with a passwd(5) or group(5) file.
+fn lookup_id(db: &Path, name: &str) -> Result<u32> {
+	if let Ok(id) = name.parse::<u32>() {
+		return Ok(id);
+	}
+	let content = fs::read_to_string(db).context(format!("Failed to read {}", db.display()))?;
+	content.lines()
+		.map(|l| l.split(':').collect::<Vec<_>>())
+		.find(|f| f.len() > 2 && f[0] == name)
+		.context(format!("'{}' is not found in the target", name))?[2]
+		.parse::<u32>()
+		.context(format!("Invalid ID of '{}'", name))
+}
+
+impl PathSpec {
+	/// Check validity of the path declaration.
+	pub fn check(&self) -> Result<()> {
+		let path = &self.path;
+		if !path.is_absolute() || path.parent().is_none() {
+			bail!("'{}' must be an absolute path other than /", path.display());
+		}
+		if path.components().any(|c| c == Component::ParentDir) {
+			bail!("'{}' can not contain '..'", path.display());
+		}
+		if let Some(mode) = self.mode {
+			if mode > 0o7777 {
+				bail!("Invalid mode {:o} for '{}'", mode, path.display());
+			}
+		}
+		match self.path_type {
+			PathType::Directory => {
+				if self.target.is_some() || self.content.is_some() {
+					bail!(
+						"Directory '{}' can not have a target or content",
+						path.display()
+					);
+				}
+			}
+			PathType::File => {
+				if self.target.is_some() {
+					bail!("File '{}' can not have a target", path.display());
+				}
+			}
+			PathType::Symlink => {
+				if self.target.is_none() {
+					bail!(
+						"Symbolic link '{}' requires a target",
+						path.display()
+					);
+				}
+				if self.mode.is_some() || self.content.is_some() {
+					bail!(
+						"Symbolic link '{}' can not have a mode or content",
+						path.display()
+					);
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Create the path in the target root.
+	pub fn apply(&self, root: &Path) -> Result<()> {
+		let uid = lookup_id(
+			&root.join(PASSWD_PATH),
+			self.owner.as_deref().unwrap_or("root"),
+		)?;
+		let gid = lookup_id(
+			&root.join(GROUP_PATH),
+			self.group.as_deref().unwrap_or("root"),
+		)?;
+		let full_path = root.join(self.path.strip_prefix("/")?);
+		// Absolute symlinks in the target must not lead us to the host.
+		let mut ancestor = full_path.parent().context("Path has no parent")?;
+		while !ancestor.exists() {
+			ancestor = ancestor.parent().context("Path has no parent")?;
+		}
+		if !ancestor.canonicalize()?.starts_with(root.canonicalize()?) {
+			bail!(
+				"'{}' resolves to a path outside of the target",
+				self.path.display()
+			);
+		}
+		if let Some(parent) = full_path.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		let existing = fs::symlink_metadata(&full_path).ok();
+		let conflict = |found: &str| -> Result<()> {
+			bail!(
+				"Can not create {:?} '{}': a {} already exists",
+				self.path_type,
+				self.path.display(),
+				found
+			)
+		};
+		let describe = |m: &fs::Metadata| {
+			if m.file_type().is_symlink() {
+				"symbolic link"
+			} else if m.is_dir() {
+				"directory"
+			} else {
+				"file"
+			}
+		};
+		match self.path_type {
+			PathType::Directory => match &existing {
+				Some(m) if !m.is_dir() => conflict(describe(m))?,
+				Some(_) => (),
+				None => fs::create_dir(&full_path)?,
+			},
+			PathType::File => match &existing {
+				Some(m) if !m.is_file() => conflict(describe(m))?,
+				Some(_) if self.content.is_none() => (),
+				_ => {
+					let mut fd = File::create(&full_path)?;
+					fd.write_all(
+						self.content.as_deref().unwrap_or("").as_bytes(),
+					)?;
+					fd.sync_all()?;
+				}
+			},
+			PathType::Symlink => {
+				let target = self
+					.target
+					.as_ref()
+					.context("Symbolic link without a target")?;
+				match &existing {
+					Some(m) if !m.file_type().is_symlink() => {
+						conflict(describe(m))?
+					}
+					Some(_) => {
+						if &fs::read_link(&full_path)? != target {
+							bail!(
+								"Symbolic link '{}' already exists with a different target",
+								self.path.display()
+							);
+						}
+					}
+					None => symlink(target, &full_path)?,
+				}
+			}
+		}
+		lchown(&full_path, Some(uid), Some(gid)).context(format!(
+			"Failed to change the owner of '{}'",
+			self.path.display()
+		))?;
+		let mode = match self.path_type {
+			PathType::Directory => self.mode.unwrap_or(0o755),
+			PathType::File => self.mode.unwrap_or(0o644),
+			PathType::Symlink => return Ok(()),
+		};
+		fs::set_permissions(&full_path, fs::Permissions::from_mode(mode))?;
+		Ok(())
+	}
+}
+
+impl ImageContext<'_> {
+	/// Create the declared paths in the target root.
+	pub fn setup_paths<P: AsRef<Path>>(&self, root: P) -> Result<()> {
+		let root = root.as_ref();
+		let paths = if let Some(p) = &self.device.paths {
+			p
+		} else {
+			return Ok(());
+		};
+		self.info("Creating declared paths ...");
+		for path in paths {
+			path.apply(root)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::os::unix::fs::MetadataExt;
+
+	use super::*;
+	use crate::testutil::TempDir;
+
+	fn fake_root() -> Result<(TempDir, u32, u32)> {
+		let root = TempDir::new("paths")?;
+		let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+		fs::create_dir_all(root.join("etc"))?;
+		fs::write(
+			root.join(PASSWD_PATH),
+			format!("root:x:0:0:root:/root:/bin/bash\nkiosk:x:{}:{}::/home/kiosk:/bin/bash\n", uid, gid),
+		)?;
+		fs::write(
+			root.join(GROUP_PATH),
+			format!("root:x:0:\nkiosk:x:{}:\n", gid),
+		)?;
+		Ok((root, uid, gid))
+	}
+
+	fn spec(path: &str, path_type: PathType) -> PathSpec {
+		PathSpec {
+			path: PathBuf::from(path),
+			path_type,
+			mode: None,
+			owner: Some("kiosk".into()),
+			group: Some("kiosk".into()),
+			target: None,
+			content: None,
+		}
+	}
+
+	#[test]
+	fn test_apply_paths() -> Result<()> {
+		let (root, uid, gid) = fake_root()?;
+		let dir = PathSpec {
+			mode: Some(0o750),
+			..spec("/media/usb0", PathType::Directory)
+		};
+		dir.check()?;
+		dir.apply(&root)?;
+		let m = fs::metadata(root.join("media/usb0"))?;
+		assert!(m.is_dir());
+		assert_eq!((m.uid(), m.gid(), m.mode() & 0o7777), (uid, gid, 0o750));
+		// Applying twice is fine.
+		dir.apply(&root)?;
+		let link = PathSpec {
+			target: Some("/media/usb0".into()),
+			..spec("/srv/data", PathType::Symlink)
+		};
+		link.check()?;
+		link.apply(&root)?;
+		assert_eq!(
+			fs::read_link(root.join("srv/data"))?,
+			Path::new("/media/usb0")
+		);
+		let file = PathSpec {
+			content: Some("https://aosc.io\n".into()),
+			..spec("/etc/kiosk/url", PathType::File)
+		};
+		file.apply(&root)?;
+		assert_eq!(
+			fs::read_to_string(root.join("etc/kiosk/url"))?,
+			"https://aosc.io\n"
+		);
+		assert_eq!(
+			fs::metadata(root.join("etc/kiosk/url"))?.mode() & 0o7777,
+			0o644
+		);
+		// Conflicts with existing paths of a different type.
+		assert!(spec("/srv/data", PathType::Directory).apply(&root).is_err());
+		assert!(spec("/media/usb0", PathType::File).apply(&root).is_err());
+		// Unknown users in the target.
+		let nobody = PathSpec {
+			owner: Some("nobody".into()),
+			..spec("/media/usb1", PathType::Directory)
+		};
+		assert!(nobody.apply(&root).is_err());
+		assert!(spec("/srv/../etc", PathType::Directory).check().is_err());
+		assert!(spec("/srv/link", PathType::Symlink).check().is_err());
+		Ok(())
+	}
+}
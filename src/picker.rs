@@ -0,0 +1,173 @@
+//! Picking the device interactively, with `build` without a device.
+//!
+//! On a terminal, `mkrawimg build` asks for a search, lists the devices
+//! matching it, and asks to confirm the selected one before building:
+//!
+//! ```plain
+//! Search for a device (empty for all): pi5
+//!   1. rpi-5b           Raspberry Pi 5 Model B (raspberrypi)
+//! Build images for Raspberry Pi 5 Model B (rpi-5b)? [y/N] y
+//! ```
+//!
+//! The search is fuzzy: the characters of the search must appear in the
+//! ID, an alias or the name of the device in order, but not necessarily
+//! next to each other. Devices with the characters closer together and at
+//! the start of the words come first.
+//!
+//! Without a terminal, a device is still required.
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use anyhow::{bail, Result};
+
+use crate::registry::DeviceEntry;
+
+/// Matches shown at once.
+const MAX_SHOWN: usize = 20;
+
+/// Whether the devices can be picked interactively.
+pub fn interactive() -> bool {
+	io::stdin().is_terminal() && io::stderr().is_terminal()
+}
+
+/// Score how well the query matches the text, `None` if it does not.
+///
+/// Every character of the query must appear in the text in order,
+/// case-insensitively. Consecutive characters and the characters at the
+/// start of the words score higher, the gaps between them score lower.
+pub fn fuzzy_score(query: &str, text: &str) -> Option<i64> {
+	let text = text.to_lowercase().chars().collect::<Vec<_>>();
+	let mut score = 0;
+	let mut last: Option<usize> = None;
+	let mut pos = 0;
+	for c in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+		let idx = pos + text[pos..].iter().position(|t| *t == c)?;
+		score += match last {
+			Some(last) if idx == last + 1 => 8,
+			Some(last) => -((idx - last - 1).min(8) as i64),
+			None => -(idx.min(8) as i64),
+		};
+		if idx == 0 || !text[idx - 1].is_alphanumeric() {
+			score += 4;
+		}
+		last = Some(idx);
+		pos = idx + 1;
+	}
+	Some(score)
+}
+
+/// The devices matching the query, the best matches first.
+pub fn search<'a>(entries: &'a [DeviceEntry], query: &str) -> Vec<&'a DeviceEntry> {
+	let mut matches = entries
+		.iter()
+		.filter_map(|e| {
+			std::iter::once(&e.id)
+				.chain(&e.aliases)
+				.chain([&e.name])
+				.filter_map(|s| fuzzy_score(query, s))
+				.max()
+				.map(|score| (score, e))
+		})
+		.collect::<Vec<_>>();
+	// Sorting is stable, ties stay in the order of the IDs.
+	matches.sort_by_key(|(score, _)| -score);
+	matches.into_iter().map(|(_, e)| e).collect()
+}
+
+/// Print the prompt to stderr and read a line, failing at the end of input.
+fn prompt(input: &mut impl BufRead, message: &str) -> Result<String> {
+	eprint!("{}", message);
+	io::stderr().flush()?;
+	let mut line = String::new();
+	if input.read_line(&mut line)? == 0 {
+		eprintln!();
+		bail!("No device selected.");
+	}
+	Ok(line.trim().to_owned())
+}
+
+/// Ask for the device until one is selected and confirmed, returning its ID.
+pub fn pick_device(entries: &[DeviceEntry]) -> Result<String> {
+	if entries.is_empty() {
+		bail!("Device registry contains no device.");
+	}
+	let mut input = io::stdin().lock();
+	loop {
+		let query = prompt(&mut input, "Search for a device (empty for all): ")?;
+		let matches = search(entries, &query);
+		if matches.is_empty() {
+			eprintln!("No device matches '{}'.", query);
+			continue;
+		}
+		for (idx, e) in matches.iter().take(MAX_SHOWN).enumerate() {
+			eprintln!("{:>3}. {:<16} {} ({})", idx + 1, e.id, e.name, e.vendor);
+		}
+		if matches.len() > MAX_SHOWN {
+			eprintln!(
+				"     ... and {} more, refine the search to see them.",
+				matches.len() - MAX_SHOWN
+			);
+		}
+		let shown = matches.len().min(MAX_SHOWN);
+		let selected = if shown == 1 {
+			matches[0]
+		} else {
+			let message = format!(
+				"Select a device [1-{}], or press Enter to search again: ",
+				shown
+			);
+			match prompt(&mut input, &message)?.parse::<usize>() {
+				Ok(n) if (1..=shown).contains(&n) => matches[n - 1],
+				_ => continue,
+			}
+		};
+		let answer = prompt(
+			&mut input,
+			&format!(
+				"Build images for {} ({})? [y/N] ",
+				selected.name, selected.id
+			),
+		)?;
+		if answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes") {
+			return Ok(selected.id.clone());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(id: &str, aliases: &[&str], name: &str) -> DeviceEntry {
+		DeviceEntry {
+			id: id.into(),
+			aliases: aliases.iter().map(|a| a.to_string()).collect(),
+			name: name.into(),
+			vendor: "vendor".into(),
+		}
+	}
+
+	#[test]
+	fn test_search_devices() {
+		assert!(fuzzy_score("r5b", "rpi-5b").is_some());
+		assert!(fuzzy_score("b5", "rpi-5b").is_none());
+		assert!(fuzzy_score("rpi5", "rpi-5b") > fuzzy_score("rpi5", "rock-pi-s-v5"));
+		assert!(fuzzy_score("pi", "rpi-5b") < fuzzy_score("pi", "pi-zero"));
+		assert_eq!(fuzzy_score("", "rpi-5b"), Some(0));
+		let entries = [
+			entry("rock-5b", &[], "Radxa ROCK 5 Model B"),
+			entry("rpi-4b", &["pi4"], "Raspberry Pi 4 Model B"),
+			entry("rpi-5b", &["pi5", "pi5b"], "Raspberry Pi 5 Model B"),
+		];
+		let ids = |query| {
+			search(&entries, query)
+				.iter()
+				.map(|e| e.id.as_str())
+				.collect::<Vec<_>>()
+		};
+		assert_eq!(ids(""), ["rock-5b", "rpi-4b", "rpi-5b"]);
+		assert_eq!(ids("pi5"), ["rpi-5b"]);
+		assert_eq!(ids("Raspberry 4"), ["rpi-4b"]);
+		assert_eq!(ids("5b")[0], "rpi-5b");
+		assert!(ids("tinker").is_empty());
+	}
+}
@@ -0,0 +1,1040 @@
+//! Build plans, printed instead of building with `--dry-run`.
+//!
+//! The plan walks through the same steps as [`ImageContext::execute`], with
+//! the parameters each step would use. Nothing is created, attached,
+//! formatted or installed, but the partition layout is the one the
+//! partition tables are created from, so mistakes like partitions not
+//! fitting in the image are caught before a long build.
+use std::fmt::Write;
+
+use anyhow::{bail, Result};
+
+use crate::{
+	bootloader::{flash_file_name, sort_steps, BootloaderSpec},
+	btrfs::{INSTALL_SNAPSHOT, SNAPSHOTS_SUBVOLUME},
+	cli::{Compression, DEFAULT_COMPRESS_LEVEL},
+	content::PartitionContent,
+	context::{ImageContext, ImageVariant},
+	device::{check_partition_nums, PartitionMapType, MBR_FIRST_LOGICAL},
+	encryption::EncryptionSpec,
+	erofs::{DEFAULT_COMPRESSION, OVERLAY_DIRS},
+	filesystem::FilesystemType,
+	metadata::DEFAULT_METADATA_OFFSET,
+	partition::{PartitionSpec, PartitionUsage},
+	reserved::{skip_reserved, ReservedRegion},
+	shrink::GROWFS_UNIT,
+	stage::Stage,
+};
+
+/// Sector size of the loop devices.
+const SECTOR_SIZE: u64 = 512;
+/// The max sized partition needs at least 1MiB.
+const MIN_REST_BYTES: u64 = 1 << 20;
+
+/// Where a partition would be placed, in sectors.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlannedPartition {
+	pub num: u32,
+	pub start: u64,
+	pub size: u64,
+}
+
+/// Compute the partition layout of the variant in an image of `image_size`
+/// bytes, with `pad` bytes left unpartitioned at the end.
+///
+/// The first partition starts at `first_offset` sectors if it does not
+/// define its starting position, or at its alignment without it.
+pub fn plan_layout(
+	map: PartitionMapType,
+	partitions: &[PartitionSpec],
+	reserved: &[ReservedRegion],
+	first_offset: Option<u64>,
+	variant: &ImageVariant,
+	image_size: u64,
+	pad: u64,
+) -> Result<Vec<PlannedPartition>> {
+	plan_layout_in(
+		map,
+		partitions,
+		reserved,
+		first_offset,
+		variant,
+		image_size,
+		pad,
+		SECTOR_SIZE,
+	)
+}
+
+/// Compute the partition layout in sectors of `sector_size` bytes, see [`plan_layout`].
+///
+/// The partition tables are created from this layout, so that the plan is
+/// exactly what is built.
+#[allow(clippy::too_many_arguments)]
+pub fn plan_layout_in(
+	map: PartitionMapType,
+	partitions: &[PartitionSpec],
+	reserved: &[ReservedRegion],
+	first_offset: Option<u64>,
+	variant: &ImageVariant,
+	image_size: u64,
+	pad: u64,
+	sector_size: u64,
+) -> Result<Vec<PlannedPartition>> {
+	let total = image_size / sector_size;
+	// The 128 entries of 128 bytes of a GPT.
+	let gpt_entries = (128 * 128) / sector_size;
+	let nums = partitions.iter().map(|p| p.num).collect::<Vec<_>>();
+	check_partition_nums(&nums, partitions.len() as u32, &map)?;
+	// Usable sectors, the end is exclusive.
+	let (first, end) = match map {
+		PartitionMapType::None => {
+			return Ok(vec![PlannedPartition {
+				num: 1,
+				start: 0,
+				size: total,
+			}])
+		}
+		// The protective MBR, the primary and backup GPT headers and entries.
+		PartitionMapType::GPT | PartitionMapType::Hybrid => {
+			(2 + gpt_entries, total.saturating_sub(1 + gpt_entries))
+		}
+		PartitionMapType::MBR => (1, total),
+	};
+	let end = end.saturating_sub(pad / sector_size);
+	let regions = reserved
+		.iter()
+		.map(|r| r.sectors_in(sector_size))
+		.collect::<Vec<_>>();
+	let mut layout: Vec<PlannedPartition> = Vec::new();
+	// Extended partition of a MBR, spanning the rest of the image from the first EBR.
+	let mut extended: Option<PlannedPartition> = None;
+	let last_num = partitions.last().map(|p| p.num);
+	for partition in partitions {
+		let logical = map == PartitionMapType::MBR && partition.num >= MBR_FIRST_LOGICAL;
+		let align = partition.align_in(sector_size);
+		let mut next = layout
+			.iter()
+			.map(|p| p.start + p.size)
+			.max()
+			.unwrap_or(first)
+			.next_multiple_of(align);
+		if logical && extended.is_none() {
+			extended = Some(PlannedPartition {
+				num: layout.len() as u32 + 1,
+				start: next,
+				size: end.saturating_sub(next),
+			});
+		}
+		// The partitions placed automatically skip the reserved regions.
+		let size_hint = partition.sectors_in(variant, sector_size).unwrap_or(1);
+		let start = match partition.start_in(sector_size) {
+			Some(start) => start,
+			None if partition.num == 1 => skip_reserved(
+				&regions,
+				first_offset.unwrap_or(align),
+				size_hint,
+				align,
+			),
+			// Each logical partition is preceded by its EBR.
+			None if logical => {
+				next = skip_reserved(&regions, next, align + size_hint, align);
+				next + align
+			}
+			None => skip_reserved(&regions, next, size_hint, align),
+		};
+		if start < first {
+			bail!(
+				"Partition {} starts at sector {}, which overlaps the partition table",
+				partition.num,
+				start
+			);
+		}
+		if logical && start <= next {
+			bail!(
+				"Logical partition {} starts at sector {}, which overlaps its EBR at sector {}",
+				partition.num,
+				start,
+				next
+			);
+		}
+		let size = match partition.sectors_in(variant, sector_size) {
+			Some(size) => size,
+			None => {
+				if Some(partition.num) != last_num {
+					bail!("Max sized partition must stay at the end of the table.");
+				}
+				let size = end.saturating_sub(start);
+				if size < MIN_REST_BYTES / sector_size {
+					bail!(
+						"Not enough space for the max sized partition {}: {} sectors left",
+						partition.num,
+						size
+					);
+				}
+				size
+			}
+		};
+		if start + size > end {
+			bail!(
+				"Partition {} ends at sector {}, beyond the usable space of the {} bytes image (sector {})",
+				partition.num,
+				start + size,
+				image_size,
+				end
+			);
+		}
+		if map == PartitionMapType::MBR && start + size > u32::MAX as u64 {
+			bail!("Partition {} exceeds the limit of MBR", partition.num);
+		}
+		if partition.hybrid_mbr && start + size > u32::MAX as u64 {
+			bail!(
+				"Partition {} is beyond 2 TiB, it can not be mirrored in the hybrid MBR",
+				partition.num
+			);
+		}
+		if let Some(other) = layout
+			.iter()
+			.find(|p| start < p.start + p.size && p.start < start + size)
+		{
+			bail!(
+				"Partition {} (sectors {}-{}) overlaps partition {} (sectors {}-{})",
+				partition.num,
+				start,
+				start + size - 1,
+				other.num,
+				other.start,
+				other.start + other.size - 1
+			);
+		}
+		if let Some((region, (r_start, r_end))) = reserved
+			.iter()
+			.zip(&regions)
+			.find(|(_, &(s, e))| start < e && s < start + size)
+		{
+			bail!(
+				"Partition {} (sectors {}-{}) overlaps the reserved region '{}' (sectors {}-{})",
+				partition.num,
+				start,
+				start + size - 1,
+				region.name,
+				r_start,
+				r_end - 1
+			);
+		}
+		layout.push(PlannedPartition {
+			num: partition.num,
+			start,
+			size,
+		});
+	}
+	if let Some(extended) = extended {
+		let pos = layout.iter().position(|p| p.num >= MBR_FIRST_LOGICAL);
+		layout.insert(pos.unwrap_or(layout.len()), extended);
+	}
+	Ok(layout)
+}
+
+impl ImageContext<'_> {
+	/// Print the steps to build this image, without touching anything.
+	///
+	/// Fails if the device specification is invalid, or the partitions do not fit in the image.
+	pub fn plan(&self, num: usize, len: usize) -> Result<()> {
+		let device = self.device;
+		device.check()?;
+		let size = self.padded_image_size(self.nominal_image_size());
+		let layout = plan_layout(
+			device.partition_map,
+			&device.partitions,
+			&device.reserved,
+			device.first_partition_offset
+				.map(|o| o.sectors(SECTOR_SIZE)),
+			self.variant,
+			size,
+			self.get_trailing_pad(),
+		)?;
+		let root = device
+			.partitions
+			.iter()
+			.find(|p| p.usage == PartitionUsage::Rootfs)
+			.map(|p| p.num)
+			.unwrap_or(1);
+		let mut steps: Vec<String> = Vec::new();
+		let rawimg_path = self.sketch_dir().join("rawmedia.img");
+		steps.push(format!(
+			"Create a sparse image of {} bytes at {}",
+			size,
+			rawimg_path.display()
+		));
+		steps.push("Attach the image to a loop device".into());
+		let mut table = match device.partition_map {
+			PartitionMapType::None => {
+				"Create no partition table, the filesystem occupies the whole image"
+					.to_owned()
+			}
+			map => match self.fixed_disk_id()? {
+				Some(id) => format!(
+					"Create a {} partition table, disk identifier {}:",
+					map, id
+				),
+				None => format!("Create a {} partition table:", map),
+			},
+		};
+		for p in &layout {
+			write!(
+				table,
+				"\n\tp{}: sectors {}-{} ({} MiB), ",
+				p.num,
+				p.start,
+				p.start + p.size - 1,
+				(p.size * SECTOR_SIZE) >> 20
+			)?;
+			let Some(spec) = device.partitions.iter().find(|s| s.num == p.num) else {
+				write!(table, "extended")?;
+				continue;
+			};
+			write!(table, "{:?}", spec.part_type)?;
+			if let Some(label) = spec.get_label() {
+				write!(table, ", label \"{}\"", label)?;
+			}
+		}
+		steps.push(table);
+		if device.partition_map == PartitionMapType::Hybrid {
+			let mirrored = device
+				.hybrid_partitions()
+				.map(|p| format!("p{}", p.num))
+				.collect::<Vec<_>>();
+			steps.push(format!(
+				"Replace the protective MBR with a hybrid MBR mirroring {}",
+				mirrored.join(", ")
+			));
+		}
+		for spec in &device.partitions {
+			let fs = self.effective_fstype(spec);
+			if fs == FilesystemType::None {
+				continue;
+			}
+			if let Some(EncryptionSpec::Luks2 {
+				passphrase_file, ..
+			}) = &spec.encryption
+			{
+				steps.push(format!(
+					"Encrypt p{} with LUKS2, the passphrase from {}",
+					spec.num,
+					passphrase_file.display()
+				));
+			}
+			let mut step = if fs == FilesystemType::Erofs {
+				format!(
+					"Build p{} as erofs ({}) from the populated root",
+					spec.num,
+					spec.fs_compression
+						.as_deref()
+						.unwrap_or(DEFAULT_COMPRESSION)
+				)
+			} else {
+				format!(
+					"Format p{} as {}",
+					spec.num,
+					format!("{:?}", fs).to_lowercase()
+				)
+			};
+			if let Some(label) = &spec.fs_label {
+				write!(step, " labelled \"{}\"", label)?;
+			}
+			if let Some(uuid) = &spec.fs_uuid {
+				write!(step, " with UUID {}", uuid)?;
+			}
+			let mkfs_options = self.mkfs_options(spec);
+			if !mkfs_options.is_empty() {
+				write!(step, " and the options \"{}\"", mkfs_options.join(" "))?;
+			}
+			if let Some(mp) = &spec.mountpoint {
+				write!(step, ", mounted at {}", mp)?;
+			}
+			steps.push(step);
+			let tune2fs_args = self.tune2fs_args(spec);
+			if !tune2fs_args.is_empty() {
+				steps.push(format!(
+					"Tune p{} with \"tune2fs {}\"",
+					spec.num,
+					tune2fs_args.join(" ")
+				));
+			}
+			let subvolumes = self.subvolumes(spec);
+			if !subvolumes.is_empty() {
+				let names = subvolumes
+					.iter()
+					.map(|s| match &s.mountpoint {
+						Some(mp) => format!("{} ({})", s.name, mp),
+						None => s.name.clone(),
+					})
+					.collect::<Vec<_>>();
+				let mut step = format!(
+					"Create the subvolumes {} on p{}",
+					names.join(", "),
+					spec.num
+				);
+				if let Some(default) = &spec.default_subvolume {
+					write!(step, ", {} by default", default)?;
+				}
+				steps.push(step);
+			}
+		}
+		for spec in &device.partitions {
+			if let Some(PartitionContent::Raw {
+				path,
+				from_target: false,
+			}) = &spec.content
+			{
+				steps.push(format!("Write {} to p{}", path.display(), spec.num));
+			}
+		}
+		let install = if self.rootfs_source.is_some() {
+			"Extract"
+		} else {
+			"Install"
+		};
+		if self.mounts() {
+			steps.push(format!(
+				"{} the system distribution from {} to p{}",
+				install,
+				self.base_dist.display(),
+				root
+			));
+		} else {
+			steps.push(format!(
+				"{} the system distribution from {} to the staging directory",
+				install,
+				self.base_dist.display()
+			));
+		}
+		steps.push("Generate /etc/fstab".into());
+		if let Some(overlay) = device.overlay_partition() {
+			steps.push(format!(
+				"Mount the overlays on {}, with the upper layers on p{}",
+				OVERLAY_DIRS.join(" and "),
+				overlay.num
+			));
+		}
+		if device
+			.partitions
+			.iter()
+			.any(|p| p.encryption.is_some() && p.mountpoint.is_some())
+		{
+			steps.push("Generate /etc/crypttab".into());
+		}
+		let packages = device.bsp_packages.get(self.variant);
+		if !packages.is_empty() {
+			steps.push(format!("Install the BSP packages: {}", packages.join(", ")));
+		}
+		match device.kernel.as_ref().and_then(|k| k.flavor.as_ref()) {
+			Some(flavor) => steps.push(format!("Select the kernel from {}", flavor)),
+			None => steps.push("Select the latest installed kernel".into()),
+		}
+		if let Some(dtb) = &device.dtb {
+			steps.push(format!("Install the device tree blob {}", dtb.display()));
+		}
+		if !device.dtb_overlays.is_empty() {
+			steps.push(format!(
+				"Install the device tree overlays: {}",
+				device.dtb_overlays
+					.iter()
+					.map(|p| p.display().to_string())
+					.collect::<Vec<_>>()
+					.join(", ")
+			));
+		}
+		if device.services.is_some() {
+			steps.push("Enable, disable or mask the services".into());
+		}
+		if let Some(swap) = &device.swap {
+			steps.push(format!("Set up the swap space: {:?}", swap));
+		}
+		if self.shrink {
+			steps.push(format!(
+				"Install {} to grow p{} on the first boot",
+				GROWFS_UNIT,
+				self.last_partition()?.num
+			));
+		}
+		match self.user {
+			Some(user) => {
+				let mut step = format!("Create the user {}", user);
+				if self.expire_password {
+					step += ", whose password expires on the first login";
+				}
+				steps.push(step);
+			}
+			None => steps.push("Create no user".into()),
+		}
+		for (user, keys) in self.ssh_keys_by_user() {
+			if !keys.is_empty() {
+				steps.push(format!(
+					"Install {} SSH public key(s) for {}",
+					keys.len(),
+					user
+				));
+			}
+		}
+		if let Some(timezone) = self.timezone() {
+			steps.push(format!("Set the timezone to {}", timezone));
+		}
+		steps.push(format!("Set the hostname to {}", self.hostname()));
+		if let Some(script) = self.find_postinst_script()? {
+			steps.push(format!(
+				"Run the post installation script {}",
+				script.display()
+			));
+		}
+		if let Some(paths) = &device.paths {
+			steps.push(format!("Create {} paths", paths.len()));
+		}
+		if !self.customize_scripts.is_empty() {
+			let mut step = "Run the customize scripts:".to_owned();
+			for script in self.customize_scripts {
+				write!(step, "\n\t{}", script.display())?;
+			}
+			steps.push(step);
+		}
+		if let Some(bootloaders) = &device.bootloaders {
+			let mut step = "Apply the bootloaders:".to_owned();
+			for (idx, bl) in sort_steps(bootloaders)? {
+				let action = match &bl.spec {
+					BootloaderSpec::Script { name } => format!("run {}", name),
+					BootloaderSpec::FlashPartition {
+						path,
+						partition,
+						source,
+					} => {
+						format!(
+							"flash {} to p{}",
+							flash_file_name(
+								path.as_deref(),
+								source.as_ref()
+							),
+							partition
+						)
+					}
+					BootloaderSpec::FlashOffset {
+						path,
+						source,
+						offset,
+						reserved,
+					} => {
+						let name = flash_file_name(
+							path.as_deref(),
+							source.as_ref(),
+						);
+						match reserved {
+							Some(region) => format!(
+								"flash {} at {:#x}, in the reserved region '{}'",
+								name, offset, region
+							),
+							None => format!(
+								"flash {} at {:#x}",
+								name, offset
+							),
+						}
+					}
+					BootloaderSpec::Config { template, path } => {
+						format!(
+							"write {} from {}",
+							path.display(),
+							template
+						)
+					}
+					BootloaderSpec::Extlinux(spec) => {
+						format!(
+							"generate {}",
+							device.extlinux_config_path(spec)?
+								.display()
+						)
+					}
+					BootloaderSpec::UbootScript(spec) => {
+						format!(
+							"compile {} with mkimage",
+							spec.path.display()
+						)
+					}
+					BootloaderSpec::GrubEfi(spec) => {
+						format!(
+							"install GRUB ({}) to p{}{}, and generate {}",
+							device.grub_target(spec)?,
+							device.grub_esp(spec)?.num,
+							if spec.removable {
+								" and the removable media path"
+							} else {
+								""
+							},
+							spec.config_path().display()
+						)
+					}
+					BootloaderSpec::SystemdBoot(spec) => {
+						format!(
+							"install systemd-boot to p{}, with an entry for every kernel",
+							device.efi_partition(spec.esp, "systemd_boot")?.num
+						)
+					}
+					BootloaderSpec::Raw(spec) => {
+						format!(
+							"write {} at {:#x}",
+							spec.input.describe(),
+							spec.offset.0
+						)
+					}
+					BootloaderSpec::Sunxi(spec) => {
+						format!(
+							"write the SPL {} at {:#x}, after verifying its eGON checksum",
+							spec.input.describe(),
+							spec.spl_offset()?
+						)
+					}
+				};
+				write!(step, "\n\t{}: {}", bl.name(idx), action)?;
+			}
+			steps.push(step);
+		}
+		for spec in &device.partitions {
+			if let Some(PartitionContent::Raw {
+				path,
+				from_target: true,
+			}) = &spec.content
+			{
+				steps.push(format!(
+					"Write {} from the target to p{}",
+					path.display(),
+					spec.num
+				));
+			}
+		}
+		for spec in &device.partitions {
+			if !spec.install_snapshot || self.subvolumes(spec).is_empty() {
+				continue;
+			}
+			if let Some(main) = spec.main_subvolume() {
+				steps.push(format!(
+					"Snapshot the installation in {} of p{} as {}/{}",
+					main.name, spec.num, SNAPSHOTS_SUBVOLUME, INSTALL_SNAPSHOT
+				));
+			}
+		}
+		let built = self
+			.staged_partitions()
+			.into_iter()
+			.filter(|p| self.effective_fstype(p) != FilesystemType::Erofs)
+			.map(|p| format!("p{}", p.num))
+			.collect::<Vec<_>>();
+		if !built.is_empty() {
+			steps.push(format!(
+				"Build {} from the staged files without mounting",
+				built.join(", ")
+			));
+		}
+		let checked = device
+			.partitions
+			.iter()
+			.filter(|p| self.effective_fstype(p).fsck_program().is_some())
+			.map(|p| format!("p{}", p.num))
+			.collect::<Vec<_>>();
+		if self.fsck && !checked.is_empty() {
+			steps.push(format!(
+				"Check the filesystems of {} read-only",
+				checked.join(", ")
+			));
+		}
+		if device.partition_map != PartitionMapType::None {
+			steps.push(format!(
+				"Write the image metadata at {:#x}",
+				device.metadata_offset.unwrap_or(DEFAULT_METADATA_OFFSET)
+			));
+		}
+		if self.shrink {
+			steps.push(format!(
+				"Shrink p{} and the image to the contents",
+				self.last_partition()?.num
+			));
+		}
+		let output = self.output_dir().join(&self.filename);
+		steps.push(match self.compress {
+			Compression::None => format!("Copy the image to {}", output.display()),
+			c => format!(
+				"Compress the image with {:?} at level {} to {}",
+				c,
+				self.compress_level.unwrap_or(DEFAULT_COMPRESS_LEVEL),
+				output.display()
+			),
+		});
+		if self.contains_secrets() && !self.public_artifacts {
+			steps.push("Make the image unreadable by others".into());
+		}
+		println!(
+			"[{}/{}] {} ({}) - {}",
+			num,
+			len,
+			&device.name,
+			&device.id,
+			self.variant.to_string().to_lowercase()
+		);
+		if self.is_partial() {
+			println!("     Only the stages: {}", Stage::join(self.stages));
+		}
+		for (idx, step) in steps.iter().enumerate() {
+			println!("{:>3}. {}", idx + 1, step);
+		}
+		println!();
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::partition::{PartitionSize, PartitionType, SectorOffset, SectorSize};
+
+	fn partition(num: u32, start: Option<u64>, size: u64) -> PartitionSpec {
+		let size = match size {
+			0 => SectorSize::Rest,
+			n => SectorSize::Sectors(n),
+		};
+		PartitionSpec {
+			num,
+			part_type: PartitionType::Linux,
+			start_sector: start.map(SectorOffset::Sectors),
+			align: None,
+			size_in_sectors: PartitionSize::Fixed(size),
+			label: None,
+			mountpoint: None,
+			filesystem: FilesystemType::Ext4,
+			mount_opts: None,
+			fs_label: None,
+			part_uuid: None,
+			fs_uuid: None,
+			fs_compression: None,
+			mkfs_options: None,
+			fstab_style: None,
+			ext4_reserved_percent: None,
+			ext4_default_mount_opts: None,
+			ext4_check_interval: None,
+			subvolumes: Vec::new(),
+			default_subvolume: None,
+			install_snapshot: false,
+			attributes: Default::default(),
+			content: None,
+			encryption: None,
+			usage: PartitionUsage::Data,
+			slots: None,
+			empty_slot_b: false,
+			hybrid_mbr: false,
+			slot: None,
+		}
+	}
+
+	#[test]
+	fn test_plan_layout() -> Result<()> {
+		const MIB: u64 = 1 << 20;
+		let parts = [partition(1, None, 614400), partition(2, None, 0)];
+		let layout = plan_layout(
+			PartitionMapType::GPT,
+			&parts,
+			&[],
+			None,
+			&ImageVariant::Base,
+			6144 * MIB,
+			0,
+		)?;
+		assert_eq!(
+			layout,
+			vec![
+				PlannedPartition {
+					num: 1,
+					start: 2048,
+					size: 614400
+				},
+				PlannedPartition {
+					num: 2,
+					start: 616448,
+					size: 6144 * 2048 - 33 - 616448
+				},
+			]
+		);
+		// The GPT entries take 4 sectors of 4096 bytes, the sizes are still in 512-byte sectors.
+		let layout = plan_layout_in(
+			PartitionMapType::GPT,
+			&[partition(1, None, 2048), partition(2, None, 0)],
+			&[],
+			None,
+			&ImageVariant::Base,
+			64 * MIB,
+			0,
+			4096,
+		)?;
+		assert_eq!(
+			layout,
+			vec![
+				PlannedPartition {
+					num: 1,
+					start: 256,
+					size: 256
+				},
+				PlannedPartition {
+					num: 2,
+					start: 512,
+					size: 16384 - 5 - 512
+				},
+			]
+		);
+		// Trailing padding
+		let layout = plan_layout(
+			PartitionMapType::MBR,
+			&parts,
+			&[],
+			None,
+			&ImageVariant::Base,
+			6144 * MIB,
+			8 * MIB,
+		)?;
+		assert_eq!(layout[1].start + layout[1].size, 6136 * 2048);
+		// Does not fit
+		let err = plan_layout(
+			PartitionMapType::GPT,
+			&parts,
+			&[],
+			None,
+			&ImageVariant::Base,
+			300 * MIB,
+			0,
+		)
+		.unwrap_err();
+		assert!(err.to_string().contains("Partition 1 ends at sector"));
+		// Overlapping
+		let parts = [partition(1, Some(2048), 4096), partition(2, Some(4096), 0)];
+		let err = plan_layout(
+			PartitionMapType::GPT,
+			&parts,
+			&[],
+			None,
+			&ImageVariant::Base,
+			64 * MIB,
+			0,
+		)
+		.unwrap_err();
+		assert!(err.to_string().contains("overlaps partition 1"));
+		// Max sized partition in the middle
+		let parts = [partition(1, None, 0), partition(2, None, 2048)];
+		assert!(plan_layout(
+			PartitionMapType::GPT,
+			&parts,
+			&[],
+			None,
+			&ImageVariant::Base,
+			64 * MIB,
+			0
+		)
+		.is_err());
+		// Gaps in the partition numbers
+		let parts = [partition(1, None, 2048), partition(3, None, 0)];
+		assert!(plan_layout(
+			PartitionMapType::GPT,
+			&parts,
+			&[],
+			None,
+			&ImageVariant::Base,
+			64 * MIB,
+			0
+		)
+		.is_err());
+		// No partition table
+		let layout = plan_layout(
+			PartitionMapType::None,
+			&parts[..1],
+			&[],
+			None,
+			&ImageVariant::Base,
+			64 * MIB,
+			0,
+		)?;
+		assert_eq!(layout[0].size, 64 * 2048);
+		// Sizes of each variant
+		let mut parts = [partition(1, None, 614400), partition(2, None, 0)];
+		parts[1].size_in_sectors = PartitionSize::Variants {
+			base: SectorSize::Sectors(4096 * 2048),
+			desktop: SectorSize::Rest,
+			server: SectorSize::Rest,
+		};
+		let base = plan_layout(
+			PartitionMapType::GPT,
+			&parts,
+			&[],
+			None,
+			&ImageVariant::Base,
+			6144 * MIB,
+			0,
+		)?;
+		assert_eq!(base[1].size, 4096 * 2048);
+		let desktop = plan_layout(
+			PartitionMapType::GPT,
+			&parts,
+			&[],
+			None,
+			&ImageVariant::Desktop,
+			6144 * MIB,
+			0,
+		)?;
+		assert_eq!(desktop[1].size, 6144 * 2048 - 33 - 616448);
+		let err = plan_layout(
+			PartitionMapType::GPT,
+			&parts,
+			&[],
+			None,
+			&ImageVariant::Base,
+			2048 * MIB,
+			0,
+		)
+		.unwrap_err();
+		assert!(err.to_string().contains("Partition 2 ends at sector"));
+		Ok(())
+	}
+
+	#[test]
+	fn test_plan_logical_partitions() -> Result<()> {
+		const MIB: u64 = 1 << 20;
+		let parts = [1, 2, 3, 5, 6]
+			.map(|num| partition(num, None, 64 * 2048))
+			.into_iter()
+			.chain([partition(7, None, 0)])
+			.collect::<Vec<_>>();
+		let layout = plan_layout(
+			PartitionMapType::MBR,
+			&parts,
+			&[],
+			None,
+			&ImageVariant::Base,
+			1024 * MIB,
+			0,
+		)?;
+		let starts = layout.iter().map(|p| (p.num, p.start)).collect::<Vec<_>>();
+		assert_eq!(
+			starts,
+			[
+				(1, 2048),
+				(2, 133120),
+				(3, 264192),
+				// The extended partition, starting with the first EBR
+				(4, 395264),
+				(5, 397312),
+				(6, 530432),
+				(7, 663552)
+			]
+		);
+		assert_eq!(layout[3].size, 1024 * 2048 - 395264);
+		assert_eq!(layout[6].size, 1024 * 2048 - 663552);
+		// Logical partitions are not supported by GPT.
+		assert!(plan_layout(
+			PartitionMapType::GPT,
+			&parts,
+			&[],
+			None,
+			&ImageVariant::Base,
+			1024 * MIB,
+			0
+		)
+		.is_err());
+		// No room for the EBR
+		let parts = [partition(1, None, 2048), partition(5, Some(4096), 0)];
+		let err = plan_layout(
+			PartitionMapType::MBR,
+			&parts,
+			&[],
+			None,
+			&ImageVariant::Base,
+			64 * MIB,
+			0,
+		)
+		.unwrap_err();
+		assert!(err.to_string().contains("overlaps its EBR"));
+		Ok(())
+	}
+
+	#[test]
+	fn test_plan_alignment() -> Result<()> {
+		const MIB: u64 = 1 << 20;
+		let mut parts = [
+			partition(1, None, 1000),
+			partition(2, None, 1000),
+			partition(3, None, 0),
+		];
+		parts[2].align = Some(SectorOffset::Bytes(4 * MIB));
+		// Room for idbloader and U-Boot
+		let layout = plan_layout(
+			PartitionMapType::GPT,
+			&parts,
+			&[],
+			Some(16 * 2048),
+			&ImageVariant::Base,
+			64 * MIB,
+			0,
+		)?;
+		let starts = layout.iter().map(|p| (p.num, p.start)).collect::<Vec<_>>();
+		assert_eq!(starts, [(1, 32768), (2, 34816), (3, 40960)]);
+		// The first partition starts at its alignment by default.
+		parts[0].align = Some(SectorOffset::Bytes(4 * MIB));
+		let layout = plan_layout(
+			PartitionMapType::GPT,
+			&parts,
+			&[],
+			None,
+			&ImageVariant::Base,
+			64 * MIB,
+			0,
+		)?;
+		assert_eq!(layout[0].start, 8192);
+		assert!(plan_layout(
+			PartitionMapType::GPT,
+			&parts,
+			&[],
+			Some(16),
+			&ImageVariant::Base,
+			64 * MIB,
+			0
+		)
+		.is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_plan_reserved() -> Result<()> {
+		const MIB: u64 = 1 << 20;
+		let reserved = [ReservedRegion {
+			name: "env".into(),
+			start: SectorOffset::Sectors(2048),
+			size: SectorSize::Sectors(2048),
+		}];
+		let mut parts = [partition(1, None, 1000), partition(2, None, 0)];
+		let layout = plan_layout(
+			PartitionMapType::GPT,
+			&parts,
+			&reserved,
+			None,
+			&ImageVariant::Base,
+			64 * MIB,
+			0,
+		)?;
+		let starts = layout.iter().map(|p| (p.num, p.start)).collect::<Vec<_>>();
+		assert_eq!(starts, [(1, 4096), (2, 6144)]);
+		parts[0].start_sector = Some(SectorOffset::Sectors(3072));
+		let err = plan_layout(
+			PartitionMapType::GPT,
+			&parts,
+			&reserved,
+			None,
+			&ImageVariant::Base,
+			64 * MIB,
+			0,
+		)
+		.unwrap_err();
+		assert!(err
+			.to_string()
+			.contains("overlaps the reserved region 'env'"));
+		Ok(())
+	}
+}
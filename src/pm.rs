@@ -3,11 +3,12 @@
 
 use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use serde::Deserialize;
+use toml::Value;
 
 use crate::{
-	context::ImageContext,
+	context::{ImageContext, ImageVariant},
 	device::DeviceArch,
 	utils::{run_str_script_with_chroot, setup_scroll_region},
 };
@@ -23,6 +24,100 @@ pub enum Distro {
 	Fedora,
 }
 
+/// BSP packages of the device, either a list for every variant, or a table of lists:
+///
+/// ```toml
+/// [bsp_packages]
+/// # Installed in every variant.
+/// common = ["linux+kernel+sdm845", "qcom-firmware"]
+/// desktop = ["phosh"]
+/// ```
+///
+/// The packages of a variant are the `common` ones followed by its own.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "Value")]
+pub struct BspPackages {
+	pub common: Vec<String>,
+	pub base: Vec<String>,
+	pub desktop: Vec<String>,
+	pub server: Vec<String>,
+}
+
+fn package_list(key: &str, value: Value) -> Result<Vec<String>> {
+	let Value::Array(values) = value else {
+		bail!("bsp_packages.{} must be a list of package names", key);
+	};
+	values.into_iter()
+		.map(|v| match v {
+			Value::String(s) => Ok(s),
+			v => bail!("Invalid package name '{}' in bsp_packages.{}", v, key),
+		})
+		.collect()
+}
+
+impl TryFrom<Value> for BspPackages {
+	type Error = anyhow::Error;
+
+	fn try_from(value: Value) -> Result<Self> {
+		match value {
+			Value::Array(_) => Ok(Self {
+				common: package_list("common", value)?,
+				..Default::default()
+			}),
+			Value::Table(table) => {
+				let mut packages = Self::default();
+				for (key, value) in table {
+					let list = match key.as_str() {
+						"common" => &mut packages.common,
+						"base" => &mut packages.base,
+						"desktop" => &mut packages.desktop,
+						"server" => &mut packages.server,
+						_ => bail!("Unknown key bsp_packages.{}, expected one of common, base, desktop and server", key),
+					};
+					*list = package_list(&key, value)?;
+				}
+				Ok(packages)
+			}
+			v => bail!(
+				"bsp_packages must be a list or a table of lists, got '{}'",
+				v
+			),
+		}
+	}
+}
+
+impl BspPackages {
+	/// Get the packages to be installed for the variant, without duplicates.
+	pub fn get(&self, variant: &ImageVariant) -> Vec<&str> {
+		let own = match variant {
+			ImageVariant::Base => &self.base,
+			ImageVariant::Desktop => &self.desktop,
+			ImageVariant::Server => &self.server,
+		};
+		let mut packages = Vec::new();
+		for p in self.common.iter().chain(own) {
+			if !packages.contains(&p.as_str()) {
+				packages.push(p.as_str());
+			}
+		}
+		packages
+	}
+
+	/// Whether the packages differ between the variants.
+	pub fn per_variant(&self) -> bool {
+		!(self.base.is_empty() && self.desktop.is_empty() && self.server.is_empty())
+	}
+
+	/// Iterate over all package names.
+	pub fn iter(&self) -> impl Iterator<Item = &String> {
+		self.common
+			.iter()
+			.chain(&self.base)
+			.chain(&self.desktop)
+			.chain(&self.server)
+	}
+}
+
 pub enum APT {}
 pub enum Oma {}
 
@@ -125,4 +220,50 @@ impl ImageContext<'_> {
 		setup_scroll_region();
 		Ok(())
 	}
+
+	/// Install the BSP packages of the variant being built.
+	pub fn install_bsp_packages<P: AsRef<Path>>(&self, container: P) -> Result<()> {
+		let packages = self.device.bsp_packages.get(self.variant);
+		if !packages.is_empty() {
+			self.info(format!("BSP packages: {}", packages.join(" ")));
+		}
+		self.install_packages(&packages, container)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn strings(values: &[&str]) -> Value {
+		Value::Array(values.iter().map(|&s| s.into()).collect())
+	}
+
+	#[test]
+	fn test_bsp_packages() -> Result<()> {
+		let flat = BspPackages::try_from(strings(&["linux+kernel", "firmware"]))?;
+		assert!(!flat.per_variant());
+		assert_eq!(
+			flat.get(&ImageVariant::Server),
+			["linux+kernel", "firmware"]
+		);
+		let mut table = toml::Table::new();
+		table.insert("common".into(), strings(&["linux+kernel", "firmware"]));
+		table.insert("desktop".into(), strings(&["phosh", "firmware"]));
+		let packages = BspPackages::try_from(Value::Table(table.clone()))?;
+		assert!(packages.per_variant());
+		assert_eq!(
+			packages.get(&ImageVariant::Base),
+			["linux+kernel", "firmware"]
+		);
+		assert_eq!(
+			packages.get(&ImageVariant::Desktop),
+			["linux+kernel", "firmware", "phosh"]
+		);
+		table.insert("kde".into(), strings(&["plasma"]));
+		assert!(BspPackages::try_from(Value::Table(table)).is_err());
+		assert!(BspPackages::try_from(Value::Array(vec![Value::Integer(1)])).is_err());
+		assert!(BspPackages::try_from(Value::String("firmware".into())).is_err());
+		Ok(())
+	}
 }
@@ -0,0 +1,497 @@
+//! Populating the filesystems without mounting them.
+//!
+//! Loop-mounting the filesystems to install the distribution into them is
+//! what requires root most of the time. With `--populate-backend no-mount`,
+//! no loop device is attached and nothing is mounted. The image file is
+//! partitioned as is, and each partition is a sparse file next to it, named
+//! after the image like the partitions of a disk (`rawmedia.img2`), which
+//! the partitions are formatted in, and the bootloaders and the raw
+//! contents are written to. Once the stages are done, the files are copied
+//! into the image at the offsets of the partitions, and copied out again
+//! to resume the later stages. The files in the target must still be owned
+//! by root, so the build runs as root in a user namespace instead:
+//!
+//! ```sh
+//! unshare --map-root-user --mount mkrawimg build --populate-backend no-mount rpi-5b
+//! ```
+//!
+//! The distribution is installed into a staging directory in the working
+//! directory, the partitions mounted in the target are staged in it at
+//! their mountpoints, and once the post installation
+//! and the bootloader steps are done, each partition is built from its part
+//! of the staging directory, then written to the image:
+//!
+//! - ext4 with `mkfs.ext4 -d`, which copies the files with their
+//!   ownership, permissions and extended attributes.
+//! - FAT with `mkfs.vfat`, then the files are copied with `mcopy` of mtools.
+//! - EROFS as usual, see [`crate::erofs`].
+//!
+//! The filesystems are built with the UUIDs and the labels they got when
+//! the partitions were formatted, so `/etc/fstab` and the kernel command
+//! line still refer to them, and the UUIDs are read back to make sure.
+//!
+//! Other filesystems (xfs, btrfs and exFAT) can only be populated by
+//! mounting them, so are the btrfs subvolumes. A swap file can not be
+//! created either, it would have holes once copied into ext4, nor can GRUB
+//! be installed, `grub-mkconfig` probes the mounted root filesystem. The commands
+//! are run in the staging directory by the chroot backend, which must set
+//! up the API filesystems by itself, i.e. `nspawn` or `bwrap`. Encrypted
+//! partitions need the device mapper, and `--shrink` resizes the root
+//! filesystem on a loop device, so neither can be used without mounting.
+//! The image file and the partition files are bound into the chroot
+//! instead of the loop devices.
+use std::{
+	fs::{self, create_dir_all, File},
+	io::{Seek, SeekFrom},
+	path::{Path, PathBuf},
+	process::Command,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+
+use crate::{
+	bootloader::BootloaderSpec,
+	chroot,
+	content::write_raw,
+	context::ImageContext,
+	device::{PartitionMapData, PartitionMapType},
+	filesystem::FilesystemType,
+	partition::{PartitionSpec, PartitionUsage, SECTOR_SIZE},
+	swap::SwapSpec,
+	utils::{
+		cmd_run_check_status, copy_sparse_range, create_sparse_file, get_fslabel,
+		get_fsuuid, get_sparse_file,
+	},
+};
+
+/// How the filesystems are populated.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, strum::Display)]
+#[strum(serialize_all = "kebab-case")]
+pub enum PopulateBackend {
+	/// Mount the filesystems on the loop device, and install into them.
+	#[default]
+	Mount,
+	/// Install into a staging directory, and build the filesystems from it.
+	NoMount,
+}
+
+/// Size of the partition, the block device or the file is seeked to its end.
+fn device_size(dev: &Path) -> Result<u64> {
+	let mut fd = File::open(dev).context(format!("Unable to open {}", dev.display()))?;
+	Ok(fd.seek(SeekFrom::End(0))?)
+}
+
+/// Whether `path` is strictly within the mountpoint `base`.
+fn is_within(path: &str, base: &str) -> bool {
+	path != base && Path::new(path).starts_with(base)
+}
+
+impl ImageContext<'_> {
+	/// Whether the filesystems are mounted to be populated.
+	pub(crate) fn mounts(&self) -> bool {
+		self.populate_backend == PopulateBackend::Mount
+	}
+
+	/// Whether the partition is populated in the staging directory and built afterwards, instead of being mounted.
+	pub(crate) fn is_staged(&self, partition: &PartitionSpec) -> bool {
+		let filesystem = self.effective_fstype(partition);
+		filesystem == FilesystemType::Erofs
+			|| (self.populate_backend == PopulateBackend::NoMount
+				&& filesystem.is_mountable() && partition.mountpoint.is_some())
+	}
+
+	/// The partitions populated in the staging directory.
+	pub(crate) fn staged_partitions(&self) -> Vec<&PartitionSpec> {
+		self.device
+			.partitions
+			.iter()
+			.filter(|p| self.is_staged(p))
+			.collect()
+	}
+
+	/// Make sure the filesystems can be populated with the backend, see the [module documentation](self).
+	pub(crate) fn check_populate_backend(&self) -> Result<()> {
+		if self.populate_backend != PopulateBackend::NoMount {
+			return Ok(());
+		}
+		let id = &self.device.id;
+		let backend = chroot::backend();
+		if backend.needs_api_mounts() {
+			bail!(
+				"Unable to build {} without mounting: the {} backend requires the API filesystems to be mounted, use --chroot-backend nspawn or bwrap.",
+				id,
+				backend
+			);
+		}
+		if let Some(SwapSpec::File { .. }) = &self.device.swap {
+			bail!(
+				"Unable to build {} without mounting: the swap file would have holes once copied into the root filesystem. Use zram or a swap partition instead.",
+				id
+			);
+		}
+		if self.device
+			.bootloaders
+			.iter()
+			.flatten()
+			.any(|bl| matches!(bl.spec, BootloaderSpec::GrubEfi(_)))
+		{
+			bail!(
+				"Unable to build {} without mounting: grub-mkconfig must probe the mounted root filesystem.",
+				id
+			);
+		}
+		if let Some(partition) = self
+			.device
+			.partitions
+			.iter()
+			.find(|p| p.encryption.is_some())
+		{
+			bail!(
+				"Unable to build {} without mounting: partition {} is encrypted, which requires the device mapper.",
+				id,
+				partition.num
+			);
+		}
+		if self.shrink {
+			bail!(
+				"Unable to build {} without mounting: --shrink resizes the root filesystem on a loop device.",
+				id
+			);
+		}
+		for partition in self.staged_partitions() {
+			let num = partition.num;
+			match self.effective_fstype(partition) {
+				FilesystemType::Ext4
+				| FilesystemType::Fat16
+				| FilesystemType::Fat32
+				| FilesystemType::Erofs => (),
+				fs => bail!(
+					"Unable to build {} without mounting: partition {} is {}, which can only be populated by mounting it. Build it with --populate-backend mount{}.",
+					id,
+					num,
+					format!("{:?}", fs).to_lowercase(),
+					if partition.usage == PartitionUsage::Rootfs {
+						", or with --fstype ext4"
+					} else {
+						""
+					}
+				),
+			}
+		}
+		Ok(())
+	}
+
+	/// Copy the partitions out of the image into their files, see the [module documentation](self).
+	pub(crate) fn split_partitions(&self, rawimg: &Path) -> Result<()> {
+		if self.device.partition_map == PartitionMapType::None {
+			return Ok(());
+		}
+		let mut image = File::open(rawimg)
+			.context(format!("Unable to open {}", rawimg.display()))?;
+		for planned in self.layout_in(&mut image, SECTOR_SIZE)? {
+			let path = self.device.partition_path(rawimg, planned.num);
+			let path = Path::new(&path);
+			// Left by a failed build, the image holds what the finished stages produced.
+			if path.exists() {
+				fs::remove_file(path)?;
+			}
+			let len = planned.size * SECTOR_SIZE;
+			let file = get_sparse_file(path, len)?;
+			copy_sparse_range(&image, planned.start * SECTOR_SIZE, &file, 0, len)
+				.context(format!(
+					"Failed to copy partition {} out of the image",
+					planned.num
+				))?;
+		}
+		Ok(())
+	}
+
+	/// Copy the partition files into the image, and remove them.
+	pub(crate) fn join_partitions(&self, rawimg: &Path) -> Result<()> {
+		if self.device.partition_map == PartitionMapType::None {
+			return Ok(());
+		}
+		self.info("Writing the partitions into the image ...");
+		let mut image = File::options()
+			.read(true)
+			.write(true)
+			.open(rawimg)
+			.context(format!("Unable to open {}", rawimg.display()))?;
+		for planned in self.layout_in(&mut image, SECTOR_SIZE)? {
+			let path = self.device.partition_path(rawimg, planned.num);
+			let file = File::open(&path).context(format!("Unable to open {}", path))?;
+			let len = planned.size * SECTOR_SIZE;
+			if file.metadata()?.len() != len {
+				bail!(
+					"{} is not of the size of partition {} ({} bytes)",
+					path,
+					planned.num,
+					len
+				);
+			}
+			copy_sparse_range(&file, 0, &image, planned.start * SECTOR_SIZE, len)
+				.context(format!(
+					"Failed to copy partition {} into the image",
+					planned.num
+				))?;
+			fs::remove_file(&path)?;
+		}
+		Ok(())
+	}
+
+	/// Empty the staging directory, left by an earlier build.
+	pub(crate) fn clear_staging(&self, staging: &Path) -> Result<()> {
+		for entry in fs::read_dir(staging)? {
+			let path = entry?.path();
+			if path.is_dir() && !path.is_symlink() {
+				fs::remove_dir_all(&path)
+			} else {
+				fs::remove_file(&path)
+			}
+			.context(format!("Failed to remove {}", path.display()))?;
+		}
+		Ok(())
+	}
+
+	/// Move the staged partitions within the mountpoint of `partition` out of the way while `f` runs.
+	///
+	/// They are moved next to the staging directory, deepest first, and moved back afterwards.
+	fn with_hidden<T>(
+		&self,
+		rootdir: &Path,
+		partition: &PartitionSpec,
+		f: impl FnOnce() -> Result<T>,
+	) -> Result<T> {
+		let base = partition.mountpoint.as_deref().unwrap_or("/");
+		let mut nested = self
+			.staged_partitions()
+			.into_iter()
+			.filter_map(|p| p.mountpoint.as_deref().map(|mp| (p.num, mp)))
+			.filter(|(_, mp)| is_within(mp, base))
+			.collect::<Vec<_>>();
+		nested.sort_by_key(|(_, mp)| std::cmp::Reverse(Path::new(mp).components().count()));
+		let mut hidden: Vec<(PathBuf, PathBuf)> = Vec::new();
+		let mut result = Ok(());
+		for (num, mp) in nested {
+			let dir = rootdir.join(mp.trim_start_matches('/'));
+			let aside = self.sketch_dir().join(format!("p{}.hidden", num));
+			result = fs::rename(&dir, &aside)
+				.and_then(|_| create_dir_all(&dir))
+				.context(format!("Failed to move {} out of the way", mp));
+			if result.is_err() {
+				break;
+			}
+			hidden.push((dir, aside));
+		}
+		let output = result.and_then(|_| f());
+		// Moved back even if building the filesystem fails.
+		for (dir, aside) in hidden.iter().rev() {
+			fs::remove_dir(dir)
+				.and_then(|_| fs::rename(aside, dir))
+				.context(format!("Failed to move {} back", dir.display()))?;
+		}
+		output
+	}
+
+	/// Build the staged ext4 or FAT filesystem from `dir`, and write it to the partition.
+	fn build_partition(
+		&self,
+		partition: &PartitionSpec,
+		dir: &Path,
+		loop_dev: &Path,
+		pm_data: &mut PartitionMapData,
+	) -> Result<()> {
+		let num = partition.num;
+		let filesystem = self.effective_fstype(partition);
+		let part_data = pm_data.data.get_mut(&num).context(format!(
+			"Unable to get partition data for partition {}",
+			num
+		))?;
+		let uuid = part_data
+			.fs_uuid
+			.clone()
+			.context(format!("No filesystem UUID of partition {}", num))?;
+		let label = partition
+			.fs_label
+			.as_deref()
+			.map(|l| filesystem.applied_label(l));
+		let dev = self.device.filesystem_path(loop_dev, partition);
+		let dev = Path::new(&dev);
+		let image = self.sketch_dir().join(format!("p{}.img", num));
+		create_sparse_file(&image, device_size(dev)?)?;
+		self.info(format!(
+			"Building partition {} ({:?}) from the staged files ...",
+			num, filesystem
+		));
+		let mut options = self.mkfs_options(partition).to_vec();
+		if filesystem == FilesystemType::Ext4 {
+			options.extend(["-d".to_owned(), dir.to_string_lossy().into_owned()]);
+		}
+		let result = filesystem
+			.format(&image, label, Some(&uuid.to_string()), &options)
+			.and_then(|_| self.tune_ext4(partition, &image))
+			.and_then(|_| {
+				let entries = fs::read_dir(dir)?
+					.map(|e| e.map(|e| e.path()))
+					.collect::<std::io::Result<Vec<_>>>()?;
+				if filesystem == FilesystemType::Ext4 || entries.is_empty() {
+					return Ok(());
+				}
+				let mut cmd = Command::new("mcopy");
+				cmd.args(["-s", "-p", "-Q", "-i"])
+					.arg(&image)
+					.args(entries)
+					.arg("::/");
+				cmd_run_check_status(&mut cmd)
+			})
+			.context(format!("Failed to build partition {}", num))
+			.and_then(|_| write_raw(&image, dev));
+		fs::remove_file(&image)?;
+		result?;
+		let read_back = get_fsuuid(&dev)?;
+		if read_back != uuid {
+			bail!(
+				"Partition {} was built with the filesystem UUID {}, but read back as {}",
+				num,
+				uuid,
+				read_back
+			);
+		}
+		part_data.fs_label = get_fslabel(&dev)?;
+		Ok(())
+	}
+
+	/// Build the staged filesystems from the staging directory, see the [module documentation](self).
+	pub(crate) fn build_staged_partitions(
+		&self,
+		rootdir: &Path,
+		loop_dev: &Path,
+		pm_data: &mut PartitionMapData,
+	) -> Result<()> {
+		for partition in self.staged_partitions() {
+			let mountpoint = partition.mountpoint.as_deref().unwrap_or("/");
+			let dir = rootdir.join(mountpoint.trim_start_matches('/'));
+			self.with_hidden(rootdir, partition, || {
+				match self.effective_fstype(partition) {
+					FilesystemType::Erofs => self.build_erofs_root(
+						partition, &dir, loop_dev, pm_data,
+					),
+					_ => self.build_partition(
+						partition, &dir, loop_dev, pm_data,
+					),
+				}
+			})?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::os::unix::fs::FileExt;
+
+	use super::*;
+	use crate::testutil::{load_device, test_context, TempDir};
+
+	const PARTITIONS: &str = r#"partition_map = "gpt"
+
+[[partition]]
+num = 1
+type = "esp"
+usage = "boot"
+filesystem = "fat32"
+mountpoint = "/boot"
+size = "16MiB"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+"#;
+
+	#[test]
+	fn test_check_populate_backend() -> Result<()> {
+		let dir = TempDir::new("populate")?;
+		let check = |spec: &str, backend: PopulateBackend| -> Result<()> {
+			let device = load_device(&dir, spec)?;
+			let mut ctx = test_context(&device, &dir);
+			ctx.populate_backend = backend;
+			ctx.check_populate_backend()
+		};
+		let no_mount = |spec: &str| check(spec, PopulateBackend::NoMount);
+		no_mount(PARTITIONS)?;
+		let xfs = PARTITIONS.replace("\"ext4\"", "\"xfs\"");
+		let err = no_mount(&xfs).unwrap_err().to_string();
+		assert!(err.contains("partition 2 is xfs"), "{}", err);
+		assert!(err.contains("--fstype ext4"), "{}", err);
+		let swap_file =
+			format!("swap = {{ type = \"file\", size = 1024 }}\n{}", PARTITIONS);
+		let err = no_mount(&swap_file).unwrap_err().to_string();
+		assert!(err.contains("swap file"), "{}", err);
+		let grub = format!("{}\n[[bootloader]]\ntype = \"grub_efi\"\n", PARTITIONS);
+		let err = no_mount(&grub).unwrap_err().to_string();
+		assert!(err.contains("grub-mkconfig"), "{}", err);
+		let encrypted = PARTITIONS.replace(
+			"mountpoint = \"/\"\n",
+			"mountpoint = \"/\"\nencryption = { type = \"luks2\", passphrase_file = \"key\" }\n",
+		);
+		let err = no_mount(&encrypted).unwrap_err().to_string();
+		assert!(err.contains("partition 2 is encrypted"), "{}", err);
+		// Anything goes with mounting.
+		for spec in [&xfs, &swap_file, &grub, &encrypted] {
+			check(spec, PopulateBackend::Mount)?;
+		}
+
+		let device = load_device(&dir, &xfs)?;
+		let mut ctx = test_context(&device, &dir);
+		ctx.populate_backend = PopulateBackend::NoMount;
+		ctx.override_rootfs_fstype = &Some(FilesystemType::Ext4);
+		ctx.check_populate_backend()?;
+		ctx.shrink = true;
+		let err = ctx.check_populate_backend().unwrap_err().to_string();
+		assert!(err.contains("--shrink"), "{}", err);
+		Ok(())
+	}
+
+	#[test]
+	fn test_split_partitions() -> Result<()> {
+		const MIB: u64 = 1 << 20;
+		let dir = TempDir::new("split")?;
+		let device = load_device(&dir, PARTITIONS)?;
+		let mut ctx = test_context(&device, &dir);
+		ctx.populate_backend = PopulateBackend::NoMount;
+		let image = dir.join("rawmedia.img");
+		create_sparse_file(&image, 64 * MIB)?;
+		ctx.split_partitions(&image)?;
+		let layout = ctx.layout_in(&mut File::open(&image)?, SECTOR_SIZE)?;
+		let (esp, root) = (dir.join("rawmedia.img1"), dir.join("rawmedia.img2"));
+		assert_eq!(fs::metadata(&esp)?.len(), 16 * MIB);
+		assert_eq!(fs::metadata(&root)?.len(), layout[1].size * SECTOR_SIZE);
+		File::options()
+			.write(true)
+			.open(&root)?
+			.write_all_at(b"root", 512)?;
+		ctx.join_partitions(&image)?;
+		assert!(!esp.exists() && !root.exists());
+		let mut buf = [0; 4];
+		File::open(&image)?.read_exact_at(&mut buf, layout[1].start * SECTOR_SIZE + 512)?;
+		assert_eq!(&buf, b"root");
+		// Copied out again to resume.
+		ctx.split_partitions(&image)?;
+		File::open(&root)?.read_exact_at(&mut buf, 512)?;
+		assert_eq!(&buf, b"root");
+		Ok(())
+	}
+
+	#[test]
+	fn test_is_within() {
+		assert!(is_within("/boot", "/"));
+		assert!(is_within("/boot/efi", "/boot"));
+		assert!(!is_within("/boot", "/boot"));
+		assert!(!is_within("/bootloader", "/boot"));
+	}
+}
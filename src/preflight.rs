@@ -0,0 +1,389 @@
+//! Checking the host before the queue is executed, so a build does not fail
+//! an hour in because of something which was missing from the start.
+//!
+//! The following are checked, and everything missing is reported at once:
+//!
+//! - The external programs the selected stages of each image run on the
+//!   host, found in `PATH`: `partprobe` to partition, the `mkfs` of each
+//!   filesystem to format (and `tune2fs` to tune ext4), `rsync`, `tar` and `bash` to install the
+//!   distribution, `mkswap` (and `chattr` on Btrfs) for the swap file, the
+//!   program of the chroot backend, `chroot` to create the user, and
+//!   `aoscbootstrap` if a distribution is to be bootstrapped, the tools
+//!   of the filesystem to `--shrink`, `cryptsetup` for the encrypted
+//!   partitions, the checker of each filesystem unless `--no-fsck`, and
+//!   `mcopy` to build FAT filesystems with `--populate-backend no-mount`,
+//!   `mkimage` to compile the U-Boot scripts of the `uboot_script` steps.
+//! - The files of the bootloader steps downloaded from a `source` are
+//!   fetched into the cache (see [`crate::fetch`]), a broken link or a
+//!   checksum mismatch is reported with the rest.
+//! - `useradd` and `chpasswd` in the distributions which already exist, they
+//!   are run inside the target. The bootloader scripts are run inside the
+//!   target as well, so the tools they use (e.g. `grub-install`) come from
+//!   the BSP packages, not the host.
+//! - The free space in the working directory and the output directory,
+//!   estimated from the image sizes. A raw image takes up to its full size in
+//!   the working directory, for `--jobs` images at once (or every image, if
+//!   the sketch directories are kept). A compressed image is estimated at
+//!   a third of the raw image. If both directories are on the same
+//!   filesystem, the space is added up.
+//!
+//! Use `--skip-preflight` for the setups the checks get wrong, e.g. the
+//! programs are not in `PATH`.
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	path::Path,
+};
+
+use anyhow::{bail, Result};
+use log::info;
+
+use crate::{
+	bootloader::BootloaderSpec,
+	chroot,
+	clean::format_size,
+	cli::{Compression, KeepWorkdir},
+	context::ImageContext,
+	filesystem::FilesystemType,
+	partition::{PartitionSpec, PartitionUsage},
+	stage::Stage,
+	swap::SwapSpec,
+	utils::{available_space, find_program},
+};
+
+/// Compressed images are estimated at a third of the raw images.
+const COMPRESSION_RATIO: u64 = 3;
+
+/// Directories in the distribution to find the programs in.
+const TARGET_PATHS: &[&str] = &["usr/bin", "usr/sbin", "bin", "sbin"];
+
+/// What the queue needs, with what needs each of them.
+#[derive(Debug, Default)]
+pub struct Requirements {
+	/// Programs on the host.
+	pub programs: BTreeMap<&'static str, BTreeSet<String>>,
+	/// Programs in the distributions, by the path of the distribution.
+	pub target_programs: BTreeMap<(String, &'static str), BTreeSet<String>>,
+	/// Space in the working directory, in bytes.
+	pub workdir_space: u64,
+	/// Space in the output directory, in bytes.
+	pub outdir_space: u64,
+}
+
+impl Requirements {
+	pub fn require(&mut self, program: &'static str, reason: impl Into<String>) {
+		self.programs
+			.entry(program)
+			.or_default()
+			.insert(reason.into());
+	}
+}
+
+impl ImageContext<'_> {
+	/// Add the programs the selected stages of the image run.
+	pub(crate) fn require_programs(&self, req: &mut Requirements) {
+		let id = &self.device.id;
+		if self.runs(Stage::Partition) {
+			req.require("partprobe", "partitioning");
+		}
+		let fstype = |p: &PartitionSpec| self.effective_fstype(p);
+		if self.runs(Stage::Format) {
+			for p in &self.device.partitions {
+				let fs = fstype(p);
+				if let Some(program) = fs.mkfs_program() {
+					let fs = format!("{:?}", fs).to_lowercase();
+					req.require(
+						program,
+						format!("formatting {} as {}", id, fs),
+					);
+				}
+				if !self.subvolumes(p).is_empty() {
+					req.require(
+						"btrfs",
+						format!("creating the subvolumes of {}", id),
+					);
+				}
+				if !self.tune2fs_args(p).is_empty() {
+					req.require(
+						"tune2fs",
+						format!("tuning the ext4 filesystems of {}", id),
+					);
+				}
+				// mkfs.exfat can not set the volume serial.
+				if fs == FilesystemType::Exfat
+					&& self.filesystem_uuid(p, &fs).is_some()
+				{
+					req.require(
+						"tune.exfat",
+						format!("setting the volume ID of {}", id),
+					);
+				}
+			}
+		}
+		let encrypted = self
+			.device
+			.partitions
+			.iter()
+			.any(|p| p.encryption.is_some());
+		if encrypted
+			&& [
+				Stage::Format,
+				Stage::Populate,
+				Stage::Postinst,
+				Stage::Bootloader,
+			]
+			.iter()
+			.any(|s| self.runs(*s))
+		{
+			req.require("cryptsetup", format!("the encrypted partitions of {}", id));
+		}
+		if self.runs(Stage::Populate) {
+			for program in ["rsync", "tar", "bash"] {
+				req.require(program, "installing the distribution");
+			}
+			if let Some(SwapSpec::File { .. }) = &self.device.swap {
+				req.require("mkswap", format!("the swap file of {}", id));
+				let rootfs = self
+					.device
+					.partitions
+					.iter()
+					.find(|p| p.usage == PartitionUsage::Rootfs);
+				if rootfs.is_some_and(|p| fstype(p) == FilesystemType::Btrfs) {
+					req.require(
+						"chattr",
+						format!("the swap file of {} on btrfs", id),
+					);
+				}
+			}
+		}
+		if [Stage::Populate, Stage::Postinst, Stage::Bootloader]
+			.iter()
+			.any(|s| self.runs(*s))
+		{
+			let backend = chroot::backend();
+			req.require(
+				backend.program(),
+				format!(
+					"running commands in the target with the {} backend",
+					backend
+				),
+			);
+			if self.runs(Stage::Bootloader)
+				&& self.device.partitions.iter().any(|p| {
+					p.install_snapshot && !self.subvolumes(p).is_empty()
+				}) {
+				req.require(
+					"btrfs",
+					format!("snapshotting the installation of {}", id),
+				);
+			}
+			if self.runs(Stage::Bootloader)
+				&& self.device
+					.bootloaders
+					.iter()
+					.flatten()
+					.any(|bl| matches!(bl.spec, BootloaderSpec::UbootScript(_)))
+			{
+				req.require(
+					"mkimage",
+					format!("compiling the U-Boot scripts of {}", id),
+				);
+			}
+			if self.erofs_root().is_some() {
+				req.require(
+					"mkfs.erofs",
+					format!("building the EROFS root filesystem of {}", id),
+				);
+			}
+			if !self.mounts() {
+				for p in self.staged_partitions() {
+					let fs = fstype(p);
+					if let Some(program) = fs.mkfs_program() {
+						req.require(
+							program,
+							format!(
+								"building the filesystems of {} without mounting",
+								id
+							),
+						);
+					}
+					if matches!(
+						fs,
+						FilesystemType::Fat16 | FilesystemType::Fat32
+					) {
+						req.require(
+							"mcopy",
+							format!(
+								"copying the files into the FAT filesystems of {}",
+								id
+							),
+						);
+					}
+				}
+			}
+			if self.fsck {
+				for p in &self.device.partitions {
+					if let Some(program) = fstype(p).fsck_program() {
+						req.require(
+							program,
+							format!(
+								"checking the filesystems of {}",
+								id
+							),
+						);
+					}
+				}
+			}
+		}
+		if self.runs(Stage::Postinst) && self.user.is_some() {
+			req.require("chroot", "creating the user");
+			// Distributions to be bootstrapped are checked by aoscbootstrap,
+			// and the archives can not be checked before they are extracted.
+			if self.rootfs_source.is_none() && self.base_dist.exists() {
+				let dist = self.base_dist.display().to_string();
+				for program in ["useradd", "chpasswd"] {
+					req.target_programs
+						.entry((dist.clone(), program))
+						.or_default()
+						.insert(id.to_owned());
+				}
+			}
+		}
+		if self.shrink && self.runs(Stage::Compress) {
+			let programs: &[&'static str] = match self.last_partition().map(fstype) {
+				Ok(FilesystemType::Btrfs) => &["btrfs"],
+				_ => &["e2fsck", "dumpe2fs", "resize2fs"],
+			};
+			for program in programs {
+				req.require(program, format!("shrinking {}", id));
+			}
+		}
+	}
+
+	/// Space the raw image takes in the working directory, and the image
+	/// in the output directory, in bytes.
+	pub(crate) fn required_space(&self) -> (u64, u64) {
+		let raw = self.padded_image_size(self.nominal_image_size());
+		// The raw image is already there.
+		let workdir = if self.runs(Stage::Partition) { raw } else { 0 };
+		let outdir = match self.compress {
+			_ if !self.runs(Stage::Compress) => 0,
+			Compression::None => raw,
+			_ => raw / COMPRESSION_RATIO,
+		};
+		(workdir, outdir)
+	}
+}
+
+/// Collect the requirements of the queue, running up to `jobs` at once.
+pub fn collect(queue: &[ImageContext], jobs: usize) -> Requirements {
+	let mut req = Requirements::default();
+	let mut raw_sizes = Vec::new();
+	for j in queue {
+		j.require_programs(&mut req);
+		let (workdir, outdir) = j.required_space();
+		raw_sizes.push(workdir);
+		req.outdir_space += outdir;
+	}
+	// The sketch directories are removed after each job, unless kept.
+	let kept = queue
+		.iter()
+		.any(|j| j.keep_workdir == KeepWorkdir::Always || j.is_partial());
+	raw_sizes.sort_unstable_by(|a, b| b.cmp(a));
+	let at_once = if kept { raw_sizes.len() } else { jobs.max(1) };
+	req.workdir_space = raw_sizes.iter().take(at_once).sum();
+	req
+}
+
+/// Make sure the host meets the requirements, see the [module documentation](self).
+pub fn check(
+	req: &Requirements,
+	queue: &[ImageContext],
+	workdir: &Path,
+	outdir: &Path,
+) -> Result<()> {
+	info!("Running the preflight check ...");
+	let mut problems = Vec::new();
+	for (program, reasons) in &req.programs {
+		if find_program(program).is_none() {
+			problems.push(format!(
+				"{} is not found, required for {}",
+				program,
+				reasons.iter().cloned().collect::<Vec<_>>().join(", ")
+			));
+		}
+	}
+	for ((dist, program), devices) in &req.target_programs {
+		let found = TARGET_PATHS.iter().any(|dir| {
+			Path::new(dist)
+				.join(dir)
+				.join(program)
+				.symlink_metadata()
+				.is_ok()
+		});
+		if !found {
+			problems.push(format!(
+				"{} is not found in {}, required for creating the user of {}",
+				program,
+				dist,
+				devices.iter().cloned().collect::<Vec<_>>().join(", ")
+			));
+		}
+	}
+	let (workdir_avail, workdir_fs) = available_space(workdir)?;
+	let (outdir_avail, outdir_fs) = available_space(outdir)?;
+	let mut space = vec![(workdir, workdir_avail, req.workdir_space)];
+	if workdir_fs == outdir_fs {
+		space[0].2 += req.outdir_space;
+	} else {
+		space.push((outdir, outdir_avail, req.outdir_space));
+	}
+	for j in queue {
+		if !j.runs(Stage::Bootloader) {
+			continue;
+		}
+		if let Err(e) = j.fetch_bootloader_blobs() {
+			problems.push(format!("{:#}", e));
+		}
+	}
+	for (dir, avail, required) in space {
+		if required > avail {
+			problems.push(format!(
+				"Not enough space in {}: about {} required, {} available",
+				dir.display(),
+				format_size(required),
+				format_size(avail)
+			));
+		}
+	}
+	if !problems.is_empty() {
+		bail!(
+			"Preflight check failed:\n\t{}\nInstall the missing programs, free up the space and fix the downloads, or skip the check with --skip-preflight.",
+			problems.join("\n\t")
+		);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_preflight_check() -> Result<()> {
+		let mut req = Requirements::default();
+		req.require("sh", "testing");
+		check(&req, &[], Path::new("/tmp"), Path::new("/tmp/no/such/dir"))?;
+		req.require("mkrawimg-no-such-program", "formatting a");
+		req.require("mkrawimg-no-such-program", "formatting b");
+		req.workdir_space = u64::MAX / 2;
+		let err = check(&req, &[], Path::new("/tmp"), Path::new("/tmp"))
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains(
+			"mkrawimg-no-such-program is not found, required for formatting a, formatting b"
+		));
+		assert!(!err.contains("sh is not found"));
+		assert!(err.contains("Not enough space in /tmp"));
+		Ok(())
+	}
+}
@@ -0,0 +1,243 @@
+//! Execution of the job queue, with `--jobs N`.
+//!
+//! Up to `N` jobs are run at once, each on its own thread. Most of the time
+//! of a job is spent in rsync, mkfs and the package manager, none of which
+//! saturates the host, so running a few of them at once saves a lot of time
+//! with `build-all`.
+//!
+//! The jobs do not step on each other:
+//!
+//! - Each job works in its own sketch directory, see
+//!   [`ImageContext::sketch_dir`].
+//! - Loop devices are allocated one at a time, see
+//!   [`ImageContext::attach_loop_device`].
+//! - Log records and the output of the external commands are prefixed with
+//!   the job, see [`crate::joblog`]. The progress bar is disabled, since
+//!   only one job can have it.
+//!
+//! Once a job fails, no more jobs are started. The running ones are allowed
+//! to finish, and the status of every job is reported.
+//!
+//! With `--continue-on-error`, the remaining jobs are started anyway. The
+//! mounts and the loop device of a failed job are released first, so they
+//! do not pile up over a long `build-all`.
+use std::{
+	collections::VecDeque,
+	fmt::Display,
+	path::Path,
+	sync::Mutex,
+	thread,
+	time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::{info, Level};
+
+use crate::{
+	context::ImageContextQueue,
+	report::ImageRecord,
+	utils::{loop_devices_backed_by, mounts_under, release_leftovers},
+};
+
+/// Target of the log records of the summary, which are shown even with `--quiet`.
+pub const SUMMARY_TARGET: &str = "summary";
+
+/// Status of a job after the queue is executed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+	Done(Duration),
+	Failed(String),
+	/// Not started, because another job has failed.
+	NotRun,
+}
+
+/// Status of the jobs, in the order of the queue.
+#[derive(Debug, Default)]
+pub struct QueueReport {
+	pub jobs: Vec<(String, JobStatus)>,
+	/// Records of the built images for the build report, in the order of the queue.
+	pub records: Vec<ImageRecord>,
+}
+
+impl QueueReport {
+	pub fn failed(&self) -> usize {
+		self.jobs
+			.iter()
+			.filter(|(_, s)| matches!(s, JobStatus::Failed(_)))
+			.count()
+	}
+}
+
+impl Display for QueueReport {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		for (job, status) in &self.jobs {
+			match status {
+				JobStatus::Done(d) => writeln!(
+					f,
+					"\tDONE      {} ({:.03} seconds)",
+					job,
+					d.as_secs_f32()
+				)?,
+				JobStatus::Failed(e) => writeln!(f, "\tFAILED    {}: {}", job, e)?,
+				JobStatus::NotRun => writeln!(f, "\tNOT RUN   {}", job)?,
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Run `f` on the items with up to `jobs` threads, in the order of the items.
+///
+/// `f` is given the 1-based index of the item. No more items are started
+/// once one of them fails, unless `keep_going`.
+pub fn run_parallel<T, F>(items: Vec<T>, jobs: usize, keep_going: bool, f: F) -> Vec<JobStatus>
+where
+	T: Send,
+	F: Fn(usize, T) -> Result<()> + Sync,
+{
+	let len = items.len();
+	let pending = Mutex::new(items.into_iter().enumerate().collect::<VecDeque<_>>());
+	let statuses = Mutex::new(vec![JobStatus::NotRun; len]);
+	let failed = Mutex::new(false);
+	thread::scope(|s| {
+		for _ in 0..jobs.clamp(1, len.max(1)) {
+			s.spawn(|| loop {
+				if *failed.lock().unwrap() && !keep_going {
+					break;
+				}
+				let (idx, item) = match pending.lock().unwrap().pop_front() {
+					Some(x) => x,
+					None => break,
+				};
+				let start = Instant::now();
+				let status = match f(idx + 1, item) {
+					Ok(()) => JobStatus::Done(start.elapsed()),
+					Err(e) => {
+						*failed.lock().unwrap() = true;
+						JobStatus::Failed(
+							e.chain()
+								.map(|c| c.to_string())
+								.collect::<Vec<_>>()
+								.join(": "),
+						)
+					}
+				};
+				statuses.lock().unwrap()[idx] = status;
+			});
+		}
+	});
+	statuses.into_inner().unwrap()
+}
+
+/// Release the mounts and the loop device left by a failed job.
+fn release_job(sketch_dir: &Path) -> Result<()> {
+	let mounts = mounts_under(sketch_dir)?;
+	let loop_devs = loop_devices_backed_by(sketch_dir.join("rawmedia.img"));
+	release_leftovers(&mounts, &loop_devs)
+}
+
+/// Execute the queue with up to `jobs` jobs at once.
+///
+/// With `keep_going`, the failure of a job does not stop the others.
+pub fn execute_queue(queue: ImageContextQueue, jobs: usize, keep_going: bool) -> QueueReport {
+	let len = queue.len();
+	let names = queue
+		.iter()
+		.map(|j| match j.revision {
+			Some(revision) => format!(
+				"{} ({}, revision {})",
+				&j.device.id,
+				j.variant.to_string().to_lowercase(),
+				revision
+			),
+			None => format!(
+				"{} ({})",
+				&j.device.id,
+				j.variant.to_string().to_lowercase()
+			),
+		})
+		.collect::<Vec<_>>();
+	let started = Mutex::new(0usize);
+	let records = Mutex::new(vec![None; len]);
+	let statuses = run_parallel(queue, jobs, keep_going, |num, j| {
+		{
+			let mut started = started.lock().unwrap();
+			info!("{} images pending.", len - *started);
+			*started += 1;
+		}
+		// The others may run for a long time before the report.
+		let logger = j.logger.clone();
+		let sketch_dir = j.sketch_dir();
+		let record = j.execute(num, len).inspect_err(|e| {
+			logger.log(Level::Error, format!("Failed: {:#}", e));
+			if keep_going {
+				if let Err(e) = release_job(&sketch_dir) {
+					logger.log(
+						Level::Warn,
+						format!(
+							"Unable to release the mounts and the loop device under {}: {:#}",
+							sketch_dir.display(),
+							e
+						),
+					);
+				}
+			}
+		})?;
+		records.lock().unwrap()[num - 1] = record;
+		Ok(())
+	});
+	QueueReport {
+		jobs: names.into_iter().zip(statuses).collect(),
+		records: records
+			.into_inner()
+			.unwrap()
+			.into_iter()
+			.flatten()
+			.collect(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use anyhow::bail;
+
+	use super::*;
+
+	#[test]
+	fn test_run_parallel() {
+		let running = AtomicUsize::new(0);
+		let peak = AtomicUsize::new(0);
+		let statuses = run_parallel((0..8).collect(), 3, false, |num, item: usize| {
+			assert_eq!(num, item + 1);
+			let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+			peak.fetch_max(now, Ordering::SeqCst);
+			thread::sleep(Duration::from_millis(20));
+			running.fetch_sub(1, Ordering::SeqCst);
+			Ok(())
+		});
+		assert!(statuses.iter().all(|s| matches!(s, JobStatus::Done(_))));
+		assert!(peak.load(Ordering::SeqCst) <= 3);
+		assert!(peak.load(Ordering::SeqCst) > 1);
+		// Nothing is started after a failure.
+		let broken = |_, item: usize| {
+			if item == 1 {
+				bail!("broken");
+			}
+			Ok(())
+		};
+		let statuses = run_parallel((0..4).collect(), 1, false, broken);
+		assert!(matches!(statuses[0], JobStatus::Done(_)));
+		assert_eq!(statuses[1], JobStatus::Failed("broken".into()));
+		assert_eq!(statuses[2..], [JobStatus::NotRun, JobStatus::NotRun]);
+		// Unless keep going.
+		let statuses = run_parallel((0..4).collect(), 1, true, broken);
+		assert_eq!(statuses[1], JobStatus::Failed("broken".into()));
+		assert!(statuses
+			.iter()
+			.enumerate()
+			.all(|(idx, s)| idx == 1 || matches!(s, JobStatus::Done(_))));
+		assert!(run_parallel(Vec::<usize>::new(), 4, false, |_, _| Ok(())).is_empty());
+	}
+}
@@ -0,0 +1,566 @@
+//! Writing raw bootloader images to the image.
+//!
+//! A `raw` bootloader step copies a file to an offset of the image with
+//! the semantics of `dd`, without spawning it:
+//!
+//! ```toml
+//! [[bootloader]]
+//! type = "raw"
+//! # The file within the same directory as the device.toml file.
+//! file = "u-boot-sunxi-with-spl.bin"
+//! # Where the file is written, an integer in bytes or a size with a unit.
+//! offset = "8KiB"
+//! ```
+//!
+//! The file can also come from the populated target, e.g. from the BSP
+//! packages, with `from_target`. It may refer to the outputs of other steps
+//! with `{{step:<id>:output}}` then:
+//!
+//! ```toml
+//! [[bootloader]]
+//! type = "raw"
+//! file = "/usr/lib/u-boot/rk64/idbloader.img"
+//! from_target = true
+//! offset = "32KiB"
+//! # Optional, the size of the blocks of skip and count, 512 bytes by default.
+//! bs = 512
+//! # Optional, the blocks skipped at the start of the file.
+//! skip = 64
+//! # Optional, the blocks written, the rest of the file by default.
+//! count = 1024
+//! ```
+//!
+//! Unlike `dd`, the build fails if the file ends before `count` blocks.
+//!
+//! Files which can not be placed in the device registry are downloaded
+//! instead, with `source = { url = "...", sha256 = "..." }` in place of
+//! `file`, see [`crate::fetch`].
+//!
+//! The written range must stay within the image, and out of the partition
+//! table (except the boot code of the MBR, i.e. the first 440 bytes), the
+//! backup GPT and the partitions, where the partitions are laid out the same
+//! way as the partitioning does (see [`plan_layout`]). `check` verifies it
+//! for every variant, when the length is known, i.e. the file is within the
+//! device directory or `count` is specified. Otherwise `check` only verifies
+//! the first byte at `offset`, specify `count` to have the whole range
+//! verified early. Either way, the range is verified again before the file is
+//! written, against the partition table read back from the image, with the
+//! sector size of the loop device. The [reserved regions] are not
+//! partitions, a `raw` step may write to them.
+//!
+//! [`plan_layout`]: crate::plan::plan_layout
+//! [reserved regions]: crate::reserved
+use std::{
+	fs::File,
+	io::{copy, BufReader, Read, Seek, SeekFrom},
+	path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use gptman::GPT;
+use log::debug;
+use mbrman::MBR;
+use serde::Deserialize;
+use strum::VariantArray;
+
+use crate::{
+	bootloader::{resolve_step_vars, BootloaderStep},
+	context::{ImageContext, ImageVariant},
+	device::{DeviceSpec, PartitionMapType},
+	fetch::BlobSource,
+	partition::SECTOR_SIZE,
+	plan::PlannedPartition,
+	size::ByteSize,
+	utils::sector_size,
+};
+
+/// Block size of `skip` and `count` by default.
+const DEFAULT_BS: u64 = 512;
+/// The boot code of the MBR, the rest of the first sector is the partition table.
+const MBR_BOOT_CODE_SIZE: u64 = 440;
+/// Sectors of the primary GPT, including the protective MBR.
+const GPT_SECTORS: u64 = 34;
+/// Sectors of the backup GPT at the end of the image.
+const BACKUP_GPT_SECTORS: u64 = 33;
+
+/// A file written by a bootloader step: within the device directory, within the target with `from_target`, or downloaded from `source`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct BootloaderFile {
+	/// Path to the file, within the device directory or the target.
+	pub file: Option<PathBuf>,
+	/// Whether the file is within the target.
+	#[serde(default)]
+	pub from_target: bool,
+	/// Where the file is downloaded from, see [`crate::fetch`].
+	pub source: Option<BlobSource>,
+}
+
+impl BootloaderFile {
+	/// Name of the file for humans.
+	pub fn name(&self) -> String {
+		match (&self.file, &self.source) {
+			(Some(file), _) => file.display().to_string(),
+			(None, Some(source)) => source.name().to_owned(),
+			(None, None) => "(no file)".to_owned(),
+		}
+	}
+
+	/// Where the file comes from, for the build plans.
+	pub fn describe(&self) -> String {
+		match (&self.source, self.from_target) {
+			(Some(source), _) => {
+				format!("{} downloaded from {}", self.name(), source.url)
+			}
+			(None, true) => format!("{} from the target", self.name()),
+			(None, false) => self.name(),
+		}
+	}
+
+	/// Path to the file within the target, if it is.
+	pub fn target_path(&self) -> Option<&Path> {
+		self.file.as_deref().filter(|_| self.from_target)
+	}
+}
+
+/// Write a file to an offset of the image, see the [module documentation](self).
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct RawSpec {
+	/// The file written.
+	#[serde(flatten)]
+	pub input: BootloaderFile,
+	/// Offset from the start of the image.
+	pub offset: ByteSize,
+	/// Blocks skipped at the start of the file.
+	#[serde(default)]
+	pub skip: u64,
+	/// Size of the blocks of `skip` and `count`.
+	pub bs: Option<ByteSize>,
+	/// Blocks written.
+	pub count: Option<u64>,
+}
+
+impl RawSpec {
+	fn block_size(&self) -> u64 {
+		self.bs.map_or(DEFAULT_BS, |bs| bs.0)
+	}
+
+	/// Bytes skipped at the start of the file.
+	fn skip_bytes(&self) -> Result<u64> {
+		self.skip
+			.checked_mul(self.block_size())
+			.context("skip is too large")
+	}
+
+	/// Bytes written if `count` is specified.
+	fn count_bytes(&self) -> Result<Option<u64>> {
+		self.count
+			.map(|count| {
+				count.checked_mul(self.block_size())
+					.context("count is too large")
+			})
+			.transpose()
+	}
+
+	/// Bytes written from a file of `file_len` bytes.
+	pub(crate) fn write_len(&self, file_len: u64) -> Result<u64> {
+		let skip = self.skip_bytes()?;
+		if skip >= file_len {
+			bail!(
+				"{} is {} bytes, nothing is left after skipping {} bytes",
+				self.input.name(),
+				file_len,
+				skip
+			);
+		}
+		let rest = file_len - skip;
+		match self.count_bytes()? {
+			Some(len) if len > rest => bail!(
+				"{} has {} bytes after skipping {} bytes, less than the {} bytes of count",
+				self.input.name(),
+				rest,
+				skip,
+				len
+			),
+			Some(len) => Ok(len),
+			None => Ok(rest),
+		}
+	}
+}
+
+/// A range of bytes (the end is exclusive) the raw writes must stay out of, with its name.
+type ProtectedRange = (String, u64, u64);
+
+/// Ranges of the partition table planned for the image of `image_size` bytes, and of the partitions laid out.
+fn protected_ranges(
+	map: PartitionMapType,
+	layout: &[PlannedPartition],
+	image_size: u64,
+) -> Vec<ProtectedRange> {
+	let mut ranges = Vec::new();
+	match map {
+		PartitionMapType::GPT | PartitionMapType::Hybrid => {
+			ranges.push((
+				"the primary GPT".to_owned(),
+				MBR_BOOT_CODE_SIZE,
+				GPT_SECTORS * SECTOR_SIZE,
+			));
+			ranges.push((
+				"the backup GPT".to_owned(),
+				image_size.saturating_sub(BACKUP_GPT_SECTORS * SECTOR_SIZE),
+				image_size,
+			));
+		}
+		PartitionMapType::MBR => {
+			ranges.push((
+				"the MBR partition table".to_owned(),
+				MBR_BOOT_CODE_SIZE,
+				SECTOR_SIZE,
+			));
+		}
+		// The filesystem occupies the whole image.
+		PartitionMapType::None => ranges.push(("the filesystem".to_owned(), 0, image_size)),
+	}
+	for p in layout {
+		ranges.push((
+			format!("partition {}", p.num),
+			p.start * SECTOR_SIZE,
+			(p.start + p.size) * SECTOR_SIZE,
+		));
+	}
+	ranges
+}
+
+/// Ranges of the partition table written to `disk` of `image_size` bytes, and of the partitions recorded in it.
+fn read_protected_ranges(
+	disk: &mut File,
+	map: PartitionMapType,
+	image_size: u64,
+) -> Result<Vec<ProtectedRange>> {
+	let mut ranges = Vec::new();
+	if map == PartitionMapType::None {
+		ranges.push(("the filesystem".to_owned(), 0, image_size));
+		return Ok(ranges);
+	}
+	let sector_size = sector_size(disk)?;
+	match map {
+		PartitionMapType::GPT | PartitionMapType::Hybrid => {
+			let gpt = GPT::read_from(disk, sector_size)
+				.context("Unable to read the GPT of the image")?;
+			ranges.push((
+				"the primary GPT".to_owned(),
+				MBR_BOOT_CODE_SIZE,
+				gpt.header.first_usable_lba * sector_size,
+			));
+			ranges.push((
+				"the backup GPT".to_owned(),
+				(gpt.header.last_usable_lba + 1) * sector_size,
+				image_size,
+			));
+			for (num, p) in gpt.iter().filter(|(_, p)| p.is_used()) {
+				ranges.push((
+					format!("partition {}", num),
+					p.starting_lba * sector_size,
+					(p.ending_lba + 1) * sector_size,
+				));
+			}
+		}
+		_ => {
+			let mbr = MBR::read_from(disk, sector_size as u32)
+				.context("Unable to read the MBR of the image")?;
+			ranges.push((
+				"the MBR partition table".to_owned(),
+				MBR_BOOT_CODE_SIZE,
+				sector_size,
+			));
+			// The extended partition covers the logical partitions and their EBRs.
+			let primary = [
+				&mbr.header.partition_1,
+				&mbr.header.partition_2,
+				&mbr.header.partition_3,
+				&mbr.header.partition_4,
+			];
+			for (idx, p) in primary.into_iter().enumerate() {
+				if p.is_used() {
+					ranges.push((
+						format!("partition {}", idx + 1),
+						p.starting_lba as u64 * sector_size,
+						(p.starting_lba as u64 + p.sectors as u64)
+							* sector_size,
+					));
+				}
+			}
+		}
+	}
+	Ok(ranges)
+}
+
+/// Make sure writing `len` bytes at `offset` stays within the image of
+/// `image_size` bytes, and out of the partition table and the partitions.
+fn check_write_range(
+	map: PartitionMapType,
+	layout: &[PlannedPartition],
+	image_size: u64,
+	offset: u64,
+	len: u64,
+) -> Result<()> {
+	check_ranges(
+		&protected_ranges(map, layout, image_size),
+		image_size,
+		offset,
+		len,
+	)
+}
+
+/// Make sure writing `len` bytes at `offset` stays within the image of `image_size` bytes, and out of `ranges`.
+fn check_ranges(ranges: &[ProtectedRange], image_size: u64, offset: u64, len: u64) -> Result<()> {
+	let end = offset
+		.checked_add(len)
+		.context("The write range is too large")?;
+	if end > image_size {
+		bail!(
+			"The write range {:#x}..{:#x} exceeds the image of {} bytes",
+			offset,
+			end,
+			image_size
+		);
+	}
+	for (name, start, stop) in ranges {
+		if offset < *stop && *start < end {
+			bail!(
+				"The write range {:#x}..{:#x} overlaps {} ({:#x}..{:#x})",
+				offset,
+				end,
+				name,
+				start,
+				stop
+			);
+		}
+	}
+	Ok(())
+}
+
+impl DeviceSpec {
+	/// Make sure the bootloader file is found, returns its length if it is within the device directory.
+	pub(crate) fn check_bootloader_file(
+		&self,
+		dirname: &Path,
+		input: &BootloaderFile,
+		steps: &[BootloaderStep],
+	) -> Result<Option<u64>> {
+		let file = match (&input.file, &input.source) {
+			(Some(file), None) => file,
+			(None, Some(source)) => {
+				if input.from_target {
+					bail!("A downloaded file can not be from_target");
+				}
+				source.check()?;
+				// The length of a downloaded file is only known when it is written.
+				return Ok(None);
+			}
+			(Some(_), Some(_)) => {
+				bail!("A bootloader file can not have both file and source")
+			}
+			(None, None) => bail!("A bootloader file requires either file or source"),
+		};
+		if input.from_target {
+			if !file.is_absolute() {
+				bail!(
+					"The file '{}' within the target must be an absolute path",
+					file.display()
+				);
+			}
+			resolve_step_vars(file, steps)?;
+			return Ok(None);
+		}
+		let path = dirname.join(file);
+		if !path.is_file() {
+			bail!(
+				"File '{}' not found within the same directory as the device.toml",
+				file.display()
+			);
+		}
+		Ok(Some(path.metadata()?.len()))
+	}
+
+	/// Make sure writing `len` bytes at `offset` stays out of the partition table and the partitions in the image of every variant.
+	pub(crate) fn check_image_write(&self, offset: u64, len: u64) -> Result<()> {
+		for variant in ImageVariant::VARIANTS {
+			// Reported by check_layout().
+			let Ok((size, layout)) = self.variant_layout(variant) else {
+				continue;
+			};
+			check_write_range(self.partition_map, &layout, size, offset, len).context(
+				format!("In the {} image", variant.to_string().to_lowercase()),
+			)?;
+		}
+		Ok(())
+	}
+
+	/// Make sure the file can be written, see the [module documentation](crate::raw).
+	pub(crate) fn check_raw(
+		&self,
+		dirname: &Path,
+		spec: &RawSpec,
+		steps: &[BootloaderStep],
+	) -> Result<()> {
+		if spec.block_size() == 0 {
+			bail!("bs of a raw bootloader can not be zero");
+		}
+		let len = match self.check_bootloader_file(dirname, &spec.input, steps)? {
+			Some(file_len) => Some(spec.write_len(file_len)?),
+			None => spec.count_bytes()?,
+		};
+		// The length of a file within the target or downloaded is only known when it is
+		// written, only the first byte is verified without count.
+		if len.is_none() {
+			debug!(
+				"The length of {} is unknown, only its offset is verified, specify count to verify the whole range",
+				spec.input.name()
+			);
+		}
+		self.check_image_write(spec.offset.0, len.unwrap_or(1))
+			.context(format!("Unable to write {}", spec.input.name()))
+	}
+}
+
+impl ImageContext<'_> {
+	/// Path to the bootloader file on the host, downloads it if it is from a `source`.
+	pub(crate) fn bootloader_file_path(
+		&self,
+		input: &BootloaderFile,
+		dirname: &Path,
+		root: &Path,
+		steps: &[BootloaderStep],
+	) -> Result<PathBuf> {
+		match (&input.file, &input.source) {
+			(_, Some(source)) => self.fetch_blob(source),
+			(Some(file), None) if input.from_target => {
+				let file = resolve_step_vars(file, steps)?;
+				// join()ing with an absolute path replaces the whole path.
+				Ok(root.join(file.to_string_lossy().trim_start_matches('/')))
+			}
+			(Some(file), None) => Ok(dirname.join(file)),
+			(None, None) => bail!("A bootloader file requires either file or source"),
+		}
+	}
+
+	/// Write the file to the image, see the [module documentation](self).
+	pub(crate) fn write_raw(
+		&self,
+		dirname: &Path,
+		spec: &RawSpec,
+		root: &Path,
+		loopdev: &Path,
+		steps: &[BootloaderStep],
+	) -> Result<()> {
+		let src = self.bootloader_file_path(&spec.input, dirname, root, steps)?;
+		let mut src_fd = File::open(&src)
+			.context(format!("Unable to find {}", spec.input.name()))?;
+		let len = spec.write_len(src_fd.metadata()?.len())?;
+		src_fd.seek(SeekFrom::Start(spec.skip_bytes()?))?;
+		let written = self
+			.write_to_image(BufReader::new(src_fd), spec.offset.0, len, loopdev)
+			.context(format!("Unable to write {}", spec.input.name()))?;
+		self.info(format!(
+			"Wrote {} bytes of {} at {:#x}",
+			written,
+			spec.input.name(),
+			spec.offset.0
+		));
+		Ok(())
+	}
+
+	/// Write `len` bytes from `src` at `offset` of the image, after making sure
+	/// it stays out of the partition table and the partitions read from the
+	/// image, returns the bytes written.
+	pub(crate) fn write_to_image<R: Read>(
+		&self,
+		src: R,
+		offset: u64,
+		len: u64,
+		loopdev: &Path,
+	) -> Result<u64> {
+		let mut loop_dev_fd = File::options()
+			.read(true)
+			.write(true)
+			.truncate(false)
+			.append(false)
+			.open(loopdev)?;
+		let image_size = loop_dev_fd.seek(SeekFrom::End(0))?;
+		let ranges = read_protected_ranges(
+			&mut loop_dev_fd,
+			self.device.partition_map,
+			image_size,
+		)?;
+		check_ranges(&ranges, image_size, offset, len)?;
+		loop_dev_fd.seek(SeekFrom::Start(offset))?;
+		let written = copy(&mut src.take(len), &mut loop_dev_fd)?;
+		if written != len {
+			bail!("Only {} of {} bytes are written", written, len);
+		}
+		loop_dev_fd.sync_all()?;
+		Ok(written)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_write_len() -> Result<()> {
+		let spec = RawSpec {
+			input: BootloaderFile {
+				file: Some(PathBuf::from("u-boot.bin")),
+				..Default::default()
+			},
+			..Default::default()
+		};
+		assert_eq!(spec.write_len(1000)?, 1000);
+		assert!(spec.write_len(0).is_err());
+		let spec = RawSpec {
+			skip: 2,
+			bs: Some(ByteSize(1024)),
+			count: Some(4),
+			..spec
+		};
+		assert_eq!(spec.write_len(8192)?, 4096);
+		assert_eq!(spec.write_len(6144)?, 4096);
+		assert!(spec.write_len(6143).is_err());
+		assert!(spec.write_len(2048).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_write_range() {
+		let size = 64 << 20;
+		let layout = [
+			PlannedPartition {
+				num: 1,
+				start: 2048,
+				size: 2048,
+			},
+			PlannedPartition {
+				num: 2,
+				start: 8192,
+				size: 8192,
+			},
+		];
+		let gpt = PartitionMapType::GPT;
+		// The boot code of the protective MBR, and the gap before the first partition.
+		assert!(check_write_range(gpt, &layout, size, 0, 440).is_ok());
+		assert!(check_write_range(gpt, &layout, size, 0x8000, 0xf8000).is_ok());
+		// The gap between the partitions.
+		assert!(check_write_range(gpt, &layout, size, 4096 * 512, 4096 * 512).is_ok());
+		assert!(check_write_range(gpt, &layout, size, 0, 446).is_err());
+		assert!(check_write_range(gpt, &layout, size, 0x4000, 0x400).is_err());
+		assert!(check_write_range(gpt, &layout, size, 0x8000, 0xf8001).is_err());
+		assert!(check_write_range(gpt, &layout, size, 4096 * 512 - 1, 2).is_err());
+		assert!(check_write_range(gpt, &layout, size, size - 512, 512).is_err());
+		assert!(check_write_range(gpt, &layout, size, size, 1).is_err());
+		let mbr = PartitionMapType::MBR;
+		assert!(check_write_range(mbr, &layout, size, 512, 0x4000).is_ok());
+		assert!(check_write_range(mbr, &layout, size, size - 512, 512).is_ok());
+		assert!(check_write_range(mbr, &layout, size, 0, 512).is_err());
+	}
+}
@@ -0,0 +1,435 @@
+//! Recipes of the system distributions bootstrapped by aoscbootstrap.
+//!
+//! A distribution is bootstrapped for each pair of the variant and the
+//! architecture, with the following files from aoscbootstrap:
+//!
+//! - The configuration, `AB_DIR/config/aosc-mainline.toml`.
+//! - The scripts run after bootstrapping, `AB_DIR/scripts/*.sh`.
+//! - The recipe, which is the list of packages to be installed,
+//!   `AB_DIR/recipes/mainline/{base,kde,server}-common.lst`.
+//!
+//! Ports whose package set diverges can override the recipe, in order of
+//! precedence:
+//!
+//! 1. `--recipe ARCH.VARIANT=PATH` on the command line.
+//! 2. `[recipes]` in the device specification, see [`RecipeSpec`].
+//! 3. The default one above.
+//!
+//! The default recipes only cover the mainline architectures, the devices
+//! of the retro ones (see [`DeviceArch::is_mainline`]) must define their
+//! own recipes for every variant.
+//!
+//! The bootstrapped distributions are cached in the working directory, and
+//! the ones built with an overridden recipe are cached separately (see
+//! [`BootstrapRecipe::key`]). A stamp file records the files used and the
+//! SHA-256 of their contents, so a cached distribution built from other
+//! files, or from the same files before they are edited, is never reused.
+use std::{
+	fmt::Display,
+	fs,
+	path::{Path, PathBuf},
+	str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::{
+	context::ImageVariant,
+	device::{DeviceArch, DeviceSpec},
+	report::sha256_file,
+};
+
+/// Where aoscbootstrap is installed.
+pub const AB_DIR: &str = "/usr/share/aoscbootstrap";
+const AB_CONFIG: &str = "config/aosc-mainline.toml";
+const AB_SCRIPTS: &[&str] = &["scripts/reset-repo.sh", "scripts/enable-dkms.sh"];
+
+/// Recipes overridden by the device specification, by variant.
+///
+/// Relative paths are relative to the directory containing the `device.toml`.
+///
+/// ```toml
+/// [recipes]
+/// desktop = "desktop-loongson3.lst"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecipeSpec {
+	pub base: Option<PathBuf>,
+	pub desktop: Option<PathBuf>,
+	pub server: Option<PathBuf>,
+}
+
+impl RecipeSpec {
+	pub fn get(&self, variant: &ImageVariant) -> Option<&PathBuf> {
+		match variant {
+			ImageVariant::Base => self.base.as_ref(),
+			ImageVariant::Desktop => self.desktop.as_ref(),
+			ImageVariant::Server => self.server.as_ref(),
+		}
+	}
+}
+
+/// A recipe overridden on the command line, in the form of `ARCH.VARIANT=PATH`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecipeOverride {
+	pub arch: DeviceArch,
+	pub variant: ImageVariant,
+	pub path: PathBuf,
+}
+
+/// Parse `ARCH.VARIANT=PATH`, `what` names the path in the errors.
+pub(crate) fn parse_arch_variant_path(
+	s: &str,
+	what: &str,
+) -> Result<(DeviceArch, ImageVariant, PathBuf)> {
+	let (key, path) = s
+		.split_once('=')
+		.context(format!("Expected ARCH.VARIANT=PATH, got '{}'", s))?;
+	let (arch, variant) = key
+		.split_once('.')
+		.context(format!("Expected ARCH.VARIANT, got '{}'", key))?;
+	// Same as the names used in the bootstrap directories and the image names.
+	let arch = *DeviceArch::value_variants()
+		.iter()
+		.find(|a| a.to_string().eq_ignore_ascii_case(arch))
+		.context(format!("Invalid architecture '{}'", arch))?;
+	let variant = *ImageVariant::value_variants()
+		.iter()
+		.find(|v| v.to_string().eq_ignore_ascii_case(variant))
+		.context(format!("Invalid variant '{}'", variant))?;
+	if path.is_empty() {
+		bail!("Empty {} path for {}", what, key);
+	}
+	Ok((arch, variant, PathBuf::from(path)))
+}
+
+impl FromStr for RecipeOverride {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		let (arch, variant, path) = parse_arch_variant_path(s, "recipe")?;
+		Ok(Self {
+			arch,
+			variant,
+			path,
+		})
+	}
+}
+
+/// Where the recipe comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecipeSource {
+	Default,
+	Device(String),
+	CommandLine,
+}
+
+impl Display for RecipeSource {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Default => write!(f, "default"),
+			Self::Device(id) => write!(f, "device {}", id),
+			Self::CommandLine => write!(f, "command line"),
+		}
+	}
+}
+
+/// Files used to bootstrap a distribution.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BootstrapRecipe {
+	pub variant: ImageVariant,
+	pub arch: DeviceArch,
+	pub config: PathBuf,
+	pub scripts: Vec<PathBuf>,
+	pub recipe: PathBuf,
+	pub source: RecipeSource,
+}
+
+impl BootstrapRecipe {
+	/// Resolve the recipe to bootstrap the distribution for the device.
+	pub fn resolve<P: AsRef<Path>>(
+		ab_dir: P,
+		device: &DeviceSpec,
+		variant: &ImageVariant,
+		overrides: &[RecipeOverride],
+	) -> Result<Self> {
+		let spec = match device.recipes.as_ref().and_then(|r| r.get(variant)) {
+			Some(path) => {
+				let dir = device.file_path.parent().context(
+					"Failed to get the directory containing the device spec file",
+				)?;
+				Some((device.id.as_str(), dir.join(path)))
+			}
+			None => None,
+		};
+		Ok(Self::resolve_with(
+			ab_dir,
+			device.arch,
+			variant,
+			spec,
+			overrides,
+		))
+	}
+
+	/// Resolve the recipe, with the one from the device specification if any.
+	fn resolve_with<P: AsRef<Path>>(
+		ab_dir: P,
+		arch: DeviceArch,
+		variant: &ImageVariant,
+		spec: Option<(&str, PathBuf)>,
+		overrides: &[RecipeOverride],
+	) -> Self {
+		let ab_dir = ab_dir.as_ref();
+		let cli = overrides
+			.iter()
+			.rev()
+			.find(|o| o.arch == arch && &o.variant == variant);
+		let (recipe, source) = if let Some(o) = cli {
+			(o.path.clone(), RecipeSource::CommandLine)
+		} else if let Some((id, path)) = spec {
+			(path, RecipeSource::Device(id.to_owned()))
+		} else {
+			let name = match variant {
+				ImageVariant::Desktop => "kde".to_owned(),
+				_ => variant.to_string().to_lowercase(),
+			};
+			(
+				ab_dir.join(format!("recipes/mainline/{}-common.lst", name)),
+				RecipeSource::Default,
+			)
+		};
+		Self {
+			variant: *variant,
+			arch,
+			config: ab_dir.join(AB_CONFIG),
+			scripts: AB_SCRIPTS.iter().map(|s| ab_dir.join(s)).collect(),
+			recipe,
+			source,
+		}
+	}
+
+	/// Make sure all of the files exist, before anything is built.
+	pub fn check(&self) -> Result<()> {
+		let files = std::iter::once(&self.config)
+			.chain(&self.scripts)
+			.chain(std::iter::once(&self.recipe));
+		for file in files {
+			if !file.is_file() {
+				bail!(
+					"{} is not found, which is required to bootstrap the {} distribution for {} (recipe from the {}).",
+					file.display(),
+					self.variant.to_string().to_lowercase(),
+					self.arch.to_string().to_lowercase(),
+					self.source
+				);
+			}
+		}
+		Ok(())
+	}
+
+	/// Name of the cached distribution in the working directory.
+	///
+	/// Overridden recipes get a suffix derived from the path, so they never
+	/// share the cache with the default one.
+	pub fn key(&self) -> String {
+		let key = format!(
+			"{}-{}",
+			self.variant.to_string().to_lowercase(),
+			self.arch.to_string().to_lowercase()
+		);
+		if self.source == RecipeSource::Default {
+			return key;
+		}
+		let mut crc = flate2::Crc::new();
+		crc.update(self.recipe.as_os_str().as_encoded_bytes());
+		format!("{}-{:08x}", key, crc.sum())
+	}
+
+	/// Content of the stamp file, the files with the checksums of their contents.
+	pub fn stamp(&self) -> Result<String> {
+		let line = |name: &str, path: &Path| -> Result<String> {
+			let sum = sha256_file(path)
+				.context(format!("Failed to read {}", path.display()))?;
+			Ok(format!("{}={} {}\n", name, path.display(), sum))
+		};
+		let mut stamp = line("config", &self.config)?;
+		for script in &self.scripts {
+			stamp += &line("script", script)?;
+		}
+		stamp += &line("recipe", &self.recipe)?;
+		Ok(stamp)
+	}
+
+	/// Path to the cached distribution.
+	pub fn path<P: AsRef<Path>>(&self, workdir: P) -> PathBuf {
+		workdir.as_ref().join("bootstrap").join(self.key())
+	}
+
+	fn stamp_path<P: AsRef<Path>>(&self, workdir: P) -> PathBuf {
+		workdir.as_ref()
+			.join("bootstrap")
+			.join(format!("{}.stamp", self.key()))
+	}
+
+	/// Whether the cached distribution is complete, and was built from the same files.
+	pub fn is_cached<P: AsRef<Path>>(&self, workdir: P) -> bool {
+		let path = self.path(&workdir);
+		if !path.join("etc/os-release").exists() {
+			return false;
+		}
+		match fs::read_to_string(self.stamp_path(&workdir)) {
+			Ok(s) if self.stamp().is_ok_and(|stamp| stamp == s) => true,
+			Ok(_) => {
+				warn!(
+					"{} was bootstrapped from other files, bootstrapping again.",
+					path.display()
+				);
+				false
+			}
+			Err(_) => {
+				// Distributions bootstrapped before stamps were introduced.
+				info!(
+					"{} has no stamp, assuming it is built from the default recipe.",
+					path.display()
+				);
+				self.source == RecipeSource::Default
+			}
+		}
+	}
+
+	/// Record the files used to bootstrap the distribution.
+	pub fn write_stamp<P: AsRef<Path>>(&self, workdir: P) -> Result<()> {
+		fs::write(self.stamp_path(&workdir), self.stamp()?)
+			.context("Failed to write the bootstrap stamp")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	fn touch(path: &Path) -> Result<()> {
+		fs::create_dir_all(path.parent().unwrap())?;
+		fs::write(path, "")?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_parse_override() {
+		let o: RecipeOverride = "loongson3.desktop=/srv/desktop.lst".parse().unwrap();
+		assert_eq!(o.arch, DeviceArch::Loongson3);
+		assert_eq!(o.variant, ImageVariant::Desktop);
+		assert_eq!(o.path, PathBuf::from("/srv/desktop.lst"));
+		assert!("loongson3=/srv/desktop.lst"
+			.parse::<RecipeOverride>()
+			.is_err());
+		assert!("vax.base=/srv/base.lst".parse::<RecipeOverride>().is_err());
+		assert!("arm64.base=".parse::<RecipeOverride>().is_err());
+	}
+
+	#[test]
+	fn test_resolve_recipe() -> Result<()> {
+		let base = TempDir::new("recipe")?;
+		let ab_dir = base.join("aoscbootstrap");
+		touch(&ab_dir.join(AB_CONFIG))?;
+		for script in AB_SCRIPTS {
+			touch(&ab_dir.join(script))?;
+		}
+		touch(&ab_dir.join("recipes/mainline/base-common.lst"))?;
+		touch(&ab_dir.join("recipes/mainline/kde-common.lst"))?;
+		let device_dir = base.join("devices/loongson/ls3a6000");
+		touch(&device_dir.join("desktop.lst"))?;
+		let arch = DeviceArch::Loongson3;
+		let spec = || Some(("ls3a6000", device_dir.join("desktop.lst")));
+
+		// Default
+		let recipe = BootstrapRecipe::resolve_with(
+			&ab_dir,
+			arch,
+			&ImageVariant::Base,
+			None,
+			&[],
+		);
+		assert_eq!(recipe.source, RecipeSource::Default);
+		assert_eq!(
+			recipe.recipe,
+			ab_dir.join("recipes/mainline/base-common.lst")
+		);
+		assert_eq!(recipe.key(), "base-loongson3");
+		recipe.check()?;
+		// Device specification
+		let recipe = BootstrapRecipe::resolve_with(
+			&ab_dir,
+			arch,
+			&ImageVariant::Desktop,
+			spec(),
+			&[],
+		);
+		assert_eq!(recipe.source, RecipeSource::Device("ls3a6000".into()));
+		assert_eq!(recipe.recipe, device_dir.join("desktop.lst"));
+		assert!(recipe.key().starts_with("desktop-loongson3-"));
+		recipe.check()?;
+		// Command line, overrides for other architectures are ignored.
+		let overrides = [
+			"loongson3.desktop=/nonexistent/desktop.lst".parse()?,
+			"arm64.server=/nonexistent/server.lst".parse()?,
+		];
+		let recipe = BootstrapRecipe::resolve_with(
+			&ab_dir,
+			arch,
+			&ImageVariant::Desktop,
+			spec(),
+			&overrides,
+		);
+		assert_eq!(recipe.source, RecipeSource::CommandLine);
+		assert_eq!(recipe.recipe, PathBuf::from("/nonexistent/desktop.lst"));
+		let err = recipe.check().unwrap_err().to_string();
+		assert!(err.contains("/nonexistent/desktop.lst"));
+		assert!(err.contains("command line"));
+		// No server recipe in this aoscbootstrap.
+		let recipe = BootstrapRecipe::resolve_with(
+			&ab_dir,
+			arch,
+			&ImageVariant::Server,
+			None,
+			&overrides,
+		);
+		assert_eq!(recipe.source, RecipeSource::Default);
+		assert!(recipe.check().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_stamp() -> Result<()> {
+		let base = TempDir::new("stamp")?;
+		let ab_dir = base.join("aoscbootstrap");
+		touch(&ab_dir.join(AB_CONFIG))?;
+		for script in AB_SCRIPTS {
+			touch(&ab_dir.join(script))?;
+		}
+		let list = ab_dir.join("recipes/mainline/base-common.lst");
+		touch(&list)?;
+		let recipe = BootstrapRecipe::resolve_with(
+			&ab_dir,
+			DeviceArch::Arm64,
+			&ImageVariant::Base,
+			None,
+			&[],
+		);
+		let workdir = base.join("work");
+		touch(&recipe.path(&workdir).join("etc/os-release"))?;
+		recipe.write_stamp(&workdir)?;
+		assert!(recipe.is_cached(&workdir));
+		// The same files, edited
+		fs::write(&list, "aosc-os-core\n")?;
+		assert!(!recipe.is_cached(&workdir));
+		recipe.write_stamp(&workdir)?;
+		assert!(recipe.is_cached(&workdir));
+		Ok(())
+	}
+}
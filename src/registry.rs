@@ -1,13 +1,18 @@
 //! Module handling the registry of the device specifications.
 //!
 //! See [`DeviceRegistry`] for details.
-use crate::{cli::ListFormat, device::DeviceSpec};
+use crate::{
+	cli::ListFormat,
+	device::{DeviceArch, DeviceMetadata, DeviceSpec},
+	extends::load_spec_table,
+};
 use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
 use log::{debug, error, info};
 use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
 use std::{
-	collections::HashMap,
-	fs,
+	collections::{BTreeMap, HashMap},
 	path::{Path, PathBuf},
 };
 use walkdir::WalkDir;
@@ -58,6 +63,116 @@ pub struct DeviceRegistry {
 	registry: HashMap<String, usize>,
 }
 
+/// Result of checking a device specification file, see
+/// [`DeviceRegistry::check_files`].
+pub struct SpecCheck {
+	pub file: PathBuf,
+	/// `None` if the file can not be parsed.
+	pub device: Option<DeviceSpec>,
+	pub errors: Vec<anyhow::Error>,
+}
+
+impl SpecCheck {
+	/// ID of the device, or `-` if the file can not be parsed.
+	pub fn id(&self) -> &str {
+		self.device.as_ref().map(|d| d.id.as_str()).unwrap_or("-")
+	}
+
+	/// Whether the device has the ID or alias.
+	pub fn matches(&self, name: &str) -> bool {
+		self.device.as_ref().is_some_and(|d| {
+			d.id == name || d.aliases.iter().flatten().any(|a| a == name)
+		})
+	}
+}
+
+/// The names of a device, read without parsing the whole specification, see
+/// [`DeviceRegistry::list_entries`].
+#[derive(Clone, Debug, Deserialize)]
+pub struct DeviceEntry {
+	pub id: String,
+	#[serde(default)]
+	pub aliases: Vec<String>,
+	pub name: String,
+	pub vendor: String,
+}
+
+/// A device in the JSON output of `list`.
+#[derive(Serialize)]
+struct DeviceListEntry<'a> {
+	id: &'a str,
+	aliases: &'a [String],
+	tags: &'a [String],
+	vendor: &'a str,
+	arch: String,
+	name: &'a str,
+	deprecated: bool,
+	deprecation_reason: Option<&'a str>,
+	metadata: &'a DeviceMetadata,
+}
+
+/// Filters selecting the devices by architecture, vendor and tags.
+///
+/// Names are matched case-insensitively, a device matches if its
+/// architecture is one of `arch`, its vendor is one of `vendor`, and it has
+/// all of the `tags` (any of them with `any_tag`). An empty list matches
+/// everything.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceFilter {
+	pub arch: Vec<String>,
+	pub vendor: Vec<String>,
+	pub tags: Vec<String>,
+	pub any_tag: bool,
+}
+
+impl DeviceFilter {
+	/// Make sure the architectures exist, a typo would silently match nothing.
+	pub fn check(&self) -> Result<()> {
+		let known = DeviceArch::value_variants()
+			.iter()
+			.map(|a| a.to_string().to_lowercase())
+			.collect::<Vec<_>>();
+		for arch in &self.arch {
+			if !known.contains(&arch.to_lowercase()) {
+				bail!(
+					"Unknown architecture '{}', possible values are: {}",
+					arch,
+					known.join(", ")
+				);
+			}
+		}
+		Ok(())
+	}
+
+	pub fn matches(&self, device: &DeviceSpec) -> bool {
+		let arch = device.arch.to_string();
+		let has_tag =
+			|tag: &String| device.tags.iter().any(|t| t.eq_ignore_ascii_case(tag));
+		let tags = if self.any_tag {
+			self.tags.iter().any(has_tag)
+		} else {
+			self.tags.iter().all(has_tag)
+		};
+		(self.arch.is_empty() || self.arch.iter().any(|a| a.eq_ignore_ascii_case(&arch)))
+			&& (self.vendor.is_empty()
+				|| self.vendor
+					.iter()
+					.any(|v| v.eq_ignore_ascii_case(&device.vendor)))
+			&& (self.tags.is_empty() || tags)
+	}
+}
+
+/// Leave the deprecated devices out of `build-all`, unless `allow_deprecated`.
+///
+/// Returns the devices to build, and the skipped ones.
+pub fn skip_deprecated(
+	devices: Vec<DeviceSpec>,
+	allow_deprecated: bool,
+) -> (Vec<DeviceSpec>, Vec<DeviceSpec>) {
+	devices.into_iter()
+		.partition(|d| !d.deprecated || allow_deprecated)
+}
+
 impl DeviceRegistry {
 	pub fn get_all(self) -> Result<Vec<DeviceSpec>> {
 		if self.devices.is_empty() {
@@ -67,6 +182,25 @@ impl DeviceRegistry {
 		Ok(self.devices)
 	}
 
+	/// Get the devices matching the filter, and how many devices are skipped.
+	pub fn get_filtered(self, filter: &DeviceFilter) -> Result<(Vec<DeviceSpec>, usize)> {
+		filter.check()?;
+		let all = self.get_all()?;
+		let total = all.len();
+		let devices = all
+			.into_iter()
+			.filter(|d| filter.matches(d))
+			.collect::<Vec<_>>();
+		if devices.is_empty() {
+			bail!(
+				"None of the {} devices in the registry matches the filter.",
+				total
+			);
+		}
+		let skipped = total - devices.len();
+		Ok((devices, skipped))
+	}
+
 	pub fn get(self, str: &String) -> Result<DeviceSpec> {
 		if !self.registry.contains_key(str) {
 			bail!("Can't find a device with provided ID or alias '{}'", &str);
@@ -88,14 +222,8 @@ impl DeviceRegistry {
 		);
 		let mut devices = Vec::new();
 		let mut hashmap = HashMap::new();
-		let walker = WalkDir::new(registry_dir).max_depth(4).into_iter();
-		for file in walker {
-			let f = file?;
-			let p = f.path();
-			if !p.is_file() || p.file_name().unwrap() != "device.toml" {
-				continue;
-			}
-			let dev: DeviceSpec = DeviceSpec::from_path(p)?;
+		for p in Self::find_spec_files(registry_dir)? {
+			let dev: DeviceSpec = DeviceSpec::from_path(&p)?;
 			debug!("Parsed device \"{}\"\n{:#?}", &dev.name, &dev);
 			let name = dev.name.clone();
 			let id = dev.id.clone();
@@ -162,7 +290,7 @@ impl DeviceRegistry {
 		} else {
 			bail!("Custom path should be either a directory that contains a device.toml or the device.toml itself.");
 		};
-		let device: DeviceSpec = toml::from_str(&fs::read_to_string(&devicetoml)?)?;
+		let device = DeviceSpec::from_path(&devicetoml)?;
 		let name = &device.name;
 		let id = device.id.clone();
 		debug!(
@@ -178,51 +306,161 @@ impl DeviceRegistry {
 		})
 	}
 
-	pub fn check_validity(self) -> Result<()> {
-		let mut errs = Vec::<anyhow::Error>::new();
-		for d in self.devices {
-			let result = d.check().context(format!(
-				"Sanity check failed for device '{}' at {}:",
-				&d.id,
-				&d.file_path.display()
-			));
-			match result {
+	/// List the names of the devices within the registry directory.
+	///
+	/// Only the names are read from the specification files, which are not
+	/// validated. Files which can not be read are skipped.
+	pub fn list_entries<P: AsRef<Path>>(registry_dir: P) -> Result<Vec<DeviceEntry>> {
+		let mut entries = Vec::new();
+		for p in Self::find_spec_files(registry_dir)? {
+			let entry = load_spec_table(&p)
+				.and_then(|t| Ok(toml::Value::Table(t).try_into::<DeviceEntry>()?));
+			match entry {
+				Ok(entry) => entries.push(entry),
+				Err(e) => debug!("Skipping {}: {:#}", p.display(), e),
+			}
+		}
+		entries.sort_by(|a, b| a.id.cmp(&b.id));
+		Ok(entries)
+	}
+
+	/// The tags used by only one device, in lower case, with the ID of the device.
+	///
+	/// They are likely typos of the other tags, `check` warns about them.
+	/// Tags differing only in case are the same, as in the filters.
+	pub fn single_use_tags(results: &[SpecCheck]) -> Vec<(String, &str)> {
+		let mut tags: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+		for device in results.iter().filter_map(|r| r.device.as_ref()) {
+			for tag in &device.tags {
+				tags.entry(tag.to_ascii_lowercase())
+					.or_default()
+					.push(&device.id);
+			}
+		}
+		tags.into_iter()
+			.filter_map(|(tag, ids)| match ids[..] {
+				[id] => Some((tag, id)),
+				_ => None,
+			})
+			.collect()
+	}
+
+	/// Find all device specification files within the registry directory.
+	pub fn find_spec_files<P: AsRef<Path>>(registry_dir: P) -> Result<Vec<PathBuf>> {
+		let mut files = Vec::new();
+		for file in WalkDir::new(registry_dir).max_depth(4) {
+			let f = file?;
+			let p = f.path();
+			if p.is_file() && p.file_name().unwrap() == "device.toml" {
+				files.push(p.to_owned());
+			}
+		}
+		files.sort();
+		Ok(files)
+	}
+
+	/// Check the device specification files, without stopping at the first
+	/// broken one.
+	///
+	/// Besides the errors of [`DeviceSpec::check`], the files which can not
+	/// be parsed, the IDs and aliases used more than once, and the partitions
+	/// which can not be laid out are reported. Unknown keys are reported if
+	/// `strict`, otherwise they are only warned about.
+	pub fn check_files(files: &[PathBuf], strict: bool) -> Vec<SpecCheck> {
+		let mut names: HashMap<String, PathBuf> = HashMap::new();
+		let mut results = Vec::new();
+		for file in files {
+			let device = match DeviceSpec::from_path(file) {
+				Ok(d) => d,
 				Err(e) => {
-					error!(
-						"FAIL: {} ({})\n\t{}",
-						&d.id,
-						&d.name,
-						&d.file_path.display()
-					);
-					errs.push(e);
+					results.push(SpecCheck {
+						file: file.to_owned(),
+						device: None,
+						errors: vec![e],
+					});
+					continue;
 				}
-				Ok(_) => {
-					info!(
-						"PASS: {} ({})\n\t{}",
-						&d.id,
-						&d.name,
-						&d.file_path.display()
-					)
+			};
+			let mut errors = Vec::new();
+			for name in
+				std::iter::once(&device.id).chain(device.aliases.iter().flatten())
+			{
+				match names.get(name) {
+					Some(occupant) => errors.push(anyhow!(
+						"ID or alias \"{}\" is already used by {}",
+						name,
+						occupant.display()
+					)),
+					None => {
+						names.insert(name.to_owned(), file.to_owned());
+					}
 				}
 			}
+			if let Err(e) = device.check() {
+				errors.push(e);
+			}
+			if let Err(e) = device.check_layout() {
+				errors.push(e.context("Invalid partition layout"));
+			}
+			if let Err(e) = device.check_unknown_keys(strict) {
+				errors.push(e);
+			}
+			results.push(SpecCheck {
+				file: file.to_owned(),
+				device: Some(device),
+				errors,
+			});
 		}
-		if errs.is_empty() {
-			Ok(())
-		} else {
-			for e in errs {
-				let mut s = String::new();
-				s += &e.to_string();
-				e.chain().skip(1).for_each(|c| {
-					s += "\n";
-					s += &c.to_string();
-				});
-				error!("{}", s);
+		results
+	}
+
+	/// Print a PASS/FAIL table of the results, followed by the errors of the
+	/// failing devices. Returns the number of failing devices.
+	pub fn print_check_report(results: &[SpecCheck]) -> usize {
+		let id_width = results
+			.iter()
+			.map(|r| r.id().len())
+			.max()
+			.unwrap_or_default()
+			.max("Device ID".len());
+		println!(
+			"Result {:<2$} File\n{}",
+			"Device ID",
+			"=".repeat(80),
+			id_width
+		);
+		for r in results {
+			let result = if r.errors.is_empty() {
+				"PASS".bright_green().to_string()
+			} else {
+				"FAIL".bright_red().to_string()
+			};
+			println!("{}   {:<3$} {}", result, r.id(), r.file.display(), id_width);
+		}
+		let failed = results
+			.iter()
+			.filter(|r| !r.errors.is_empty())
+			.collect::<Vec<_>>();
+		for r in &failed {
+			let mut s = format!("{} ({}):", r.id(), r.file.display());
+			for e in &r.errors {
+				s += "\n- ";
+				s += &e.chain()
+					.map(|c| c.to_string())
+					.collect::<Vec<_>>()
+					.join("\n  ");
 			}
-			bail!("Sanity check failed. Please check the output for details.")
+			error!("{}", s);
 		}
+		info!(
+			"{} of {} devices passed the check.",
+			results.len() - failed.len(),
+			results.len()
+		);
+		failed.len()
 	}
 
-	fn list_pretty(devices: Vec<DeviceSpec>) {
+	fn list_pretty(devices: Vec<DeviceSpec>, long: bool) {
 		// The following variables are used for formatting.
 		// I prefer formatting this table by hand, since it does not bring
 		// unnecessary dependencies.
@@ -266,6 +504,29 @@ impl DeviceRegistry {
 					_ => "None".to_owned(),
 				}
 			);
+			if device.deprecated {
+				println!(
+					"{} {}",
+					" ".repeat(idx_width),
+					format!("Deprecated: {}", device.deprecation())
+						.bright_yellow()
+				);
+			}
+			if long {
+				let metadata = &device.metadata;
+				for (name, value) in [
+					("Wiki", &metadata.wiki_url),
+					("Flashing", &metadata.flash_instructions),
+					("Maintainer", &metadata.maintainer),
+				] {
+					println!(
+						"{} {:<12}{}",
+						" ".repeat(idx_width),
+						format!("{}:", name),
+						value.as_deref().unwrap_or("None")
+					);
+				}
+			}
 			idx += 1;
 			if idx > devices.len() {
 				println!("\n Done listing devices.");
@@ -278,26 +539,259 @@ impl DeviceRegistry {
 	fn list_simple(devices: Vec<DeviceSpec>) {
 		for device in devices {
 			println!(
-				"{:<31}\t{:<15}\t{}",
+				"{:<31}\t{:<15}\t{}{}",
 				&device.id,
 				&device.arch.to_string().to_lowercase(),
-				&device.name
+				&device.name,
+				if device.deprecated {
+					" [deprecated]"
+				} else {
+					""
+				}
 			);
 		}
 	}
 
-	pub fn list_devices(self, style: ListFormat) -> Result<()> {
-		let mut devices = self.devices;
+	fn list_json(devices: Vec<DeviceSpec>) -> Result<()> {
+		println!("{}", Self::devices_json(&devices)?);
+		Ok(())
+	}
+
+	/// The JSON output of `list`, the metadata included as is.
+	fn devices_json(devices: &[DeviceSpec]) -> Result<String> {
+		let entries = devices
+			.iter()
+			.map(|device| DeviceListEntry {
+				id: &device.id,
+				aliases: device.aliases.as_deref().unwrap_or_default(),
+				tags: &device.tags,
+				vendor: &device.vendor,
+				arch: device.arch.to_string().to_lowercase(),
+				name: &device.name,
+				deprecated: device.deprecated,
+				deprecation_reason: device.deprecation_reason.as_deref(),
+				metadata: &device.metadata,
+			})
+			.collect::<Vec<_>>();
+		Ok(serde_json::to_string_pretty(&entries)?)
+	}
+
+	pub fn list_devices(
+		self,
+		style: ListFormat,
+		long: bool,
+		filter: &DeviceFilter,
+	) -> Result<()> {
+		let (mut devices, skipped) = self.get_filtered(filter)?;
+		if skipped > 0 {
+			info!("{} devices are skipped by the filter.", skipped);
+		}
 		devices.sort_by_key(|f| f.id.clone());
 		info!("The list is being printned out to stdout.");
 		match style {
 			ListFormat::Pretty => {
-				DeviceRegistry::list_pretty(devices);
+				DeviceRegistry::list_pretty(devices, long);
 			}
 			ListFormat::Simple => {
 				DeviceRegistry::list_simple(devices);
-			} // _ => todo!(),
+			}
+			ListFormat::Json => {
+				DeviceRegistry::list_json(devices)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Print the IDs and the aliases of the devices, one per line.
+	pub fn list_ids(self, filter: &DeviceFilter) -> Result<()> {
+		let (devices, _) = self.get_filtered(filter)?;
+		let mut ids = devices
+			.into_iter()
+			.flat_map(|d| std::iter::once(d.id).chain(d.aliases.unwrap_or_default()))
+			.collect::<Vec<_>>();
+		ids.sort();
+		ids.dedup();
+		for id in ids {
+			println!("{}", id);
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	/// Write a registry of fixture devices to `dir`, in the layout of `devices/`.
+	fn fixture_registry(dir: &Path) -> Result<()> {
+		for (vendor, id, arch, tags) in [
+			("generic", "pc-efi", "amd64", "[]"),
+			("raspberrypi", "rpi-4b", "arm64", r#"["sbc", "release"]"#),
+			("raspberrypi", "rpi-5b", "arm64", r#"["sbc", "release"]"#),
+			("starfive", "visionfive-2", "riscv64", r#"["SBC"]"#),
+		] {
+			let spec_dir = dir.join(vendor).join(id);
+			std::fs::create_dir_all(&spec_dir)?;
+			std::fs::write(
+				spec_dir.join("device.toml"),
+				format!(
+					r#"id = "{}"
+vendor = "{}"
+name = "Fixture {}"
+arch = "{}"
+tags = {}
+bsp_packages = []
+partition_map = "gpt"
+size = {{ base = 1024, desktop = 1024, server = 1024 }}
+
+[[partition]]
+num = 1
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+"#,
+					id, vendor, id, arch, tags
+				),
+			)?;
+		}
+		Ok(())
+	}
+
+	fn ids(devices: &[DeviceSpec]) -> Vec<&str> {
+		let mut ids = devices.iter().map(|d| d.id.as_str()).collect::<Vec<_>>();
+		ids.sort();
+		ids
+	}
+
+	#[test]
+	fn test_device_filter() -> Result<()> {
+		let dir = TempDir::new("device-filter")?;
+		fixture_registry(&dir)?;
+		let filter = DeviceFilter {
+			arch: vec!["ARM64".into(), "riscv64".into()],
+			vendor: vec![],
+			..Default::default()
+		};
+		let (devices, skipped) = DeviceRegistry::scan(&*dir)?.get_filtered(&filter)?;
+		assert_eq!(ids(&devices), ["rpi-4b", "rpi-5b", "visionfive-2"]);
+		assert_eq!(skipped, 1);
+		let filter = DeviceFilter {
+			arch: vec!["riscv64".into()],
+			vendor: vec!["RaspberryPi".into(), "starfive".into()],
+			..Default::default()
+		};
+		let (devices, skipped) = DeviceRegistry::scan(&*dir)?.get_filtered(&filter)?;
+		assert_eq!(ids(&devices), ["visionfive-2"]);
+		assert_eq!(skipped, 3);
+		let filter = DeviceFilter {
+			arch: vec!["riscv64".into()],
+			vendor: vec!["raspberrypi".into()],
+			..Default::default()
+		};
+		assert!(DeviceRegistry::scan(&*dir)?.get_filtered(&filter).is_err());
+		let filter = DeviceFilter {
+			arch: vec!["arm46".into()],
+			vendor: vec![],
+			..Default::default()
+		};
+		let err = filter.check().unwrap_err().to_string();
+		assert!(err.contains("Unknown architecture 'arm46'"));
+		let filter = DeviceFilter {
+			tags: vec!["sbc".into(), "release".into()],
+			..Default::default()
+		};
+		let (devices, _) = DeviceRegistry::scan(&*dir)?.get_filtered(&filter)?;
+		assert_eq!(ids(&devices), ["rpi-4b", "rpi-5b"]);
+		let filter = DeviceFilter {
+			tags: vec!["SBC".into(), "release".into()],
+			any_tag: true,
+			..Default::default()
+		};
+		let (devices, skipped) = DeviceRegistry::scan(&*dir)?.get_filtered(&filter)?;
+		assert_eq!(ids(&devices), ["rpi-4b", "rpi-5b", "visionfive-2"]);
+		assert_eq!(skipped, 1);
+		Ok(())
+	}
+
+	#[test]
+	fn test_skip_deprecated() -> Result<()> {
+		let dir = TempDir::new("skip-deprecated")?;
+		fixture_registry(&dir)?;
+		let mut devices = DeviceRegistry::scan(&*dir)?.get_all()?;
+		for device in devices.iter_mut().filter(|d| d.id == "pc-efi") {
+			device.deprecated = true;
+			device.deprecation_reason = Some("Superseded".into());
 		}
+		let (build, skipped) = skip_deprecated(devices.clone(), false);
+		assert_eq!(ids(&build), ["rpi-4b", "rpi-5b", "visionfive-2"]);
+		assert_eq!(ids(&skipped), ["pc-efi"]);
+		let (build, skipped) = skip_deprecated(devices, true);
+		assert_eq!(build.len(), 4);
+		assert!(skipped.is_empty());
+		Ok(())
+	}
+
+	#[test]
+	fn test_devices_json() -> Result<()> {
+		let dir = TempDir::new("devices-json")?;
+		fixture_registry(&dir)?;
+		let mut devices = DeviceRegistry::scan(&*dir)?.get_all()?;
+		devices.sort_by(|a, b| a.id.cmp(&b.id));
+		devices[0].deprecated = true;
+		devices[0].deprecation_reason = Some("Superseded".into());
+		devices[1].metadata.wiki_url =
+			Some("https://wiki.aosc.io/en/hardware/rpi-4b".into());
+		let json: serde_json::Value =
+			serde_json::from_str(&DeviceRegistry::devices_json(&devices)?)?;
+		let entries = json.as_array().unwrap();
+		assert_eq!(entries.len(), 4);
+		assert_eq!(entries[0]["id"], "pc-efi");
+		assert_eq!(entries[0]["arch"], "amd64");
+		assert_eq!(entries[0]["deprecated"], true);
+		assert_eq!(entries[0]["deprecation_reason"], "Superseded");
+		assert_eq!(
+			entries[1]["metadata"]["wiki_url"],
+			"https://wiki.aosc.io/en/hardware/rpi-4b"
+		);
+		assert!(entries[1]["metadata"]["maintainer"].is_null());
+		assert_eq!(entries[1]["tags"][1], "release");
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_files() -> Result<()> {
+		let dir = TempDir::new("check")?;
+		let registry = dir.join("registry");
+		fixture_registry(&registry)?;
+		let broken = dir.join("device.toml");
+		std::fs::write(&broken, "id = \"broken\"\n[[partition]\n")?;
+		let mut files = DeviceRegistry::find_spec_files(&registry)?;
+		let len = files.len();
+		// Checked twice, the IDs collide the second time.
+		files.extend(DeviceRegistry::find_spec_files(&registry)?);
+		files.insert(0, broken.clone());
+		let results = DeviceRegistry::check_files(&files, true);
+		assert_eq!(results.len(), len * 2 + 1);
+		assert_eq!(results[0].id(), "-");
+		assert_eq!(results[0].errors.len(), 1);
+		assert!(results
+			.iter()
+			.filter(|r| r.device.is_some())
+			.take(len)
+			.all(|r| r.errors.is_empty()));
+		assert!(results[len + 1..]
+			.iter()
+			.all(|r| r.errors[0].to_string().contains("is already used by")));
+		assert!(results.iter().any(|r| r.matches("rpi-5b")));
+		assert!(DeviceRegistry::single_use_tags(&results[..len + 1]).is_empty());
+		// rpi-5b and visionfive-2, sorted by the path.
+		assert_eq!(
+			DeviceRegistry::single_use_tags(&results[len - 1..len + 1]),
+			[("release".to_owned(), "rpi-5b")]
+		);
 		Ok(())
 	}
 }
@@ -0,0 +1,307 @@
+//! Machine-readable report of the built images, for the release pipelines.
+//!
+//! After the queue is executed, a record of each successfully built image is
+//! appended to [`REPORT_NAME`] in the output directory, which looks like:
+//!
+//! ```json
+//! {
+//!   "schema_version": 1,
+//!   "images": [
+//!     {
+//!       "build_id": "01JC4ZQ3V8X4Q8N5X4Y4M2K7QZ",
+//!       "device_id": "rpi-5b",
+//!       "variant": "desktop",
+//!       "arch": "arm64",
+//!       "filename": "aosc-os_desktop_rawimg_raspberrypi_rpi-5b_20241108_arm64.img.xz",
+//!       "path": "os-arm64/desktop/rawimg/raspberrypi/aosc-os_desktop_rawimg_raspberrypi_rpi-5b_20241108_arm64.img.xz",
+//!       "revision": null,
+//!       "raw_size": 26214400000,
+//!       "size": 1876543210,
+//!       "sha256": "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+//!       "duration": 1834.52,
+//!       "disk_uuid": "C8E4A7A0-1B2C-4D3E-8F9A-0B1C2D3E4F5A",
+//!       "partitions": [
+//!         { "num": 1, "part_uuid": "...", "fs_uuid": "...", "fs_label": "BOOT" }
+//!       ],
+//!       "kernel_cmdline": "root=UUID=... rw console=ttyS0,115200",
+//!       "chroot_backend": "nspawn",
+//!       "expired_accounts": ["aosc"],
+//!       "stages": [
+//!         { "stage": "partition", "duration": 0.84, "strategy": null },
+//!         { "stage": "populate", "duration": 402.17, "strategy": "tar-pipe" }
+//!       ]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! - `path` is relative to the output directory.
+//! - `raw_size` and `size` are the sizes of the raw image and the output
+//!   file in bytes, `duration` is the time the job took in seconds.
+//! - `sha256` is the checksum of the output file.
+//! - `disk_uuid` is the GUID of the GPT, or the disk identifier of the MBR.
+//! - `fs_label` is the label applied to the filesystem, FAT volume labels
+//!   are uppercase.
+//! - `kernel_cmdline` is the resolved kernel command line, `null` if the
+//!   device does not define one.
+//! - `chroot_backend` is the backend which ran the commands inside the
+//!   target, see [`crate::chroot`].
+//! - `expired_accounts` lists the accounts which have to change the password
+//!   on the first login.
+//! - `stages` is the time each stage took in seconds, including the ones
+//!   finished by earlier builds with `--stages`, see [`crate::stage`].
+//!   `strategy` is how `populate` installed the distribution, `rsync` or
+//!   `tar-pipe`, `null` for the other stages and `--rootfs-source`.
+//!
+//! Fields are only added within a schema version. A report of another
+//! version is never appended to.
+use std::{
+	fs::{self, File},
+	io,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+	chroot, context::ImageContext, device::PartitionMapData, filesystem::FsUuid,
+	stage::StageTiming,
+};
+
+/// Name of the report in the output directory.
+pub const REPORT_NAME: &str = "build-report.json";
+/// Version of the schema of the report.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A partition of a built image.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PartitionRecord {
+	pub num: u32,
+	pub part_uuid: String,
+	pub fs_uuid: Option<String>,
+	#[serde(default)]
+	pub fs_label: Option<String>,
+}
+
+/// A successfully built image.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ImageRecord {
+	pub build_id: String,
+	pub device_id: String,
+	pub variant: String,
+	pub arch: String,
+	pub filename: String,
+	/// Path to the image, relative to the output directory.
+	pub path: PathBuf,
+	pub revision: Option<u32>,
+	/// Size of the raw image, in bytes.
+	pub raw_size: u64,
+	/// Size of the output file, in bytes.
+	pub size: u64,
+	/// SHA-256 of the output file.
+	pub sha256: String,
+	/// Time the job took, in seconds.
+	pub duration: f64,
+	pub disk_uuid: String,
+	pub partitions: Vec<PartitionRecord>,
+	/// Resolved kernel command line.
+	#[serde(default)]
+	pub kernel_cmdline: Option<String>,
+	/// Backend running the commands inside the target.
+	#[serde(default)]
+	pub chroot_backend: Option<String>,
+	/// Accounts which have to change the password on the first login.
+	#[serde(default)]
+	pub expired_accounts: Vec<String>,
+	/// Time each stage took.
+	#[serde(default)]
+	pub stages: Vec<StageTiming>,
+}
+
+/// Content of [`REPORT_NAME`].
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct BuildReport {
+	pub schema_version: u32,
+	pub images: Vec<ImageRecord>,
+}
+
+impl Default for BuildReport {
+	fn default() -> Self {
+		Self {
+			schema_version: SCHEMA_VERSION,
+			images: Vec::new(),
+		}
+	}
+}
+
+impl BuildReport {
+	/// Load the report in the output directory, an empty one if there is none.
+	pub fn load(outdir: &Path) -> Result<Self> {
+		let path = outdir.join(REPORT_NAME);
+		if !path.exists() {
+			return Ok(Self::default());
+		}
+		let content = fs::read_to_string(&path)?;
+		let report: Self = serde_json::from_str(&content)
+			.context(format!("Invalid build report {}", path.display()))?;
+		if report.schema_version != SCHEMA_VERSION {
+			bail!(
+				"Build report {} has schema version {}, but this version writes {}. Move it away to start a new one.",
+				path.display(),
+				report.schema_version,
+				SCHEMA_VERSION
+			);
+		}
+		Ok(report)
+	}
+
+	/// Append the records to the report in the output directory.
+	pub fn append(outdir: &Path, records: &[ImageRecord]) -> Result<()> {
+		if records.is_empty() {
+			return Ok(());
+		}
+		let mut report = Self::load(outdir)?;
+		report.images.extend_from_slice(records);
+		// Never leave a truncated report behind.
+		let path = outdir.join(REPORT_NAME);
+		let tmp = outdir.join(format!(".{}.tmp", REPORT_NAME));
+		fs::write(&tmp, serde_json::to_string_pretty(&report)? + "\n")?;
+		fs::rename(&tmp, &path).context(format!(
+			"Failed to write the build report {}",
+			path.display()
+		))?;
+		Ok(())
+	}
+}
+
+/// SHA-256 of the file, in lowercase hex.
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> Result<String> {
+	let mut file = File::open(path.as_ref())?;
+	let mut hasher = Sha256::new();
+	io::copy(&mut file, &mut hasher)?;
+	Ok(hasher
+		.finalize()
+		.iter()
+		.map(|b| format!("{:02x}", b))
+		.collect())
+}
+
+impl ImageContext<'_> {
+	/// Make the record of the image built by this job.
+	pub(crate) fn image_record(
+		&self,
+		rawimg_path: &Path,
+		outfile_path: &Path,
+		pm_data: &PartitionMapData,
+		duration: Duration,
+	) -> Result<ImageRecord> {
+		self.info("Calculating the checksum of the image ...");
+		let mut partitions = pm_data
+			.data
+			.values()
+			.map(|p| PartitionRecord {
+				num: p.num,
+				part_uuid: p.part_uuid.clone(),
+				fs_uuid: p.fs_uuid.as_ref().map(FsUuid::to_string),
+				fs_label: p.fs_label.clone(),
+			})
+			.collect::<Vec<_>>();
+		partitions.sort_by_key(|p| p.num);
+		Ok(ImageRecord {
+			build_id: self.build_id.to_owned(),
+			device_id: self.device.id.clone(),
+			variant: self.variant.to_string().to_lowercase(),
+			arch: self.device.arch.to_string().to_lowercase(),
+			filename: self.filename.clone(),
+			path: outfile_path
+				.strip_prefix(self.outdir)
+				.unwrap_or(outfile_path)
+				.to_owned(),
+			revision: self.revision,
+			raw_size: fs::metadata(rawimg_path)?.len(),
+			size: fs::metadata(outfile_path)?.len(),
+			sha256: sha256_file(outfile_path)?,
+			duration: duration.as_secs_f64(),
+			disk_uuid: pm_data.uuid.clone(),
+			partitions,
+			kernel_cmdline: match self.device.kernel_cmdline {
+				Some(_) => Some(self.device.gen_kernel_cmdline(pm_data)?),
+				None => None,
+			},
+			chroot_backend: Some(chroot::backend().to_string()),
+			expired_accounts: self.expired_accounts(),
+			stages: self.stage_timings()?,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{stage::Stage, testutil::TempDir, utils::SysrootStrategy};
+
+	fn record(device_id: &str) -> ImageRecord {
+		ImageRecord {
+			build_id: "01JC4ZQ3V8X4Q8N5X4Y4M2K7QZ".into(),
+			device_id: device_id.into(),
+			variant: "base".into(),
+			arch: "arm64".into(),
+			filename: format!("{}.img.xz", device_id),
+			path: PathBuf::from(format!(
+				"os-arm64/base/rawimg/raspberrypi/{}.img.xz",
+				device_id
+			)),
+			revision: Some(1),
+			raw_size: 6144 << 20,
+			size: 1 << 30,
+			sha256: "0".repeat(64),
+			duration: 12.5,
+			disk_uuid: "C8E4A7A0-1B2C-4D3E-8F9A-0B1C2D3E4F5A".into(),
+			partitions: vec![PartitionRecord {
+				num: 1,
+				part_uuid: "8C2D6A1E-3F4B-4C5D-9E6F-7A8B9C0D1E2F".into(),
+				fs_uuid: None,
+				fs_label: Some("BOOT".into()),
+			}],
+			kernel_cmdline: Some("root=UUID=0 rw".into()),
+			chroot_backend: Some("nspawn".into()),
+			expired_accounts: Vec::new(),
+			stages: vec![StageTiming {
+				stage: Stage::Populate,
+				duration: 402.17,
+				strategy: Some(SysrootStrategy::TarPipe),
+			}],
+		}
+	}
+
+	#[test]
+	fn test_append_report() -> Result<()> {
+		let outdir = TempDir::new("report")?;
+		BuildReport::append(&outdir, &[])?;
+		assert!(!outdir.join(REPORT_NAME).exists());
+		BuildReport::append(&outdir, &[record("rpi-5b")])?;
+		BuildReport::append(&outdir, &[record("rpi-4b"), record("rpi-3b")])?;
+		let report = BuildReport::load(&outdir)?;
+		assert_eq!(report.schema_version, SCHEMA_VERSION);
+		let ids = report
+			.images
+			.iter()
+			.map(|r| r.device_id.as_str())
+			.collect::<Vec<_>>();
+		assert_eq!(ids, ["rpi-5b", "rpi-4b", "rpi-3b"]);
+		fs::write(
+			outdir.join(REPORT_NAME),
+			"{\"schema_version\": 0, \"images\": []}",
+		)?;
+		assert!(BuildReport::append(&outdir, &[record("rpi-5b")]).is_err());
+		fs::write(outdir.join("image"), "hello")?;
+		assert_eq!(
+			sha256_file(outdir.join("image"))?,
+			"2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+		);
+		Ok(())
+	}
+}
@@ -0,0 +1,390 @@
+//! Reserved regions of the disk, which no partition may be placed in.
+//!
+//! Some SoCs load their bootloaders from fixed places of the disk, e.g.
+//! sectors 16 to 2047 for the SPL of Allwinner SoCs, or a vendor
+//! environment area between two partitions. Such a hole in the layout is
+//! declared in the partition list, as a pseudo-partition without a number:
+//!
+//! ```toml
+//! [[partition]]
+//! type = "reserved"
+//! name = "env"
+//! start = "16MiB"
+//! size = "1MiB"
+//! ```
+//!
+//! - A reserved region is not recorded in the partition table, and does not
+//!   count in `num_partitions`.
+//! - The partitions placed automatically skip the reserved regions, the
+//!   next one starts after the region at its alignment.
+//! - A partition with a pinned start sector, or filling the rest of the
+//!   image, overlapping a reserved region fails `check`.
+//!
+//! The `flash_offset` bootloader steps refer to a region by name, with the
+//! offset relative to the start of the region:
+//!
+//! ```toml
+//! [[bootloader]]
+//! type = "flash_offset"
+//! path = "/usr/lib/u-boot/env.bin"
+//! reserved = "env"
+//! # Optional, 0 by default
+//! offset = 0
+//! ```
+//!
+//! Such a step is not limited to the gap before the first partition, but
+//! the file must fit in the region. Other `flash_offset` steps can not
+//! start within a reserved region, and can not write across one.
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use crate::{
+	bootloader::BootloaderSpec,
+	device::{DeviceSpec, PartitionMapType},
+	partition::{SectorOffset, SectorSize, SECTOR_SIZE},
+};
+
+/// Partition type of the reserved regions in the partition list.
+const RESERVED_TYPE: &str = "reserved";
+
+/// A region of the disk kept out of the partitions, see the [module documentation](self).
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct ReservedRegion {
+	/// Name referred to by the bootloader steps.
+	pub name: String,
+	#[serde(alias = "start_sector")]
+	pub start: SectorOffset,
+	#[serde(alias = "size_in_sectors")]
+	pub size: SectorSize,
+}
+
+impl ReservedRegion {
+	/// First and the end (exclusive) sectors of the region, in sectors of `sector_size` bytes.
+	pub fn sectors_in(&self, sector_size: u64) -> (u64, u64) {
+		let start = self.start.sectors(sector_size);
+		(start, start + self.size.sectors(sector_size).unwrap_or(0))
+	}
+
+	/// First and the end (exclusive) bytes of the region.
+	fn bytes(&self) -> (u64, u64) {
+		let (start, end) = self.sectors_in(SECTOR_SIZE);
+		(start * SECTOR_SIZE, end * SECTOR_SIZE)
+	}
+}
+
+/// Take the reserved regions out of the partition list of the specification.
+///
+/// The offsets of the bootloader steps referring to the regions are made
+/// absolute, see [`resolve_reserved_offsets`].
+pub fn take_reserved(table: &mut Table) -> Result<Vec<ReservedRegion>> {
+	let mut regions = Vec::new();
+	for key in ["partitions", "partition"] {
+		let Some(Value::Array(entries)) = table.get_mut(key) else {
+			continue;
+		};
+		let (reserved, partitions) = std::mem::take(entries).into_iter().partition(|v| {
+			v.get("type").and_then(Value::as_str) == Some(RESERVED_TYPE)
+		});
+		*entries = partitions;
+		for value in reserved {
+			let region: ReservedRegion =
+				value.try_into().context("Invalid reserved region")?;
+			regions.push(region);
+		}
+	}
+	for key in ["bootloaders", "bootloader"] {
+		if let Some(Value::Array(steps)) = table.get_mut(key) {
+			resolve_reserved_offsets(steps, &regions)?;
+		}
+	}
+	Ok(regions)
+}
+
+/// Move `start` past the reserved regions (`(start, end)` pairs) a place of `size` sectors overlaps.
+pub fn skip_reserved(regions: &[(u64, u64)], start: u64, size: u64, align: u64) -> u64 {
+	let mut start = start;
+	while let Some(&(_, end)) = regions
+		.iter()
+		.find(|&&(s, e)| start < e && s < start + size.max(1))
+	{
+		start = end.next_multiple_of(align);
+	}
+	start
+}
+
+/// Make the offsets of the bootloader steps referring to the reserved regions
+/// absolute, the offset is 0 (the start of the region) if omitted.
+fn resolve_reserved_offsets(steps: &mut [Value], regions: &[ReservedRegion]) -> Result<()> {
+	for (idx, step) in steps.iter_mut().enumerate() {
+		let Some(step) = step.as_table_mut() else {
+			continue;
+		};
+		let Some(reserved) = step
+			.get("reserved")
+			.and_then(Value::as_str)
+			.map(str::to_owned)
+		else {
+			continue;
+		};
+		let name = match step.get("id").and_then(Value::as_str) {
+			Some(id) => id.to_owned(),
+			None => format!("#{}", idx + 1),
+		};
+		let offset = match step.get("offset") {
+			None => 0,
+			Some(Value::Integer(n)) if *n >= 0 => *n as u64,
+			Some(v) => bail!("Bootloader {}: invalid offset '{}'", name, v),
+		};
+		let region = regions
+			.iter()
+			.find(|r| r.name == reserved)
+			.context(format!(
+				"Bootloader {} refers to the reserved region '{}', which is not declared",
+				name, reserved
+			))?;
+		let (start, end) = region.bytes();
+		if start + offset >= end {
+			bail!(
+				"Bootloader {}: offset {:#x} is beyond the end of the reserved region '{}' ({} bytes)",
+				name,
+				offset,
+				reserved,
+				end - start
+			);
+		}
+		step.insert("offset".into(), Value::Integer((start + offset) as i64));
+	}
+	Ok(())
+}
+
+impl DeviceSpec {
+	/// The reserved regions as `(start, end)` pairs, in sectors of `sector_size` bytes.
+	pub fn reserved_in(&self, sector_size: u64) -> Vec<(u64, u64)> {
+		self.reserved
+			.iter()
+			.map(|r| r.sectors_in(sector_size))
+			.collect()
+	}
+
+	/// Make sure writing `len` bytes at `offset` stays out of the reserved
+	/// regions, other than the one named `reserved` it is written to.
+	pub fn check_reserved_write(
+		&self,
+		offset: u64,
+		len: u64,
+		reserved: Option<&str>,
+	) -> Result<()> {
+		let end = offset + len.max(1);
+		for region in &self.reserved {
+			let (r_start, r_end) = region.bytes();
+			if Some(region.name.as_str()) == reserved {
+				if offset < r_start || end > r_end {
+					bail!(
+						"{:#x}-{:#x} does not fit in the reserved region '{}' ({:#x}-{:#x})",
+						offset,
+						end - 1,
+						region.name,
+						r_start,
+						r_end - 1
+					);
+				}
+			} else if offset < r_end && r_start < end {
+				bail!(
+					"{:#x}-{:#x} overlaps the reserved region '{}' ({:#x}-{:#x})",
+					offset,
+					end - 1,
+					region.name,
+					r_start,
+					r_end - 1
+				);
+			}
+		}
+		Ok(())
+	}
+
+	/// Make sure the reserved regions are valid, and the bootloaders only write to the ones they refer to.
+	///
+	/// The partitions overlapping the reserved regions are found while they are laid out.
+	pub(crate) fn check_reserved(&self) -> Result<()> {
+		if self.reserved.is_empty() {
+			return Ok(());
+		}
+		let table_end = match self.partition_map {
+			PartitionMapType::GPT | PartitionMapType::Hybrid => 34,
+			PartitionMapType::MBR => 1,
+			PartitionMapType::None => {
+				bail!("Reserved regions require a partition table")
+			}
+		};
+		for (idx, region) in self.reserved.iter().enumerate() {
+			let name = &region.name;
+			if name.is_empty() || name.contains(char::is_whitespace) {
+				bail!(
+					"Name of the reserved region '{}' must not be empty or contain white spaces",
+					name
+				);
+			}
+			if self.reserved[..idx].iter().any(|r| &r.name == name) {
+				bail!("Reserved region '{}' is declared more than once", name);
+			}
+			if region.size == SectorSize::Rest {
+				bail!("Reserved region '{}' must have a fixed size", name);
+			}
+			let (start, end) = region.sectors_in(SECTOR_SIZE);
+			if start < table_end {
+				bail!(
+					"Reserved region '{}' starts at sector {}, which overlaps the partition table",
+					name,
+					start
+				);
+			}
+			if let Some(other) = self.reserved[..idx].iter().find(|r| {
+				let (s, e) = r.sectors_in(SECTOR_SIZE);
+				start < e && s < end
+			}) {
+				bail!(
+					"Reserved region '{}' overlaps the reserved region '{}'",
+					name,
+					other.name
+				);
+			}
+		}
+		for (idx, step) in self.bootloaders.iter().flatten().enumerate() {
+			if let BootloaderSpec::FlashOffset {
+				offset, reserved, ..
+			} = &step.spec
+			{
+				// The length of the file is only known when it is written.
+				self.check_reserved_write(*offset, 1, reserved.as_deref())
+					.context(format!("Bootloader {}", step.name(idx)))?;
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::{load_device, TempDir};
+
+	/// The root partition filling the rest of the image.
+	const ROOT: &str = r#"
+[[partition]]
+num = 1
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+"#;
+
+	/// A reserved region named `name`, from `start` sectors for `size`.
+	fn region(name: &str, start: u64, size: &str) -> String {
+		format!(
+			"\n[[partition]]\ntype = \"reserved\"\nname = \"{}\"\nstart = {}\nsize = \"{}\"\n",
+			name, start, size
+		)
+	}
+
+	#[test]
+	fn test_skip_reserved() {
+		let regions = [(16, 2048), (32768, 34816)];
+		assert_eq!(skip_reserved(&regions, 2048, 2048, 2048), 2048);
+		assert_eq!(skip_reserved(&regions, 0, 2048, 2048), 2048);
+		assert_eq!(skip_reserved(&regions, 30720, 4096, 2048), 34816);
+		assert_eq!(skip_reserved(&regions, 30720, 2048, 2048), 30720);
+		assert_eq!(skip_reserved(&regions, 32769, 1, 4096), 36864);
+	}
+
+	#[test]
+	fn test_take_reserved() -> Result<()> {
+		let mut table: Table = toml::from_str(
+			r#"
+[[partition]]
+type = "reserved"
+name = "spl"
+start = 16
+size = "1MiB"
+
+[[partition]]
+num = 1
+type = "linux"
+
+[[bootloader]]
+type = "flash_offset"
+path = "/usr/lib/u-boot/u-boot-sunxi-with-spl.bin"
+reserved = "spl"
+offset = 512
+"#,
+		)?;
+		let regions = take_reserved(&mut table)?;
+		assert_eq!(regions.len(), 1);
+		assert_eq!(regions[0].name, "spl");
+		assert_eq!(regions[0].sectors_in(512), (16, 2064));
+		assert_eq!(table["partition"].as_array().map(Vec::len), Some(1));
+		// The offset is made relative to the start of the image.
+		assert_eq!(
+			table["bootloader"]
+				.as_array()
+				.and_then(|steps| steps[0].get("offset"))
+				.and_then(Value::as_integer),
+			Some(16 * 512 + 512)
+		);
+		let err = |steps: &str| -> Result<String> {
+			let mut table: Table = toml::from_str(&format!(
+				"[[partition]]\ntype = \"reserved\"\nname = \"spl\"\nstart = 16\nsize = \"1MiB\"\n\n[[bootloader]]\ntype = \"flash_offset\"\npath = \"spl.bin\"\n{}",
+				steps
+			))?;
+			Ok(take_reserved(&mut table).unwrap_err().to_string())
+		};
+		let e = err("reserved = \"env\"\n")?;
+		assert!(e.contains("which is not declared"), "{}", e);
+		let e = err("reserved = \"spl\"\noffset = 1048576\n")?;
+		assert!(e.contains("beyond the end"), "{}", e);
+		let e = err("reserved = \"spl\"\noffset = -1\n")?;
+		assert!(e.contains("invalid offset"), "{}", e);
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_reserved() -> Result<()> {
+		let dir = TempDir::new("check-reserved")?;
+		let check = |spec: &str| load_device(&dir, spec)?.check_reserved();
+		let err = |spec: &str| format!("{:#}", check(spec).unwrap_err());
+		let spec = |map: &str, parts: &[&str]| {
+			format!("partition_map = \"{}\"\n{}", map, parts.concat())
+		};
+		// The SPL of the Allwinner SoCs, right after the MBR.
+		let spl = region("spl", 16, "1MiB");
+		let env = region("env", 4096, "1MiB");
+		check(&spec("mbr", &[&spl, &env, ROOT]))?;
+		check(&spec("gpt", &[&region("spl", 34, "1MiB"), ROOT]))?;
+		let e = err(&spec("gpt", &[&spl, ROOT]));
+		assert!(e.contains("overlaps the partition table"), "{}", e);
+		let e = err(&spec("mbr", &[&region("spl", 0, "1MiB"), ROOT]));
+		assert!(e.contains("overlaps the partition table"), "{}", e);
+		let e = err(&spec("mbr", &[&spl, &region("spl", 4096, "1MiB"), ROOT]));
+		assert!(e.contains("declared more than once"), "{}", e);
+		let e = err(&spec("mbr", &[&spl, &region("env", 1024, "1MiB"), ROOT]));
+		assert!(e.contains("overlaps the reserved region 'spl'"), "{}", e);
+		let e = err(&spec("mbr", &[&region("spl env", 16, "1MiB"), ROOT]));
+		assert!(e.contains("must not be empty"), "{}", e);
+		let e = err(&spec("mbr", &[&region("spl", 16, "rest"), ROOT]));
+		assert!(e.contains("must have a fixed size"), "{}", e);
+		let e = err(&spec("none", &[&spl, ROOT]));
+		assert!(e.contains("require a partition table"), "{}", e);
+		// The bootloaders write to the regions they refer to, and only to them.
+		let step = |extra: &str| {
+			format!(
+				"\n[[bootloader]]\ntype = \"flash_offset\"\npath = \"spl.bin\"\n{}",
+				extra
+			)
+		};
+		check(&spec("mbr", &[&spl, ROOT, &step("reserved = \"spl\"\n")]))?;
+		let e = err(&spec("mbr", &[&spl, ROOT, &step("offset = 16384\n")]));
+		assert!(e.contains("overlaps the reserved region 'spl'"), "{}", e);
+		Ok(())
+	}
+}
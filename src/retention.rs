@@ -0,0 +1,393 @@
+//! Retention policy of the images in the output directory.
+//!
+//! Nightly builds accumulate images in the output directory, which follows
+//! the hierarchy of AOSC OS releases:
+//!
+//! ```text
+//! OUTDIR/os-ARCH/VARIANT/rawimg/VENDOR/aosc-os_VARIANT_rawimg_VENDOR_ID_DATE{.REV}_ARCH.img{.EXT}
+//! ```
+//!
+//! Images of the same device, variant and architecture form a series, which
+//! is pruned according to the [`RetentionPolicy`]. Only the files matching
+//! the naming scheme above in the directories following the hierarchy above
+//! are ever considered, anything else is left alone.
+//!
+//! The log of a pruned image (`IMAGE.log`) is removed along with it, if it
+//! is still there. Neither the logs nor the build report are required, the
+//! images are found by their names alone.
+use std::{
+	cmp::Reverse,
+	collections::HashMap,
+	fs,
+	path::{Path, PathBuf},
+	str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::NaiveDate;
+use log::{info, warn};
+
+/// Extensions of the images, see [`Compression`](crate::cli::Compression).
+const IMAGE_EXTENSIONS: &[&str] = &[".img.xz", ".img.zst", ".img.gz", ".img"];
+
+/// Which images to keep in the output directory.
+///
+/// Written as comma separated rules, e.g. `keep-last=5,keep-days=30`:
+///
+/// - `keep-last=N`: Keep the newest N images of each series.
+/// - `keep-days=N`: Keep the images built in the last N days.
+///
+/// An image is kept if any of the rules keeps it. Regardless of the rules,
+/// the newest image of each series, and images referenced by a symbolic link
+/// (e.g. `latest`) in the same directory are always kept.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+	pub keep_last: Option<usize>,
+	pub keep_days: Option<i64>,
+}
+
+impl FromStr for RetentionPolicy {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		let mut policy = Self::default();
+		for rule in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+			let (key, value) = rule
+				.split_once('=')
+				.context(format!("Invalid retention rule '{}'", rule))?;
+			match key.trim() {
+				"keep-last" => {
+					policy.keep_last = Some(value.trim().parse().context(
+						format!("Invalid value of keep-last: '{}'", value),
+					)?)
+				}
+				"keep-days" => {
+					let days: i64 = value.trim().parse().context(format!(
+						"Invalid value of keep-days: '{}'",
+						value
+					))?;
+					if days < 0 {
+						bail!("keep-days can not be negative");
+					}
+					policy.keep_days = Some(days);
+				}
+				_ => bail!("Unknown retention rule '{}'", key),
+			}
+		}
+		if policy == Self::default() {
+			bail!("No retention rule specified");
+		}
+		Ok(policy)
+	}
+}
+
+/// An image found in the output directory.
+#[derive(Debug)]
+struct Artifact {
+	path: PathBuf,
+	date: NaiveDate,
+	revision: u32,
+}
+
+/// The log written next to the image.
+fn log_path(image: &Path) -> PathBuf {
+	let mut path = image.as_os_str().to_owned();
+	path.push(".log");
+	PathBuf::from(path)
+}
+
+/// Parse the name of an image, returns the series, the date and the revision.
+fn parse_image_name(name: &str) -> Option<(String, NaiveDate, u32)> {
+	let rest = name.strip_prefix("aosc-os_")?;
+	let rest = IMAGE_EXTENSIONS
+		.iter()
+		.find_map(|ext| rest.strip_suffix(ext))?;
+	let (rest, arch) = rest.rsplit_once('_')?;
+	let (head, date) = rest.rsplit_once('_')?;
+	if arch.is_empty() || !head.contains("_rawimg_") {
+		return None;
+	}
+	let (date, revision) = match date.split_once('.') {
+		Some((d, r)) => (d, r.parse().ok()?),
+		None => (date, 0),
+	};
+	if date.len() != 8 || !date.bytes().all(|b| b.is_ascii_digit()) {
+		return None;
+	}
+	let date = NaiveDate::parse_from_str(date, "%Y%m%d").ok()?;
+	Some((format!("{}_{}", head, arch), date, revision))
+}
+
+/// Subdirectories of `dir`, symbolic links are not followed.
+fn subdirs(dir: &Path) -> Result<Vec<PathBuf>> {
+	let mut dirs = Vec::new();
+	for entry in fs::read_dir(dir).context(format!("Failed to read {}", dir.display()))? {
+		let entry = entry?;
+		if entry.file_type()?.is_dir() {
+			dirs.push(entry.path());
+		}
+	}
+	dirs.sort();
+	Ok(dirs)
+}
+
+/// Directories containing the images, i.e. `OUTDIR/os-*/*/rawimg/*`.
+fn image_dirs(outdir: &Path) -> Result<Vec<PathBuf>> {
+	let mut dirs = Vec::new();
+	for arch_dir in subdirs(outdir)? {
+		if !arch_dir
+			.file_name()
+			.unwrap_or_default()
+			.to_string_lossy()
+			.starts_with("os-")
+		{
+			continue;
+		}
+		for variant_dir in subdirs(&arch_dir)? {
+			let rawimg_dir = variant_dir.join("rawimg");
+			if !fs::symlink_metadata(&rawimg_dir).is_ok_and(|m| m.is_dir()) {
+				continue;
+			}
+			dirs.extend(subdirs(&rawimg_dir)?);
+		}
+	}
+	Ok(dirs)
+}
+
+impl RetentionPolicy {
+	/// Find the images to be pruned in a directory of images.
+	fn select_in(&self, dir: &Path, today: NaiveDate) -> Result<Vec<PathBuf>> {
+		let mut series: HashMap<String, Vec<Artifact>> = HashMap::new();
+		let mut referenced = Vec::new();
+		for entry in fs::read_dir(dir)? {
+			let entry = entry?;
+			let file_type = entry.file_type()?;
+			let path = entry.path();
+			if file_type.is_symlink() {
+				// Broken links do not reference anything.
+				if let Ok(target) = path.canonicalize() {
+					referenced.push(target);
+				}
+				continue;
+			}
+			if !file_type.is_file() {
+				continue;
+			}
+			let name = entry.file_name();
+			if let Some((key, date, revision)) =
+				parse_image_name(&name.to_string_lossy())
+			{
+				series.entry(key).or_default().push(Artifact {
+					path,
+					date,
+					revision,
+				});
+			}
+		}
+		let mut expired = Vec::new();
+		for artifacts in series.values_mut() {
+			// Newest first.
+			artifacts.sort_by_key(|a| Reverse((a.date, a.revision)));
+			for (idx, artifact) in artifacts.iter().enumerate() {
+				let keep =
+					idx == 0 || self.keep_last.is_some_and(|n| idx < n)
+						|| self.keep_days.is_some_and(|d| {
+							(today - artifact.date).num_days() <= d
+						}) || referenced.contains(&artifact.path.canonicalize()?);
+				if !keep {
+					expired.push(artifact.path.clone());
+				}
+			}
+		}
+		expired.sort();
+		Ok(expired)
+	}
+
+	/// Find the images to be pruned in the output directory.
+	pub fn select(&self, outdir: &Path, today: NaiveDate) -> Result<Vec<PathBuf>> {
+		let mut expired = Vec::new();
+		for dir in image_dirs(outdir)? {
+			expired.extend(self.select_in(&dir, today)?);
+		}
+		Ok(expired)
+	}
+
+	/// Prune the images in the output directory, or only list them if `dry_run` is set.
+	pub fn apply(&self, outdir: &Path, dry_run: bool) -> Result<()> {
+		let expired = self
+			.select(outdir, chrono::Utc::now().date_naive())?
+			.into_iter()
+			.flat_map(|image| {
+				let log = log_path(&image);
+				// The log may be removed by hand, or be never written.
+				let log = fs::symlink_metadata(&log)
+					.is_ok_and(|m| m.is_file())
+					.then_some(log);
+				std::iter::once(image).chain(log)
+			})
+			.collect::<Vec<_>>();
+		if expired.is_empty() {
+			info!("Retention: nothing to prune in {}.", outdir.display());
+			return Ok(());
+		}
+		if dry_run {
+			info!("Retention: the following files would be removed:");
+			for path in &expired {
+				println!("{}", path.display());
+			}
+			return Ok(());
+		}
+		info!("Retention: removing {} file(s) ...", expired.len());
+		for path in &expired {
+			info!("Removing {} ...", path.display());
+			if let Err(e) = fs::remove_file(path) {
+				warn!("Unable to remove {}: {}", path.display(), e);
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::os::unix::fs::symlink;
+
+	use super::*;
+	use crate::testutil::TempDir;
+
+	fn image(device: &str, date: &str) -> String {
+		format!(
+			"aosc-os_desktop_rawimg_raspberrypi_{}_{}_arm64.img.xz",
+			device, date
+		)
+	}
+
+	#[test]
+	fn test_parse_retention() {
+		let policy: RetentionPolicy = "keep-last=5,keep-days=30".parse().unwrap();
+		assert_eq!(policy.keep_last, Some(5));
+		assert_eq!(policy.keep_days, Some(30));
+		assert!("".parse::<RetentionPolicy>().is_err());
+		assert!("keep-last".parse::<RetentionPolicy>().is_err());
+		assert!("keep-weeks=2".parse::<RetentionPolicy>().is_err());
+		assert!("keep-days=-1".parse::<RetentionPolicy>().is_err());
+	}
+
+	#[test]
+	fn test_parse_image_name() {
+		let (series, _, revision) = parse_image_name(
+			"aosc-os_base_rawimg_raspberrypi_rpi-5b_20241108.2_arm64.img.zst",
+		)
+		.unwrap();
+		assert_eq!(series, "base_rawimg_raspberrypi_rpi-5b_arm64");
+		assert_eq!(revision, 2);
+		assert!(
+			parse_image_name("aosc-os_base_rawimg_raspberrypi_rpi-5b_arm64.img")
+				.is_none()
+		);
+		assert!(parse_image_name(
+			"aosc-os_base_rawimg_raspberrypi_rpi-5b_2024110_arm64.img"
+		)
+		.is_none());
+		assert!(parse_image_name(
+			"aosc-os_base_rawimg_raspberrypi_rpi-5b_20241108_arm64.iso"
+		)
+		.is_none());
+		assert!(parse_image_name(
+			"notes_base_rawimg_raspberrypi_rpi-5b_20241108_arm64.img"
+		)
+		.is_none());
+	}
+
+	#[test]
+	fn test_retention() -> Result<()> {
+		let outdir = TempDir::new("retention")?;
+		let dir = outdir.join("os-arm64/desktop/rawimg/raspberrypi");
+		fs::create_dir_all(&dir)?;
+		let dates = ["20240901", "20241001", "20241020", "20241101", "20241108"];
+		for date in dates {
+			fs::write(dir.join(image("rpi-5b", date)), "")?;
+		}
+		// Only old images in this series, the newest one is kept anyway.
+		fs::write(dir.join(image("rpi-4b", "20240101")), "")?;
+		fs::write(dir.join(image("rpi-4b", "20240102")), "")?;
+		// Referenced by a symbolic link.
+		symlink(image("rpi-5b", "20240901"), dir.join("latest"))?;
+		symlink("does-not-exist", dir.join("broken"))?;
+		// Foreign files and directories.
+		fs::write(dir.join("README"), "")?;
+		fs::write(dir.join(image("rpi-5b", "20241001") + ".sha256sum"), "")?;
+		fs::create_dir_all(dir.join(image("rpi-5b", "20200101")))?;
+		let foreign = outdir.join("misc/desktop/rawimg/raspberrypi");
+		fs::create_dir_all(&foreign)?;
+		fs::write(foreign.join(image("rpi-5b", "20200101")), "")?;
+		fs::write(outdir.join(image("rpi-5b", "20200101")), "")?;
+
+		let today = NaiveDate::parse_from_str("20241110", "%Y%m%d")?;
+		let policy: RetentionPolicy = "keep-days=30".parse()?;
+		let expired = policy.select(&outdir, today)?;
+		assert_eq!(
+			expired,
+			vec![
+				dir.join(image("rpi-4b", "20240101")),
+				dir.join(image("rpi-5b", "20241001")),
+			]
+		);
+		policy.apply(&outdir, true)?;
+		assert!(dir.join(image("rpi-5b", "20241001")).exists());
+		let policy: RetentionPolicy = "keep-last=1".parse()?;
+		policy.apply(&outdir, false)?;
+		let mut left = fs::read_dir(&dir)?
+			.map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+			.collect::<Vec<_>>();
+		left.sort();
+		let mut expected = vec![
+			image("rpi-4b", "20240102"),
+			image("rpi-5b", "20200101"),
+			image("rpi-5b", "20240901"),
+			image("rpi-5b", "20241001") + ".sha256sum",
+			image("rpi-5b", "20241108"),
+			"README".to_string(),
+			"broken".to_string(),
+			"latest".to_string(),
+		];
+		expected.sort();
+		assert_eq!(left, expected);
+		assert!(foreign.join(image("rpi-5b", "20200101")).exists());
+		assert!(outdir.join(image("rpi-5b", "20200101")).exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_retention_logs() -> Result<()> {
+		let outdir = TempDir::new("retention-logs")?;
+		let dir = outdir.join("os-arm64/desktop/rawimg/raspberrypi");
+		fs::create_dir_all(&dir)?;
+		let log = |name: String| name + ".log";
+		for date in ["20240901", "20241001", "20241108"] {
+			fs::write(dir.join(image("rpi-5b", date)), "")?;
+		}
+		// The log of the oldest image is missing, and there is no build report.
+		fs::write(dir.join(log(image("rpi-5b", "20241001"))), "")?;
+		fs::write(dir.join(log(image("rpi-5b", "20241108"))), "")?;
+		// The image of this log is already removed.
+		fs::write(dir.join(log(image("rpi-5b", "20240801"))), "")?;
+		let policy: RetentionPolicy = "keep-last=1".parse()?;
+		policy.apply(&outdir, true)?;
+		assert!(dir.join(log(image("rpi-5b", "20241001"))).exists());
+		policy.apply(&outdir, false)?;
+		let mut left = fs::read_dir(&dir)?
+			.map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+			.collect::<Vec<_>>();
+		left.sort();
+		assert_eq!(
+			left,
+			[
+				log(image("rpi-5b", "20240801")),
+				image("rpi-5b", "20241108"),
+				log(image("rpi-5b", "20241108")),
+			]
+		);
+		Ok(())
+	}
+}
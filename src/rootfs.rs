@@ -0,0 +1,510 @@
+//! System distributions installed from tarballs and OCI images, with `--rootfs-source`.
+//!
+//! `--rootfs-source PATH` installs the distribution from an archive built
+//! elsewhere (e.g. by the CI), instead of bootstrapping one:
+//!
+//! - A system tarball, uncompressed or compressed with gzip, xz or zstd.
+//!   The compression is told from the contents, not the name.
+//! - An OCI image layout directory, with `oci-layout` and `index.json`,
+//!   holding the image of a single platform. Its layers are extracted in
+//!   order, and the whiteouts (`.wh.NAME` and `.wh..wh..opq`) of each layer
+//!   remove the files of the layers below. The symbolic links in the target
+//!   are not followed, a whiteout below one fails the build.
+//!
+//! The archives are extracted right into the target with `tar`, preserving
+//! the ownership (by the numeric IDs), the permissions, the hard links, the
+//! extended attributes and the ACLs. The progress is logged every 10% of
+//! each archive.
+//!
+//! Like `--sysroot`, the architecture of the distribution is checked with
+//! the ELF header of the shell (see [`elf_arch`]) before anything is built,
+//! and must match every device to build. The variant can not be told from
+//! the archive, so a single variant has to be selected with `--variants`.
+//! Nothing is bootstrapped.
+use std::{
+	fs::{self, File},
+	io::{self, BufRead, BufReader, Read},
+	path::{Component, Path, PathBuf},
+	process::Command,
+	str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+	context::ImageContext,
+	device::DeviceArch,
+	runner,
+	sysroot::{elf_arch, PROBED_EXECUTABLES},
+};
+
+const OCI_LAYOUT: &str = "oci-layout";
+const OCI_INDEX: &str = "index.json";
+const OCI_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+pub(crate) const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+pub(crate) const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0];
+pub(crate) const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// A blob of an OCI image, referred to by its digest.
+#[derive(Deserialize)]
+struct OciDescriptor {
+	#[serde(rename = "mediaType")]
+	media_type: String,
+	digest: String,
+}
+
+#[derive(Deserialize)]
+struct OciIndex {
+	manifests: Vec<OciDescriptor>,
+}
+
+#[derive(Deserialize)]
+struct OciManifest {
+	layers: Vec<OciDescriptor>,
+}
+
+/// Reader calling `report` with the number of bytes read so far.
+struct ProgressReader<R, F> {
+	inner: R,
+	read: u64,
+	report: F,
+}
+
+impl<R: Read, F: FnMut(u64)> Read for ProgressReader<R, F> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let len = self.inner.read(buf)?;
+		self.read += len as u64;
+		(self.report)(self.read);
+		Ok(len)
+	}
+}
+
+/// Decompress the archive, the compression is told from the magic number.
+pub(crate) fn decompress<'a>(mut reader: impl BufRead + 'a) -> Result<Box<dyn Read + 'a>> {
+	let magic = reader.fill_buf()?;
+	Ok(if magic.starts_with(GZIP_MAGIC) {
+		Box::new(flate2::read::MultiGzDecoder::new(reader))
+	} else if magic.starts_with(XZ_MAGIC) {
+		Box::new(xz2::read::XzDecoder::new_multi_decoder(reader))
+	} else if magic.starts_with(ZSTD_MAGIC) {
+		Box::new(zstd::stream::read::Decoder::new(reader)?)
+	} else {
+		Box::new(reader)
+	})
+}
+
+/// Path of a member of the archive relative to the root, without the leading `./` or `/`.
+fn member_path(name: &str) -> &str {
+	let mut name = name;
+	while let Some(rest) = name.strip_prefix("./").or(name.strip_prefix('/')) {
+		name = rest;
+	}
+	name.trim_end_matches('/')
+}
+
+/// Walk the members of a tar archive.
+///
+/// `f` gets the path relative to the root and each member, and returns
+/// whether to go on.
+fn walk_tar<R: Read>(
+	reader: R,
+	mut f: impl FnMut(&str, &mut tar::Entry<'_, R>) -> Result<bool>,
+) -> Result<()> {
+	let mut archive = tar::Archive::new(reader);
+	for entry in archive.entries()? {
+		let mut entry = entry?;
+		let path = entry.path()?.to_string_lossy().into_owned();
+		if !f(member_path(&path), &mut entry)? {
+			break;
+		}
+	}
+	Ok(())
+}
+
+/// Make sure none of the existing components of `path` in the root is a
+/// symbolic link, which may point out of the root.
+fn check_no_symlinks(root: &Path, path: &Path) -> Result<()> {
+	let mut current = root.to_path_buf();
+	for component in path.components() {
+		current.push(component);
+		match current.symlink_metadata() {
+			Ok(metadata) if metadata.file_type().is_symlink() => bail!(
+				"/{} is a symbolic link, which is not followed",
+				current.strip_prefix(root)?.display()
+			),
+			Ok(_) => (),
+			// Nothing below is to be removed.
+			Err(_) => break,
+		}
+	}
+	Ok(())
+}
+
+/// Remove the files of the lower layers hidden by the whiteouts.
+fn apply_whiteouts(root: &Path, whiteouts: &[String]) -> Result<()> {
+	for whiteout in whiteouts {
+		let path = Path::new(whiteout);
+		if path.components()
+			.any(|c| !matches!(c, Component::Normal(_)))
+		{
+			bail!("Invalid whiteout '{}' in the OCI image", whiteout);
+		}
+		let parent = path.parent().unwrap_or(Path::new(""));
+		check_no_symlinks(root, parent)
+			.context(format!("Unable to apply the whiteout '{}'", whiteout))?;
+		let parent = root.join(parent);
+		let name = path
+			.file_name()
+			.context(format!("Invalid whiteout '{}'", whiteout))?
+			.to_string_lossy();
+		let hidden = if name == OPAQUE_WHITEOUT {
+			// Everything in the directory.
+			match fs::read_dir(&parent) {
+				Ok(entries) => entries
+					.map(|e| e.map(|e| e.path()))
+					.collect::<io::Result<Vec<_>>>()?,
+				Err(_) => Vec::new(),
+			}
+		} else {
+			vec![parent.join(name.trim_start_matches(WHITEOUT_PREFIX))]
+		};
+		for path in hidden {
+			let Ok(metadata) = path.symlink_metadata() else {
+				continue;
+			};
+			if metadata.is_dir() {
+				fs::remove_dir_all(&path)
+			} else {
+				fs::remove_file(&path)
+			}
+			.context(format!("Failed to remove {}", path.display()))?;
+		}
+	}
+	Ok(())
+}
+
+/// A system distribution archive specified with `--rootfs-source`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RootfsSource {
+	pub path: PathBuf,
+}
+
+impl FromStr for RootfsSource {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		if s.is_empty() {
+			bail!("Path to the rootfs source can not be empty");
+		}
+		Ok(Self { path: s.into() })
+	}
+}
+
+impl RootfsSource {
+	/// Whether the source is an OCI image layout directory.
+	pub fn is_oci(&self) -> bool {
+		self.path.join(OCI_LAYOUT).is_file()
+	}
+
+	/// Path of a blob of the OCI image.
+	fn oci_blob(&self, digest: &str) -> Result<PathBuf> {
+		let (algorithm, hex) = digest
+			.split_once(':')
+			.context(format!("Invalid digest '{}' in the OCI image", digest))?;
+		if !hex.chars().all(|c| c.is_ascii_hexdigit()) || algorithm.contains('/') {
+			bail!("Invalid digest '{}' in the OCI image", digest);
+		}
+		Ok(self.path.join("blobs").join(algorithm).join(hex))
+	}
+
+	/// The layers of the OCI image, from the bottom.
+	fn oci_layers(&self) -> Result<Vec<PathBuf>> {
+		let path = self.path.join(OCI_INDEX);
+		let index: OciIndex = serde_json::from_slice(&fs::read(&path)?)
+			.context(format!("Failed to parse {}", path.display()))?;
+		let manifest = match index.manifests.as_slice() {
+			[manifest] => manifest,
+			manifests => bail!(
+				"OCI image {} has {} manifests, only images of a single platform are supported",
+				self.path.display(),
+				manifests.len()
+			),
+		};
+		if manifest.media_type == OCI_INDEX_MEDIA_TYPE {
+			bail!(
+				"OCI image {} refers to another index, only images of a single platform are supported",
+				self.path.display()
+			);
+		}
+		let path = self.oci_blob(&manifest.digest)?;
+		let manifest: OciManifest = serde_json::from_slice(&fs::read(&path)?)
+			.context(format!("Failed to parse the manifest {}", path.display()))?;
+		manifest.layers
+			.iter()
+			.map(|layer| {
+				if !layer.media_type.contains("layer")
+					&& !layer.media_type.contains("rootfs.diff")
+				{
+					bail!(
+						"Unsupported layer {} of the media type {}",
+						layer.digest,
+						layer.media_type
+					);
+				}
+				self.oci_blob(&layer.digest)
+			})
+			.collect()
+	}
+
+	/// The archives to extract, in order.
+	pub fn archives(&self) -> Result<Vec<PathBuf>> {
+		if self.path.is_file() {
+			return Ok(vec![self.path.clone()]);
+		}
+		if !self.is_oci() {
+			bail!(
+				"Rootfs source {} is neither a tarball nor an OCI image layout directory.",
+				self.path.display()
+			);
+		}
+		let layers = self.oci_layers()?;
+		if let Some(missing) = layers.iter().find(|l| !l.is_file()) {
+			bail!("Layer {} of the OCI image is missing", missing.display());
+		}
+		Ok(layers)
+	}
+
+	/// Tell the architecture of the distribution, see [`crate::sysroot`].
+	pub fn probe_arch(&self) -> Result<DeviceArch> {
+		// The upper layers take precedence.
+		for archive in self.archives()?.iter().rev() {
+			let reader = decompress(BufReader::new(File::open(archive)?))?;
+			let mut header = None;
+			walk_tar(reader, |path, entry| {
+				// Regular files only, links may point to the host.
+				if !entry.header().entry_type().is_file()
+					|| !PROBED_EXECUTABLES.contains(&path)
+				{
+					return Ok(true);
+				}
+				let mut buf = Vec::with_capacity(64);
+				entry.take(64).read_to_end(&mut buf)?;
+				header = Some((path.to_owned(), buf));
+				Ok(false)
+			})
+			.context(format!("Failed to read {}", archive.display()))?;
+			if let Some((path, header)) = header {
+				return elf_arch(&header).context(format!(
+					"{} in {} is not an executable of any supported architecture",
+					path,
+					archive.display()
+				));
+			}
+		}
+		bail!(
+			"Unable to tell the architecture of {}, none of {} is found",
+			self.path.display(),
+			PROBED_EXECUTABLES.join(", ")
+		)
+	}
+
+	/// The whiteouts in the layer.
+	fn whiteouts(layer: &Path) -> Result<Vec<String>> {
+		let reader = decompress(BufReader::new(File::open(layer)?))?;
+		let mut whiteouts = Vec::new();
+		walk_tar(reader, |path, _| {
+			let name = path.rsplit('/').next().unwrap_or(path);
+			if name.starts_with(WHITEOUT_PREFIX) {
+				whiteouts.push(path.to_owned());
+			}
+			Ok(true)
+		})
+		.context(format!("Failed to read {}", layer.display()))?;
+		Ok(whiteouts)
+	}
+}
+
+impl ImageContext<'_> {
+	/// Extract the distribution into the root, see the [module documentation](self).
+	pub(crate) fn extract_rootfs(&self, source: &RootfsSource, root: &Path) -> Result<()> {
+		let archives = source.archives()?;
+		let oci = source.is_oci();
+		for (idx, archive) in archives.iter().enumerate() {
+			let name = if oci {
+				format!("layer {} of {}", idx + 1, archives.len())
+			} else {
+				archive.display().to_string()
+			};
+			self.info(format!("Extracting {} ...", name));
+			if oci {
+				apply_whiteouts(root, &RootfsSource::whiteouts(archive)?)?;
+			}
+			let size = fs::metadata(archive)?.len();
+			let mut last = 0;
+			let progress = ProgressReader {
+				inner: File::open(archive)?,
+				read: 0,
+				report: |read: u64| {
+					let percent = read * 100 / size.max(1);
+					if percent >= last + 10 {
+						last = percent;
+						self.info(format!(
+							"Extracted {}% of {}",
+							percent, name
+						));
+					}
+				},
+			};
+			let mut reader = decompress(BufReader::with_capacity(1048576, progress))?;
+			let mut cmd = Command::new("tar");
+			cmd.args([
+				"--extract",
+				"--file=-",
+				"--numeric-owner",
+				"--same-owner",
+				"--preserve-permissions",
+				"--xattrs",
+				"--xattrs-include=*",
+				"--acls",
+				"--exclude=.wh.*",
+			])
+			.arg("--directory")
+			.arg(root);
+			let status = runner::run_with_reader(&mut cmd, &mut reader)?;
+			if !status.success() {
+				bail!("Failed to extract {}: tar exited with {}", name, status);
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	/// A tar archive of the members, with their paths, types and contents.
+	fn archive(members: &[(&str, tar::EntryType, &[u8])]) -> Result<Vec<u8>> {
+		let mut builder = tar::Builder::new(Vec::new());
+		for (path, entry_type, data) in members {
+			let mut header = tar::Header::new_gnu();
+			header.set_entry_type(*entry_type);
+			header.set_size(data.len() as u64);
+			header.set_mode(0o755);
+			builder.append_data(&mut header, path, *data)?;
+		}
+		Ok(builder.into_inner()?)
+	}
+
+	#[test]
+	fn test_walk_tar() -> Result<()> {
+		let long = format!("usr/share/{}", "a".repeat(120));
+		let archive = archive(&[
+			("./usr/", tar::EntryType::Directory, b""),
+			(&long, tar::EntryType::Regular, b"long"),
+			("usr/bin/bash", tar::EntryType::Regular, b"\x7fELF"),
+			("usr/lib/.wh.libfoo.so", tar::EntryType::Regular, b""),
+		])?;
+		let mut members = Vec::new();
+		walk_tar(archive.as_slice(), |path, entry| {
+			let mut content = String::new();
+			entry.read_to_string(&mut content)?;
+			members.push((path.to_owned(), entry.header().entry_type(), content));
+			Ok(true)
+		})?;
+		assert_eq!(members.len(), 4);
+		assert_eq!(
+			members[0],
+			("usr".into(), tar::EntryType::Directory, "".into())
+		);
+		assert_eq!(members[1].0, long);
+		assert_eq!(members[1].2, "long");
+		assert_eq!(
+			members[2],
+			(
+				"usr/bin/bash".into(),
+				tar::EntryType::Regular,
+				"\x7fELF".into()
+			)
+		);
+		assert_eq!(members[3].0, "usr/lib/.wh.libfoo.so");
+		// Stops when asked to.
+		let mut count = 0;
+		walk_tar(archive.as_slice(), |_, _| {
+			count += 1;
+			Ok(false)
+		})?;
+		assert_eq!(count, 1);
+		// Truncated
+		let truncated = &archive[..600];
+		assert!(walk_tar(truncated, |_, _| Ok(true)).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_apply_whiteouts() -> Result<()> {
+		let root = TempDir::new("whiteouts")?;
+		fs::create_dir_all(root.join("etc/skel"))?;
+		fs::create_dir_all(root.join("usr/lib"))?;
+		fs::write(root.join("etc/skel/.bashrc"), "")?;
+		fs::write(root.join("usr/lib/libfoo.so"), "")?;
+		fs::write(root.join("usr/lib/libbar.so"), "")?;
+		apply_whiteouts(
+			&root,
+			&[
+				"usr/lib/.wh.libfoo.so".into(),
+				"etc/skel/.wh..wh..opq".into(),
+				"opt/.wh.missing".into(),
+			],
+		)?;
+		assert!(!root.join("usr/lib/libfoo.so").exists());
+		assert!(root.join("usr/lib/libbar.so").exists());
+		assert!(root.join("etc/skel").is_dir());
+		assert_eq!(fs::read_dir(root.join("etc/skel"))?.count(), 0);
+		assert!(apply_whiteouts(&root, &["../.wh.etc".into()]).is_err());
+		// Symbolic links are not followed out of the root.
+		let outside = TempDir::new("whiteouts-outside")?;
+		fs::write(outside.join("passwd"), "")?;
+		std::os::unix::fs::symlink(&*outside, root.join("etc/outside"))?;
+		assert!(apply_whiteouts(&root, &["etc/outside/.wh.passwd".into()]).is_err());
+		assert!(apply_whiteouts(&root, &["etc/outside/.wh..wh..opq".into()]).is_err());
+		assert!(outside.join("passwd").exists());
+		// The link itself can be removed.
+		apply_whiteouts(&root, &["etc/.wh.outside".into()])?;
+		assert!(!root.join("etc/outside").exists());
+		assert!(outside.join("passwd").exists());
+		Ok(())
+	}
+
+	#[test]
+	fn test_oci_layers() -> Result<()> {
+		let root = TempDir::new("oci")?;
+		let blobs = root.join("blobs/sha256");
+		fs::create_dir_all(&blobs)?;
+		let source = RootfsSource::from_str(&root.to_string_lossy())?;
+		assert!(!source.is_oci());
+		assert!(source.archives().is_err());
+		fs::write(root.join(OCI_LAYOUT), r#"{"imageLayoutVersion": "1.0.0"}"#)?;
+		fs::write(
+			root.join(OCI_INDEX),
+			r#"{"schemaVersion": 2, "manifests": [{"mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:aa", "size": 1}]}"#,
+		)?;
+		fs::write(
+			blobs.join("aa"),
+			r#"{"schemaVersion": 2, "layers": [
+				{"mediaType": "application/vnd.oci.image.layer.v1.tar+zstd", "digest": "sha256:bb", "size": 1},
+				{"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": "sha256:cc", "size": 1}
+			]}"#,
+		)?;
+		assert!(source.is_oci());
+		assert_eq!(source.oci_layers()?, [blobs.join("bb"), blobs.join("cc")]);
+		// The layers are missing.
+		assert!(source.archives().is_err());
+		assert!(source.oci_blob("sha256:../../etc").is_err());
+		Ok(())
+	}
+}
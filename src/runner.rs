@@ -0,0 +1,797 @@
+//! Runner for external commands.
+//!
+//! Every external command (mkfs, rsync, systemd-nspawn, etc.) is spawned
+//! through this module. With `--record DIR`, each invocation is saved into
+//! `DIR` as a numbered JSON file (`00001.json`, `00002.json`, ...) holding a
+//! [`CommandRecord`], with its standard output and standard error saved next
+//! to it (`00001.stdout`, `00001.stderr`). This makes it possible to see what
+//! exactly happened on a user's machine.
+//!
+//! With `--replay DIR`, no command is spawned at all. Instead, the recorded
+//! results are returned in order, as if the commands were run again. The
+//! replay fails as soon as a command or its arguments differ from the
+//! recording, so the build must use the same paths as the recorded one.
+//!
+//! The probes run in process which read back what the commands produced,
+//! e.g. the filesystem UUIDs probed with libblkid, are recorded and replayed
+//! like commands as well, see [`probe`].
+//!
+//! The mode applies to the whole process. The tests set it for their own
+//! thread instead, so that they can run in parallel, and map the recorded
+//! paths to their temporary directories.
+//!
+//! If several jobs are running, the output of the commands is forwarded line
+//! by line with the prefix of the job active on the current thread, see
+//! [`crate::joblog`]. Otherwise the commands inherit the terminal. The
+//! forwarded output is also appended to the log file of the job.
+//!
+//! The output of the commands is only shown if the debug output is enabled
+//! (`-v`), otherwise it is captured and discarded. If such a command fails,
+//! the last lines of its output are shown as a warning.
+//!
+//! Secrets registered with [`register_secret`] (e.g. the password of the
+//! built-in user) are redacted from the recordings.
+use std::{
+	cell::RefCell,
+	collections::BTreeMap,
+	fs::{create_dir_all, File},
+	io::{self, Read, Write},
+	os::unix::process::ExitStatusExt,
+	path::{Path, PathBuf},
+	process::{ChildStdin, Command, ExitStatus, Output, Stdio},
+	sync::Mutex,
+	thread::{self, JoinHandle},
+	time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, log_enabled, warn, Level};
+use serde::{Deserialize, Serialize};
+
+use crate::joblog::{current_log_writer, current_prefix, prefix_output, LogFile, PrefixWriter};
+
+/// The replacement of registered secrets in the recordings.
+pub(crate) const REDACTED: &str = "<redacted>";
+/// Environment variables which are always recorded if they are set,
+/// in addition to the ones explicitly set for the command.
+const RECORDED_ENVS: &[&str] = &["PATH", "LANG", "LC_ALL", "TERM"];
+/// Lines of the hidden output shown if the command fails.
+const FAILURE_TAIL_LINES: usize = 20;
+
+/// How the external commands are handled.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum RunnerMode {
+	/// Just run the commands.
+	#[default]
+	Normal,
+	/// Run the commands, and record them into the directory.
+	Record(PathBuf),
+	/// Do not run anything, return the results recorded in the directory.
+	Replay(PathBuf),
+}
+
+#[derive(Clone)]
+struct Runner {
+	mode: RunnerMode,
+	/// Sequence number of the last invocation.
+	seq: usize,
+	build_id: Option<String>,
+	/// Recorded paths and the current ones replacing them, see [`map_path`].
+	paths: Vec<(String, String)>,
+}
+
+impl Runner {
+	const fn new(mode: RunnerMode) -> Self {
+		Self {
+			mode,
+			seq: 0,
+			build_id: None,
+			paths: Vec::new(),
+		}
+	}
+}
+
+static RUNNER: Mutex<Runner> = Mutex::new(Runner::new(RunnerMode::Normal));
+thread_local! {
+	/// The runner set by [`scoped`], used instead of [`RUNNER`] on this thread.
+	static SCOPED: RefCell<Option<Runner>> = const { RefCell::new(None) };
+}
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// A recorded invocation of an external command.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CommandRecord {
+	/// Sequence number of this invocation, starting from 1.
+	pub seq: usize,
+	/// Build ID of the mkrawimg invocation which ran this command.
+	#[serde(default)]
+	pub build_id: Option<String>,
+	pub program: String,
+	pub args: Vec<String>,
+	/// Environment variables explicitly set for the command, plus the
+	/// ones listed in [`RECORDED_ENVS`].
+	pub env: BTreeMap<String, String>,
+	/// Working directory, if it is not inherited.
+	pub cwd: Option<PathBuf>,
+	/// Exit code, `None` if the command is terminated by a signal.
+	pub code: Option<i32>,
+	/// The signal which terminated the command.
+	pub signal: Option<i32>,
+	/// Name of the file containing the standard output, if there is any.
+	pub stdout: Option<String>,
+	/// Name of the file containing the standard error, if there is any.
+	pub stderr: Option<String>,
+	pub duration_ms: u64,
+}
+
+/// Run `f` with the runner of the current thread.
+fn with_runner<T>(f: impl FnOnce(&mut Runner) -> T) -> T {
+	SCOPED.with(|scoped| match scoped.borrow_mut().as_mut() {
+		Some(runner) => f(runner),
+		None => f(&mut RUNNER.lock().unwrap()),
+	})
+}
+
+fn check_mode(mode: &RunnerMode) -> Result<()> {
+	match mode {
+		RunnerMode::Record(dir) => create_dir_all(dir).context(format!(
+			"Failed to create the recording directory '{}'",
+			dir.display()
+		))?,
+		RunnerMode::Replay(dir) => {
+			if !dir.is_dir() {
+				bail!("Recording directory '{}' does not exist.", dir.display());
+			}
+		}
+		RunnerMode::Normal => (),
+	}
+	Ok(())
+}
+
+/// Set how the external commands are handled.
+pub fn set_mode(mode: RunnerMode) -> Result<()> {
+	check_mode(&mode)?;
+	let mut runner = RUNNER.lock().unwrap();
+	runner.mode = mode;
+	runner.seq = 0;
+	Ok(())
+}
+
+/// Restores the runner of the process on the current thread when dropped, see [`scoped`].
+#[cfg(test)]
+pub struct ScopedRunner {
+	/// The runner belongs to the thread which created it.
+	_thread: std::marker::PhantomData<*const ()>,
+}
+
+#[cfg(test)]
+impl Drop for ScopedRunner {
+	fn drop(&mut self) {
+		SCOPED.with(|scoped| scoped.borrow_mut().take());
+	}
+}
+
+/// Handle the external commands run on the current thread with `mode`,
+/// until the returned guard is dropped. Other threads are not affected.
+#[cfg(test)]
+pub fn scoped(mode: RunnerMode) -> Result<ScopedRunner> {
+	check_mode(&mode)?;
+	SCOPED.with(|scoped| {
+		let mut scoped = scoped.borrow_mut();
+		if scoped.is_some() {
+			bail!("A runner is already set for this thread.");
+		}
+		*scoped = Some(Runner::new(mode));
+		Ok(())
+	})?;
+	Ok(ScopedRunner {
+		_thread: std::marker::PhantomData,
+	})
+}
+
+/// Replace the path `recorded` with `current` in the recorded arguments
+/// before comparing them with the ones being run, when replaying.
+#[cfg(test)]
+pub fn map_path<P: AsRef<Path>, Q: AsRef<Path>>(recorded: P, current: Q) {
+	let recorded = recorded.as_ref().to_string_lossy().to_string();
+	let current = current.as_ref().to_string_lossy().to_string();
+	with_runner(|runner| runner.paths.push((recorded, current)));
+}
+
+/// Apply the mapped paths to a recorded argument.
+fn map_recorded_arg(arg: &str, paths: &[(String, String)]) -> String {
+	for (recorded, current) in paths {
+		if let Some(rest) = arg.strip_prefix(recorded.as_str()) {
+			if rest.is_empty() || rest.starts_with('/') {
+				return format!("{}{}", current, rest);
+			}
+		}
+	}
+	arg.to_owned()
+}
+
+/// Set the build ID to be saved in the recordings.
+pub fn set_build_id<S: AsRef<str>>(build_id: S) {
+	let build_id = build_id.as_ref().to_owned();
+	with_runner(|runner| runner.build_id = Some(build_id));
+}
+
+/// Register a secret to be redacted from the recordings.
+pub fn register_secret<S: AsRef<str>>(secret: S) {
+	let secret = secret.as_ref();
+	if secret.is_empty() {
+		return;
+	}
+	let mut secrets = SECRETS.lock().unwrap();
+	if !secrets.iter().any(|x| x == secret) {
+		secrets.push(secret.to_owned());
+	}
+}
+
+/// Replace all registered secrets in the string.
+pub fn redact<S: AsRef<str>>(s: S) -> String {
+	let mut s = s.as_ref().to_owned();
+	for secret in SECRETS.lock().unwrap().iter() {
+		s = s.replace(secret.as_str(), REDACTED);
+	}
+	s
+}
+
+/// Replace all registered secrets in the raw output, which might not be UTF-8.
+fn redact_bytes(data: &[u8]) -> Vec<u8> {
+	let mut data = data.to_vec();
+	for secret in SECRETS.lock().unwrap().iter() {
+		let needle = secret.as_bytes();
+		let mut result = Vec::with_capacity(data.len());
+		let mut i = 0;
+		while i < data.len() {
+			if data[i..].starts_with(needle) {
+				result.extend_from_slice(REDACTED.as_bytes());
+				i += needle.len();
+			} else {
+				result.push(data[i]);
+				i += 1;
+			}
+		}
+		data = result;
+	}
+	data
+}
+
+/// Run the command with the standard output and error inherited, and wait
+/// for it to finish.
+pub fn run(cmd: &mut Command) -> Result<ExitStatus> {
+	Ok(invoke(cmd, true, None)?.status)
+}
+
+/// Run the command and collect all of its output.
+pub fn output(cmd: &mut Command) -> Result<Output> {
+	invoke(cmd, false, None)
+}
+
+/// Run the command with the input fed into its standard input.
+pub fn run_with_input(cmd: &mut Command, mut input: &[u8]) -> Result<ExitStatus> {
+	Ok(invoke(cmd, true, Some(&mut input))?.status)
+}
+
+/// Run the command with everything read from the reader streamed into its standard input.
+///
+/// Nothing is read while replaying.
+pub fn run_with_reader(cmd: &mut Command, reader: &mut dyn Read) -> Result<ExitStatus> {
+	Ok(invoke(cmd, true, Some(reader))?.status)
+}
+
+/// Run an in-process probe (e.g. of libblkid) in place of a command, so
+/// that it is recorded and replayed like one. `program` and `args` name it
+/// in the recording, what the probe returns is its standard output, and
+/// its error is the standard error of a failed command.
+pub fn probe(program: &str, args: &[&str], f: impl FnOnce() -> Result<Vec<u8>>) -> Result<Vec<u8>> {
+	let mut cmd = Command::new(program);
+	cmd.args(args);
+	let runner = next_invocation();
+	match &runner.mode {
+		RunnerMode::Normal => f(),
+		RunnerMode::Record(dir) => {
+			let start = Instant::now();
+			let result = f();
+			let out = match &result {
+				Ok(stdout) => Output {
+					status: ExitStatus::from_raw(0),
+					stdout: stdout.clone(),
+					stderr: Vec::new(),
+				},
+				Err(e) => Output {
+					status: ExitStatus::from_raw(1 << 8),
+					stdout: Vec::new(),
+					stderr: format!("{:#}", e).into_bytes(),
+				},
+			};
+			save_record(
+				dir,
+				runner.seq,
+				runner.build_id,
+				&cmd,
+				&out,
+				start.elapsed(),
+			)
+			.context("Failed to record the probe")?;
+			result
+		}
+		RunnerMode::Replay(dir) => {
+			let out = replay(dir, runner.seq, &runner.paths, &cmd, false)?;
+			if !out.status.success() {
+				bail!("{}", String::from_utf8_lossy(&out.stderr));
+			}
+			Ok(out.stdout)
+		}
+	}
+}
+
+/// Make sure every recorded command has been replayed.
+pub fn finish() -> Result<()> {
+	with_runner(|runner| {
+		if let RunnerMode::Replay(dir) = &runner.mode {
+			let next = runner.seq + 1;
+			if record_path(dir, next).exists() {
+				bail!(
+					"Replay finished early: command #{} and the following ones were never run.",
+					next
+				);
+			}
+		}
+		Ok(())
+	})
+}
+
+fn record_path(dir: &Path, seq: usize) -> PathBuf {
+	dir.join(format!("{:05}.json", seq))
+}
+
+/// The runner as of this invocation.
+fn next_invocation() -> Runner {
+	with_runner(|runner| {
+		if runner.mode != RunnerMode::Normal {
+			runner.seq += 1;
+		}
+		runner.clone()
+	})
+}
+
+fn invoke(cmd: &mut Command, inherit: bool, input: Option<&mut dyn Read>) -> Result<Output> {
+	let runner = next_invocation();
+	match &runner.mode {
+		RunnerMode::Normal => spawn(cmd, inherit, false, input),
+		RunnerMode::Record(dir) => {
+			let start = Instant::now();
+			let out = spawn(cmd, inherit, true, input)?;
+			save_record(dir, runner.seq, runner.build_id, cmd, &out, start.elapsed())
+				.context("Failed to record the command")?;
+			Ok(out)
+		}
+		RunnerMode::Replay(dir) => replay(dir, runner.seq, &runner.paths, cmd, inherit),
+	}
+}
+
+/// Feed the input into the standard input, the pipe is closed once it is dropped.
+fn feed(stdin: Option<ChildStdin>, input: Option<&mut dyn Read>) -> io::Result<()> {
+	if let (Some(mut stdin), Some(input)) = (stdin, input) {
+		io::copy(input, &mut stdin)?;
+	}
+	Ok(())
+}
+
+fn spawn(
+	cmd: &mut Command,
+	inherit: bool,
+	capture: bool,
+	input: Option<&mut dyn Read>,
+) -> Result<Output> {
+	if input.is_some() {
+		cmd.stdin(Stdio::piped());
+	}
+	// The output has to be forwarded to insert the prefix of the job.
+	let prefix = current_prefix().filter(|_| prefix_output());
+	let show = log_enabled!(Level::Debug);
+	if !inherit || capture || prefix.is_some() || !show {
+		cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+	}
+	debug!("Running command {:?}", cmd);
+	let mut child = cmd
+		.spawn()
+		.context(format!("Failed to run {:?}", cmd.get_program()))?;
+	let stdin = match input {
+		Some(_) => Some(child.stdin.take().context("Failed to open stdin")?),
+		None => None,
+	};
+	if !inherit {
+		feed(stdin, input)?;
+		return Ok(child.wait_with_output()?);
+	}
+	if !capture && prefix.is_none() && show {
+		let fed = feed(stdin, input);
+		let status = child.wait()?;
+		// The command may stop reading because it failed.
+		if status.success() {
+			fed?;
+		}
+		return Ok(Output {
+			status,
+			stdout: Vec::new(),
+			stderr: Vec::new(),
+		});
+	}
+	// Show the output to the user while it is being recorded.
+	let stdout = child.stdout.take().map(|r| {
+		tee(
+			r,
+			output_writer(io::stdout(), prefix.clone(), show, current_log_writer()),
+		)
+	});
+	let stderr = child.stderr.take().map(|r| {
+		tee(
+			r,
+			output_writer(io::stderr(), prefix, show, current_log_writer()),
+		)
+	});
+	// Fed after the output is forwarded, so a long input does not fill up the pipes.
+	let fed = feed(stdin, input);
+	let status = child.wait()?;
+	if status.success() {
+		fed?;
+	}
+	let output = Output {
+		status,
+		stdout: join(stdout)?,
+		stderr: join(stderr)?,
+	};
+	if !show && !status.success() {
+		warn_failure_tail(cmd, &output);
+	}
+	Ok(output)
+}
+
+/// Where the output of a command goes: discarded unless it is shown, and
+/// appended to the log file of the job.
+fn output_writer<W>(
+	inner: W,
+	prefix: Option<String>,
+	show: bool,
+	log: Option<PrefixWriter<LogFile>>,
+) -> Box<dyn Write + Send>
+where
+	W: Write + Send + 'static,
+{
+	let shown: Box<dyn Write + Send> = if show {
+		Box::new(PrefixWriter::new(inner, prefix.unwrap_or_default()))
+	} else {
+		Box::new(io::sink())
+	};
+	match log {
+		Some(log) => Box::new(LoggedWriter { inner: shown, log }),
+		None => shown,
+	}
+}
+
+/// Writer copying everything into the log file of the job as well.
+struct LoggedWriter {
+	inner: Box<dyn Write + Send>,
+	log: PrefixWriter<LogFile>,
+}
+
+impl Write for LoggedWriter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.inner.write_all(buf)?;
+		self.log.write_all(buf)?;
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()?;
+		self.log.flush()
+	}
+}
+
+/// Show the last lines of the hidden output of a failed command.
+fn warn_failure_tail(cmd: &Command, output: &Output) {
+	let data = if output.stderr.is_empty() {
+		&output.stdout
+	} else {
+		&output.stderr
+	};
+	let text = String::from_utf8_lossy(data);
+	let lines = text.lines().collect::<Vec<_>>();
+	if lines.is_empty() {
+		return;
+	}
+	warn!(
+		"{:?} failed ({}), the last lines of its output:\n{}",
+		cmd.get_program(),
+		output.status,
+		lines[lines.len().saturating_sub(FAILURE_TAIL_LINES)..].join("\n")
+	);
+}
+
+fn tee<R, W>(mut reader: R, mut writer: W) -> JoinHandle<io::Result<Vec<u8>>>
+where
+	R: Read + Send + 'static,
+	W: Write + Send + 'static,
+{
+	thread::spawn(move || forward(&mut reader, &mut writer))
+}
+
+/// Copy everything from the reader to the writer, and return what has been read.
+fn forward<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<Vec<u8>> {
+	let mut buf = [0u8; 8192];
+	let mut data = Vec::new();
+	loop {
+		let len = reader.read(&mut buf)?;
+		if len == 0 {
+			break;
+		}
+		writer.write_all(&buf[..len])?;
+		writer.flush()?;
+		data.extend_from_slice(&buf[..len]);
+	}
+	Ok(data)
+}
+
+fn join(handle: Option<JoinHandle<io::Result<Vec<u8>>>>) -> Result<Vec<u8>> {
+	match handle {
+		Some(h) => Ok(h.join().map_err(|_| anyhow!("Output reader panicked"))??),
+		None => Ok(Vec::new()),
+	}
+}
+
+/// Get the (redacted) program, arguments, environment and working directory.
+fn describe(
+	cmd: &Command,
+) -> (
+	String,
+	Vec<String>,
+	BTreeMap<String, String>,
+	Option<PathBuf>,
+) {
+	let program = redact(cmd.get_program().to_string_lossy());
+	let args = cmd
+		.get_args()
+		.map(|x| redact(x.to_string_lossy()))
+		.collect();
+	let mut env = BTreeMap::new();
+	for name in RECORDED_ENVS {
+		if let Ok(value) = std::env::var(name) {
+			env.insert(name.to_string(), redact(value));
+		}
+	}
+	for (k, v) in cmd.get_envs() {
+		let k = k.to_string_lossy().to_string();
+		match v {
+			Some(v) => env.insert(k, redact(v.to_string_lossy())),
+			None => env.remove(&k),
+		};
+	}
+	let cwd = cmd.get_current_dir().map(|x| x.to_path_buf());
+	(program, args, env, cwd)
+}
+
+fn save_record(
+	dir: &Path,
+	seq: usize,
+	build_id: Option<String>,
+	cmd: &Command,
+	out: &Output,
+	duration: Duration,
+) -> Result<()> {
+	let (program, args, env, cwd) = describe(cmd);
+	let save_blob = |suffix: &str, data: &[u8]| -> Result<Option<String>> {
+		if data.is_empty() {
+			return Ok(None);
+		}
+		let name = format!("{:05}.{}", seq, suffix);
+		std::fs::write(dir.join(&name), redact_bytes(data))?;
+		Ok(Some(name))
+	};
+	let record = CommandRecord {
+		seq,
+		build_id,
+		program,
+		args,
+		env,
+		cwd,
+		code: out.status.code(),
+		signal: out.status.signal(),
+		stdout: save_blob("stdout", &out.stdout)?,
+		stderr: save_blob("stderr", &out.stderr)?,
+		duration_ms: duration.as_millis() as u64,
+	};
+	let file = File::create(record_path(dir, seq))?;
+	serde_json::to_writer_pretty(file, &record)?;
+	Ok(())
+}
+
+fn replay(
+	dir: &Path,
+	seq: usize,
+	paths: &[(String, String)],
+	cmd: &Command,
+	inherit: bool,
+) -> Result<Output> {
+	let path = record_path(dir, seq);
+	let content = std::fs::read_to_string(&path).context(format!(
+		"Command #{} ({:?}) was not recorded in '{}'",
+		seq,
+		cmd.get_program(),
+		dir.display()
+	))?;
+	let record: CommandRecord = serde_json::from_str(&content)
+		.context(format!("Failed to parse '{}'", path.display()))?;
+	let (program, args, ..) = describe(cmd);
+	if record.program != program {
+		bail!(
+			"Replay diverged at command #{}: '{}' was recorded, but '{}' is being run.",
+			seq,
+			record.program,
+			program
+		);
+	}
+	let recorded = record
+		.args
+		.iter()
+		.map(|arg| map_recorded_arg(arg, paths))
+		.collect::<Vec<_>>();
+	if recorded != args {
+		bail!(
+			"Replay diverged at command #{}: the arguments differ from the recording.\nRecorded: {:?}\nCurrent:  {:?}",
+			seq,
+			recorded,
+			args
+		);
+	}
+	debug!(
+		"Replaying command #{} of build {:?}: {:?} (cwd: {:?}, env: {:?}, took {} ms)",
+		record.seq, record.build_id, cmd, record.cwd, record.env, record.duration_ms
+	);
+	let read_blob = |name: &Option<String>| -> Result<Vec<u8>> {
+		match name {
+			Some(name) => Ok(std::fs::read(dir.join(name))?),
+			None => Ok(Vec::new()),
+		}
+	};
+	let stdout = read_blob(&record.stdout)?;
+	let stderr = read_blob(&record.stderr)?;
+	if inherit && log_enabled!(Level::Debug) {
+		let prefix = current_prefix().unwrap_or_default();
+		PrefixWriter::new(io::stdout(), &prefix).write_all(&stdout)?;
+		PrefixWriter::new(io::stderr(), &prefix).write_all(&stderr)?;
+	}
+	let status = match (record.code, record.signal) {
+		(Some(code), _) => ExitStatus::from_raw(code << 8),
+		(None, Some(signal)) => ExitStatus::from_raw(signal),
+		(None, None) => ExitStatus::from_raw(0),
+	};
+	Ok(Output {
+		status,
+		stdout,
+		stderr,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::process::{Command, Stdio};
+
+	use super::{
+		finish, forward, map_path, output, probe, redact, redact_bytes, register_secret,
+		run, scoped, PrefixWriter, RunnerMode,
+	};
+	use crate::{context::ImageVariant, joblog::JobLogger, testutil::TempDir};
+
+	#[test]
+	fn test_redact() {
+		register_secret("hunter2");
+		assert_eq!(redact("aosc:hunter2"), "aosc:<redacted>");
+		assert_eq!(
+			redact_bytes(b"\xffhunter2\xfe"),
+			b"\xff<redacted>\xfe".to_vec()
+		);
+	}
+
+	#[test]
+	fn test_replay() -> anyhow::Result<()> {
+		let dir = TempDir::new("replay-divergence")?;
+		std::fs::write(
+			dir.join("00001.json"),
+			r#"{"seq": 1, "program": "ls", "args": ["/tmp/recorded/root"], "env": {}, "cwd": null,
+"code": 2, "signal": null, "stdout": "00001.stdout", "stderr": null, "duration_ms": 1}"#,
+		)?;
+		std::fs::write(dir.join("00001.stdout"), "recorded\n")?;
+		let ls = |path: &str| {
+			let mut cmd = Command::new("ls");
+			cmd.arg(path);
+			output(&mut cmd)
+		};
+		{
+			let _runner = scoped(RunnerMode::Replay(dir.to_path_buf()))?;
+			assert!(scoped(RunnerMode::Normal).is_err());
+			assert!(finish().is_err());
+			assert!(ls("/tmp/current/root").is_err());
+		}
+		let _runner = scoped(RunnerMode::Replay(dir.to_path_buf()))?;
+		map_path("/tmp/recorded", "/tmp/current");
+		let out = ls("/tmp/current/root")?;
+		assert_eq!(out.status.code(), Some(2));
+		assert_eq!(out.stdout, b"recorded\n");
+		finish()?;
+		// Nothing more is recorded.
+		assert!(ls("/tmp/current/root").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_replay_probe() -> anyhow::Result<()> {
+		let dir = TempDir::new("replay-probe")?;
+		{
+			let _runner = scoped(RunnerMode::Record(dir.to_path_buf()))?;
+			let found = probe("libblkid", &["/dev/loop0p1"], || {
+				Ok(b"TYPE=vfat\n".to_vec())
+			})?;
+			assert_eq!(found, b"TYPE=vfat\n");
+			let failed = probe("libblkid", &["/dev/loop0p2"], || {
+				anyhow::bail!("No filesystem")
+			});
+			assert!(failed.is_err());
+		}
+		let _runner = scoped(RunnerMode::Replay(dir.to_path_buf()))?;
+		let found = probe("libblkid", &["/dev/loop0p1"], || unreachable!())?;
+		assert_eq!(found, b"TYPE=vfat\n");
+		let err = probe("libblkid", &["/dev/loop0p2"], || unreachable!()).unwrap_err();
+		assert_eq!(err.to_string(), "No filesystem");
+		finish()?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_run_logged() -> anyhow::Result<()> {
+		let dir = TempDir::new("runner-log")?;
+		let log = dir.join("rpi-5b.img.log");
+		let job = JobLogger::new("rpi-5b", &ImageVariant::Desktop);
+		job.open_log_file(&log)?;
+		{
+			let _job = job.enter();
+			job.info("Partitioning ...");
+			let status =
+				run(Command::new("sh").args(["-c", "echo one; echo two >&2"]))?;
+			assert!(status.success());
+			// Collected without being forwarded.
+			let out = output(Command::new("echo").arg("three"))?;
+			assert_eq!(out.stdout, b"three\n");
+		}
+		// Not logged outside of the job.
+		run(Command::new("echo").arg("four"))?;
+		let content = std::fs::read_to_string(&log)?;
+		assert!(content.starts_with("[rpi-5b/desktop] Partitioning ...\n"));
+		assert!(content.contains("[rpi-5b/desktop] one\n"));
+		assert!(content.contains("[rpi-5b/desktop] two\n"));
+		assert!(!content.contains("three") && !content.contains("four"));
+		Ok(())
+	}
+
+	#[test]
+	fn test_forward_prefixed() -> anyhow::Result<()> {
+		let job = JobLogger::new("rpi-5b", &ImageVariant::Desktop);
+		let mut child = Command::new("sh")
+			.args(["-c", "echo one; echo two"])
+			.stdout(Stdio::piped())
+			.spawn()?;
+		let mut out = Vec::new();
+		let mut writer = PrefixWriter::new(&mut out, job.prefix());
+		let data = forward(child.stdout.as_mut().unwrap(), &mut writer)?;
+		assert!(child.wait()?.success());
+		// The recorded output is not prefixed.
+		assert_eq!(data, b"one\ntwo\n");
+		assert_eq!(
+			String::from_utf8(out)?,
+			"[rpi-5b/desktop] one\n[rpi-5b/desktop] two\n"
+		);
+		Ok(())
+	}
+}
@@ -0,0 +1,346 @@
+//! Installing systemd-boot on the EFI system partition.
+//!
+//! A `systemd_boot` bootloader step installs systemd-boot to the EFI system
+//! partition, and writes a [boot loader entry] for every kernel installed in
+//! the target:
+//!
+//! ```toml
+//! [[bootloader]]
+//! type = "systemd_boot"
+//! # Optional, the number of the EFI system partition, defaults to the
+//! # partition with type = "esp".
+//! esp = 1
+//! # Optional, seconds to show the menu.
+//! timeout = 3
+//! # Optional, the entry booted by default, globs are allowed. Defaults to
+//! # the entry of the selected kernel.
+//! default = "aosc-*"
+//! # Optional, the title of the entries, and the prefix of their names.
+//! title = "AOSC OS"
+//! entry_id = "aosc"
+//! # Optional, the kernel command line, defaults to {KERNEL_CMDLINE}.
+//! append = "root=PARTUUID={ROOT_PARTUUID} rw"
+//! ```
+//!
+//! The EFI binaries are installed with `bootctl install --root` on the host,
+//! which never touches the EFI variables of the host. If `bootctl` is not
+//! installed on the host, or fails to operate on the target offline, the
+//! binaries shipped by the target in `/usr/lib/systemd/boot/efi` are copied
+//! to `/EFI/systemd` and the removable media path (e.g.
+//! `/EFI/BOOT/BOOTAA64.EFI`) of the ESP instead.
+//!
+//! The kernels are found in `/boot` and `/usr/lib/modules`, see
+//! [`crate::kernel`]. The ESP is FAT, which has no symbolic links, so the
+//! image, the initramfs (unless `initrdless`) and the device tree blob of
+//! `dtb` of each kernel are copied into `/<entry_id>/<version>` of the ESP.
+//! The entries are `/loader/entries/<entry_id>-<version>.conf`.
+//!
+//! The placeholders in `append` are substituted as in the `config` steps,
+//! e.g. `{ROOT_PARTUUID}`, see [`crate::cmdline`].
+//!
+//! [boot loader entry]: https://uapi-group.org/specifications/specs/boot_loader_specification/
+use std::{
+	fs,
+	path::{Path, PathBuf},
+	process::Command,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+	cmdline::substitute,
+	context::ImageContext,
+	device::{DeviceArch, DeviceSpec, PartitionMapData},
+	dtb::{dtb_dirs, find_dtb},
+	grub::is_plain,
+	kernel::{discover_kernels, KernelInfo},
+	utils::{cmd_run_check_status, find_program},
+};
+
+/// Directory of the EFI binaries of systemd-boot, within the target.
+const EFI_BINARIES_DIR: &str = "usr/lib/systemd/boot/efi";
+/// Prefix of the names of the entries, by default.
+const DEFAULT_ENTRY_ID: &str = "linux";
+/// Title of the entries, by default.
+const DEFAULT_TITLE: &str = "Linux";
+
+/// Install systemd-boot to the EFI system partition, see the [module documentation](self).
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct SystemdBootSpec {
+	/// Number of the EFI system partition.
+	pub esp: Option<u32>,
+	/// Seconds to show the menu.
+	pub timeout: Option<u32>,
+	/// The entry booted by default.
+	pub default: Option<String>,
+	/// Title of the entries.
+	pub title: Option<String>,
+	/// Prefix of the names of the entries.
+	pub entry_id: Option<String>,
+	/// The kernel command line.
+	pub append: Option<String>,
+}
+
+/// The suffix of the EFI binaries for the architecture, e.g. `aa64`.
+fn efi_arch(arch: &DeviceArch) -> Option<&'static str> {
+	match arch {
+		DeviceArch::Amd64 => Some("x64"),
+		DeviceArch::I486 => Some("ia32"),
+		DeviceArch::Arm64 => Some("aa64"),
+		DeviceArch::Armv7hf => Some("arm"),
+		DeviceArch::Riscv64 => Some("riscv64"),
+		DeviceArch::LoongArch64 => Some("loongarch64"),
+		_ => None,
+	}
+}
+
+/// Render `loader.conf`.
+fn loader_conf(timeout: Option<u32>, default: &str) -> String {
+	let mut content = String::new();
+	if let Some(timeout) = timeout {
+		content += &format!("timeout {}\n", timeout);
+	}
+	content += &format!("default {}\n", default);
+	content
+}
+
+/// A boot loader entry, the paths are within the ESP.
+struct Entry<'a> {
+	title: &'a str,
+	version: &'a str,
+	linux: PathBuf,
+	initrd: Option<PathBuf>,
+	devicetree: Option<PathBuf>,
+	options: &'a str,
+}
+
+impl Entry<'_> {
+	/// Render the entry.
+	fn render(&self) -> String {
+		let mut content = format!(
+			"title {}\nversion {}\nlinux {}\n",
+			self.title,
+			self.version,
+			self.linux.display()
+		);
+		if let Some(initrd) = &self.initrd {
+			content += &format!("initrd {}\n", initrd.display());
+		}
+		if let Some(devicetree) = &self.devicetree {
+			content += &format!("devicetree {}\n", devicetree.display());
+		}
+		content += &format!("options {}\n", self.options);
+		content
+	}
+}
+
+impl SystemdBootSpec {
+	fn entry_id(&self) -> &str {
+		self.entry_id.as_deref().unwrap_or(DEFAULT_ENTRY_ID)
+	}
+
+	/// Name of the entry of the kernel.
+	fn entry_name(&self, kernel: &KernelInfo) -> String {
+		format!("{}-{}.conf", self.entry_id(), kernel.version)
+	}
+}
+
+impl DeviceSpec {
+	/// Make sure systemd-boot can be installed, see the [module documentation](crate::sdboot).
+	pub(crate) fn check_systemd_boot(&self, spec: &SystemdBootSpec) -> Result<()> {
+		self.efi_partition(spec.esp, "systemd_boot")?;
+		if efi_arch(&self.arch).is_none() {
+			bail!("systemd-boot is not available for {:?}", self.arch);
+		}
+		if !is_plain(spec.entry_id()) {
+			bail!(
+				"Invalid entry_id '{}', it may only contain letters, digits, '-', '_' and '.'",
+				spec.entry_id()
+			);
+		}
+		if spec.default.as_deref().is_some_and(str::is_empty) {
+			bail!("The default entry of systemd-boot can not be empty");
+		}
+		substitute(
+			spec.append.as_deref().unwrap_or("{KERNEL_CMDLINE}"),
+			&self.template_vars(None)?,
+		)
+		.context("systemd-boot: invalid append line")?;
+		Ok(())
+	}
+}
+
+/// Copy the file within the target into `dir` of the ESP, returns its path within the ESP.
+fn copy_to_esp(root: &Path, esp_dir: &Path, src: &Path, dir: &Path) -> Result<PathBuf> {
+	let name = src
+		.file_name()
+		.context(format!("Invalid path {}", src.display()))?;
+	let dst = dir.join(name);
+	// Symbolic links are followed, FAT has none.
+	fs::copy(
+		root.join(src.strip_prefix("/").unwrap_or(src)),
+		esp_dir.join(dst.strip_prefix("/")?),
+	)
+	.context(format!("Failed to copy {} into the ESP", src.display()))?;
+	Ok(dst)
+}
+
+impl ImageContext<'_> {
+	/// Install the EFI binaries of systemd-boot into the ESP mounted at `esp_mp` within the target.
+	fn install_systemd_boot_binaries(
+		&self,
+		root: &Path,
+		esp_mp: &str,
+		arch: &str,
+	) -> Result<()> {
+		if find_program("bootctl").is_some() {
+			let mut cmd = Command::new("bootctl");
+			cmd.arg(format!("--root={}", root.display()))
+				.arg(format!("--esp-path={}", esp_mp))
+				.args(["--no-variables", "install"]);
+			match cmd_run_check_status(&mut cmd) {
+				Ok(()) => return Ok(()),
+				Err(e) => self.warn(format!(
+					"bootctl failed to install systemd-boot, copying the EFI binaries instead: {:#}",
+					e
+				)),
+			}
+		}
+		let name = format!("systemd-boot{}.efi", arch);
+		let src = root.join(EFI_BINARIES_DIR).join(&name);
+		if !src.is_file() {
+			bail!(
+				"systemd-boot is not installed in the target, /{}/{} is not found",
+				EFI_BINARIES_DIR,
+				name
+			);
+		}
+		let esp_dir = root.join(esp_mp.trim_start_matches('/'));
+		for (dir, dst) in [
+			("EFI/systemd", name.clone()),
+			("EFI/BOOT", format!("BOOT{}.EFI", arch.to_uppercase())),
+		] {
+			fs::create_dir_all(esp_dir.join(dir))?;
+			fs::copy(&src, esp_dir.join(dir).join(&dst))
+				.context(format!("Failed to install /{}/{}", dir, dst))?;
+		}
+		Ok(())
+	}
+
+	/// Install systemd-boot and write the entries, see the [module documentation](self).
+	pub(crate) fn install_systemd_boot(
+		&self,
+		spec: &SystemdBootSpec,
+		root: &Path,
+		pm_data: &PartitionMapData,
+	) -> Result<()> {
+		let esp = self.device.efi_partition(spec.esp, "systemd_boot")?;
+		// Safe to unwrap, checked by efi_partition().
+		let esp_mp = esp.mountpoint.as_deref().unwrap();
+		let esp_dir = root.join(esp_mp.trim_start_matches('/'));
+		let arch = efi_arch(&self.device.arch).context(format!(
+			"systemd-boot is not available for {:?}",
+			self.device.arch
+		))?;
+		let kernels = discover_kernels(root)?;
+		let selected = self
+			.find_kernel(root)?
+			.context("No kernel is found in the target")?;
+		self.info(format!(
+			"Installing systemd-boot to the EFI system partition {} ...",
+			esp.num
+		));
+		self.install_systemd_boot_binaries(root, esp_mp, arch)?;
+		let options = substitute(
+			spec.append.as_deref().unwrap_or("{KERNEL_CMDLINE}"),
+			&self.device.template_vars(Some(pm_data))?,
+		)?;
+		let entries_dir = esp_dir.join("loader/entries");
+		fs::create_dir_all(&entries_dir)?;
+		for kernel in &kernels {
+			let dir = Path::new("/").join(spec.entry_id()).join(&kernel.version);
+			fs::create_dir_all(esp_dir.join(dir.strip_prefix("/")?))?;
+			let linux = copy_to_esp(root, &esp_dir, &kernel.image, &dir)?;
+			let initrd = match kernel.initrd(root) {
+				Some(initrd) if !self.device.initrdless => {
+					Some(copy_to_esp(root, &esp_dir, &initrd, &dir)?)
+				}
+				_ => None,
+			};
+			let devicetree = match &self.device.dtb {
+				Some(dtb) => {
+					let src = find_dtb(root, &dtb_dirs(Some(kernel)), dtb)?;
+					let src = Path::new("/").join(src);
+					Some(copy_to_esp(root, &esp_dir, &src, &dir)?)
+				}
+				None => None,
+			};
+			let entry = Entry {
+				title: spec.title.as_deref().unwrap_or(DEFAULT_TITLE),
+				version: &kernel.version,
+				linux,
+				initrd,
+				devicetree,
+				options: &options,
+			};
+			let name = spec.entry_name(kernel);
+			self.info(format!("Writing the boot loader entry {} ...", name));
+			fs::write(entries_dir.join(&name), entry.render()).context(format!(
+				"Failed to write the boot loader entry {}",
+				name
+			))?;
+		}
+		let default = spec
+			.default
+			.clone()
+			.unwrap_or_else(|| spec.entry_name(&selected));
+		fs::write(
+			esp_dir.join("loader/loader.conf"),
+			loader_conf(spec.timeout, &default),
+		)
+		.context("Failed to write loader.conf")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_loader_conf() {
+		assert_eq!(
+			loader_conf(Some(3), "aosc-6.12.3.conf"),
+			"timeout 3\ndefault aosc-6.12.3.conf\n"
+		);
+		assert_eq!(loader_conf(None, "aosc-*"), "default aosc-*\n");
+	}
+
+	#[test]
+	fn test_entry() {
+		let entry = Entry {
+			title: "AOSC OS",
+			version: "6.12.3",
+			linux: PathBuf::from("/aosc/6.12.3/vmlinuz-6.12.3"),
+			initrd: Some(PathBuf::from("/aosc/6.12.3/initrd.img-6.12.3")),
+			devicetree: None,
+			options: "root=PARTUUID=1234 rw",
+		};
+		assert_eq!(
+			entry.render(),
+			"title AOSC OS
+version 6.12.3
+linux /aosc/6.12.3/vmlinuz-6.12.3
+initrd /aosc/6.12.3/initrd.img-6.12.3
+options root=PARTUUID=1234 rw
+"
+		);
+		let spec = SystemdBootSpec::default();
+		let kernel = KernelInfo {
+			version: "6.12.3".to_owned(),
+			image: PathBuf::from("/boot/vmlinuz-6.12.3"),
+			modules: PathBuf::from("/usr/lib/modules/6.12.3"),
+		};
+		assert_eq!(spec.entry_name(&kernel), "linux-6.12.3.conf");
+	}
+}
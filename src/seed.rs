@@ -0,0 +1,156 @@
+//! Reproducible identifiers of the images.
+//!
+//! The disk GUID (or the disk signature of the MBR), the PARTUUIDs and the
+//! UUIDs of the filesystems and the LUKS2 containers are random, unless
+//! they are pinned in the device specification. A new identifier on every
+//! build breaks the byte-for-byte reproducibility of the images, and the
+//! bootloaders embedding the disk identifier.
+//!
+//! With `--seed`, the identifiers not pinned are derived from the seed
+//! instead:
+//!
+//! ```shell
+//! mkrawimg build --seed 20241108 rpi-5b
+//! ```
+//!
+//! Each identifier is the HMAC-SHA256 of its purpose, e.g.
+//! `rpi-5b/Base/part2`, keyed with the seed. Two builds with the same seed
+//! and inputs thus have identical partition tables, while the devices and
+//! the variants built with the same seed do not share their identifiers.
+//!
+//! The pinned identifiers (`disk_uuid`, `part_uuid` and `fs_uuid`) always
+//! take precedence over the seed.
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::{
+	context::ImageContext,
+	device::{parse_disk_signature, PartitionMapType},
+	filesystem::FilesystemType,
+	partition::PartitionSpec,
+};
+
+/// HMAC-SHA256 of the message (RFC 2104).
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+	// HMAC takes keys of any length.
+	let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+	mac.update(message);
+	mac.finalize().into_bytes().into()
+}
+
+/// A random (version 4) UUID made of the derived bytes.
+fn uuid_from(bytes: &[u8; 32]) -> Uuid {
+	let mut b = [0u8; 16];
+	b.copy_from_slice(&bytes[..16]);
+	b[6] = (b[6] & 0x0f) | 0x40;
+	b[8] = (b[8] & 0x3f) | 0x80;
+	Uuid::from_bytes(b)
+}
+
+impl ImageContext<'_> {
+	/// Derive the bytes of the identifier for `purpose` from `--seed`, if given.
+	fn derive(&self, purpose: &str) -> Option<[u8; 32]> {
+		let seed = self.seed?;
+		let message = format!("{}/{}/{}", self.device.id, self.variant, purpose);
+		Some(hmac_sha256(seed.as_bytes(), message.as_bytes()))
+	}
+
+	/// GUID of the GPT, pinned or derived from the seed. `None` if it is random.
+	pub(crate) fn disk_guid(&self) -> Result<Option<Uuid>> {
+		match &self.device.disk_uuid {
+			Some(uuid) => Ok(Some(Uuid::parse_str(uuid)
+				.context(format!("Invalid disk GUID '{}'", uuid))?)),
+			None => Ok(self.derive("disk").map(|b| uuid_from(&b))),
+		}
+	}
+
+	/// Disk signature of the MBR, pinned or derived from the seed. `None` if it is random.
+	pub(crate) fn disk_signature(&self) -> Result<Option<u32>> {
+		match &self.device.disk_uuid {
+			Some(signature) => Ok(Some(parse_disk_signature(signature)?)),
+			None => Ok(self
+				.derive("disk")
+				.map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))),
+		}
+	}
+
+	/// The disk identifier shown in the plan, if it is not random.
+	pub(crate) fn fixed_disk_id(&self) -> Result<Option<String>> {
+		Ok(match self.device.partition_map {
+			PartitionMapType::GPT | PartitionMapType::Hybrid => {
+				self.disk_guid()?.map(|u| u.to_string())
+			}
+			PartitionMapType::MBR => {
+				self.disk_signature()?.map(|s| format!("{:08x}", s))
+			}
+			PartitionMapType::None => None,
+		})
+	}
+
+	/// Unique GUID of the GPT partition, pinned or derived from the seed. `None` if it is random.
+	pub(crate) fn partition_guid(&self, partition: &PartitionSpec) -> Result<Option<Uuid>> {
+		match &partition.part_uuid {
+			Some(uuid) => Ok(Some(Uuid::parse_str(uuid)?)),
+			None => Ok(self
+				.derive(&format!("part{}", partition.num))
+				.map(|b| uuid_from(&b))),
+		}
+	}
+
+	/// UUID of the filesystem, pinned or derived from the seed. `None` if it is random.
+	pub(crate) fn filesystem_uuid(
+		&self,
+		partition: &PartitionSpec,
+		filesystem: &FilesystemType,
+	) -> Option<String> {
+		if let Some(uuid) = &partition.fs_uuid {
+			return Some(uuid.clone());
+		}
+		let bytes = self.derive(&format!("fs{}", partition.num))?;
+		match filesystem {
+			FilesystemType::None => None,
+			// FAT and exFAT filesystems have a 32-bit volume ID.
+			FilesystemType::Fat16 | FilesystemType::Fat32 | FilesystemType::Exfat => {
+				Some(format!(
+					"{:02X}{:02X}-{:02X}{:02X}",
+					bytes[0], bytes[1], bytes[2], bytes[3]
+				))
+			}
+			_ => Some(uuid_from(&bytes).to_string()),
+		}
+	}
+
+	/// UUID of the LUKS2 container of the partition, derived from the seed. `None` if it is random.
+	pub(crate) fn container_uuid(&self, partition: &PartitionSpec) -> Option<String> {
+		self.derive(&format!("luks{}", partition.num))
+			.map(|b| uuid_from(&b).to_string())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_hmac_sha256() {
+		// RFC 4231, test case 2
+		let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+		let hex = mac.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+		assert_eq!(
+			hex,
+			"5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+		);
+	}
+
+	#[test]
+	fn test_uuid_from() {
+		let uuid = uuid_from(&hmac_sha256(b"seed", b"rpi-5b/Base/disk"));
+		assert_eq!(uuid, uuid_from(&hmac_sha256(b"seed", b"rpi-5b/Base/disk")));
+		assert_ne!(uuid, uuid_from(&hmac_sha256(b"seed", b"rpi-5b/Base/part1")));
+		let bytes = uuid.as_bytes();
+		assert_eq!(bytes[6] >> 4, 4);
+		assert_eq!(bytes[8] >> 6, 0b10);
+	}
+}
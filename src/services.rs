@@ -0,0 +1,466 @@
+//! Module handling the enablement of systemd services in the target.
+//!
+//! Running `systemctl enable` in the target container requires a working
+//! systemd invocation, which often fails under QEMU user emulation. Instead,
+//! the symbolic links are created directly, following the layout described
+//! in `systemd.unit(5)` and `systemd.preset(5)`.
+//!
+//! For details please go to [`ServicesSpec`].
+use std::{
+	collections::HashSet,
+	fs::{self, create_dir_all, remove_file},
+	os::unix::fs::symlink,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::context::ImageContext;
+
+/// Directory containing the units shipped by packages, relative to the root.
+const UNIT_DIR: &str = "usr/lib/systemd/system";
+/// Directory containing the units configured by the administrator, relative to the root.
+const ADMIN_UNIT_DIR: &str = "etc/systemd/system";
+/// Preset file written into the target, relative to the root.
+///
+/// Preset files are sorted by their names, and the first matching line wins.
+const PRESET_FILE: &str = "etc/systemd/system-preset/10-mkrawimg.preset";
+const UNIT_SUFFIXES: &[&str] = &[
+	".service",
+	".socket",
+	".timer",
+	".path",
+	".target",
+	".mount",
+	".automount",
+	".swap",
+	".device",
+	".slice",
+	".scope",
+];
+
+/// Specifies the systemd units to be enabled, disabled or masked in the target.
+///
+/// Units are enabled according to the `[Install]` section of the unit file,
+/// without invoking `systemctl` in the target. A preset file is also written
+/// into the target, so that `systemctl preset` keeps the same result.
+///
+/// If a unit name does not have a suffix, `.service` is assumed.
+///
+/// Usage
+/// -----
+///
+/// ```toml
+/// [services]
+/// enable = ["fake-hwclock", "getty@ttyS0.service"]
+/// disable = ["bluetooth.service"]
+/// mask = ["rfkill-state.service"]
+/// # Treat units that can not be found in the target as errors.
+/// # Default is false, in which case only a warning is printed.
+/// strict = true
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct ServicesSpec {
+	/// Units to be enabled.
+	#[serde(default)]
+	pub enable: Vec<String>,
+	/// Units to be disabled.
+	#[serde(default)]
+	pub disable: Vec<String>,
+	/// Units to be masked, i.e. linked to `/dev/null`.
+	#[serde(default)]
+	pub mask: Vec<String>,
+	/// Treat unknown units as errors.
+	#[serde(default)]
+	pub strict: bool,
+}
+
+/// Get the full name of the unit, `.service` is appended if there's no suffix.
+fn unit_name(name: &str) -> String {
+	if UNIT_SUFFIXES.iter().any(|s| name.ends_with(s)) {
+		name.to_owned()
+	} else {
+		format!("{}.service", name)
+	}
+}
+
+/// Get the name of the template, if the unit is an instance of it.
+///
+/// `getty@tty1.service` -> `getty@.service`.
+fn template_name(unit: &str) -> Option<String> {
+	let (prefix, rest) = unit.split_once('@')?;
+	let suffix = rest.rfind('.').map(|i| &rest[i..])?;
+	Some(format!("{}@{}", prefix, suffix))
+}
+
+fn check_unit_name(name: &str) -> Result<()> {
+	let name = unit_name(name);
+	if name.len() > 255 {
+		bail!("Unit name '{}' is too long", name);
+	}
+	if !name.chars()
+		.all(|c| c.is_ascii_alphanumeric() || ":-_.\\@".contains(c))
+	{
+		bail!("Unit name '{}' contains invalid characters", name);
+	}
+	if name.starts_with('.') || name.starts_with('@') {
+		bail!("Invalid unit name '{}'", name);
+	}
+	Ok(())
+}
+
+/// Find the unit file in the target root, returns the path within the target.
+fn find_unit(root: &Path, unit: &str) -> Option<PathBuf> {
+	let mut names = vec![unit.to_owned()];
+	if let Some(t) = template_name(unit) {
+		names.push(t);
+	}
+	for name in &names {
+		for dir in [ADMIN_UNIT_DIR, UNIT_DIR] {
+			let path = Path::new(dir).join(name);
+			if root.join(&path).is_file() {
+				return Some(Path::new("/").join(path));
+			}
+		}
+	}
+	None
+}
+
+/// A symbolic link relative to the root, and its target.
+type Link = (PathBuf, PathBuf);
+
+/// Values of the `[Install]` section of a unit file.
+#[derive(Default, Debug)]
+struct InstallSection {
+	wanted_by: Vec<String>,
+	required_by: Vec<String>,
+	alias: Vec<String>,
+	also: Vec<String>,
+	default_instance: Option<String>,
+}
+
+fn parse_install_section(content: &str) -> InstallSection {
+	let mut install = InstallSection::default();
+	let mut in_install = false;
+	for line in content.lines() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+			continue;
+		}
+		if line.starts_with('[') {
+			in_install = line == "[Install]";
+			continue;
+		}
+		if !in_install {
+			continue;
+		}
+		let Some((key, value)) = line.split_once('=') else {
+			continue;
+		};
+		let values = value.split_whitespace().map(str::to_owned);
+		match key.trim() {
+			"WantedBy" => install.wanted_by.extend(values),
+			"RequiredBy" => install.required_by.extend(values),
+			"Alias" => install.alias.extend(values),
+			"Also" => install.also.extend(values),
+			"DefaultInstance" => {
+				install.default_instance = Some(value.trim().to_owned())
+			}
+			_ => (),
+		}
+	}
+	install
+}
+
+/// Symbolic links (relative to the root) to be created for the unit, and their targets.
+fn install_links(root: &Path, unit: &str) -> Result<(Vec<Link>, Vec<String>)> {
+	let unit_path = find_unit(root, unit).context(format!("Unit '{}' not found", unit))?;
+	let content = fs::read_to_string(root.join(unit_path.strip_prefix("/")?)).context(
+		format!("Failed to read unit file '{}'", unit_path.display()),
+	)?;
+	let install = parse_install_section(&content);
+	// A template without an instance uses DefaultInstance=.
+	let link_name = match (unit.split_once("@."), &install.default_instance) {
+		(Some((prefix, suffix)), Some(i)) => format!("{}@{}.{}", prefix, i, suffix),
+		_ => unit.to_owned(),
+	};
+	let admin_dir = Path::new(ADMIN_UNIT_DIR);
+	let mut links = Vec::new();
+	for target in &install.wanted_by {
+		let path = admin_dir.join(format!("{}.wants", target)).join(&link_name);
+		links.push((path, unit_path.clone()));
+	}
+	for target in &install.required_by {
+		let path = admin_dir
+			.join(format!("{}.requires", target))
+			.join(&link_name);
+		links.push((path, unit_path.clone()));
+	}
+	for alias in &install.alias {
+		links.push((admin_dir.join(alias), unit_path.clone()));
+	}
+	if links.is_empty() && install.also.is_empty() {
+		warn!(
+			"Unit '{}' has no installation config, nothing to enable.",
+			unit
+		);
+	}
+	Ok((links, install.also))
+}
+
+fn create_link(root: &Path, path: &Path, target: &Path) -> Result<()> {
+	let full_path = root.join(path);
+	if let Some(parent) = full_path.parent() {
+		create_dir_all(parent)?;
+	}
+	if full_path.symlink_metadata().is_ok() {
+		remove_file(&full_path)?;
+	}
+	debug!(
+		"Creating symlink /{} -> {}",
+		path.display(),
+		target.display()
+	);
+	symlink(target, &full_path).context(format!(
+		"Failed to create symlink /{} -> {}",
+		path.display(),
+		target.display()
+	))
+}
+
+/// Remove all of the symbolic links pointing to the unit (or named after it)
+/// under the administrator unit directory.
+fn remove_links(root: &Path, unit: &str) -> Result<()> {
+	let admin_dir = root.join(ADMIN_UNIT_DIR);
+	if !admin_dir.is_dir() {
+		return Ok(());
+	}
+	let walker = walkdir::WalkDir::new(&admin_dir).min_depth(1).max_depth(2);
+	for entry in walker {
+		let entry = entry?;
+		if !entry.path_is_symlink() {
+			continue;
+		}
+		let link = fs::read_link(entry.path())?;
+		// Masked units are not touched.
+		if link == Path::new("/dev/null") {
+			continue;
+		}
+		let pointed = link.file_name().map(|x| x.to_string_lossy().to_string());
+		if entry.file_name().to_string_lossy() == unit || pointed.as_deref() == Some(unit) {
+			debug!("Removing symlink {}", entry.path().display());
+			remove_file(entry.path())?;
+		}
+	}
+	Ok(())
+}
+
+impl ServicesSpec {
+	/// Check validity of the unit names.
+	pub fn check(&self) -> Result<()> {
+		let mut seen = HashSet::new();
+		for name in self.enable.iter().chain(&self.disable).chain(&self.mask) {
+			check_unit_name(name)?;
+			if !seen.insert(unit_name(name)) {
+				bail!("Unit '{}' is listed more than once in services", name);
+			}
+		}
+		Ok(())
+	}
+
+	/// Warn or bail on units that can not be found in the target.
+	fn check_existence(&self, root: &Path) -> Result<()> {
+		for name in self.enable.iter().chain(&self.disable).chain(&self.mask) {
+			let unit = unit_name(name);
+			if find_unit(root, &unit).is_none() {
+				if self.strict {
+					bail!("Unit '{}' does not exist in the target", unit);
+				}
+				warn!("Unit '{}' does not exist in the target", unit);
+			}
+		}
+		Ok(())
+	}
+
+	/// Enable, disable and mask the units in the target root.
+	pub fn apply(&self, root: &Path) -> Result<()> {
+		self.check()?;
+		self.check_existence(root)?;
+		for name in &self.disable {
+			remove_links(root, &unit_name(name))?;
+		}
+		for name in &self.mask {
+			let unit = unit_name(name);
+			remove_links(root, &unit)?;
+			create_link(
+				root,
+				&Path::new(ADMIN_UNIT_DIR).join(&unit),
+				Path::new("/dev/null"),
+			)?;
+		}
+		let mut queue = self.enable.iter().map(|x| unit_name(x)).collect::<Vec<_>>();
+		let mut done = HashSet::new();
+		while let Some(unit) = queue.pop() {
+			if !done.insert(unit.clone()) {
+				continue;
+			}
+			if find_unit(root, &unit).is_none() {
+				// Already warned.
+				continue;
+			}
+			let (links, also) = install_links(root, &unit)?;
+			for (path, target) in links {
+				create_link(root, &path, &target)?;
+			}
+			queue.extend(also.iter().map(|x| unit_name(x)));
+		}
+		self.write_preset(root)
+	}
+
+	fn write_preset(&self, root: &Path) -> Result<()> {
+		let mut content = String::from("# ---- Auto generated by mkrawimg ----\n");
+		for name in &self.enable {
+			content += &format!("enable {}\n", unit_name(name));
+		}
+		// Presets can not mask units, they are treated as disabled.
+		for name in self.disable.iter().chain(&self.mask) {
+			content += &format!("disable {}\n", unit_name(name));
+		}
+		let path = root.join(PRESET_FILE);
+		if let Some(parent) = path.parent() {
+			create_dir_all(parent)?;
+		}
+		fs::write(&path, content).context("Failed to write the preset file")
+	}
+
+	/// Verify the links are in place after [`ServicesSpec::apply`].
+	pub fn verify(&self, root: &Path) -> Result<()> {
+		let admin_dir = Path::new(ADMIN_UNIT_DIR);
+		for name in &self.mask {
+			let path = root.join(admin_dir).join(unit_name(name));
+			if fs::read_link(&path).ok().as_deref() != Some(Path::new("/dev/null")) {
+				bail!("Unit '{}' is not masked", unit_name(name));
+			}
+		}
+		for name in &self.enable {
+			let unit = unit_name(name);
+			if find_unit(root, &unit).is_none() {
+				continue;
+			}
+			let (links, _) = install_links(root, &unit)?;
+			for (path, target) in links {
+				if fs::read_link(root.join(&path)).ok() != Some(target) {
+					bail!(
+						"Unit '{}' is not enabled: /{} is missing",
+						unit,
+						path.display()
+					);
+				}
+			}
+		}
+		for name in &self.disable {
+			let unit = unit_name(name);
+			let walker = walkdir::WalkDir::new(root.join(admin_dir))
+				.min_depth(2)
+				.max_depth(2);
+			for entry in walker.into_iter().flatten() {
+				if entry.path_is_symlink()
+					&& entry.file_name().to_string_lossy() == unit
+				{
+					bail!(
+						"Unit '{}' is not disabled: {} exists",
+						unit,
+						entry.path().display()
+					);
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+impl ImageContext<'_> {
+	/// Apply the service settings of the device to the target root.
+	pub fn setup_services<P: AsRef<Path>>(&self, root: P) -> Result<()> {
+		let root = root.as_ref();
+		if let Some(services) = &self.device.services {
+			self.info("Setting up services ...");
+			services.apply(root)?;
+			services.verify(root)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	#[test]
+	fn test_apply_services() -> Result<()> {
+		let root = TempDir::new("services")?;
+		let unit_dir = root.join(UNIT_DIR);
+		create_dir_all(&unit_dir)?;
+		fs::write(
+			unit_dir.join("fake-hwclock.service"),
+			"[Unit]\nDescription=Fake hwclock\n\n[Install]\nWantedBy=sysinit.target\nAlias=hwclock.service\n",
+		)?;
+		fs::write(
+			unit_dir.join("getty@.service"),
+			"[Service]\nExecStart=-/sbin/agetty %I\n[Install]\nWantedBy=getty.target\n",
+		)?;
+		fs::write(
+			unit_dir.join("bluetooth.service"),
+			"[Install]\nWantedBy=bluetooth.target\n",
+		)?;
+		fs::write(unit_dir.join("rfkill-state.service"), "[Service]\n")?;
+		let wants = root.join(ADMIN_UNIT_DIR).join("bluetooth.target.wants");
+		create_dir_all(&wants)?;
+		symlink(
+			"/usr/lib/systemd/system/bluetooth.service",
+			wants.join("bluetooth.service"),
+		)?;
+
+		let spec = ServicesSpec {
+			enable: vec!["fake-hwclock".into(), "getty@ttyS0.service".into()],
+			disable: vec!["bluetooth".into()],
+			mask: vec!["rfkill-state.service".into()],
+			strict: true,
+		};
+		spec.apply(&root)?;
+		spec.verify(&root)?;
+
+		let admin_dir = root.join(ADMIN_UNIT_DIR);
+		assert_eq!(
+			fs::read_link(admin_dir.join("sysinit.target.wants/fake-hwclock.service"))?,
+			Path::new("/usr/lib/systemd/system/fake-hwclock.service")
+		);
+		assert_eq!(
+			fs::read_link(admin_dir.join("hwclock.service"))?,
+			Path::new("/usr/lib/systemd/system/fake-hwclock.service")
+		);
+		assert_eq!(
+			fs::read_link(admin_dir.join("getty.target.wants/getty@ttyS0.service"))?,
+			Path::new("/usr/lib/systemd/system/getty@.service")
+		);
+		assert_eq!(
+			fs::read_link(admin_dir.join("rfkill-state.service"))?,
+			Path::new("/dev/null")
+		);
+		assert!(wants.join("bluetooth.service").symlink_metadata().is_err());
+		let preset = fs::read_to_string(root.join(PRESET_FILE))?;
+		assert!(preset.contains("enable fake-hwclock.service\n"));
+		assert!(preset.contains("disable bluetooth.service\n"));
+
+		let unknown = ServicesSpec {
+			enable: vec!["nonexistent".into()],
+			strict: true,
+			..Default::default()
+		};
+		assert!(unknown.apply(&root).is_err());
+		Ok(())
+	}
+}
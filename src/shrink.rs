@@ -0,0 +1,541 @@
+//! Shrinking the images to their contents, with `--shrink`.
+//!
+//! Most of a freshly built image is empty space, which is still stored in
+//! the artifacts and written while flashing. With `--shrink`, right before
+//! the image is compressed:
+//!
+//! 1. The filesystem of the last partition is shrunk to its used size, plus
+//!    [`SHRINK_MARGIN`] of free space for the first boot: `resize2fs` for
+//!    ext4, `btrfs filesystem resize` for btrfs. XFS can not be shrunk, and
+//!    the other filesystems are not supported.
+//! 2. The partition is shrunk to the filesystem, rounded up to 1 MiB.
+//! 3. The backup GPT header is moved right after the partition, and the
+//!    image is truncated after it. The trailing padding is dropped. The
+//!    partition table is verified again (see [`crate::verify`]), and the
+//!    image metadata records the new size.
+//!
+//! To fill the medium again, `mkrawimg-growfs.service` is installed into
+//! the target while populating it. On the first boot, it grows the
+//! partition with `growpart` (from cloud-utils, which must be installed in
+//! the target, e.g. by `bsp_packages`), then the filesystem, and never runs
+//! again.
+//!
+//! The last partition must have a filesystem mounted in the target (usually
+//! the root partition), and must not be a logical partition of a MBR. This
+//! is checked before anything is built.
+//!
+//! ```shell
+//! # mkrawimg build --shrink -V desktop rpi-5b
+//! ```
+use std::{
+	fs::{self, create_dir_all, File},
+	os::unix::fs::{symlink, PermissionsExt},
+	path::Path,
+	process::Command,
+};
+
+use anyhow::{bail, Context, Result};
+use gptman::GPT;
+use mbrman::MBR;
+use sys_mount::{unmount, Mount, UnmountFlags};
+
+use crate::{
+	context::ImageContext,
+	device::{PartitionMapType, MBR_FIRST_LOGICAL},
+	filesystem::FilesystemType,
+	metadata::{update_image_size, DEFAULT_METADATA_OFFSET},
+	partition::{PartitionSpec, SECTOR_SIZE},
+	plan::plan_layout,
+	runner,
+	size::{bytes_to_sectors, MIB},
+	utils::cmd_run_check_status,
+	verify::{verify_gpt, verify_mbr},
+};
+
+/// Free space left in the shrunk filesystem, in bytes.
+pub const SHRINK_MARGIN: u64 = 64 * MIB;
+/// Name of the unit growing the partition on the first boot.
+pub const GROWFS_UNIT: &str = "mkrawimg-growfs.service";
+const GROWFS_UNIT_PATH: &str = "usr/lib/systemd/system/mkrawimg-growfs.service";
+const GROWFS_SCRIPT_PATH: &str = "usr/lib/mkrawimg/growfs";
+const GROWFS_WANTS_DIR: &str = "etc/systemd/system/local-fs.target.wants";
+/// Created after the partition is grown, so the unit never runs again.
+const GROWFS_DONE: &str = "/var/lib/mkrawimg/growfs.done";
+
+/// Parse the output of `resize2fs -P`, returns the minimum size in blocks.
+fn parse_resize2fs_min(output: &str) -> Option<u64> {
+	output.lines()
+		.find_map(|l| l.strip_prefix("Estimated minimum size of the filesystem:"))
+		.and_then(|n| n.trim().parse().ok())
+}
+
+/// Parse the block size from the output of `dumpe2fs -h`.
+fn parse_block_size(output: &str) -> Option<u64> {
+	output.lines()
+		.find_map(|l| l.strip_prefix("Block size:"))
+		.and_then(|n| n.trim().parse().ok())
+}
+
+/// Parse the output of `btrfs inspect-internal min-dev-size`, e.g. `1234567 bytes (1.18MiB)`.
+fn parse_btrfs_min_size(output: &str) -> Option<u64> {
+	output.split_whitespace()
+		.next()
+		.and_then(|n| n.parse().ok())
+}
+
+fn command_output(cmd: &mut Command) -> Result<String> {
+	let output = runner::output(cmd)?;
+	if !output.status.success() {
+		bail!(
+			"Command {:?} failed: {}",
+			cmd,
+			String::from_utf8_lossy(&output.stderr).trim()
+		);
+	}
+	Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Whether `e2fsck` exited with `code` on a filesystem that can be shrunk.
+///
+/// Exit code 1 means the errors are corrected, and the filesystem is clean.
+fn e2fsck_succeeded(code: Option<i32>) -> bool {
+	matches!(code, Some(0) | Some(1))
+}
+
+/// Size of the image after the partition is shrunk to end at `end` (exclusive), in sectors.
+fn shrunk_image_sectors(map: PartitionMapType, end: u64, backup_gpt_sectors: u64) -> u64 {
+	let mib = MIB / SECTOR_SIZE;
+	match map {
+		PartitionMapType::GPT | PartitionMapType::Hybrid => {
+			(end + backup_gpt_sectors).next_multiple_of(mib)
+		}
+		PartitionMapType::MBR | PartitionMapType::None => end.next_multiple_of(mib),
+	}
+}
+
+/// Shrink partition `num` to `fs_sectors` sectors, and truncate the image after it.
+///
+/// Returns the new size of the image in bytes, or `None` if it is already as small as
+/// its contents.
+fn shrink_table(
+	rawimg: &Path,
+	map: PartitionMapType,
+	num: u32,
+	fs_sectors: u64,
+) -> Result<Option<u64>> {
+	let old_size = fs::metadata(rawimg)?.len();
+	let mut fd = File::options().read(true).write(true).open(rawimg)?;
+	let new_size = match map {
+		PartitionMapType::GPT | PartitionMapType::Hybrid => {
+			let mut table = GPT::read_from(&mut fd, SECTOR_SIZE)?;
+			let entries = (table.header.number_of_partition_entries as u64
+				* table.header.size_of_partition_entry as u64)
+				.div_ceil(SECTOR_SIZE);
+			let start = table[num].starting_lba;
+			let end = start + fs_sectors;
+			let sectors = shrunk_image_sectors(map, end, entries + 1);
+			if sectors * SECTOR_SIZE >= old_size {
+				return Ok(None);
+			}
+			table[num].ending_lba = end - 1;
+			table.header.backup_lba = sectors - 1;
+			table.header.last_usable_lba = sectors - 2 - entries;
+			fd.set_len(sectors * SECTOR_SIZE)?;
+			table.write_into(&mut fd)?;
+			// The hybrid MBR only covers the GPT and the mirrored partitions,
+			// but the protective MBR spans the whole image.
+			if map == PartitionMapType::GPT {
+				GPT::write_protective_mbr_into(&mut fd, SECTOR_SIZE)?;
+			}
+			fd.sync_all()?;
+			verify_gpt(rawimg, &table)
+				.context("The partition table is not written correctly")?;
+			sectors * SECTOR_SIZE
+		}
+		PartitionMapType::MBR => {
+			let mut table = MBR::read_from(&mut fd, SECTOR_SIZE as u32)?;
+			let start = table[num as usize].starting_lba as u64;
+			let sectors = shrunk_image_sectors(map, start + fs_sectors, 0);
+			if sectors * SECTOR_SIZE >= old_size {
+				return Ok(None);
+			}
+			table[num as usize].sectors = fs_sectors
+				.try_into()
+				.context("Partition size exceeds the limit of MBR")?;
+			table.write_into(&mut fd)?;
+			fd.set_len(sectors * SECTOR_SIZE)?;
+			fd.sync_all()?;
+			verify_mbr(rawimg, &table)
+				.context("The partition table is not written correctly")?;
+			sectors * SECTOR_SIZE
+		}
+		PartitionMapType::None => {
+			let sectors = shrunk_image_sectors(map, fs_sectors, 0);
+			if sectors * SECTOR_SIZE >= old_size {
+				return Ok(None);
+			}
+			fd.set_len(sectors * SECTOR_SIZE)?;
+			fd.sync_all()?;
+			sectors * SECTOR_SIZE
+		}
+	};
+	Ok(Some(new_size))
+}
+
+/// The first boot script growing the partition mounted at `mountpoint` and its filesystem.
+fn growfs_script(fs: FilesystemType, mountpoint: &str, has_table: bool) -> String {
+	let mut script = format!(
+		"#!/bin/sh\n# ---- Auto generated by mkrawimg ----\nset -e\ndev=\"$(findmnt -no SOURCE '{}')\"\n",
+		mountpoint
+	);
+	if has_table {
+		script += "disk=\"/dev/$(lsblk -no PKNAME \"$dev\")\"\n";
+		script += "num=\"$(cat \"/sys/class/block/$(basename \"$dev\")/partition\")\"\n";
+		// Exits with 1 if the partition already fills the disk.
+		script += "growpart \"$disk\" \"$num\" || [ $? -eq 1 ]\n";
+	}
+	script += &match fs {
+		FilesystemType::Btrfs => format!("btrfs filesystem resize max '{}'\n", mountpoint),
+		_ => "resize2fs \"$dev\"\n".to_owned(),
+	};
+	script += &format!("mkdir -p \"$(dirname {0})\"\ntouch {0}\n", GROWFS_DONE);
+	script
+}
+
+fn growfs_unit() -> String {
+	format!(
+		"# ---- Auto generated by mkrawimg ----\n[Unit]\nDescription=Grow the shrunk partition to fill the disk\nDefaultDependencies=no\nAfter=local-fs-pre.target systemd-remount-fs.service\nBefore=local-fs.target shutdown.target\nConflicts=shutdown.target\nConditionPathExists=!{}\n\n[Service]\nType=oneshot\nRemainAfterExit=yes\nExecStart=/{}\n\n[Install]\nWantedBy=local-fs.target\n",
+		GROWFS_DONE, GROWFS_SCRIPT_PATH
+	)
+}
+
+impl ImageContext<'_> {
+	/// The partition at the end of the image, which is shrunk.
+	pub(crate) fn last_partition(&self) -> Result<&PartitionSpec> {
+		let layout = plan_layout(
+			self.device.partition_map,
+			&self.device.partitions,
+			&self.device.reserved,
+			self.device
+				.first_partition_offset
+				.map(|o| o.sectors(SECTOR_SIZE)),
+			self.variant,
+			self.padded_image_size(self.nominal_image_size()),
+			self.get_trailing_pad(),
+		)?;
+		layout.iter()
+			.filter_map(|p| {
+				self.device
+					.partitions
+					.iter()
+					.find(|s| s.num == p.num)
+					.map(|s| (p.start, s))
+			})
+			.max_by_key(|(start, _)| *start)
+			.map(|(_, s)| s)
+			.context("No partition to shrink")
+	}
+
+	/// The filesystem of the partition, after `--fstype`.
+	pub(crate) fn effective_fstype(&self, partition: &PartitionSpec) -> FilesystemType {
+		self.device
+			.effective_fstype(partition, *self.override_rootfs_fstype)
+	}
+
+	/// Make sure the last partition can be shrunk, and grown again on the first boot.
+	pub(crate) fn check_shrink(&self) -> Result<()> {
+		let id = &self.device.id;
+		let partition = self
+			.last_partition()
+			.context(format!("Unable to find the last partition of {}", id))?;
+		let num = partition.num;
+		match self.effective_fstype(partition) {
+			FilesystemType::Ext4 | FilesystemType::Btrfs => (),
+			FilesystemType::Xfs => bail!(
+				"Unable to shrink {}: partition {} is XFS, which can not be shrunk. Build it without --shrink, or with --fstype ext4 or btrfs.",
+				id,
+				num
+			),
+			fs => bail!(
+				"Unable to shrink {}: partition {} is {:?}, only ext4 and btrfs can be shrunk.",
+				id,
+				num,
+				fs
+			),
+		}
+		if partition.hybrid_mbr {
+			bail!(
+				"Unable to shrink {}: partition {} is mirrored in the hybrid MBR.",
+				id,
+				num
+			);
+		}
+		if partition.mountpoint.is_none() {
+			bail!(
+				"Unable to shrink {}: partition {} is not mounted in the target, so it can not be grown on the first boot.",
+				id,
+				num
+			);
+		}
+		if partition.encryption.is_some() {
+			bail!(
+				"Unable to shrink {}: partition {} is encrypted, the LUKS2 container can not be shrunk.",
+				id,
+				num
+			);
+		}
+		if self.device.partition_map == PartitionMapType::MBR && num >= MBR_FIRST_LOGICAL {
+			bail!(
+				"Unable to shrink {}: partition {} is a logical partition.",
+				id,
+				num
+			);
+		}
+		Ok(())
+	}
+
+	/// Install the unit growing the last partition on the first boot into the target.
+	pub(crate) fn setup_growfs(&self, root: &Path) -> Result<()> {
+		let partition = self.last_partition()?;
+		let mountpoint = partition
+			.mountpoint
+			.as_deref()
+			.context("The last partition is not mounted")?;
+		self.info(format!(
+			"Installing {} to grow partition {} on the first boot ...",
+			GROWFS_UNIT, partition.num
+		));
+		if !["usr/bin/growpart", "usr/sbin/growpart", "bin/growpart"]
+			.iter()
+			.any(|p| root.join(p).exists())
+			&& self.device.partition_map != PartitionMapType::None
+		{
+			self.warn("growpart is not installed in the target, the image will not be grown on the first boot.");
+		}
+		let script = root.join(GROWFS_SCRIPT_PATH);
+		create_dir_all(script.parent().unwrap())?;
+		fs::write(
+			&script,
+			growfs_script(
+				self.effective_fstype(partition),
+				mountpoint,
+				self.device.partition_map != PartitionMapType::None,
+			),
+		)?;
+		fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+		let unit = root.join(GROWFS_UNIT_PATH);
+		create_dir_all(unit.parent().unwrap())?;
+		fs::write(&unit, growfs_unit())?;
+		let wants = root.join(GROWFS_WANTS_DIR);
+		create_dir_all(&wants)?;
+		let link = wants.join(GROWFS_UNIT);
+		if link.symlink_metadata().is_ok() {
+			fs::remove_file(&link)?;
+		}
+		symlink(Path::new("/").join(GROWFS_UNIT_PATH), link)
+			.context(format!("Failed to enable {}", GROWFS_UNIT))
+	}
+
+	/// Shrink the filesystem to its contents, returns its new size in bytes.
+	///
+	/// The filesystem is left as is if it can not be smaller than `capacity` bytes.
+	fn shrink_filesystem(&self, fs: FilesystemType, dev: &Path, capacity: u64) -> Result<u64> {
+		let min = match fs {
+			FilesystemType::Ext4 => {
+				let mut cmd = Command::new("e2fsck");
+				cmd.arg("-fy").arg(dev);
+				let status = runner::run(&mut cmd)?;
+				if !e2fsck_succeeded(status.code()) {
+					bail!("Failed to check the filesystem: {:?} exited with {}", cmd, status);
+				}
+				let info = command_output(
+					Command::new("dumpe2fs").arg("-h").arg(dev),
+				)?;
+				let block_size = parse_block_size(&info).context(
+					"Unable to find the block size of the filesystem",
+				)?;
+				let output = command_output(
+					Command::new("resize2fs").arg("-P").arg(dev),
+				)?;
+				parse_resize2fs_min(&output).context(
+					"Unable to find the minimum size of the filesystem",
+				)? * block_size
+			}
+			FilesystemType::Btrfs => {
+				let mnt = self.sketch_dir().join("mnt").join("shrink");
+				create_dir_all(&mnt)?;
+				Mount::builder().fstype("btrfs").mount(dev, &mnt)?;
+				let result = command_output(
+					Command::new("btrfs")
+						.args(["inspect-internal", "min-dev-size"])
+						.arg(&mnt),
+				)
+				.and_then(|output| {
+					let min = parse_btrfs_min_size(&output).context(
+						"Unable to find the minimum size of the filesystem",
+					)?;
+					let size = (min + SHRINK_MARGIN).next_multiple_of(MIB);
+					if size >= capacity {
+						return Ok(capacity);
+					}
+					cmd_run_check_status(
+						Command::new("btrfs")
+							.args(["filesystem", "resize"])
+							.arg(size.to_string())
+							.arg(&mnt),
+					)?;
+					Ok(size)
+				});
+				unmount(&mnt, UnmountFlags::empty())?;
+				return result;
+			}
+			fs => bail!("{:?} filesystems can not be shrunk", fs),
+		};
+		let size = (min + SHRINK_MARGIN).next_multiple_of(MIB);
+		if size >= capacity {
+			return Ok(capacity);
+		}
+		cmd_run_check_status(
+			Command::new("resize2fs")
+				.arg(dev)
+				.arg(format!("{}K", size / 1024)),
+		)?;
+		Ok(size)
+	}
+
+	/// Shrink the last partition and the image to the contents, see the [module documentation](self).
+	pub(crate) fn shrink_image(&self, rawimg: &Path) -> Result<()> {
+		let partition = self.last_partition()?;
+		let num = partition.num;
+		let fs = self.effective_fstype(partition);
+		let map = self.device.partition_map;
+		let old_size = fs::metadata(rawimg)?.len();
+		let mut fd = File::options().read(true).write(true).open(rawimg)?;
+		// Size of the partition, in bytes.
+		let capacity = match map {
+			PartitionMapType::GPT | PartitionMapType::Hybrid => {
+				let table = GPT::read_from(&mut fd, SECTOR_SIZE)?;
+				(table[num].ending_lba + 1 - table[num].starting_lba) * SECTOR_SIZE
+			}
+			PartitionMapType::MBR => {
+				let table = MBR::read_from(&mut fd, SECTOR_SIZE as u32)?;
+				table[num as usize].sectors as u64 * SECTOR_SIZE
+			}
+			PartitionMapType::None => old_size,
+		};
+		self.info(format!("Shrinking partition {} ({:?}) ...", num, fs));
+		let (loop_dev, loop_dev_path) = Self::attach_loop_device(rawimg)?;
+		let dev = self.device.partition_path(&loop_dev_path, num);
+		let result = self.shrink_filesystem(fs, Path::new(&dev), capacity);
+		loop_dev.detach()?;
+		let fs_size = result.context(format!("Failed to shrink partition {}", num))?;
+		let fs_sectors = bytes_to_sectors(fs_size, SECTOR_SIZE);
+		let new_size = match shrink_table(rawimg, map, num, fs_sectors)? {
+			Some(new_size) => new_size,
+			None => {
+				self.info("The image is already as small as its contents.");
+				return Ok(());
+			}
+		};
+		self.info(format!(
+			"Shrunk the image from {} MiB to {} MiB.",
+			old_size / MIB,
+			new_size / MIB
+		));
+		if map != PartitionMapType::None {
+			let offset = self
+				.device
+				.metadata_offset
+				.unwrap_or(DEFAULT_METADATA_OFFSET);
+			update_image_size(rawimg, offset, new_size)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use gptman::GPTPartitionEntry;
+
+	use super::*;
+	use crate::testutil::TempDir;
+
+	#[test]
+	fn test_parse_sizes() {
+		let output = "resize2fs 1.47.0 (5-Feb-2023)\nEstimated minimum size of the filesystem: 1048576\n";
+		assert_eq!(parse_resize2fs_min(output), Some(1048576));
+		let output = "Filesystem UUID:          0d9f8c7b\nBlock count:              6553600\nBlock size:               4096\n";
+		assert_eq!(parse_block_size(output), Some(4096));
+		assert_eq!(
+			parse_btrfs_min_size("1234567168 bytes (1.15GiB)\n"),
+			Some(1234567168)
+		);
+		assert_eq!(parse_resize2fs_min("resize2fs: Bad magic number"), None);
+	}
+
+	#[test]
+	fn test_shrunk_image_sectors() {
+		// The backup GPT follows the partition, rounded up to 1 MiB.
+		assert_eq!(shrunk_image_sectors(PartitionMapType::GPT, 4096, 33), 6144);
+		assert_eq!(shrunk_image_sectors(PartitionMapType::GPT, 4000, 33), 4096);
+		assert_eq!(shrunk_image_sectors(PartitionMapType::MBR, 4096, 0), 4096);
+		assert_eq!(shrunk_image_sectors(PartitionMapType::None, 1, 0), 2048);
+	}
+
+	#[test]
+	fn test_e2fsck_succeeded() {
+		assert!(e2fsck_succeeded(Some(0)));
+		assert!(e2fsck_succeeded(Some(1)));
+		assert!(!e2fsck_succeeded(Some(4)));
+		assert!(!e2fsck_succeeded(None));
+	}
+
+	#[test]
+	fn test_shrink_table() -> Result<()> {
+		let dir = TempDir::new("shrink")?;
+		let img = dir.join("disk.img");
+		let mut fd = File::create(&img)?;
+		fd.set_len(64 * MIB)?;
+		let mut table = GPT::new_from(&mut fd, SECTOR_SIZE, [1; 16])?;
+		table[1] = GPTPartitionEntry {
+			partition_type_guid: [2; 16],
+			unique_partition_guid: [3; 16],
+			starting_lba: 2048,
+			ending_lba: table.header.last_usable_lba,
+			attribute_bits: 0,
+			partition_name: "root".into(),
+		};
+		GPT::write_protective_mbr_into(&mut fd, SECTOR_SIZE)?;
+		table.write_into(&mut fd)?;
+		drop(fd);
+		// The filesystem was shrunk to 2 MiB.
+		assert_eq!(
+			shrink_table(&img, PartitionMapType::GPT, 1, 4096)?,
+			Some(8192 * SECTOR_SIZE)
+		);
+		assert_eq!(fs::metadata(&img)?.len(), 8192 * SECTOR_SIZE);
+		let mut fd = File::open(&img)?;
+		let table = GPT::read_from(&mut fd, SECTOR_SIZE)?;
+		assert_eq!((table[1].starting_lba, table[1].ending_lba), (2048, 6143));
+		assert_eq!(table.header.backup_lba, 8191);
+		// The protective MBR spans the new size.
+		let mbr = MBR::read_from(&mut fd, SECTOR_SIZE as u32)?;
+		assert_eq!(mbr[1].sys, 0xee);
+		assert_eq!((mbr[1].starting_lba, mbr[1].sectors), (1, 8191));
+		drop(fd);
+		// Already as small as the contents.
+		assert_eq!(shrink_table(&img, PartitionMapType::GPT, 1, 4096)?, None);
+		Ok(())
+	}
+
+	#[test]
+	fn test_growfs_script() {
+		let script = growfs_script(FilesystemType::Ext4, "/", true);
+		assert!(script.contains("growpart \"$disk\" \"$num\""));
+		assert!(script.contains("resize2fs \"$dev\""));
+		let script = growfs_script(FilesystemType::Btrfs, "/", false);
+		assert!(!script.contains("growpart"));
+		assert!(script.contains("btrfs filesystem resize max '/'"));
+		assert!(growfs_unit().contains(GROWFS_DONE));
+	}
+}
@@ -0,0 +1,163 @@
+//! Sizes with units, in the device specifications and on the command line.
+//!
+//! A size is a non-negative integer followed by a binary unit, with an
+//! optional space in between:
+//!
+//! - `B`: bytes.
+//! - `K`, `KiB`: 1024 bytes.
+//! - `M`, `MiB`: 1024 KiB.
+//! - `G`, `GiB`: 1024 MiB.
+//! - `T`, `TiB`: 1024 GiB.
+//!
+//! `KB`, `MB`, `GB` and `TB` are read as binary units as well, since the
+//! sizes of the images and the partitions are always in binary units. The
+//! units are case-insensitive.
+//!
+//! Bare integers are not accepted by [`parse_size`], their unit depends on
+//! the field, see [`SectorSize`], [`ImageVariantSizes`] and [`ByteSize`].
+//!
+//! [`SectorSize`]: crate::partition::SectorSize
+//! [`ImageVariantSizes`]: crate::device::ImageVariantSizes
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use toml::Value;
+
+pub const KIB: u64 = 1 << 10;
+pub const MIB: u64 = 1 << 20;
+pub const GIB: u64 = 1 << 30;
+pub const TIB: u64 = 1 << 40;
+
+/// Units from the largest, used to format the sizes.
+const UNITS: &[(&str, u64)] = &[("TiB", TIB), ("GiB", GIB), ("MiB", MIB), ("KiB", KIB)];
+
+/// Parse a size with a unit, e.g. `256MiB` or `2G`, returns the size in bytes.
+pub fn parse_size(s: &str) -> Result<u64> {
+	let s = s.trim();
+	let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+	let (num, unit) = s.split_at(split);
+	let num: u64 = num.parse().context(format!(
+		"Invalid size '{}', expected e.g. 256MiB or 2GiB",
+		s
+	))?;
+	let unit = unit.trim();
+	let factor = match unit
+		.to_ascii_uppercase()
+		.trim_end_matches("IB")
+		.trim_end_matches('B')
+	{
+		"" if unit.is_empty() => bail!(
+			"Size '{}' has no unit, possible units are B, KiB, MiB, GiB and TiB",
+			s
+		),
+		"" => 1,
+		"K" => KIB,
+		"M" => MIB,
+		"G" => GIB,
+		"T" => TIB,
+		_ => bail!(
+			"Unknown unit '{}' of size '{}', possible units are B, KiB, MiB, GiB and TiB",
+			unit,
+			s
+		),
+	};
+	num.checked_mul(factor)
+		.context(format!("Size '{}' is too large", s))
+}
+
+/// Format a size in bytes with the largest unit it is a multiple of, e.g. `256MiB`.
+pub fn format_size(bytes: u64) -> String {
+	UNITS.iter()
+		.find(|(_, factor)| bytes != 0 && bytes.is_multiple_of(*factor))
+		.map(|(unit, factor)| format!("{}{}", bytes / factor, unit))
+		.unwrap_or_else(|| format!("{}B", bytes))
+}
+
+/// A size in bytes in the device specifications, an integer in bytes or a string with a unit, e.g. `"16KiB"`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "Value")]
+pub struct ByteSize(pub u64);
+
+impl TryFrom<Value> for ByteSize {
+	type Error = anyhow::Error;
+
+	fn try_from(value: Value) -> Result<Self> {
+		match value {
+			Value::Integer(n) if n >= 0 => Ok(Self(n as u64)),
+			Value::String(s) => Ok(Self(parse_size(&s)?)),
+			v => bail!(
+				"Invalid size '{}', expected a non-negative integer in bytes or a size with a unit",
+				v
+			),
+		}
+	}
+}
+
+/// Convert a size in bytes to sectors, rounded up to the sector boundary.
+pub fn bytes_to_sectors(bytes: u64, sector_size: u64) -> u64 {
+	bytes.div_ceil(sector_size)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_size() -> Result<()> {
+		assert_eq!(parse_size("256MiB")?, 256 * MIB);
+		assert_eq!(parse_size("2GiB")?, 2 * GIB);
+		assert_eq!(parse_size("2G")?, 2 * GIB);
+		assert_eq!(parse_size("2gb")?, 2 * GIB);
+		assert_eq!(parse_size(" 300 MiB ")?, 300 * MIB);
+		assert_eq!(parse_size("32KiB")?, 32 * KIB);
+		assert_eq!(parse_size("1TiB")?, TIB);
+		assert_eq!(parse_size("512B")?, 512);
+		assert_eq!(parse_size("0MiB")?, 0);
+		assert!(parse_size("256").is_err());
+		assert!(parse_size("MiB").is_err());
+		assert!(parse_size("1.5GiB").is_err());
+		assert!(parse_size("-1MiB").is_err());
+		assert!(parse_size("2PiB").is_err());
+		assert!(parse_size("100%").is_err());
+		assert!(parse_size("99999999999TiB").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_format_size() -> Result<()> {
+		assert_eq!(format_size(256 * MIB), "256MiB");
+		assert_eq!(format_size(1536 * MIB), "1536MiB");
+		assert_eq!(format_size(2 * GIB), "2GiB");
+		assert_eq!(format_size(32 * KIB), "32KiB");
+		assert_eq!(format_size(1000), "1000B");
+		assert_eq!(format_size(0), "0B");
+		for size in ["300MiB", "4GiB", "1TiB", "513B"] {
+			assert_eq!(format_size(parse_size(size)?), size);
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_byte_size() -> Result<()> {
+		assert_eq!(ByteSize::try_from(Value::Integer(4096))?, ByteSize(4096));
+		assert_eq!(
+			ByteSize::try_from(Value::String("16KiB".to_owned()))?,
+			ByteSize(16384)
+		);
+		assert_eq!(ByteSize::try_from(Value::Integer(0))?, ByteSize(0));
+		assert!(ByteSize::try_from(Value::Integer(-1)).is_err());
+		assert!(ByteSize::try_from(Value::String("16".to_owned())).is_err());
+		assert!(ByteSize::try_from(Value::Boolean(true)).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_bytes_to_sectors() {
+		assert_eq!(bytes_to_sectors(MIB, 512), 2048);
+		assert_eq!(bytes_to_sectors(MIB, 4096), 256);
+		// Rounded up to the sector boundary
+		assert_eq!(bytes_to_sectors(MIB + 1, 512), 2049);
+		assert_eq!(bytes_to_sectors(513, 512), 2);
+		assert_eq!(bytes_to_sectors(4095, 4096), 1);
+		assert_eq!(bytes_to_sectors(0, 512), 0);
+	}
+}
@@ -0,0 +1,310 @@
+//! A/B slots of the partitions, for the OTA updates.
+//!
+//! An OTA scheme with two identical root partitions updates the inactive
+//! one and switches to it. Instead of writing both partitions by hand, a
+//! partition declares `slots = 2`, and is expanded into two partitions
+//! when the specification is loaded:
+//!
+//! ```toml
+//! [[partition]]
+//! num = 2
+//! type = "linux"
+//! label = "root"
+//! size = "4GiB"
+//! filesystem = "ext4"
+//! mountpoint = "/"
+//! usage = "rootfs"
+//! slots = 2
+//!
+//! # Partition 3 is slot B of the root partition
+//! [[partition]]
+//! num = 4
+//! # ...
+//! ```
+//!
+//! - Slot A keeps the number of the partition, slot B takes the next one.
+//!   The following partitions are numbered after slot B, and
+//!   `num_partitions` counts both the slots.
+//! - Both the slots have the same type, size, alignment, filesystem and
+//!   attributes. The labels and the filesystem labels, if any, are suffixed
+//!   with `_a` and `_b`.
+//! - Slot A is the partition as declared, with the contents, the mountpoint
+//!   and the pinned UUIDs. Slot B is formatted but left empty, or not
+//!   formatted at all with `empty_slot_b = true`. It has random UUIDs, and
+//!   is neither mounted, listed in `/etc/fstab`, nor mirrored in the hybrid
+//!   MBR.
+//! - The usage of slot B is `data`, so the root partition placeholders of
+//!   the kernel command line (e.g. `{ROOT_PARTUUID}`) refer to slot A. Slot
+//!   B is still reachable as `{PARTn_PARTUUID}`.
+//!
+//! The slots can not have a pinned start sector, nor fill the rest of the
+//! image. `mkrawimg inspect` shows the expanded partitions.
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::{
+	device::DeviceSpec,
+	filesystem::FilesystemType,
+	partition::{PartitionSize, PartitionSpec, PartitionUsage, SectorSize},
+};
+
+/// A slot of a partition with `slots = 2`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Slot {
+	A,
+	B,
+}
+
+impl Slot {
+	pub fn suffix(&self) -> &'static str {
+		match self {
+			Self::A => "_a",
+			Self::B => "_b",
+		}
+	}
+}
+
+fn fills_rest(size: &PartitionSize) -> bool {
+	match size {
+		PartitionSize::Fixed(size) => *size == SectorSize::Rest,
+		PartitionSize::Variants {
+			base,
+			desktop,
+			server,
+		} => [base, desktop, server].contains(&&SectorSize::Rest),
+	}
+}
+
+/// Make the slot of the partition, with its number and labels.
+fn make_slot(partition: &PartitionSpec, slot: Slot) -> PartitionSpec {
+	let suffixed = |label: Option<String>| label.map(|l| l + slot.suffix());
+	let mut p = partition.clone();
+	p.slots = None;
+	p.slot = Some(slot);
+	p.label = suffixed(partition.get_label());
+	p.fs_label = suffixed(partition.fs_label.clone());
+	if slot == Slot::A {
+		return p;
+	}
+	p.num += 1;
+	p.start_sector = None;
+	p.mountpoint = None;
+	p.mount_opts = None;
+	p.part_uuid = None;
+	p.fs_uuid = None;
+	p.content = None;
+	p.hybrid_mbr = false;
+	p.install_snapshot = false;
+	for subvol in &mut p.subvolumes {
+		subvol.mountpoint = None;
+		subvol.mount_opts = None;
+	}
+	if partition.empty_slot_b {
+		p.filesystem = FilesystemType::None;
+		p.encryption = None;
+	}
+	if matches!(p.usage, PartitionUsage::Rootfs | PartitionUsage::Boot) {
+		p.usage = PartitionUsage::Data;
+	}
+	p
+}
+
+/// Expand the partitions with `slots = 2` into slot A and slot B.
+pub fn expand_slots(partitions: Vec<PartitionSpec>) -> Result<Vec<PartitionSpec>> {
+	let mut expanded = Vec::with_capacity(partitions.len());
+	for partition in partitions {
+		let num = partition.num;
+		match partition.slots {
+			None => {
+				if partition.empty_slot_b {
+					bail!("Partition {}: empty_slot_b requires slots = 2", num);
+				}
+				let taken = expanded.last().is_some_and(|p: &PartitionSpec| {
+					p.slot == Some(Slot::B) && p.num == num
+				});
+				if taken {
+					bail!("Partition {} is slot B of partition {}, number the following partitions from {}", num, num - 1, num + 1);
+				}
+				expanded.push(partition);
+				continue;
+			}
+			Some(2) => (),
+			Some(n) => bail!(
+				"Partition {}: only 2 slots (A/B) are supported, got {}",
+				num,
+				n
+			),
+		}
+		if partition.usage == PartitionUsage::Swap {
+			bail!("Partition {}: swap partitions can not have slots", num);
+		}
+		if partition.start_sector.is_some() {
+			bail!(
+				"Partition {}: partitions with slots can not have a pinned start sector",
+				num
+			);
+		}
+		if fills_rest(&partition.size_in_sectors) {
+			bail!(
+				"Partition {}: partitions with slots must have a fixed size, not the rest of the image",
+				num
+			);
+		}
+		expanded.push(make_slot(&partition, Slot::A));
+		expanded.push(make_slot(&partition, Slot::B));
+	}
+	Ok(expanded)
+}
+
+impl DeviceSpec {
+	/// Whether the filesystem of the partition follows `--fstype`, i.e. it is the root partition or its slot B.
+	pub fn follows_rootfs_fstype(&self, partition: &PartitionSpec) -> bool {
+		if partition.usage == PartitionUsage::Rootfs {
+			return true;
+		}
+		partition.slot == Some(Slot::B)
+			&& self.partitions.iter().any(|p| {
+				p.num + 1 == partition.num && p.usage == PartitionUsage::Rootfs
+			})
+	}
+
+	/// The filesystem of the partition, after `--fstype` if given.
+	pub fn effective_fstype(
+		&self,
+		partition: &PartitionSpec,
+		fstype: Option<FilesystemType>,
+	) -> FilesystemType {
+		match fstype {
+			Some(fs) if self.follows_rootfs_fstype(partition) => fs,
+			_ => partition.filesystem,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		partition::PartitionType,
+		testutil::{load_device, TempDir},
+	};
+
+	fn partition(num: u32, slots: Option<u32>, size: SectorSize) -> PartitionSpec {
+		PartitionSpec {
+			num,
+			part_type: PartitionType::Linux,
+			start_sector: None,
+			align: None,
+			size_in_sectors: PartitionSize::Fixed(size),
+			label: Some("root".into()),
+			mountpoint: Some("/".into()),
+			filesystem: FilesystemType::Ext4,
+			mount_opts: None,
+			fs_label: None,
+			part_uuid: Some("5B1B7E3C-2D0A-4F5E-9C1D-3A8E6F4B2C10".into()),
+			fs_uuid: None,
+			fs_compression: None,
+			mkfs_options: None,
+			fstab_style: None,
+			ext4_reserved_percent: None,
+			ext4_default_mount_opts: None,
+			ext4_check_interval: None,
+			subvolumes: Vec::new(),
+			default_subvolume: None,
+			install_snapshot: false,
+			attributes: Default::default(),
+			content: None,
+			encryption: None,
+			usage: PartitionUsage::Rootfs,
+			slots,
+			empty_slot_b: false,
+			hybrid_mbr: false,
+			slot: None,
+		}
+	}
+
+	#[test]
+	fn test_expand_slots() -> Result<()> {
+		let partitions = expand_slots(vec![
+			partition(1, Some(2), SectorSize::Sectors(8388608)),
+			partition(3, None, SectorSize::Rest),
+		])?;
+		let [a, b, rest] = &partitions[..] else {
+			panic!("Expected three partitions");
+		};
+		assert_eq!((a.num, b.num, rest.num), (1, 2, 3));
+		assert_eq!(a.label.as_deref(), Some("root_a"));
+		assert_eq!(b.label.as_deref(), Some("root_b"));
+		assert_eq!(a.slot, Some(Slot::A));
+		assert_eq!(b.slot, Some(Slot::B));
+		assert_eq!(a.size_in_sectors, b.size_in_sectors);
+		assert_eq!(a.usage, PartitionUsage::Rootfs);
+		assert_eq!(a.mountpoint.as_deref(), Some("/"));
+		assert!(a.part_uuid.is_some());
+		assert_eq!(b.usage, PartitionUsage::Data);
+		assert_eq!(b.filesystem, FilesystemType::Ext4);
+		assert_eq!(b.mountpoint, None);
+		assert_eq!(b.part_uuid, None);
+		assert_eq!(rest.slot, None);
+		let mut empty = partition(1, Some(2), SectorSize::Sectors(2048));
+		empty.empty_slot_b = true;
+		let partitions = expand_slots(vec![empty])?;
+		assert_eq!(partitions[1].filesystem, FilesystemType::None);
+		Ok(())
+	}
+
+	#[test]
+	fn test_invalid_slots() {
+		let sized = |num, slots| partition(num, slots, SectorSize::Sectors(2048));
+		// Partition 2 is taken by slot B
+		assert!(expand_slots(vec![sized(1, Some(2)), sized(2, None)]).is_err());
+		assert!(expand_slots(vec![sized(1, Some(3))]).is_err());
+		assert!(expand_slots(vec![partition(1, Some(2), SectorSize::Rest)]).is_err());
+		let mut empty = sized(1, None);
+		empty.empty_slot_b = true;
+		assert!(expand_slots(vec![empty]).is_err());
+	}
+
+	#[test]
+	fn test_effective_fstype() -> Result<()> {
+		let dir = TempDir::new("effective-fstype")?;
+		let device = load_device(
+			&dir,
+			r#"partition_map = "gpt"
+
+[[partition]]
+num = 1
+type = "esp"
+usage = "boot"
+filesystem = "fat32"
+mountpoint = "/efi"
+size = "512MiB"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "4GiB"
+slots = 2
+"#,
+		)?;
+		let [efi, root_a, root_b] = &device.partitions[..] else {
+			panic!("Expected three partitions");
+		};
+		let btrfs = Some(FilesystemType::Btrfs);
+		assert_eq!(device.effective_fstype(efi, btrfs), FilesystemType::Fat32);
+		assert_eq!(
+			device.effective_fstype(root_a, btrfs),
+			FilesystemType::Btrfs
+		);
+		assert_eq!(
+			device.effective_fstype(root_b, btrfs),
+			FilesystemType::Btrfs
+		);
+		assert_eq!(device.effective_fstype(root_a, None), FilesystemType::Ext4);
+		Ok(())
+	}
+}
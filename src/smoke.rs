@@ -0,0 +1,420 @@
+//! Smoke tests of the device specifications, run by `check --smoke`.
+//!
+//! Most errors in a device specification (bad filesystem parameters,
+//! missing scripts, syntax errors in scripts, etc.) only show up in a real
+//! build. A smoke test runs the build pipeline against a miniature image:
+//!
+//! - Partitions are shrunk to the smallest size their filesystems accept,
+//!   see [`FilesystemType::min_size`].
+//! - A stub root filesystem of a few files is used instead of a bootstrapped
+//!   distribution, and no package is installed.
+//! - Scripts are checked with `bash -n` instead of being run.
+//!
+//! The image is discarded right after the test. Stages which can not be
+//! tested this way are reported as not covered.
+use std::{
+	fmt::Display,
+	fs::{self, create_dir_all},
+	path::{Path, PathBuf},
+	process::Command,
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+
+use crate::{
+	bootloader::BootloaderSpec,
+	cli::{Compression, KeepWorkdir},
+	context::{ImageContext, ImageVariant},
+	device::{DeviceSpec, ImageVariantSizes, PartitionMapType, MBR_FIRST_LOGICAL},
+	filesystem::FilesystemType,
+	interrupt,
+	joblog::JobLogger,
+	partition::{PartitionSize, PartitionUsage, SectorSize},
+	populate::PopulateBackend,
+	stage::Stage,
+	utils::{cmd_run_check_status, create_sparse_file},
+};
+
+/// Size of the stub root filesystem in MiB, on top of the minimum size of the filesystem.
+const STUB_ROOTFS_SIZE: u64 = 10;
+
+/// Result of a stage in a smoke test.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StageStatus {
+	Passed,
+	Failed(String),
+	/// The stage is not run, with the reason.
+	NotCovered(&'static str),
+}
+
+/// Results of the stages in a smoke test, in order.
+#[derive(Debug, Default)]
+pub struct SmokeReport {
+	pub stages: Vec<(&'static str, StageStatus)>,
+}
+
+impl SmokeReport {
+	/// Run a stage, returns whether it passed.
+	fn run<F: FnOnce() -> Result<()>>(&mut self, stage: &'static str, f: F) -> bool {
+		let status = match f() {
+			Ok(()) => StageStatus::Passed,
+			Err(e) => StageStatus::Failed(
+				e.chain()
+					.map(|c| c.to_string())
+					.collect::<Vec<_>>()
+					.join(": "),
+			),
+		};
+		let passed = status == StageStatus::Passed;
+		self.stages.push((stage, status));
+		passed
+	}
+
+	fn not_covered(&mut self, stage: &'static str, reason: &'static str) {
+		self.stages.push((stage, StageStatus::NotCovered(reason)));
+	}
+
+	pub fn passed(&self) -> bool {
+		!self.stages
+			.iter()
+			.any(|(_, s)| matches!(s, StageStatus::Failed(_)))
+	}
+}
+
+impl Display for SmokeReport {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		for (stage, status) in &self.stages {
+			match status {
+				StageStatus::Passed => writeln!(f, "\tPASS          {}", stage)?,
+				StageStatus::Failed(e) => {
+					writeln!(f, "\tFAIL          {}: {}", stage, e)?
+				}
+				StageStatus::NotCovered(r) => {
+					writeln!(f, "\tNOT COVERED   {} ({})", stage, r)?
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Shrink the partitions of the device, returns the miniature device and its image size in MiB.
+pub fn miniaturize(device: &DeviceSpec) -> (DeviceSpec, u64) {
+	let mut mini = device.clone();
+	// In sectors, starting after the gap before the first partition.
+	let mut end = mini.reserved_sectors();
+	let map = mini.partition_map;
+	for partition in mini.partitions.iter_mut() {
+		let align = partition.align();
+		// Logical partitions are preceded by their EBRs.
+		if map == PartitionMapType::MBR && partition.num >= MBR_FIRST_LOGICAL {
+			end = end.next_multiple_of(align) + align;
+		}
+		let min = partition.filesystem.min_size() * 2048;
+		// Partitions filling the rest of any variant fill the rest of the miniature.
+		let size = if partition.size_in_sectors.is_rest() {
+			partition.size_in_sectors = PartitionSize::Fixed(SectorSize::Rest);
+			min + STUB_ROOTFS_SIZE * 2048
+		} else {
+			let orig = partition.size_in_sectors.max_sectors().unwrap_or_default();
+			// Raw partitions are usually small, and may be required to have the exact size.
+			let size = if partition.filesystem == FilesystemType::None {
+				orig
+			} else {
+				orig.min(min)
+			};
+			partition.size_in_sectors = PartitionSize::Fixed(SectorSize::Sectors(size));
+			size
+		};
+		let start = partition.start().unwrap_or(end.next_multiple_of(align));
+		end = start + size;
+	}
+	// The backup GPT, and some space to spare.
+	let size = (end * 512).div_ceil(1 << 20) + 2;
+	mini.size = ImageVariantSizes {
+		base: size,
+		desktop: size,
+		server: size,
+	};
+	mini.image_size_round_to = None;
+	mini.trailing_pad = None;
+	(mini, size)
+}
+
+/// Create a stub root filesystem with a few files the pipeline relies on.
+fn create_stub_rootfs(root: &Path) -> Result<()> {
+	for dir in ["etc", "tmp", "boot", "usr/bin", "root"] {
+		create_dir_all(root.join(dir))?;
+	}
+	fs::write(root.join("etc/passwd"), "root:x:0:0:root:/root:/bin/bash\n")?;
+	fs::write(root.join("etc/group"), "root:x:0:\n")?;
+	fs::write(root.join("etc/shadow"), "root:*:19000:0:99999:7:::\n")?;
+	fs::write(root.join("etc/fstab"), "")?;
+	fs::write(
+		root.join("etc/os-release"),
+		"NAME=\"mkrawimg smoke test\"\n",
+	)?;
+	Ok(())
+}
+
+/// Check the syntax of a shell script without running it.
+fn check_script_syntax(path: &Path) -> Result<()> {
+	if !path.is_file() {
+		anyhow::bail!("{} not found", path.display());
+	}
+	let mut cmd = Command::new("bash");
+	cmd.arg("-n").arg(path);
+	cmd_run_check_status(&mut cmd).context(format!("Syntax error in {}", path.display()))
+}
+
+impl ImageContext<'_> {
+	/// Run the stages of a build which work on a miniature image.
+	fn smoke_stages(
+		&self,
+		report: &mut SmokeReport,
+		loop_dev_path: &Path,
+		mountdir_base: &Path,
+		stack: &mut Vec<String>,
+	) {
+		let root_num = match self
+			.device
+			.partitions
+			.iter()
+			.find(|p| p.usage == PartitionUsage::Rootfs)
+		{
+			Some(p) => p.num,
+			None => return,
+		};
+		let mut pm_data = None;
+		if !report.run("partitioning and formatting", || {
+			pm_data = Some(self.partition_disk(loop_dev_path, true)?);
+			Ok(())
+		}) {
+			return;
+		}
+		let pm_data = pm_data.unwrap();
+		let rootfs_mount = mountdir_base.join(format!("p{}", root_num));
+		if !report.run("mounting", || {
+			self.mount_partitions(loop_dev_path, mountdir_base, stack)?;
+			create_stub_rootfs(&rootfs_mount)?;
+			self.mount_partitions_in_root(loop_dev_path, rootfs_mount.as_path(), stack)
+		}) {
+			return;
+		}
+		report.not_covered(
+			"distribution installation",
+			"a stub root filesystem is used",
+		);
+		if !report.run("fstab", || self.generate_fstab(&pm_data, &rootfs_mount)) {
+			return;
+		}
+		let rootpart_dev = self.device.partition_path(loop_dev_path, root_num);
+		if !report.run("spec script", || {
+			self.setup_chroot_mounts(&rootfs_mount, stack)?;
+			self.write_spec_script(
+				&loop_dev_path,
+				&rootpart_dev,
+				&rootfs_mount,
+				&pm_data,
+			)
+		}) {
+			return;
+		}
+		report.not_covered("package installation", "no package is installed");
+		report.not_covered("kernel selection", "no kernel is installed");
+		if self.device.dtb.is_some() || !self.device.dtb_overlays.is_empty() {
+			report.not_covered("device tree blobs", "no package is installed");
+		}
+		if self.device.services.is_some() {
+			report.not_covered("services", "systemctl is not available in the stub");
+		}
+		if self.device.swap.is_some() {
+			report.not_covered("swap", "the stub root filesystem is too small");
+		}
+		if self.erofs_root().is_some() {
+			report.not_covered(
+				"EROFS root filesystem",
+				"the stub root filesystem is not built",
+			);
+		}
+		report.run("post installation script", || {
+			match self.find_postinst_script()? {
+				Some(path) => check_script_syntax(&path),
+				None => Ok(()),
+			}
+		});
+		report.run("paths", || self.setup_paths(&rootfs_mount));
+		let bootloaders = self.device.bootloaders.as_deref().unwrap_or_default();
+		let spec_dir = self.device.file_path.parent().unwrap_or(Path::new("."));
+		report.run("bootloader scripts", || {
+			for step in bootloaders {
+				if let BootloaderSpec::Script { name } = &step.spec {
+					check_script_syntax(&spec_dir.join(name))?;
+				}
+			}
+			Ok(())
+		});
+		report.run("bootloader configs", || {
+			let vars = self.device.template_vars(Some(&pm_data))?;
+			for step in bootloaders {
+				if let BootloaderSpec::Config { template, path } = &step.spec {
+					BootloaderSpec::write_config(
+						spec_dir.join(template),
+						path,
+						&rootfs_mount,
+						&vars,
+					)?;
+				}
+			}
+			Ok(())
+		});
+		let flashes = bootloaders.iter().any(|s| {
+			!matches!(
+				s.spec,
+				BootloaderSpec::Script { .. } | BootloaderSpec::Config { .. }
+			)
+		});
+		if flashes {
+			report.not_covered(
+				"bootloader images",
+				"the images are installed by packages",
+			);
+		}
+		if flashes || self.device.partition_map == PartitionMapType::None {
+			report.not_covered("metadata", "depends on the bootloader images");
+		} else {
+			report.run("metadata", || {
+				let size = self.get_image_size(self.device.size.base);
+				self.write_metadata(rootfs_mount.as_path(), loop_dev_path, size)
+			});
+		}
+		report.not_covered("compression", "the image is discarded");
+	}
+
+	/// Run the smoke test, the image is discarded afterwards.
+	fn smoke_test(&self, size: u64) -> Result<SmokeReport> {
+		let _job = self.logger.enter();
+		let workdir_base = self.workdir.join(format!("smoke/{}", &self.device.id));
+		let mountdir_base = workdir_base.join("mnt");
+		create_dir_all(&mountdir_base)?;
+		let rawimg_path = workdir_base.join("rawmedia.img");
+		if rawimg_path.is_file() {
+			fs::remove_file(&rawimg_path)?;
+		}
+		create_sparse_file(&rawimg_path, self.get_image_size(size))?;
+		let _interrupt = interrupt::register(Some(&workdir_base), &rawimg_path);
+		let (loop_dev, loop_dev_path) = Self::attach_loop_device(&rawimg_path)?;
+		let mut report = SmokeReport::default();
+		let mut stack = Vec::new();
+		self.smoke_stages(&mut report, &loop_dev_path, &mountdir_base, &mut stack);
+		debug!("Cleaning up the smoke test of {} ...", &self.device.id);
+		// Detach even if unmounting failed, or the loop device is leaked.
+		let umounted = Self::umount_stack(&mut stack);
+		loop_dev.detach()?;
+		umounted?;
+		fs::remove_dir_all(&workdir_base)?;
+		Ok(report)
+	}
+}
+
+/// Run smoke tests for the devices, and report the results like `check` does.
+pub fn smoke_test_devices(
+	devices: &[DeviceSpec],
+	workdir: &Path,
+	outdir: &Path,
+	build_id: &str,
+) -> Result<()> {
+	let mut failed = Vec::new();
+	for device in devices {
+		let (mini, size) = miniaturize(device);
+		info!(
+			"Smoke testing {} ({}) with a {} MiB image ...",
+			&device.id, &device.name, size
+		);
+		let ctx = ImageContext {
+			device: &mini,
+			variant: &ImageVariant::Base,
+			workdir,
+			outdir,
+			user: Some("root"),
+			password: "",
+			password_hashed: false,
+			hostname: None,
+			timezone: None,
+			ssh_keys: &[],
+			root_ssh_keys: &[],
+			customize_scripts: &[],
+			keep_workdir: KeepWorkdir::Always,
+			stages: &Stage::ALL,
+			shrink: false,
+			fsck: false,
+			populate_backend: PopulateBackend::Mount,
+			expire_password: false,
+			public_artifacts: false,
+			filename: String::new(),
+			revision: None,
+			reserved: false,
+			base_dist: PathBuf::new(),
+			rootfs_source: None,
+			offline: false,
+			override_rootfs_fstype: &None,
+			additional_packages: &None,
+			compress: &Compression::None,
+			compress_level: None,
+			compress_threads: None,
+			topics: None,
+			image_size: None,
+			image_size_round_to: None,
+			trailing_pad: None,
+			build_id,
+			logger: JobLogger::new(&device.id, &ImageVariant::Base),
+			force: false,
+			seed: None,
+		};
+		let report = ctx.smoke_test(size)?;
+		if report.passed() {
+			info!(
+				"PASS: {} ({})\n\t{}\n{}",
+				&device.id,
+				&device.name,
+				&device.file_path.display(),
+				report
+			);
+		} else {
+			error!(
+				"FAIL: {} ({})\n\t{}\n{}",
+				&device.id,
+				&device.name,
+				&device.file_path.display(),
+				report
+			);
+			failed.push(device.id.as_str());
+		}
+	}
+	if !failed.is_empty() {
+		anyhow::bail!("Smoke test failed for: {}", failed.join(", "));
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::registry::DeviceRegistry;
+
+	#[test]
+	fn test_miniaturize() -> Result<()> {
+		for device in DeviceRegistry::scan("devices")?.get_all()? {
+			let (mini, size) = miniaturize(&device);
+			assert!(size <= device.size.base, "{} is not shrunk", &device.id);
+			for (p, orig) in mini.partitions.iter().zip(&device.partitions) {
+				assert!(p.size_in_sectors.max_sectors()
+					<= orig.size_in_sectors.max_sectors());
+				assert_eq!(p.start_sector, orig.start_sector);
+			}
+			mini.check()?;
+		}
+		Ok(())
+	}
+}
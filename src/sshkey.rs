@@ -0,0 +1,378 @@
+//! SSH public keys installed into the images, with `--ssh-key` and `--root-ssh-key`.
+//!
+//! Each argument is either a public key itself (`ssh-ed25519 AAAA... comment`)
+//! or the path to a file of public keys, one per line like
+//! `authorized_keys`. Every key is checked to be a valid OpenSSH public key
+//! line before anything is built, so a truncated or mistyped key does not
+//! leave a headless device unreachable. Options in front of the key (e.g.
+//! `from="10.0.0.1",no-pty ssh-ed25519 AAAA...`) are kept as they are.
+//!
+//! The keys are appended to `~/.ssh/authorized_keys` of the user, which is
+//! owned by the user with the mode `0600` (`0700` for `~/.ssh`). The user,
+//! its home and its IDs are resolved from `/etc/passwd` of the target, as the
+//! IDs on the host have nothing to do with the ones in the image.
+use std::{
+	fmt::Display,
+	fs::{self, File},
+	io::Write,
+	os::unix::fs::{lchown, PermissionsExt},
+	path::{Component, Path, PathBuf},
+	str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::context::ImageContext;
+
+pub(crate) const PASSWD_PATH: &str = "etc/passwd";
+
+/// Key types accepted by OpenSSH, with the number of fields in their keys
+/// (including the type itself).
+pub const KEY_TYPES: &[(&str, usize)] = &[
+	("ssh-ed25519", 2),
+	("ssh-rsa", 3),
+	("ecdsa-sha2-nistp256", 3),
+	("ecdsa-sha2-nistp384", 3),
+	("ecdsa-sha2-nistp521", 3),
+	("sk-ssh-ed25519@openssh.com", 3),
+	("sk-ecdsa-sha2-nistp256@openssh.com", 4),
+	("ssh-dss", 5),
+];
+
+fn is_key_type(s: &str) -> bool {
+	KEY_TYPES.iter().any(|(t, _)| *t == s)
+}
+
+/// Split the key into its length prefixed fields, `None` if it is truncated.
+fn split_fields(mut data: &[u8]) -> Option<Vec<&[u8]>> {
+	let mut fields = Vec::new();
+	while !data.is_empty() {
+		let len = data
+			.get(..4)
+			.map(|l| u32::from_be_bytes([l[0], l[1], l[2], l[3]]) as usize)?;
+		fields.push(data.get(4..4 + len)?);
+		data = &data[4 + len..];
+	}
+	Some(fields)
+}
+
+/// Split the options of an `authorized_keys` line (e.g. `no-pty,from="10.0.0.1"`)
+/// from the key following them, `None` if the line does not start with options.
+fn split_options(s: &str) -> Option<(&str, &str)> {
+	let mut quoted = false;
+	let mut escaped = false;
+	for (i, c) in s.char_indices() {
+		match c {
+			_ if escaped => escaped = false,
+			'\\' if quoted => escaped = true,
+			'"' => quoted = !quoted,
+			c if c.is_whitespace() && !quoted => {
+				let key = s[i..].trim_start();
+				let key_type = key.split_whitespace().next().unwrap_or_default();
+				let options = &s[..i];
+				return (!is_key_type(options) && is_key_type(key_type))
+					.then_some((options, key));
+			}
+			_ => (),
+		}
+	}
+	None
+}
+
+/// A valid OpenSSH public key, e.g. `ssh-ed25519 AAAAC3Nza... user@host`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SshPublicKey {
+	/// Options of the `authorized_keys` line, e.g. `no-pty,from="10.0.0.1"`.
+	pub options: Option<String>,
+	pub key_type: String,
+	pub blob: String,
+	pub comment: Option<String>,
+}
+
+impl FromStr for SshPublicKey {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		let (options, s) = match split_options(s.trim()) {
+			Some((options, key)) => (Some(options.to_owned()), key),
+			None => (None, s),
+		};
+		let mut fields = s.split_whitespace();
+		let key_type = fields.next().context("Empty SSH public key")?;
+		let num_fields =
+			match KEY_TYPES.iter().find(|(t, _)| *t == key_type) {
+				Some((_, n)) => *n,
+				None => {
+					bail!(
+				"Unsupported SSH key type '{}', possible types are: {}",
+				key_type,
+				KEY_TYPES.iter().map(|(t, _)| *t).collect::<Vec<_>>().join(", ")
+			)
+				}
+			};
+		let blob = fields
+			.next()
+			.context(format!("SSH public key of type {} has no key", key_type))?;
+		let data = STANDARD.decode(blob).context(format!(
+			"Key of the {} public key is not valid base64",
+			key_type
+		))?;
+		// The key starts with its type, followed by the parameters of the key.
+		let valid = split_fields(&data).is_some_and(|f| {
+			f.len() == num_fields
+				&& f[0] == key_type.as_bytes()
+				&& f[1..].iter().all(|p| !p.is_empty())
+		});
+		if !valid {
+			bail!(
+				"Key of the {} public key is corrupted or of another type",
+				key_type
+			);
+		}
+		let comment = fields.collect::<Vec<_>>().join(" ");
+		Ok(Self {
+			options,
+			key_type: key_type.to_owned(),
+			blob: blob.to_owned(),
+			comment: (!comment.is_empty()).then_some(comment),
+		})
+	}
+}
+
+impl Display for SshPublicKey {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		if let Some(options) = &self.options {
+			write!(f, "{} ", options)?;
+		}
+		write!(f, "{} {}", self.key_type, self.blob)?;
+		if let Some(comment) = &self.comment {
+			write!(f, " {}", comment)?;
+		}
+		Ok(())
+	}
+}
+
+/// Load the keys from the arguments, each of them is a key or a file of keys.
+pub fn load_ssh_keys(args: &[String]) -> Result<Vec<SshPublicKey>> {
+	let mut keys = Vec::new();
+	for arg in args {
+		let first = arg.split_whitespace().next().unwrap_or_default();
+		if is_key_type(first) || split_options(arg.trim()).is_some() {
+			keys.push(arg
+				.parse()
+				.context(format!("Invalid SSH public key '{}'", arg))?);
+			continue;
+		}
+		let content = fs::read_to_string(arg)
+			.context(format!("Failed to read SSH public keys from {}", arg))?;
+		let mut found = false;
+		for (num, line) in content.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			keys.push(line.parse().context(format!(
+				"Invalid SSH public key at {}:{}",
+				arg,
+				num + 1
+			))?);
+			found = true;
+		}
+		if !found {
+			bail!("No SSH public key is found in {}", arg);
+		}
+	}
+	Ok(keys)
+}
+
+/// Find the UID, GID and home directory of the user in the passwd database.
+pub(crate) fn lookup_user(passwd: &str, name: &str) -> Option<(u32, u32, PathBuf)> {
+	passwd.lines()
+		.map(|l| l.split(':').collect::<Vec<_>>())
+		.find(|f| f.len() > 5 && f[0] == name)
+		.and_then(|f| Some((f[2].parse().ok()?, f[3].parse().ok()?, PathBuf::from(f[5]))))
+}
+
+/// Append the keys to `~/.ssh/authorized_keys` of the user in the target root.
+pub fn install_ssh_keys(root: &Path, user: &str, keys: &[SshPublicKey]) -> Result<()> {
+	if keys.is_empty() {
+		return Ok(());
+	}
+	let passwd = root.join(PASSWD_PATH);
+	let content = fs::read_to_string(&passwd)
+		.context(format!("Failed to read {}", passwd.display()))?;
+	let (uid, gid, home) = lookup_user(&content, user)
+		.context(format!("User '{}' is not found in the target", user))?;
+	if !home.is_absolute() || home.components().any(|c| c == Component::ParentDir) {
+		bail!(
+			"Invalid home directory '{}' of user '{}'",
+			home.display(),
+			user
+		);
+	}
+	let home = root.join(home.strip_prefix("/")?);
+	// Absolute symlinks in the target must not lead us to the host.
+	for path in [&home, &home.join(".ssh")] {
+		if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()) {
+			bail!(
+				"{} is a symbolic link, refusing to follow it",
+				path.display()
+			);
+		}
+	}
+	if !home.is_dir() {
+		fs::create_dir_all(&home)?;
+		lchown(&home, Some(uid), Some(gid))?;
+	}
+	let ssh_dir = home.join(".ssh");
+	fs::create_dir_all(&ssh_dir)?;
+	fs::set_permissions(&ssh_dir, fs::Permissions::from_mode(0o700))?;
+	lchown(&ssh_dir, Some(uid), Some(gid))?;
+	let authorized_keys = ssh_dir.join("authorized_keys");
+	if fs::symlink_metadata(&authorized_keys).is_ok_and(|m| !m.is_file()) {
+		bail!("{} is not a regular file", authorized_keys.display());
+	}
+	let existing = fs::read_to_string(&authorized_keys).unwrap_or_default();
+	let mut fd = File::options()
+		.append(true)
+		.create(true)
+		.open(&authorized_keys)
+		.context(format!("Failed to open {}", authorized_keys.display()))?;
+	if !existing.is_empty() && !existing.ends_with('\n') {
+		writeln!(fd)?;
+	}
+	// Do not add the same key twice.
+	let mut installed = existing
+		.lines()
+		.filter_map(|l| l.parse::<SshPublicKey>().ok())
+		.map(|k| k.blob)
+		.collect::<Vec<_>>();
+	for key in keys {
+		if !installed.contains(&key.blob) {
+			writeln!(fd, "{}", key)?;
+			installed.push(key.blob.clone());
+		}
+	}
+	fd.sync_all()?;
+	fs::set_permissions(&authorized_keys, fs::Permissions::from_mode(0o600))?;
+	lchown(&authorized_keys, Some(uid), Some(gid))?;
+	Ok(())
+}
+
+impl ImageContext<'_> {
+	/// The SSH public keys of the built-in user (if any) and root.
+	pub fn ssh_keys_by_user(&self) -> Vec<(&str, &[SshPublicKey])> {
+		let mut keys = vec![("root", self.root_ssh_keys)];
+		if let Some(user) = self.user {
+			keys.insert(0, (user, self.ssh_keys));
+		}
+		keys
+	}
+
+	/// Install the SSH public keys of the built-in user and root.
+	pub fn install_ssh_keys(&self, rootdir: &Path) -> Result<()> {
+		for (user, keys) in self.ssh_keys_by_user() {
+			if keys.is_empty() {
+				continue;
+			}
+			self.info(format!(
+				"Installing {} SSH public key(s) for {} ...",
+				keys.len(),
+				user
+			));
+			install_ssh_keys(rootdir, user, keys)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::os::unix::fs::MetadataExt;
+
+	use super::*;
+	use crate::testutil::TempDir;
+
+	const ED25519: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHKHxs0LCaOdYpNJMeDSUbzpoBc3aARhbJGpMAFaaZOc user@host";
+
+	#[test]
+	fn test_parse_ssh_key() -> Result<()> {
+		let key: SshPublicKey = ED25519.parse()?;
+		assert_eq!(key.key_type, "ssh-ed25519");
+		assert_eq!(key.comment.as_deref(), Some("user@host"));
+		assert_eq!(key.to_string(), ED25519);
+		let key: SshPublicKey = ED25519.rsplit_once(' ').unwrap().0.parse()?;
+		assert_eq!(key.comment, None);
+		// Truncated
+		assert!(ED25519[..40].parse::<SshPublicKey>().is_err());
+		// Type mismatch
+		assert!(ED25519
+			.replace("ssh-ed25519", "ssh-rsa")
+			.parse::<SshPublicKey>()
+			.is_err());
+		assert!("ssh-ed25519".parse::<SshPublicKey>().is_err());
+		assert!("ssh-foo AAAA".parse::<SshPublicKey>().is_err());
+		assert!("ssh-ed25519 aGVs*G8=".parse::<SshPublicKey>().is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_parse_ssh_key_options() -> Result<()> {
+		let line = format!(
+			"from=\"10.0.0.1\",command=\"echo \\\"hello world\\\"\",no-pty {}",
+			ED25519
+		);
+		let key: SshPublicKey = line.parse()?;
+		assert_eq!(
+			key.options.as_deref(),
+			Some("from=\"10.0.0.1\",command=\"echo \\\"hello world\\\"\",no-pty")
+		);
+		assert_eq!(key.key_type, "ssh-ed25519");
+		assert_eq!(key.comment.as_deref(), Some("user@host"));
+		assert_eq!(key.to_string(), line);
+		assert_eq!(load_ssh_keys(&[line])?, [key]);
+		// Not options followed by a key
+		assert!(format!("no-pty ssh-foo {}", ED25519)
+			.parse::<SshPublicKey>()
+			.is_err());
+		assert!("command=\"echo ssh-ed25519 AAAA"
+			.parse::<SshPublicKey>()
+			.is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_install_ssh_keys() -> Result<()> {
+		let root = TempDir::new("sshkey")?;
+		let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+		fs::create_dir_all(root.join("etc"))?;
+		fs::write(
+			root.join(PASSWD_PATH),
+			format!("root:x:0:0:root:/root:/bin/bash\naosc:x:{}:{}::/home/aosc:/bin/bash\n", uid, gid),
+		)?;
+		let file = root.join("keys.pub");
+		fs::write(&file, format!("# Keys\n\n{}\n", ED25519))?;
+		let keys = load_ssh_keys(&[file.display().to_string(), ED25519.to_owned()])?;
+		assert_eq!(keys.len(), 2);
+		install_ssh_keys(&root, "aosc", &keys)?;
+		install_ssh_keys(&root, "aosc", &keys)?;
+		let path = root.join("home/aosc/.ssh/authorized_keys");
+		assert_eq!(fs::read_to_string(&path)?, format!("{}\n", ED25519));
+		let meta = fs::metadata(&path)?;
+		assert_eq!(meta.mode() & 0o777, 0o600);
+		assert_eq!((meta.uid(), meta.gid()), (uid, gid));
+		assert_eq!(
+			fs::metadata(root.join("home/aosc/.ssh"))?.mode() & 0o777,
+			0o700
+		);
+		let err = install_ssh_keys(&root, "kiosk", &keys)
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("'kiosk' is not found"));
+		fs::write(&file, "ssh-ed25519 AAAA\n")?;
+		let err = load_ssh_keys(&[file.display().to_string()]).unwrap_err();
+		assert!(err.to_string().contains("keys.pub:1"));
+		Ok(())
+	}
+}
@@ -0,0 +1,310 @@
+//! Running only part of the build pipeline, with `--stages`.
+//!
+//! The build of an image is split into the following stages, in order:
+//!
+//! - `partition`: Create the raw image and write the partition table.
+//! - `format`: Create the filesystems, and write the raw contents of the
+//!   partitions from the directory of `device.toml`.
+//! - `populate`: Install the system distribution, the BSP packages and the
+//!   kernel, set up the services and the swap space, and install the unit
+//!   growing the image on the first boot with `--shrink`.
+//! - `postinst`: Set up the user, run the post installation script, create
+//!   the declared paths and run the customize scripts.
+//! - `bootloader`: Apply the bootloaders, write the raw contents of the
+//!   partitions from the target and write the image metadata.
+//! - `compress`: Shrink the raw image with `--shrink`, then compress (or
+//!   copy) it to the output directory.
+//!
+//! The selected stages must be consecutive. After each stage, a marker
+//! ([`MARKER_NAME`]) is saved in the sketch directory, recording the last
+//! finished stage and the UUIDs of the partitions. A build starting with a
+//! later stage reuses the raw image in the sketch directory, which must have
+//! finished the stage before it, e.g. `--stages bootloader,compress` requires
+//! an image which finished `postinst`:
+//!
+//! ```shell
+//! # mkrawimg --keep-workdir always build rpi-5b
+//! # mkrawimg --stages bootloader,compress build rpi-5b
+//! ```
+//!
+//! The sketch directory of a build which does not run every stage is kept
+//! with `--keep-workdir on-failure`.
+//!
+//! The marker also records the time each stage took, including the stages
+//! finished by the earlier builds, which ends up in the build report.
+use std::{
+	fs,
+	path::{Path, PathBuf},
+	time::Instant,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use strum::Display;
+
+use crate::{context::ImageContext, device::PartitionMapData, utils::SysrootStrategy};
+
+/// Name of the marker in the sketch directory.
+pub const MARKER_NAME: &str = "stage.json";
+
+/// A stage of the build pipeline, see the [module documentation](self).
+#[derive(
+	Copy,
+	Clone,
+	Debug,
+	Display,
+	PartialEq,
+	Eq,
+	PartialOrd,
+	Ord,
+	ValueEnum,
+	Serialize,
+	Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+	Partition,
+	Format,
+	Populate,
+	Postinst,
+	Bootloader,
+	Compress,
+}
+
+impl Stage {
+	/// Every stage, in order.
+	pub const ALL: [Stage; 6] = [
+		Self::Partition,
+		Self::Format,
+		Self::Populate,
+		Self::Postinst,
+		Self::Bootloader,
+		Self::Compress,
+	];
+
+	/// Sort the stages in the pipeline order, every stage if empty.
+	///
+	/// Fails if the stages are not consecutive.
+	pub fn normalize(stages: &[Stage]) -> Result<Vec<Stage>> {
+		if stages.is_empty() {
+			return Ok(Self::ALL.to_vec());
+		}
+		let mut stages = stages.to_vec();
+		stages.sort();
+		stages.dedup();
+		let first = Self::ALL.iter().position(|s| *s == stages[0]).unwrap();
+		if Self::ALL[first..].iter().zip(&stages).any(|(a, b)| a != b) {
+			bail!("Stages must be consecutive, got: {}", Self::join(&stages));
+		}
+		Ok(stages)
+	}
+
+	/// The stage before this one.
+	pub fn previous(self) -> Option<Stage> {
+		let idx = Self::ALL.iter().position(|s| *s == self).unwrap();
+		idx.checked_sub(1).map(|idx| Self::ALL[idx])
+	}
+
+	/// Names of the stages, separated by commas.
+	pub fn join(stages: &[Stage]) -> String {
+		stages.iter()
+			.map(|s| s.to_string().to_lowercase())
+			.collect::<Vec<_>>()
+			.join(", ")
+	}
+}
+
+/// Time a stage took.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct StageTiming {
+	pub stage: Stage,
+	/// In seconds.
+	pub duration: f64,
+	/// How the distribution is installed in `populate`, `None` if it is
+	/// extracted from `--rootfs-source`.
+	#[serde(default)]
+	pub strategy: Option<SysrootStrategy>,
+}
+
+impl StageTiming {
+	/// The stage started at `start`, and finished just now.
+	pub fn since(stage: Stage, start: Instant) -> Self {
+		Self {
+			stage,
+			duration: start.elapsed().as_secs_f64(),
+			strategy: None,
+		}
+	}
+}
+
+/// Saved in the sketch directory after each stage.
+#[derive(Serialize, Deserialize)]
+pub struct StageMarker {
+	/// The last finished stage.
+	pub stage: Stage,
+	pub pm_data: PartitionMapData,
+	/// The finished stages, in order.
+	#[serde(default)]
+	pub timings: Vec<StageTiming>,
+}
+
+impl StageMarker {
+	fn path(sketch_dir: &Path) -> PathBuf {
+		sketch_dir.join(MARKER_NAME)
+	}
+
+	pub fn load(sketch_dir: &Path) -> Result<Option<Self>> {
+		let path = Self::path(sketch_dir);
+		if !path.exists() {
+			return Ok(None);
+		}
+		let content = fs::read_to_string(&path)?;
+		let marker = serde_json::from_str(&content)
+			.context(format!("Invalid stage marker {}", path.display()))?;
+		Ok(Some(marker))
+	}
+
+	pub fn save(&self, sketch_dir: &Path) -> Result<()> {
+		fs::write(Self::path(sketch_dir), serde_json::to_string_pretty(self)?)?;
+		Ok(())
+	}
+
+	pub fn remove(sketch_dir: &Path) -> Result<()> {
+		let path = Self::path(sketch_dir);
+		if path.exists() {
+			fs::remove_file(path)?;
+		}
+		Ok(())
+	}
+}
+
+impl ImageContext<'_> {
+	/// Whether the stage is selected.
+	pub(crate) fn runs(&self, stage: Stage) -> bool {
+		self.stages.contains(&stage)
+	}
+
+	/// Whether only part of the stages are selected.
+	pub(crate) fn is_partial(&self) -> bool {
+		self.stages.len() != Stage::ALL.len()
+	}
+
+	/// Find the partition data of the raw image left by an earlier build,
+	/// which must have finished the stage before `first`.
+	pub(crate) fn resume_stages(&self, first: Stage) -> Result<PartitionMapData> {
+		let sketch_dir = self.sketch_dir();
+		let required = first.previous().context("Nothing to resume")?;
+		let stage_name = |s: Stage| s.to_string().to_lowercase();
+		if !sketch_dir.join("rawmedia.img").is_file() {
+			bail!(
+				"No raw image in {} to start with stage '{}', run the earlier stages first",
+				sketch_dir.display(),
+				stage_name(first)
+			);
+		}
+		let marker = StageMarker::load(&sketch_dir)?.context(format!(
+			"No stage marker in {}, the raw image is not left by a build of this version",
+			sketch_dir.display()
+		))?;
+		if marker.stage < required {
+			bail!(
+				"Stage '{}' requires an image which finished stage '{}', but it only finished stage '{}'",
+				stage_name(first),
+				stage_name(required),
+				stage_name(marker.stage)
+			);
+		}
+		self.info(format!(
+			"Resuming the raw image which finished stage '{}'",
+			stage_name(marker.stage)
+		));
+		Ok(marker.pm_data)
+	}
+
+	/// Record the stage as finished, after the ones finished before it.
+	pub(crate) fn finish_stage(
+		&self,
+		timing: StageTiming,
+		pm_data: &PartitionMapData,
+	) -> Result<()> {
+		let sketch_dir = self.sketch_dir();
+		let mut timings = self.stage_timings()?;
+		// Run again.
+		timings.retain(|t| t.stage < timing.stage);
+		let marker = StageMarker {
+			stage: timing.stage,
+			pm_data: pm_data.clone(),
+			timings: [timings, vec![timing]].concat(),
+		};
+		marker.save(&sketch_dir)
+	}
+
+	/// Time each of the finished stages took, in order.
+	pub(crate) fn stage_timings(&self) -> Result<Vec<StageTiming>> {
+		Ok(StageMarker::load(&self.sketch_dir())?
+			.map(|m| m.timings)
+			.unwrap_or_default())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::*;
+	use crate::testutil::{load_device, test_context, TempDir};
+
+	#[test]
+	fn test_normalize_stages() -> Result<()> {
+		assert_eq!(Stage::normalize(&[])?, Stage::ALL);
+		assert_eq!(
+			Stage::normalize(&[Stage::Compress, Stage::Bootloader, Stage::Compress])?,
+			[Stage::Bootloader, Stage::Compress]
+		);
+		assert_eq!(Stage::normalize(&[Stage::Format])?, [Stage::Format]);
+		assert!(Stage::normalize(&[Stage::Partition, Stage::Compress]).is_err());
+		assert_eq!(Stage::Partition.previous(), None);
+		assert_eq!(Stage::Compress.previous(), Some(Stage::Bootloader));
+		Ok(())
+	}
+
+	#[test]
+	fn test_stage_timings() -> Result<()> {
+		let dir = TempDir::new("stage-timings")?;
+		let device = load_device(
+			&dir,
+			"partition_map = \"gpt\"\n\n[[partition]]\nnum = 1\ntype = \"linux\"\nusage = \"rootfs\"\nfilesystem = \"ext4\"\nmountpoint = \"/\"\nsize = \"rest\"\n",
+		)?;
+		let context = test_context(&device, &dir);
+		fs::create_dir_all(context.sketch_dir())?;
+		let pm_data = PartitionMapData {
+			uuid: "disk".to_owned(),
+			data: HashMap::new(),
+		};
+		let timing = |stage: Stage, duration: f64| StageTiming {
+			stage,
+			duration,
+			strategy: None,
+		};
+		assert!(context.stage_timings()?.is_empty());
+		context.finish_stage(timing(Stage::Partition, 1.0), &pm_data)?;
+		context.finish_stage(timing(Stage::Format, 2.0), &pm_data)?;
+		// The stages run again replace the earlier timings.
+		context.finish_stage(timing(Stage::Format, 3.0), &pm_data)?;
+		let populate = StageTiming {
+			strategy: Some(SysrootStrategy::Rsync),
+			..timing(Stage::Populate, 4.0)
+		};
+		context.finish_stage(populate.clone(), &pm_data)?;
+		assert_eq!(
+			context.stage_timings()?,
+			[
+				timing(Stage::Partition, 1.0),
+				timing(Stage::Format, 3.0),
+				populate
+			]
+		);
+		Ok(())
+	}
+}
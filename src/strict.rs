@@ -0,0 +1,594 @@
+//! Unknown keys in the device specifications.
+//!
+//! Unknown keys are ignored by the parser, so a misspelled key (e.g.
+//! `partitons` or `alias`) silently falls back to the default. Every
+//! specification file, including its bases, is compared against the keys
+//! known to this version of mkrawimg.
+//!
+//! The keys are known by type, mirroring the structures they are parsed
+//! into. The tables parsed by their `type`, e.g. the bootloader steps, only
+//! know the keys of that type: `offset` in a `script` step is reported, as
+//! the step ignores it.
+//!
+//!
+//! - `mkrawimg check` and the builds warn about the unknown keys, with the
+//!   line numbers in the files.
+//! - `mkrawimg check --strict` fails the devices having unknown keys.
+//!
+//! Keys unknown to an older version of mkrawimg are thus only warnings,
+//! unless the registry opts in to `--strict`, e.g. in the CI.
+use std::{
+	fmt::Display,
+	fs,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use toml::{Table, Value};
+
+use crate::{
+	extends::{spec_files, EXTENDS_KEY, MERGE_KEY},
+	variables::VARIABLES_KEY,
+};
+
+/// The key selecting the variant of a tagged table.
+const TAG_KEY: &str = "type";
+
+/// A known key, with the known keys of the table (or the array of tables) it holds.
+struct Key {
+	name: &'static str,
+	nested: Option<Nested>,
+}
+
+/// Known keys of a nested table.
+#[derive(Clone, Copy)]
+enum Nested {
+	Keys(fn() -> &'static [Key]),
+	/// The common keys, and the keys by the value of [`TAG_KEY`].
+	Tagged(fn() -> &'static [Key], fn() -> &'static [Variant]),
+}
+
+/// Keys of a tagged table of the type.
+struct Variant {
+	tag: &'static str,
+	keys: &'static [Key],
+}
+
+const fn key(name: &'static str) -> Key {
+	Key { name, nested: None }
+}
+
+const fn table(name: &'static str, keys: fn() -> &'static [Key]) -> Key {
+	Key {
+		name,
+		nested: Some(Nested::Keys(keys)),
+	}
+}
+
+const fn tagged(
+	name: &'static str,
+	common: fn() -> &'static [Key],
+	variants: fn() -> &'static [Variant],
+) -> Key {
+	Key {
+		name,
+		nested: Some(Nested::Tagged(common, variants)),
+	}
+}
+
+const fn variant(tag: &'static str, keys: &'static [Key]) -> Variant {
+	Variant { tag, keys }
+}
+
+/// Common keys of the tagged tables having no other common keys.
+const TAG_KEYS: &[Key] = &[key(TAG_KEY)];
+
+const DEVICE_KEYS: &[Key] = &[
+	key(EXTENDS_KEY),
+	key(MERGE_KEY),
+	// Any name is allowed in the table.
+	key(VARIABLES_KEY),
+	key("id"),
+	key("aliases"),
+	key("tags"),
+	key("distro"),
+	key("vendor"),
+	key("arch"),
+	key("soc_vendor"),
+	key("name"),
+	key("model"),
+	key("compatible"),
+	key("hostname"),
+	key("timezone"),
+	table("bsp_packages", || VARIANT_KEYS),
+	key("initrdless"),
+	key("fstab_style"),
+	key("disk_device"),
+	key("kernel_cmdline"),
+	key("dtb"),
+	key("dtb_overlays"),
+	key("deprecated"),
+	key("deprecation_reason"),
+	table("metadata", || METADATA_KEYS),
+	key("partition_map"),
+	key("disk_uuid"),
+	key("disk_guid"),
+	key("disk_signature"),
+	key("num_partitions"),
+	table("size", || VARIANT_KEYS),
+	table("partitions", || PARTITION_KEYS),
+	table("partition", || PARTITION_KEYS),
+	tagged("bootloaders", || STEP_KEYS, || BOOTLOADER_VARIANTS),
+	tagged("bootloader", || STEP_KEYS, || BOOTLOADER_VARIANTS),
+	table("services", || SERVICES_KEYS),
+	table("kernel", || KERNEL_KEYS),
+	tagged("swap", || TAG_KEYS, || SWAP_VARIANTS),
+	table("paths", || PATH_KEYS),
+	table("recipes", || VARIANT_KEYS),
+	key("image_size_round_to"),
+	key("first_partition_offset"),
+	key("metadata_offset"),
+	key("trailing_pad"),
+	key("target_media_capacity"),
+	key("name_template"),
+];
+
+/// Keys of the tables by variant, `common` is only used by `bsp_packages`.
+const VARIANT_KEYS: &[Key] = &[key("common"), key("base"), key("desktop"), key("server")];
+
+const PARTITION_KEYS: &[Key] = &[
+	key("num"),
+	key("no"),
+	key("type"),
+	key("uuid"),
+	key("byte"),
+	key("alias"),
+	key("name"),
+	key("table_type"),
+	key("priority"),
+	key("tries"),
+	key("successful"),
+	table("partitions", || PARTITION_KEYS),
+	key("start_sector"),
+	key("start"),
+	key("align"),
+	table("size_in_sectors", || VARIANT_KEYS),
+	table("size", || VARIANT_KEYS),
+	key("label"),
+	key("mountpoint"),
+	key("filesystem"),
+	key("mount_opts"),
+	key("fs_label"),
+	key("part_uuid"),
+	key("fs_uuid"),
+	key("fs_compression"),
+	key("mkfs_options"),
+	key("fstab_style"),
+	key("ext4_reserved_percent"),
+	key("ext4_default_mount_opts"),
+	key("ext4_check_interval"),
+	table("subvolumes", || SUBVOLUME_KEYS),
+	key("default_subvolume"),
+	key("install_snapshot"),
+	key("attributes"),
+	tagged("content", || TAG_KEYS, || CONTENT_VARIANTS),
+	tagged("encryption", || TAG_KEYS, || ENCRYPTION_VARIANTS),
+	key("usage"),
+	key("slots"),
+	key("empty_slot_b"),
+	key("hybrid_mbr"),
+];
+
+const SUBVOLUME_KEYS: &[Key] = &[key("name"), key("mountpoint"), key("mount_opts")];
+
+const CONTENT_VARIANTS: &[Variant] = &[variant("raw", &[key("path"), key("from_target")])];
+
+const ENCRYPTION_VARIANTS: &[Variant] = &[variant(
+	"luks2",
+	&[
+		key("passphrase_file"),
+		key("key_slot"),
+		key("pbkdf"),
+		key("iter_time"),
+	],
+)];
+
+const METADATA_KEYS: &[Key] = &[
+	key("wiki_url"),
+	key("flash_instructions"),
+	key("maintainer"),
+];
+
+const SERVICES_KEYS: &[Key] = &[key("enable"), key("disable"), key("mask"), key("strict")];
+
+const KERNEL_KEYS: &[Key] = &[key("flavor"), key("version")];
+
+const SWAP_VARIANTS: &[Variant] = &[
+	variant("zram", &[key("ram_fraction"), key("compression_algorithm")]),
+	variant("file", &[key("size"), key("path")]),
+];
+
+const PATH_KEYS: &[Key] = &[
+	key("path"),
+	key("type"),
+	key("mode"),
+	key("owner"),
+	key("group"),
+	key("target"),
+	key("content"),
+];
+
+/// Keys of every bootloader step, see [`crate::bootloader::BootloaderStep`].
+const STEP_KEYS: &[Key] = &[key("id"), key("after"), key("output"), key(TAG_KEY)];
+
+/// Keys of the bootloader steps by type, see [`crate::bootloader::BootloaderSpec`].
+const BOOTLOADER_VARIANTS: &[Variant] = &[
+	variant("script", &[key("name")]),
+	variant(
+		"flash_partition",
+		&[
+			key("path"),
+			key("partition"),
+			table("source", || SOURCE_KEYS),
+		],
+	),
+	variant(
+		"flash_offset",
+		&[
+			key("path"),
+			table("source", || SOURCE_KEYS),
+			key("offset"),
+			key("reserved"),
+		],
+	),
+	variant("config", &[key("template"), key("path")]),
+	variant(
+		"extlinux",
+		&[
+			key("partition"),
+			key("menu_title"),
+			key("timeout"),
+			key("kernel"),
+			key("initrd"),
+			key("fdt"),
+			key("fdtdir"),
+			key("append"),
+			table("entries", || EXTLINUX_ENTRY_KEYS),
+		],
+	),
+	// The source of a script is a path, rather than a download.
+	variant(
+		"uboot_script",
+		&[key("source"), key("script"), key("path"), key("max_size")],
+	),
+	variant(
+		"grub_efi",
+		&[
+			key("target"),
+			key("esp"),
+			key("removable"),
+			key("bootloader_id"),
+			key("config"),
+		],
+	),
+	variant(
+		"systemd_boot",
+		&[
+			key("esp"),
+			key("timeout"),
+			key("default"),
+			key("title"),
+			key("entry_id"),
+			key("append"),
+		],
+	),
+	variant(
+		"raw",
+		&[
+			key("file"),
+			key("from_target"),
+			table("source", || SOURCE_KEYS),
+			key("offset"),
+			key("skip"),
+			key("bs"),
+			key("count"),
+		],
+	),
+	variant(
+		"sunxi",
+		&[
+			key("file"),
+			key("from_target"),
+			table("source", || SOURCE_KEYS),
+			key("soc"),
+			key("offset"),
+			key("size"),
+		],
+	),
+];
+
+/// Keys of the files downloaded by the bootloader steps.
+const SOURCE_KEYS: &[Key] = &[key("url"), key("sha256")];
+
+const EXTLINUX_ENTRY_KEYS: &[Key] = &[
+	key("label"),
+	key("menu_label"),
+	key("kernel"),
+	key("initrd"),
+	key("fdt"),
+	key("fdtdir"),
+	key("append"),
+];
+
+/// An unknown key in a specification file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownKey {
+	pub file: PathBuf,
+	/// Line of the key in the file, starting from 1, if it can be found.
+	pub line: Option<usize>,
+	/// Path to the key, e.g. `partitions.1.filesytem`.
+	pub key: String,
+	/// Type of the tagged table holding the key, e.g. `script` for a bootloader step.
+	pub tag: Option<String>,
+}
+
+impl Display for UnknownKey {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self.line {
+			Some(line) => write!(
+				f,
+				"{}:{}: unknown key '{}'",
+				self.file.display(),
+				line,
+				self.key
+			)?,
+			None => write!(f, "{}: unknown key '{}'", self.file.display(), self.key)?,
+		}
+		if let Some(tag) = &self.tag {
+			write!(f, " for type '{}'", tag)?;
+		}
+		Ok(())
+	}
+}
+
+/// An unknown key found, with the type of the tagged table holding it.
+type Found = (Vec<String>, Option<&'static str>);
+
+/// Find the unknown keys in the table, as paths to the keys.
+///
+/// A tagged table of an unknown type is only checked against the keys of
+/// every type, the parser rejects the type anyway.
+fn unknown_keys(table: &Table, known: Nested, prefix: &[String], found: &mut Vec<Found>) {
+	let (common, variants, tag) = match known {
+		Nested::Keys(keys) => (keys(), Vec::new(), None),
+		Nested::Tagged(common, variants) => {
+			let tag = table.get(TAG_KEY).and_then(Value::as_str);
+			match variants().iter().find(|v| Some(v.tag) == tag) {
+				Some(v) => (common(), vec![v], Some(v.tag)),
+				None => (common(), variants().iter().collect(), None),
+			}
+		}
+	};
+	for (name, value) in table {
+		let mut path = prefix.to_vec();
+		path.push(name.to_owned());
+		let Some(k) = common
+			.iter()
+			.chain(variants.iter().flat_map(|v| v.keys))
+			.find(|k| k.name == name)
+		else {
+			found.push((path, tag));
+			continue;
+		};
+		let Some(nested) = k.nested else {
+			continue;
+		};
+		match value {
+			Value::Table(t) => unknown_keys(t, nested, &path, found),
+			Value::Array(values) => {
+				for (idx, value) in values.iter().enumerate() {
+					if let Value::Table(t) = value {
+						let mut path = path.clone();
+						path.push(idx.to_string());
+						unknown_keys(t, nested, &path, found);
+					}
+				}
+			}
+			_ => (),
+		}
+	}
+}
+
+fn is_assignment(line: &str, key: &str) -> bool {
+	line.strip_prefix(key)
+		.is_some_and(|rest| rest.trim_start().starts_with('='))
+}
+
+/// Find the line of the key in the file, starting from 1.
+///
+/// The tables and arrays of tables on the path are followed by their
+/// headers. Keys in inline tables can not be found.
+fn find_line(content: &str, path: &[String]) -> Option<usize> {
+	let lines = content.lines().map(str::trim).collect::<Vec<_>>();
+	let mut from = 0;
+	let mut header = String::new();
+	for (idx, name) in path.iter().enumerate() {
+		if name.parse::<usize>().is_ok() {
+			continue;
+		}
+		if !header.is_empty() {
+			header.push('.');
+		}
+		header += name;
+		let table_header = format!("[{}]", header);
+		let array_header = format!("[[{}]]", header);
+		if idx + 1 == path.len() {
+			return lines[from..]
+				.iter()
+				.position(|l| {
+					is_assignment(l, name)
+						|| *l == table_header || *l == array_header
+				})
+				.map(|pos| from + pos + 1);
+		}
+		let pos = match path[idx + 1].parse::<usize>() {
+			Ok(n) => lines[from..]
+				.iter()
+				.enumerate()
+				.filter(|(_, l)| **l == array_header)
+				.nth(n)
+				.map(|(pos, _)| pos),
+			Err(_) => lines[from..].iter().position(|l| *l == table_header),
+		};
+		if let Some(pos) = pos {
+			from += pos + 1;
+		}
+	}
+	None
+}
+
+/// Find the unknown keys in a single specification file.
+fn check_file(file: &Path) -> Result<Vec<UnknownKey>> {
+	let content = fs::read_to_string(file)
+		.context(format!("Unable to read file '{}'", file.display()))?;
+	let table: Table = toml::from_str(&content)
+		.context(format!("Unable to parse '{}'", file.display()))?;
+	let mut found = Vec::new();
+	unknown_keys(&table, Nested::Keys(|| DEVICE_KEYS), &[], &mut found);
+	Ok(found.into_iter()
+		.map(|(path, tag)| UnknownKey {
+			file: file.to_owned(),
+			line: find_line(&content, &path),
+			key: path.join("."),
+			tag: tag.map(str::to_owned),
+		})
+		.collect())
+}
+
+/// Find the unknown keys in the specification and its bases.
+pub fn find_unknown_keys(file: &Path) -> Result<Vec<UnknownKey>> {
+	let mut found = Vec::new();
+	for file in spec_files(file)? {
+		found.extend(check_file(&file)?);
+	}
+	Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn path(s: &str) -> Vec<String> {
+		s.split('.').map(str::to_owned).collect()
+	}
+
+	#[test]
+	fn test_unknown_keys() {
+		let partition = |key: &str| {
+			let mut t = Table::new();
+			t.insert("num".into(), Value::Integer(1));
+			t.insert(key.into(), Value::String("ext4".into()));
+			Value::Table(t)
+		};
+		let mut size = Table::new();
+		size.insert("base".into(), Value::Integer(6144));
+		size.insert("desktpo".into(), Value::Integer(25000));
+		let mut device = Table::new();
+		device.insert("id".into(), Value::String("rpi-5b".into()));
+		device.insert(
+			"alias".into(),
+			Value::Array(vec![Value::String("pi5".into())]),
+		);
+		device.insert("size".into(), Value::Table(size));
+		device.insert(
+			"partitions".into(),
+			Value::Array(vec![partition("filesystem"), partition("filesytem")]),
+		);
+		let mut found = Vec::new();
+		unknown_keys(&device, Nested::Keys(|| DEVICE_KEYS), &[], &mut found);
+		found.sort();
+		assert_eq!(
+			found,
+			[
+				(path("alias"), None),
+				(path("partitions.1.filesytem"), None),
+				(path("size.desktpo"), None)
+			]
+		);
+	}
+
+	#[test]
+	fn test_unknown_keys_by_type() {
+		let step = |tag: &str, key: &str| {
+			let mut t = Table::new();
+			t.insert("type".into(), Value::String(tag.into()));
+			t.insert(key.into(), Value::Integer(0x400));
+			Value::Table(t)
+		};
+		let mut device = Table::new();
+		device.insert(
+			"bootloader".into(),
+			Value::Array(vec![
+				step("flash_offset", "offset"),
+				step("script", "offset"),
+				step("raw", "ofset"),
+				// Rejected by the parser instead.
+				step("flash", "offset"),
+			]),
+		);
+		let mut swap = Table::new();
+		swap.insert("type".into(), Value::String("zram".into()));
+		swap.insert("size".into(), Value::Integer(2048));
+		device.insert("swap".into(), Value::Table(swap));
+		let mut found = Vec::new();
+		unknown_keys(&device, Nested::Keys(|| DEVICE_KEYS), &[], &mut found);
+		found.sort();
+		assert_eq!(
+			found,
+			[
+				(path("bootloader.1.offset"), Some("script")),
+				(path("bootloader.2.ofset"), Some("raw")),
+				(path("swap.size"), Some("zram")),
+			]
+		);
+	}
+
+	#[test]
+	fn test_display_unknown_key() {
+		let key = UnknownKey {
+			file: "device.toml".into(),
+			line: Some(12),
+			key: "bootloader.1.offset".into(),
+			tag: Some("script".into()),
+		};
+		assert_eq!(
+			key.to_string(),
+			"device.toml:12: unknown key 'bootloader.1.offset' for type 'script'"
+		);
+		let key = UnknownKey {
+			line: None,
+			tag: None,
+			..key
+		};
+		assert_eq!(
+			key.to_string(),
+			"device.toml: unknown key 'bootloader.1.offset'"
+		);
+	}
+
+	#[test]
+	fn test_find_line() {
+		let content = "id = \"rpi-5b\"\nalias = [\"pi5\"]\n\n[size]\nbase = 6144\ndesktpo = 25000\n\n[[partitions]]\nnum = 1\nfilesytem = \"fat32\"\n\n[[partitions]]\nnum = 2\n  filesytem = \"ext4\"\n";
+		assert_eq!(find_line(content, &path("alias")), Some(2));
+		assert_eq!(find_line(content, &path("size.desktpo")), Some(6));
+		assert_eq!(
+			find_line(content, &path("partitions.0.filesytem")),
+			Some(10)
+		);
+		assert_eq!(
+			find_line(content, &path("partitions.1.filesytem")),
+			Some(14)
+		);
+		assert_eq!(find_line(content, &path("partitons")), None);
+	}
+}
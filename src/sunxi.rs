@@ -0,0 +1,373 @@
+//! Writing the SPL of the Allwinner (sunxi) SoCs.
+//!
+//! The boot ROMs of the Allwinner SoCs load the SPL from a fixed offset of
+//! the boot media, 8 KiB on all of them, and also 128 KiB on the newer ones
+//! (H616 and later). U-Boot builds the SPL and itself into
+//! `u-boot-sunxi-with-spl.bin`, which a `sunxi` bootloader step writes:
+//!
+//! ```toml
+//! [[bootloader]]
+//! type = "sunxi"
+//! file = "/usr/lib/u-boot/orangepi_zero3/u-boot-sunxi-with-spl.bin"
+//! from_target = true
+//! # Optional, selects the offset, 8 KiB by default.
+//! soc = "h616"
+//! # Optional, overrides the offset of the SoC.
+//! offset = "128KiB"
+//! # Optional, the largest file expected, see below.
+//! size = "768KiB"
+//! ```
+//!
+//! `file`, `from_target` and `source` are the same as the ones of a `raw`
+//! step, see [`crate::raw`].
+//!
+//! The length of a file within the target is only known when it is written,
+//! so `check` verifies the space for `size` bytes instead, 896 KiB by
+//! default: `u-boot-sunxi-with-spl.bin` is 500 to 900 KiB typically, and
+//! 896 KiB fills the default 1 MiB gap from the 128 KiB offset. A larger file
+//! is refused when it is written.
+//!
+//! A corrupted SPL leaves the board dead without any diagnostics, so the
+//! eGON.BT0 header of the SPL is verified before writing: the magic, the
+//! length, and the checksum over the SPL. The write range is verified as
+//! the `raw` steps do, it must fit in the gap before the first partition.
+//! Note the 8 KiB offset overlaps the partition entries of GPT, the SoCs
+//! loading the SPL from 128 KiB can be used with GPT.
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+	bootloader::BootloaderStep,
+	context::ImageContext,
+	device::DeviceSpec,
+	raw::BootloaderFile,
+	size::{format_size, ByteSize, KIB},
+};
+
+/// Offsets the boot ROMs load the SPL from, by the SoCs.
+const SPL_OFFSETS: &[(u64, &[&str])] = &[
+	(
+		8 * KIB,
+		&[
+			"a10", "a10s", "a13", "a20", "a23", "a31", "a33", "a64", "a80", "a83t",
+			"d1", "f1c100s", "h2+", "h3", "h5", "h6", "r40", "t113", "v3s",
+		],
+	),
+	(
+		128 * KIB,
+		&[
+			"a523", "a527", "h313", "h616", "h618", "h700", "t507", "t527",
+		],
+	),
+];
+/// Offset of the SPL if the SoC is not specified.
+const DEFAULT_SPL_OFFSET: u64 = 8 * KIB;
+/// Length of the file assumed when it is unknown, see the [module documentation](self).
+const DEFAULT_IMAGE_SIZE: u64 = 896 * KIB;
+
+/// Magic of the eGON header, after the jump instruction.
+const EGON_MAGIC: &[u8; 8] = b"eGON.BT0";
+/// Value of the checksum field while the checksum is computed.
+const EGON_STAMP: u32 = 0x5f0a_6c39;
+/// Size of the jump instruction, the magic, the checksum and the length.
+const EGON_HEADER_SIZE: usize = 20;
+
+/// Write the SPL and U-Boot of the Allwinner SoCs, see the [module documentation](self).
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+pub struct SunxiSpec {
+	/// The `u-boot-sunxi-with-spl.bin` file.
+	#[serde(flatten)]
+	pub input: BootloaderFile,
+	/// The SoC, e.g. `h616`, selecting the offset.
+	pub soc: Option<String>,
+	/// Offset from the start of the image, overriding the one of the SoC.
+	pub offset: Option<ByteSize>,
+	/// The largest file expected.
+	pub size: Option<ByteSize>,
+}
+
+/// The offset the boot ROM of the SoC loads the SPL from.
+fn spl_offset(soc: &str) -> Option<u64> {
+	let soc = soc.to_ascii_lowercase();
+	SPL_OFFSETS
+		.iter()
+		.find(|(_, socs)| socs.contains(&soc.as_str()))
+		.map(|(offset, _)| *offset)
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+	u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+/// Verify the eGON.BT0 header of the SPL at the start of `blob`, returns the length of the SPL.
+fn check_egon(blob: &[u8]) -> Result<u64> {
+	if blob.len() < EGON_HEADER_SIZE {
+		bail!("It is {} bytes, too small for an SPL", blob.len());
+	}
+	if &blob[4..12] != EGON_MAGIC {
+		bail!("No eGON.BT0 magic is found in the SPL header");
+	}
+	let checksum = read_u32(blob, 12);
+	let len = read_u32(blob, 16) as usize;
+	if len < EGON_HEADER_SIZE || !len.is_multiple_of(4) {
+		bail!("Invalid length {} in the SPL header", len);
+	}
+	if len > blob.len() {
+		bail!(
+			"The SPL header claims {} bytes, but the file is only {} bytes",
+			len,
+			blob.len()
+		);
+	}
+	let sum = (0..len).step_by(4).fold(0u32, |sum, at| {
+		let word = if at == 12 {
+			EGON_STAMP
+		} else {
+			read_u32(blob, at)
+		};
+		sum.wrapping_add(word)
+	});
+	if sum != checksum {
+		bail!(
+			"Checksum mismatch of the SPL: {:#010x} in the header, {:#010x} computed",
+			checksum,
+			sum
+		);
+	}
+	Ok(len as u64)
+}
+
+impl SunxiSpec {
+	/// The offset the SPL is written at.
+	pub(crate) fn spl_offset(&self) -> Result<u64> {
+		match (&self.offset, &self.soc) {
+			(Some(offset), _) => Ok(offset.0),
+			(None, Some(soc)) => spl_offset(soc).context(format!(
+				"Unknown Allwinner SoC '{}', specify the offset of the SPL",
+				soc
+			)),
+			(None, None) => Ok(DEFAULT_SPL_OFFSET),
+		}
+	}
+
+	/// The largest file expected, see the [module documentation](self).
+	fn max_size(&self) -> u64 {
+		self.size.map_or(DEFAULT_IMAGE_SIZE, |size| size.0)
+	}
+}
+
+impl DeviceSpec {
+	/// Make sure the SPL can be written, see the [module documentation](crate::sunxi).
+	pub(crate) fn check_sunxi(
+		&self,
+		dirname: &Path,
+		spec: &SunxiSpec,
+		steps: &[BootloaderStep],
+	) -> Result<()> {
+		let offset = spec.spl_offset()?;
+		if offset % 512 != 0 {
+			bail!(
+				"The offset of the SPL ({:#x}) must be aligned to 512 bytes",
+				offset
+			);
+		}
+		let len = match (
+			self.check_bootloader_file(dirname, &spec.input, steps)?,
+			&spec.input.file,
+		) {
+			(Some(_), Some(file)) => {
+				let blob = fs::read(dirname.join(file))?;
+				check_egon(&blob).context(format!(
+					"{} is not a valid sunxi SPL image",
+					file.display()
+				))?;
+				blob.len() as u64
+			}
+			_ => spec.max_size(),
+		};
+		if spec.size.is_some_and(|size| size.0 == 0) {
+			bail!("size of a sunxi bootloader can not be zero");
+		}
+		self.check_image_write(offset, len).context(format!(
+			"Not enough space for {} ({}) at {:#x}",
+			spec.input.name(),
+			format_size(len),
+			offset
+		))
+	}
+}
+
+impl ImageContext<'_> {
+	/// Verify the SPL and write it to the image, see the [module documentation](self).
+	pub(crate) fn write_sunxi(
+		&self,
+		dirname: &Path,
+		spec: &SunxiSpec,
+		root: &Path,
+		loopdev: &Path,
+		steps: &[BootloaderStep],
+	) -> Result<()> {
+		let offset = spec.spl_offset()?;
+		let src = self.bootloader_file_path(&spec.input, dirname, root, steps)?;
+		let blob =
+			fs::read(&src).context(format!("Unable to find {}", spec.input.name()))?;
+		let spl_len = check_egon(&blob).context(format!(
+			"{} is not a valid sunxi SPL image, refusing to write it",
+			spec.input.name()
+		))?;
+		// Only the files within the device directory are checked with their lengths.
+		if blob.len() as u64 > spec.max_size() {
+			bail!(
+				"{} is {}, larger than the {} expected, specify its size",
+				spec.input.name(),
+				format_size(blob.len() as u64),
+				format_size(spec.max_size())
+			);
+		}
+		let written = self
+			.write_to_image(&blob[..], offset, blob.len() as u64, loopdev)
+			.context(format!("Unable to write {}", spec.input.name()))?;
+		self.info(format!(
+			"Wrote {} bytes of {} at {:#x}, with an SPL of {} bytes",
+			written,
+			spec.input.name(),
+			offset,
+			spl_len
+		));
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::{load_device, TempDir};
+
+	/// An SPL of `len` bytes with a valid eGON header.
+	fn spl(len: usize) -> Vec<u8> {
+		let mut blob = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+		blob[4..12].copy_from_slice(EGON_MAGIC);
+		blob[12..16].copy_from_slice(&EGON_STAMP.to_le_bytes());
+		blob[16..20].copy_from_slice(&(len as u32).to_le_bytes());
+		let sum = (0..len)
+			.step_by(4)
+			.fold(0u32, |sum, at| sum.wrapping_add(read_u32(&blob, at)));
+		blob[12..16].copy_from_slice(&sum.to_le_bytes());
+		blob
+	}
+
+	#[test]
+	fn test_spl_offset() -> Result<()> {
+		assert_eq!(spl_offset("h3"), Some(8 * KIB));
+		assert_eq!(spl_offset("a64"), Some(8 * KIB));
+		assert_eq!(spl_offset("H616"), Some(128 * KIB));
+		assert_eq!(spl_offset("h618"), Some(128 * KIB));
+		assert_eq!(spl_offset("rk3588"), None);
+		let spec = SunxiSpec::default();
+		assert_eq!(spec.spl_offset()?, 8 * KIB);
+		let spec = SunxiSpec {
+			soc: Some("h616".to_owned()),
+			..Default::default()
+		};
+		assert_eq!(spec.spl_offset()?, 128 * KIB);
+		let spec = SunxiSpec {
+			offset: Some(ByteSize(8 * KIB)),
+			..spec
+		};
+		assert_eq!(spec.spl_offset()?, 8 * KIB);
+		let spec = SunxiSpec {
+			soc: Some("rk3588".to_owned()),
+			..Default::default()
+		};
+		assert!(spec.spl_offset().is_err());
+		// Every SoC has a single offset.
+		for (_, socs) in SPL_OFFSETS {
+			for soc in *socs {
+				let count =
+					SPL_OFFSETS.iter().filter(|(_, s)| s.contains(soc)).count();
+				assert_eq!(count, 1, "{}", soc);
+			}
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_egon() -> Result<()> {
+		let blob = spl(1024);
+		assert_eq!(check_egon(&blob)?, 1024);
+		// U-Boot follows the SPL.
+		let mut with_uboot = blob.clone();
+		with_uboot.extend([0xaa; 4096]);
+		assert_eq!(check_egon(&with_uboot)?, 1024);
+		let mut corrupted = blob.clone();
+		corrupted[600] ^= 1;
+		assert!(check_egon(&corrupted).is_err());
+		let mut no_magic = blob.clone();
+		no_magic[4] = b'E';
+		assert!(check_egon(&no_magic).is_err());
+		assert!(check_egon(&blob[..512]).is_err());
+		assert!(check_egon(&blob[..16]).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_sunxi() -> Result<()> {
+		let dir = TempDir::new("sunxi")?;
+		let device = load_device(
+			&dir,
+			r#"partition_map = "mbr"
+first_partition_offset = "512KiB"
+
+[[partition]]
+num = 1
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+"#,
+		)?;
+		let from_target = SunxiSpec {
+			input: BootloaderFile {
+				file: Some("/usr/lib/u-boot/u-boot-sunxi-with-spl.bin".into()),
+				from_target: true,
+				source: None,
+			},
+			..Default::default()
+		};
+		// 896 KiB is assumed, the gap of 512 KiB is too small.
+		assert!(device.check_sunxi(&dir, &from_target, &[]).is_err());
+		let sized = SunxiSpec {
+			size: Some(ByteSize(256 * KIB)),
+			..from_target.clone()
+		};
+		device.check_sunxi(&dir, &sized, &[])?;
+		let sized = SunxiSpec {
+			soc: Some("h616".to_owned()),
+			size: Some(ByteSize(512 * KIB)),
+			..from_target
+		};
+		assert!(device.check_sunxi(&dir, &sized, &[]).is_err());
+		// The files within the device directory are checked with their lengths.
+		fs::write(
+			dir.join("u-boot-sunxi-with-spl.bin"),
+			spl(32 * KIB as usize),
+		)?;
+		let file = SunxiSpec {
+			input: BootloaderFile {
+				file: Some("u-boot-sunxi-with-spl.bin".into()),
+				..Default::default()
+			},
+			..Default::default()
+		};
+		device.check_sunxi(&dir, &file, &[])?;
+		fs::write(
+			dir.join("u-boot-sunxi-with-spl.bin"),
+			spl(1024 * KIB as usize),
+		)?;
+		assert!(device.check_sunxi(&dir, &file, &[]).is_err());
+		Ok(())
+	}
+}
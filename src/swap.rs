@@ -0,0 +1,375 @@
+//! Module handling the swap configuration installed into the image.
+//!
+//! For details please go to [`SwapSpec`].
+use std::{
+	fs::{self, File},
+	io::Write,
+	os::{
+		fd::AsRawFd,
+		unix::fs::{OpenOptionsExt, PermissionsExt},
+	},
+	path::{Path, PathBuf},
+	process::Command,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use toml::Value;
+
+use crate::{
+	context::ImageContext,
+	filesystem::FilesystemType,
+	partition::PartitionUsage,
+	size::{parse_size, MIB},
+	utils::cmd_run_check_status,
+};
+
+const ZRAM_GENERATOR_CONF: &str = "etc/systemd/zram-generator.conf";
+const ZRAM_GENERATOR_BIN: &str = "usr/lib/systemd/system-generators/zram-generator";
+
+fn default_ram_fraction() -> f64 {
+	0.5
+}
+
+fn default_swapfile_path() -> PathBuf {
+	PathBuf::from("/swapfile")
+}
+
+/// Size of the swap file in MiB, an integer in MiB or a string with a unit, e.g. `"1GiB"`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "Value")]
+pub struct SwapFileSize(pub u64);
+
+impl TryFrom<Value> for SwapFileSize {
+	type Error = anyhow::Error;
+
+	fn try_from(value: Value) -> Result<Self> {
+		let size = match value {
+			Value::Integer(n) if n >= 0 => n as u64,
+			Value::String(s) => {
+				let bytes = parse_size(&s)?;
+				if bytes % MIB != 0 {
+					bail!("Size of the swap file '{}' must be a multiple of 1 MiB", s);
+				}
+				bytes / MIB
+			}
+			v => bail!(
+				"Invalid size of the swap file '{}', expected an integer in MiB or a size with a unit",
+				v
+			),
+		};
+		Ok(Self(size))
+	}
+}
+
+/// Fill the swap file with zeros, on the filesystems which do not support `fallocate()`.
+fn write_zeros(fd: &mut File, size: u64) -> Result<()> {
+	let buf = vec![0u8; MIB as usize];
+	for _ in 0..size {
+		fd.write_all(&buf)?;
+	}
+	Ok(())
+}
+
+/// Specifies the swap space to be set up in the image.
+///
+/// The root filesystem is expanded to the size of the medium on first boot,
+/// so a swap partition (see `usage = "swap"` of [`PartitionSpec`]) has to
+/// be placed before the root partition, at a fixed size. Prefer one of the
+/// following instead.
+///
+/// ### zram
+///
+/// A compressed swap device in RAM, set up by `zram-generator` (which must
+/// be installed in the target, e.g. by `bsp_packages`).
+///
+/// ```toml
+/// [swap]
+/// type = "zram"
+/// # Size of the zram device relative to the RAM size. Default is 0.5.
+/// ram_fraction = 0.5
+/// # Optional, the default of zram-generator is used if not specified.
+/// compression_algorithm = "zstd"
+/// ```
+///
+/// ### Swap file
+///
+/// A preallocated swap file on the root filesystem. A `/etc/fstab` entry is
+/// added for it.
+///
+/// ```toml
+/// [swap]
+/// type = "file"
+/// # Size of the swap file, in MiB or with a unit, e.g. "1GiB".
+/// size = 1024
+/// # Path to the swap file, default is /swapfile.
+/// path = "/swapfile"
+/// ```
+///
+/// The swap file is allocated with `fallocate()`, or filled with zeros on
+/// the filesystems which do not support it. On Btrfs, copy-on-write is
+/// disabled for the swap file, which disables the compression as well.
+///
+/// A swap file can not be created with `--populate-backend no-mount`, the
+/// filesystem built from the staging directory would leave holes in it.
+///
+/// [`PartitionSpec`]: crate::partition::PartitionSpec
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SwapSpec {
+	/// Compressed swap device in RAM.
+	Zram {
+		#[serde(default = "default_ram_fraction")]
+		ram_fraction: f64,
+		compression_algorithm: Option<String>,
+	},
+	/// Swap file on the root filesystem.
+	File {
+		/// Size of the swap file in MiB.
+		size: SwapFileSize,
+		#[serde(default = "default_swapfile_path")]
+		path: PathBuf,
+	},
+}
+
+impl SwapSpec {
+	/// Check validity of the swap configuration.
+	///
+	/// `mountpoints` are the mountpoints other than the root filesystem.
+	pub fn check(&self, mountpoints: &[&str]) -> Result<()> {
+		match self {
+			Self::Zram { ram_fraction, .. } => {
+				if !(*ram_fraction > 0.0 && *ram_fraction <= 4.0) {
+					bail!("ram_fraction of zram must be within (0, 4]");
+				}
+			}
+			Self::File { size, path } => {
+				if size.0 == 0 {
+					bail!("Size of the swap file can not be zero");
+				}
+				if !path.is_absolute() || path.parent().is_none() {
+					bail!("Path to the swap file must be an absolute path to a file");
+				}
+				let path_str = path.to_string_lossy();
+				if path_str.contains(char::is_whitespace) {
+					bail!("Path to the swap file can not contain white spaces");
+				}
+				for mountpoint in mountpoints {
+					if path.starts_with(mountpoint) {
+						bail!("Swap file must reside on the root filesystem, but {} is a mountpoint", mountpoint);
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+
+	fn setup_zram(
+		root: &Path,
+		ram_fraction: f64,
+		compression_algorithm: &Option<String>,
+	) -> Result<()> {
+		let mut content = format!(
+			"# ---- Auto generated by mkrawimg ----\n[zram0]\nzram-size = ram * {}\n",
+			ram_fraction
+		);
+		if let Some(algo) = compression_algorithm {
+			content += &format!("compression-algorithm = {}\n", algo);
+		}
+		let conf_path = root.join(ZRAM_GENERATOR_CONF);
+		if let Some(parent) = conf_path.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		fs::write(&conf_path, content).context("Failed to write zram-generator.conf")
+	}
+
+	fn setup_file(root: &Path, size: u64, path: &Path, fstype: &FilesystemType) -> Result<()> {
+		let full_path = root.join(path.strip_prefix("/")?);
+		if full_path.exists() {
+			bail!("{} already exists in the target", path.display());
+		}
+		// Swap files must not be readable by others.
+		let mut fd = File::options()
+			.write(true)
+			.create_new(true)
+			.mode(0o600)
+			.open(&full_path)
+			.context("Failed to create the swap file")?;
+		if fstype == &FilesystemType::Btrfs {
+			// Swap files on Btrfs must not be copy-on-write (thus not compressed).
+			// The attribute only takes effect on empty files.
+			let mut cmd = Command::new("chattr");
+			cmd.arg("+C").arg(&full_path);
+			cmd_run_check_status(&mut cmd)?;
+		}
+		// Swap files must not have holes, so they can not be sparse.
+		let len = (size * MIB) as libc::off_t;
+		let ret = unsafe { libc::fallocate(fd.as_raw_fd(), 0, 0, len) };
+		if ret != 0 {
+			let err = errno::errno();
+			if err.0 != libc::EOPNOTSUPP {
+				bail!("Failed to allocate {} MiB for the swap file: {}", size, err);
+			}
+			write_zeros(&mut fd, size).context(format!(
+				"Failed to write {} MiB for the swap file",
+				size
+			))?;
+		}
+		fd.sync_all()?;
+		drop(fd);
+		let mut cmd = Command::new("mkswap");
+		cmd.arg(&full_path);
+		cmd_run_check_status(&mut cmd)?;
+		let entry = format!("{}\tnone\tswap\tdefaults\t0\t0\n", path.display());
+		let mut fstab_fd = File::options()
+			.append(true)
+			.open(root.join("etc/fstab"))
+			.context("Failed to open /etc/fstab")?;
+		fstab_fd.write_all(entry.as_bytes())?;
+		fstab_fd.sync_all()?;
+		Ok(())
+	}
+
+	/// Make sure the swap space is set up correctly in the target.
+	pub fn verify(&self, root: &Path) -> Result<()> {
+		match self {
+			Self::Zram { .. } => {
+				if !root.join(ZRAM_GENERATOR_CONF).is_file() {
+					bail!("zram-generator.conf is missing");
+				}
+			}
+			Self::File { size, path } => {
+				let full_path = root.join(path.strip_prefix("/")?);
+				let metadata = fs::metadata(&full_path).context(format!(
+					"Swap file {} is missing",
+					path.display()
+				))?;
+				let mode = metadata.permissions().mode() & 0o777;
+				if mode != 0o600 {
+					bail!(
+						"Swap file {} has mode {:04o}, expected 0600",
+						path.display(),
+						mode
+					);
+				}
+				if metadata.len() != size.0 * MIB {
+					bail!(
+						"Swap file {} has an unexpected size",
+						path.display()
+					);
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+impl ImageContext<'_> {
+	/// Set up the swap space in the target root, must be called after fstab is generated.
+	pub fn setup_swap<P: AsRef<Path>>(&self, root: P) -> Result<()> {
+		let root = root.as_ref();
+		let swap = if let Some(s) = &self.device.swap {
+			s
+		} else {
+			return Ok(());
+		};
+		match swap {
+			SwapSpec::Zram {
+				ram_fraction,
+				compression_algorithm,
+			} => {
+				self.info("Setting up zram ...");
+				if !root.join(ZRAM_GENERATOR_BIN).exists() {
+					self.warn("zram-generator is not installed in the target, zram will not be available.");
+				}
+				SwapSpec::setup_zram(root, *ram_fraction, compression_algorithm)?;
+			}
+			SwapSpec::File { size, path } => {
+				self.info(format!(
+					"Creating swap file {} ({} MiB) ...",
+					path.display(),
+					size.0
+				));
+				let rootfs = self
+					.device
+					.partitions
+					.iter()
+					.find(|p| p.usage == PartitionUsage::Rootfs)
+					.context("Unable to find the root filesystem")?;
+				let fstype = self.effective_fstype(rootfs);
+				SwapSpec::setup_file(root, size.0, path, &fstype)?;
+			}
+		}
+		swap.verify(root)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::testutil::TempDir;
+
+	#[test]
+	fn test_check_swap() {
+		let file = SwapSpec::File {
+			size: SwapFileSize(1024),
+			path: default_swapfile_path(),
+		};
+		assert!(file.check(&["/boot/rpi"]).is_ok());
+		let file = SwapSpec::File {
+			size: SwapFileSize(1024),
+			path: PathBuf::from("/boot/rpi/swapfile"),
+		};
+		assert!(file.check(&["/boot/rpi"]).is_err());
+		let file = SwapSpec::File {
+			size: SwapFileSize(1024),
+			path: PathBuf::from("swapfile"),
+		};
+		assert!(file.check(&[]).is_err());
+		let zram = SwapSpec::Zram {
+			ram_fraction: 0.0,
+			compression_algorithm: None,
+		};
+		assert!(zram.check(&[]).is_err());
+	}
+
+	#[test]
+	fn test_swapfile_size() {
+		let size = |v: Value| SwapFileSize::try_from(v).map(|s| s.0);
+		assert_eq!(size(Value::Integer(1024)).unwrap(), 1024);
+		assert_eq!(size(Value::String("1GiB".into())).unwrap(), 1024);
+		assert_eq!(size(Value::String("512M".into())).unwrap(), 512);
+		assert!(size(Value::String("1500KiB".into())).is_err());
+		assert!(size(Value::String("1024".into())).is_err());
+		assert!(size(Value::Integer(-1)).is_err());
+	}
+
+	#[test]
+	fn test_write_zeros() -> Result<()> {
+		let dir = TempDir::new("swapfile")?;
+		let path = dir.join("swapfile");
+		let mut fd = File::create(&path)?;
+		write_zeros(&mut fd, 2)?;
+		drop(fd);
+		let content = fs::read(&path)?;
+		assert_eq!(content.len() as u64, 2 * MIB);
+		assert!(content.iter().all(|&b| b == 0));
+		Ok(())
+	}
+
+	#[test]
+	fn test_zram_config() -> Result<()> {
+		let root = TempDir::new("zram")?;
+		SwapSpec::setup_zram(&root, 0.5, &Some("zstd".into()))?;
+		let content = fs::read_to_string(root.join(ZRAM_GENERATOR_CONF))?;
+		assert!(content.contains(
+			"[zram0]\nzram-size = ram * 0.5\ncompression-algorithm = zstd\n"
+		));
+		SwapSpec::Zram {
+			ram_fraction: 0.5,
+			compression_algorithm: None,
+		}
+		.verify(&root)?;
+		Ok(())
+	}
+}
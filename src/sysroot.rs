@@ -0,0 +1,259 @@
+//! System distributions built elsewhere, used with `--sysroot`.
+//!
+//! `--sysroot ARCH.VARIANT=PATH` uses the directory at `PATH` as the
+//! `VARIANT` distribution for the devices of `ARCH`, instead of
+//! bootstrapping one. The directory is copied into the images as is, and it
+//! is never modified.
+//!
+//! Since populating an image with a tree of another architecture only shows
+//! up at the first boot, the architecture of the tree is checked with the
+//! ELF header of the shell (see [`elf_arch`]) before anything is built. The
+//! variant can not be told from the tree, it is up to the user.
+use std::{
+	fs::File,
+	io::Read,
+	path::{Path, PathBuf},
+	str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{context::ImageVariant, device::DeviceArch, recipe::parse_arch_variant_path};
+
+/// Executables checked to tell the architecture of a tree, in order.
+pub(crate) const PROBED_EXECUTABLES: &[&str] = &["usr/bin/bash", "usr/bin/ls", "bin/bash"];
+
+const EM_386: u16 = 3;
+const EM_ARM: u16 = 40;
+const EM_PPC64: u16 = 21;
+const EM_MIPS: u16 = 8;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+const EM_RISCV: u16 = 243;
+const EM_LOONGARCH: u16 = 258;
+const EF_MIPS_ARCH: u32 = 0xf0000000;
+const EF_MIPS_ARCH_64R6: u32 = 0xa0000000;
+
+/// Tell the architecture from the header of an ELF executable.
+///
+/// armv6hf and armv7hf can not be told apart from the header, both are
+/// reported as armv7hf.
+pub fn elf_arch(header: &[u8]) -> Option<DeviceArch> {
+	if header.len() < 52 || &header[0..4] != b"\x7fELF" {
+		return None;
+	}
+	// ELFCLASS64 or ELFCLASS32, ELFDATA2LSB or ELFDATA2MSB
+	let class64 = match header[4] {
+		1 => false,
+		2 => true,
+		_ => return None,
+	};
+	let lsb = match header[5] {
+		1 => true,
+		2 => false,
+		_ => return None,
+	};
+	let machine = [header[18], header[19]];
+	let machine = if lsb {
+		u16::from_le_bytes(machine)
+	} else {
+		u16::from_be_bytes(machine)
+	};
+	// e_flags follows e_entry, e_phoff and e_shoff, which are 64-bit in ELFCLASS64.
+	let offset = if class64 { 48 } else { 36 };
+	let flags = header[offset..offset + 4].try_into().ok()?;
+	let flags = if lsb {
+		u32::from_le_bytes(flags)
+	} else {
+		u32::from_be_bytes(flags)
+	};
+	match (machine, class64, lsb) {
+		(EM_X86_64, true, true) => Some(DeviceArch::Amd64),
+		(EM_AARCH64, true, true) => Some(DeviceArch::Arm64),
+		(EM_LOONGARCH, true, true) => Some(DeviceArch::LoongArch64),
+		(EM_PPC64, true, true) => Some(DeviceArch::Ppc64el),
+		(EM_PPC64, true, false) => Some(DeviceArch::Ppc64),
+		(EM_RISCV, true, true) => Some(DeviceArch::Riscv64),
+		(EM_MIPS, true, true) if flags & EF_MIPS_ARCH == EF_MIPS_ARCH_64R6 => {
+			Some(DeviceArch::Mips64r6el)
+		}
+		(EM_MIPS, true, true) => Some(DeviceArch::Loongson3),
+		(EM_386, false, true) => Some(DeviceArch::I486),
+		(EM_ARM, false, true) => Some(DeviceArch::Armv7hf),
+		_ => None,
+	}
+}
+
+/// Whether a distribution probed as `probed` can be used for `arch`.
+pub(crate) fn arch_matches(probed: DeviceArch, arch: DeviceArch) -> bool {
+	// armv6hf is probed as armv7hf.
+	probed == arch || (probed == DeviceArch::Armv7hf && arch == DeviceArch::Armv6hf)
+}
+
+/// A system distribution specified on the command line, in the form of `ARCH.VARIANT=PATH`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sysroot {
+	pub arch: DeviceArch,
+	pub variant: ImageVariant,
+	pub path: PathBuf,
+}
+
+impl FromStr for Sysroot {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self> {
+		let (arch, variant, path) = parse_arch_variant_path(s, "sysroot")?;
+		Ok(Self {
+			arch,
+			variant,
+			path,
+		})
+	}
+}
+
+impl Sysroot {
+	/// Find the system distribution for the architecture and the variant, the last one wins.
+	pub fn find<'a>(
+		sysroots: &'a [Sysroot],
+		arch: DeviceArch,
+		variant: &ImageVariant,
+	) -> Option<&'a Sysroot> {
+		sysroots.iter()
+			.rev()
+			.find(|s| s.arch == arch && &s.variant == variant)
+	}
+
+	/// Tell the architecture of the tree.
+	fn probe_arch(&self) -> Result<DeviceArch> {
+		for name in PROBED_EXECUTABLES {
+			let path = self.path.join(name);
+			// Symbolic links may point to the host.
+			if !path.symlink_metadata().is_ok_and(|m| m.is_file()) {
+				continue;
+			}
+			let mut header = Vec::with_capacity(64);
+			File::open(&path)
+				.and_then(|f| f.take(64).read_to_end(&mut header))
+				.context(format!("Failed to read {}", path.display()))?;
+			return elf_arch(&header).context(format!(
+				"{} is not an executable of any supported architecture",
+				path.display()
+			));
+		}
+		bail!(
+			"Unable to tell the architecture of {}, none of {} is found",
+			self.path.display(),
+			PROBED_EXECUTABLES.join(", ")
+		)
+	}
+
+	/// Make sure the tree is a system distribution of the right architecture.
+	pub fn check(&self) -> Result<()> {
+		if !self.path.is_dir() {
+			bail!("Sysroot {} is not a directory.", self.path.display());
+		}
+		if !self.path.join("etc/os-release").is_file() {
+			bail!(
+				"Sysroot {} is not a system distribution: etc/os-release is not found.",
+				self.path.display()
+			);
+		}
+		let arch = self.probe_arch()?;
+		if !arch_matches(arch, self.arch) {
+			bail!(
+				"Sysroot {} is a distribution for {}, but it is specified for {}.",
+				self.path.display(),
+				arch.to_string().to_lowercase(),
+				self.arch.to_string().to_lowercase()
+			);
+		}
+		Ok(())
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+
+	use super::*;
+	use crate::testutil::TempDir;
+
+	/// A minimal ELF64 LSB header.
+	fn header(machine: u16, flags: u32) -> Vec<u8> {
+		let mut header = vec![0u8; 64];
+		header[0..4].copy_from_slice(b"\x7fELF");
+		header[4] = 2;
+		header[5] = 1;
+		header[18..20].copy_from_slice(&machine.to_le_bytes());
+		header[48..52].copy_from_slice(&flags.to_le_bytes());
+		header
+	}
+
+	#[test]
+	fn test_elf_arch() {
+		assert_eq!(elf_arch(&header(62, 0)), Some(DeviceArch::Amd64));
+		assert_eq!(elf_arch(&header(183, 0)), Some(DeviceArch::Arm64));
+		assert_eq!(elf_arch(&header(258, 0)), Some(DeviceArch::LoongArch64));
+		assert_eq!(
+			elf_arch(&header(8, 0x80000007)),
+			Some(DeviceArch::Loongson3)
+		);
+		assert_eq!(
+			elf_arch(&header(8, 0xa0000407)),
+			Some(DeviceArch::Mips64r6el)
+		);
+		assert_eq!(elf_arch(&header(3, 0)), None);
+		// 32-bit
+		let mut h = header(62, 0);
+		h[4] = 1;
+		assert_eq!(elf_arch(&h), None);
+		let mut h = header(3, 0);
+		h[4] = 1;
+		assert_eq!(elf_arch(&h), Some(DeviceArch::I486));
+		let mut h = header(40, 0x05000400);
+		h[4] = 1;
+		assert_eq!(elf_arch(&h), Some(DeviceArch::Armv7hf));
+		// Big endian
+		let mut h = header(0, 0);
+		h[5] = 2;
+		h[18..20].copy_from_slice(&21u16.to_be_bytes());
+		assert_eq!(elf_arch(&h), Some(DeviceArch::Ppc64));
+		assert_eq!(elf_arch(&header(21, 0)), Some(DeviceArch::Ppc64el));
+		// Big endian MIPS64r6 is not mips64r6el, nor loongson3.
+		h[18..20].copy_from_slice(&8u16.to_be_bytes());
+		h[48..52].copy_from_slice(&0xa0000407u32.to_be_bytes());
+		assert_eq!(elf_arch(&h), None);
+		assert_eq!(elf_arch(b"#!/bin/sh\n"), None);
+	}
+
+	#[test]
+	fn test_check_sysroot() -> Result<()> {
+		let root = TempDir::new("sysroot")?;
+		fs::create_dir_all(root.join("etc"))?;
+		fs::create_dir_all(root.join("usr/bin"))?;
+		let sysroot: Sysroot = format!("arm64.base={}", root.display()).parse()?;
+		assert_eq!(sysroot.variant, ImageVariant::Base);
+		let err = sysroot.check().unwrap_err().to_string();
+		assert!(err.contains("etc/os-release is not found"));
+		fs::write(root.join("etc/os-release"), "NAME=\"AOSC OS\"\n")?;
+		assert!(sysroot.check().is_err());
+		fs::write(root.join("usr/bin/bash"), header(62, 0))?;
+		let err = sysroot.check().unwrap_err().to_string();
+		assert!(err.contains("is a distribution for amd64, but it is specified for arm64"));
+		fs::write(root.join("usr/bin/bash"), header(183, 0))?;
+		sysroot.check()?;
+		let sysroots = [
+			sysroot.clone(),
+			"arm64.desktop=/srv/desktop".parse()?,
+			"arm64.base=/srv/base".parse()?,
+		];
+		let found = Sysroot::find(&sysroots, DeviceArch::Arm64, &ImageVariant::Base);
+		assert_eq!(found.unwrap().path(), Path::new("/srv/base"));
+		assert!(Sysroot::find(&sysroots, DeviceArch::Amd64, &ImageVariant::Base).is_none());
+		Ok(())
+	}
+}
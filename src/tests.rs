@@ -1,8 +1,24 @@
 #![cfg(test)]
-use std::str::FromStr;
+use std::{
+	collections::HashMap,
+	fs,
+	path::Path,
+	process::Command,
+	str::FromStr,
+	time::{Duration, Instant},
+};
 
 use crate::{
+	context::ImageContext,
+	device::{DeviceSpec, PartitionData, PartitionMapData},
+	filesystem::FsUuid,
+	metadata::ImageMetadata,
 	partition::PartitionType,
+	populate::PopulateBackend,
+	runner::{self, CommandRecord, RunnerMode},
+	smoke::smoke_test_devices,
+	stage::{Stage, StageMarker, StageTiming},
+	testutil::{load_device, test_context, TempDir},
 	utils::{create_sparse_file, geteuid},
 };
 use anyhow::{bail, Context, Result};
@@ -34,6 +50,308 @@ fn test_loopdev() -> Result<()> {
 	Ok(())
 }
 
+/// Partition, format and mount a MBR with logical partitions on a loop device.
+#[test]
+fn test_mbr_logical_partitions() -> Result<()> {
+	if unsafe { geteuid() } != 0 {
+		bail!("Not being run as root user, aborting.");
+	}
+	let base = TempDir::new("logical")?;
+	let spec_dir = base.join("devices/tvbox");
+	std::fs::create_dir_all(&spec_dir)?;
+	let mut spec = String::from(
+		r#"id = "tvbox"
+vendor = "allwinner"
+name = "Allwinner H616 TV Box"
+arch = "arm64"
+bsp_packages = []
+partition_map = "mbr"
+size = { base = 1024, desktop = 1024, server = 1024 }
+"#,
+	);
+	// Three primary partitions, the extended partition 4 and three logical partitions.
+	for (num, usage, filesystem, mountpoint, size) in [
+		(1, "boot", "fat32", "/boot", "64MiB"),
+		(2, "other", "none", "", "16MiB"),
+		(3, "other", "ext4", "/srv", "32MiB"),
+		(5, "other", "none", "", "16MiB"),
+		(6, "other", "ext4", "/var/lib/data", "32MiB"),
+		(7, "rootfs", "ext4", "/", "rest"),
+	] {
+		spec += &format!(
+			"\n[[partition]]\nnum = {}\ntype = \"linux\"\nusage = \"{}\"\nfilesystem = \"{}\"\nsize = \"{}\"\n",
+			num, usage, filesystem, size
+		);
+		if !mountpoint.is_empty() {
+			spec += &format!("mountpoint = \"{}\"\n", mountpoint);
+		}
+	}
+	std::fs::write(spec_dir.join("device.toml"), spec)?;
+	let device = DeviceSpec::from_path(&spec_dir.join("device.toml"))?;
+	device.check()?;
+	let nums = device.partitions.iter().map(|p| p.num).collect::<Vec<_>>();
+	assert_eq!(nums, [1, 2, 3, 5, 6, 7]);
+	smoke_test_devices(&[device], &base.join("work"), &base.join("out"), "logical")?;
+	Ok(())
+}
+
+/// Partition two images with the same seed, their partition tables must be identical.
+#[test]
+fn test_seeded_partition_tables() -> Result<()> {
+	if unsafe { geteuid() } != 0 {
+		bail!("Not being run as root user, aborting.");
+	}
+	let base = TempDir::new("seed")?;
+	let spec_dir = base.join("devices/seeded");
+	std::fs::create_dir_all(&spec_dir)?;
+	std::fs::write(
+		spec_dir.join("device.toml"),
+		r#"id = "seeded"
+vendor = "generic"
+name = "Seeded Device"
+arch = "amd64"
+bsp_packages = []
+partition_map = "gpt"
+size = { base = 1024, desktop = 1024, server = 1024 }
+
+[[partition]]
+num = 1
+type = "esp"
+usage = "boot"
+filesystem = "fat32"
+mountpoint = "/efi"
+size = "64MiB"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+"#,
+	)?;
+	let device = DeviceSpec::from_path(&spec_dir.join("device.toml"))?;
+	device.check()?;
+	let mut heads = Vec::new();
+	for run in ["a.img", "b.img"] {
+		let ctx = ImageContext {
+			user: Some("root"),
+			build_id: "seed",
+			seed: Some("reproducible"),
+			..test_context(&device, &base)
+		};
+		let img = base.join(run);
+		ctx.partition_target(&img, Some(256), false)?;
+		let mut head = std::fs::read(&img)?;
+		head.truncate(1 << 20);
+		heads.push(head);
+	}
+	assert!(heads[0] == heads[1], "The partition tables differ");
+	Ok(())
+}
+
+/// Format an exFAT data partition on a loop device, and generate its fstab entry.
+#[test]
+fn test_exfat_data_partition() -> Result<()> {
+	if unsafe { geteuid() } != 0 {
+		bail!("Not being run as root user, aborting.");
+	}
+	let base = TempDir::new("exfat")?;
+	let spec_dir = base.join("devices/shared");
+	std::fs::create_dir_all(&spec_dir)?;
+	std::fs::write(
+		spec_dir.join("device.toml"),
+		r#"id = "shared"
+vendor = "generic"
+name = "Shared Storage Device"
+arch = "arm64"
+bsp_packages = []
+partition_map = "gpt"
+size = { base = 1024, desktop = 1024, server = 1024 }
+
+[[partition]]
+num = 1
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "64MiB"
+
+[[partition]]
+num = 2
+type = "basic"
+usage = "data"
+filesystem = "exfat"
+mountpoint = "/srv/share"
+fs_label = "SHARED"
+fs_uuid = "1a2b-3c4d"
+size = "rest"
+"#,
+	)?;
+	let device = DeviceSpec::from_path(&spec_dir.join("device.toml"))?;
+	device.check()?;
+	let ctx = ImageContext {
+		build_id: "exfat",
+		..test_context(&device, &base)
+	};
+	let img = base.join("shared.img");
+	create_sparse_file(&img, 256 * 1024 * 1024)?;
+	let (loop_dev, loop_dev_path) = ImageContext::attach_loop_device(&img)?;
+	let result = ctx.partition_disk(&loop_dev_path, true);
+	loop_dev.detach()?;
+	let pm_data = result?;
+	assert_eq!(
+		pm_data.data[&2].fs_uuid,
+		Some(FsUuid::VolumeId("1A2B-3C4D".into()))
+	);
+	let root = base.join("root");
+	std::fs::create_dir_all(root.join("etc"))?;
+	std::fs::write(root.join("etc/fstab"), "")?;
+	ctx.generate_fstab(&pm_data, &root)?;
+	let fstab = std::fs::read_to_string(root.join("etc/fstab"))?;
+	assert!(
+		fstab.contains("UUID=\"1A2B-3C4D\"\t/srv/share\texfat\tdefaults,uid=1000,gid=1000,umask=0022\t0\t2\n"),
+		"Unexpected fstab:\n{}",
+		fstab
+	);
+	Ok(())
+}
+
+/// Tune an ext4 partition on a loop device, and read the settings back with dumpe2fs.
+#[test]
+fn test_ext4_tuning() -> Result<()> {
+	if unsafe { geteuid() } != 0 {
+		bail!("Not being run as root user, aborting.");
+	}
+	let base = TempDir::new("ext4")?;
+	let spec_dir = base.join("devices/shared");
+	std::fs::create_dir_all(&spec_dir)?;
+	std::fs::write(
+		spec_dir.join("device.toml"),
+		r#"id = "shared"
+vendor = "generic"
+name = "Shared Storage Device"
+arch = "arm64"
+bsp_packages = []
+partition_map = "gpt"
+size = { base = 1024, desktop = 1024, server = 1024 }
+
+[[partition]]
+num = 1
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "64MiB"
+
+[[partition]]
+num = 2
+type = "linux"
+usage = "data"
+filesystem = "ext4"
+mountpoint = "/srv"
+size = "rest"
+ext4_reserved_percent = 1
+ext4_default_mount_opts = ["acl", "^user_xattr"]
+ext4_check_interval = "2w"
+"#,
+	)?;
+	let device = DeviceSpec::from_path(&spec_dir.join("device.toml"))?;
+	device.check()?;
+	let ctx = ImageContext {
+		build_id: "ext4",
+		..test_context(&device, &base)
+	};
+	let img = base.join("shared.img");
+	create_sparse_file(&img, 256 * 1024 * 1024)?;
+	let (loop_dev, loop_dev_path) = ImageContext::attach_loop_device(&img)?;
+	let result = ctx.partition_disk(&loop_dev_path, true).and_then(|_| {
+		let dev = device.partition_path(&loop_dev_path, 2);
+		runner::output(Command::new("dumpe2fs").arg("-h").arg(dev))
+	});
+	loop_dev.detach()?;
+	let output = result?;
+	let output = String::from_utf8_lossy(&output.stdout);
+	let field = |name: &str| {
+		output.lines()
+			.find_map(|l| l.strip_prefix(name))
+			.map(|v| v.trim().to_owned())
+			.unwrap_or_default()
+	};
+	let blocks: u64 = field("Block count:").parse()?;
+	let reserved: u64 = field("Reserved block count:").parse()?;
+	assert_eq!(reserved, blocks / 100);
+	assert_eq!(field("Default mount options:"), "acl");
+	assert!(
+		field("Check interval:").starts_with("1209600 "),
+		"Unexpected dumpe2fs output:\n{}",
+		output
+	);
+	Ok(())
+}
+
+/// The build ID must be found in every artifact of the build. Does not require root.
+#[test]
+fn test_build_id_in_artifacts() -> Result<()> {
+	let build_id = "01JC4ZQ3V8X4Q8N5X4Y4M2K7QZ";
+	let dir = TempDir::new("build-id")?;
+	let device = load_device(
+		&dir,
+		r#"partition_map = "gpt"
+
+[[partition]]
+num = 1
+type = "linux"
+usage = "rootfs"
+filesystem = "ext4"
+mountpoint = "/"
+size = "rest"
+"#,
+	)?;
+	let mut ctx = test_context(&device, &dir);
+	ctx.build_id = build_id;
+	let pm_data = PartitionMapData {
+		uuid: "C8E4A7A0-1B2C-4D3E-8F9A-0B1C2D3E4F5A".into(),
+		data: HashMap::from([(
+			1,
+			PartitionData {
+				num: 1,
+				part_uuid: "8C2D6A1E-3F4B-4C5D-9E6F-7A8B9C0D1E2F".into(),
+				fs_uuid: None,
+				fs_label: None,
+				luks_uuid: None,
+				fsck: None,
+			},
+		)]),
+	};
+	// Command recordings
+	let records = dir.join("records");
+	{
+		let _runner = runner::scoped(RunnerMode::Record(records.clone()))?;
+		runner::set_build_id(build_id);
+		runner::run(&mut Command::new("true"))?;
+	}
+	let record: CommandRecord =
+		serde_json::from_str(&fs::read_to_string(records.join("00001.json"))?)?;
+	assert_eq!(record.build_id.as_deref(), Some(build_id));
+	// Scripts
+	let container = dir.join("container");
+	fs::create_dir_all(container.join("tmp"))?;
+	ctx.write_spec_script(&"/dev/loop0", &"/dev/loop0p1", &container, &pm_data)?;
+	let script = fs::read_to_string(container.join("tmp/spec.sh"))?;
+	assert!(script.contains(&format!("BUILD_ID='{}'\n", build_id)));
+	// Metadata embedded in the image
+	let image = dir.join("image.img");
+	create_sparse_file(&image, 1 << 20)?;
+	ctx.write_metadata(&container, &image, 1 << 20)?;
+	assert_eq!(ImageMetadata::scan(&fs::read(&image)?)?.build_id, build_id);
+	// Build report
+	let record = ctx.image_record(&image, &image, &pm_data, Duration::from_secs(1))?;
+	assert_eq!(record.build_id, build_id);
+	Ok(())
+}
+
 #[test]
 fn test_partition_type() -> Result<()> {
 	env_logger::builder()
@@ -51,3 +369,53 @@ fn test_partition_type() -> Result<()> {
 	info!("{}\n{}\n{}\n{}\n{}\n{}", s1, s2, s3, s4, s5, s6);
 	Ok(())
 }
+
+/// Replay the commands of a build for Raspberry Pi 5B without mounting,
+/// which has been recorded in `/tmp/mkrawimg-replay`. Every recorded command
+/// must be run with the same arguments. Does not require root, nothing is
+/// actually run.
+#[test]
+fn test_replay_rpi_build() -> Result<()> {
+	let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+	let recording = manifest_dir.join("tests/replay/rpi-5b");
+	let base = TempDir::new("replay")?;
+	let device =
+		DeviceSpec::from_path(&manifest_dir.join("devices/raspberrypi/pi-5b/device.toml"))?;
+	let context = |stages| ImageContext {
+		stages,
+		populate_backend: PopulateBackend::NoMount,
+		filename: "rpi-5b.img".to_owned(),
+		image_size: Some(384),
+		..test_context(&device, &base)
+	};
+	let _runner = runner::scoped(RunnerMode::Replay(recording))?;
+	runner::map_path("/tmp/mkrawimg-replay", &*base);
+	context(&[Stage::Partition, Stage::Format]).execute(1, 1)?;
+	// A replay installs nothing, stage the files like the populate stage does instead.
+	let sketch_dir = context(&Stage::ALL).sketch_dir();
+	let root = sketch_dir.join("mnt/p2");
+	for dir in ["boot/rpi", "etc", "tmp"] {
+		fs::create_dir_all(root.join(dir))?;
+	}
+	fs::write(root.join("boot/rpi/config.txt"), "arm_64bit=1\n")?;
+	fs::write(root.join("etc/passwd"), "root:x:0:0:root:/root:/bin/bash\n")?;
+	let pm_data = StageMarker::load(&sketch_dir)?
+		.context("No stage marker")?
+		.pm_data;
+	context(&Stage::ALL).finish_stage(
+		StageTiming::since(Stage::Populate, Instant::now()),
+		&pm_data,
+	)?;
+	context(&[Stage::Postinst, Stage::Bootloader]).execute(1, 1)?;
+	runner::finish()?;
+	// Read back from the recorded probes.
+	let marker = StageMarker::load(&sketch_dir)?.context("No stage marker")?;
+	assert_eq!(marker.stage, Stage::Bootloader);
+	let root_data = &marker.pm_data.data[&2];
+	assert_eq!(
+		root_data.fs_uuid.as_ref().map(|u| u.to_string()).as_deref(),
+		Some("9b7c1f3e-52d4-4c8a-a1f0-6e2d7b3c9a15")
+	);
+	assert_eq!(root_data.fs_label.as_deref(), Some("AOSC OS"));
+	Ok(())
+}
@@ -0,0 +1,124 @@
+#![cfg(test)]
+//! Fixtures shared by the tests.
+use std::{
+	fs,
+	ops::Deref,
+	path::{Path, PathBuf},
+	sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::Result;
+
+use crate::{
+	cli::{Compression, KeepWorkdir},
+	context::{ImageContext, ImageVariant},
+	device::DeviceSpec,
+	joblog::JobLogger,
+	populate::PopulateBackend,
+	stage::Stage,
+};
+
+/// Tells apart the directories of the tests running in parallel.
+static TEMP_DIR_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// The keys every device specification requires, followed by the ones of the fixture.
+pub const DEVICE_HEADER: &str = r#"id = "fixture"
+vendor = "generic"
+name = "Fixture Device"
+arch = "arm64"
+bsp_packages = []
+size = { base = 1024, desktop = 1024, server = 1024 }
+"#;
+
+/// A temporary directory, removed with its contents when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+	/// Create an empty directory with `name` in its name.
+	pub fn new(name: &str) -> Result<Self> {
+		let path = std::env::temp_dir().join(format!(
+			"mkrawimg-{}-{}-{}",
+			name,
+			std::process::id(),
+			TEMP_DIR_SEQ.fetch_add(1, Ordering::Relaxed)
+		));
+		// Left behind by an aborted run.
+		if path.exists() {
+			fs::remove_dir_all(&path)?;
+		}
+		fs::create_dir_all(&path)?;
+		Ok(Self(path))
+	}
+}
+
+impl Deref for TempDir {
+	type Target = Path;
+
+	fn deref(&self) -> &Path {
+		&self.0
+	}
+}
+
+impl AsRef<Path> for TempDir {
+	fn as_ref(&self) -> &Path {
+		&self.0
+	}
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		fs::remove_dir_all(&self.0).ok();
+	}
+}
+
+/// Load a device specification written inline, from `device.toml` in `dir`.
+/// `spec` is appended to [`DEVICE_HEADER`].
+pub fn load_device(dir: &Path, spec: &str) -> Result<DeviceSpec> {
+	let file = dir.join("device.toml");
+	fs::write(&file, format!("{}{}", DEVICE_HEADER, spec))?;
+	DeviceSpec::from_path(&file)
+}
+
+/// A context building the base variant of `device` in `workdir` with the defaults of the options.
+pub fn test_context<'a>(device: &'a DeviceSpec, workdir: &'a Path) -> ImageContext<'a> {
+	ImageContext {
+		device,
+		variant: &ImageVariant::Base,
+		workdir,
+		outdir: workdir,
+		user: Some("aosc"),
+		password: "",
+		password_hashed: false,
+		hostname: None,
+		timezone: None,
+		ssh_keys: &[],
+		root_ssh_keys: &[],
+		customize_scripts: &[],
+		keep_workdir: KeepWorkdir::Always,
+		stages: &Stage::ALL,
+		shrink: false,
+		fsck: false,
+		populate_backend: PopulateBackend::Mount,
+		expire_password: false,
+		public_artifacts: false,
+		filename: String::new(),
+		revision: None,
+		reserved: false,
+		base_dist: PathBuf::new(),
+		rootfs_source: None,
+		offline: false,
+		override_rootfs_fstype: &None,
+		additional_packages: &None,
+		compress: &Compression::None,
+		compress_level: None,
+		compress_threads: None,
+		topics: None,
+		image_size: None,
+		image_size_round_to: None,
+		trailing_pad: None,
+		build_id: "test",
+		logger: JobLogger::new(&device.id, &ImageVariant::Base),
+		force: false,
+		seed: None,
+	}
+}
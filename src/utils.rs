@@ -1,20 +1,38 @@
 use std::{
-	ffi::{c_int, c_void, CString},
-	fs::File,
+	collections::{BTreeMap, HashMap},
+	ffi::{c_char, c_int, c_void, CString},
+	fmt::Display,
+	fs::{File, Permissions},
 	io::{Seek, Write},
-	os::unix::fs::chown,
+	os::unix::{
+		ffi::OsStrExt,
+		fs::{chown, FileTypeExt, MetadataExt, PermissionsExt},
+	},
 	path::{Path, PathBuf},
-	process::{Command, Stdio},
+	process::Command,
+	sync::atomic::{AtomicBool, Ordering},
+	time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
 use blkid::prober::ProbeState;
 use libc::{close, open, O_NONBLOCK, O_RDONLY};
 use log::{debug, info};
+use loopdev::LoopDevice;
+use serde::{Deserialize, Serialize};
+use sys_mount::{unmount, UnmountFlags};
 use termsize::Size;
 use walkdir::WalkDir;
 
-use crate::{context::ImageVariant, device::DeviceArch};
+use crate::{
+	chroot,
+	device::DeviceArch,
+	encryption::{close_mapping, mappings_on},
+	filesystem::FsUuid,
+	partition::SECTOR_SIZE,
+	recipe::BootstrapRecipe,
+	runner,
+};
 
 #[link(name = "c")]
 extern "C" {
@@ -25,10 +43,28 @@ extern "C" {
 	pub fn syncfs(fd: c_int) -> c_int;
 }
 
-const AB_DIR: &str = "/usr/share/aoscbootstrap";
 const DEFAULT_GROUPS: &[&str] = &["audio", "video", "cdrom", "plugdev", "tty", "wheel"];
 const LOCALCONF_PATH: &str = "etc/locale.conf";
+const SHADOW_PATH: &str = "etc/shadow";
+const ZONEINFO_PATH: &str = "usr/share/zoneinfo";
+const LOCALTIME_PATH: &str = "etc/localtime";
+const TIMEZONE_PATH: &str = "etc/timezone";
+/// The well-known default password of the built-in user.
+pub const DEFAULT_PASSWORD: &str = "anthon";
+/// UID and GID of the built-in user, the first regular user created by useradd.
+pub const DEFAULT_USER_ID: u32 = 1000;
+/// Schemes of crypt(3) accepted for hashed passwords: SHA-256, SHA-512,
+/// bcrypt, scrypt, yescrypt and gost-yescrypt.
+const CRYPT_SCHEMES: &[&str] = &["5", "6", "2a", "2b", "2y", "7", "y", "gy"];
 const BINFMT_DIR: &str = "/proc/sys/fs/binfmt_misc";
+/// Whether the progress bar is drawn at the bottom of the terminal.
+static PROGRESS_BAR: AtomicBool = AtomicBool::new(true);
+/// Copy the distribution with tar first if it contains more files than this.
+const TAR_COPY_THRESHOLD: usize = 200_000;
+/// How many files are checked for lost metadata after the installation.
+const NUM_SENTINELS: usize = 32;
+/// Crockford's Base32 alphabet used by ULIDs.
+const ULID_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
 
 /// Create a sparse file with specified size in bytes.
 pub fn get_sparse_file<P: AsRef<Path>>(path: P, size: u64) -> Result<File> {
@@ -63,29 +99,415 @@ pub fn create_sparse_file<P: AsRef<Path>>(path: P, size: u64) -> Result<()> {
 	Ok(())
 }
 
+/// Copy a sparse file, keeping the holes.
+///
+/// Only the data regions (found with `SEEK_DATA` and `SEEK_HOLE`) are
+/// copied, with copy_file_range(2) if both files are on the same filesystem,
+/// otherwise with plain reads and writes. The holes are recreated by
+/// truncating the destination to the apparent size of the source, so the
+/// destination ends up with the same content and apparent size.
+///
+/// `progress` is called with the bytes copied so far and the apparent size.
+/// Returns the bytes copied.
+pub fn copy_sparse<P: AsRef<Path>, Q: AsRef<Path>, F: FnMut(u64, u64)>(
+	src: P,
+	dst: Q,
+	mut progress: F,
+) -> Result<u64> {
+	let (src, dst) = (src.as_ref(), dst.as_ref());
+	let src_fd = File::open(src).context(format!("Failed to open {}", src.display()))?;
+	let size = src_fd.metadata()?.len();
+	let dst_fd = File::options()
+		.read(true)
+		.write(true)
+		.create(true)
+		.truncate(true)
+		.open(dst)
+		.context(format!("Failed to open {}", dst.display()))?;
+	// Everything is a hole now.
+	dst_fd.set_len(size)?;
+	let mut use_cfr = src_fd.metadata()?.dev() == dst_fd.metadata()?.dev();
+	let mut copied = 0;
+	let mut offset = 0;
+	progress(copied, size);
+	while offset < size {
+		let (data, hole) = match next_data_region(&src_fd, offset, size)? {
+			Some(r) => r,
+			None => break,
+		};
+		let mut pos = data;
+		while pos < hole {
+			let len = (hole - pos).min(SPARSE_COPY_CHUNK);
+			let n = if use_cfr {
+				match copy_range(&src_fd, &dst_fd, pos, len) {
+					Ok(n) => n,
+					// Not supported by the filesystem.
+					Err(e) if [
+						libc::EXDEV,
+						libc::ENOSYS,
+						libc::EINVAL,
+						libc::EOPNOTSUPP,
+					]
+					.contains(&e.0) =>
+					{
+						debug!("copy_file_range(2) is not usable ({}), falling back to read(2) and write(2).", e);
+						use_cfr = false;
+						continue;
+					}
+					Err(e) => bail!("Failed to copy {}: {}", src.display(), e),
+				}
+			} else {
+				copy_range_rw(&src_fd, &dst_fd, pos, len)?
+			};
+			if n == 0 {
+				bail!("{} is truncated while being copied", src.display());
+			}
+			pos += n;
+			copied += n;
+			progress(copied, size);
+		}
+		offset = hole;
+	}
+	dst_fd.sync_all()?;
+	Ok(copied)
+}
+
+/// Size of the chunks copied at once by [`copy_sparse`].
+const SPARSE_COPY_CHUNK: u64 = 16 << 20;
+
+/// Find the next data region from `offset`, returns its start and end.
+fn next_data_region(fd: &File, offset: u64, size: u64) -> Result<Option<(u64, u64)>> {
+	use std::os::fd::AsRawFd;
+	let data = unsafe { libc::lseek(fd.as_raw_fd(), offset as libc::off_t, libc::SEEK_DATA) };
+	if data < 0 {
+		let e = errno::errno();
+		return match e.0 {
+			// No data after the offset.
+			libc::ENXIO => Ok(None),
+			// Holes are not supported, the rest is data.
+			libc::EINVAL => Ok(Some((offset, size))),
+			_ => bail!("Failed to find the data region: {}", e),
+		};
+	}
+	let hole = unsafe { libc::lseek(fd.as_raw_fd(), data, libc::SEEK_HOLE) };
+	if hole < 0 {
+		bail!("Failed to find the hole: {}", errno::errno());
+	}
+	Ok(Some((data as u64, (hole as u64).min(size))))
+}
+
+/// Copy a range with copy_file_range(2), the offset is the same in both files.
+fn copy_range(src: &File, dst: &File, pos: u64, len: u64) -> Result<u64, errno::Errno> {
+	use std::os::fd::AsRawFd;
+	let mut off_in = pos as libc::loff_t;
+	let mut off_out = pos as libc::loff_t;
+	let n = unsafe {
+		libc::copy_file_range(
+			src.as_raw_fd(),
+			&mut off_in,
+			dst.as_raw_fd(),
+			&mut off_out,
+			len as usize,
+			0,
+		)
+	};
+	if n < 0 {
+		return Err(errno::errno());
+	}
+	Ok(n as u64)
+}
+
+/// Copy `len` bytes at `src_offset` in `src` to `dst_offset` in `dst`, keeping the holes.
+///
+/// The range in the destination is punched into a hole first, then only the
+/// data regions of the source are written, without their blocks of zeros.
+/// If the filesystem can not punch holes, the whole range is written.
+pub fn copy_sparse_range(
+	src: &File,
+	src_offset: u64,
+	dst: &File,
+	dst_offset: u64,
+	len: u64,
+) -> Result<()> {
+	use std::os::{fd::AsRawFd, unix::fs::FileExt};
+	let punched = unsafe {
+		libc::fallocate(
+			dst.as_raw_fd(),
+			libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+			dst_offset as libc::off_t,
+			len as libc::off_t,
+		)
+	} == 0;
+	let end = src_offset + len;
+	let mut buf = vec![0u8; 1 << 20];
+	let mut offset = src_offset;
+	while offset < end {
+		let (data, hole) = if punched {
+			match next_data_region(src, offset, end)? {
+				Some(r) => r,
+				None => break,
+			}
+		} else {
+			(offset, end)
+		};
+		let mut pos = data;
+		while pos < hole {
+			let n = (hole - pos).min(buf.len() as u64) as usize;
+			src.read_exact_at(&mut buf[..n], pos)?;
+			for (idx, block) in buf[..n].chunks(4096).enumerate() {
+				if !punched || block.iter().any(|&b| b != 0) {
+					dst.write_all_at(
+						block,
+						dst_offset + pos - src_offset + (idx * 4096) as u64,
+					)?;
+				}
+			}
+			pos += n as u64;
+		}
+		offset = hole;
+	}
+	dst.sync_all()?;
+	Ok(())
+}
+
+/// Sector size of the disk, in bytes. Image files are read in 512-byte sectors.
+pub fn sector_size(fd: &mut File) -> Result<u64> {
+	if fd.metadata()?.file_type().is_block_device() {
+		Ok(gptman::linux::get_sector_size(fd)?)
+	} else {
+		Ok(SECTOR_SIZE)
+	}
+}
+
+/// Copy a range with read(2) and write(2), the offset is the same in both files.
+///
+/// Blocks of zeros are skipped, they are already holes in the destination.
+fn copy_range_rw(src: &File, dst: &File, pos: u64, len: u64) -> Result<u64> {
+	use std::os::unix::fs::FileExt;
+	let mut buf = vec![0u8; len.min(1 << 20) as usize];
+	let n = src.read_at(&mut buf, pos)?;
+	let buf = &buf[..n];
+	for (idx, block) in buf.chunks(4096).enumerate() {
+		if block.iter().any(|&b| b != 0) {
+			dst.write_all_at(block, pos + (idx * 4096) as u64)?;
+		}
+	}
+	Ok(n as u64)
+}
+
 /// Tell kernel to reread the partition table.
 pub fn refresh_partition_table<P: AsRef<Path>>(dev: P) -> Result<()> {
 	debug!("Refreshing partition table ...");
 	let dev = dev.as_ref();
 	let mut command = Command::new("partprobe");
-	let command = command.arg("--summary").arg(dev).stdout(Stdio::piped());
-	let out = command
-		.output()
+	let command = command.arg("--summary").arg(dev);
+	let out = runner::output(command)
 		.context("Failed to run partprobe(8) to refresh the partition table")?
 		.stdout;
 	info!("partprobe: {}", String::from_utf8_lossy(&out).trim());
 	Ok(())
 }
 
+/// Get the path of a partition on the given block device.
+///
+/// Devices ending with a digit (e.g. `/dev/loop0`, `/dev/mmcblk0`) have a
+/// `p` between the device name and the partition number.
+pub fn get_partition_path<P: AsRef<Path>>(dev: P, num: u32) -> String {
+	let dev = dev.as_ref().to_string_lossy();
+	if dev.ends_with(|c: char| c.is_ascii_digit()) {
+		format!("{}p{}", dev, num)
+	} else {
+		format!("{}{}", dev, num)
+	}
+}
+
+/// Decode the octal escapes (e.g. `\040` for spaces) in `/proc/self/mounts`.
+fn unescape_mount_path(s: &str) -> String {
+	let bytes = s.as_bytes();
+	let mut result = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		let code = bytes
+			.get(i + 1..i + 4)
+			.filter(|_| bytes[i] == b'\\')
+			.and_then(|o| u8::from_str_radix(std::str::from_utf8(o).ok()?, 8).ok());
+		match code {
+			Some(c) => {
+				result.push(c);
+				i += 4;
+			}
+			None => {
+				result.push(bytes[i]);
+				i += 1;
+			}
+		}
+	}
+	String::from_utf8_lossy(&result).into_owned()
+}
+
+/// Mount points in `mounts` (the content of `/proc/self/mounts`) under `dir`, deepest first.
+fn parse_mounts_under(mounts: &str, dir: &Path) -> Vec<PathBuf> {
+	let mut result = mounts
+		.lines()
+		.filter_map(|l| l.split_whitespace().nth(1))
+		.map(|m| PathBuf::from(unescape_mount_path(m)))
+		.filter(|m| m.starts_with(dir))
+		.collect::<Vec<_>>();
+	result.sort_by_key(|m| std::cmp::Reverse(m.components().count()));
+	result.dedup();
+	result
+}
+
+/// Find the filesystems mounted under the directory, deepest first.
+pub fn mounts_under<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>> {
+	let dir = match dir.as_ref().canonicalize() {
+		Ok(d) => d,
+		// Nothing can be mounted under a directory which does not exist.
+		Err(_) => return Ok(Vec::new()),
+	};
+	let mounts = std::fs::read_to_string("/proc/self/mounts")
+		.context("Failed to read the list of mounted filesystems")?;
+	Ok(parse_mounts_under(&mounts, &dir))
+}
+
+/// Find the loop devices the file is attached to.
+pub fn loop_devices_backed_by<P: AsRef<Path>>(file: P) -> Vec<PathBuf> {
+	let file = match file.as_ref().canonicalize() {
+		Ok(f) => f,
+		Err(_) => return Vec::new(),
+	};
+	let entries = match std::fs::read_dir("/sys/block") {
+		Ok(e) => e,
+		Err(_) => return Vec::new(),
+	};
+	let mut result = Vec::new();
+	for entry in entries.flatten() {
+		let name = entry.file_name().to_string_lossy().to_string();
+		if !name.starts_with("loop") {
+			continue;
+		}
+		let backing = std::fs::read_to_string(entry.path().join("loop/backing_file"))
+			.unwrap_or_default();
+		if Path::new(backing.trim_end()) == file {
+			result.push(PathBuf::from("/dev").join(name));
+		}
+	}
+	result.sort();
+	result
+}
+
+/// Find the program in `PATH`.
+pub fn find_program(program: &str) -> Option<PathBuf> {
+	let paths = std::env::var_os("PATH")?;
+	std::env::split_paths(&paths)
+		.map(|dir| dir.join(program))
+		.find(|p| p.is_file())
+}
+
+/// The closest existing ancestor of the path, where it would be created.
+fn existing_ancestor(path: &Path) -> &Path {
+	path.ancestors()
+		.find(|p| p.exists())
+		.unwrap_or(Path::new("/"))
+}
+
+/// Space available to unprivileged users on the filesystem the path would
+/// be created on, and the ID of the filesystem.
+pub fn available_space<P: AsRef<Path>>(path: P) -> Result<(u64, u64)> {
+	let path = existing_ancestor(path.as_ref());
+	let path_str = CString::new(path.as_os_str().as_bytes())?;
+	let mut stat = unsafe { std::mem::zeroed::<libc::statvfs>() };
+	if unsafe { libc::statvfs(path_str.as_ptr(), &mut stat) } != 0 {
+		bail!(
+			"Failed to get the free space of {}: {}",
+			path.display(),
+			errno::errno()
+		);
+	}
+	let dev = std::fs::metadata(path)?.dev();
+	Ok((stat.f_bavail * stat.f_frsize, dev))
+}
+
+/// Unmount the filesystems and detach the loop devices left by a build which died halfway.
+///
+/// The dm-crypt mappings on the loop devices are closed before they are detached.
+pub fn release_leftovers(mounts: &[PathBuf], loop_devs: &[PathBuf]) -> Result<()> {
+	for mountpoint in mounts {
+		unmount(mountpoint, UnmountFlags::DETACH)
+			.context(format!("Failed to unmount {}", mountpoint.display()))?;
+	}
+	for dev in loop_devs {
+		for name in mappings_on(dev) {
+			close_mapping(&name)?;
+		}
+		LoopDevice::open(dev)
+			.and_then(|l| l.detach())
+			.context(format!("Failed to detach {}", dev.display()))?;
+	}
+	Ok(())
+}
+
+/// Whether `src` is the block device `dev` itself or one of its partitions,
+/// named like [`get_partition_path`] does, e.g. sda1 or mmcblk0p1.
+///
+/// Other devices sharing the prefix, e.g. loop10 for loop1, are not.
+fn is_on_disk(dev: &str, src: &str) -> bool {
+	let Some(rest) = src.strip_prefix(dev) else {
+		return false;
+	};
+	if rest.is_empty() {
+		return true;
+	}
+	let num = if dev.ends_with(|c: char| c.is_ascii_digit()) {
+		rest.strip_prefix('p')
+	} else {
+		Some(rest)
+	};
+	num.is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Refuse to touch a block device if it or any of its partitions is in use.
+pub fn check_block_device_unused<P: AsRef<Path>>(dev: P) -> Result<()> {
+	let dev = dev
+		.as_ref()
+		.canonicalize()
+		.context("Failed to canonicalize the path of the block device")?;
+	let dev_str = dev.to_string_lossy();
+	let is_same_disk = |src: &str| is_on_disk(&dev_str, src);
+	let mounts = std::fs::read_to_string("/proc/self/mounts")
+		.context("Failed to read the list of mounted filesystems")?;
+	for line in mounts.lines() {
+		let mut fields = line.split_whitespace();
+		if let (Some(src), Some(mountpoint)) = (fields.next(), fields.next()) {
+			if is_same_disk(src) {
+				bail!(
+					"{} is mounted at {}, refusing to continue.",
+					src,
+					mountpoint
+				);
+			}
+		}
+	}
+	let swaps = std::fs::read_to_string("/proc/swaps").unwrap_or_default();
+	for line in swaps.lines().skip(1) {
+		if let Some(src) = line.split_whitespace().next() {
+			if is_same_disk(src) {
+				bail!("{} is being used as swap, refusing to continue.", src);
+			}
+		}
+	}
+	Ok(())
+}
+
 /// Run aoscbootstrap to generate a system release
 pub fn bootstrap_distribution<P: AsRef<Path>, S: AsRef<str>>(
-	variant: &ImageVariant,
+	recipe: &BootstrapRecipe,
 	path: P,
-	arch: DeviceArch,
 	mirror: S,
 ) -> Result<()> {
 	let path = path.as_ref();
 	let mirror = mirror.as_ref();
+	let variant = &recipe.variant;
 
 	// Display a progressbar
 	setup_scroll_region();
@@ -103,33 +525,27 @@ pub fn bootstrap_distribution<P: AsRef<Path>, S: AsRef<str>>(
 		variant,
 		path.display()
 	);
+	info!(
+		"Using recipe {} (from the {}).",
+		recipe.recipe.display(),
+		recipe.source
+	);
 	let mut command = Command::new("aoscbootstrap");
 	let command = command
 		.arg("stable")
 		.arg(path)
 		.arg(mirror)
 		.arg("-x")
-		.args([
-			"--config",
-			&format!("{}/{}", AB_DIR, "config/aosc-mainline.toml"),
-		])
-		.args(["--arch", &arch.to_string().to_lowercase()])
-		.args(["-s", &format!("{}/{}", AB_DIR, "scripts/reset-repo.sh")])
-		.args(["-s", &format!("{}/{}", AB_DIR, "scripts/enable-dkms.sh")])
-		.args([
-			"--include-files",
-			&format!(
-				"{}/recipes/mainline/{}-common.lst",
-				AB_DIR,
-				match &variant {
-					ImageVariant::Desktop => "kde".to_owned(),
-					_ => variant.to_string().to_lowercase(),
-				}
-			),
-		]);
+		.arg("--config")
+		.arg(&recipe.config)
+		.args(["--arch", &recipe.arch.to_string().to_lowercase()]);
+	for script in &recipe.scripts {
+		command.arg("-s").arg(script);
+	}
+	command.arg("--include-files").arg(&recipe.recipe);
 
 	debug!("Runnig command {:?} ...", command);
-	let status = command.status().context("Failed to run aoscbootstrap")?;
+	let status = runner::run(command).context("Failed to run aoscbootstrap")?;
 	// Recover the terminal
 	restore_term();
 	if status.success() {
@@ -145,29 +561,196 @@ pub fn bootstrap_distribution<P: AsRef<Path>, S: AsRef<str>>(
 	}
 }
 
-pub fn rsync_sysroot<P: AsRef<Path>>(src: P, dst: P) -> Result<()> {
+/// Extended attributes of a file (symbolic links are not followed), sorted by their names.
+fn get_xattrs(path: &Path) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+	let c_path = CString::new(path.as_os_str().as_bytes())?;
+	let len = unsafe { libc::llistxattr(c_path.as_ptr(), std::ptr::null_mut(), 0) };
+	if len < 0 {
+		bail!(
+			"Failed to list the extended attributes of {}: {}",
+			path.display(),
+			errno::errno()
+		);
+	}
+	let mut names = vec![0u8; len as usize];
+	let len = unsafe {
+		libc::llistxattr(
+			c_path.as_ptr(),
+			names.as_mut_ptr() as *mut c_char,
+			names.len(),
+		)
+	};
+	names.truncate(len.max(0) as usize);
+	let mut xattrs = Vec::new();
+	for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+		let c_name = CString::new(name)?;
+		let len = unsafe {
+			libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0)
+		};
+		let mut value = vec![0u8; len.max(0) as usize];
+		let len = unsafe {
+			libc::lgetxattr(
+				c_path.as_ptr(),
+				c_name.as_ptr(),
+				value.as_mut_ptr() as *mut c_void,
+				value.len(),
+			)
+		};
+		value.truncate(len.max(0) as usize);
+		xattrs.push((name.to_vec(), value));
+	}
+	xattrs.sort();
+	Ok(xattrs)
+}
+
+/// Metadata which must survive the copy: mode, owner, group, mtime and extended attributes.
+type Fidelity = (u32, u32, u32, i64, Vec<(Vec<u8>, Vec<u8>)>);
+
+fn get_fidelity(path: &Path) -> Result<Fidelity> {
+	let m = std::fs::symlink_metadata(path)
+		.context(format!("{} is missing", path.display()))?;
+	Ok((m.mode(), m.uid(), m.gid(), m.mtime(), get_xattrs(path)?))
+}
+
+/// Count the files in the distribution, and pick some files carrying
+/// metadata which is easy to lose (setuid/setgid bits and extended
+/// attributes, e.g. file capabilities) as sentinels.
+fn scan_sysroot(src: &Path) -> Result<(usize, Vec<PathBuf>)> {
+	let mut count = 0;
+	let mut sentinels = Vec::new();
+	for entry in WalkDir::new(src).same_file_system(true) {
+		let entry = entry?;
+		count += 1;
+		if sentinels.len() >= NUM_SENTINELS || !entry.file_type().is_file() {
+			continue;
+		}
+		let mode = entry.metadata()?.mode();
+		if mode & 0o6000 != 0 || !get_xattrs(entry.path())?.is_empty() {
+			sentinels.push(entry.path().strip_prefix(src)?.to_path_buf());
+		}
+	}
+	Ok((count, sentinels))
+}
+
+/// Make sure the metadata of the sentinels is preserved.
+fn check_fidelity(src: &Path, dst: &Path, sentinels: &[PathBuf]) -> Result<()> {
+	for path in sentinels {
+		if get_fidelity(&src.join(path))? != get_fidelity(&dst.join(path))? {
+			bail!(
+				"Metadata of /{} is not preserved during the installation (mode, owner, mtime or extended attributes differ).",
+				path.display()
+			);
+		}
+	}
+	Ok(())
+}
+
+/// Whether the directory is a freshly formatted filesystem.
+fn is_fresh_dir(dir: &Path) -> Result<bool> {
+	for entry in std::fs::read_dir(dir)? {
+		if entry?.file_name() != "lost+found" {
+			return Ok(false);
+		}
+	}
+	Ok(true)
+}
+
+/// How [`rsync_sysroot`] installs the distribution.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SysrootStrategy {
+	Rsync,
+	/// Copy with a tar pipe, then fix up the metadata with rsync.
+	TarPipe,
+}
+
+impl Display for SysrootStrategy {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			Self::Rsync => "rsync",
+			Self::TarPipe => "tar pipe, then rsync for the metadata",
+		})
+	}
+}
+
+/// Install the distribution in `src` to `dst`, returns how it is installed.
+pub fn rsync_sysroot<P: AsRef<Path>>(src: P, dst: P) -> Result<SysrootStrategy> {
 	let src = src.as_ref();
 	let dst = dst.as_ref();
 	if !src.is_dir() || !dst.is_dir() {
 		bail!("Neither directory exists.");
 	}
+	let (count, sentinels) = scan_sysroot(src)?;
+	let fresh = is_fresh_dir(dst)?;
+	// The delta algorithm of rsync is slow with a large number of files,
+	// while there is nothing to compare against in a fresh filesystem.
+	let use_tar = fresh && count > TAR_COPY_THRESHOLD;
+	let strategy = if use_tar {
+		SysrootStrategy::TarPipe
+	} else {
+		SysrootStrategy::Rsync
+	};
 	info!(
-		"Installing the distribution in {} to {} ...",
+		"Installing the distribution in {} to {} ({} files, using {}) ...",
 		src.display(),
-		dst.display()
+		dst.display(),
+		count,
+		strategy
 	);
+	let start = Instant::now();
+	if use_tar {
+		let mut command = Command::new("bash");
+		command.args([
+			"-o",
+			"pipefail",
+			"-c",
+			"tar -C \"$1\" --numeric-owner --xattrs --xattrs-include='*' --acls -cpf - . | tar -C \"$2\" --numeric-owner --xattrs --xattrs-include='*' --acls -xpf -",
+			"tar-pipe",
+		]);
+		command.arg(src).arg(dst);
+		cmd_run_check_status(&mut command)
+			.context("Failed to copy the distribution with tar")?;
+	}
 	let mut command = Command::new("rsync");
-	command.args(["-axAHXSW", "--numeric-ids", "--info=progress2", "--no-i-r"]);
+	command.args([
+		"-axAHXSW",
+		"--numeric-ids",
+		"--info=progress2",
+		"--no-i-r",
+		"--max-alloc=4G",
+	]);
+	if fresh && !use_tar {
+		command.args(["--inplace", "--preallocate"]);
+	}
 	command.arg(format!("{}/", src.to_string_lossy()));
 	command.arg(format!("{}/", dst.to_string_lossy()));
 	debug!("Running command {:?}", command);
-	// return Ok(());
-	cmd_run_check_status(&mut command)
+	cmd_run_check_status(&mut command)?;
+	check_fidelity(src, dst, &sentinels)?;
+	info!(
+		"Installed the distribution using {} in {:.03} seconds.",
+		strategy,
+		start.elapsed().as_secs_f32()
+	);
+	Ok(strategy)
+}
+
+/// Enable or disable the progress bar, only one job can have it.
+pub fn set_progress_bar(enabled: bool) {
+	PROGRESS_BAR.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether the progress bar is drawn.
+pub fn progress_bar_enabled() -> bool {
+	PROGRESS_BAR.load(Ordering::Relaxed)
 }
 
 /// Set up the scroll region (for a progress bar on the bottom)
 #[inline]
 pub fn setup_scroll_region() {
+	if !progress_bar_enabled() {
+		return;
+	}
 	let term_geometry = termsize::get().unwrap_or(Size { rows: 25, cols: 80 });
 	// Set up the scroll region
 	eprint!("\n\x1b7\x1b[0;{}r\x1b8\x1b[1A", term_geometry.rows - 1);
@@ -176,6 +759,9 @@ pub fn setup_scroll_region() {
 /// Recover the terminal
 #[inline]
 pub fn restore_term() {
+	if !progress_bar_enabled() {
+		return;
+	}
 	let term_geometry = termsize::get().unwrap_or(Size { rows: 25, cols: 80 });
 	eprint!(
 		"\x1b7\x1b[0;{}r\x1b[{};0f\x1b[0K\x1b8",
@@ -226,10 +812,40 @@ pub fn sync_filesystem(path: &dyn AsRef<Path>) -> Result<()> {
 	Ok(())
 }
 
+/// Check whether the string looks like a password hashed with crypt(3), e.g. `$6$salt$hash`.
+pub fn check_password_hash(hash: &str) -> Result<()> {
+	let fields = hash.split('$').collect::<Vec<_>>();
+	if fields.len() < 4 || !fields[0].is_empty() {
+		bail!("The hashed password is not in the form of $ID$SALT$HASH");
+	}
+	if !CRYPT_SCHEMES.contains(&fields[1]) {
+		bail!(
+			"Unsupported hashing scheme ${}$, possible schemes are: {}",
+			fields[1],
+			CRYPT_SCHEMES
+				.iter()
+				.map(|s| format!("${}$", s))
+				.collect::<Vec<_>>()
+				.join(", ")
+		);
+	}
+	let valid = fields[2..].iter().all(|f| {
+		!f.is_empty()
+			&& f.chars()
+				.all(|c| c.is_ascii_alphanumeric() || "./=,".contains(c))
+	});
+	if !valid {
+		bail!("The hashed password contains empty fields or invalid characters");
+	}
+	Ok(())
+}
+
+/// Create the user, with the password in plaintext, or hashed with crypt(3) if `hashed`.
 pub fn add_user<S, T, P>(
 	root: P,
 	name: S,
 	password: S,
+	hashed: bool,
 	comment: Option<T>,
 	homedir: Option<P>,
 	groups: Option<&[&str]>,
@@ -268,17 +884,24 @@ where
 		cmd_useradd.args(["-c", c.as_ref()]);
 	}
 	cmd_useradd.arg(name);
-	cmd_chpasswd.stdin(Stdio::piped()).args([&root, "chpasswd"]);
+	cmd_chpasswd.args([&root, "chpasswd"]);
+	if hashed {
+		check_password_hash(password)?;
+		cmd_chpasswd.arg("-e");
+	}
 	cmd_run_check_status(&mut cmd_useradd)?;
-	let mut chpasswd_proc = cmd_chpasswd.spawn().context("Failed to run chpasswd")?;
-	let chpasswd_stdin = chpasswd_proc
-		.stdin
-		.as_mut()
-		.context("Failed to open stdin for chpasswd")?;
 	// echo "$name:$password" | chpasswd -R /target/root
 	let chpasswd_buf = format!("{}:{}", name, password);
-	chpasswd_stdin.write_all(chpasswd_buf.as_bytes())?;
-	chpasswd_proc.wait()?;
+	let status = runner::run_with_input(&mut cmd_chpasswd, chpasswd_buf.as_bytes())
+		.context("Failed to run chpasswd")?;
+	// The input is not shown, it contains the password.
+	if !status.success() {
+		bail!(
+			"Failed to set the password of {}: chpasswd {}",
+			name,
+			status
+		);
+	}
 	Ok(())
 }
 
@@ -297,6 +920,148 @@ pub fn set_locale<S: AsRef<str>, P: AsRef<Path>>(root: P, locale: S) -> Result<(
 	Ok(())
 }
 
+/// Check whether the timezone looks like a zoneinfo name, e.g. `Asia/Shanghai`.
+pub fn check_timezone_name(timezone: &str) -> Result<()> {
+	if timezone.is_empty()
+		|| timezone.starts_with('/')
+		|| timezone
+			.split('/')
+			.any(|c| c.is_empty() || c == "." || c == "..")
+		|| !timezone
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || "/_+-".contains(c))
+	{
+		bail!(
+			"Invalid timezone '{}', expected e.g. Asia/Shanghai",
+			timezone
+		);
+	}
+	Ok(())
+}
+
+/// Check whether the timezone exists in the zoneinfo database of the root.
+pub fn check_timezone<P: AsRef<Path>>(root: P, timezone: &str) -> Result<()> {
+	check_timezone_name(timezone)?;
+	let zone_path = root.as_ref().join(ZONEINFO_PATH).join(timezone);
+	if !zone_path.is_file() {
+		bail!(
+			"Timezone '{}' is not found in the zoneinfo database of {} ({} does not exist).",
+			timezone,
+			root.as_ref().display(),
+			zone_path.display()
+		);
+	}
+	Ok(())
+}
+
+/// Set the timezone of the root, with a relative /etc/localtime symlink and /etc/timezone.
+pub fn set_timezone<P: AsRef<Path>>(root: P, timezone: &str) -> Result<()> {
+	let root = root.as_ref();
+	check_timezone(root, timezone)?;
+	let localtime = root.join(LOCALTIME_PATH);
+	if localtime.symlink_metadata().is_ok() {
+		std::fs::remove_file(&localtime).context("Failed to remove /etc/localtime")?;
+	}
+	std::os::unix::fs::symlink(
+		Path::new("..").join(ZONEINFO_PATH).join(timezone),
+		&localtime,
+	)
+	.context("Failed to create /etc/localtime")?;
+	std::fs::write(root.join(TIMEZONE_PATH), format!("{}\n", timezone))
+		.context("Failed to write /etc/timezone")?;
+	Ok(())
+}
+
+/// Generate a ULID to identify a build.
+///
+/// A ULID consists of a 48-bit timestamp in milliseconds and 80 random bits,
+/// encoded as 26 characters, so that they sort by their creation time.
+pub fn generate_build_id() -> String {
+	let millis = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_millis())
+		.unwrap_or(0);
+	let random = rand::random::<u128>() & ((1 << 80) - 1);
+	let value = ((millis & ((1 << 48) - 1)) << 80) | random;
+	(0..26).rev()
+		.map(|i| ULID_ALPHABET[((value >> (i * 5)) & 0x1f) as usize] as char)
+		.collect()
+}
+
+/// Check whether the hostname is valid (RFC 1123).
+pub fn check_hostname(hostname: &str) -> Result<()> {
+	if hostname.is_empty() || hostname.len() > 253 {
+		bail!("Hostname '{}' must be 1 to 253 characters long", hostname);
+	}
+	for label in hostname.split('.') {
+		if label.is_empty() || label.len() > 63 {
+			bail!(
+				"Every part of hostname '{}' must be 1 to 63 characters long",
+				hostname
+			);
+		}
+		if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+			bail!(
+				"Hostname '{}' can only contain letters, digits, '-' and '.'",
+				hostname
+			);
+		}
+		if label.starts_with('-') || label.ends_with('-') {
+			bail!(
+				"Parts of hostname '{}' can not start or end with '-'",
+				hostname
+			);
+		}
+	}
+	Ok(())
+}
+
+/// Turn a device ID into a valid hostname.
+///
+/// Letters are lowercased, and anything other than letters and digits
+/// becomes a single '-'.
+pub fn sanitize_hostname(id: &str) -> String {
+	let mut hostname = String::new();
+	for c in id.chars() {
+		if c.is_ascii_alphanumeric() {
+			hostname.push(c.to_ascii_lowercase());
+		} else if !hostname.is_empty() && !hostname.ends_with('-') {
+			hostname.push('-');
+		}
+	}
+	hostname.truncate(63);
+	let hostname = hostname.trim_end_matches('-');
+	if hostname.is_empty() {
+		"aosc".to_owned()
+	} else {
+		hostname.to_owned()
+	}
+}
+
+/// Replace the `127.0.1.1` entry in the content of `/etc/hosts`.
+pub fn set_hosts_entry(hosts: &str, hostname: &str) -> String {
+	let mut result = hosts
+		.lines()
+		.filter(|l| l.split_whitespace().next() != Some("127.0.1.1"))
+		.map(|l| format!("{}\n", l))
+		.collect::<String>();
+	result += &format!("127.0.1.1\t{}\n", hostname);
+	result
+}
+
+/// Check the build ID supplied by the user, since it ends up in file contents.
+pub fn check_build_id(id: &str) -> Result<()> {
+	if id.is_empty() || id.len() > 64 {
+		bail!("Build ID must be 1 to 64 characters long");
+	}
+	if !id.chars()
+		.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+	{
+		bail!("Build ID can only contain letters, digits, '-', '_' and '.'");
+	}
+	Ok(())
+}
+
 pub fn check_binfmt(arch: &DeviceArch) -> Result<()> {
 	if arch.is_native() {
 		return Ok(());
@@ -314,9 +1079,7 @@ pub fn check_binfmt(arch: &DeviceArch) -> Result<()> {
 }
 
 pub fn cmd_run_check_status(cmd: &mut Command) -> Result<()> {
-	let result = cmd
-		.status()
-		.context(format!("Failed to run {:?}", cmd.get_program()))?;
+	let result = runner::run(cmd)?;
 	if result.success() {
 		Ok(())
 	} else if let Some(c) = result.code() {
@@ -339,7 +1102,6 @@ pub fn run_str_script_with_chroot(
 	binds: &[&str],
 	shell: Option<&dyn AsRef<str>>,
 ) -> Result<()> {
-	let mut cmd = Command::new("systemd-nspawn");
 	let shell = if let Some(s) = shell {
 		s.as_ref()
 	} else {
@@ -348,13 +1110,13 @@ pub fn run_str_script_with_chroot(
 	// Let's assume all shells supports "-c SCRIPT".
 	// But I think it is better to pipe into the shell's stdin.
 	// bash -c -- script $0 $1 ...
-	// The positional param after "-c script" is $0 of that script.
+	// The positional param after "-c script" is $0 of that script.
 	let script = format!("source /tmp/spec.sh ;{}", script);
-	cmd.args(["-q", "-D", &root.as_ref().to_string_lossy()]);
-	for bind in binds {
-		cmd.args(["--bind", bind]);
-	}
-	cmd.args(["--", shell, "-c", "--", &script, "<tmp_script>"]);
+	let mut cmd = chroot::command(
+		root.as_ref(),
+		binds,
+		&[shell, "-c", "--", &script, "<tmp_script>"],
+	);
 	cmd_run_check_status(&mut cmd)
 }
 
@@ -364,7 +1126,6 @@ pub fn run_script_with_chroot<P: AsRef<Path>>(
 	binds: &[&str],
 	shell: Option<&dyn AsRef<str>>,
 ) -> Result<()> {
-	let mut cmd = Command::new("systemd-nspawn");
 	let shell = if let Some(s) = shell {
 		s.as_ref()
 	} else {
@@ -373,31 +1134,32 @@ pub fn run_script_with_chroot<P: AsRef<Path>>(
 	// Let's assume all shells supports "-c SCRIPT".
 	// But I think it is better to pipe into the shell's stdin.
 	// bash -c -- script $0 $1 ...
-	// The positional param after "-c script" is $0 of that script.
+	// The positional param after "-c script" is $0 of that script.
 	// We are using 'source' to let the script being run to use the information we provided.
 	let full_script = format!(
 		"source /tmp/spec.sh ; source {}",
 		&script.as_ref().to_string_lossy()
 	);
-	cmd.args(["-q", "-D", &root.as_ref().to_string_lossy()]);
-	for bind in binds {
-		cmd.args(["--bind", bind]);
-	}
-	cmd.args([
-		"--",
-		shell,
-		"-c",
-		"--",
-		&full_script,
-		// Set $0 to the path of the script
-		&script.as_ref().to_string_lossy(),
-	]);
+	let mut cmd = chroot::command(
+		root.as_ref(),
+		binds,
+		&[
+			shell,
+			"-c",
+			"--",
+			&full_script,
+			// Set $0 to the path of the script
+			&script.as_ref().to_string_lossy(),
+		],
+	);
 	cmd_run_check_status(&mut cmd).context("Failed to run script with chroot")
 }
 
-/// Get filesystem UUID of the given block device.
-pub fn get_fsuuid(fspath: &dyn AsRef<Path>) -> Result<String> {
-	let fspath = fspath.as_ref();
+/// Probe the filesystem on the given block device with libblkid.
+///
+/// The values are recorded like the output of `blkid -o export`, so that
+/// the probe can be replayed, see [`runner::probe`].
+fn probe_filesystem(fspath: &Path) -> Result<HashMap<String, String>> {
 	// WARNING! ACHTUNG!
 	// libblkid's cache does not cache loop devices.
 	// You will get an EINVAL if you try to use the cache to get FSUUID
@@ -422,18 +1184,39 @@ pub fn get_fsuuid(fspath: &dyn AsRef<Path>) -> Result<String> {
 
 	// We have to do the low-level probing.
 	// Wow, somehow the code is simpler.
-	let probe = blkid::prober::Prober::new_from_filename(fspath)?;
-	let result = probe.do_safe_probe()?;
-	match result {
-		ProbeState::Success => {
-			let x = probe.get_values_map()?;
-			let uuid = x.get("UUID").context("No filesystem UUID found in the probe results; Perhaps there's no filesystem in this partition, or the type of the filesystem can't be identified")?;
-			Ok(uuid.to_owned())
+	let out = runner::probe("libblkid", &[&fspath.to_string_lossy()], || {
+		let probe = blkid::prober::Prober::new_from_filename(fspath)?;
+		match probe.do_safe_probe()? {
+			ProbeState::Success => {
+				// Sorted, the recording must not depend on the order of a hash map.
+				let values: BTreeMap<_, _> =
+					probe.get_values_map()?.into_iter().collect();
+				Ok(values
+					.iter()
+					.map(|(k, v)| format!("{}={}\n", k, v))
+					.collect::<String>()
+					.into_bytes())
+			}
+			_ => bail!("Can not get necessary information of {}", &fspath.display()),
 		}
-		_ => {
-			bail!("Can not get necessary information of {}", &fspath.display());
-		}
-	}
+	})?;
+	Ok(String::from_utf8_lossy(&out)
+		.lines()
+		.filter_map(|l| l.split_once('='))
+		.map(|(k, v)| (k.to_owned(), v.to_owned()))
+		.collect())
+}
+
+/// Get filesystem UUID of the given block device.
+pub fn get_fsuuid(fspath: &dyn AsRef<Path>) -> Result<FsUuid> {
+	let values = probe_filesystem(fspath.as_ref())?;
+	let uuid = values.get("UUID").context("No filesystem UUID found in the probe results; Perhaps there's no filesystem in this partition, or the type of the filesystem can't be identified")?;
+	uuid.parse()
+}
+
+/// Get the filesystem label of the given block device, `None` if it has no label.
+pub fn get_fslabel(fspath: &dyn AsRef<Path>) -> Result<Option<String>> {
+	Ok(probe_filesystem(fspath.as_ref())?.remove("LABEL"))
 }
 
 /// Change the ownership of a filesystem object, recursively.
@@ -455,10 +1238,415 @@ pub fn return_ownership_recursive(
 	Ok(())
 }
 
+/// Directories in the target which hold Wi-Fi credentials, and the keys of the pre-shared keys in their files.
+const WIFI_PSK_DIRS: &[(&str, &[&str])] = &[
+	("etc/NetworkManager/system-connections", &["psk="]),
+	("etc/wpa_supplicant", &["psk="]),
+	("var/lib/iwd", &["Passphrase=", "PreSharedKey="]),
+];
+
+/// Find a file holding a Wi-Fi pre-shared key in the target.
+pub fn find_wifi_psk(root: &Path) -> Result<Option<PathBuf>> {
+	for (dir, keys) in WIFI_PSK_DIRS {
+		let Ok(entries) = std::fs::read_dir(root.join(dir)) else {
+			continue;
+		};
+		for entry in entries {
+			let path = entry?.path();
+			if !path.is_file() {
+				continue;
+			}
+			let content = std::fs::read(&path)?;
+			let found = String::from_utf8_lossy(&content).lines().any(|line| {
+				keys.iter().any(|key| {
+					line.trim()
+						.strip_prefix(key)
+						.is_some_and(|value| !value.trim().is_empty())
+				})
+			});
+			if found {
+				return Ok(Some(path));
+			}
+		}
+	}
+	Ok(None)
+}
+
+/// Make an artifact unreadable by others (mode 0640), and return the
+/// directories from its parent up to `outdir` which are still accessible by
+/// others.
+pub fn restrict_artifact(artifact: &Path, outdir: &Path) -> Result<Vec<PathBuf>> {
+	std::fs::set_permissions(artifact, Permissions::from_mode(0o640)).context(format!(
+		"Failed to change the permissions of {}",
+		artifact.display()
+	))?;
+	let mut exposed = Vec::new();
+	for dir in artifact.ancestors().skip(1) {
+		if dir.as_os_str().is_empty() {
+			break;
+		}
+		if std::fs::metadata(dir)?.permissions().mode() & 0o007 != 0 {
+			exposed.push(dir.to_path_buf());
+		}
+		if dir == outdir {
+			break;
+		}
+	}
+	Ok(exposed)
+}
+
+/// Force the user to change the password on the first login.
+///
+/// Same as `chage -d 0`, but edits `/etc/shadow` directly to avoid another chroot.
+pub fn expire_password<P, S>(root: P, name: S) -> Result<()>
+where
+	P: AsRef<Path>,
+	S: AsRef<str>,
+{
+	let name = name.as_ref();
+	let path = root.as_ref().join(SHADOW_PATH);
+	let content = std::fs::read_to_string(&path).context("Failed to read /etc/shadow")?;
+	let mut found = false;
+	let mut result = String::with_capacity(content.len());
+	for line in content.lines() {
+		let mut fields = line.split(':').collect::<Vec<_>>();
+		if fields.len() == 9 && fields[0] == name {
+			// The date of the last password change, 0 means it must be changed.
+			fields[2] = "0";
+			found = true;
+		}
+		result += &fields.join(":");
+		result.push('\n');
+	}
+	if !found {
+		bail!("User '{}' is not found in /etc/shadow", name);
+	}
+	// Writing to the existing file keeps its permissions.
+	std::fs::write(&path, result).context("Failed to write /etc/shadow")?;
+	Ok(())
+}
+
+/// Whether the password of the built-in user expires on the first login if
+/// not specified: only the default password does, unless there is an SSH key
+/// to log in with.
+pub fn expire_password_by_default(password: &str, has_ssh_key: bool) -> bool {
+	password == DEFAULT_PASSWORD && !has_ssh_key
+}
+
+/// Check whether the user has to change the password on the first login.
+pub fn is_password_expired<P, S>(root: P, name: S) -> Result<bool>
+where
+	P: AsRef<Path>,
+	S: AsRef<str>,
+{
+	let name = name.as_ref();
+	let content = std::fs::read_to_string(root.as_ref().join(SHADOW_PATH))
+		.context("Failed to read /etc/shadow")?;
+	let entry = content
+		.lines()
+		.map(|l| l.split(':').collect::<Vec<_>>())
+		.find(|f| f.len() == 9 && f[0] == name)
+		.context(format!("User '{}' is not found in /etc/shadow", name))?;
+	Ok(entry[2] == "0")
+}
+
 #[cfg(test)]
 mod tests {
-	use super::get_fsuuid;
+	use super::{
+		check_build_id, check_fidelity, check_hostname, check_password_hash, copy_sparse,
+		copy_sparse_range, expire_password, expire_password_by_default, find_wifi_psk,
+		generate_build_id, get_fsuuid, get_partition_path, get_sparse_file, is_fresh_dir,
+		is_on_disk, is_password_expired, parse_mounts_under, restrict_artifact,
+		sanitize_hostname, scan_sysroot, set_hosts_entry, set_timezone, DEFAULT_PASSWORD,
+		SHADOW_PATH, ULID_ALPHABET,
+	};
+	use crate::testutil::TempDir;
 	use anyhow::Result;
+	use std::path::Path;
+
+	#[test]
+	fn test_is_on_disk() {
+		assert!(is_on_disk("/dev/loop1", "/dev/loop1"));
+		assert!(is_on_disk(
+			"/dev/loop1",
+			&get_partition_path("/dev/loop1", 2)
+		));
+		assert!(!is_on_disk("/dev/loop1", "/dev/loop10"));
+		assert!(!is_on_disk("/dev/loop1", "/dev/loop10p2"));
+		assert!(!is_on_disk("/dev/loop1", "/dev/loop1p"));
+		assert!(is_on_disk("/dev/sda", &get_partition_path("/dev/sda", 1)));
+		assert!(!is_on_disk("/dev/sda", "/dev/sdab"));
+		assert!(!is_on_disk("/dev/sda", "/dev/sdap1"));
+	}
+
+	#[test]
+	fn test_build_id() -> Result<()> {
+		let id = generate_build_id();
+		assert_eq!(id.len(), 26);
+		assert!(id.bytes().all(|c| ULID_ALPHABET.contains(&c)));
+		// The first character only carries 3 bits.
+		assert!(id.as_bytes()[0] <= b'7');
+		check_build_id(&id)?;
+		assert!(check_build_id("").is_err());
+		assert!(check_build_id("ci'; rm -rf /").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_check_password_hash() {
+		assert!(check_password_hash("$6$rounds=5000$saltsalt$IxDD3jeSOb5eB1CX5LBsqZFVkJdido3OUILO5Ifz5iwMuTS4XMS130MTSuDDl3aCI6WouIL9AjRbLCelDCy.g.").is_ok());
+		assert!(check_password_hash(
+			"$y$j9T$F5Jx5fExrKuPp53xLKQ..1$X3DX6M94c7o.9agCG9G317fhZg9SqC.5i5rd.RhAtQ7"
+		)
+		.is_ok());
+		assert!(check_password_hash(
+			"$2b$12$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW"
+		)
+		.is_ok());
+		assert!(check_password_hash("anthon").is_err());
+		// MD5 is too weak.
+		assert!(check_password_hash("$1$salt$hash").is_err());
+		assert!(check_password_hash("$6$salt$").is_err());
+		assert!(check_password_hash("$6$salt$hash:0").is_err());
+		assert!(check_password_hash("$6$salt$hash\nroot:x").is_err());
+	}
+
+	#[test]
+	fn test_expire_password_by_default() {
+		assert!(expire_password_by_default(DEFAULT_PASSWORD, false));
+		assert!(!expire_password_by_default(DEFAULT_PASSWORD, true));
+		assert!(!expire_password_by_default("correct horse", false));
+	}
+
+	#[test]
+	fn test_expire_password() -> Result<()> {
+		let root = TempDir::new("shadow")?;
+		std::fs::create_dir_all(root.join("etc"))?;
+		std::fs::write(
+			root.join(SHADOW_PATH),
+			"root:*:19000:0:99999:7:::\naosc:$y$j9T$salt$hash:20000:0:99999:7:::\n",
+		)?;
+		assert!(!is_password_expired(&root, "aosc")?);
+		expire_password(&root, "aosc")?;
+		assert!(is_password_expired(&root, "aosc")?);
+		assert!(!is_password_expired(&root, "root")?);
+		assert_eq!(
+			std::fs::read_to_string(root.join(SHADOW_PATH))?,
+			"root:*:19000:0:99999:7:::\naosc:$y$j9T$salt$hash:0:0:99999:7:::\n"
+		);
+		assert!(expire_password(&root, "nobody").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_sysroot_fidelity() -> Result<()> {
+		use std::{fs, os::unix::fs::PermissionsExt};
+
+		let base = TempDir::new("fidelity")?;
+		let (src, dst) = (base.join("src"), base.join("dst"));
+		fs::create_dir_all(src.join("usr/bin"))?;
+		fs::create_dir_all(dst.join("lost+found"))?;
+		assert!(is_fresh_dir(&dst)?);
+		fs::write(src.join("usr/bin/true"), "")?;
+		fs::write(src.join("usr/bin/su"), "")?;
+		fs::set_permissions(src.join("usr/bin/su"), fs::Permissions::from_mode(0o4755))?;
+		let (count, sentinels) = scan_sysroot(&src)?;
+		assert_eq!(count, 5);
+		assert_eq!(sentinels, vec![Path::new("usr/bin/su")]);
+		// A copy which drops the setuid bit.
+		fs::create_dir_all(dst.join("usr/bin"))?;
+		fs::copy(src.join("usr/bin/su"), dst.join("usr/bin/su"))?;
+		assert!(!is_fresh_dir(&dst)?);
+		fs::set_permissions(dst.join("usr/bin/su"), fs::Permissions::from_mode(0o755))?;
+		assert!(check_fidelity(&src, &dst, &sentinels).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_restrict_artifact() -> Result<()> {
+		use std::{fs, os::unix::fs::PermissionsExt};
+
+		let outdir = TempDir::new("artifact")?;
+		let dir = outdir.join("os-arm64/desktop/rawimg/raspberrypi");
+		fs::create_dir_all(&dir)?;
+		let artifact = dir.join("aosc-os.img.xz");
+		fs::write(&artifact, "")?;
+		fs::set_permissions(&artifact, fs::Permissions::from_mode(0o644))?;
+		for d in artifact.ancestors().skip(1).take(5) {
+			fs::set_permissions(d, fs::Permissions::from_mode(0o750))?;
+		}
+		fs::set_permissions(&outdir, fs::Permissions::from_mode(0o755))?;
+		fs::set_permissions(&dir, fs::Permissions::from_mode(0o711))?;
+		assert_eq!(
+			restrict_artifact(&artifact, &outdir)?,
+			vec![dir.clone(), outdir.to_path_buf()]
+		);
+		assert_eq!(fs::metadata(&artifact)?.permissions().mode() & 0o777, 0o640);
+		Ok(())
+	}
+
+	#[test]
+	fn test_find_wifi_psk() -> Result<()> {
+		let root = TempDir::new("wifi")?;
+		assert_eq!(find_wifi_psk(&root)?, None);
+		let dir = root.join("etc/NetworkManager/system-connections");
+		std::fs::create_dir_all(&dir)?;
+		std::fs::write(
+			dir.join("wired.nmconnection"),
+			"[connection]\nid=wired\ntype=ethernet\n",
+		)?;
+		std::fs::write(dir.join("open.nmconnection"), "[wifi-security]\npsk=\n")?;
+		assert_eq!(find_wifi_psk(&root)?, None);
+		let iwd = root.join("var/lib/iwd");
+		std::fs::create_dir_all(&iwd)?;
+		std::fs::write(iwd.join("home.psk"), "[Security]\nPassphrase=hunter22\n")?;
+		assert_eq!(find_wifi_psk(&root)?, Some(iwd.join("home.psk")));
+		Ok(())
+	}
+
+	#[test]
+	fn test_copy_sparse() -> Result<()> {
+		use std::{
+			fs,
+			os::unix::fs::{FileExt, MetadataExt},
+		};
+
+		const MIB: u64 = 1 << 20;
+		let base = TempDir::new("sparse")?;
+		let (src, dst) = (base.join("src.img"), base.join("dst.img"));
+		// Data at 0 and 8MiB, holes everywhere else, and a hole at the end.
+		let fd = fs::File::create(&src)?;
+		fd.set_len(32 * MIB)?;
+		fd.write_all_at(&[0x55; 4096], 0)?;
+		fd.write_all_at(&vec![0xaa; MIB as usize], 8 * MIB)?;
+		fd.sync_all()?;
+		drop(fd);
+		let mut reported = (0, 0);
+		let copied = copy_sparse(&src, &dst, |c, s| reported = (c, s))?;
+		assert_eq!(reported, (copied, 32 * MIB));
+		let (src_meta, dst_meta) = (fs::metadata(&src)?, fs::metadata(&dst)?);
+		assert_eq!(dst_meta.len(), 32 * MIB);
+		assert_eq!(fs::read(&src)?, fs::read(&dst)?);
+		// Filesystems may allocate a bit more than the data, but not the holes.
+		assert!(dst_meta.blocks() <= src_meta.blocks().max(8) * 2);
+		assert!(dst_meta.blocks() * 512 < 4 * MIB);
+		// Copying over an existing file.
+		fs::write(&src, "short")?;
+		copy_sparse(&src, &dst, |_, _| ())?;
+		assert_eq!(fs::read(&dst)?, b"short");
+		Ok(())
+	}
+
+	#[test]
+	fn test_copy_sparse_range() -> Result<()> {
+		use std::{fs, os::unix::fs::FileExt};
+
+		const MIB: u64 = 1 << 20;
+		let base = TempDir::new("sparse-range")?;
+		let (part, image) = (base.join("part.img"), base.join("image.img"));
+		let part_fd = get_sparse_file(&part, 4 * MIB)?;
+		part_fd.write_all_at(&[0x55; 4096], 0)?;
+		part_fd.write_all_at(&[0xaa; 100], 3 * MIB)?;
+		let part_fd = fs::File::open(&part)?;
+		// Stale data where the partition goes, and data around it.
+		let image_fd = fs::File::options()
+			.read(true)
+			.write(true)
+			.create_new(true)
+			.open(&image)?;
+		image_fd.set_len(8 * MIB)?;
+		image_fd.write_all_at(&vec![0x11; 6 * MIB as usize], MIB)?;
+		copy_sparse_range(&part_fd, 0, &image_fd, 2 * MIB, 4 * MIB)?;
+		let content = fs::read(&image)?;
+		assert_eq!(content.len() as u64, 8 * MIB);
+		assert!(content[MIB as usize..2 * MIB as usize]
+			.iter()
+			.all(|&b| b == 0x11));
+		assert_eq!(
+			&content[2 * MIB as usize..][..4 * MIB as usize],
+			fs::read(&part)?
+		);
+		assert!(content[6 * MIB as usize..7 * MIB as usize]
+			.iter()
+			.all(|&b| b == 0x11));
+		// And back out of the image.
+		let copy = base.join("copy.img");
+		let copy_fd = get_sparse_file(&copy, 4 * MIB)?;
+		copy_sparse_range(&image_fd, 2 * MIB, &copy_fd, 0, 4 * MIB)?;
+		assert_eq!(fs::read(&copy)?, fs::read(&part)?);
+		Ok(())
+	}
+
+	#[test]
+	fn test_hostname() {
+		assert_eq!(sanitize_hostname("rpi-5b"), "rpi-5b");
+		assert_eq!(sanitize_hostname("VisionFive_2"), "visionfive-2");
+		assert_eq!(sanitize_hostname("_pc__EFI_"), "pc-efi");
+		assert_eq!(sanitize_hostname("___"), "aosc");
+		let long = sanitize_hostname(&format!("{}_x", "a".repeat(62)));
+		assert_eq!(long, "a".repeat(62));
+		for id in ["rpi-5b", "VisionFive_2", "_pc__EFI_", "___", "Ä"] {
+			check_hostname(&sanitize_hostname(id)).unwrap();
+		}
+		assert!(check_hostname("lab-01.example.org").is_ok());
+		assert!(check_hostname("VisionFive2").is_ok());
+		assert!(check_hostname("vision_five").is_err());
+		assert!(check_hostname("-rpi").is_err());
+		assert!(check_hostname("rpi..lab").is_err());
+		assert!(check_hostname(&"a".repeat(64)).is_err());
+		assert!(check_hostname("").is_err());
+		let hosts = "127.0.0.1\tlocalhost\n127.0.1.1\told-name\n::1\tlocalhost";
+		assert_eq!(
+			set_hosts_entry(hosts, "rpi-5b"),
+			"127.0.0.1\tlocalhost\n::1\tlocalhost\n127.0.1.1\trpi-5b\n"
+		);
+		assert_eq!(set_hosts_entry("", "rpi-5b"), "127.0.1.1\trpi-5b\n");
+	}
+
+	#[test]
+	fn test_set_timezone() -> Result<()> {
+		use std::fs;
+		let root = TempDir::new("timezone")?;
+		fs::create_dir_all(root.join("etc"))?;
+		fs::create_dir_all(root.join("usr/share/zoneinfo/Asia"))?;
+		fs::write(root.join("usr/share/zoneinfo/Asia/Shanghai"), "TZif")?;
+		std::os::unix::fs::symlink("/usr/share/zoneinfo/UTC", root.join("etc/localtime"))?;
+		set_timezone(&root, "Asia/Shanghai")?;
+		assert_eq!(
+			fs::read_link(root.join("etc/localtime"))?,
+			Path::new("../usr/share/zoneinfo/Asia/Shanghai")
+		);
+		assert_eq!(fs::read(root.join("etc/localtime"))?, b"TZif");
+		assert_eq!(
+			fs::read_to_string(root.join("etc/timezone"))?,
+			"Asia/Shanghai\n"
+		);
+		let err = set_timezone(&root, "Asia/Shangai").unwrap_err().to_string();
+		assert!(err.contains("'Asia/Shangai' is not found"));
+		assert!(set_timezone(&root, "../../etc/passwd").is_err());
+		assert!(set_timezone(&root, "/usr/share/zoneinfo/Asia/Shanghai").is_err());
+		assert!(set_timezone(&root, "Asia/").is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_mounts_under() {
+		let mounts = "\
+/dev/nvme0n1p2 / btrfs rw,relatime 0 0
+/dev/loop0p2 /work/sketches/rpi-5b-Base/mnt/p2 ext4 rw 0 0
+/dev/loop0p1 /work/sketches/rpi-5b-Base/mnt/p2/boot/firmware vfat rw 0 0
+tmpfs /work/sketches/rpi-5b-Base/mnt/p2/tmp tmpfs rw 0 0
+/dev/loop1p2 /work/sketches/rpi-5b-Base2/mnt/p2 ext4 rw 0 0
+/dev/loop2p1 /work/sketches/pc\\040efi-Base/mnt/p1 vfat rw 0 0
+";
+		let found = parse_mounts_under(mounts, Path::new("/work/sketches/rpi-5b-Base"));
+		assert_eq!(found.len(), 3);
+		assert_eq!(found[2], Path::new("/work/sketches/rpi-5b-Base/mnt/p2"));
+		assert!(found[..2]
+			.contains(&Path::new("/work/sketches/rpi-5b-Base/mnt/p2/tmp").into()));
+		let found = parse_mounts_under(mounts, Path::new("/work/sketches/pc efi-Base"));
+		assert_eq!(found, [Path::new("/work/sketches/pc efi-Base/mnt/p1")]);
+	}
 
 	#[test]
 	fn test_get_uuid() -> Result<()> {
@@ -0,0 +1,335 @@
+//! Variables in the device specifications, defined in the `[variables]` table.
+//!
+//! Specifications which only differ in a few words, e.g. the name of the SoC
+//! in the paths of the bootloaders, can define them once and refer to them
+//! with `${NAME}` in the string values:
+//!
+//! ```toml
+//! soc_vendor = "rockchip"
+//!
+//! [variables]
+//! SOC = "rk3588"
+//! UBOOT_DIR = "/usr/lib/u-boot/${SOC_VENDOR}-${SOC}"
+//!
+//! [[bootloaders]]
+//! type = "flash_offset"
+//! path = "${UBOOT_DIR}/idbloader.img"
+//! offset = 64
+//! ```
+//!
+//! The following variables are built in, taken from the keys of the
+//! specification:
+//!
+//! - `${ARCH}`: `arch`, e.g. `arm64`.
+//! - `${DEVICE_ID}`: `id`.
+//! - `${VENDOR}`: `vendor`.
+//! - `${SOC_VENDOR}`: `soc_vendor`, if defined.
+//!
+//! The bodies handed over to the target as they are, which use `${var}` on
+//! their own, are not expanded:
+//!
+//! - `kernel_cmdline`.
+//! - `content` of the `[[paths]]`.
+//! - `script` of the `uboot_script` bootloaders.
+//! - `append` of the `extlinux` bootloaders and their entries.
+//!
+//! Variables can refer to other variables and to the built-in ones, which
+//! can not be redefined. `$$` is a literal `$`, any other `$` not followed by
+//! `{` is left as is.
+//!
+//! The variables are expanded after the bases are applied (see
+//! [`crate::extends`]), so a base can define the variables used by the
+//! devices extending it, and the devices can override them. Undefined
+//! variables and cycles are refused, naming the variable and the key it is
+//! referred to in.
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context, Result};
+use toml::{Table, Value};
+
+/// Key of the table defining the variables.
+pub const VARIABLES_KEY: &str = "variables";
+
+/// Names of the built-in variables, with the keys they are taken from.
+const BUILTINS: &[(&str, &str)] = &[
+	("ARCH", "arch"),
+	("DEVICE_ID", "id"),
+	("VENDOR", "vendor"),
+	("SOC_VENDOR", "soc_vendor"),
+];
+
+/// Fields not expanded, with the indices of the arrays left out.
+const VERBATIM: &[&str] = &[
+	"kernel_cmdline",
+	"paths.content",
+	"bootloaders.script",
+	"bootloader.script",
+	"bootloaders.append",
+	"bootloader.append",
+	"bootloaders.entries.append",
+	"bootloader.entries.append",
+];
+
+/// Resolves the variables, on demand.
+struct Variables {
+	/// Values of the variables as defined, not yet expanded.
+	defined: BTreeMap<String, String>,
+	resolved: BTreeMap<String, String>,
+	/// The variables being resolved, to find the cycles.
+	resolving: Vec<String>,
+}
+
+impl Variables {
+	fn new(table: &mut Table) -> Result<Self> {
+		let mut defined = BTreeMap::new();
+		let mut resolved = BTreeMap::new();
+		for (name, key) in BUILTINS {
+			if let Some(value) = table.get(*key).and_then(Value::as_str) {
+				resolved.insert(name.to_string(), value.to_owned());
+			}
+		}
+		match table.remove(VARIABLES_KEY) {
+			None => (),
+			Some(Value::Table(variables)) => {
+				for (name, value) in variables {
+					if BUILTINS.iter().any(|(builtin, _)| *builtin == name) {
+						bail!("'{}' is a built-in variable, it can not be redefined", name);
+					}
+					if !is_name(&name) {
+						bail!("Invalid variable name '{}', expected letters, digits and underscores", name);
+					}
+					match value {
+						Value::String(value) => defined.insert(name, value),
+						v => bail!(
+							"Variable '{}' must be a string, got '{}'",
+							name,
+							v
+						),
+					};
+				}
+			}
+			Some(_) => bail!("'{}' must be a table", VARIABLES_KEY),
+		}
+		Ok(Self {
+			defined,
+			resolved,
+			resolving: Vec::new(),
+		})
+	}
+
+	/// Get the expanded value of a variable, `None` if it is not defined.
+	fn get(&mut self, name: &str) -> Result<Option<String>> {
+		if let Some(value) = self.resolved.get(name) {
+			return Ok(Some(value.clone()));
+		}
+		let Some(value) = self.defined.get(name).cloned() else {
+			return Ok(None);
+		};
+		if let Some(pos) = self.resolving.iter().position(|n| n == name) {
+			bail!(
+				"Cycle in the variables: {} -> {}",
+				self.resolving[pos..].join(" -> "),
+				name
+			);
+		}
+		self.resolving.push(name.to_owned());
+		let field = format!("{}.{}", VARIABLES_KEY, name);
+		let value = expand(&value, &field, |n| self.get(n))?;
+		self.resolving.pop();
+		self.resolved.insert(name.to_owned(), value.clone());
+		Ok(Some(value))
+	}
+}
+
+fn is_name(name: &str) -> bool {
+	!name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Expand the variables in the text of the field.
+fn expand<F>(text: &str, field: &str, mut lookup: F) -> Result<String>
+where
+	F: FnMut(&str) -> Result<Option<String>>,
+{
+	let mut result = String::with_capacity(text.len());
+	let mut rest = text;
+	while let Some(pos) = rest.find('$') {
+		result += &rest[..pos];
+		rest = &rest[pos + 1..];
+		if let Some(after) = rest.strip_prefix('$') {
+			result.push('$');
+			rest = after;
+		} else if let Some(after) = rest.strip_prefix('{') {
+			let end = after.find('}').context(format!(
+				"Unterminated variable reference in '{}', use $$ for a literal $",
+				field
+			))?;
+			let name = &after[..end];
+			if !is_name(name) {
+				bail!("Invalid variable name '{}' in '{}'", name, field);
+			}
+			match lookup(name)? {
+				Some(value) => result += &value,
+				None => bail!("Undefined variable '{}' in '{}'", name, field),
+			}
+			rest = &after[end + 1..];
+		} else {
+			result.push('$');
+		}
+	}
+	result += rest;
+	Ok(result)
+}
+
+/// Whether the field is left as is, see [`VERBATIM`].
+fn is_verbatim(field: &str) -> bool {
+	let keys = field
+		.split('.')
+		.filter(|k| k.parse::<usize>().is_err())
+		.collect::<Vec<_>>()
+		.join(".");
+	VERBATIM.contains(&keys.as_str())
+}
+
+fn expand_value(value: &mut Value, field: &str, variables: &mut Variables) -> Result<()> {
+	if is_verbatim(field) {
+		return Ok(());
+	}
+	match value {
+		Value::String(s) => *s = expand(s, field, |n| variables.get(n))?,
+		Value::Array(values) => {
+			for (idx, value) in values.iter_mut().enumerate() {
+				expand_value(value, &format!("{}.{}", field, idx), variables)?;
+			}
+		}
+		Value::Table(table) => {
+			for (key, value) in table.iter_mut() {
+				expand_value(value, &format!("{}.{}", field, key), variables)?;
+			}
+		}
+		_ => (),
+	}
+	Ok(())
+}
+
+/// Expand the variables in the string values of the specification, and remove the `[variables]` table.
+pub fn expand_variables(table: &mut Table) -> Result<()> {
+	let mut variables = Variables::new(table)?;
+	for (key, value) in table.iter_mut() {
+		expand_value(value, key, &mut variables)?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn spec(variables: &[(&str, &str)]) -> Table {
+		let mut table = Table::new();
+		table.insert("id".into(), Value::String("rock-5b".into()));
+		table.insert("arch".into(), Value::String("arm64".into()));
+		table.insert("soc_vendor".into(), Value::String("rockchip".into()));
+		let mut vars = Table::new();
+		for (name, value) in variables {
+			vars.insert(name.to_string(), Value::String(value.to_string()));
+		}
+		table.insert(VARIABLES_KEY.into(), Value::Table(vars));
+		table
+	}
+
+	fn bootloader(path: &str) -> Value {
+		let mut bl = Table::new();
+		bl.insert("type".into(), Value::String("flash_offset".into()));
+		bl.insert("path".into(), Value::String(path.into()));
+		Value::Array(vec![Value::Table(bl)])
+	}
+
+	fn bootloader_path(table: &Table) -> &str {
+		table["bootloaders"].as_array().unwrap()[0]
+			.as_table()
+			.unwrap()["path"]
+			.as_str()
+			.unwrap()
+	}
+
+	#[test]
+	fn test_expand() -> Result<()> {
+		let lookup = |name: &str| Ok((name == "SOC").then(|| "rk3588".to_string()));
+		assert_eq!(
+			expand("u-boot-${SOC}.bin", "f", lookup)?,
+			"u-boot-rk3588.bin"
+		);
+		assert_eq!(
+			expand("$${SOC} costs $5$", "f", lookup)?,
+			"${SOC} costs $5$"
+		);
+		assert_eq!(expand("$$${SOC}", "f", lookup)?, "$rk3588");
+		assert_eq!(expand("$$$${SOC}", "f", lookup)?, "$${SOC}");
+		let err = expand("${BOARD}", "bootloaders.0.path", lookup).unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"Undefined variable 'BOARD' in 'bootloaders.0.path'"
+		);
+		assert!(expand("${SOC", "f", lookup).is_err());
+		assert!(expand("${SOC-1}", "f", lookup).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_expand_variables() -> Result<()> {
+		let mut table = spec(&[
+			("SOC", "rk3588"),
+			("UBOOT_DIR", "/usr/lib/u-boot/${SOC_VENDOR}-${SOC}"),
+			("IDBLOADER", "${UBOOT_DIR}/idbloader-${DEVICE_ID}.img"),
+		]);
+		table.insert("bootloaders".into(), bootloader("${IDBLOADER}"));
+		table.insert("name".into(), Value::String("${ARCH} $$5".into()));
+		table.insert(
+			"kernel_cmdline".into(),
+			Value::String("root=${root} $$".into()),
+		);
+		expand_variables(&mut table)?;
+		assert_eq!(
+			bootloader_path(&table),
+			"/usr/lib/u-boot/rockchip-rk3588/idbloader-rock-5b.img"
+		);
+		assert_eq!(table["name"].as_str(), Some("arm64 $5"));
+		assert_eq!(table["kernel_cmdline"].as_str(), Some("root=${root} $$"));
+		assert!(!table.contains_key(VARIABLES_KEY));
+		Ok(())
+	}
+
+	#[test]
+	fn test_is_verbatim() {
+		assert!(is_verbatim("kernel_cmdline"));
+		assert!(is_verbatim("paths.3.content"));
+		assert!(is_verbatim("bootloaders.0.script"));
+		assert!(is_verbatim("bootloader.1.entries.12.append"));
+		assert!(!is_verbatim("bootloaders.0.path"));
+		assert!(!is_verbatim("paths.0.path"));
+		assert!(!is_verbatim("script"));
+	}
+
+	#[test]
+	fn test_expand_variables_errors() {
+		let mut table = spec(&[("SOC", "rk3588")]);
+		table.insert(
+			"bootloaders".into(),
+			bootloader("${UBOOT_DIR}/idbloader.img"),
+		);
+		let err = expand_variables(&mut table).unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"Undefined variable 'UBOOT_DIR' in 'bootloaders.0.path'"
+		);
+		let mut table = spec(&[("A", "${B}"), ("B", "${A}")]);
+		table.insert("name".into(), Value::String("${A}".into()));
+		let err = expand_variables(&mut table).unwrap_err();
+		assert_eq!(err.to_string(), "Cycle in the variables: A -> B -> A");
+		let mut table = spec(&[("A", "${SOC}")]);
+		table.insert("name".into(), Value::String("${A}".into()));
+		let err = expand_variables(&mut table).unwrap_err();
+		assert_eq!(err.to_string(), "Undefined variable 'SOC' in 'variables.A'");
+		assert!(expand_variables(&mut spec(&[("ARCH", "amd64")])).is_err());
+	}
+}
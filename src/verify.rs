@@ -0,0 +1,249 @@
+//! Verification of the partition tables written to the images.
+//!
+//! Writing a partition table can not fail loudly on every mistake, e.g. the
+//! backup GPT header landing in the wrong place after a miscalculated image
+//! size is only noticed by the firmware on the first boot. Right after the
+//! partition table is written, it is read back from the image and compared
+//! with what was intended:
+//!
+//! - The disk GUID of the GPT, or the disk signature of the MBR.
+//! - The number of the partitions.
+//! - The boundaries and the types of the partitions, including the extended
+//!   partition and the logical partitions of a MBR.
+//! - The unique GUIDs of the GPT partitions, i.e. the PARTUUIDs referred to
+//!   by the kernel command line and `/etc/fstab`.
+//!
+//! For GPT, the backup header must also sit at the last LBA of the image,
+//! and both the headers must point to each other. Any mismatch fails the
+//! build.
+use std::{
+	fmt::Display,
+	fs::File,
+	io::{Read, Seek, SeekFrom},
+	path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use gptman::GPT;
+use mbrman::MBR;
+use uuid::Uuid;
+
+/// Signature of the GPT headers.
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// A partition as recorded in the partition table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct TableEntry {
+	num: u32,
+	/// First and last sectors, both inclusive.
+	start: u64,
+	end: u64,
+	/// Partition type GUID, or the system ID byte of a MBR partition.
+	part_type: String,
+	/// Unique partition GUID, GPT only.
+	part_uuid: Option<String>,
+}
+
+impl Display for TableEntry {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"sectors {}-{}, type {}",
+			self.start, self.end, self.part_type
+		)?;
+		if let Some(uuid) = &self.part_uuid {
+			write!(f, ", PARTUUID {}", uuid)?;
+		}
+		Ok(())
+	}
+}
+
+fn gpt_entries(table: &GPT) -> Vec<TableEntry> {
+	table.iter()
+		.filter(|(_, p)| p.is_used())
+		.map(|(num, p)| TableEntry {
+			num,
+			start: p.starting_lba,
+			end: p.ending_lba,
+			part_type: Uuid::from_bytes_le(p.partition_type_guid).to_string(),
+			part_uuid: Some(Uuid::from_bytes_le(p.unique_partition_guid).to_string()),
+		})
+		.collect()
+}
+
+fn mbr_entries(table: &MBR) -> Vec<TableEntry> {
+	table.iter()
+		.filter(|(_, p)| p.is_used())
+		.map(|(num, p)| TableEntry {
+			num: num as u32,
+			start: p.starting_lba as u64,
+			end: (p.starting_lba as u64 + p.sectors as u64).saturating_sub(1),
+			part_type: format!("{:#04x}", p.sys),
+			part_uuid: None,
+		})
+		.collect()
+}
+
+/// Compare the partitions read back with the written ones.
+fn compare_entries(written: &[TableEntry], read: &[TableEntry]) -> Result<()> {
+	for w in written {
+		match read.iter().find(|r| r.num == w.num) {
+			Some(r) if r == w => (),
+			Some(r) => bail!(
+				"Partition {} was written with {}, but read back with {}",
+				w.num,
+				w,
+				r
+			),
+			None => bail!("Partition {} is missing from the partition table", w.num),
+		}
+	}
+	if written.len() != read.len() {
+		bail!(
+			"{} partitions were written, but {} are read back",
+			written.len(),
+			read.len()
+		);
+	}
+	Ok(())
+}
+
+/// Make sure the backup GPT header sits at the last LBA of the disk, and points to the primary header.
+fn check_backup_gpt_header<R: Read + Seek>(fd: &mut R, sector_size: u64) -> Result<()> {
+	let size = fd.seek(SeekFrom::End(0))?;
+	let last_lba = (size / sector_size)
+		.checked_sub(1)
+		.context("The image is empty")?;
+	fd.seek(SeekFrom::Start(last_lba * sector_size))?;
+	let mut header = [0u8; 40];
+	fd.read_exact(&mut header)
+		.context("Unable to read the backup GPT header")?;
+	if &header[..8] != GPT_SIGNATURE {
+		bail!("No backup GPT header found at the last LBA ({})", last_lba);
+	}
+	let lba = |range: std::ops::Range<usize>| {
+		u64::from_le_bytes(header[range].try_into().unwrap_or_default())
+	};
+	let (my_lba, alternate_lba) = (lba(24..32), lba(32..40));
+	if my_lba != last_lba {
+		bail!(
+			"The backup GPT header at the last LBA ({}) claims to be at LBA {}",
+			last_lba,
+			my_lba
+		);
+	}
+	if alternate_lba != 1 {
+		bail!(
+			"The backup GPT header points to the primary header at LBA {}, expected LBA 1",
+			alternate_lba
+		);
+	}
+	Ok(())
+}
+
+/// Read the GPT back from the disk, and compare it with the written one.
+pub fn verify_gpt(img: &Path, written: &GPT) -> Result<()> {
+	let mut fd = File::open(img)?;
+	let read = GPT::read_from(&mut fd, written.sector_size)
+		.context("Unable to read the partition table back")?;
+	if !read.is_primary() {
+		bail!("The primary GPT header is corrupted");
+	}
+	if read.header.disk_guid != written.header.disk_guid {
+		bail!(
+			"The disk GUID was written as {}, but read back as {}",
+			Uuid::from_bytes_le(written.header.disk_guid),
+			Uuid::from_bytes_le(read.header.disk_guid)
+		);
+	}
+	compare_entries(&gpt_entries(written), &gpt_entries(&read))?;
+	let last_lba = fd.seek(SeekFrom::End(0))? / written.sector_size - 1;
+	if read.header.backup_lba != last_lba {
+		bail!(
+			"The primary GPT header points to the backup header at LBA {}, but the last LBA is {}",
+			read.header.backup_lba,
+			last_lba
+		);
+	}
+	check_backup_gpt_header(&mut fd, written.sector_size)
+}
+
+/// Read the MBR back from the disk, and compare it with the written one.
+pub fn verify_mbr(img: &Path, written: &MBR) -> Result<()> {
+	let mut fd = File::open(img)?;
+	let read = MBR::read_from(&mut fd, written.sector_size)
+		.context("Unable to read the partition table back")?;
+	if read.header.disk_signature != written.header.disk_signature {
+		bail!(
+			"The disk signature was written as {:08x}, but read back as {:08x}",
+			u32::from_le_bytes(written.header.disk_signature),
+			u32::from_le_bytes(read.header.disk_signature)
+		);
+	}
+	compare_entries(&mbr_entries(written), &mbr_entries(&read))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use super::*;
+
+	fn entry(num: u32, start: u64, end: u64) -> TableEntry {
+		TableEntry {
+			num,
+			start,
+			end,
+			part_type: "0x83".into(),
+			part_uuid: None,
+		}
+	}
+
+	#[test]
+	fn test_compare_entries() {
+		let written = [entry(1, 2048, 616447), entry(2, 616448, 12582878)];
+		assert!(compare_entries(&written, &written).is_ok());
+		let err = compare_entries(
+			&written,
+			&[entry(1, 2048, 616447), entry(2, 616448, 12582911)],
+		)
+		.unwrap_err();
+		assert_eq!(
+			err.to_string(),
+			"Partition 2 was written with sectors 616448-12582878, type 0x83, but read back with sectors 616448-12582911, type 0x83"
+		);
+		let err = compare_entries(&written, &written[..1]).unwrap_err();
+		assert!(err.to_string().contains("missing"));
+		let err = compare_entries(&written[..1], &written).unwrap_err();
+		assert!(err.to_string().contains("1 partitions were written, but 2"));
+		let mut other = written.clone();
+		other[0].part_type = "0x0c".into();
+		assert!(compare_entries(&written, &other).is_err());
+		let mut other = written.clone();
+		other[1].part_uuid = Some("8c2d6a1e-3f4b-4c5d-9e6f-7a8b9c0d1e2f".into());
+		assert!(compare_entries(&written, &other).is_err());
+	}
+
+	fn disk(last_lba: u64, my_lba: u64, alternate_lba: u64) -> Cursor<Vec<u8>> {
+		let mut disk = vec![0u8; (last_lba as usize + 1) * 512];
+		let header = &mut disk[last_lba as usize * 512..];
+		header[..8].copy_from_slice(GPT_SIGNATURE);
+		header[24..32].copy_from_slice(&my_lba.to_le_bytes());
+		header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+		Cursor::new(disk)
+	}
+
+	#[test]
+	fn test_backup_gpt_header() {
+		assert!(check_backup_gpt_header(&mut disk(2047, 2047, 1), 512).is_ok());
+		// Written for a larger image
+		let err = check_backup_gpt_header(&mut disk(2047, 4095, 1), 512).unwrap_err();
+		assert!(err.to_string().contains("claims to be at LBA 4095"));
+		assert!(check_backup_gpt_header(&mut disk(2047, 2047, 2), 512).is_err());
+		let mut disk = disk(2047, 2047, 1);
+		disk.get_mut().extend([0; 512]);
+		let err = check_backup_gpt_header(&mut disk, 512).unwrap_err();
+		assert!(err.to_string().contains("No backup GPT header"));
+		assert!(check_backup_gpt_header(&mut Cursor::new(Vec::new()), 512).is_err());
+	}
+}